@@ -0,0 +1,161 @@
+//! Per-page access counters for hot/cold classification, e.g., to decide
+//! which pages to migrate between memory tiers.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use memory_addr::{AddrRange, MemoryAddr};
+
+use crate::{MappingBackend, MemoryArea, MemorySet};
+
+/// The pages of an area split by their access counts, returned by
+/// [`MemoryArea::hot_cold`].
+///
+/// Adjacent pages of the same class are merged into one range. Pages never
+/// faulted in nor harvested are in neither class.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HotCold<A: MemoryAddr> {
+    /// The ranges of the pages accessed at least as often as the threshold.
+    pub hot: Vec<AddrRange<A>>,
+    /// The ranges of the other tracked pages.
+    pub cold: Vec<AddrRange<A>>,
+}
+
+/// Saturating access counters, keyed by page.
+#[derive(Debug, Clone)]
+pub(crate) struct AccessCounts<A> {
+    counts: BTreeMap<A, u8>,
+}
+
+impl<A: MemoryAddr> AccessCounts<A> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            counts: BTreeMap::new(),
+        }
+    }
+
+    /// Counts an access to `page`.
+    pub(crate) fn record(&mut self, page: A) {
+        let count = self.counts.entry(page).or_insert(0);
+        *count = count.saturating_add(1);
+    }
+
+    /// Starts tracking `page` without counting an access.
+    pub(crate) fn track(&mut self, page: A) {
+        self.counts.entry(page).or_insert(0);
+    }
+
+    pub(crate) fn get(&self, page: A) -> u8 {
+        self.counts.get(&page).copied().unwrap_or(0)
+    }
+
+    /// Halves all the counters, so that old accesses weigh less.
+    pub(crate) fn age(&mut self) {
+        self.counts.values_mut().for_each(|count| *count /= 2);
+    }
+
+    pub(crate) fn split_off(&mut self, pos: A) -> Self {
+        Self {
+            counts: self.counts.split_off(&pos),
+        }
+    }
+
+    pub(crate) fn append(&mut self, other: &mut Self) {
+        self.counts.append(&mut other.counts);
+    }
+
+    pub(crate) fn rebase(&mut self, rebase: impl Fn(A) -> A) {
+        self.counts = core::mem::take(&mut self.counts)
+            .into_iter()
+            .map(|(page, count)| (rebase(page), count))
+            .collect();
+    }
+
+    /// Forgets the pages within `range`.
+    pub(crate) fn clear(&mut self, range: AddrRange<A>) {
+        self.counts.retain(|&page, _| !range.contains(page));
+    }
+
+    /// Classifies the tracked pages within `range`, see [`HotCold`].
+    pub(crate) fn hot_cold(
+        &self,
+        range: AddrRange<A>,
+        page_size: usize,
+        threshold: u8,
+    ) -> HotCold<A> {
+        let mut result = HotCold {
+            hot: Vec::new(),
+            cold: Vec::new(),
+        };
+        let start = range.start.align_down(page_size);
+        if start >= range.end {
+            return result;
+        }
+        for (&page, &count) in self.counts.range(start..range.end) {
+            let class = if count >= threshold {
+                &mut result.hot
+            } else {
+                &mut result.cold
+            };
+            let end = page.add(page_size);
+            match class.last_mut() {
+                Some(last) if last.end == page => last.end = end,
+                _ => class.push(AddrRange::new(page, end)),
+            }
+        }
+        result
+    }
+}
+
+impl<B: MappingBackend> MemoryArea<B> {
+    /// Returns the access count of the page containing `vaddr`, which
+    /// saturates at `255`.
+    ///
+    /// The page is counted each time a fault in it is handled and each time
+    /// [`MemorySet::harvest_accessed`] finds its accessed bit set.
+    pub fn access_count(&self, vaddr: B::Addr) -> u8 {
+        self.access.get(vaddr.align_down(self.page_size()))
+    }
+
+    /// Splits the tracked pages of the area into the ones whose access count
+    /// is at least `threshold` and the others.
+    pub fn hot_cold(&self, threshold: u8) -> HotCold<B::Addr> {
+        self.access
+            .hot_cold(self.va_range(), self.page_size(), threshold)
+    }
+
+    /// Halves the access counts of the pages, e.g., after each harvest, so
+    /// that pages no longer accessed eventually turn cold.
+    pub fn age_access_counts(&mut self) {
+        self.access.age();
+    }
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Harvests the accessed bits of the resident pages with
+    /// [`MappingBackend::test_and_clear_accessed`], counting an access to
+    /// the pages whose bit was set, see [`MemoryArea::access_count`].
+    ///
+    /// Pages whose bit was clear start being tracked with no new access.
+    /// Returns the number of accessed pages.
+    ///
+    /// It clears the same bits as [`StatsSampler`](crate::StatsSampler), so
+    /// the two should not be used on the same set.
+    pub fn harvest_accessed(&mut self, page_table: &mut B::PageTable) -> usize {
+        let mut accessed = 0;
+        for area in self.areas.values_mut() {
+            let pages: Vec<_> = crate::sample::resident_pages(area, area.start()).collect();
+            for page in pages {
+                match area.backend().test_and_clear_accessed(page, page_table) {
+                    Some(true) => {
+                        area.access.record(page);
+                        accessed += 1;
+                    }
+                    Some(false) => area.access.track(page),
+                    None => {}
+                }
+            }
+        }
+        accessed
+    }
+}
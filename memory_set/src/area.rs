@@ -1,17 +1,92 @@
 use core::fmt;
 
-use memory_addr::{AddrRange, MemoryAddr, PAGE_SIZE_4K};
-
-use crate::{MappingBackend, MappingError, MappingResult};
-use alloc::collections::BTreeMap;
+use memory_addr::{AddrRange, MemoryAddr, PhysAddr};
 
+#[cfg(feature = "access-count")]
+use crate::access::AccessCounts;
+#[cfg(feature = "mmap")]
+use crate::mmap::FileMapping;
+#[cfg(feature = "RAII")]
+use crate::shared::SharedMapping;
+use crate::{
+    Advice, Confidentiality, InterleavePolicy, MappingBackend, MappingError, MappingKind,
+    MappingResult, backend_error, err_range,
+};
+#[cfg(feature = "RAII")]
+use crate::{FrameMap, SwapSlot};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+#[cfg(feature = "RAII")]
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+#[cfg(feature = "RAII")]
+use memory_addr::FrameTracker;
 
+/// Statistics of a memory area, returned by [`MemoryArea::stat`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub struct AreaStat {
+    /// The start address of the area.
     pub start: usize,
+    /// The end address of the area.
     pub end: usize,
+    /// The virtual size of the area in bytes.
     pub size: usize,
+    /// The resident bytes, i.e., the size of the frames held by the area,
+    /// without the zero pages.
     pub rss: usize,
+    /// The swapped out bytes.
     pub swap: usize,
+    /// Resident bytes whose frames are shared with other areas, e.g. through
+    /// copy-on-write or shared memory.
+    pub shared: usize,
+    /// When the area was created and last active.
+    pub times: AreaTimes,
+}
+
+/// Coarse timestamps of the lifetime of a memory area, read from the clock
+/// set with [`MemorySet::set_clock`](crate::MemorySet::set_clock).
+///
+/// They are all `None` if the set has no clock. The unit is up to the clock.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AreaTimes {
+    /// When the area was mapped or inserted into the set. Parts split off an
+    /// area keep its creation time.
+    pub created: Option<u64>,
+    /// When a page fault in the area was last handled.
+    pub last_fault: Option<u64>,
+    /// When the flags of the area were last changed.
+    pub last_protect: Option<u64>,
+}
+
+impl AreaTimes {
+    /// Returns the latest of the timestamps, e.g., to find the areas idle for
+    /// longer than a threshold.
+    pub fn last_active(&self) -> Option<u64> {
+        self.created.max(self.last_fault).max(self.last_protect)
+    }
+
+    /// Returns the timestamps of an area merged from areas with `self` and
+    /// `other`: the earliest creation and the latest activities.
+    fn merge(self, other: Self) -> Self {
+        let earliest = match (self.created, other.created) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        Self {
+            created: earliest,
+            last_fault: self.last_fault.max(other.last_fault),
+            last_protect: self.last_protect.max(other.last_protect),
+        }
+    }
+}
+
+/// Frames detached from a memory area by an unmap operation.
+#[cfg(feature = "RAII")]
+pub struct AreaFrames<B: MappingBackend> {
+    /// The unmapped part of the area that held the frames.
+    pub range: AddrRange<B::Addr>,
+    /// The frames, keyed by their old virtual addresses.
+    pub frames: BTreeMap<B::Addr, B::FrameTrackerRef>,
 }
 
 /// A memory area represents a continuous range of virtual memory with the same
@@ -24,11 +99,52 @@ pub struct MemoryArea<B: MappingBackend> {
     va_range: AddrRange<B::Addr>,
     /// Hold pages with RAII.
     /// The key is the vpn of the page,
-    /// so it must be aligned to PAGE_SIZE_4K.
+    /// so it must be aligned to the frame size.
     #[cfg(feature = "RAII")]
-    pub frames: BTreeMap<B::Addr, B::FrameTrackerRef>,
+    pub frames: FrameMap<B>,
+    /// The swapped-out pages, see [`is_swapped`](Self::is_swapped).
+    #[cfg(feature = "RAII")]
+    pub(crate) swapped: BTreeMap<B::Addr, Arc<SwapSlot>>,
+    /// The shared object mapped by the area, see
+    /// [`new_shared`](Self::new_shared).
+    #[cfg(feature = "RAII")]
+    pub(crate) shared: Option<SharedMapping<B>>,
+    /// The file mapped by the area, see [`new_file`](Self::new_file).
+    #[cfg(feature = "mmap")]
+    pub(crate) file: Option<FileMapping<B>>,
     flags: B::Flags,
     pub(crate) backend: B,
+    interleave: Option<InterleavePolicy>,
+    /// The page size declared with
+    /// [`with_map_page_size`](Self::with_map_page_size).
+    map_page_size: Option<usize>,
+    /// The huge page advice of the area, see [`thp_advice`](Self::thp_advice).
+    thp_advice: Option<bool>,
+    pub(crate) write_protected: bool,
+    /// Pages written since the soft-dirty marks were last cleared, or `None`
+    /// if they have never been cleared (all pages are soft-dirty).
+    soft_dirty: Option<BTreeSet<B::Addr>>,
+    /// Pages of a shared file mapping written since they were last written
+    /// back by [`MemorySet::sync`](crate::MemorySet::sync), or `None` if
+    /// they never were (all resident pages are dirty).
+    #[cfg(feature = "mmap")]
+    pub(crate) file_dirty: Option<BTreeSet<B::Addr>>,
+    /// The NUMA nodes the pages were allocated on at their first touch.
+    first_touch: BTreeMap<B::Addr, usize>,
+    /// The access counts of the pages, see [`access_count`](Self::access_count).
+    #[cfg(feature = "access-count")]
+    pub(crate) access: AccessCounts<B::Addr>,
+    locked: bool,
+    sealed: bool,
+    confidentiality: Confidentiality,
+    /// Reserved but not committed yet, see [`is_reserved`](Self::is_reserved).
+    reserved: bool,
+    hole: bool,
+    /// A name for diagnostics, e.g., `"[stack]"` or `"libfoo.so .text"`.
+    label: Option<String>,
+    /// The sizes of the leading and trailing guard regions.
+    guards: (usize, usize),
+    times: AreaTimes,
 }
 
 // TODO: should decrease ref of page if mapping is changed.
@@ -49,13 +165,95 @@ impl<B: MappingBackend> MemoryArea<B> {
         Self {
             va_range: AddrRange::from_start_size(start, size),
             #[cfg(feature = "RAII")]
-            frames: frame_alloced.unwrap_or(BTreeMap::new()),
+            frames: frame_alloced.map(FrameMap::from).unwrap_or_default(),
+            #[cfg(feature = "RAII")]
+            swapped: BTreeMap::new(),
+            #[cfg(feature = "RAII")]
+            shared: None,
+            #[cfg(feature = "mmap")]
+            file: None,
             flags,
             backend,
+            interleave: None,
+            map_page_size: None,
+            thp_advice: None,
+            write_protected: false,
+            soft_dirty: None,
+            #[cfg(feature = "mmap")]
+            file_dirty: None,
+            first_touch: BTreeMap::new(),
+            #[cfg(feature = "access-count")]
+            access: AccessCounts::new(),
+            locked: false,
+            sealed: false,
+            confidentiality: Confidentiality::Private,
+            reserved: false,
+            hole: false,
+            label: None,
+            guards: (0, 0),
+            times: AreaTimes::default(),
         }
     }
 
+    /// Adds a `leading` and a `trailing` guard region of the given sizes
+    /// right before and after the area, e.g., to catch stack overflows.
+    ///
+    /// The guard regions are part of the [reserved range](Self::reserved_range)
+    /// of the area, so [`MemorySet`](crate::MemorySet) does not place other
+    /// areas there, but they are never mapped: accesses to them fault like
+    /// accesses to unmapped memory. The sizes must be multiples of the
+    /// granularity of the backend.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the reserved range overflows.
+    pub fn with_guards(mut self, leading: usize, trailing: usize) -> Self {
+        assert!(
+            self.start().checked_sub(leading).is_some()
+                && self.end().checked_add(trailing).is_some(),
+            "guard regions out of the address space"
+        );
+        self.guards = (leading, trailing);
+        self
+    }
+
+    /// Declares that the area is mapped with pages of `page_size` bytes,
+    /// e.g., 2M or 1G huge pages.
+    ///
+    /// The page size becomes the [granularity](Self::granularity) of the
+    /// area if it is larger than the backend's, so the area, and any range
+    /// splitting, resizing or protecting it, must be aligned to it. The
+    /// backend maps the area with [`MappingBackend::map_with_page_size`], so
+    /// that it can install block mappings.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `page_size` is not a power of two.
+    pub fn with_map_page_size(mut self, page_size: usize) -> Self {
+        assert!(page_size.is_power_of_two(), "page size not a power of two");
+        self.map_page_size = Some(page_size);
+        self
+    }
+
+    /// Returns the page size declared with
+    /// [`with_map_page_size`](Self::with_map_page_size), if any.
+    pub const fn map_page_size(&self) -> Option<usize> {
+        self.map_page_size
+    }
+
+    /// Clones the area with new flags, sharing the frames.
+    ///
+    /// Same as [`clone_shared`](Self::clone_shared).
     pub fn clone_(&self, flags: B::Flags) -> Self {
+        self.clone_shared(flags)
+    }
+
+    /// Clones the area with new flags.
+    ///
+    /// The clone holds references to the same frames (if RAII is on), so both
+    /// areas alias the same physical memory. Use
+    /// [`clone_copied`](Self::clone_copied) to get private copies instead.
+    pub fn clone_shared(&self, flags: B::Flags) -> Self {
         let mut area = self.clone();
         area.set_flags(flags);
         area
@@ -86,20 +284,229 @@ impl<B: MappingBackend> MemoryArea<B> {
         self.va_range.size()
     }
 
+    /// Returns the sizes of the leading and trailing guard regions, see
+    /// [`with_guards`](Self::with_guards).
+    pub const fn guards(&self) -> (usize, usize) {
+        self.guards
+    }
+
+    /// Returns the virtual address range reserved by the area, i.e., its
+    /// range extended by the guard regions.
+    pub fn reserved_range(&self) -> AddrRange<B::Addr> {
+        AddrRange::new(
+            self.start().wrapping_sub(self.guards.0),
+            self.end().wrapping_add(self.guards.1),
+        )
+    }
+
     /// Returns the mapping backend of the memory area.
     pub const fn backend(&self) -> &B {
         &self.backend
     }
 
+    /// Returns the mapping granularity of the area: the backend's, or the
+    /// [declared page size](Self::with_map_page_size) if it is larger.
+    pub fn granularity(&self) -> usize {
+        let granularity = self.backend.granularity();
+        self.map_page_size
+            .map_or(granularity, |size| size.max(granularity))
+    }
+
+    /// Maps `[start, start + size)` with the backend, passing the declared
+    /// page size down if any.
+    #[cfg(feature = "RAII")]
+    pub(crate) fn backend_map(
+        &self,
+        start: B::Addr,
+        size: usize,
+        flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> Result<BTreeMap<B::Addr, B::FrameTrackerRef>, B::Error> {
+        match self.map_page_size {
+            Some(page_size) => self
+                .backend
+                .map_with_page_size(start, size, flags, page_size, page_table),
+            None => self.backend.map(start, size, flags, page_table),
+        }
+    }
+
+    /// Maps `[start, start + size)` with the backend, passing the declared
+    /// page size down if any.
+    #[cfg(not(feature = "RAII"))]
+    pub(crate) fn backend_map(
+        &self,
+        start: B::Addr,
+        size: usize,
+        flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> Result<(), B::Error> {
+        match self.map_page_size {
+            Some(page_size) => self
+                .backend
+                .map_with_page_size(start, size, flags, page_size, page_table),
+            None => self.backend.map(start, size, flags, page_table),
+        }
+    }
+
+    /// Write-protects `[start, start + size)` with the backend, failing if it
+    /// does not support write protection.
+    pub(crate) fn backend_write_protect(
+        &self,
+        start: B::Addr,
+        size: usize,
+        flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        match self.backend.write_protect(start, size, flags, page_table) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(MappingError::BadState(err_range(start, size), None)),
+            Err(err) => Err(backend_error(start, size, err)),
+        }
+    }
+
+    /// Installs `frame` at the page `vaddr` with the backend, failing if it
+    /// does not support it.
+    #[cfg(feature = "RAII")]
+    pub(crate) fn backend_map_frame(
+        &self,
+        vaddr: B::Addr,
+        frame: &B::FrameTrackerImpl,
+        flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        let size = self.frame_size();
+        match self.backend.map_frame(vaddr, frame, flags, page_table) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(MappingError::BadState(err_range(vaddr, size), None)),
+            Err(err) => Err(backend_error(vaddr, size, err)),
+        }
+    }
+
+    /// Moves the mappings of `[old_start, old_start + size)` to `new_start`
+    /// with the backend, failing if it does not support moving mappings.
+    pub(crate) fn backend_move_mappings(
+        &self,
+        old_start: B::Addr,
+        new_start: B::Addr,
+        size: usize,
+        flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        match self
+            .backend
+            .move_mappings(old_start, new_start, size, flags, page_table)
+        {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(MappingError::BadState(err_range(old_start, size), None)),
+            Err(err) => Err(backend_error(old_start, size, err)),
+        }
+    }
+
+    /// Returns whether the area's range and guard regions are aligned to its
+    /// backend's mapping granularity.
+    pub fn is_granule_aligned(&self) -> bool {
+        let granularity = self.granularity();
+        self.start().is_aligned(granularity)
+            && self.end().is_aligned(granularity)
+            && self.guards.0.is_multiple_of(granularity)
+            && self.guards.1.is_multiple_of(granularity)
+    }
+
+    /// Returns the interleaving policy of the area, if any.
+    pub fn interleave(&self) -> Option<&InterleavePolicy> {
+        self.interleave.as_ref()
+    }
+
+    /// Sets the interleaving policy used when allocating frames for the area.
+    pub fn set_interleave(&mut self, policy: Option<InterleavePolicy>) {
+        self.interleave = policy;
+    }
+
+    /// Returns the label of the area, see [`set_label`](Self::set_label).
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Sets or clears the label of the area, e.g., `"heap"`, `"[stack]"` or
+    /// `"libfoo.so .text"`.
+    ///
+    /// It is only used for diagnostics, like the [`Debug`](fmt::Debug)
+    /// output and [`MemorySet::dump_maps`](crate::MemorySet::dump_maps), and
+    /// is kept by the parts when the area is split.
+    pub fn set_label(&mut self, label: Option<String>) {
+        self.label = label;
+    }
+
+    /// Returns the commit charge of the area in bytes, i.e., the memory that
+    /// may still have to be allocated for it: the pages that are not resident
+    /// yet, plus the resident pages shared copy-on-write and the zero pages
+    /// (if RAII is on), which are copied on the first write.
+    ///
+    /// It is 0 for reserved areas and for areas that
+    /// [`MappingBackend::charges_commit`] does not charge.
+    pub fn commit_charge(&self) -> usize {
+        if self.reserved || !self.backend.charges_commit(self.flags) {
+            return 0;
+        }
+        #[cfg(feature = "RAII")]
+        {
+            let cow = if self.write_protected {
+                self.shared_pages() * self.frame_size()
+            } else {
+                0
+            };
+            let zero = self.zero_pages() * self.frame_size();
+            self.size().saturating_sub(self.resident_size()) + cow + zero
+        }
+        #[cfg(not(feature = "RAII"))]
+        self.size()
+    }
+
+    /// Returns the commit charge of growing the area by `size` bytes, see
+    /// [`commit_charge`](Self::commit_charge).
+    pub(crate) fn growth_charge(&self, size: usize) -> usize {
+        if self.reserved || !self.backend.charges_commit(self.flags) {
+            return 0;
+        }
+        size
+    }
+
+    /// Returns the frame source to allocate the next page of the area from,
+    /// or `None` if the area has no interleaving policy.
+    ///
+    /// [`handle_fault`](Self::handle_fault) calls it for every fault it
+    /// delegates to the backend, see [`MappingBackend::select_source`].
+    /// Other paths allocating frames for the area should call it once per
+    /// page so that the per-source statistics stay accurate.
+    pub fn next_alloc_source(&mut self) -> Option<usize> {
+        self.interleave.as_mut().map(InterleavePolicy::next_source)
+    }
+
     pub fn stat(&self) -> AreaStat {
         AreaStat {
             start: self.start().into(),
             end: self.end().into(),
             size: self.size(),
-            rss: self.frames_count() * PAGE_SIZE_4K, // TODO: large page
-            swap: 0
+            #[cfg(feature = "RAII")]
+            rss: self.resident_size() - self.zero_pages() * self.frame_size(),
+            #[cfg(not(feature = "RAII"))]
+            rss: 0,
+            #[cfg(feature = "RAII")]
+            swap: self.swapped_size(),
+            #[cfg(not(feature = "RAII"))]
+            swap: 0,
+            #[cfg(feature = "RAII")]
+            shared: self.shared_pages() * self.frame_size(),
+            #[cfg(not(feature = "RAII"))]
+            shared: 0,
+            times: self.times,
         }
     }
+
+    /// Returns the lifetime timestamps of the area, see [`AreaTimes`].
+    pub const fn times(&self) -> AreaTimes {
+        self.times
+    }
 }
 
 #[allow(unused)]
@@ -109,11 +516,133 @@ impl<B: MappingBackend> MemoryArea<B> {
         self.flags = new_flags;
     }
 
+    /// Returns the lifetime timestamps to update, see [`times`](Self::times).
+    pub(crate) fn times_mut(&mut self) -> &mut AreaTimes {
+        &mut self.times
+    }
+
+    /// Changes the lock state, see [`is_locked`](Self::is_locked).
+    pub(crate) fn set_locked(&mut self, locked: bool) {
+        self.locked = locked;
+    }
+
+    /// Changes the confidentiality, see
+    /// [`confidentiality`](Self::confidentiality).
+    pub(crate) fn set_confidentiality(&mut self, confidentiality: Confidentiality) {
+        self.confidentiality = confidentiality;
+    }
+
+    /// Converts the area between private and shared memory of a
+    /// confidential VM, see
+    /// [`MemorySet::convert_private_to_shared`](crate::MemorySet::convert_private_to_shared).
+    ///
+    /// The frames shared copy-on-write and the zero frame are replaced by
+    /// private copies first, so that the conversion does not reach other
+    /// areas. Reserved areas only take the new flags, since nothing is
+    /// mapped yet.
+    pub(crate) fn convert_confidentiality(
+        &mut self,
+        to: Confidentiality,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        #[cfg(feature = "RAII")]
+        {
+            let page_size = self.frame_size();
+            let pages: Vec<_> = self
+                .frames
+                .iter()
+                .filter(|(_, frame)| self.write_protected || self.is_zero_frame(frame))
+                .flat_map(|(&start, _)| {
+                    let pages = self.frames.frame_size(&start) / page_size;
+                    (0..pages).map(move |i| start.add(i * page_size))
+                })
+                .collect();
+            for page in pages {
+                self.break_cow(page, page_table)?;
+            }
+        }
+        let flags = self.backend.confidential_flags(self.flags, to);
+        if !self.reserved
+            && !self.backend.convert_confidentiality(
+                self.start(),
+                self.size(),
+                flags,
+                to,
+                page_table,
+            )
+        {
+            return Err(MappingError::BadState(
+                err_range(self.start(), self.size()),
+                None,
+            ));
+        }
+        self.flags = flags;
+        self.confidentiality = to;
+        Ok(())
+    }
+
+    /// Seals the area for good, see [`is_sealed`](Self::is_sealed).
+    pub(crate) fn seal(&mut self) {
+        self.sealed = true;
+    }
+
+    /// Changes the reservation state, see [`is_reserved`](Self::is_reserved).
+    pub(crate) fn set_reserved(&mut self, reserved: bool) {
+        self.reserved = reserved;
+    }
+
+    /// Turns the area into a hole, see [`is_hole`](Self::is_hole).
+    pub(crate) fn make_hole(&mut self) {
+        self.reserved = true;
+        self.sealed = true;
+        self.hole = true;
+    }
+
     /// Changes the end address of the memory area.
-    pub(crate) fn set_end(&mut self, new_end: B::Addr) {
+    pub(crate) fn set_end(&mut self, new_end: B::Addr) -> MappingResult {
+        #[cfg(feature = "RAII")]
+        self.frames.demote(new_end)?;
         self.va_range.end = new_end;
         #[cfg(feature = "RAII")]
         self.retain_frames_in_range();
+        Ok(())
+    }
+
+    /// Moves the bookkeeping of the area to start at `new_start`, rebasing the
+    /// per-page state (frames, soft-dirty pages) and the backend along with
+    /// it.
+    ///
+    /// Only the bookkeeping changes, the page table entries must be moved by
+    /// the caller.
+    pub(crate) fn relocate(&mut self, new_start: B::Addr) {
+        let old_start = self.start();
+        let rebase = |vaddr: B::Addr| new_start.add(vaddr.sub_addr(old_start));
+        self.va_range = AddrRange::from_start_size(new_start, self.size());
+        self.backend.relocate(old_start, new_start);
+        #[cfg(feature = "RAII")]
+        {
+            self.frames = core::mem::take(&mut self.frames)
+                .into_iter()
+                .map(|(vaddr, frame)| (rebase(vaddr), frame))
+                .collect();
+            self.swapped = core::mem::take(&mut self.swapped)
+                .into_iter()
+                .map(|(vaddr, slot)| (rebase(vaddr), slot))
+                .collect();
+        }
+        if let Some(dirty) = self.soft_dirty.as_mut() {
+            *dirty = core::mem::take(dirty).into_iter().map(rebase).collect();
+        }
+        #[cfg(feature = "mmap")]
+        if let Some(dirty) = self.file_dirty.as_mut() {
+            *dirty = core::mem::take(dirty).into_iter().map(rebase).collect();
+        }
+        self.first_touch = core::mem::take(&mut self.first_touch)
+            .into_iter()
+            .map(|(vaddr, node)| (rebase(vaddr), node))
+            .collect();
+        #[cfg(feature = "access-count")]
+        self.access.rebase(rebase);
     }
 
     /// Maps the whole memory area in the page table.
@@ -123,10 +652,14 @@ impl<B: MappingBackend> MemoryArea<B> {
         flags: Option<B::Flags>,
     ) -> MappingResult {
         let flag = flags.unwrap_or(self.flags);
+        #[cfg(feature = "RAII")]
+        if self.shared.is_some() {
+            return self.map_shared(flag, page_table);
+        }
+        #[cfg_attr(not(feature = "RAII"), allow(clippy::let_unit_value))]
         let frame_refs = self
-            .backend
-            .map(self.start(), self.size(), flag, page_table)
-            .or(Err(MappingError::BadState))?;
+            .backend_map(self.start(), self.size(), flag, page_table)
+            .map_err(|err| backend_error(self.start(), self.size(), err))?;
         #[cfg(feature = "RAII")]
         self.frames.extend(frame_refs);
         Ok(())
@@ -135,13 +668,54 @@ impl<B: MappingBackend> MemoryArea<B> {
     /// Unmaps the whole memory area in the page table.
     pub fn unmap_area(&mut self, page_table: &mut B::PageTable) -> MappingResult {
         // Backend::Unmap will not deallocate the frames if feature = "RAII".
+        if !self.reserved {
+            self.backend
+                .unmap(self.start(), self.size(), page_table)
+                .map_err(|err| backend_error(self.start(), self.size(), err))?;
+        }
+        // Decrease the ref of frame trackers.
+        #[cfg(feature = "RAII")]
+        {
+            self.frames.clear();
+            self.swapped.clear();
+        }
+        Ok(())
+    }
+
+    /// Unmaps the whole memory area in the page table, but keeps the frames
+    /// so that the area can be restored by [`Self::remap_area`].
+    pub(crate) fn unmap_area_keep_frames(&self, page_table: &mut B::PageTable) -> MappingResult {
+        if self.reserved {
+            return Ok(());
+        }
         self.backend
             .unmap(self.start(), self.size(), page_table)
-            .then_some(())
-            .ok_or(MappingError::BadState)?;
-        // Decrease the ref of frame trackers.
+            .map_err(|err| backend_error(self.start(), self.size(), err))
+    }
+
+    /// Maps the whole memory area again after
+    /// [`Self::unmap_area_keep_frames`].
+    ///
+    /// Frames still held by the area take precedence over the ones returned by
+    /// the backend.
+    pub(crate) fn remap_area(&mut self, page_table: &mut B::PageTable) -> MappingResult {
+        if self.reserved {
+            return Ok(());
+        }
+        #[cfg(feature = "RAII")]
+        if self.shared.is_some() {
+            return self.map_shared(self.flags, page_table);
+        }
+        #[cfg_attr(not(feature = "RAII"), allow(clippy::let_unit_value))]
+        let frame_refs = self
+            .backend_map(self.start(), self.size(), self.flags, page_table)
+            .map_err(|err| backend_error(self.start(), self.size(), err))?;
         #[cfg(feature = "RAII")]
-        self.frames.clear();
+        for (vaddr, frame) in frame_refs {
+            if !self.frames.contains_key(&vaddr) {
+                self.frames.insert(vaddr, frame);
+            }
+        }
         Ok(())
     }
 
@@ -151,16 +725,26 @@ impl<B: MappingBackend> MemoryArea<B> {
         size: usize,
         page_table: &mut B::PageTable,
     ) -> MappingResult {
+        // Split the huge frames crossing the bounds before anything is
+        // unmapped, so that failing to split them leaves the area as is.
+        #[cfg(feature = "RAII")]
+        {
+            self.frames.demote(start)?;
+            self.frames.demote(start.add(size))?;
+        }
         // Backend::Unmap will not deallocate the frames if feature = "RAII".
-        self.backend
-            .unmap(start, size, page_table)
-            .then_some(())
-            .ok_or(MappingError::BadState)?;
+        if !self.reserved {
+            self.backend
+                .unmap(start, size, page_table)
+                .map_err(|err| backend_error(start, size, err))?;
+        }
         // Decrease the ref of frame trackers.
         #[cfg(feature = "RAII")]
         {
-            let mut tail = self.frames.split_off(&start);
-            self.frames.append(&mut tail.split_off(&(start.add(size))));
+            self.take_frames(start, size);
+            let end = start.add(size);
+            self.swapped
+                .retain(|&vaddr, _| vaddr < start || vaddr >= end);
         }
         Ok(())
     }
@@ -171,11 +755,475 @@ impl<B: MappingBackend> MemoryArea<B> {
         new_flags: B::Flags,
         page_table: &mut B::PageTable,
     ) -> MappingResult {
+        if self.reserved {
+            return Ok(());
+        }
         self.backend
-            .protect(self.start(), self.size(), new_flags, page_table);
+            .protect(self.start(), self.size(), new_flags, page_table)
+            .map_err(|err| backend_error(self.start(), self.size(), err))?;
+        if self.write_protected {
+            // Keep copy-on-write and soft-dirty pages faulting on write.
+            self.write_protect_with(new_flags, page_table)?;
+        }
         Ok(())
     }
 
+    /// Downgrades the page table entries of the area to read-only, without
+    /// changing the flags stored in the area.
+    ///
+    /// Used by snapshotting tools that detect writes through faults. Unlike
+    /// [`MemorySet::protect`](crate::MemorySet::protect), the logical
+    /// permissions are kept, so later permission checks are not affected. With
+    /// RAII, only the resident pages are visited. Use
+    /// [`restore_write`](Self::restore_write) to undo it.
+    pub fn write_protect(&mut self, page_table: &mut B::PageTable) -> MappingResult {
+        self.write_protect_with(self.flags, page_table)?;
+        self.write_protected = true;
+        Ok(())
+    }
+
+    /// Write-protects the resident pages of the area with the given flags.
+    fn write_protect_with(&self, flags: B::Flags, page_table: &mut B::PageTable) -> MappingResult {
+        if self.reserved {
+            return Ok(());
+        }
+        #[cfg(feature = "RAII")]
+        let ranges = self.resident_ranges();
+        #[cfg(not(feature = "RAII"))]
+        let ranges = core::iter::once(self.va_range);
+        for range in ranges {
+            self.backend_write_protect(range.start, range.size(), flags, page_table)?;
+        }
+        Ok(())
+    }
+
+    /// Applies an [`Advice`] to the part of the area within `range`, like
+    /// `madvise`.
+    ///
+    /// The backend gets the first chance to handle it with
+    /// [`MappingBackend::advise`]. Otherwise, [`Advice::WillNeed`] is ignored,
+    /// and [`Advice::DontNeed`] and [`Advice::Free`] drop the frames of the
+    /// part (if RAII is on) and map it again as if it was newly created, so a
+    /// lazy backend will demand-fault it afresh, unless the area maps
+    /// [device](MappingKind::Device) memory, in which case
+    /// [`MappingError::InvalidParam`] is returned. The pages of an area
+    /// mapping a shared object or a file are instead left unmapped, to be
+    /// faulted in again from it. The area itself is kept intact.
+    ///
+    /// [`Advice::HugePage`] and [`Advice::NoHugePage`] are recorded for the
+    /// whole area, see [`thp_advice`](Self::thp_advice), before the backend
+    /// is consulted.
+    pub fn advise(
+        &mut self,
+        range: AddrRange<B::Addr>,
+        advice: Advice,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        let start = range.start.max(self.start());
+        let end = range.end.min(self.end());
+        if start >= end || self.reserved {
+            return Ok(());
+        }
+        let size = end.sub_addr(start);
+        match advice {
+            Advice::HugePage => self.thp_advice = Some(true),
+            Advice::NoHugePage => self.thp_advice = Some(false),
+            _ => {}
+        }
+        if self
+            .backend
+            .advise(start, size, advice, self.flags, page_table)
+            .map_err(|err| backend_error(start, size, err))?
+        {
+            return Ok(());
+        }
+        match advice {
+            Advice::WillNeed | Advice::HugePage | Advice::NoHugePage => Ok(()),
+            Advice::DontNeed | Advice::Free
+                if self.backend.mapping_kind() == MappingKind::Device =>
+            {
+                Err(MappingError::InvalidParam(err_range(start, size)))
+            }
+            Advice::DontNeed | Advice::Free => self.discard(start, size, page_table),
+        }
+    }
+
+    /// Drops the contents of `[start, start + size)` and maps it again empty,
+    /// or leaves it to be faulted in again if the area maps a shared object
+    /// or a file.
+    /// Forgets what was recorded about the pages in `[start, end)`, so that
+    /// they start afresh when mapped again.
+    fn forget_pages(&mut self, start: B::Addr, end: B::Addr) {
+        self.first_touch
+            .retain(|&vaddr, _| vaddr < start || vaddr >= end);
+        if let Some(dirty) = self.soft_dirty.as_mut() {
+            let mut tail = dirty.split_off(&start);
+            dirty.append(&mut tail.split_off(&end));
+        }
+        #[cfg(feature = "access-count")]
+        self.access.clear(AddrRange::new(start, end));
+    }
+
+    fn discard(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.unmap_frames(start, size, page_table)?;
+        let end = start.add(size);
+        self.forget_pages(start, end);
+        // Mapping the range again would give it private frames in place of
+        // the ones of the object.
+        #[cfg(feature = "RAII")]
+        if self.maps_object() {
+            return Ok(());
+        }
+        #[cfg_attr(not(feature = "RAII"), allow(clippy::let_unit_value))]
+        let frame_refs = self
+            .backend_map(start, size, self.flags, page_table)
+            .map_err(|err| backend_error(start, size, err))?;
+        #[cfg(feature = "RAII")]
+        self.frames.extend(frame_refs);
+        if self.write_protected {
+            self.backend_write_protect(start, size, self.flags, page_table)?;
+        }
+        Ok(())
+    }
+
+    /// Restores the page table entries of the area to the stored flags after
+    /// [`write_protect`](Self::write_protect).
+    ///
+    /// The pages mapping the [zero frame](MappingBackend::zero_frame) stay
+    /// write-protected, since a write must still copy them.
+    pub fn restore_write(&mut self, page_table: &mut B::PageTable) -> MappingResult {
+        if !self.reserved {
+            self.backend
+                .protect(self.start(), self.size(), self.flags, page_table)
+                .map_err(|err| backend_error(self.start(), self.size(), err))?;
+            #[cfg(feature = "RAII")]
+            for (&start, frame) in self.frames.iter() {
+                if self.is_zero_frame(frame) {
+                    let size = self.frames.frame_size(&start);
+                    self.backend_write_protect(start, size, self.flags, page_table)?;
+                }
+            }
+        }
+        self.write_protected = false;
+        Ok(())
+    }
+
+    /// Returns whether the page table entries of the area are currently
+    /// downgraded by [`write_protect`](Self::write_protect).
+    pub const fn is_write_protected(&self) -> bool {
+        self.write_protected
+    }
+
+    /// Resolves a page fault at `vaddr` whose access is already known to be
+    /// allowed by the area's flags.
+    ///
+    /// A write to a write-protected resident page or to a zero page breaks
+    /// copy-on-write sharing, and a read of an untouched page of an
+    /// anonymous area maps the [zero frame](MappingBackend::zero_frame) if
+    /// any (with RAII); other faults are delegated to
+    /// [`MappingBackend::handle_fault`], after selecting the frame source of
+    /// the [interleaving policy](Self::interleave) if any. Writes are
+    /// recorded for soft-dirty tracking.
+    pub fn handle_fault(
+        &mut self,
+        vaddr: B::Addr,
+        access_flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        if self.reserved {
+            return Err(MappingError::PermissionDenied(err_range(vaddr, 1)));
+        }
+        let page = vaddr.align_down(self.page_size());
+        let is_write = self.backend.is_write_access(access_flags);
+        #[cfg(feature = "RAII")]
+        if self.swapped.contains_key(&page) {
+            self.swap_in_page(page, page_table)?;
+            if !is_write || !self.write_protected {
+                #[cfg(feature = "access-count")]
+                self.access.record(page);
+                return Ok(());
+            }
+        }
+        #[cfg(feature = "RAII")]
+        if is_write
+            && let Some((_, frame, _)) = self.frames.covering(page)
+            && (self.write_protected || self.is_zero_frame(frame))
+        {
+            self.break_cow(page, page_table)?;
+            self.record_write(page);
+            #[cfg(feature = "access-count")]
+            self.access.record(page);
+            return Ok(());
+        }
+
+        #[cfg(feature = "RAII")]
+        if self.maps_object() && !self.frames.contains_key(&page) {
+            let flags = self.flags;
+            self.map_object_page(page, flags, page_table)?;
+            if is_write {
+                self.record_write(page);
+            } else if self.write_protected {
+                self.backend_write_protect(page, self.frame_size(), flags, page_table)?;
+            }
+            #[cfg(feature = "access-count")]
+            self.access.record(page);
+            return Ok(());
+        }
+
+        #[cfg(feature = "RAII")]
+        if !is_write
+            && !self.frames.contains_key(&page)
+            && self.backend.mapping_kind() == MappingKind::Anonymous
+            && let Some(zero) = self.backend.zero_frame()
+        {
+            self.backend_map_frame(page, &zero, self.flags, page_table)?;
+            self.backend_write_protect(page, self.frame_size(), self.flags, page_table)?;
+            self.frames.insert(page, zero);
+            #[cfg(feature = "access-count")]
+            self.access.record(page);
+            return Ok(());
+        }
+
+        if let Some(source) = self.next_alloc_source() {
+            self.backend.select_source(source);
+        }
+        #[cfg(feature = "RAII")]
+        {
+            let frame = self
+                .backend
+                .handle_fault(page, access_flags, self.flags, page_table)
+                .map_err(|err| match err {
+                    Some(err) => backend_error(vaddr, 1, err),
+                    None => MappingError::BadState(err_range(vaddr, 1), None),
+                })?;
+            let paddr = match &frame {
+                Some(frame) => Some(frame.start()),
+                None => self.backend.translate(page).map(|(paddr, _)| paddr),
+            };
+            self.record_first_touch(page, paddr);
+            if let Some(frame) = frame {
+                self.frames.insert(page, frame);
+            }
+        }
+        #[cfg(not(feature = "RAII"))]
+        if !self
+            .backend
+            .handle_fault(page, access_flags, self.flags, page_table)
+        {
+            return Err(MappingError::BadState(err_range(vaddr, 1), None));
+        }
+        #[cfg(not(feature = "RAII"))]
+        self.record_first_touch(page, self.backend.translate(page).map(|(paddr, _)| paddr));
+        if is_write {
+            self.record_write(page);
+        }
+        #[cfg(feature = "access-count")]
+        self.access.record(page);
+        Ok(())
+    }
+
+    /// Records the NUMA node of the frame at `paddr` that was just faulted in
+    /// for `page`, as reported by [`MappingBackend::numa_node`].
+    fn record_first_touch(&mut self, page: B::Addr, paddr: Option<PhysAddr>) {
+        if let Some(node) = paddr.and_then(|paddr| self.backend.numa_node(paddr)) {
+            self.first_touch.entry(page).or_insert(node);
+        }
+    }
+
+    /// Returns the huge page advice given to the area: `Some(true)` after
+    /// [`Advice::HugePage`], `Some(false)` after [`Advice::NoHugePage`], and
+    /// `None` if neither was given.
+    pub const fn thp_advice(&self) -> Option<bool> {
+        self.thp_advice
+    }
+
+    /// Returns the NUMA node the page containing `vaddr` was allocated on at
+    /// its first touch, if known.
+    pub fn first_touch_node(&self, vaddr: B::Addr) -> Option<usize> {
+        let page = vaddr.align_down(self.page_size());
+        self.first_touch.get(&page).copied()
+    }
+
+    /// Returns whether the area is locked in memory, like with `mlock`.
+    ///
+    /// Its frames must then not be swapped out or reclaimed.
+    pub const fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Returns whether the memory of the area is private to a confidential
+    /// VM or shared with the host, see
+    /// [`MemorySet::convert_private_to_shared`](crate::MemorySet::convert_private_to_shared).
+    pub const fn confidentiality(&self) -> Confidentiality {
+        self.confidentiality
+    }
+
+    /// Returns whether the area is sealed, like with `mseal`.
+    ///
+    /// A sealed area cannot be unmapped, moved, resized or protected with
+    /// other flags through its [`MemorySet`](crate::MemorySet), see
+    /// [`MemorySet::seal`](crate::MemorySet::seal).
+    pub const fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+
+    /// Returns whether the area is only reserved, i.e., added to the set by
+    /// [`MemorySet::reserve`](crate::MemorySet::reserve) and not committed
+    /// yet.
+    ///
+    /// Nothing is mapped in a reserved area and its backend is never called:
+    /// unmapping, protecting or advising it only updates the bookkeeping, and
+    /// page faults in it fail with [`MappingError::PermissionDenied`].
+    pub const fn is_reserved(&self) -> bool {
+        self.reserved
+    }
+
+    /// Returns whether the area is a hole added by
+    /// [`MemorySet::add_hole`](crate::MemorySet::add_hole), e.g., for a PCI
+    /// hole.
+    ///
+    /// A hole is a reserved and sealed pseudo-area: it blocks placement and
+    /// mapping, but never maps anything and is left out of the statistics and
+    /// dumps of the set.
+    pub const fn is_hole(&self) -> bool {
+        self.hole
+    }
+
+    /// Returns the number of pages within `range` allocated on each NUMA node
+    /// at their first touch, keyed by node.
+    ///
+    /// Only the pages whose node is reported by [`MappingBackend::numa_node`]
+    /// are counted.
+    pub fn first_touch_nodes(&self, range: AddrRange<B::Addr>) -> BTreeMap<usize, usize> {
+        let mut nodes = BTreeMap::new();
+        let start = range.start.max(self.start()).align_down(self.page_size());
+        let end = range.end.min(self.end());
+        if start >= end {
+            return nodes;
+        }
+        for &node in self.first_touch.range(start..end).map(|(_, node)| node) {
+            *nodes.entry(node).or_insert(0) += 1;
+        }
+        nodes
+    }
+
+    /// Returns whether the page containing `vaddr` is resident, i.e., has a
+    /// frame (if RAII is on) or a translation by
+    /// [`MappingBackend::translate`].
+    pub fn is_resident(&self, vaddr: B::Addr) -> bool {
+        #[cfg(feature = "RAII")]
+        if self
+            .frames
+            .contains_key(&vaddr.align_down(self.frame_size()))
+        {
+            return true;
+        }
+        self.backend.translate(vaddr).is_some()
+    }
+
+    /// Returns the size of the pages of the area.
+    ///
+    /// It is the frame size if RAII is on, or otherwise the backend's base
+    /// page size, or the mapping granularity of the area if it is larger.
+    pub fn page_size(&self) -> usize {
+        #[cfg(feature = "RAII")]
+        return self.frame_size();
+        #[cfg(not(feature = "RAII"))]
+        return self.granularity().max(B::BASE_PAGE_SIZE);
+    }
+
+    /// Clears the soft-dirty marks of the pages within `range`, and
+    /// write-protects them so that the next write faults and can be recorded
+    /// with [`record_write`](Self::record_write).
+    ///
+    /// The pages partially within `range` are cleared as well. The pages
+    /// outside of it keep their marks, even if they were never cleared
+    /// before.
+    pub fn clear_soft_dirty(
+        &mut self,
+        range: AddrRange<B::Addr>,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        let page_size = self.page_size();
+        let start = range.start.max(self.start()).align_down(page_size);
+        let end = range.end.min(self.end());
+        if start >= end || self.reserved {
+            return Ok(());
+        }
+        let end = end.align_up(page_size).min(self.end());
+        #[cfg(feature = "RAII")]
+        let ranges: Vec<_> = self
+            .resident_ranges_in(AddrRange::new(start, end))
+            .collect();
+        #[cfg(not(feature = "RAII"))]
+        let ranges = [AddrRange::new(start, end)];
+        for r in ranges {
+            let (s, e) = (r.start.max(start), r.end.min(end));
+            self.backend_write_protect(s, e.sub_addr(s), self.flags, page_table)?;
+        }
+        self.write_protected = true;
+        if self.soft_dirty.is_none() {
+            let mut kept = self.soft_dirty_pages(AddrRange::new(self.start(), start));
+            kept.extend(self.soft_dirty_pages(AddrRange::new(end, self.end())));
+            self.soft_dirty = Some(kept.into_iter().collect());
+        }
+        let dirty = self.soft_dirty.as_mut().unwrap();
+        let mut tail = dirty.split_off(&start);
+        dirty.append(&mut tail.split_off(&end));
+        Ok(())
+    }
+
+    /// Marks the page containing `vaddr` soft-dirty, and dirty for
+    /// [`MemorySet::sync`](crate::MemorySet::sync) in a shared file mapping.
+    /// Called on write faults.
+    pub fn record_write(&mut self, vaddr: B::Addr) {
+        let page = vaddr.align_down(self.page_size());
+        if let Some(dirty) = self.soft_dirty.as_mut() {
+            dirty.insert(page);
+        }
+        #[cfg(feature = "mmap")]
+        if let Some(dirty) = self.file_dirty.as_mut() {
+            dirty.insert(page);
+        }
+    }
+
+    /// Returns whether the page containing `vaddr` is soft-dirty.
+    pub fn is_soft_dirty(&self, vaddr: B::Addr) -> bool {
+        let page = vaddr.align_down(self.page_size());
+        self.soft_dirty
+            .as_ref()
+            .is_none_or(|dirty| dirty.contains(&page))
+    }
+
+    /// Returns the soft-dirty pages within `range`, in ascending order.
+    ///
+    /// If the marks of the area have never been cleared, every page (every
+    /// resident page with RAII) is reported.
+    pub fn soft_dirty_pages(&self, range: AddrRange<B::Addr>) -> Vec<B::Addr> {
+        let start = range.start.max(self.start()).align_down(self.page_size());
+        let end = range.end.min(self.end());
+        if start >= end {
+            return Vec::new();
+        }
+        match &self.soft_dirty {
+            Some(dirty) => dirty.range(start..end).copied().collect(),
+            #[cfg(feature = "RAII")]
+            None => self.frames.range(start..end).map(|(&va, _)| va).collect(),
+            #[cfg(not(feature = "RAII"))]
+            None => {
+                let page_size = self.page_size();
+                (0..end.sub_addr(start).div_ceil(page_size))
+                    .map(|i| start.add(i * page_size))
+                    .collect()
+            }
+        }
+    }
+
     /// Shrinks the memory area at the left side.
     ///
     /// The start address of the memory area is increased by `new_size`. The
@@ -192,12 +1240,17 @@ impl<B: MappingBackend> MemoryArea<B> {
         let old_size = self.size();
         let unmap_size = old_size - new_size;
 
-        if !self.backend.unmap(self.start(), unmap_size, page_table) {
-            return Err(MappingError::BadState);
+        #[cfg(feature = "RAII")]
+        self.frames.demote(self.start().add(unmap_size))?;
+        if !self.reserved {
+            self.backend
+                .unmap(self.start(), unmap_size, page_table)
+                .map_err(|err| backend_error(self.start(), unmap_size, err))?;
         }
         // Use wrapping_add to avoid overflow check.
         // Safety: `unmap_size` is less than the current size, so it will never
         // overflow.
+        self.forget_pages(self.start(), self.start().wrapping_add(unmap_size));
         self.va_range.start = self.va_range.start.wrapping_add(unmap_size);
         #[cfg(feature = "RAII")]
         self.retain_frames_in_range();
@@ -224,10 +1277,15 @@ impl<B: MappingBackend> MemoryArea<B> {
         // Safety: `new_size` is less than the current size, so it will never overflow.
         let unmap_start = self.start().wrapping_add(new_size);
 
-        if !self.backend.unmap(unmap_start, unmap_size, page_table) {
-            return Err(MappingError::BadState);
+        #[cfg(feature = "RAII")]
+        self.frames.demote(unmap_start)?;
+        if !self.reserved {
+            self.backend
+                .unmap(unmap_start, unmap_size, page_table)
+                .map_err(|err| backend_error(unmap_start, unmap_size, err))?;
         }
 
+        self.forget_pages(unmap_start, self.end());
         // Use wrapping_sub to avoid overflow check, same as above.
         self.va_range.end = self.va_range.end.wrapping_sub(unmap_size);
         #[cfg(feature = "RAII")]
@@ -243,21 +1301,23 @@ impl<B: MappingBackend> MemoryArea<B> {
         assert!(new_size > 0 && new_size > self.size());
         let map_size = new_size - self.size();
         let map_start = self.start().wrapping_sub(map_size);
-        let map_result = self
-            .backend
-            .map(map_start, map_size, self.flags, page_table);
+        if self.reserved {
+            self.va_range.start = map_start;
+            return Ok(());
+        }
+        let map_result = self.backend_map(map_start, map_size, self.flags, page_table);
 
         #[cfg(feature = "RAII")]
         {
-            let mut new_frames = match map_result {
+            let new_frames = match map_result {
                 Ok(r) => r,
-                Err(_) => return Err(MappingError::BadState),
+                Err(err) => return Err(backend_error(map_start, map_size, err)),
             };
-            self.frames.append(&mut new_frames);
+            self.frames.extend(new_frames);
         }
         #[cfg(not(feature = "RAII"))]
-        if map_result.is_err() {
-            return Err(MappingError::BadState);
+        if let Err(err) = map_result {
+            return Err(backend_error(map_start, map_size, err));
         }
         self.va_range.start = map_start;
         Ok(())
@@ -271,51 +1331,179 @@ impl<B: MappingBackend> MemoryArea<B> {
         assert!(new_size > 0 && new_size > self.size());
         let map_size = new_size - self.size();
         let map_start = self.start().wrapping_add(self.size());
-        let map_result = self
-            .backend
-            .map(map_start, map_size, self.flags, page_table);
+        if self.reserved {
+            self.va_range.end = self.va_range.end.wrapping_add(map_size);
+            return Ok(());
+        }
+        let map_result = self.backend_map(map_start, map_size, self.flags, page_table);
 
         #[cfg(feature = "RAII")]
         {
-            let mut new_frames = match map_result {
+            let new_frames = match map_result {
                 Ok(r) => r,
-                Err(_) => return Err(MappingError::BadState),
+                Err(err) => return Err(backend_error(map_start, map_size, err)),
             };
-            self.frames.append(&mut new_frames);
+            self.frames.extend(new_frames);
         }
         #[cfg(not(feature = "RAII"))]
-        if map_result.is_err() {
-            return Err(MappingError::BadState);
+        if let Err(err) = map_result {
+            return Err(backend_error(map_start, map_size, err));
         }
         self.va_range.end = self.va_range.end.wrapping_add(map_size);
         Ok(())
     }
 
+    /// Copies the per-area attributes to an area split off from this one.
+    fn inherit_attrs(&mut self, from: &Self) {
+        self.interleave = from.interleave.as_ref().map(InterleavePolicy::fork);
+        self.map_page_size = from.map_page_size;
+        self.thp_advice = from.thp_advice;
+        self.write_protected = from.write_protected;
+        self.locked = from.locked;
+        self.sealed = from.sealed;
+        self.confidentiality = from.confidentiality;
+        self.reserved = from.reserved;
+        self.hole = from.hole;
+        self.label.clone_from(&from.label);
+        self.times = from.times;
+    }
+
+    /// Returns whether `next` starts at the end of this area and can be merged
+    /// into it.
+    ///
+    /// The backends must agree with [`MappingBackend::can_merge`], and the
+    /// per-area states must match. Areas with an interleave policy are never
+    /// merged, since the policies have their own progress, and neither are
+    /// areas with guard regions between them.
+    pub(crate) fn can_merge(&self, next: &Self) -> bool {
+        self.end() == next.start()
+            && self.guards.1 == 0
+            && next.guards.0 == 0
+            && self.interleave.is_none()
+            && next.interleave.is_none()
+            && self.map_page_size == next.map_page_size
+            && self.thp_advice == next.thp_advice
+            && self.write_protected == next.write_protected
+            && self.locked == next.locked
+            && self.sealed == next.sealed
+            && self.confidentiality == next.confidentiality
+            && self.reserved == next.reserved
+            && self.hole == next.hole
+            && self.label == next.label
+            && self.soft_dirty.is_some() == next.soft_dirty.is_some()
+            && self.shared_continued_by(next)
+            && self
+                .backend
+                .can_merge(self.flags, &next.backend, next.flags)
+    }
+
+    /// Returns whether `next` maps the rest of the shared object or file
+    /// mapped by this area, or neither maps one.
+    fn shared_continued_by(&self, next: &Self) -> bool {
+        #[cfg(feature = "RAII")]
+        match (&self.shared, &next.shared) {
+            (Some(shared), Some(next_shared)) => {
+                return shared.is_continued_by(self.size(), next_shared);
+            }
+            (None, None) => {}
+            _ => return false,
+        }
+        #[cfg(feature = "mmap")]
+        match (&self.file, &next.file) {
+            (Some(file), Some(next_file)) => {
+                return file.is_continued_by(self.size(), next_file)
+                    && self.file_dirty.is_some() == next.file_dirty.is_some();
+            }
+            (None, None) => {}
+            _ => return false,
+        }
+        let _ = next;
+        true
+    }
+
+    /// Merges `next` into this area, which must be checked with
+    /// [`can_merge`](Self::can_merge) first.
+    pub(crate) fn merge(&mut self, mut next: Self) {
+        debug_assert!(self.can_merge(&next));
+        self.va_range.end = next.end();
+        self.guards.1 = next.guards.1;
+        #[cfg(feature = "RAII")]
+        {
+            self.frames.append(&mut next.frames);
+            self.swapped.append(&mut next.swapped);
+        }
+        if let (Some(dirty), Some(next_dirty)) = (&mut self.soft_dirty, &mut next.soft_dirty) {
+            dirty.append(next_dirty);
+        }
+        #[cfg(feature = "mmap")]
+        if let (Some(dirty), Some(next_dirty)) = (&mut self.file_dirty, &mut next.file_dirty) {
+            dirty.append(next_dirty);
+        }
+        self.first_touch.append(&mut next.first_touch);
+        #[cfg(feature = "access-count")]
+        self.access.append(&mut next.access);
+        self.times = self.times.merge(next.times);
+    }
+
     /// Splits the memory area at the given position.
     ///
     /// The original memory area is shrunk to the left part, and the right part
-    /// is returned.
+    /// is returned. The leading guard region stays with the left part and the
+    /// trailing one goes to the right part.
     ///
     /// Returns `None` if the given position is not in the memory area, or one
-    /// of the parts is empty after splitting.
-    pub fn split(&mut self, pos: B::Addr) -> Option<Self> {
+    /// of the parts is empty after splitting. Fails if a frame larger than a
+    /// page crosses the position and cannot be
+    /// [split](MappingBackend::split_frame), leaving the area unchanged.
+    pub fn split(&mut self, pos: B::Addr) -> MappingResult<Option<Self>> {
         if self.start() < pos && pos < self.end() {
-            let new_area = Self::new(
+            #[cfg(feature = "RAII")]
+            self.frames.demote(pos)?;
+            let mut new_area = Self::new(
                 pos,
                 // Use wrapping_sub_addr to avoid overflow check. It is safe because
                 // `pos` is within the memory area.
                 self.end().wrapping_sub_addr(pos),
                 #[cfg(feature = "RAII")]
-                Some(self.frames.split_off(&pos)), // pages retained here
+                None,
                 self.flags,
                 self.backend.clone(),
             );
+            #[cfg(feature = "RAII")]
+            {
+                new_area.frames = self.frames.split_off(&pos); // pages retained here
+                new_area.swapped = self.swapped.split_off(&pos);
+                new_area.shared = self.shared.clone().map(|mut shared| {
+                    shared.offset += pos.sub_addr(self.start());
+                    shared
+                });
+            }
+            #[cfg(feature = "mmap")]
+            {
+                new_area.file = self.file.clone().map(|mut file| {
+                    file.offset += pos.sub_addr(self.start());
+                    file
+                });
+            }
+            new_area.inherit_attrs(self);
+            new_area.soft_dirty = self.soft_dirty.as_mut().map(|dirty| dirty.split_off(&pos));
+            #[cfg(feature = "mmap")]
+            {
+                new_area.file_dirty = self.file_dirty.as_mut().map(|dirty| dirty.split_off(&pos));
+            }
+            new_area.first_touch = self.first_touch.split_off(&pos);
+            #[cfg(feature = "access-count")]
+            {
+                new_area.access = self.access.split_off(pos);
+            }
+            new_area.guards = (0, self.guards.1);
+            self.guards.1 = 0;
             self.va_range.end = pos;
             // already retained
             //self.retain_pages_in_range();
-            Some(new_area)
+            Ok(Some(new_area))
         } else {
-            None
+            Ok(None)
         }
     }
 }
@@ -328,12 +1516,32 @@ impl<B: MappingBackend> MemoryArea<B> {
         vaddr: B::Addr,
         frame: B::FrameTrackerRef,
     ) -> Option<<B as MappingBackend>::FrameTrackerRef> {
-        debug_assert!(vaddr.is_aligned_4k());
+        debug_assert!(vaddr.is_aligned(self.frame_size()));
         self.frames.insert(vaddr, frame)
     }
 
+    /// Inserts a frame of `size` bytes mapping the pages from `vaddr`, e.g.,
+    /// a 2M or 1G huge page, and returns the old frame of the page at
+    /// `vaddr`.
+    ///
+    /// The frame is split with [`MappingBackend::split_frame`] if the area
+    /// is later split, shrunk or partially unmapped through it. The other
+    /// pages it covers must not have frames of their own.
+    pub fn insert_frame_sized(
+        &mut self,
+        vaddr: B::Addr,
+        frame: B::FrameTrackerRef,
+        size: usize,
+    ) -> Option<<B as MappingBackend>::FrameTrackerRef> {
+        debug_assert!(vaddr.is_aligned(self.frame_size()));
+        debug_assert!(
+            vaddr >= self.start() && vaddr.checked_add(size).is_some_and(|end| end <= self.end())
+        );
+        self.frames.insert_sized(vaddr, frame, size)
+    }
+
     pub fn find_frame(&self, vaddr: B::Addr) -> Option<B::FrameTrackerRef> {
-        debug_assert!(vaddr.is_aligned_4k());
+        debug_assert!(vaddr.is_aligned(self.frame_size()));
         self.frames.get(&vaddr).cloned()
     }
 
@@ -341,11 +1549,171 @@ impl<B: MappingBackend> MemoryArea<B> {
         self.frames.len()
     }
 
+    /// Clones the area with new flags and private copies of its frames, and
+    /// maps the clone in the given page table.
+    ///
+    /// A new frame is allocated for every private frame held by the area and
+    /// the contents are copied over, so writes to one area are never visible
+    /// in the other. The other attributes are kept as in
+    /// [`clone_shared`](Self::clone_shared). An area mapping a
+    /// [shared](Self::is_shared) object keeps sharing it, and pages of the
+    /// [zero frame](MappingBackend::zero_frame) keep it, read-only.
+    pub fn clone_copied(
+        &self,
+        flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult<Self> {
+        let mut area = self.clone_shared(flags);
+        if !self.is_shared() {
+            area.frames = self.frames.copied(|frame| !self.is_zero_frame(frame));
+        }
+        area.interleave = self.interleave.as_ref().map(InterleavePolicy::fork);
+        area.write_protected = false;
+        area.remap_area(page_table)?;
+        if area.soft_dirty.is_some() {
+            // Keep recording the writes to the clone.
+            area.write_protect(page_table)?;
+        } else {
+            for (&page, frame) in area.frames.iter() {
+                if area.is_zero_frame(frame) {
+                    area.backend_write_protect(page, area.frame_size(), flags, page_table)?;
+                }
+            }
+        }
+        Ok(area)
+    }
+
+    /// Breaks the copy-on-write sharing of the page containing `vaddr`.
+    ///
+    /// The frame of the page is replaced by a private copy, which is installed
+    /// by [`MappingBackend::map_frame`] with the area's flags, making the page
+    /// writable again. The copy is skipped if
+    /// [`MappingBackend::frame_ref_count`] reports that the frame is no longer
+    /// shared. Does nothing if the page has no frame yet; the fault
+    /// should then be handled as a demand fault. A frame larger than a page
+    /// is [demoted](crate::FrameMap::demote) first, so that only the page is
+    /// copied.
+    pub fn break_cow(&mut self, vaddr: B::Addr, page_table: &mut B::PageTable) -> MappingResult {
+        let page = vaddr.align_down(self.frame_size());
+        let Some((start, frame, _)) = self.frames.covering(page) else {
+            return Ok(());
+        };
+        // Shared memory is never copied, and for copy-on-write the other side
+        // may be gone: then the page is just made writable again.
+        let copy = if (self.is_shared() || B::frame_ref_count(frame) == Some(1))
+            && !self.is_zero_frame(frame)
+        {
+            None
+        } else {
+            Some(B::copy_frame(frame, page.sub_addr(start)))
+        };
+        self.frames
+            .demote_range(page, page.add(self.frame_size()))?;
+        let frame = match &copy {
+            Some(copy) => copy,
+            None => self.frames.get(&page).unwrap(),
+        };
+        self.backend_map_frame(page, frame, self.flags, page_table)?;
+        if let Some(copy) = copy {
+            self.frames.insert(page, copy);
+        }
+        Ok(())
+    }
+
+    /// Returns the number of resident pages whose frames are shared with
+    /// other areas, according to [`MappingBackend::frame_ref_count`].
+    ///
+    /// Every page of a shared frame larger than a page is counted.
+    pub fn shared_pages(&self) -> usize {
+        self.frames
+            .iter()
+            .filter(|(_, frame)| {
+                !self.is_zero_frame(frame)
+                    && B::frame_ref_count(frame).is_some_and(|count| count > 1)
+            })
+            .map(|(start, _)| self.frames.frame_size(start) / self.frame_size())
+            .sum()
+    }
+
+    /// Returns whether `frame` is the [zero frame](MappingBackend::zero_frame)
+    /// of the backend.
+    pub(crate) fn is_zero_frame(&self, frame: &B::FrameTrackerRef) -> bool {
+        self.backend
+            .zero_frame()
+            .is_some_and(|zero| zero.start() == frame.start())
+    }
+
+    /// Returns the number of pages mapping the
+    /// [zero frame](MappingBackend::zero_frame), which are not counted in
+    /// the RSS.
+    pub fn zero_pages(&self) -> usize {
+        let Some(zero) = self.backend.zero_frame() else {
+            return 0;
+        };
+        self.frames
+            .values()
+            .filter(|frame| frame.start() == zero.start())
+            .count()
+    }
+
+    /// Returns the size of a frame held by the area.
+    pub fn frame_size(&self) -> usize {
+        <B::FrameTrackerImpl as FrameTracker>::PAGE_SIZE
+    }
+
+    /// Returns the total size of the resident frames.
+    pub fn resident_size(&self) -> usize {
+        self.frames.total_size()
+    }
+
+    /// Returns an iterator over the maximal ranges backed by resident frames,
+    /// in ascending order.
+    ///
+    /// Absent regions are skipped without being visited, so this is cheap
+    /// even for huge, sparsely populated areas.
+    pub fn resident_ranges(&self) -> impl Iterator<Item = AddrRange<B::Addr>> + '_ {
+        self.resident_ranges_in(self.va_range)
+    }
+
+    /// Returns an iterator over the maximal ranges backed by resident frames
+    /// whose pages start within the given range.
+    pub fn resident_ranges_in(
+        &self,
+        range: AddrRange<B::Addr>,
+    ) -> impl Iterator<Item = AddrRange<B::Addr>> + '_ {
+        let frames = &self.frames;
+        let mut pages = frames
+            .range(range.start..range.end)
+            .map(|(&vaddr, _)| vaddr)
+            .peekable();
+        core::iter::from_fn(move || {
+            let start = pages.next()?;
+            let mut end = start.add(frames.frame_size(&start));
+            while let Some(vaddr) = pages.next_if(|&vaddr| vaddr == end) {
+                end = end.add(frames.frame_size(&vaddr));
+            }
+            Some(AddrRange::new(start, end))
+        })
+    }
+
+    /// Removes and returns the frames within `[start, start + size)`.
+    ///
+    /// The frames crossing its bounds must be [demoted](FrameMap::demote)
+    /// first.
+    pub(crate) fn take_frames(&mut self, start: B::Addr, size: usize) -> FrameMap<B> {
+        let mut taken = self.frames.split_off(&start);
+        let mut tail = taken.split_off(&start.add(size));
+        self.frames.append(&mut tail);
+        taken
+    }
+
     /// Retains only the pages in [self.va_range].
-    /// called manually when the va_range is changed.
+    /// called manually when the va_range is changed, after the frames
+    /// crossing its bounds were [demoted](FrameMap::demote).
     fn retain_frames_in_range(&mut self) {
         let range = self.va_range();
         self.frames.retain(|&frame, _| range.contains(frame));
+        self.swapped.retain(|&page, _| range.contains(page));
     }
 }
 
@@ -360,9 +1728,33 @@ impl<B: MappingBackend> MemoryArea<B> {
     ) -> Self {
         Self {
             va_range: AddrRange::from_start_size(start, size),
-            frames: frame_alloced.unwrap_or(BTreeMap::new()),
+            frames: frame_alloced.map(FrameMap::from).unwrap_or_default(),
+            #[cfg(feature = "RAII")]
+            swapped: BTreeMap::new(),
+            #[cfg(feature = "RAII")]
+            shared: None,
+            #[cfg(feature = "mmap")]
+            file: None,
             flags,
             backend,
+            interleave: None,
+            map_page_size: None,
+            thp_advice: None,
+            write_protected: false,
+            soft_dirty: None,
+            #[cfg(feature = "mmap")]
+            file_dirty: None,
+            first_touch: BTreeMap::new(),
+            #[cfg(feature = "access-count")]
+            access: AccessCounts::new(),
+            locked: false,
+            sealed: false,
+            confidentiality: Confidentiality::Private,
+            reserved: false,
+            hole: false,
+            label: None,
+            guards: (0, 0),
+            times: AreaTimes::default(),
         }
     }
 }
@@ -372,10 +1764,45 @@ where
     B::Addr: fmt::Debug,
     B::Flags: fmt::Debug + Copy,
 {
+    /// Terse by default, and with the state of the backend, the frames and
+    /// the attributes in the alternate form (`{:#?}`).
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("MemoryArea")
-            .field("va_range", &self.va_range)
+        self.debug_struct(f).finish()
+    }
+}
+
+impl<B: MappingBackend> MemoryArea<B>
+where
+    B::Addr: fmt::Debug,
+    B::Flags: fmt::Debug + Copy,
+{
+    /// Starts the [`Debug`](fmt::Debug) output of the area, for the set to
+    /// add fields of its own.
+    pub(crate) fn debug_struct<'a, 'b>(
+        &self,
+        f: &'a mut fmt::Formatter<'b>,
+    ) -> fmt::DebugStruct<'a, 'b> {
+        let alternate = f.alternate();
+        let mut s = f.debug_struct("MemoryArea");
+        s.field("va_range", &self.va_range)
             .field("flags", &self.flags)
-            .finish()
+            .field("label", &self.label)
+            .field("guards", &self.guards);
+        if !alternate {
+            return s;
+        }
+        s.field("backend", &self.backend.kind())
+            .field("mapping_kind", &self.backend.mapping_kind());
+        #[cfg(feature = "RAII")]
+        s.field("frames", &self.frames.len())
+            .field("zero_pages", &self.zero_pages())
+            .field("shared_pages", &self.shared_pages())
+            .field("swapped", &self.swapped.len())
+            .field("resident", &self.resident_ranges().collect::<Vec<_>>());
+        s.field("write_protected", &self.write_protected)
+            .field("locked", &self.locked)
+            .field("sealed", &self.sealed)
+            .field("reserved", &self.reserved);
+        s
     }
 }
@@ -0,0 +1,332 @@
+//! An ordered map of memory areas with links between neighbors.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::ops::{Index, RangeBounds};
+
+const NIL: usize = usize::MAX;
+
+struct Node<K, V> {
+    key: K,
+    value: V,
+    prev: usize,
+    next: usize,
+}
+
+/// The areas of a set, keyed by their start addresses.
+///
+/// The values live in an arena whose nodes are linked to their neighbors in
+/// key order, with a [`BTreeMap`] from the keys to the nodes for lookups.
+/// Once an entry is found, its previous and next entries are reached in
+/// `O(1)` through the links, instead of another tree descent. The arena never
+/// moves a node, unlike the [`BTreeMap`] with its values, so the links stay
+/// valid until the nodes are removed.
+pub(crate) struct AreaMap<K, V> {
+    index: BTreeMap<K, usize>,
+    nodes: Vec<Option<Node<K, V>>>,
+    free: Vec<usize>,
+    head: usize,
+    tail: usize,
+}
+
+impl<K: Ord + Copy, V> AreaMap<K, V> {
+    pub const fn new() -> Self {
+        Self {
+            index: BTreeMap::new(),
+            nodes: Vec::new(),
+            free: Vec::new(),
+            head: NIL,
+            tail: NIL,
+        }
+    }
+
+    fn node(&self, node: usize) -> &Node<K, V> {
+        self.nodes[node].as_ref().unwrap()
+    }
+
+    fn node_mut(&mut self, node: usize) -> &mut Node<K, V> {
+        self.nodes[node].as_mut().unwrap()
+    }
+
+    fn value_at(&self, node: usize) -> Option<&V> {
+        (node != NIL).then(|| &self.node(node).value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.index.get(key).map(|&node| &self.node(node).value)
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let node = *self.index.get(key)?;
+        Some(&mut self.node_mut(node).value)
+    }
+
+    /// Inserts `value` at `key`, returning the value it replaces.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(&node) = self.index.get(&key) {
+            return Some(core::mem::replace(&mut self.node_mut(node).value, value));
+        }
+        let prev = self
+            .index
+            .range(..key)
+            .next_back()
+            .map_or(NIL, |(_, &node)| node);
+        let next = if prev == NIL {
+            self.head
+        } else {
+            self.node(prev).next
+        };
+        let node = Some(Node {
+            key,
+            value,
+            prev,
+            next,
+        });
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        match prev {
+            NIL => self.head = index,
+            prev => self.node_mut(prev).next = index,
+        }
+        match next {
+            NIL => self.tail = index,
+            next => self.node_mut(next).prev = index,
+        }
+        self.index.insert(key, index);
+        None
+    }
+
+    /// Inserts `value` at `key` if it is vacant, and returns the value there.
+    #[cfg(feature = "RAII")]
+    pub fn get_or_insert(&mut self, key: K, value: V) -> &mut V {
+        if !self.index.contains_key(&key) {
+            self.insert(key, value);
+        }
+        self.get_mut(&key).unwrap()
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let index = self.index.remove(key)?;
+        let node = self.nodes[index].take().unwrap();
+        match node.prev {
+            NIL => self.head = node.next,
+            prev => self.node_mut(prev).next = node.next,
+        }
+        match node.next {
+            NIL => self.tail = node.prev,
+            next => self.node_mut(next).prev = node.prev,
+        }
+        self.free.push(index);
+        Some(node.value)
+    }
+
+    pub fn first_key_value(&self) -> Option<(&K, &V)> {
+        (self.head != NIL).then(|| {
+            let node = self.node(self.head);
+            (&node.key, &node.value)
+        })
+    }
+
+    pub fn last_key_value(&self) -> Option<(&K, &V)> {
+        (self.tail != NIL).then(|| {
+            let node = self.node(self.tail);
+            (&node.key, &node.value)
+        })
+    }
+
+    /// Returns the value with the highest key not above `key`, along with
+    /// the values right before and after it.
+    ///
+    /// If every key is above `key`, returns `(None, None, first)`.
+    pub fn floor_with_neighbors(&self, key: &K) -> (Option<&V>, Option<&V>, Option<&V>) {
+        match self.index.range(..=key).next_back() {
+            Some((_, &floor)) => {
+                let node = self.node(floor);
+                (
+                    self.value_at(node.prev),
+                    Some(&node.value),
+                    self.value_at(node.next),
+                )
+            }
+            None => (None, None, self.value_at(self.head)),
+        }
+    }
+
+    /// Returns whether walking the links in both directions visits the
+    /// entries in key order.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn links_consistent(&self) -> bool {
+        let mut prev = NIL;
+        let mut node = self.head;
+        for &indexed in self.index.values() {
+            if node != indexed || self.node(node).prev != prev {
+                return false;
+            }
+            prev = node;
+            node = self.node(node).next;
+        }
+        node == NIL && self.tail == prev
+    }
+
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = &K> {
+        self.index.keys()
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&K, &V)> {
+        self.range(..)
+    }
+
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    pub fn values_mut(&mut self) -> impl DoubleEndedIterator<Item = &mut V> {
+        self.range_mut(..).map(|(_, value)| value)
+    }
+
+    pub fn range(&self, range: impl RangeBounds<K>) -> impl DoubleEndedIterator<Item = (&K, &V)> {
+        self.index
+            .range(range)
+            .map(|(key, &node)| (key, &self.node(node).value))
+    }
+
+    pub fn range_mut(&mut self, range: impl RangeBounds<K>) -> RangeMut<'_, K, V> {
+        RangeMut {
+            index: self.index.range(range),
+            nodes: self.nodes.as_mut_ptr(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Splits the map at `key`, returning the entries at or after it.
+    pub fn split_off(&mut self, key: &K) -> Self {
+        let keys: Vec<_> = self.index.range(key..).map(|(&key, _)| key).collect();
+        let mut other = Self::new();
+        for key in keys {
+            let value = self.remove(&key).unwrap();
+            other.insert(key, value);
+        }
+        other
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        let removed: Vec<_> = self
+            .range_mut(..)
+            .filter_map(|(&key, value)| (!f(&key, value)).then_some(key))
+            .collect();
+        for key in removed {
+            self.remove(&key);
+        }
+    }
+
+    pub fn into_values(self) -> impl Iterator<Item = V> {
+        self.into_iter().map(|(_, value)| value)
+    }
+}
+
+impl<K: Ord + Copy, V> IntoIterator for AreaMap<K, V> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    fn into_iter(self) -> IntoIter<K, V> {
+        IntoIter {
+            index: self.index.into_iter(),
+            nodes: self.nodes,
+        }
+    }
+}
+
+impl<K: Ord + Copy, V> Default for AreaMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Copy, V> Extend<(K, V)> for AreaMap<K, V> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl<K: Ord + Copy, V> Index<&K> for AreaMap<K, V> {
+    type Output = V;
+
+    fn index(&self, key: &K) -> &V {
+        self.get(key).expect("no entry found for key")
+    }
+}
+
+/// A mutable iterator over a range of an [`AreaMap`], in key order.
+pub(crate) struct RangeMut<'a, K, V> {
+    index: alloc::collections::btree_map::Range<'a, K, usize>,
+    nodes: *mut Option<Node<K, V>>,
+    _marker: PhantomData<&'a mut V>,
+}
+
+impl<'a, K, V> RangeMut<'a, K, V> {
+    fn value(&mut self, node: usize) -> &'a mut V {
+        // SAFETY: the map is mutably borrowed for `'a`, and the index maps
+        // every key to a distinct occupied node, so each node is yielded at
+        // most once and the references never alias.
+        let node = unsafe { (*self.nodes.add(node)).as_mut().unwrap() };
+        &mut node.value
+    }
+}
+
+impl<'a, K, V> Iterator for RangeMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, &node) = self.index.next()?;
+        Some((key, self.value(node)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.index.size_hint()
+    }
+}
+
+impl<K, V> DoubleEndedIterator for RangeMut<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (key, &node) = self.index.next_back()?;
+        Some((key, self.value(node)))
+    }
+}
+
+/// An owning iterator over an [`AreaMap`], in key order.
+pub(crate) struct IntoIter<K, V> {
+    index: alloc::collections::btree_map::IntoIter<K, usize>,
+    nodes: Vec<Option<Node<K, V>>>,
+}
+
+impl<K, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        let (key, node) = self.index.next()?;
+        Some((key, self.nodes[node].take().unwrap().value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.index.size_hint()
+    }
+}
@@ -1,8 +1,63 @@
+#[cfg(feature = "RAII")]
 use alloc::collections::BTreeMap;
 use alloc::string::ToString;
+#[cfg(feature = "RAII")]
+use alloc::vec::Vec;
+#[cfg(feature = "RAII")]
 use core::ops::Deref;
+use core::task::{Context, Poll};
 
-use memory_addr::MemoryAddr;
+use memory_addr::{AddrRange, MemoryAddr, PAGE_SIZE_4K, PhysAddr};
+
+/// Advice about the use of a memory range, like the `advice` of `madvise`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advice {
+    /// The range will be accessed soon (`MADV_WILLNEED`).
+    WillNeed,
+    /// The range will not be accessed soon (`MADV_DONTNEED`). Its frames are
+    /// dropped, so the next access faults in fresh pages, i.e., zero pages
+    /// for anonymous memory.
+    DontNeed,
+    /// The contents of the range are no longer needed (`MADV_FREE`), so its
+    /// frames can be reclaimed.
+    Free,
+    /// The range should be backed by huge pages (`MADV_HUGEPAGE`), see
+    /// [`ThpPolicy::Madvise`](crate::ThpPolicy::Madvise).
+    HugePage,
+    /// The range must not be backed by huge pages (`MADV_NOHUGEPAGE`).
+    NoHugePage,
+}
+
+/// Whether the memory of an area is private to a confidential VM (e.g.,
+/// with SEV or TDX) or shared with the host, see
+/// [`MemorySet::convert_private_to_shared`].
+///
+/// [`MemorySet::convert_private_to_shared`]: crate::MemorySet::convert_private_to_shared
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Confidentiality {
+    /// Encrypted memory, only accessible to the guest. This is the default.
+    #[default]
+    Private,
+    /// Memory accessible to the host too, e.g., for I/O buffers.
+    Shared,
+}
+
+/// The broad class of memory behind a mapping, returned by
+/// [`MappingBackend::mapping_kind`].
+///
+/// Unlike the free-form [`MappingBackend::kind`], it lets generic code treat
+/// the classes differently without knowing the concrete backends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingKind {
+    /// Anonymous memory, whose pages start zeroed.
+    Anonymous,
+    /// Memory backed by a file or another object with contents of its own.
+    File,
+    /// Device memory (MMIO), which must not be discarded or swapped.
+    Device,
+    /// Anything else, or unknown.
+    Other,
+}
 
 /// Underlying operations to do when manipulating mappings within the specific
 /// [`MemoryArea`](crate::MemoryArea).
@@ -18,11 +73,31 @@ pub trait MappingBackend: Clone {
     type Flags: Copy + ToString;
     /// The page table type used in the memory area.
     type PageTable;
+    /// The error of [`map`](Self::map), [`unmap`](Self::unmap) and
+    /// [`protect`](Self::protect), carried by
+    /// [`MappingError::BadState`](crate::MappingError::BadState).
+    type Error: core::error::Error + Send + Sync + 'static;
+
+    /// The size of the base pages of the target, in bytes.
+    ///
+    /// Defaults to 4K, override it for e.g. ARM64 kernels built with 16K or
+    /// 64K pages. It must be a power of two. With RAII, it should be the
+    /// `PAGE_SIZE` of [`FrameTrackerImpl`](Self::FrameTrackerImpl).
+    const BASE_PAGE_SIZE: usize = PAGE_SIZE_4K;
+
+    /// The minimum mapping granularity of the backend, in bytes.
+    ///
+    /// Areas managed by this backend must start and end at multiples of it.
+    /// Defaults to [`BASE_PAGE_SIZE`](Self::BASE_PAGE_SIZE), override it for
+    /// e.g. IOMMUs that only map 64K chunks. It must be a power of two.
+    const MIN_GRANULARITY: usize = Self::BASE_PAGE_SIZE;
 
     #[cfg(feature = "RAII")]
     type FrameTrackerImpl: memory_addr::FrameTracker;
     #[cfg(feature = "RAII")]
-    type FrameTrackerRef: Deref<Target = Self::FrameTrackerImpl> + Clone;
+    type FrameTrackerRef: Deref<Target = Self::FrameTrackerImpl>
+        + Clone
+        + From<Self::FrameTrackerImpl>;
 
     #[cfg(feature = "RAII")]
     /// What to do when mapping a region within the area with the given flags.
@@ -32,7 +107,7 @@ pub trait MappingBackend: Clone {
         size: usize,
         flags: Self::Flags,
         page_table: &mut Self::PageTable,
-    ) -> Result<BTreeMap<Self::Addr, Self::FrameTrackerRef>, ()>;
+    ) -> Result<BTreeMap<Self::Addr, Self::FrameTrackerRef>, Self::Error>;
 
     #[cfg(not(feature = "RAII"))]
     /// What to do when mapping a region within the area with the given flags.
@@ -42,11 +117,56 @@ pub trait MappingBackend: Clone {
         size: usize,
         flags: Self::Flags,
         page_table: &mut Self::PageTable,
-    ) -> Result<(), ()>;
+    ) -> Result<(), Self::Error>;
+
+    #[cfg(feature = "RAII")]
+    /// What to do when mapping a region within an area that declares a page
+    /// size with [`MemoryArea::with_map_page_size`], e.g., installing block
+    /// mappings of 2M or 1G.
+    ///
+    /// The region is aligned to `page_size`. Defaults to [`map`](Self::map),
+    /// ignoring the page size.
+    ///
+    /// [`MemoryArea::with_map_page_size`]: crate::MemoryArea::with_map_page_size
+    fn map_with_page_size(
+        &self,
+        start: Self::Addr,
+        size: usize,
+        flags: Self::Flags,
+        _page_size: usize,
+        page_table: &mut Self::PageTable,
+    ) -> Result<BTreeMap<Self::Addr, Self::FrameTrackerRef>, Self::Error> {
+        self.map(start, size, flags, page_table)
+    }
+
+    #[cfg(not(feature = "RAII"))]
+    /// What to do when mapping a region within an area that declares a page
+    /// size with [`MemoryArea::with_map_page_size`], e.g., installing block
+    /// mappings of 2M or 1G.
+    ///
+    /// The region is aligned to `page_size`. Defaults to [`map`](Self::map),
+    /// ignoring the page size.
+    ///
+    /// [`MemoryArea::with_map_page_size`]: crate::MemoryArea::with_map_page_size
+    fn map_with_page_size(
+        &self,
+        start: Self::Addr,
+        size: usize,
+        flags: Self::Flags,
+        _page_size: usize,
+        page_table: &mut Self::PageTable,
+    ) -> Result<(), Self::Error> {
+        self.map(start, size, flags, page_table)
+    }
 
     /// What to do when unmaping a memory region within the area.
     /// Should not deallocate frames if RAII is on.
-    fn unmap(&self, start: Self::Addr, size: usize, page_table: &mut Self::PageTable) -> bool;
+    fn unmap(
+        &self,
+        start: Self::Addr,
+        size: usize,
+        page_table: &mut Self::PageTable,
+    ) -> Result<(), Self::Error>;
 
     /// What to do when changing access flags.
     fn protect(
@@ -55,5 +175,392 @@ pub trait MappingBackend: Clone {
         size: usize,
         new_flags: Self::Flags,
         page_table: &mut Self::PageTable,
-    ) -> bool;
+    ) -> Result<(), Self::Error>;
+
+    #[cfg(feature = "RAII")]
+    /// What to do when installing a single frame at the page `vaddr` with the
+    /// given flags, replacing the existing page table entry if any.
+    ///
+    /// Used when the frame of a page is replaced, e.g. when breaking
+    /// copy-on-write sharing. Returns `Ok(false)` if the backend does not
+    /// support it, which is the default.
+    fn map_frame(
+        &self,
+        _vaddr: Self::Addr,
+        _frame: &Self::FrameTrackerImpl,
+        _flags: Self::Flags,
+        _page_table: &mut Self::PageTable,
+    ) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    #[cfg(feature = "RAII")]
+    /// Returns the shared zero frame, e.g., a global frame filled with zeros
+    /// once at boot, or `None` (the default) to not share zero frames.
+    ///
+    /// If it is provided, read faults on untouched pages of
+    /// [anonymous](MappingKind::Anonymous) areas map it read-only with
+    /// [`map_frame`](Self::map_frame) and
+    /// [`write_protect`](Self::write_protect), which must then be supported,
+    /// instead of allocating a frame. The first write to such a page copies
+    /// it into a private frame, like copy-on-write.
+    fn zero_frame(&self) -> Option<Self::FrameTrackerRef> {
+        None
+    }
+
+    #[cfg(feature = "RAII")]
+    /// Returns the number of references to the frame, e.g.
+    /// `Arc::strong_count`, or `None` if it is unknown (the default).
+    ///
+    /// Used to account shared pages and to avoid needless copies when
+    /// breaking copy-on-write sharing.
+    fn frame_ref_count(_frame: &Self::FrameTrackerRef) -> Option<usize> {
+        None
+    }
+
+    #[cfg(feature = "RAII")]
+    /// Returns a new frame of one page with the same contents as the page at
+    /// `offset` bytes into `frame`, e.g., copied with a DMA engine instead of
+    /// the CPU.
+    ///
+    /// Used whenever the contents of a frame are duplicated: when breaking
+    /// copy-on-write sharing, for private file pages, and by
+    /// [`MemorySet::clone_into`](crate::MemorySet::clone_into). Frames larger
+    /// than a page, inserted with
+    /// [`MemoryArea::insert_frame_sized`](crate::MemoryArea::insert_frame_sized),
+    /// are copied page by page, so `offset` is a multiple of the page size
+    /// within the frame. By default, a frame is allocated with
+    /// [`FrameTracker::alloc_frame`](memory_addr::FrameTracker::alloc_frame)
+    /// and the bytes are copied over.
+    fn copy_frame(frame: &Self::FrameTrackerRef, offset: usize) -> Self::FrameTrackerRef {
+        use memory_addr::FrameTracker;
+        let mut copy = Self::FrameTrackerImpl::alloc_frame();
+        let size = <Self::FrameTrackerImpl as FrameTracker>::PAGE_SIZE;
+        // SAFETY: The page at `offset` is within the frame, which is accessed
+        // through its physical address like by `FrameTracker::as_slice`.
+        let page = unsafe { core::slice::from_raw_parts(frame.as_ptr().add(offset), size) };
+        copy.as_mut_slice().copy_from_slice(page);
+        copy.into()
+    }
+
+    #[cfg(feature = "RAII")]
+    /// Splits `frame`, a frame of `size` bytes inserted with
+    /// [`MemoryArea::insert_frame_sized`](crate::MemoryArea::insert_frame_sized),
+    /// into the frames of its pages in ascending order, e.g., by splitting
+    /// the allocation of a huge page. The pages keep their contents.
+    ///
+    /// Called when an area is split, shrunk or partially unmapped through
+    /// the frame, which then fails if the frame cannot be split. Returns
+    /// `Err(None)` if the backend does not support it, which is the default,
+    /// or the error of the backend if splitting failed.
+    fn split_frame(
+        _frame: &Self::FrameTrackerRef,
+        _size: usize,
+    ) -> Result<Vec<Self::FrameTrackerRef>, Option<Self::Error>> {
+        Err(None)
+    }
+
+    /// Returns whether an access described by `access_flags` is allowed in an
+    /// area with `area_flags`.
+    ///
+    /// Used by [`MemorySet::handle_page_fault`](crate::MemorySet::handle_page_fault).
+    /// Allows everything by default, leaving the check to
+    /// [`handle_fault`](Self::handle_fault).
+    fn check_access(&self, _area_flags: Self::Flags, _access_flags: Self::Flags) -> bool {
+        true
+    }
+
+    /// Returns whether an access described by `access_flags` is a write.
+    ///
+    /// Write faults on write-protected resident pages are resolved by the
+    /// memory set itself (copy-on-write, soft-dirty tracking). Returns `false`
+    /// by default.
+    fn is_write_access(&self, _access_flags: Self::Flags) -> bool {
+        false
+    }
+
+    #[cfg(feature = "RAII")]
+    /// What to do when a page fault happens at the page `vaddr` that the
+    /// memory set cannot resolve itself, e.g. for demand paging.
+    ///
+    /// Returns the newly allocated frame if any, which is then tracked by the
+    /// area. Returns `Err(None)` if the fault cannot be resolved, which is the
+    /// default, or the error of the backend if resolving it failed.
+    fn handle_fault(
+        &self,
+        _vaddr: Self::Addr,
+        _access_flags: Self::Flags,
+        _area_flags: Self::Flags,
+        _page_table: &mut Self::PageTable,
+    ) -> Result<Option<Self::FrameTrackerRef>, Option<Self::Error>> {
+        Err(None)
+    }
+
+    #[cfg(not(feature = "RAII"))]
+    /// What to do when a page fault happens at the page `vaddr` that the
+    /// memory set cannot resolve itself, e.g. for demand paging.
+    ///
+    /// Returns `false` if the fault cannot be resolved, which is the default.
+    fn handle_fault(
+        &self,
+        _vaddr: Self::Addr,
+        _access_flags: Self::Flags,
+        _area_flags: Self::Flags,
+        _page_table: &mut Self::PageTable,
+    ) -> bool {
+        false
+    }
+
+    /// What to do when write-protecting a region, i.e. making the page table
+    /// entries read-only while keeping the other permissions in `flags`.
+    ///
+    /// Returns `Ok(false)` if the backend does not support it, which is the
+    /// default.
+    fn write_protect(
+        &self,
+        _start: Self::Addr,
+        _size: usize,
+        _flags: Self::Flags,
+        _page_table: &mut Self::PageTable,
+    ) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    /// Returns `flags` with the pages marked as private or shared memory of
+    /// a confidential VM, e.g., with the encryption bit (SEV) or the shared
+    /// bit (TDX) set accordingly.
+    ///
+    /// Areas converted by [`MemorySet::convert_private_to_shared`] or back
+    /// take the returned flags, so that the pages faulted in afterwards are
+    /// marked right. Returns `flags` unchanged by default.
+    ///
+    /// [`MemorySet::convert_private_to_shared`]: crate::MemorySet::convert_private_to_shared
+    fn confidential_flags(&self, flags: Self::Flags, _to: Confidentiality) -> Self::Flags {
+        flags
+    }
+
+    /// What to do when converting a region between private and shared
+    /// memory of a confidential VM, e.g., removing and flushing the
+    /// translations, asking the hypervisor to convert the pages (page state
+    /// change or `MapGPA`), accepting them again if they become private, and
+    /// mapping the resident ones again with `flags`, which already have the
+    /// new marking.
+    ///
+    /// The contents of the pages need not be kept. Returns `false` if the
+    /// conversion failed or the backend does not support it, which is the
+    /// default.
+    fn convert_confidentiality(
+        &self,
+        _start: Self::Addr,
+        _size: usize,
+        _flags: Self::Flags,
+        _to: Confidentiality,
+        _page_table: &mut Self::PageTable,
+    ) -> bool {
+        false
+    }
+
+    /// What to do on an [`Advice`] about a region, e.g., prefetching the
+    /// backing data for [`Advice::WillNeed`].
+    ///
+    /// Returns `Ok(false)` to leave it to the default handling of
+    /// [`MemoryArea::advise`](crate::MemoryArea::advise), which is the default.
+    fn advise(
+        &self,
+        _start: Self::Addr,
+        _size: usize,
+        _advice: Advice,
+        _flags: Self::Flags,
+        _page_table: &mut Self::PageTable,
+    ) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    /// What to do when collapsing the pages of `[start, start + size)` into
+    /// one huge page of `size` bytes, e.g., by copying them into a huge frame
+    /// or by mapping physically contiguous frames with one huge entry.
+    ///
+    /// Called by [`MemorySet::promote_huge_pages`](crate::MemorySet::promote_huge_pages)
+    /// with a `start` aligned to `size`. Frames held by the area (if RAII is
+    /// on) are kept as they are. Once collapsed, [`translate`](Self::translate)
+    /// should report the huge page size for the region. Returns `false` if
+    /// the backend does not support it (the default) or the region cannot be
+    /// collapsed.
+    fn collapse_huge(
+        &self,
+        _start: Self::Addr,
+        _size: usize,
+        _flags: Self::Flags,
+        _page_table: &mut Self::PageTable,
+    ) -> bool {
+        false
+    }
+
+    /// What to do when moving the mappings of a region to another address.
+    ///
+    /// The page table entries of `[old_start, old_start + size)` should be
+    /// moved to `[new_start, new_start + size)` with the given flags, leaving
+    /// the old region unmapped. Should not deallocate frames if RAII is on.
+    ///
+    /// Returns `Ok(false)` if the backend does not support moving mappings,
+    /// which is the default.
+    fn move_mappings(
+        &self,
+        _old_start: Self::Addr,
+        _new_start: Self::Addr,
+        _size: usize,
+        _flags: Self::Flags,
+        _page_table: &mut Self::PageTable,
+    ) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    /// Starts a batch of changes to `page_table`, e.g., by
+    /// [`MemorySet::unmap_ranges`](crate::MemorySet::unmap_ranges) or a
+    /// [`TlbBatch`](crate::TlbBatch).
+    ///
+    /// Until [`end_batch`](Self::end_batch), the backends may skip the TLB
+    /// invalidations of [`map`](Self::map), [`unmap`](Self::unmap) and
+    /// [`protect`](Self::protect): the changed ranges are passed to
+    /// [`flush_tlb`](Self::flush_tlb) at the end. Does nothing by default.
+    fn begin_batch(_page_table: &mut Self::PageTable) {}
+
+    /// Invalidates the TLB entries of `ranges`, which are sorted and
+    /// disjoint, at the end of a batch. Does nothing by default.
+    fn flush_tlb(_page_table: &mut Self::PageTable, _ranges: &[AddrRange<Self::Addr>]) {}
+
+    /// Ends a batch started by [`begin_batch`](Self::begin_batch), after
+    /// [`flush_tlb`](Self::flush_tlb). Does nothing by default.
+    fn end_batch(_page_table: &mut Self::PageTable) {}
+
+    /// Updates the backend state after its area is moved from `old_start` to
+    /// `new_start`, e.g., by [`MemorySet::remap`], so that state keyed by
+    /// virtual address (like file offsets or a linear offset) stays with the
+    /// moved contents. Does nothing by default.
+    ///
+    /// [`MemorySet::remap`]: crate::MemorySet::remap
+    fn relocate(&mut self, _old_start: Self::Addr, _new_start: Self::Addr) {}
+
+    /// Translates a virtual address within the area to the physical address
+    /// of the page containing it, and the size of that page.
+    ///
+    /// Used to refill software-managed TLBs without a hardware page table.
+    /// Frames tracked by the area are looked up first (if RAII is on), so
+    /// this only needs to handle the other cases, e.g. linear mappings.
+    /// Returns `None` by default, meaning the address has no translation and
+    /// a page fault should be raised.
+    fn translate(&self, _vaddr: Self::Addr) -> Option<(PhysAddr, usize)> {
+        None
+    }
+
+    /// Returns the NUMA node of the physical memory at `paddr`.
+    ///
+    /// Used to record where the pages of an area were allocated when they are
+    /// first touched. Returns `None` by default, meaning the node is unknown.
+    fn numa_node(&self, _paddr: PhysAddr) -> Option<usize> {
+        None
+    }
+
+    /// Chooses the frame source (e.g., the NUMA node or memory tier) to
+    /// allocate the frame of the next [`handle_fault`](Self::handle_fault)
+    /// from.
+    ///
+    /// Called right before the fault is delegated to the backend, for the
+    /// areas with an [`InterleavePolicy`](crate::InterleavePolicy) only, with
+    /// the source picked by the policy. Does nothing by default.
+    fn select_source(&self, _source: usize) {}
+
+    /// Tests and clears the accessed bit of the page at `vaddr`, i.e.,
+    /// returns whether the page was accessed since the previous call.
+    ///
+    /// Used by [`StatsSampler`](crate::StatsSampler) to tell the working set
+    /// from the idle pages. Returns `None` by default, meaning it is unknown.
+    fn test_and_clear_accessed(
+        &self,
+        _vaddr: Self::Addr,
+        _page_table: &mut Self::PageTable,
+    ) -> Option<bool> {
+        None
+    }
+
+    /// Polls whether a fault at `vaddr` can be resolved without blocking,
+    /// for [`MemorySet::handle_page_fault_async`].
+    ///
+    /// A backend fetching pages slowly, e.g., from a remote node, starts the
+    /// fetch on the first poll and returns [`Poll::Pending`] until it is
+    /// done, waking the waker of `cx` then, so that
+    /// [`handle_fault`](Self::handle_fault) finds the page ready. Returns
+    /// [`Poll::Ready`] by default.
+    ///
+    /// [`MemorySet::handle_page_fault_async`]: crate::MemorySet::handle_page_fault_async
+    fn poll_fault_ready(
+        &self,
+        _vaddr: Self::Addr,
+        _access_flags: Self::Flags,
+        _cx: &mut Context<'_>,
+    ) -> Poll<()> {
+        Poll::Ready(())
+    }
+
+    /// Returns whether the frame allocator is under memory pressure, in which
+    /// case opportunistic operations like
+    /// [`MemorySet::populate`](crate::MemorySet::populate) stop early.
+    ///
+    /// Returns `false` by default.
+    fn memory_pressure(&self) -> bool {
+        false
+    }
+
+    /// Returns whether areas with this backend and `flags` are charged to the
+    /// commit charge, i.e., whether they are private writable memory that
+    /// must be backed by RAM (or swap) once touched, like `VM_ACCOUNT`.
+    ///
+    /// See [`MemorySet::commit_charge`](crate::MemorySet::commit_charge).
+    /// Returns `false` by default.
+    fn charges_commit(&self, _flags: Self::Flags) -> bool {
+        false
+    }
+
+    /// Returns whether an area with this backend and `flags` can be merged
+    /// with the area following it, which has `next` as backend and
+    /// `next_flags`, i.e., whether the backends are equivalent and the flags
+    /// are the same.
+    ///
+    /// Returns `false` by default, so areas are never merged.
+    fn can_merge(&self, _flags: Self::Flags, _next: &Self, _next_flags: Self::Flags) -> bool {
+        false
+    }
+
+    /// Returns a short name of the kind of mapping, e.g., `"linear"` or
+    /// `"lazy"`, shown in diagnostics like [`MemorySet::layout_json`].
+    ///
+    /// [`MemorySet::layout_json`]: crate::MemorySet::layout_json
+    fn kind(&self) -> &'static str {
+        "unknown"
+    }
+
+    /// Returns the class of memory behind the mapping, see [`MappingKind`].
+    ///
+    /// Returns [`MappingKind::Other`] by default.
+    fn mapping_kind(&self) -> MappingKind {
+        MappingKind::Other
+    }
+
+    /// Returns the offset of `vaddr` in the object backing the mapping (e.g.,
+    /// a file), shown by [`MemorySet::dump_maps`]. Anonymous mappings keep
+    /// the default of 0.
+    ///
+    /// [`MemorySet::dump_maps`]: crate::MemorySet::dump_maps
+    fn file_offset(&self, _vaddr: Self::Addr) -> usize {
+        0
+    }
+
+    /// Returns the mapping granularity of this backend instance.
+    ///
+    /// Defaults to [`MIN_GRANULARITY`](Self::MIN_GRANULARITY). Backends that
+    /// are enums over several mapping kinds may override it to report a
+    /// per-instance value.
+    fn granularity(&self) -> usize {
+        Self::MIN_GRANULARITY
+    }
 }
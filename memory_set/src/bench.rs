@@ -0,0 +1,241 @@
+//! Trace-driven microbenchmarks for [`MemorySet`] algorithms.
+//!
+//! A trace is a [journal](crate::journal) recorded from a real workload,
+//! e.g., saved with [`format_journal`](crate::journal::format_journal) and
+//! read back with [`parse_journal`](crate::journal::parse_journal).
+//! [`replay`] drives it against a [`MemorySet`] backed by [`NullBackend`], so
+//! only the set algorithms are measured, and collects per-operation timing
+//! distributions with a caller-supplied clock.
+
+use core::fmt;
+
+use memory_addr::MemoryAddr;
+
+use crate::journal::JournalEntry;
+use crate::{MappingBackend, MemoryArea, MemorySet};
+
+/// A backend that does nothing, isolating the cost of the set algorithms.
+#[derive(Clone)]
+pub struct NullBackend;
+
+#[cfg(feature = "RAII")]
+/// The frame tracker of [`NullBackend`]. It is never allocated.
+pub struct NullFrame(memory_addr::PhysAddr);
+
+#[cfg(feature = "RAII")]
+impl memory_addr::FrameTracker for NullFrame {
+    const PAGE_SIZE: usize = memory_addr::PAGE_SIZE_4K;
+
+    fn new(pa: memory_addr::PhysAddr) -> Self {
+        Self(pa)
+    }
+
+    fn no_tracking(pa: memory_addr::PhysAddr) -> Self {
+        Self(pa)
+    }
+
+    fn alloc_frame() -> Self {
+        Self(0.into())
+    }
+
+    fn dealloc_frame(&mut self) {}
+
+    fn start(&self) -> memory_addr::PhysAddr {
+        self.0
+    }
+}
+
+impl MappingBackend for NullBackend {
+    type Addr = usize;
+    type Flags = u64;
+    type PageTable = ();
+    type Error = core::convert::Infallible;
+
+    #[cfg(feature = "RAII")]
+    type FrameTrackerImpl = NullFrame;
+    #[cfg(feature = "RAII")]
+    type FrameTrackerRef = alloc::sync::Arc<NullFrame>;
+
+    #[cfg(feature = "RAII")]
+    fn map(
+        &self,
+        _start: usize,
+        _size: usize,
+        _flags: u64,
+        _page_table: &mut (),
+    ) -> Result<alloc::collections::BTreeMap<usize, Self::FrameTrackerRef>, Self::Error> {
+        Ok(alloc::collections::BTreeMap::new())
+    }
+
+    #[cfg(not(feature = "RAII"))]
+    fn map(
+        &self,
+        _start: usize,
+        _size: usize,
+        _flags: u64,
+        _page_table: &mut (),
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn unmap(&self, _start: usize, _size: usize, _page_table: &mut ()) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn protect(
+        &self,
+        _start: usize,
+        _size: usize,
+        _new_flags: u64,
+        _page_table: &mut (),
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+const BUCKETS: usize = u64::BITS as usize;
+
+/// The timing distribution of one kind of operation.
+///
+/// Durations are kept in power-of-two buckets, so percentiles are accurate to
+/// within a factor of two.
+#[derive(Debug, Clone)]
+pub struct OpTiming {
+    /// Number of operations.
+    pub count: u64,
+    /// Number of operations that returned an error.
+    pub errors: u64,
+    /// Sum of all durations.
+    pub total: u64,
+    /// Shortest duration.
+    pub min: u64,
+    /// Longest duration.
+    pub max: u64,
+    buckets: [u64; BUCKETS],
+}
+
+impl OpTiming {
+    const fn new() -> Self {
+        Self {
+            count: 0,
+            errors: 0,
+            total: 0,
+            min: u64::MAX,
+            max: 0,
+            buckets: [0; BUCKETS],
+        }
+    }
+
+    fn record(&mut self, duration: u64, ok: bool) {
+        self.count += 1;
+        self.errors += !ok as u64;
+        self.total = self.total.saturating_add(duration);
+        self.min = self.min.min(duration);
+        self.max = self.max.max(duration);
+        let bucket = (BUCKETS - duration.leading_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket] += 1;
+    }
+
+    /// Returns the mean duration, or `0` if nothing was recorded.
+    pub fn mean(&self) -> u64 {
+        self.total.checked_div(self.count).unwrap_or(0)
+    }
+
+    /// Returns an upper bound of the `p`-th percentile (`0..=100`) duration.
+    pub fn percentile(&self, p: u32) -> u64 {
+        let target = (self.count * p.min(100) as u64).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                let upper = if i == 0 { 0 } else { (1u64 << i) - 1 };
+                return upper.min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// The result of [`replay`].
+#[derive(Debug, Clone)]
+pub struct TraceReport {
+    /// Timings of `map` operations.
+    pub map: OpTiming,
+    /// Timings of `unmap` operations.
+    pub unmap: OpTiming,
+    /// Timings of `protect` operations.
+    pub protect: OpTiming,
+}
+
+impl fmt::Display for TraceReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rows = [
+            ("map", &self.map),
+            ("unmap", &self.unmap),
+            ("protect", &self.protect),
+        ];
+        for (name, t) in rows {
+            if t.count == 0 {
+                continue;
+            }
+            writeln!(
+                f,
+                "{name:<15} n={} err={} mean={} p50<={} p99<={} max={}",
+                t.count,
+                t.errors,
+                t.mean(),
+                t.percentile(50),
+                t.percentile(99),
+                t.max
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Replays a journal against a fresh [`MemorySet`] with [`NullBackend`].
+///
+/// Each entry is replayed as the operation that caused it: a mapping
+/// replacing what it overlaps, an unmapping, or a protection to the new
+/// flags. `clock` returns a monotonic timestamp (e.g., a cycle counter) and
+/// is read before and after each operation. Operations that fail are still
+/// timed and counted as errors.
+pub fn replay<A: MemoryAddr, F: Into<u64>>(
+    entries: impl IntoIterator<Item = JournalEntry<A, F>>,
+    clock: impl Fn() -> u64,
+) -> TraceReport {
+    let mut set = MemorySet::<NullBackend>::new();
+    let mut report = TraceReport {
+        map: OpTiming::new(),
+        unmap: OpTiming::new(),
+        protect: OpTiming::new(),
+    };
+    for entry in entries {
+        let range = entry.range();
+        let (start, size) = (range.start.into(), range.size());
+        let begin = clock();
+        let (timing, ok) = match entry {
+            JournalEntry::Map { flags, .. } => {
+                let area = MemoryArea::new(
+                    start,
+                    size,
+                    #[cfg(feature = "RAII")]
+                    None,
+                    flags.into(),
+                    NullBackend,
+                );
+                (&mut report.map, set.map(area, &mut (), true, None).is_ok())
+            }
+            JournalEntry::Unmap { .. } => {
+                (&mut report.unmap, set.unmap(start, size, &mut ()).is_ok())
+            }
+            JournalEntry::Protect { new_flags, .. } => {
+                let flags = new_flags.into();
+                let ok = set.protect(start, size, |_| Some(flags), &mut ()).is_ok();
+                (&mut report.protect, ok)
+            }
+        };
+        timing.record(clock().saturating_sub(begin), ok);
+    }
+    report
+}
@@ -0,0 +1,174 @@
+use memory_addr::{AddrRange, MemoryAddr};
+
+use crate::{MappingBackend, MappingError, MappingResult, MemoryArea, MemorySet, err_range};
+
+/// A cursor over the areas of a [`MemorySet`] that can modify the set in
+/// place, returned by [`MemorySet::cursor_mut`].
+///
+/// It points either to an area, or past the last area, and allows a series
+/// of splits, merges and removals around a position (e.g., for emulating
+/// `mprotect` or `madvise` on a range of VMAs) without looking the areas up
+/// again from their addresses.
+pub struct CursorMut<'a, B: MappingBackend> {
+    set: &'a mut MemorySet<B>,
+    /// The start of the current area, or `None` if past the last area.
+    current: Option<B::Addr>,
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Returns a cursor pointing to the area containing `addr`, or to the
+    /// first area after it if there is none.
+    pub fn cursor_mut(&mut self, addr: B::Addr) -> CursorMut<'_, B> {
+        let current = match self.areas.range(..=addr).next_back() {
+            Some((&start, area)) if area.va_range().contains(addr) => Some(start),
+            _ => self.areas.range(addr..).next().map(|(&start, _)| start),
+        };
+        CursorMut { set: self, current }
+    }
+
+    /// Returns a cursor pointing to the first area.
+    pub fn cursor_front_mut(&mut self) -> CursorMut<'_, B> {
+        let current = self.areas.keys().next().copied();
+        CursorMut { set: self, current }
+    }
+}
+
+impl<B: MappingBackend> CursorMut<'_, B> {
+    /// Returns the current area, or `None` if past the last area.
+    pub fn current(&self) -> Option<&MemoryArea<B>> {
+        self.current.map(|start| &self.set.areas[&start])
+    }
+
+    /// Returns the current area mutably, or `None` if past the last area.
+    ///
+    /// The range of the area must not be changed through it.
+    pub fn current_mut(&mut self) -> Option<&mut MemoryArea<B>> {
+        self.set.bump_generation();
+        self.current
+            .map(|start| self.set.areas.get_mut(&start).unwrap())
+    }
+
+    /// Returns the area before the current one, or the last area if past
+    /// the last area.
+    pub fn peek_prev(&self) -> Option<&MemoryArea<B>> {
+        match self.current {
+            Some(start) => self.set.areas.floor_with_neighbors(&start).0,
+            None => self.set.areas.last_key_value().map(|(_, area)| area),
+        }
+    }
+
+    /// Returns the area after the current one.
+    pub fn peek_next(&self) -> Option<&MemoryArea<B>> {
+        let start = self.current?;
+        self.set.areas.floor_with_neighbors(&start).2
+    }
+
+    /// Moves to the next area, or past the last area.
+    ///
+    /// Does nothing if already past the last area.
+    pub fn move_next(&mut self) {
+        self.current = self.peek_next().map(|area| area.start());
+    }
+
+    /// Moves to the previous area, or to the last area if past the last
+    /// area.
+    ///
+    /// Returns `false` and stays at the current area if it is the first one.
+    pub fn move_prev(&mut self) -> bool {
+        match self.peek_prev() {
+            Some(area) => {
+                self.current = Some(area.start());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Splits the current area at `pos`, and stays at the left part.
+    ///
+    /// Only the bookkeeping is changed, the page table is left as is.
+    ///
+    /// Returns [`MappingError::InvalidParam`] if there is no current area,
+    /// or `pos` is not strictly inside it, and
+    /// [`MappingError::LimitExceeded`] if the MPU has no room for another
+    /// region.
+    pub fn split(&mut self, pos: B::Addr) -> MappingResult {
+        let area = self
+            .current()
+            .ok_or(MappingError::InvalidParam(err_range(pos, 0)))?;
+        let (start, end) = (area.start(), area.end());
+        if pos <= start || pos >= end || !pos.is_aligned(area.granularity()) {
+            return Err(MappingError::InvalidParam(err_range(pos, 0)));
+        }
+        self.set
+            .check_mpu_regions([AddrRange::new(start, pos), AddrRange::new(pos, end)], 1)?;
+        self.set.bump_generation();
+        let right = self
+            .set
+            .areas
+            .get_mut(&start)
+            .unwrap()
+            .split(pos)?
+            .ok_or(MappingError::BadState(err_range(pos, 0), None))?;
+        self.set.areas.insert(pos, right);
+        if let Some(observer) = self.set.observer() {
+            observer.on_split(AddrRange::new(start, end), pos);
+        }
+        Ok(())
+    }
+
+    /// Merges the next area into the current one, if they are adjacent and
+    /// compatible, and the merged area is a valid MPU region in MPU mode.
+    ///
+    /// Returns whether the areas are merged.
+    pub fn merge_next(&mut self) -> bool {
+        let (Some(current), Some(next)) = (self.current(), self.peek_next()) else {
+            return false;
+        };
+        if !current.can_merge(next) {
+            return false;
+        }
+        let (start, next_start) = (current.start(), next.start());
+        let merged = AddrRange::new(start, next.end());
+        if self.set.check_mpu_regions([merged], 2).is_err() {
+            return false;
+        }
+        self.set.bump_generation();
+        let next = self.set.areas.remove(&next_start).unwrap();
+        self.set.areas.get_mut(&start).unwrap().merge(next);
+        true
+    }
+
+    /// Unmaps and removes the current area, and moves to the next one.
+    ///
+    /// Returns [`MappingError::InvalidParam`] if past the last area, and
+    /// [`MappingError::PermissionDenied`] if the current area is sealed. An
+    /// area that fails to unmap stays in the set with the cursor at it, since
+    /// some of its mappings may still be live.
+    pub fn remove_current(&mut self, page_table: &mut B::PageTable) -> MappingResult {
+        let start = self
+            .current
+            .ok_or(MappingError::InvalidParam(AddrRange::default()))?;
+        let area = &self.set.areas[&start];
+        if area.is_sealed() {
+            return Err(MappingError::PermissionDenied(err_range(
+                start,
+                area.size(),
+            )));
+        }
+        self.set.bump_generation();
+        let area = self.set.areas.get_mut(&start).unwrap();
+        let range = area.va_range();
+        let result = area.unmap_area(page_table);
+        if result.is_ok() {
+            self.move_next();
+            self.set.areas.remove(&start);
+            self.set.refresh_gaps(range);
+            if let Some(observer) = self.set.observer() {
+                observer.on_unmap(range);
+            }
+        }
+        self.set.settle_commit();
+        result
+    }
+}
@@ -0,0 +1,85 @@
+use alloc::vec::Vec;
+
+use memory_addr::{AddrRange, MemoryAddr};
+
+use crate::{MappingBackend, MappingResult, MemorySet};
+
+/// The pages written within a range since dirty logging was started or last
+/// collected, returned by [`MemorySet::collect_dirty`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DirtyLog<A: MemoryAddr> {
+    /// The dirty pages in ascending order, with adjacent pages merged into
+    /// one range.
+    pub ranges: Vec<AddrRange<A>>,
+}
+
+impl<A: MemoryAddr> DirtyLog<A> {
+    /// Returns whether no page was written.
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Returns the total size of the dirty pages in bytes, e.g., to decide
+    /// when an incremental copy has converged.
+    pub fn size(&self) -> usize {
+        self.ranges.iter().map(|range| range.size()).sum()
+    }
+
+    fn push(&mut self, page: A, page_size: usize) {
+        let end = page.add(page_size);
+        match self.ranges.last_mut() {
+            Some(last) if last.end == page => last.end = end,
+            _ => self.ranges.push(AddrRange::new(page, end)),
+        }
+    }
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Starts dirty logging on `[start, start + size)`: clears the
+    /// soft-dirty marks of its pages and write-protects them, so that the
+    /// next write to each page faults and is recorded by
+    /// [`handle_page_fault`](Self::handle_page_fault).
+    ///
+    /// The range must be fully covered by areas, otherwise
+    /// [`MappingError::NotMapped`](crate::MappingError::NotMapped) is
+    /// returned and nothing is done. See [`MemoryArea::clear_soft_dirty`].
+    ///
+    /// [`MemoryArea::clear_soft_dirty`]: crate::MemoryArea::clear_soft_dirty
+    pub fn write_protect_range(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        let range = self.granular_range(start, size)?;
+        self.check_covered(range)?;
+        self.clear_soft_dirty(range, page_table)
+    }
+
+    /// Collects the pages written within `[start, start + size)` since the
+    /// last collection or [`write_protect_range`](Self::write_protect_range),
+    /// and write-protects them again, e.g., for each round of a live
+    /// migration.
+    ///
+    /// Areas whose dirty logging was never started report all their pages
+    /// (all their resident pages with RAII). The range must be fully covered
+    /// by areas, like for [`write_protect_range`](Self::write_protect_range).
+    pub fn collect_dirty(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult<DirtyLog<B::Addr>> {
+        let range = self.granular_range(start, size)?;
+        self.check_covered(range)?;
+        let mut log = DirtyLog { ranges: Vec::new() };
+        for area in self.iter_range(range) {
+            let page_size = area.page_size();
+            for page in area.soft_dirty_pages(range) {
+                log.push(page, page_size);
+            }
+        }
+        self.clear_soft_dirty(range, page_table)?;
+        Ok(log)
+    }
+}
@@ -0,0 +1,158 @@
+use alloc::string::ToString;
+use core::fmt;
+
+use memory_addr::MemoryAddr;
+
+use crate::{MappingBackend, MemoryArea, MemorySet};
+
+/// The layout of a [`MemorySet`] as JSON, returned by
+/// [`MemorySet::layout_json`].
+///
+/// It is formatted with [`Display`](fmt::Display) as an object with the
+/// following fields:
+///
+/// - `label`: the owner label of the set, or `null`.
+/// - `areas`: the areas in ascending order, each with its `start` and `end`,
+///   `flags`, backend `kind`, `locked` and `write_protected` states, and the
+///   `resident` ranges backed by frames (if RAII is on).
+/// - `gaps`: the unmapped ranges between the areas, holes included since
+///   nothing is mapped there.
+///
+/// Ranges are `[start, end]` pairs, and addresses are hexadecimal strings so
+/// that 64-bit addresses survive JSON parsers using doubles.
+pub struct JsonLayout<'a, B: MappingBackend>(&'a MemorySet<B>);
+
+/// Writes a JSON string literal.
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+fn write_range<A: MemoryAddr>(f: &mut fmt::Formatter, start: A, end: A) -> fmt::Result {
+    write!(f, "[\"{:#x}\",\"{:#x}\"]", start.into(), end.into())
+}
+
+fn write_area<B: MappingBackend>(f: &mut fmt::Formatter, area: &MemoryArea<B>) -> fmt::Result {
+    write!(
+        f,
+        "{{\"start\":\"{:#x}\",\"end\":\"{:#x}\"",
+        area.start().into(),
+        area.end().into()
+    )?;
+    f.write_str(",\"flags\":")?;
+    write_str(f, &area.flags().to_string())?;
+    f.write_str(",\"kind\":")?;
+    write_str(f, area.backend().kind())?;
+    write!(
+        f,
+        ",\"locked\":{},\"write_protected\":{}",
+        area.is_locked(),
+        area.is_write_protected()
+    )?;
+    #[cfg(feature = "RAII")]
+    {
+        f.write_str(",\"resident\":[")?;
+        for (i, range) in area.resident_ranges().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write_range(f, range.start, range.end)?;
+        }
+        f.write_str("]")?;
+    }
+    f.write_str("}")
+}
+
+impl<B: MappingBackend> fmt::Display for JsonLayout<'_, B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let set = self.0;
+        f.write_str("{\"label\":")?;
+        match set.label() {
+            Some(label) => write_str(f, &label.to_string())?,
+            None => f.write_str("null")?,
+        }
+        f.write_str(",\"areas\":[")?;
+        for (i, area) in set.iter().filter(|area| !area.is_hole()).enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write_area(f, area)?;
+        }
+        f.write_str("],\"gaps\":[")?;
+        let mut first = true;
+        let areas = || set.iter().filter(|area| !area.is_hole());
+        for (prev, next) in areas().zip(areas().skip(1)) {
+            if prev.end() < next.start() {
+                if !first {
+                    f.write_str(",")?;
+                }
+                first = false;
+                write_range(f, prev.end(), next.start())?;
+            }
+        }
+        f.write_str("]}")
+    }
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Returns the layout of the set (areas, gaps, and frame residency) as
+    /// JSON, e.g., for an external inspector. See [`JsonLayout`].
+    pub fn layout_json(&self) -> JsonLayout<'_, B> {
+        JsonLayout(self)
+    }
+
+    /// Writes the areas in the format of `/proc/<pid>/maps`, one per line:
+    ///
+    /// ```text
+    /// start-end flags offset kind [label]
+    /// ```
+    ///
+    /// The addresses and the offset are zero-padded hexadecimal, the flags
+    /// are written with their [`ToString`] implementation, the kind is from
+    /// [`MappingBackend::kind`], and the label (see
+    /// [`MemoryArea::set_label`]) is omitted if the area has none. The offset
+    /// is the one in the mapped file for the areas created with
+    /// `MemoryArea::new_file` (with the `mmap` feature), and comes from
+    /// [`MappingBackend::file_offset`] otherwise.
+    ///
+    /// If the set has a [label](Self::label), it comes first on a line of its
+    /// own, as `# label`, so that dumps of several sets can be told apart.
+    pub fn dump_maps(&self, w: &mut impl fmt::Write) -> fmt::Result {
+        if let Some(label) = self.label() {
+            writeln!(w, "# {label}")?;
+        }
+        for area in self.iter().filter(|area| !area.is_hole()) {
+            write!(
+                w,
+                "{:08x}-{:08x} {} {:08x} {}",
+                area.start().into(),
+                area.end().into(),
+                area.flags().to_string(),
+                maps_offset(area),
+                area.backend().kind()
+            )?;
+            match area.label() {
+                Some(label) => writeln!(w, " {label}")?,
+                None => writeln!(w)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the offset shown for `area` by [`MemorySet::dump_maps`].
+fn maps_offset<B: MappingBackend>(area: &MemoryArea<B>) -> usize {
+    #[cfg(feature = "mmap")]
+    if let Some((_, offset)) = area.file_object() {
+        return offset;
+    }
+    area.backend().file_offset(area.start())
+}
@@ -0,0 +1,93 @@
+//! Resolving page faults as [`Future`]s, for async kernels whose faults may
+//! wait for a remote page or a swap-in from a disk without blocking the hart.
+//!
+//! It only relies on [`core::future`]: whatever executor the kernel runs
+//! polls the futures, and the backends wake them through the [`Context`].
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use crate::{MappingBackend, MappingError, MappingResult, MemorySet, err_range};
+
+/// The future returned by [`MemorySet::handle_page_fault_async`].
+#[must_use = "futures do nothing unless polled"]
+pub struct FaultFuture<'a, B: MappingBackend> {
+    set: &'a mut MemorySet<B>,
+    page_table: &'a mut B::PageTable,
+    vaddr: B::Addr,
+    access_flags: B::Flags,
+}
+
+// Nothing is pinned: the future only holds references and copies.
+impl<B: MappingBackend> Unpin for FaultFuture<'_, B> {}
+
+impl<B: MappingBackend> FaultFuture<'_, B> {
+    /// Polls whether the fault can be resolved without blocking: if the page
+    /// is swapped out, whether the swap can load it, and otherwise whether
+    /// the backend is ready.
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<MappingResult> {
+        let (vaddr, access_flags) = (self.vaddr, self.access_flags);
+        let Some(area) = self.set.find(vaddr) else {
+            return Poll::Ready(Err(MappingError::NotMapped(err_range(vaddr, 1))));
+        };
+        if !area.backend().check_access(area.flags(), access_flags) {
+            return Poll::Ready(Err(MappingError::PermissionDenied(err_range(vaddr, 1))));
+        }
+        #[cfg(feature = "RAII")]
+        {
+            use memory_addr::MemoryAddr;
+            let page = vaddr.align_down(area.frame_size());
+            if let Some(slot) = area.swapped.get(&page) {
+                return slot.poll_ready(cx).map(Ok);
+            }
+        }
+        area.backend()
+            .poll_fault_ready(vaddr, access_flags, cx)
+            .map(Ok)
+    }
+}
+
+impl<B: MappingBackend> Future for FaultFuture<'_, B> {
+    type Output = MappingResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<MappingResult> {
+        let this = self.get_mut();
+        match this.poll_ready(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(this.set.handle_page_fault(
+                this.vaddr,
+                this.access_flags,
+                this.page_table,
+            )),
+            other => other,
+        }
+    }
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Handles a page fault at `vaddr` like
+    /// [`handle_page_fault`](Self::handle_page_fault), as a future that
+    /// first waits for the page to be ready.
+    ///
+    /// Until then, it returns [`Poll::Pending`] as told by
+    /// [`SwapBackend::poll_ready`] for swapped-out pages, or by
+    /// [`MappingBackend::poll_fault_ready`] for the others, so that the
+    /// faulting task can be suspended while the page is fetched. The set
+    /// and the page table stay borrowed by the future, and the area is
+    /// looked up again at every poll.
+    ///
+    /// [`SwapBackend::poll_ready`]: crate::SwapBackend::poll_ready
+    pub fn handle_page_fault_async<'a>(
+        &'a mut self,
+        vaddr: B::Addr,
+        access_flags: B::Flags,
+        page_table: &'a mut B::PageTable,
+    ) -> FaultFuture<'a, B> {
+        FaultFuture {
+            set: self,
+            page_table,
+            vaddr,
+            access_flags,
+        }
+    }
+}
@@ -0,0 +1,623 @@
+//! The frames held by a memory area, see [`FrameMap`].
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, btree_map};
+use alloc::vec::{self, Vec};
+use core::mem;
+use core::ops::{Bound, RangeBounds};
+use core::slice;
+
+use memory_addr::{FrameTracker, MemoryAddr};
+
+use crate::{BackendError, MappingBackend, MappingError, MappingResult, err_range};
+
+/// Maps with fewer frames than this stay sparse.
+const DENSE_MIN_FRAMES: usize = 64;
+
+type Slot<B> = Option<(
+    <B as MappingBackend>::Addr,
+    <B as MappingBackend>::FrameTrackerRef,
+)>;
+
+/// The frames held by a [`MemoryArea`](crate::MemoryArea), keyed by the
+/// virtual addresses of their pages.
+///
+/// It has the interface of a `BTreeMap`, but switches to an array indexed by
+/// page number once at least 3/4 of the pages it spans have frames, so that
+/// lookups in densely populated areas (e.g., large heaps during copy-on-write
+/// or writeback) take constant time. It switches back to a tree once fewer
+/// than 1/4 of the slots of the array are used.
+///
+/// Frames larger than a page (e.g., 2M or 1G huge pages) can be inserted with
+/// [`insert_sized`](Self::insert_sized). Such a frame is keyed by its first
+/// page only, and is split into frames of one page with
+/// [`MappingBackend::split_frame`] when the map is split through it.
+#[derive(Clone)]
+pub struct FrameMap<B: MappingBackend> {
+    repr: Repr<B>,
+    /// The sizes of the frames larger than a page, keyed by their first
+    /// pages.
+    sizes: BTreeMap<B::Addr, usize>,
+}
+
+#[derive(Clone)]
+enum Repr<B: MappingBackend> {
+    Sparse(BTreeMap<B::Addr, B::FrameTrackerRef>),
+    Dense {
+        /// The address of the page of the first slot.
+        base: B::Addr,
+        slots: Vec<Slot<B>>,
+        /// The number of occupied slots.
+        len: usize,
+    },
+}
+
+impl<B: MappingBackend> FrameMap<B> {
+    /// Creates an empty map.
+    pub const fn new() -> Self {
+        Self {
+            repr: Repr::Sparse(BTreeMap::new()),
+            sizes: BTreeMap::new(),
+        }
+    }
+
+    const fn page_size() -> usize {
+        <B::FrameTrackerImpl as FrameTracker>::PAGE_SIZE
+    }
+
+    /// Returns whether the frames are stored in an array.
+    pub fn is_dense(&self) -> bool {
+        matches!(self.repr, Repr::Dense { .. })
+    }
+
+    /// Returns the number of frames.
+    pub fn len(&self) -> usize {
+        match &self.repr {
+            Repr::Sparse(map) => map.len(),
+            Repr::Dense { len, .. } => *len,
+        }
+    }
+
+    /// Returns `true` if there are no frames.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the frame of the page at `vaddr`.
+    pub fn get(&self, vaddr: &B::Addr) -> Option<&B::FrameTrackerRef> {
+        match &self.repr {
+            Repr::Sparse(map) => map.get(vaddr),
+            Repr::Dense { base, slots, .. } => {
+                let offset = vaddr.checked_sub_addr(*base)?;
+                if offset % Self::page_size() != 0 {
+                    return None;
+                }
+                let (_, frame) = slots.get(offset / Self::page_size())?.as_ref()?;
+                Some(frame)
+            }
+        }
+    }
+
+    /// Returns `true` if the page at `vaddr` has a frame.
+    pub fn contains_key(&self, vaddr: &B::Addr) -> bool {
+        self.get(vaddr).is_some()
+    }
+
+    /// Inserts the frame of the page at `vaddr`, and returns the old one.
+    pub fn insert(
+        &mut self,
+        vaddr: B::Addr,
+        frame: B::FrameTrackerRef,
+    ) -> Option<B::FrameTrackerRef> {
+        self.sizes.remove(&vaddr);
+        let index = self.dense_index(vaddr);
+        if index.is_none() {
+            self.make_sparse();
+        }
+        let old = match (&mut self.repr, index) {
+            (Repr::Dense { slots, len, .. }, Some(index)) => {
+                let old = slots[index].replace((vaddr, frame)).map(|(_, old)| old);
+                if old.is_none() {
+                    *len += 1;
+                }
+                old
+            }
+            (Repr::Sparse(map), _) => map.insert(vaddr, frame),
+            (Repr::Dense { .. }, None) => unreachable!(),
+        };
+        self.adjust();
+        old
+    }
+
+    /// Inserts a frame of `size` bytes mapping the pages from `vaddr`, e.g.,
+    /// a huge page, and returns the old frame of the page at `vaddr`.
+    ///
+    /// `size` must be a multiple of the page size, and the other pages
+    /// covered by the frame must not have frames of their own.
+    pub fn insert_sized(
+        &mut self,
+        vaddr: B::Addr,
+        frame: B::FrameTrackerRef,
+        size: usize,
+    ) -> Option<B::FrameTrackerRef> {
+        debug_assert!(size > 0 && size.is_multiple_of(Self::page_size()));
+        let old = self.insert(vaddr, frame);
+        if size > Self::page_size() {
+            self.sizes.insert(vaddr, size);
+        }
+        old
+    }
+
+    /// Returns the size of the frame of the page at `vaddr`, which is the
+    /// page size unless it was inserted with
+    /// [`insert_sized`](Self::insert_sized).
+    pub fn frame_size(&self, vaddr: &B::Addr) -> usize {
+        self.sizes.get(vaddr).copied().unwrap_or(Self::page_size())
+    }
+
+    /// Returns the frame covering the page at `vaddr`, along with the address
+    /// of its first page and its size.
+    pub fn covering(&self, vaddr: B::Addr) -> Option<(B::Addr, &B::FrameTrackerRef, usize)> {
+        if let Some(frame) = self.get(&vaddr) {
+            return Some((vaddr, frame, self.frame_size(&vaddr)));
+        }
+        let (&start, &size) = self.sizes.range(..vaddr).next_back()?;
+        if vaddr.sub_addr(start) >= size {
+            return None;
+        }
+        Some((start, self.get(&start)?, size))
+    }
+
+    /// Returns the total size of the frames in bytes.
+    pub fn total_size(&self) -> usize {
+        let extra: usize = self
+            .sizes
+            .values()
+            .map(|size| size - Self::page_size())
+            .sum();
+        self.len() * Self::page_size() + extra
+    }
+
+    /// Splits the frame crossing `vaddr`, if any, into frames of one page
+    /// with [`MappingBackend::split_frame`], so that no frame spans both
+    /// sides of it.
+    ///
+    /// Returns [`MappingError::BadState`] with the range of the frame if the
+    /// backend cannot split it, leaving the map unchanged.
+    pub fn demote(&mut self, vaddr: B::Addr) -> MappingResult {
+        let Some((&start, &size)) = self.sizes.range(..vaddr).next_back() else {
+            return Ok(());
+        };
+        if vaddr.sub_addr(start) >= size {
+            return Ok(());
+        }
+        let pieces = B::split_frame(self.get(&start).unwrap(), size).map_err(|err| {
+            MappingError::BadState(
+                err_range(start, size),
+                err.map(|err| Box::new(err) as BackendError),
+            )
+        })?;
+        assert_eq!(
+            pieces.len(),
+            size / Self::page_size(),
+            "frame split into the wrong number of pages"
+        );
+        self.remove(&start);
+        for (index, piece) in pieces.into_iter().enumerate() {
+            self.insert(start.add(index * Self::page_size()), piece);
+        }
+        Ok(())
+    }
+
+    /// Splits the frames larger than a page overlapping `[start, end)` into
+    /// frames of one page, see [`demote`](Self::demote).
+    ///
+    /// Fails on the first frame that cannot be split, the frames before it
+    /// stay split.
+    pub fn demote_range(&mut self, start: B::Addr, end: B::Addr) -> MappingResult {
+        let firsts: Vec<_> = self
+            .sizes
+            .range(..end)
+            .filter(|&(&first, &size)| first.add(size) > start)
+            .map(|(&first, _)| first)
+            .collect();
+        for first in firsts {
+            self.demote(first.add(Self::page_size()))?;
+        }
+        Ok(())
+    }
+
+    /// Returns a map of private copies of the frames for which `copy` returns
+    /// `true`, made with [`MappingBackend::copy_frame`] page by page, so that
+    /// frames larger than a page are copied into frames of one page. The
+    /// other frames are shared.
+    pub(crate) fn copied(&self, copy: impl Fn(&B::FrameTrackerRef) -> bool) -> Self {
+        let mut copied = Self::new();
+        for (&vaddr, frame) in self {
+            if !copy(frame) {
+                copied.insert_sized(vaddr, frame.clone(), self.frame_size(&vaddr));
+                continue;
+            }
+            for offset in (0..self.frame_size(&vaddr)).step_by(Self::page_size()) {
+                copied.insert(vaddr.add(offset), B::copy_frame(frame, offset));
+            }
+        }
+        copied
+    }
+
+    /// Returns the map with every frame moved by `new_base - old_base`,
+    /// keeping the sizes of the frames.
+    pub(crate) fn moved(mut self, old_base: B::Addr, new_base: B::Addr) -> Self {
+        let rebase = |vaddr: B::Addr| new_base.add(vaddr.sub_addr(old_base));
+        let sizes = mem::take(&mut self.sizes)
+            .into_iter()
+            .map(|(vaddr, size)| (rebase(vaddr), size))
+            .collect();
+        let mut moved: Self = self
+            .into_iter()
+            .map(|(vaddr, frame)| (rebase(vaddr), frame))
+            .collect();
+        moved.sizes = sizes;
+        moved
+    }
+
+    /// Removes the frame of the page at `vaddr`, and returns it.
+    pub fn remove(&mut self, vaddr: &B::Addr) -> Option<B::FrameTrackerRef> {
+        self.sizes.remove(vaddr);
+        let removed = match &mut self.repr {
+            Repr::Sparse(map) => map.remove(vaddr),
+            Repr::Dense { base, slots, len } => {
+                let offset = vaddr.checked_sub_addr(*base)?;
+                if offset % Self::page_size() != 0 {
+                    return None;
+                }
+                let (_, frame) = slots.get_mut(offset / Self::page_size())?.take()?;
+                *len -= 1;
+                Some(frame)
+            }
+        };
+        self.adjust();
+        removed
+    }
+
+    /// Removes all the frames.
+    pub fn clear(&mut self) {
+        self.repr = Repr::Sparse(BTreeMap::new());
+        self.sizes.clear();
+    }
+
+    /// Returns an iterator over the pages and their frames, in ascending
+    /// order.
+    pub fn iter(&self) -> Iter<'_, B> {
+        self.range(..)
+    }
+
+    /// Returns an iterator over the frames, in ascending order of their
+    /// pages.
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = &B::FrameTrackerRef> {
+        self.iter().map(|(_, frame)| frame)
+    }
+
+    /// Returns an iterator over the pages within `range` and their frames, in
+    /// ascending order.
+    pub fn range(&self, range: impl RangeBounds<B::Addr>) -> Iter<'_, B> {
+        let inner = match &self.repr {
+            Repr::Sparse(map) => {
+                let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+                IterInner::Sparse(map.range(bounds))
+            }
+            Repr::Dense { base, slots, .. } => {
+                let page_size = Self::page_size();
+                // The index of the first slot at or above `vaddr`.
+                let ceil = |vaddr: B::Addr| {
+                    vaddr
+                        .checked_sub_addr(*base)
+                        .map_or(0, |offset| offset.div_ceil(page_size))
+                };
+                // The index of the first slot above `vaddr`.
+                let above = |vaddr: B::Addr| {
+                    vaddr
+                        .checked_sub_addr(*base)
+                        .map_or(0, |offset| offset / page_size + 1)
+                };
+                let start = match range.start_bound() {
+                    Bound::Included(&vaddr) => ceil(vaddr),
+                    Bound::Excluded(&vaddr) => above(vaddr),
+                    Bound::Unbounded => 0,
+                };
+                let end = match range.end_bound() {
+                    Bound::Included(&vaddr) => above(vaddr),
+                    Bound::Excluded(&vaddr) => ceil(vaddr),
+                    Bound::Unbounded => slots.len(),
+                };
+                let end = end.min(slots.len());
+                IterInner::Dense(slots[start.min(end)..end].iter())
+            }
+        };
+        Iter { inner }
+    }
+
+    /// Splits the map in two at `vaddr`, and returns the frames at or above
+    /// it. A frame crossing `vaddr` must be [demoted](Self::demote) first.
+    pub fn split_off(&mut self, vaddr: &B::Addr) -> Self {
+        debug_assert!(
+            self.sizes
+                .range(..*vaddr)
+                .next_back()
+                .is_none_or(|(&start, &size)| vaddr.sub_addr(start) >= size),
+            "splitting a frame map through a frame"
+        );
+        let sizes = self.sizes.split_off(vaddr);
+        let mut right = match &mut self.repr {
+            Repr::Sparse(map) => Self {
+                repr: Repr::Sparse(map.split_off(vaddr)),
+                sizes,
+            },
+            Repr::Dense { base, slots, len } => {
+                let Some(offset) = vaddr.checked_sub_addr(*base) else {
+                    let mut right = mem::take(self);
+                    right.sizes = sizes;
+                    return right;
+                };
+                let index = offset.div_ceil(Self::page_size()).min(slots.len());
+                let right_slots = slots.split_off(index);
+                let right_len = right_slots.iter().filter(|slot| slot.is_some()).count();
+                *len -= right_len;
+                Self {
+                    repr: Repr::Dense {
+                        base: base.add(index * Self::page_size()),
+                        slots: right_slots,
+                        len: right_len,
+                    },
+                    sizes,
+                }
+            }
+        };
+        self.adjust();
+        right.adjust();
+        right
+    }
+
+    /// Moves all the frames of `other` into the map, leaving `other` empty.
+    pub fn append(&mut self, other: &mut Self) {
+        let mut other = mem::take(other);
+        self.sizes.append(&mut other.sizes);
+        match (&mut self.repr, &mut other.repr) {
+            (Repr::Sparse(map), Repr::Sparse(other_map)) => map.append(other_map),
+            (
+                Repr::Dense { base, slots, len },
+                Repr::Dense {
+                    base: other_base,
+                    slots: other_slots,
+                    len: other_len,
+                },
+            ) if base.checked_add(slots.len() * Self::page_size()) == Some(*other_base) => {
+                slots.append(other_slots);
+                *len += *other_len;
+            }
+            _ => self.extend(other),
+        }
+        self.adjust();
+    }
+
+    /// Retains only the frames for which `f` returns `true`.
+    pub fn retain(&mut self, mut f: impl FnMut(&B::Addr, &mut B::FrameTrackerRef) -> bool) {
+        match &mut self.repr {
+            Repr::Sparse(map) => map.retain(f),
+            Repr::Dense { slots, len, .. } => {
+                for slot in slots.iter_mut() {
+                    if let Some((vaddr, frame)) = slot
+                        && !f(vaddr, frame)
+                    {
+                        *slot = None;
+                        *len -= 1;
+                    }
+                }
+            }
+        }
+        if !self.sizes.is_empty() {
+            let sizes = mem::take(&mut self.sizes)
+                .into_iter()
+                .filter(|(vaddr, _)| self.contains_key(vaddr))
+                .collect();
+            self.sizes = sizes;
+        }
+        self.adjust();
+    }
+
+    /// Returns the index of the slot for `vaddr` in the array, growing the
+    /// array to cover it if that keeps it dense enough.
+    ///
+    /// Returns `None` if the map is sparse, or `vaddr` does not fit.
+    fn dense_index(&mut self, vaddr: B::Addr) -> Option<usize> {
+        let page_size = Self::page_size();
+        let Repr::Dense { base, slots, len } = &mut self.repr else {
+            return None;
+        };
+        let max_slots = (*len + 1) * 4;
+        if let Some(offset) = vaddr.checked_sub_addr(*base) {
+            let index = offset / page_size;
+            if offset % page_size != 0 || index >= max_slots {
+                return None;
+            }
+            if index >= slots.len() {
+                slots.resize(index + 1, None);
+            }
+            return Some(index);
+        }
+        let offset = base.sub_addr(vaddr);
+        let missing = offset / page_size;
+        if offset % page_size != 0 || slots.len() + missing > max_slots {
+            return None;
+        }
+        // Leave some room below for areas growing downward, e.g., stacks.
+        let mut room = (slots.len() / 2).min(vaddr.into() / page_size);
+        if slots.len() + missing + room > max_slots {
+            room = 0;
+        }
+        slots.splice(0..0, core::iter::repeat_n(None, missing + room));
+        *base = vaddr.sub(room * page_size);
+        Some(room)
+    }
+
+    /// Switches to a tree.
+    fn make_sparse(&mut self) {
+        if let Repr::Dense { slots, .. } = &mut self.repr {
+            let map = mem::take(slots).into_iter().flatten().collect();
+            self.repr = Repr::Sparse(map);
+        }
+    }
+
+    /// Switches the representation if the density crosses the thresholds.
+    fn adjust(&mut self) {
+        let page_size = Self::page_size();
+        match &mut self.repr {
+            Repr::Sparse(map) => {
+                if map.len() < DENSE_MIN_FRAMES {
+                    return;
+                }
+                let (&first, _) = map.first_key_value().unwrap();
+                let (&last, _) = map.last_key_value().unwrap();
+                let span = last.sub_addr(first) / page_size + 1;
+                if map.len() * 4 < span * 3
+                    || map
+                        .keys()
+                        .any(|vaddr| vaddr.sub_addr(first) % page_size != 0)
+                {
+                    return;
+                }
+                let mut slots: Vec<Slot<B>> = (0..span).map(|_| None).collect();
+                let len = map.len();
+                for (vaddr, frame) in mem::take(map) {
+                    slots[vaddr.sub_addr(first) / page_size] = Some((vaddr, frame));
+                }
+                self.repr = Repr::Dense {
+                    base: first,
+                    slots,
+                    len,
+                };
+            }
+            Repr::Dense { slots, len, .. } => {
+                if *len * 4 < slots.len() || *len < DENSE_MIN_FRAMES / 2 {
+                    self.make_sparse();
+                }
+            }
+        }
+    }
+}
+
+impl<B: MappingBackend> Default for FrameMap<B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<B: MappingBackend> From<BTreeMap<B::Addr, B::FrameTrackerRef>> for FrameMap<B> {
+    fn from(map: BTreeMap<B::Addr, B::FrameTrackerRef>) -> Self {
+        let mut frames = Self {
+            repr: Repr::Sparse(map),
+            sizes: BTreeMap::new(),
+        };
+        frames.adjust();
+        frames
+    }
+}
+
+impl<B: MappingBackend> From<FrameMap<B>> for BTreeMap<B::Addr, B::FrameTrackerRef> {
+    fn from(frames: FrameMap<B>) -> Self {
+        match frames.repr {
+            Repr::Sparse(map) => map,
+            Repr::Dense { slots, .. } => slots.into_iter().flatten().collect(),
+        }
+    }
+}
+
+impl<B: MappingBackend> Extend<(B::Addr, B::FrameTrackerRef)> for FrameMap<B> {
+    fn extend<I: IntoIterator<Item = (B::Addr, B::FrameTrackerRef)>>(&mut self, iter: I) {
+        for (vaddr, frame) in iter {
+            self.insert(vaddr, frame);
+        }
+    }
+}
+
+impl<B: MappingBackend> FromIterator<(B::Addr, B::FrameTrackerRef)> for FrameMap<B> {
+    fn from_iter<I: IntoIterator<Item = (B::Addr, B::FrameTrackerRef)>>(iter: I) -> Self {
+        BTreeMap::from_iter(iter).into()
+    }
+}
+
+impl<B: MappingBackend> IntoIterator for FrameMap<B> {
+    type Item = (B::Addr, B::FrameTrackerRef);
+    type IntoIter = IntoIter<B>;
+
+    fn into_iter(self) -> IntoIter<B> {
+        let inner = match self.repr {
+            Repr::Sparse(map) => IntoIterInner::Sparse(map.into_iter()),
+            Repr::Dense { slots, .. } => IntoIterInner::Dense(slots.into_iter()),
+        };
+        IntoIter { inner }
+    }
+}
+
+impl<'a, B: MappingBackend> IntoIterator for &'a FrameMap<B> {
+    type Item = (&'a B::Addr, &'a B::FrameTrackerRef);
+    type IntoIter = Iter<'a, B>;
+
+    fn into_iter(self) -> Iter<'a, B> {
+        self.iter()
+    }
+}
+
+/// An iterator over the pages and frames of a [`FrameMap`].
+pub struct Iter<'a, B: MappingBackend> {
+    inner: IterInner<'a, B>,
+}
+
+enum IterInner<'a, B: MappingBackend> {
+    Sparse(btree_map::Range<'a, B::Addr, B::FrameTrackerRef>),
+    Dense(slice::Iter<'a, Slot<B>>),
+}
+
+impl<'a, B: MappingBackend> Iterator for Iter<'a, B> {
+    type Item = (&'a B::Addr, &'a B::FrameTrackerRef);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.inner {
+            IterInner::Sparse(iter) => iter.next(),
+            IterInner::Dense(iter) => iter.find_map(|slot| slot.as_ref().map(|(a, f)| (a, f))),
+        }
+    }
+}
+
+impl<B: MappingBackend> DoubleEndedIterator for Iter<'_, B> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        match &mut self.inner {
+            IterInner::Sparse(iter) => iter.next_back(),
+            IterInner::Dense(iter) => iter
+                .by_ref()
+                .rev()
+                .find_map(|slot| slot.as_ref().map(|(a, f)| (a, f))),
+        }
+    }
+}
+
+/// An owning iterator over the pages and frames of a [`FrameMap`].
+pub struct IntoIter<B: MappingBackend> {
+    inner: IntoIterInner<B>,
+}
+
+enum IntoIterInner<B: MappingBackend> {
+    Sparse(btree_map::IntoIter<B::Addr, B::FrameTrackerRef>),
+    Dense(vec::IntoIter<Slot<B>>),
+}
+
+impl<B: MappingBackend> Iterator for IntoIter<B> {
+    type Item = (B::Addr, B::FrameTrackerRef);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.inner {
+            IntoIterInner::Sparse(iter) => iter.next(),
+            IntoIterInner::Dense(iter) => iter.by_ref().flatten().next(),
+        }
+    }
+}
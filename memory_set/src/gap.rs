@@ -0,0 +1,217 @@
+//! An index of the gaps between memory areas, for fast free area searches.
+
+use alloc::vec::Vec;
+
+const NIL: usize = usize::MAX;
+
+struct Node {
+    start: usize,
+    end: usize,
+    /// The size of the largest gap in the subtree rooted at this node.
+    max_size: usize,
+    priority: u64,
+    left: usize,
+    right: usize,
+}
+
+/// The gaps between adjacent memory areas, keyed by their start addresses.
+///
+/// It is a treap augmented with the largest gap size of each subtree, so that
+/// the lowest (or highest) gap of at least a given size can be found in
+/// `O(log n)` expected time. The nodes live in an arena to avoid per-node allocations.
+pub(crate) struct GapIndex {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    root: usize,
+}
+
+impl GapIndex {
+    pub const fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            root: NIL,
+        }
+    }
+
+    /// Removes all the gaps.
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.free.clear();
+        self.root = NIL;
+    }
+
+    fn max_size(&self, node: usize) -> usize {
+        if node == NIL {
+            0
+        } else {
+            self.nodes[node].max_size
+        }
+    }
+
+    fn update(&mut self, node: usize) {
+        let n = &self.nodes[node];
+        let max_size = (n.end - n.start)
+            .max(self.max_size(n.left))
+            .max(self.max_size(n.right));
+        self.nodes[node].max_size = max_size;
+    }
+
+    /// Splits the subtree into the nodes starting before `key` and the rest.
+    fn split(&mut self, node: usize, key: usize) -> (usize, usize) {
+        if node == NIL {
+            return (NIL, NIL);
+        }
+        if self.nodes[node].start < key {
+            let (left, right) = self.split(self.nodes[node].right, key);
+            self.nodes[node].right = left;
+            self.update(node);
+            (node, right)
+        } else {
+            let (left, right) = self.split(self.nodes[node].left, key);
+            self.nodes[node].left = right;
+            self.update(node);
+            (left, node)
+        }
+    }
+
+    /// Merges two subtrees, all the nodes of `left` starting before those of
+    /// `right`.
+    fn merge(&mut self, left: usize, right: usize) -> usize {
+        if left == NIL {
+            return right;
+        }
+        if right == NIL {
+            return left;
+        }
+        if self.nodes[left].priority > self.nodes[right].priority {
+            let merged = self.merge(self.nodes[left].right, right);
+            self.nodes[left].right = merged;
+            self.update(left);
+            left
+        } else {
+            let merged = self.merge(left, self.nodes[right].left);
+            self.nodes[right].left = merged;
+            self.update(right);
+            right
+        }
+    }
+
+    /// Adds the gap `[start, end)`, which must not overlap with the others.
+    pub fn insert(&mut self, start: usize, end: usize) {
+        debug_assert!(start < end);
+        let node = Node {
+            start,
+            end,
+            max_size: end - start,
+            // A fixed hash keeps the shape reproducible.
+            priority: (start as u64)
+                .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+                .rotate_left(29),
+            left: NIL,
+            right: NIL,
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        let (left, right) = self.split(self.root, start);
+        let left = self.merge(left, index);
+        self.root = self.merge(left, right);
+    }
+
+    /// Removes the gaps intersecting or touching `[start, end]`.
+    pub fn remove_touching(&mut self, start: usize, end: usize) {
+        // The gap containing `start` (if any) starts at or before it.
+        let from = match self.floor(start) {
+            Some((gap_start, gap_end)) if gap_end >= start => gap_start,
+            _ => start,
+        };
+        let (left, rest) = self.split(self.root, from);
+        let (mut removed, right) = match end.checked_add(1) {
+            Some(after) => self.split(rest, after),
+            None => (rest, NIL),
+        };
+        self.root = self.merge(left, right);
+        // Recycle the removed nodes.
+        let mut stack = Vec::new();
+        while removed != NIL || !stack.is_empty() {
+            if removed != NIL {
+                stack.push(removed);
+                removed = self.nodes[removed].left;
+            } else {
+                let node = stack.pop().unwrap();
+                self.free.push(node);
+                removed = self.nodes[node].right;
+            }
+        }
+    }
+
+    /// Returns the gap with the highest start address not above `addr`.
+    pub fn floor(&self, addr: usize) -> Option<(usize, usize)> {
+        let mut node = self.root;
+        let mut found = None;
+        while node != NIL {
+            let n = &self.nodes[node];
+            if n.start <= addr {
+                found = Some((n.start, n.end));
+                node = n.right;
+            } else {
+                node = n.left;
+            }
+        }
+        found
+    }
+
+    /// Returns the lowest gap starting at or after `min_start` with at least
+    /// `size` bytes.
+    pub fn first_fit(&self, min_start: usize, size: usize) -> Option<(usize, usize)> {
+        self.first_fit_in(self.root, min_start, size)
+    }
+
+    fn first_fit_in(&self, node: usize, min_start: usize, size: usize) -> Option<(usize, usize)> {
+        if node == NIL || self.nodes[node].max_size < size {
+            return None;
+        }
+        let n = &self.nodes[node];
+        if n.start < min_start {
+            return self.first_fit_in(n.right, min_start, size);
+        }
+        if let Some(gap) = self.first_fit_in(n.left, min_start, size) {
+            return Some(gap);
+        }
+        if n.end - n.start >= size {
+            return Some((n.start, n.end));
+        }
+        self.first_fit_in(n.right, min_start, size)
+    }
+
+    /// Returns the highest gap starting before `max_start` with at least
+    /// `size` bytes.
+    pub fn last_fit(&self, max_start: usize, size: usize) -> Option<(usize, usize)> {
+        self.last_fit_in(self.root, max_start, size)
+    }
+
+    fn last_fit_in(&self, node: usize, max_start: usize, size: usize) -> Option<(usize, usize)> {
+        if node == NIL || self.nodes[node].max_size < size {
+            return None;
+        }
+        let n = &self.nodes[node];
+        if n.start >= max_start {
+            return self.last_fit_in(n.left, max_start, size);
+        }
+        if let Some(gap) = self.last_fit_in(n.right, max_start, size) {
+            return Some(gap);
+        }
+        if n.end - n.start >= size {
+            return Some((n.start, n.end));
+        }
+        self.last_fit_in(n.left, max_start, size)
+    }
+}
@@ -0,0 +1,216 @@
+//! Journaling of the changes to a [`MemorySet`].
+//!
+//! A [`Journal`] is a [`MapObserver`] recording every change to the set it
+//! observes as a [`JournalEntry`]. The entries can be saved in a textual form
+//! with [`format_journal`] and read back with [`parse_journal`], e.g., to
+//! replay a recorded workload with `bench::replay`.
+//!
+//! [`MemorySet`]: crate::MemorySet
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+
+use memory_addr::{AddrRange, MemoryAddr};
+use spin::Mutex;
+
+use crate::{MapObserver, MappingBackend};
+
+/// A change to a [`MemorySet`](crate::MemorySet), as recorded by a
+/// [`Journal`].
+///
+/// Splits are not recorded, since they follow from the other changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalEntry<A: MemoryAddr, F> {
+    /// `range` was mapped with `flags`.
+    Map {
+        /// The mapped range.
+        range: AddrRange<A>,
+        /// The flags of the mapping.
+        flags: F,
+    },
+    /// `range` was unmapped.
+    Unmap {
+        /// The unmapped range.
+        range: AddrRange<A>,
+    },
+    /// The flags of `range` were changed from `old_flags` to `new_flags`.
+    Protect {
+        /// The protected range.
+        range: AddrRange<A>,
+        /// The flags before the change.
+        old_flags: F,
+        /// The flags after the change.
+        new_flags: F,
+    },
+}
+
+impl<A: MemoryAddr, F> JournalEntry<A, F> {
+    /// Returns the range changed by the entry.
+    pub const fn range(&self) -> AddrRange<A> {
+        match self {
+            Self::Map { range, .. } | Self::Unmap { range } | Self::Protect { range, .. } => *range,
+        }
+    }
+}
+
+impl<A: MemoryAddr, F: TryFrom<u64>> JournalEntry<A, F> {
+    /// Parses an entry from a line of a textual journal.
+    ///
+    /// The format is `map START END FLAGS`, `unmap START END` or
+    /// `protect START END OLD_FLAGS NEW_FLAGS`, with numbers in hexadecimal
+    /// (an optional `0x` prefix is allowed).
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut words = line.split_whitespace();
+        let op = words.next()?;
+        let mut num = || {
+            let word = words.next()?;
+            u64::from_str_radix(word.trim_start_matches("0x"), 16).ok()
+        };
+        let mut range = || {
+            let start = usize::try_from(num()?).ok()?;
+            let end = usize::try_from(num()?).ok()?;
+            AddrRange::try_new(A::from(start), A::from(end))
+        };
+        let entry = match op {
+            "map" => Self::Map {
+                range: range()?,
+                flags: num()?.try_into().ok()?,
+            },
+            "unmap" => Self::Unmap { range: range()? },
+            "protect" => Self::Protect {
+                range: range()?,
+                old_flags: num()?.try_into().ok()?,
+                new_flags: num()?.try_into().ok()?,
+            },
+            _ => return None,
+        };
+        if words.next().is_some() {
+            return None;
+        }
+        Some(entry)
+    }
+}
+
+/// Formats the entry in the textual form read by
+/// [`JournalEntry::parse`].
+impl<A: MemoryAddr, F: fmt::LowerHex> fmt::Display for JournalEntry<A, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let range = self.range();
+        let (start, end): (usize, usize) = (range.start.into(), range.end.into());
+        match self {
+            Self::Map { flags, .. } => write!(f, "map {start:#x} {end:#x} {flags:#x}"),
+            Self::Unmap { .. } => write!(f, "unmap {start:#x} {end:#x}"),
+            Self::Protect {
+                old_flags,
+                new_flags,
+                ..
+            } => write!(
+                f,
+                "protect {start:#x} {end:#x} {old_flags:#x} {new_flags:#x}"
+            ),
+        }
+    }
+}
+
+/// A [`MapObserver`] recording the changes to a set as [`JournalEntry`]s.
+///
+/// The clones of a journal share its entries, so one clone can be given to
+/// [`MemorySet::with_observer`](crate::MemorySet::with_observer) while
+/// another one reads what was recorded.
+pub struct Journal<A: MemoryAddr, F> {
+    entries: Arc<Mutex<Vec<JournalEntry<A, F>>>>,
+}
+
+impl<A: MemoryAddr, F: Copy> Journal<A, F> {
+    /// Creates an empty journal.
+    pub fn new() -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Returns the number of recorded entries.
+    pub fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    /// Returns whether nothing was recorded.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns a copy of the recorded entries, in the order of the changes.
+    pub fn entries(&self) -> Vec<JournalEntry<A, F>> {
+        self.entries.lock().clone()
+    }
+
+    /// Removes and returns the recorded entries, in the order of the changes.
+    pub fn take(&self) -> Vec<JournalEntry<A, F>> {
+        core::mem::take(&mut *self.entries.lock())
+    }
+
+    fn record(&self, entry: JournalEntry<A, F>) {
+        self.entries.lock().push(entry);
+    }
+}
+
+impl<A: MemoryAddr, F> Clone for Journal<A, F> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+        }
+    }
+}
+
+impl<A: MemoryAddr, F: Copy> Default for Journal<A, F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<B: MappingBackend> MapObserver<B> for Journal<B::Addr, B::Flags> {
+    fn on_map(&mut self, range: AddrRange<B::Addr>, flags: B::Flags) {
+        self.record(JournalEntry::Map { range, flags });
+    }
+
+    fn on_unmap(&mut self, range: AddrRange<B::Addr>) {
+        self.record(JournalEntry::Unmap { range });
+    }
+
+    fn on_protect(&mut self, range: AddrRange<B::Addr>, old_flags: B::Flags, new_flags: B::Flags) {
+        self.record(JournalEntry::Protect {
+            range,
+            old_flags,
+            new_flags,
+        });
+    }
+}
+
+/// Parses a textual journal with [`JournalEntry::parse`], skipping empty
+/// lines and lines starting with `#`.
+///
+/// Returns the number of the first malformed line (starting from 1) on error.
+pub fn parse_journal<A: MemoryAddr, F: TryFrom<u64>>(
+    text: &str,
+) -> Result<Vec<JournalEntry<A, F>>, usize> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            let line = line.trim();
+            !line.is_empty() && !line.starts_with('#')
+        })
+        .map(|(i, line)| JournalEntry::parse(line).ok_or(i + 1))
+        .collect()
+}
+
+/// Formats entries into a textual journal, one per line.
+pub fn format_journal<A: MemoryAddr, F: fmt::LowerHex>(entries: &[JournalEntry<A, F>]) -> String {
+    use core::fmt::Write;
+    let mut out = String::new();
+    for entry in entries {
+        let _ = writeln!(out, "{entry}");
+    }
+    out
+}
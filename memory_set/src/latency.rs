@@ -0,0 +1,177 @@
+//! Latency histograms of the operations of a [`MemorySet`], measured with a
+//! caller-supplied cycle counter.
+
+use alloc::sync::Arc;
+
+use crate::{MappingBackend, MemorySet};
+
+/// An operation whose latency is measured, see [`MemorySet::latency`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatencyOp {
+    /// [`MemorySet::map`] and [`MemorySet::map_with_mode`].
+    Map,
+    /// [`MemorySet::unmap`] and [`MemorySet::unmap_with_mode`].
+    Unmap,
+    /// [`MemorySet::protect`] and [`MemorySet::protect_with_mode`].
+    Protect,
+}
+
+/// A compact histogram of latencies in cycles, with one bucket per power of
+/// two.
+///
+/// Percentiles are reported as the upper bound of the bucket they fall in,
+/// so they are within a factor of two of the exact value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// Bucket `i` counts the latencies of `i` significant bits.
+    buckets: [u64; 65],
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+
+/// The summary of a [`LatencyHistogram`], e.g., to export it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencySummary {
+    /// The number of measured operations.
+    pub count: u64,
+    /// The mean latency.
+    pub mean: u64,
+    /// The median latency.
+    pub p50: u64,
+    /// The 90th percentile.
+    pub p90: u64,
+    /// The 99th percentile.
+    pub p99: u64,
+    /// The highest latency.
+    pub max: u64,
+}
+
+impl LatencyHistogram {
+    /// Creates an empty histogram.
+    pub const fn new() -> Self {
+        Self {
+            buckets: [0; 65],
+            count: 0,
+            sum: 0,
+            max: 0,
+        }
+    }
+
+    /// Records a latency of `cycles`.
+    pub fn record(&mut self, cycles: u64) {
+        let bucket = (u64::BITS - cycles.leading_zeros()) as usize;
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(cycles);
+        self.max = self.max.max(cycles);
+    }
+
+    /// Returns the number of recorded latencies.
+    pub const fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the highest recorded latency, or `0` if there is none.
+    pub const fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Returns the mean latency, or `0` if there is none.
+    pub fn mean(&self) -> u64 {
+        self.sum.checked_div(self.count).unwrap_or(0)
+    }
+
+    /// Returns the latency below which `per_mille` thousandths of the
+    /// recorded ones fall, e.g., `990` for the 99th percentile, or `0` if
+    /// there is none.
+    pub fn percentile(&self, per_mille: u64) -> u64 {
+        let rank = (self.count.saturating_mul(per_mille.min(1000)))
+            .div_ceil(1000)
+            .max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let upper = match bucket {
+                    0 => 0,
+                    64 => u64::MAX,
+                    _ => (1 << bucket) - 1,
+                };
+                return upper.min(self.max);
+            }
+        }
+        0
+    }
+
+    /// Returns the summary of the histogram.
+    pub fn summary(&self) -> LatencySummary {
+        LatencySummary {
+            count: self.count,
+            mean: self.mean(),
+            p50: self.percentile(500),
+            p90: self.percentile(900),
+            p99: self.percentile(990),
+            max: self.max,
+        }
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The latency measurements of a set.
+pub(crate) struct Latency {
+    pub(crate) counter: Option<Arc<dyn Fn() -> u64 + Send + Sync>>,
+    histograms: [LatencyHistogram; 3],
+}
+
+impl Latency {
+    pub(crate) const fn new(counter: Option<Arc<dyn Fn() -> u64 + Send + Sync>>) -> Self {
+        Self {
+            counter,
+            histograms: [
+                LatencyHistogram::new(),
+                LatencyHistogram::new(),
+                LatencyHistogram::new(),
+            ],
+        }
+    }
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Sets the cycle counter used to measure the latency of the
+    /// [operations](LatencyOp), e.g., reading the time-stamp counter.
+    ///
+    /// Nothing is measured until it is set. Sets cloned by `clone_cow`
+    /// inherit it, but not the measurements.
+    pub fn set_cycle_counter(&mut self, counter: impl Fn() -> u64 + Send + Sync + 'static) {
+        self.latency.counter = Some(Arc::new(counter));
+    }
+
+    /// Returns the latency histogram of `op`.
+    pub fn latency(&self, op: LatencyOp) -> &LatencyHistogram {
+        &self.latency.histograms[op as usize]
+    }
+
+    /// Clears the latency histograms, e.g., after exporting them.
+    pub fn reset_latency(&mut self) {
+        self.latency.histograms = Default::default();
+    }
+
+    /// Runs `f` on the set, recording its latency as one `op` if a cycle
+    /// counter is set.
+    pub(crate) fn timed<R>(&mut self, op: LatencyOp, f: impl FnOnce(&mut Self) -> R) -> R {
+        let Some(counter) = self.latency.counter.clone() else {
+            return f(self);
+        };
+        let begin = counter();
+        let result = f(self);
+        let cycles = counter().wrapping_sub(begin);
+        self.latency.histograms[op as usize].record(cycles);
+        result
+    }
+}
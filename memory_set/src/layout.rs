@@ -0,0 +1,96 @@
+//! Serializable layouts of a [`MemorySet`], to checkpoint an address space
+//! and restore it, or to build test fixtures.
+//!
+//! Unlike [snapshots](crate::snapshot), the layout is meant for a
+//! serialization framework, and keeps the flags and the backends themselves
+//! instead of their encodings, so that a set can be re-created from it.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{MappingBackend, MappingResult, MemoryArea, MemorySet};
+
+/// The layout of a [`MemoryArea`]: its range, flags, backend and label, but
+/// not the contents of its frames.
+///
+/// The backend serves as the descriptor of the mapping, e.g., the offset of
+/// a linear mapping, so it has to be serializable itself.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "B: Serialize, B::Flags: Serialize",
+    deserialize = "B: Deserialize<'de>, B::Flags: Deserialize<'de>"
+))]
+pub struct AreaLayout<B: MappingBackend> {
+    /// The start address.
+    pub start: usize,
+    /// The size in bytes.
+    pub size: usize,
+    /// The flags.
+    pub flags: B::Flags,
+    /// The backend.
+    pub backend: B,
+    /// The label, see [`MemoryArea::label`].
+    pub label: Option<String>,
+}
+
+/// The layout of a [`MemorySet`], returned by [`MemorySet::layout`]: the
+/// layouts of its areas in ascending order.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "B: Serialize, B::Flags: Serialize",
+    deserialize = "B: Deserialize<'de>, B::Flags: Deserialize<'de>"
+))]
+pub struct SetLayout<B: MappingBackend> {
+    /// The areas.
+    pub areas: Vec<AreaLayout<B>>,
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Returns the layout of the set, to be serialized and
+    /// [restored](Self::restore) later.
+    ///
+    /// Holes are left out, like in [snapshots](crate::snapshot).
+    pub fn layout(&self) -> SetLayout<B> {
+        let areas = self
+            .iter()
+            .filter(|area| !area.is_hole())
+            .map(|area| AreaLayout {
+                start: area.start().into(),
+                size: area.size(),
+                flags: area.flags(),
+                backend: area.backend().clone(),
+                label: area.label().map(Into::into),
+            })
+            .collect();
+        SetLayout { areas }
+    }
+
+    /// Creates a set with the areas of `layout`, mapped in `page_table`.
+    ///
+    /// The areas are mapped by their backends like new ones, so their
+    /// contents are not restored: anonymous memory comes back zeroed or
+    /// demand-faulted, and linear mappings map the same physical memory
+    /// again. If an area fails to map, e.g., because it overlaps another,
+    /// the areas mapped so far are unmapped and the error is returned.
+    pub fn restore(layout: &SetLayout<B>, page_table: &mut B::PageTable) -> MappingResult<Self> {
+        let mut set = Self::new();
+        for area in &layout.areas {
+            let mut new_area = MemoryArea::new(
+                B::Addr::from(area.start),
+                area.size,
+                #[cfg(feature = "RAII")]
+                None,
+                area.flags,
+                area.backend.clone(),
+            );
+            new_area.set_label(area.label.clone());
+            if let Err(err) = set.map(new_area, page_table, false, None) {
+                set.clear(page_table)?;
+                return Err(err);
+            }
+        }
+        Ok(set)
+    }
+}
@@ -1,28 +1,371 @@
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "test-utils")), no_std)]
 #![doc = include_str!("../README.md")]
 
 extern crate alloc;
 
+use alloc::boxed::Box;
+use core::fmt;
+
+use memory_addr::{AddrRange, MemoryAddr};
+
+#[cfg(feature = "access-count")]
+mod access;
 mod area;
+mod area_map;
 mod backend;
+#[cfg(feature = "bench")]
+pub mod bench;
+mod cursor;
+mod dirty;
+mod export;
+mod fault_future;
+#[cfg(feature = "RAII")]
+mod frames;
+mod gap;
+#[cfg(feature = "journal")]
+pub mod journal;
+#[cfg(feature = "latency")]
+mod latency;
+#[cfg(feature = "serde")]
+mod layout;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod mpu;
+mod observer;
+#[cfg(feature = "paging")]
+pub mod paging;
+mod placement;
+mod policy;
+#[cfg(feature = "RAII")]
+mod quota;
+mod reader;
+mod request;
+mod sample;
+mod scan;
 mod set;
+#[cfg(feature = "RAII")]
+mod shared;
+mod shrink;
+pub mod snapshot;
+mod static_set;
+#[cfg(feature = "RAII")]
+mod swap;
+#[cfg(feature = "sync")]
+mod sync;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+mod thp;
+mod tlb;
+#[cfg(feature = "RAII")]
+mod uaccess;
+#[cfg(feature = "RAII")]
+mod window;
+mod yielding;
 
 #[cfg(test)]
 mod tests;
 
-pub use self::area::MemoryArea;
-pub use self::backend::MappingBackend;
-pub use self::set::MemorySet;
+#[cfg(feature = "access-count")]
+pub use self::access::HotCold;
+#[cfg(feature = "RAII")]
+pub use self::area::AreaFrames;
+pub use self::area::{AreaStat, AreaTimes, MemoryArea};
+pub use self::backend::{Advice, Confidentiality, MappingBackend, MappingKind};
+pub use self::cursor::CursorMut;
+pub use self::dirty::DirtyLog;
+pub use self::export::JsonLayout;
+pub use self::fault_future::FaultFuture;
+#[cfg(feature = "RAII")]
+pub use self::frames::FrameMap;
+#[cfg(feature = "latency")]
+pub use self::latency::{LatencyHistogram, LatencyOp, LatencySummary};
+#[cfg(feature = "serde")]
+pub use self::layout::{AreaLayout, SetLayout};
+#[cfg(feature = "mmap")]
+pub use self::mmap::{MmapObject, SyncMode};
+pub use self::mpu::MpuConstraints;
+pub use self::observer::MapObserver;
+pub use self::placement::{BestFit, FirstFit, NearestFit, PlacementStrategy, Random, TopDown};
+pub use self::policy::InterleavePolicy;
+pub use self::reader::{AreaView, SetReader};
+pub use self::request::{MapRequest, RequestLimits, RequestMode};
+pub use self::sample::{SampledStats, StatsSampler};
+pub use self::scan::ScanCursor;
+#[cfg(feature = "RAII")]
+pub use self::set::ExtractMode;
+pub use self::set::{
+    DetachedAreas, FreeAreaConstraint, GapMode, MapMode, MemorySet, MemorySetStat, Populated,
+    Protected, RemapFlags, SetLabel,
+};
+#[cfg(feature = "RAII")]
+pub use self::shared::SharedFrames;
+#[cfg(feature = "RAII")]
+pub use self::shrink::SetShrinker;
+pub use self::shrink::{Shrinker, ShrinkerId, ShrinkerRegistry};
+pub use self::static_set::{StaticArea, StaticMemorySet};
+#[cfg(feature = "RAII")]
+pub use self::swap::{SwapBackend, SwapSlot};
+#[cfg(feature = "sync")]
+pub use self::sync::SyncMemorySet;
+pub use self::thp::ThpPolicy;
+pub use self::tlb::{TlbBatch, TlbCache, TlbEntry};
+#[cfg(feature = "RAII")]
+pub use self::uaccess::{Pod, UserPtr, UserSlice};
+#[cfg(feature = "RAII")]
+pub use self::window::{PinnedPages, RemapWindow};
+pub use self::yielding::YieldAction;
+
+/// The error of a [`MappingBackend`], as carried by
+/// [`MappingError::BadState`].
+pub type BackendError = Box<dyn core::error::Error + Send + Sync>;
 
 /// Error type for memory mapping operations.
-#[derive(Debug, Eq, PartialEq)]
+///
+/// Every variant carries the range of addresses the operation failed on,
+/// see [`MappingError::range`]. Two errors are equal if they have the same
+/// variant and range, whatever their backend errors.
+#[derive(Debug)]
 pub enum MappingError {
     /// Invalid parameter (e.g., `addr`, `size`, `flags`, etc.)
-    InvalidParam,
+    InvalidParam(AddrRange<usize>),
     /// The given range overlaps with an existing mapping.
-    AlreadyExists,
-    /// The backend page table is in a bad state.
-    BadState,
+    AlreadyExists(AddrRange<usize>),
+    /// The backend page table is in a bad state, with the error of the
+    /// backend if it failed.
+    BadState(AddrRange<usize>, Option<BackendError>),
+    /// The address is not mapped by any area.
+    NotMapped(AddrRange<usize>),
+    /// The access is not allowed by the flags of the area, or the area is
+    /// sealed.
+    PermissionDenied(AddrRange<usize>),
+    /// A hardware or configured limit (e.g., the number of MPU regions) would
+    /// be exceeded.
+    LimitExceeded(AddrRange<usize>),
+    /// The address is not aligned as required.
+    Misaligned(AddrRange<usize>),
+    /// The range is outside of the allowed limit.
+    OutOfRange(AddrRange<usize>),
+    /// The total size of the areas would exceed the limit of the set, see
+    /// [`MemorySet::set_size_limit`].
+    QuotaExceeded(AddrRange<usize>),
+    /// A fault would need a frame beyond the frame quota of the set, see
+    /// `MemorySet::set_frame_quota`.
+    OutOfFrames(AddrRange<usize>),
+}
+
+impl MappingError {
+    /// Returns the range of addresses the operation failed on.
+    pub fn range(&self) -> AddrRange<usize> {
+        match *self {
+            Self::InvalidParam(range)
+            | Self::AlreadyExists(range)
+            | Self::BadState(range, _)
+            | Self::NotMapped(range)
+            | Self::PermissionDenied(range)
+            | Self::LimitExceeded(range)
+            | Self::Misaligned(range)
+            | Self::OutOfRange(range)
+            | Self::QuotaExceeded(range)
+            | Self::OutOfFrames(range) => range,
+        }
+    }
+
+    /// Returns the name of the variant, e.g., `"NotMapped"`.
+    fn name(&self) -> &'static str {
+        match self {
+            Self::InvalidParam(_) => "InvalidParam",
+            Self::AlreadyExists(_) => "AlreadyExists",
+            Self::BadState(..) => "BadState",
+            Self::NotMapped(_) => "NotMapped",
+            Self::PermissionDenied(_) => "PermissionDenied",
+            Self::LimitExceeded(_) => "LimitExceeded",
+            Self::Misaligned(_) => "Misaligned",
+            Self::OutOfRange(_) => "OutOfRange",
+            Self::QuotaExceeded(_) => "QuotaExceeded",
+            Self::OutOfFrames(_) => "OutOfFrames",
+        }
+    }
+
+    /// Records that a [`BadState`](Self::BadState) error happened in `step`
+    /// of `op`, while working on `area`, by wrapping its source into an
+    /// [`ErrorContext`]. Other errors are returned as they are.
+    ///
+    /// Contexts added at each level of a nested failure form a chain, which
+    /// is printed by [`Display`](fmt::Display) and can be walked with
+    /// [`Error::source`](core::error::Error::source).
+    pub fn with_context(
+        self,
+        op: &'static str,
+        step: &'static str,
+        area: AddrRange<usize>,
+    ) -> Self {
+        match self {
+            Self::BadState(range, source) => Self::BadState(
+                range,
+                Some(Box::new(ErrorContext {
+                    owner: None,
+                    op,
+                    step,
+                    area,
+                    source,
+                })),
+            ),
+            err => err,
+        }
+    }
+
+    /// Attributes the outermost context of a [`BadState`](Self::BadState)
+    /// error to the set labeled `owner`, unless it already is. Other errors
+    /// are returned as they are.
+    pub(crate) fn with_owner(mut self, owner: Option<&SetLabel>) -> Self {
+        if let (Some(owner), Self::BadState(_, Some(source))) = (owner, &mut self)
+            && let Some(context) = source.downcast_mut::<ErrorContext>()
+            && context.owner.is_none()
+        {
+            context.owner = Some(owner.clone());
+        }
+        self
+    }
+
+    /// Returns the outermost context of a [`BadState`](Self::BadState)
+    /// error, see [`with_context`](Self::with_context).
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            Self::BadState(_, Some(err)) => err.downcast_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// Where a [`MappingError::BadState`] happened: the operation, the step of
+/// it that failed and the area it was working on, e.g., the `"shrink right"`
+/// step of an `"unmap"`, see [`MappingError::with_context`].
+#[derive(Debug)]
+pub struct ErrorContext {
+    /// The [label](MemorySet::label) of the set, if it has one.
+    pub owner: Option<SetLabel>,
+    /// The operation of the set, e.g., `"unmap"`.
+    pub op: &'static str,
+    /// The step of the operation that failed.
+    pub step: &'static str,
+    /// The range of the area the step was working on.
+    pub area: AddrRange<usize>,
+    /// The error of the step, either the error of the backend or an inner
+    /// context.
+    pub source: Option<BackendError>,
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(owner) = &self.owner {
+            write!(f, "{owner}: ")?;
+        }
+        write!(
+            f,
+            "{} failed to {} area [{:#x}, {:#x})",
+            self.op, self.step, self.area.start, self.area.end
+        )?;
+        if let Some(err) = &self.source {
+            write!(f, ": {err}")?;
+        }
+        Ok(())
+    }
+}
+
+impl core::error::Error for ErrorContext {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|err| err as &(dyn core::error::Error + 'static))
+    }
+}
+
+/// Why a range failed [`MemorySet::check_access`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessError {
+    /// The first part of the range not covered by any area.
+    NotMapped(AddrRange<usize>),
+    /// The range of the first area not allowing the access.
+    PermissionDenied(AddrRange<usize>),
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        MappingError::from(*self).fmt(f)
+    }
+}
+
+impl core::error::Error for AccessError {}
+
+impl From<AccessError> for MappingError {
+    fn from(err: AccessError) -> Self {
+        match err {
+            AccessError::NotMapped(range) => Self::NotMapped(range),
+            AccessError::PermissionDenied(range) => Self::PermissionDenied(range),
+        }
+    }
+}
+
+impl PartialEq for MappingError {
+    fn eq(&self, other: &Self) -> bool {
+        self.name() == other.name() && self.range() == other.range()
+    }
+}
+
+impl Eq for MappingError {}
+
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let range = self.range();
+        write!(
+            f,
+            "{} at [{:#x}, {:#x})",
+            self.name(),
+            range.start,
+            range.end
+        )?;
+        if let Self::BadState(_, Some(err)) = self {
+            write!(f, ": {err}")?;
+        }
+        Ok(())
+    }
+}
+
+impl core::error::Error for MappingError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            Self::BadState(_, Some(err)) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the untyped range of `size` bytes from `start` for a
+/// [`MappingError`], clipped to the end of the address space.
+pub(crate) fn err_range<A: MemoryAddr>(start: A, size: usize) -> AddrRange<usize> {
+    let start = start.into();
+    AddrRange {
+        start,
+        end: start.saturating_add(size),
+    }
+}
+
+/// Returns the untyped `range` for a [`MappingError`].
+pub(crate) fn untyped<A: MemoryAddr>(range: AddrRange<A>) -> AddrRange<usize> {
+    AddrRange {
+        start: range.start.into(),
+        end: range.end.into(),
+    }
+}
+
+/// Returns the [`MappingError::BadState`] of a backend failing on `size`
+/// bytes from `start`.
+pub(crate) fn backend_error<A: MemoryAddr>(
+    start: A,
+    size: usize,
+    err: impl core::error::Error + Send + Sync + 'static,
+) -> MappingError {
+    MappingError::BadState(err_range(start, size), Some(Box::new(err)))
 }
 
 /// A [`Result`] type with [`MappingError`] as the error type.
@@ -0,0 +1,246 @@
+//! File-backed memory areas, whose pages are demand-faulted from an
+//! [`MmapObject`], like `mmap` of a file.
+
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use memory_addr::{AddrRange, MemoryAddr};
+
+use crate::{MappingBackend, MappingResult, MemoryArea, MemorySet};
+
+/// How [`MemorySet::sync`] waits for the pages written back, like the flags
+/// of `msync`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Waits for the pages to reach the object (`MS_SYNC`).
+    Sync,
+    /// Only starts writing the pages back (`MS_ASYNC`).
+    Async,
+}
+
+/// An object that can be mapped by file-backed areas, e.g., a file with its
+/// page cache, created with [`MemoryArea::new_file`].
+///
+/// It is shared by the areas mapping it, possibly in several address spaces,
+/// so it takes `&self` and has to synchronize itself.
+pub trait MmapObject<B: MappingBackend> {
+    /// Returns the frame holding the page at `offset` bytes into the object,
+    /// which is aligned to the frame size, reading it in if needed.
+    ///
+    /// Shared mappings map the returned frame itself, so it should be the
+    /// same for all the calls with the same offset (e.g., the page cache)
+    /// for them to stay coherent. Returns `None` if the page is past the end
+    /// of the object.
+    fn page(&self, offset: usize) -> Option<B::FrameTrackerRef>;
+
+    /// Writes back the pages within `[offset, offset + size)` of the object,
+    /// for [`MemorySet::msync`]. Does nothing by default.
+    fn sync(&self, _offset: usize, _size: usize) {}
+
+    /// Writes back the dirty pages within `[offset, offset + size)` of the
+    /// object, for [`MemorySet::sync`], waiting for them to be written if
+    /// `mode` is [`SyncMode::Sync`]. Same as [`sync`](Self::sync) by
+    /// default.
+    fn write_back(&self, offset: usize, size: usize, _mode: SyncMode) {
+        self.sync(offset, size);
+    }
+}
+
+/// The object mapped by a file-backed area, the offset in it of the start of
+/// the area, and whether writes reach the object.
+pub(crate) struct FileMapping<B: MappingBackend> {
+    pub(crate) object: Arc<dyn MmapObject<B> + Send + Sync>,
+    pub(crate) offset: usize,
+    pub(crate) shared: bool,
+}
+
+impl<B: MappingBackend> Clone for FileMapping<B> {
+    fn clone(&self) -> Self {
+        Self {
+            object: self.object.clone(),
+            offset: self.offset,
+            shared: self.shared,
+        }
+    }
+}
+
+impl<B: MappingBackend> FileMapping<B> {
+    /// Returns whether `next`, mapped right after an area of `size` bytes
+    /// mapping `self`, continues the same object in the same way.
+    pub(crate) fn is_continued_by(&self, size: usize, next: &Self) -> bool {
+        Arc::ptr_eq(&self.object, &next.object)
+            && self.offset + size == next.offset
+            && self.shared == next.shared
+    }
+
+    /// Returns the frame to map for the page at `offset` bytes into the
+    /// area: the page of the object for shared mappings, or a private copy
+    /// of it.
+    pub(crate) fn frame(&self, offset: usize) -> Option<B::FrameTrackerRef> {
+        let frame = self.object.page(self.offset + offset)?;
+        if self.shared {
+            Some(frame)
+        } else {
+            Some(B::copy_frame(&frame, 0))
+        }
+    }
+}
+
+impl<B: MappingBackend> MemoryArea<B> {
+    /// Creates a memory area mapping `object` from `offset` bytes into it,
+    /// like `mmap` of a file.
+    ///
+    /// The area is mapped with the backend like any other, which should
+    /// leave its pages to be demand-faulted:
+    /// [`handle_fault`](Self::handle_fault) gets each page from
+    /// [`MmapObject::page`] and installs it with
+    /// [`MappingBackend::map_frame`]. If `shared` (`MAP_SHARED`), the frame
+    /// of the object itself is mapped, so writes reach the object and are
+    /// seen by the other shared mappings, and it is never copied nor swapped
+    /// out. Otherwise (`MAP_PRIVATE`), each page gets a private copy on its
+    /// first fault.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `offset` is not aligned to the frame size.
+    pub fn new_file(
+        start: B::Addr,
+        size: usize,
+        object: Arc<dyn MmapObject<B> + Send + Sync>,
+        offset: usize,
+        shared: bool,
+        flags: B::Flags,
+        backend: B,
+    ) -> Self {
+        let mut area = Self::new(start, size, None, flags, backend);
+        assert!(
+            offset.is_multiple_of(area.frame_size()),
+            "misaligned file offset"
+        );
+        area.file = Some(FileMapping {
+            object,
+            offset,
+            shared,
+        });
+        area
+    }
+
+    /// Returns the object mapped by the area and the offset in it of the
+    /// start of the area, if the area was created with
+    /// [`new_file`](Self::new_file).
+    pub fn file_object(&self) -> Option<(&Arc<dyn MmapObject<B> + Send + Sync>, usize)> {
+        self.file.as_ref().map(|file| (&file.object, file.offset))
+    }
+
+    /// Returns whether the page containing `vaddr` is resident and was
+    /// written through a shared file mapping since it was last written back
+    /// by [`MemorySet::sync`].
+    pub fn is_file_dirty(&self, vaddr: B::Addr) -> bool {
+        let page = vaddr.align_down(self.frame_size());
+        self.file.as_ref().is_some_and(|file| file.shared)
+            && self.frames.contains_key(&page)
+            && self
+                .file_dirty
+                .as_ref()
+                .is_none_or(|dirty| dirty.contains(&page))
+    }
+
+    /// Writes back the dirty pages of a shared file mapping within `range`,
+    /// and returns the number of pages written back.
+    ///
+    /// The pages are write-protected first, so that a write racing with the
+    /// write-back faults and marks its page dirty again.
+    fn sync_file(
+        &mut self,
+        range: AddrRange<B::Addr>,
+        mode: SyncMode,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult<usize> {
+        if !self.file.as_ref().is_some_and(|file| file.shared) || self.is_reserved() {
+            return Ok(0);
+        }
+        let size = self.frame_size();
+        let start = range.start.max(self.start()).align_down(size);
+        let end = range.end.min(self.end());
+        let resident: BTreeSet<_> = self.frames.iter().map(|(&page, _)| page).collect();
+        let dirty = self.file_dirty.get_or_insert(resident);
+        let mut pages = dirty.split_off(&start);
+        dirty.append(&mut pages.split_off(&end));
+        let mut runs: Vec<AddrRange<B::Addr>> = Vec::new();
+        for page in pages {
+            match runs.last_mut() {
+                Some(run) if run.end == page => run.end = page.add(size),
+                _ => runs.push(AddrRange::from_start_size(page, size)),
+            }
+        }
+        self.write_protected = true;
+        for run in &runs {
+            self.backend_write_protect(run.start, run.size(), self.flags(), page_table)?;
+        }
+        let file = self.file.as_ref().unwrap();
+        for run in &runs {
+            let offset = file.offset + run.start.sub_addr(self.start());
+            file.object.write_back(offset, run.size(), mode);
+        }
+        Ok(runs.iter().map(|run| run.size() / size).sum())
+    }
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Writes back the pages of the shared file-backed areas within
+    /// `[start, start + size)` with [`MmapObject::sync`], like `msync`.
+    ///
+    /// The range must be fully covered by areas, otherwise
+    /// [`MappingError::NotMapped`](crate::MappingError::NotMapped) is
+    /// returned and nothing is done. Private mappings and other areas are
+    /// skipped.
+    pub fn msync(&self, start: B::Addr, size: usize) -> MappingResult {
+        let range = self.granular_range(start, size)?;
+        self.check_covered(range)?;
+        for area in self.iter_range(range) {
+            let Some(file) = area.file.as_ref().filter(|file| file.shared) else {
+                continue;
+            };
+            let sync_start = range.start.max(area.start());
+            let sync_end = range.end.min(area.end());
+            file.object.sync(
+                file.offset + sync_start.sub_addr(area.start()),
+                sync_end.sub_addr(sync_start),
+            );
+        }
+        Ok(())
+    }
+
+    /// Writes back the dirty pages of the shared file-backed areas within
+    /// `[start, start + size)` with [`MmapObject::write_back`], like `msync`
+    /// with `MS_SYNC` or `MS_ASYNC`, and returns the number of pages written
+    /// back.
+    ///
+    /// Unlike [`msync`](Self::msync), only the pages written through the set
+    /// since they were last synced are written back, see
+    /// [`MemoryArea::is_file_dirty`]. They are write-protected, so that the
+    /// next write to them faults and marks them dirty again. The range must
+    /// be fully covered by areas, otherwise
+    /// [`MappingError::NotMapped`](crate::MappingError::NotMapped) is
+    /// returned and nothing is done. Private mappings and other areas are
+    /// skipped.
+    pub fn sync(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        mode: SyncMode,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult<usize> {
+        let range = self.granular_range(start, size)?;
+        self.check_covered(range)?;
+        self.bump_generation();
+        let candidates: Vec<_> = self.area_starts_in(range).collect();
+        let mut pages = 0;
+        for area_start in candidates {
+            let area = self.areas.get_mut(&area_start).unwrap();
+            pages += area.sync_file(range, mode, page_table)?;
+        }
+        Ok(pages)
+    }
+}
@@ -0,0 +1,35 @@
+use memory_addr::{AddrRange, MemoryAddr};
+
+/// Region constraints of a memory protection unit (MPU), for targets without
+/// an MMU (e.g., Cortex-M/R).
+///
+/// A [`MemorySet`](crate::MemorySet) created with
+/// [`with_mpu`](crate::MemorySet::with_mpu) keeps every area programmable as
+/// a single MPU region: the number of areas is bounded, each area's size is a
+/// power of two and its start is aligned to its size. Operations that would
+/// split an area are rejected. The backend programs the regions through its
+/// `PageTable` type, which is the MPU register state in this mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MpuConstraints {
+    /// The number of regions the MPU provides.
+    pub max_regions: usize,
+    /// The minimum region size. Must be a power of two.
+    pub min_region_size: usize,
+}
+
+impl MpuConstraints {
+    /// Creates the constraints of an MPU with `max_regions` regions of at
+    /// least `min_region_size` bytes.
+    pub const fn new(max_regions: usize, min_region_size: usize) -> Self {
+        Self {
+            max_regions,
+            min_region_size,
+        }
+    }
+
+    /// Returns whether `range` can be programmed as a single region.
+    pub fn is_valid_region<A: MemoryAddr>(&self, range: AddrRange<A>) -> bool {
+        let size = range.size();
+        size.is_power_of_two() && size >= self.min_region_size && range.start.is_aligned(size)
+    }
+}
@@ -0,0 +1,34 @@
+use memory_addr::AddrRange;
+
+use crate::MappingBackend;
+
+/// Observes the changes to the address space of a
+/// [`MemorySet`](crate::MemorySet), e.g., for debuggers, tracing tools or
+/// dirty logging.
+///
+/// An observer is set with
+/// [`MemorySet::with_observer`](crate::MemorySet::with_observer) and is
+/// called after each change succeeds, by
+/// [`map`](crate::MemorySet::map), [`unmap`](crate::MemorySet::unmap),
+/// [`protect`](crate::MemorySet::protect) and their variants, and by
+/// [`CursorMut`](crate::CursorMut). All the methods do nothing by default.
+pub trait MapObserver<B: MappingBackend> {
+    /// Called when `range` is mapped with `flags`.
+    fn on_map(&mut self, _range: AddrRange<B::Addr>, _flags: B::Flags) {}
+
+    /// Called when `range` is unmapped.
+    fn on_unmap(&mut self, _range: AddrRange<B::Addr>) {}
+
+    /// Called when the flags of `range` are changed from `old_flags` to
+    /// `new_flags`.
+    fn on_protect(
+        &mut self,
+        _range: AddrRange<B::Addr>,
+        _old_flags: B::Flags,
+        _new_flags: B::Flags,
+    ) {
+    }
+
+    /// Called when the area over `range` is split into two at `pos`.
+    fn on_split(&mut self, _range: AddrRange<B::Addr>, _pos: B::Addr) {}
+}
@@ -0,0 +1,522 @@
+//! [`MappingBackend`]s over a hardware page table, e.g., the `PageTable64` of
+//! `page_table_multiarch`, for the usual linear and allocated mappings of a
+//! kernel.
+//!
+//! The backends only use the few per-page operations of [`PagingOps`], so
+//! that any page table can be plugged in with a handful of forwarding
+//! methods. With the `page-table-multiarch` feature, they are implemented for
+//! the `PageTable64` of `page_table_multiarch` by `PageTable64Ops`:
+//!
+//! ```ignore
+//! type Ops = PageTable64Ops<Sv39MetaData<VirtAddr>, Rv64PTE, Handler, Frame>;
+//!
+//! let backend = Linear::<Ops>::new(PHYS_VIRT_OFFSET);
+//! let area = MemoryArea::new(start, size, None, PteFlags(flags), backend);
+//! ```
+
+#[cfg(feature = "RAII")]
+use alloc::collections::BTreeMap;
+use alloc::string::ToString;
+#[cfg(feature = "RAII")]
+use alloc::sync::Arc;
+use core::marker::PhantomData;
+
+use memory_addr::{MemoryAddr, PhysAddr, VirtAddr};
+
+use crate::MappingBackend;
+#[cfg(feature = "RAII")]
+use crate::MappingKind;
+
+/// The page table operations the backends of [`paging`](self) are built on.
+///
+/// It is implemented by a marker type of the user rather than by the page
+/// table itself, so that it can be implemented for page tables of other
+/// crates. The operations work on single base pages and must flush the TLB
+/// entries they change.
+pub trait PagingOps {
+    /// The page table type.
+    type PageTable;
+    /// The flags of the page table entries.
+    type Flags: Copy + ToString;
+    /// The error of the operations.
+    type Error: core::error::Error + Send + Sync + 'static;
+    /// The frames allocated by [`Alloc`].
+    #[cfg(feature = "RAII")]
+    type Frame: memory_addr::FrameTracker;
+
+    /// Maps the page at `vaddr` to the frame at `paddr`.
+    fn map_page(
+        page_table: &mut Self::PageTable,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        flags: Self::Flags,
+    ) -> Result<(), Self::Error>;
+
+    /// Unmaps the page at `vaddr`, which is mapped.
+    fn unmap_page(page_table: &mut Self::PageTable, vaddr: VirtAddr) -> Result<(), Self::Error>;
+
+    /// Changes the flags of the page at `vaddr`, which is mapped.
+    fn protect_page(
+        page_table: &mut Self::PageTable,
+        vaddr: VirtAddr,
+        flags: Self::Flags,
+    ) -> Result<(), Self::Error>;
+
+    /// Returns the frame the page at `vaddr` is mapped to, if any.
+    fn query(page_table: &Self::PageTable, vaddr: VirtAddr) -> Option<PhysAddr>;
+
+    /// Returns the error of a [`Linear`] mapping of `vaddr`, which is below
+    /// the offset of the mapping and so has no physical address.
+    fn no_phys_addr(vaddr: VirtAddr) -> Self::Error;
+}
+
+/// Maps every page of `[start, start + size)` with `map`, unmapping the pages
+/// mapped so far if one fails.
+fn map_all<P: PagingOps>(
+    page_table: &mut P::PageTable,
+    start: VirtAddr,
+    size: usize,
+    page_size: usize,
+    mut map: impl FnMut(&mut P::PageTable, VirtAddr) -> Result<(), P::Error>,
+) -> Result<(), P::Error> {
+    for offset in (0..size).step_by(page_size) {
+        if let Err(err) = map(page_table, start.add(offset)) {
+            for done in (0..offset).step_by(page_size) {
+                // The page was just mapped, this is not expected to fail.
+                let _ = P::unmap_page(page_table, start.add(done));
+            }
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Calls `f` on every mapped page of `[start, start + size)`.
+fn for_each_mapped<P: PagingOps>(
+    page_table: &mut P::PageTable,
+    start: VirtAddr,
+    size: usize,
+    page_size: usize,
+    mut f: impl FnMut(&mut P::PageTable, VirtAddr) -> Result<(), P::Error>,
+) -> Result<(), P::Error> {
+    for offset in (0..size).step_by(page_size) {
+        let vaddr = start.add(offset);
+        if P::query(page_table, vaddr).is_some() {
+            f(page_table, vaddr)?;
+        }
+    }
+    Ok(())
+}
+
+/// A linear mapping, where the virtual addresses are mapped to the physical
+/// addresses at a fixed offset, e.g., the direct map of a kernel or MMIO
+/// regions.
+///
+/// The whole range is mapped eagerly, and there are no frames to track.
+pub struct Linear<P: PagingOps> {
+    pa_va_offset: usize,
+    _ops: PhantomData<P>,
+}
+
+impl<P: PagingOps> Linear<P> {
+    /// Creates a linear backend mapping `vaddr` to `vaddr - pa_va_offset`.
+    pub const fn new(pa_va_offset: usize) -> Self {
+        Self {
+            pa_va_offset,
+            _ops: PhantomData,
+        }
+    }
+
+    /// Returns the offset between the virtual and physical addresses.
+    pub const fn pa_va_offset(&self) -> usize {
+        self.pa_va_offset
+    }
+
+    /// Returns the physical address of `vaddr`, if it is not below the
+    /// offset.
+    fn phys_addr(&self, vaddr: VirtAddr) -> Option<PhysAddr> {
+        vaddr
+            .as_usize()
+            .checked_sub(self.pa_va_offset)
+            .map(PhysAddr::from)
+    }
+
+    fn map_linear(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        flags: P::Flags,
+        page_table: &mut P::PageTable,
+    ) -> Result<(), P::Error> {
+        let pstart = self
+            .phys_addr(start)
+            .ok_or_else(|| P::no_phys_addr(start))?;
+        map_all::<P>(
+            page_table,
+            start,
+            size,
+            Self::BASE_PAGE_SIZE,
+            |pt, vaddr| P::map_page(pt, vaddr, pstart.add(vaddr - start), flags),
+        )
+    }
+}
+
+impl<P: PagingOps> Clone for Linear<P> {
+    fn clone(&self) -> Self {
+        Self::new(self.pa_va_offset)
+    }
+}
+
+impl<P: PagingOps> MappingBackend for Linear<P> {
+    type Addr = VirtAddr;
+    type Flags = P::Flags;
+    type PageTable = P::PageTable;
+    type Error = P::Error;
+
+    #[cfg(feature = "RAII")]
+    type FrameTrackerImpl = P::Frame;
+    #[cfg(feature = "RAII")]
+    type FrameTrackerRef = Arc<P::Frame>;
+
+    #[cfg(feature = "RAII")]
+    fn map(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        flags: P::Flags,
+        page_table: &mut P::PageTable,
+    ) -> Result<BTreeMap<VirtAddr, Arc<P::Frame>>, P::Error> {
+        self.map_linear(start, size, flags, page_table)?;
+        Ok(BTreeMap::new())
+    }
+
+    #[cfg(not(feature = "RAII"))]
+    fn map(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        flags: P::Flags,
+        page_table: &mut P::PageTable,
+    ) -> Result<(), P::Error> {
+        self.map_linear(start, size, flags, page_table)
+    }
+
+    fn unmap(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        page_table: &mut P::PageTable,
+    ) -> Result<(), P::Error> {
+        for_each_mapped::<P>(page_table, start, size, Self::BASE_PAGE_SIZE, P::unmap_page)
+    }
+
+    fn protect(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        new_flags: P::Flags,
+        page_table: &mut P::PageTable,
+    ) -> Result<(), P::Error> {
+        for_each_mapped::<P>(
+            page_table,
+            start,
+            size,
+            Self::BASE_PAGE_SIZE,
+            |pt, vaddr| P::protect_page(pt, vaddr, new_flags),
+        )
+    }
+
+    fn translate(&self, vaddr: VirtAddr) -> Option<(PhysAddr, usize)> {
+        Some((self.phys_addr(vaddr)?, Self::BASE_PAGE_SIZE))
+    }
+
+    fn kind(&self) -> &'static str {
+        "linear"
+    }
+}
+
+/// A mapping to frames allocated with [`FrameTracker::alloc_frame`], either
+/// all at once when the area is mapped, or one by one as the pages are
+/// first accessed.
+///
+/// The frames are tracked by the areas, and freed when they are dropped.
+///
+/// [`FrameTracker::alloc_frame`]: memory_addr::FrameTracker::alloc_frame
+#[cfg(feature = "RAII")]
+pub struct Alloc<P: PagingOps> {
+    populate: bool,
+    _ops: PhantomData<P>,
+}
+
+#[cfg(feature = "RAII")]
+impl<P: PagingOps> Alloc<P> {
+    /// Creates an allocating backend, which allocates all the frames when
+    /// mapping if `populate` is `true`, or on page faults otherwise.
+    pub const fn new(populate: bool) -> Self {
+        Self {
+            populate,
+            _ops: PhantomData,
+        }
+    }
+
+    /// Returns whether the frames are allocated when mapping.
+    pub const fn populate(&self) -> bool {
+        self.populate
+    }
+
+    fn alloc_page(
+        vaddr: VirtAddr,
+        flags: P::Flags,
+        page_table: &mut P::PageTable,
+    ) -> Result<Arc<P::Frame>, P::Error> {
+        use memory_addr::FrameTracker;
+
+        let frame = P::Frame::alloc_frame();
+        P::map_page(page_table, vaddr, frame.start(), flags)?;
+        Ok(Arc::new(frame))
+    }
+}
+
+#[cfg(feature = "RAII")]
+impl<P: PagingOps> Clone for Alloc<P> {
+    fn clone(&self) -> Self {
+        Self::new(self.populate)
+    }
+}
+
+#[cfg(feature = "RAII")]
+impl<P: PagingOps> MappingBackend for Alloc<P> {
+    type Addr = VirtAddr;
+    type Flags = P::Flags;
+    type PageTable = P::PageTable;
+    type Error = P::Error;
+
+    type FrameTrackerImpl = P::Frame;
+    type FrameTrackerRef = Arc<P::Frame>;
+
+    fn map(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        flags: P::Flags,
+        page_table: &mut P::PageTable,
+    ) -> Result<BTreeMap<VirtAddr, Arc<P::Frame>>, P::Error> {
+        let mut frames = BTreeMap::new();
+        if self.populate {
+            // The frames mapped before a failure are freed with `frames`.
+            map_all::<P>(
+                page_table,
+                start,
+                size,
+                Self::BASE_PAGE_SIZE,
+                |pt, vaddr| {
+                    frames.insert(vaddr, Self::alloc_page(vaddr, flags, pt)?);
+                    Ok(())
+                },
+            )?;
+        }
+        Ok(frames)
+    }
+
+    /// Only the pages faulted in are unmapped, the frames are freed by the
+    /// area.
+    fn unmap(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        page_table: &mut P::PageTable,
+    ) -> Result<(), P::Error> {
+        for_each_mapped::<P>(page_table, start, size, Self::BASE_PAGE_SIZE, P::unmap_page)
+    }
+
+    fn protect(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        new_flags: P::Flags,
+        page_table: &mut P::PageTable,
+    ) -> Result<(), P::Error> {
+        for_each_mapped::<P>(
+            page_table,
+            start,
+            size,
+            Self::BASE_PAGE_SIZE,
+            |pt, vaddr| P::protect_page(pt, vaddr, new_flags),
+        )
+    }
+
+    fn map_frame(
+        &self,
+        vaddr: VirtAddr,
+        frame: &P::Frame,
+        flags: P::Flags,
+        page_table: &mut P::PageTable,
+    ) -> Result<bool, P::Error> {
+        use memory_addr::FrameTracker;
+
+        if P::query(page_table, vaddr).is_some() {
+            P::unmap_page(page_table, vaddr)?;
+        }
+        P::map_page(page_table, vaddr, frame.start(), flags)?;
+        Ok(true)
+    }
+
+    /// Allocates the frame of a page that is not mapped yet. Faults on mapped
+    /// pages are spurious and resolved as is.
+    fn handle_fault(
+        &self,
+        vaddr: VirtAddr,
+        _access_flags: P::Flags,
+        area_flags: P::Flags,
+        page_table: &mut P::PageTable,
+    ) -> Result<Option<Arc<P::Frame>>, Option<P::Error>> {
+        let page = vaddr.align_down(Self::BASE_PAGE_SIZE);
+        if P::query(page_table, page).is_some() {
+            return Ok(None);
+        }
+        Self::alloc_page(page, area_flags, page_table)
+            .map(Some)
+            .map_err(Some)
+    }
+
+    fn kind(&self) -> &'static str {
+        if self.populate { "alloc" } else { "lazy" }
+    }
+
+    fn mapping_kind(&self) -> MappingKind {
+        MappingKind::Anonymous
+    }
+}
+
+#[cfg(feature = "page-table-multiarch")]
+pub use self::multiarch::{PageTable64Ops, PageTableError, PteFlags};
+
+#[cfg(feature = "page-table-multiarch")]
+mod multiarch {
+    use core::fmt;
+    use core::marker::PhantomData;
+
+    use memory_addr::{PhysAddr, VirtAddr};
+    use page_table_multiarch::{
+        GenericPTE, MappingFlags, PageSize, PageTable64, PagingError, PagingHandler, PagingMetaData,
+    };
+
+    use super::PagingOps;
+
+    /// The [`PagingOps`] of the `PageTable64` of `page_table_multiarch`, with
+    /// the metadata `M`, the entries `PTE` and the handler `H` of the page
+    /// table, and the frames `F` allocated by [`Alloc`](super::Alloc).
+    ///
+    /// The pages are mapped as 4K pages, and the TLB entries are flushed
+    /// with [`PagingMetaData::flush_tlb`].
+    pub struct PageTable64Ops<M, PTE, H, F = ()>(PhantomData<(M, PTE, H, F)>);
+
+    /// The flags of a [`PageTable64Ops`] mapping, which are shown in the dumps
+    /// of the areas.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct PteFlags(pub MappingFlags);
+
+    impl From<MappingFlags> for PteFlags {
+        fn from(flags: MappingFlags) -> Self {
+            Self(flags)
+        }
+    }
+
+    impl fmt::Display for PteFlags {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            fmt::Debug::fmt(&self.0, f)
+        }
+    }
+
+    /// The error of a [`PageTable64Ops`] operation.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum PageTableError {
+        /// The error of the page table.
+        Paging(PagingError),
+        /// The virtual address has no physical address in a linear mapping.
+        NoPhysAddr(VirtAddr),
+    }
+
+    impl From<PagingError> for PageTableError {
+        fn from(err: PagingError) -> Self {
+            Self::Paging(err)
+        }
+    }
+
+    impl fmt::Display for PageTableError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Self::Paging(PagingError::NoMemory) => f.write_str("no memory for page tables"),
+                Self::Paging(PagingError::NotAligned) => f.write_str("address not aligned"),
+                Self::Paging(PagingError::NotMapped) => f.write_str("page not mapped"),
+                Self::Paging(PagingError::AlreadyMapped) => f.write_str("page already mapped"),
+                Self::Paging(PagingError::MappedToHugePage) => {
+                    f.write_str("page mapped to a huge page")
+                }
+                Self::NoPhysAddr(vaddr) => write!(f, "no physical address for {vaddr:#x}"),
+            }
+        }
+    }
+
+    impl core::error::Error for PageTableError {}
+
+    // The frames are only bounded with `RAII`, and attributes are not allowed
+    // in `where` clauses.
+    macro_rules! impl_paging_ops {
+        ($($frame_bound:tt)*) => {
+        impl<M, PTE, H, F> PagingOps for PageTable64Ops<M, PTE, H, F>
+        where
+            M: PagingMetaData<VirtAddr = VirtAddr>,
+            PTE: GenericPTE,
+            H: PagingHandler,
+            $($frame_bound)*
+        {
+            type PageTable = PageTable64<M, PTE, H>;
+            type Flags = PteFlags;
+            type Error = PageTableError;
+            #[cfg(feature = "RAII")]
+            type Frame = F;
+
+            fn map_page(
+                page_table: &mut Self::PageTable,
+                vaddr: VirtAddr,
+                paddr: PhysAddr,
+                flags: PteFlags,
+            ) -> Result<(), PageTableError> {
+                page_table.map(vaddr, paddr, PageSize::Size4K, flags.0)?.flush();
+                Ok(())
+            }
+
+            fn unmap_page(
+                page_table: &mut Self::PageTable,
+                vaddr: VirtAddr,
+            ) -> Result<(), PageTableError> {
+                page_table.unmap(vaddr)?.2.flush();
+                Ok(())
+            }
+
+            fn protect_page(
+                page_table: &mut Self::PageTable,
+                vaddr: VirtAddr,
+                flags: PteFlags,
+            ) -> Result<(), PageTableError> {
+                page_table.protect(vaddr, flags.0)?.1.flush();
+                Ok(())
+            }
+
+            fn query(page_table: &Self::PageTable, vaddr: VirtAddr) -> Option<PhysAddr> {
+                page_table.query(vaddr).ok().map(|(paddr, ..)| paddr)
+            }
+
+            fn no_phys_addr(vaddr: VirtAddr) -> PageTableError {
+                PageTableError::NoPhysAddr(vaddr)
+            }
+        }
+        };
+    }
+
+    #[cfg(feature = "RAII")]
+    impl_paging_ops!(F: memory_addr::FrameTracker);
+    #[cfg(not(feature = "RAII"))]
+    impl_paging_ops!();
+}
@@ -0,0 +1,128 @@
+use memory_addr::AddrRange;
+
+use crate::{MappingBackend, MemorySet};
+
+/// A strategy for choosing where to place a new area in a [`MemorySet`].
+///
+/// Strategies can be used per call with
+/// [`MemorySet::find_free_area_with`], or set as the default of a set with
+/// [`MemorySet::set_placement`], so that different kinds of regions (e.g.,
+/// anonymous `mmap`, shared libraries, device windows) can use different
+/// policies.
+pub trait PlacementStrategy<B: MappingBackend> {
+    /// Returns the start of a free area of `size` bytes within `limit`, or
+    /// `None` if there is no room.
+    ///
+    /// `hint` is the preferred address, whose meaning depends on the
+    /// strategy.
+    fn place(
+        &mut self,
+        set: &MemorySet<B>,
+        hint: B::Addr,
+        size: usize,
+        limit: AddrRange<B::Addr>,
+    ) -> Option<B::Addr>;
+}
+
+/// Places the area at the lowest address at or above `hint` where it fits,
+/// like [`MemorySet::find_free_area`]. This is the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FirstFit;
+
+impl<B: MappingBackend> PlacementStrategy<B> for FirstFit {
+    fn place(
+        &mut self,
+        set: &MemorySet<B>,
+        hint: B::Addr,
+        size: usize,
+        limit: AddrRange<B::Addr>,
+    ) -> Option<B::Addr> {
+        set.find_free_area(hint, size, limit)
+    }
+}
+
+/// Places the area at the start of the smallest gap at or above `hint`
+/// where it fits, to keep the large gaps intact. Ties go to the lowest gap.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BestFit;
+
+impl<B: MappingBackend> PlacementStrategy<B> for BestFit {
+    fn place(
+        &mut self,
+        set: &MemorySet<B>,
+        hint: B::Addr,
+        size: usize,
+        limit: AddrRange<B::Addr>,
+    ) -> Option<B::Addr> {
+        let start = hint.max(limit.start).min(limit.end);
+        set.free_ranges(AddrRange::new(start, limit.end))
+            .filter(|gap| gap.size() >= size)
+            .min_by_key(|gap| gap.size())
+            .map(|gap| gap.start)
+    }
+}
+
+/// Places the area at the highest address where it ends at or below `hint`,
+/// like [`MemorySet::find_free_area_topdown`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TopDown;
+
+impl<B: MappingBackend> PlacementStrategy<B> for TopDown {
+    fn place(
+        &mut self,
+        set: &MemorySet<B>,
+        hint: B::Addr,
+        size: usize,
+        limit: AddrRange<B::Addr>,
+    ) -> Option<B::Addr> {
+        set.find_free_area_topdown(hint, size, limit)
+    }
+}
+
+/// Places the area as close as possible to `hint`, above or below it, like
+/// [`MemorySet::find_free_area_nearest`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NearestFit;
+
+impl<B: MappingBackend> PlacementStrategy<B> for NearestFit {
+    fn place(
+        &mut self,
+        set: &MemorySet<B>,
+        hint: B::Addr,
+        size: usize,
+        limit: AddrRange<B::Addr>,
+    ) -> Option<B::Addr> {
+        set.find_free_area_nearest(hint, size, limit)
+    }
+}
+
+/// Places the area at a random `align`-aligned address, like
+/// [`MemorySet::find_free_area_randomized`], ignoring `hint`.
+///
+/// `rng` is called once per placement and should return a random number.
+#[derive(Debug, Clone, Copy)]
+pub struct Random<R> {
+    align: usize,
+    rng: R,
+}
+
+impl<R: FnMut() -> usize> Random<R> {
+    /// Creates a strategy placing areas at multiples of `align` (a power of
+    /// two), using `rng` as the source of randomness.
+    pub fn new(align: usize, rng: R) -> Self {
+        debug_assert!(align.is_power_of_two());
+        Self { align, rng }
+    }
+}
+
+impl<B: MappingBackend, R: FnMut() -> usize> PlacementStrategy<B> for Random<R> {
+    fn place(
+        &mut self,
+        set: &MemorySet<B>,
+        _hint: B::Addr,
+        size: usize,
+        limit: AddrRange<B::Addr>,
+    ) -> Option<B::Addr> {
+        set.find_free_area_randomized(size, limit, self.align, &mut self.rng)
+    }
+}
@@ -0,0 +1,76 @@
+use alloc::vec::Vec;
+
+/// A weighted interleaving policy for spreading frame allocations of a
+/// [`MemoryArea`](crate::MemoryArea) over several frame sources (e.g., NUMA
+/// nodes or memory tiers).
+///
+/// Like `numactl --interleave`, sources are used in a round-robin manner,
+/// except that source `i` receives `weights[i]` consecutive pages per round.
+/// A weight of `0` excludes the source.
+#[derive(Debug, Clone)]
+pub struct InterleavePolicy {
+    weights: Vec<usize>,
+    /// The source the next page is allocated from.
+    current: usize,
+    /// Pages left for `current` in this round.
+    remaining: usize,
+    /// Pages allocated from each source.
+    allocated: Vec<usize>,
+}
+
+impl InterleavePolicy {
+    /// Creates a policy with the given per-source weights.
+    ///
+    /// Returns `None` if all weights are `0`.
+    pub fn new(weights: Vec<usize>) -> Option<Self> {
+        let current = weights.iter().position(|&w| w > 0)?;
+        Some(Self {
+            remaining: weights[current],
+            allocated: alloc::vec![0; weights.len()],
+            weights,
+            current,
+        })
+    }
+
+    /// Creates a policy that uses each of the `sources` equally.
+    ///
+    /// Returns `None` if `sources` is `0`.
+    pub fn uniform(sources: usize) -> Option<Self> {
+        Self::new(alloc::vec![1; sources])
+    }
+
+    /// Returns the per-source weights.
+    pub fn weights(&self) -> &[usize] {
+        &self.weights
+    }
+
+    /// Returns the source to allocate the next page from, and accounts the
+    /// page to it.
+    pub fn next_source(&mut self) -> usize {
+        if self.remaining == 0 {
+            let n = self.weights.len();
+            self.current = (1..=n)
+                .map(|i| (self.current + i) % n)
+                .find(|&i| self.weights[i] > 0)
+                .unwrap();
+            self.remaining = self.weights[self.current];
+        }
+        self.remaining -= 1;
+        self.allocated[self.current] += 1;
+        self.current
+    }
+
+    /// Returns the number of pages allocated from each source so far.
+    pub fn pages_per_source(&self) -> &[usize] {
+        &self.allocated
+    }
+
+    /// Returns a copy of the policy with the page statistics cleared, for a
+    /// newly split-off area.
+    pub(crate) fn fork(&self) -> Self {
+        Self {
+            allocated: alloc::vec![0; self.weights.len()],
+            ..self.clone()
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Frame quotas of a [`MemorySet`], like the memory limits of cgroups,
+//! enforced where the frames enter the set on faults.
+
+use memory_addr::MemoryAddr;
+
+use crate::{MappingBackend, MappingError, MappingResult, MemoryArea, MemorySet, err_range};
+
+/// The frame quota of a set, and its usage as of a generation of the set.
+pub(crate) struct FrameQuota {
+    limit: usize,
+    usage: Option<(u64, usize)>,
+}
+
+impl FrameQuota {
+    pub(crate) const fn new(limit: usize) -> Self {
+        Self { limit, usage: None }
+    }
+
+    /// Returns the same quota for another set, whose usage is counted anew.
+    pub(crate) const fn fork(&self) -> Self {
+        Self::new(self.limit)
+    }
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Sets or removes the quota of the set in bytes of frames, like the
+    /// memory limit of a cgroup.
+    ///
+    /// The [frame usage](Self::frame_usage) grows as frames enter the set
+    /// and shrinks as they are released, e.g., by unmapping or swapping
+    /// out. A fault on a page without a frame of its own, through
+    /// [`handle_page_fault`](Self::handle_page_fault),
+    /// [`populate`](Self::populate), [`read_bytes`](Self::read_bytes) or the
+    /// like, fails with [`MappingError::OutOfFrames`] before allocating if
+    /// the quota has no room for one more frame, so that the caller can
+    /// reclaim memory (e.g., with [`swap_out`](Self::swap_out)) and retry,
+    /// or kill the owner. Frames entering otherwise (e.g., mapped eagerly or
+    /// inserted) are counted but not refused, so the usage may exceed the
+    /// quota. Sets cloned by `clone_cow` inherit it.
+    pub fn set_frame_quota(&mut self, quota: Option<usize>) {
+        self.frame_quota = quota.map(FrameQuota::new);
+    }
+
+    /// Returns the frame quota of the set in bytes, if any.
+    pub fn frame_quota(&self) -> Option<usize> {
+        self.frame_quota.as_ref().map(|quota| quota.limit)
+    }
+
+    /// Returns the size of the frames counted against the
+    /// [frame quota](Self::set_frame_quota), i.e., the resident size of the
+    /// set without the zero pages, as in [`stat`](Self::stat).
+    pub fn frame_usage(&self) -> usize {
+        match &self.frame_quota {
+            Some(FrameQuota {
+                usage: Some((generation, usage)),
+                ..
+            }) if *generation == self.generation => *usage,
+            _ => self.stat().rss,
+        }
+    }
+
+    /// Handles a fault at `vaddr` in the area starting at `area_start`,
+    /// refusing it if it may need a frame beyond the quota of the set.
+    pub(crate) fn fault_in_quota(
+        &mut self,
+        area_start: B::Addr,
+        vaddr: B::Addr,
+        access_flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        let Some(limit) = self.frame_quota.as_ref().map(|quota| quota.limit) else {
+            let area = self.areas.get_mut(&area_start).unwrap();
+            return area.handle_fault(vaddr, access_flags, page_table);
+        };
+        let usage = self.frame_usage();
+        let area = self.areas.get_mut(&area_start).unwrap();
+        let page = vaddr.align_down(area.page_size());
+        let before = area.charged_size(page);
+        if before == 0 && usage + area.frame_size() > limit {
+            return Err(MappingError::OutOfFrames(err_range(
+                page,
+                area.frame_size(),
+            )));
+        }
+        let result = area.handle_fault(vaddr, access_flags, page_table);
+        let after = area.charged_size(page);
+        let generation = self.generation;
+        if let Some(quota) = &mut self.frame_quota {
+            quota.usage = Some((generation, (usage + after).saturating_sub(before)));
+        }
+        result
+    }
+}
+
+impl<B: MappingBackend> MemoryArea<B> {
+    /// Returns the size of the frame of `page` counted against the frame
+    /// quota of the set, i.e., zero if it has none or it is the zero frame.
+    fn charged_size(&self, page: B::Addr) -> usize {
+        match self.frames.get(&page) {
+            Some(frame) if !self.is_zero_frame(frame) => self.frame_size(),
+            _ => 0,
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Looking up the layout of a [`MemorySet`] without its lock, e.g., on the
+//! fault path while another thread maps or unmaps.
+//!
+//! A [`SetReader`] is an immutable view of the areas, validated against the
+//! set like a seqlock: a lookup in it holds if the view is still
+//! [current](SetReader::is_current) after the lookup. Otherwise, the caller
+//! falls back to the set under its lock, and takes a new reader there.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use memory_addr::AddrRange;
+
+use crate::{MappingBackend, MappingError, MappingResult, MemorySet, err_range};
+
+/// An area as seen by a [`SetReader`]: its range, flags and backend.
+#[derive(Clone)]
+pub struct AreaView<B: MappingBackend> {
+    va_range: AddrRange<B::Addr>,
+    flags: B::Flags,
+    backend: B,
+}
+
+impl<B: MappingBackend> AreaView<B> {
+    /// Returns the virtual address range.
+    pub const fn va_range(&self) -> AddrRange<B::Addr> {
+        self.va_range
+    }
+
+    /// Returns the start address of the area.
+    pub const fn start(&self) -> B::Addr {
+        self.va_range.start
+    }
+
+    /// Returns the end address of the area.
+    pub const fn end(&self) -> B::Addr {
+        self.va_range.end
+    }
+
+    /// Returns the memory flags, e.g., the permission bits.
+    pub const fn flags(&self) -> B::Flags {
+        self.flags
+    }
+
+    /// Returns the mapping backend of the area.
+    pub const fn backend(&self) -> &B {
+        &self.backend
+    }
+}
+
+impl<B: MappingBackend> fmt::Debug for AreaView<B>
+where
+    B::Addr: fmt::Debug,
+    B::Flags: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AreaView")
+            .field("va_range", &self.va_range)
+            .field("flags", &self.flags)
+            .finish()
+    }
+}
+
+/// An immutable view of the areas of a [`MemorySet`], returned by
+/// [`MemorySet::reader`], to look them up without the lock of the set.
+///
+/// The view is shared, so cloning a reader is cheap, e.g., to keep one per
+/// CPU. It becomes stale when the set changes its layout or any other state
+/// than the pages of an area: faults keep it current, while mapping,
+/// unmapping or protecting, but also populating or advising, make it stale.
+#[derive(Clone)]
+pub struct SetReader<B: MappingBackend> {
+    areas: Arc<[AreaView<B>]>,
+    generation: u64,
+    published: Arc<AtomicU64>,
+}
+
+impl<B: MappingBackend> SetReader<B> {
+    /// Returns whether the set has not changed since the view was taken.
+    ///
+    /// Check it after a lookup in the view: if it no longer holds, the
+    /// lookup may have raced with a change and has to be redone.
+    pub fn is_current(&self) -> bool {
+        self.published.load(Ordering::Acquire) == self.generation
+    }
+
+    /// Returns the number of areas in the view.
+    pub fn len(&self) -> usize {
+        self.areas.len()
+    }
+
+    /// Returns whether the view has no area.
+    pub fn is_empty(&self) -> bool {
+        self.areas.is_empty()
+    }
+
+    /// Returns an iterator over the areas in the view, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = &AreaView<B>> {
+        self.areas.iter()
+    }
+
+    /// Finds the area containing `vaddr` in the view.
+    pub fn find(&self, vaddr: B::Addr) -> Option<&AreaView<B>> {
+        let index = self.areas.partition_point(|area| area.end() <= vaddr);
+        self.areas
+            .get(index)
+            .filter(|area| area.va_range.contains(vaddr))
+    }
+
+    /// Checks a fault at `vaddr` with `access_flags` against the view, like
+    /// [`MemorySet::handle_page_fault`] does before handling it.
+    ///
+    /// Returns [`MappingError::NotMapped`] if no area contains `vaddr`, and
+    /// [`MappingError::PermissionDenied`] if its flags do not allow the
+    /// access. The result is only valid if the view
+    /// [is still current](Self::is_current) afterwards.
+    pub fn check_fault(&self, vaddr: B::Addr, access_flags: B::Flags) -> MappingResult {
+        let area = self
+            .find(vaddr)
+            .ok_or(MappingError::NotMapped(err_range(vaddr, 1)))?;
+        if !area.backend.check_access(area.flags, access_flags) {
+            return Err(MappingError::PermissionDenied(err_range(vaddr, 1)));
+        }
+        Ok(())
+    }
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Returns a view of the current areas, to look them up without the
+    /// lock of the set, see [`SetReader`].
+    ///
+    /// It copies the range, flags and backend of every area, so it is meant
+    /// to be taken again only once the previous reader is stale. It takes
+    /// `&mut self` to set up, on the first call, the counter the set
+    /// publishes its changes to.
+    pub fn reader(&mut self) -> SetReader<B> {
+        let published = self
+            .published
+            .get_or_insert_with(|| Arc::new(AtomicU64::new(self.layout_generation)))
+            .clone();
+        let areas: Vec<_> = self
+            .areas
+            .values()
+            .map(|area| AreaView {
+                va_range: area.va_range(),
+                flags: area.flags(),
+                backend: area.backend().clone(),
+            })
+            .collect();
+        SetReader {
+            areas: areas.into(),
+            generation: self.layout_generation,
+            published,
+        }
+    }
+}
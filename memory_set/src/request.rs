@@ -0,0 +1,150 @@
+//! Validation of untrusted mapping requests, e.g., the arguments of an
+//! `mmap` system call, in one place.
+
+use memory_addr::{AddrRange, MemoryAddr};
+
+use crate::{MapMode, MappingBackend, MappingError, MappingResult, MemorySet, err_range};
+
+/// How the address of a mapping request is interpreted, like the `MAP_FIXED`
+/// flags of `mmap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestMode {
+    /// The address is only a hint, dropped if it cannot be honored.
+    Hint,
+    /// The mapping must be at the address, replacing whatever is there
+    /// (`MAP_FIXED`).
+    Fixed,
+    /// The mapping must be at the address, and must not replace anything
+    /// (`MAP_FIXED_NOREPLACE`).
+    FixedNoReplace,
+}
+
+/// The limits enforced on mapping requests by
+/// [`MemorySet::validate_request`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestLimits {
+    /// The page size the lengths are rounded up to and the fixed addresses
+    /// must be aligned to, a power of two.
+    pub page_size: usize,
+    /// The lowest address a mapping may start at, like `mmap_min_addr`.
+    pub min_addr: usize,
+    /// The highest address a mapping may end at.
+    pub max_end: usize,
+}
+
+impl RequestLimits {
+    /// Creates limits with the given page size, allowing the whole address
+    /// space.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `page_size` is not a power of two.
+    pub const fn new(page_size: usize) -> Self {
+        assert!(page_size.is_power_of_two(), "page size not a power of two");
+        Self {
+            page_size,
+            min_addr: 0,
+            max_end: usize::MAX,
+        }
+    }
+}
+
+/// A mapping request canonicalized by [`MemorySet::validate_request`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapRequest<A: MemoryAddr, F> {
+    /// The start address, or `None` if the mapping may be placed anywhere
+    /// within the limits, e.g., with
+    /// [`find_free_area`](MemorySet::find_free_area). For
+    /// [`RequestMode::Hint`], it is the aligned hint, which the caller may
+    /// still have to give up if it is taken.
+    pub start: Option<A>,
+    /// The length rounded up to whole pages.
+    pub size: usize,
+    /// The flags of the mapping, e.g., the permission bits.
+    pub flags: F,
+    /// How to handle the existing mappings at `start`.
+    pub mode: MapMode<A>,
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Returns the limits enforced by
+    /// [`validate_request`](Self::validate_request).
+    pub const fn request_limits(&self) -> RequestLimits {
+        self.request_limits
+    }
+
+    /// Sets the limits enforced by
+    /// [`validate_request`](Self::validate_request). By default, the page
+    /// size is [`MappingBackend::MIN_GRANULARITY`] and the whole address
+    /// space is allowed.
+    pub fn set_request_limits(&mut self, limits: RequestLimits) {
+        self.request_limits = limits;
+    }
+
+    /// Checks an untrusted mapping request of `len` bytes at `addr` with
+    /// `flags`, and returns its canonical form.
+    ///
+    /// The length is rounded up to whole pages and must not be zero,
+    /// otherwise [`MappingError::InvalidParam`] is returned. For the fixed
+    /// modes, `addr` must be aligned to the page size
+    /// ([`MappingError::Misaligned`]), must not be below the minimum address
+    /// ([`MappingError::PermissionDenied`]), and the mapping must not wrap
+    /// around nor end past the limit ([`MappingError::OutOfRange`]). For
+    /// [`RequestMode::Hint`], a hint breaking these rules is dropped
+    /// instead, and so is a null hint. The existing mappings are not looked
+    /// at.
+    pub fn validate_request(
+        &self,
+        addr: B::Addr,
+        len: usize,
+        flags: B::Flags,
+        mode: RequestMode,
+    ) -> MappingResult<MapRequest<B::Addr, B::Flags>> {
+        let limits = self.request_limits;
+        if len == 0 {
+            return Err(MappingError::InvalidParam(err_range(addr, 0)));
+        }
+        let size = len
+            .checked_next_multiple_of(limits.page_size)
+            .ok_or(MappingError::OutOfRange(err_range(addr, len)))?;
+        let start: usize = addr.into();
+        let end = start.checked_add(size);
+        let check = || {
+            let range = AddrRange::new(start, end.unwrap_or(usize::MAX));
+            if !start.is_multiple_of(limits.page_size) {
+                Err(MappingError::Misaligned(range))
+            } else if start < limits.min_addr {
+                Err(MappingError::PermissionDenied(range))
+            } else if end.is_none_or(|end| end > limits.max_end) {
+                Err(MappingError::OutOfRange(range))
+            } else {
+                Ok(())
+            }
+        };
+        let (start, mode) = match mode {
+            RequestMode::Fixed => {
+                check()?;
+                (Some(addr), MapMode::Fixed)
+            }
+            RequestMode::FixedNoReplace => {
+                check()?;
+                let limit = AddrRange::new(limits.min_addr.into(), limits.max_end.into());
+                let mode = MapMode::FixedNoReplace {
+                    limit,
+                    align: limits.page_size,
+                };
+                (Some(addr), mode)
+            }
+            RequestMode::Hint => {
+                let hint = (start != 0 && check().is_ok()).then_some(addr);
+                (hint, MapMode::NoReplace)
+            }
+        };
+        Ok(MapRequest {
+            start,
+            size,
+            flags,
+            mode,
+        })
+    }
+}
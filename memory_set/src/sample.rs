@@ -0,0 +1,137 @@
+use memory_addr::{AddrRange, MemoryAddr};
+
+use crate::{MappingBackend, MemoryArea, MemorySet};
+
+/// Statistics of a [`MemorySet`] gathered by a [`StatsSampler`], in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SampledStats {
+    /// The size of the resident pages.
+    pub rss: usize,
+    /// The size of the resident pages accessed since the previous pass.
+    pub working_set: usize,
+    /// The size of the resident pages not accessed since the previous pass.
+    pub idle: usize,
+}
+
+/// Gathers [`SampledStats`] of a [`MemorySet`] incrementally, e.g., from a
+/// timer tick.
+///
+/// Each call to [`sample`](Self::sample) scans a bounded number of resident
+/// pages (a frame larger than a page counting as one, with its whole size),
+/// resuming where the previous call stopped, so the cost of a full
+/// scan is spread over many calls. The statistics of the last complete pass
+/// are cached.
+///
+/// Whether a page was accessed is asked with
+/// [`MappingBackend::test_and_clear_accessed`], which also starts a new
+/// tracking period for the page. Pages it knows nothing about only count
+/// towards the RSS.
+pub struct StatsSampler<B: MappingBackend> {
+    cursor: B::Addr,
+    pending: SampledStats,
+    stats: SampledStats,
+    passes: u64,
+}
+
+impl<B: MappingBackend> StatsSampler<B> {
+    /// Creates a new sampler with no complete pass yet.
+    pub fn new() -> Self {
+        Self {
+            cursor: 0.into(),
+            pending: SampledStats::default(),
+            stats: SampledStats::default(),
+            passes: 0,
+        }
+    }
+
+    /// Returns the statistics of the last complete pass.
+    pub const fn stats(&self) -> SampledStats {
+        self.stats
+    }
+
+    /// Returns the number of complete passes.
+    pub const fn passes(&self) -> u64 {
+        self.passes
+    }
+
+    /// Scans up to `budget` resident pages of the set, and returns the
+    /// statistics of the last complete pass.
+    ///
+    /// When the end of the address space is reached, the pass is complete:
+    /// its statistics replace the cached ones and the next call starts over
+    /// from the lowest address.
+    pub fn sample(
+        &mut self,
+        set: &MemorySet<B>,
+        page_table: &mut B::PageTable,
+        budget: usize,
+    ) -> SampledStats {
+        let mut scanned = 0;
+        let rest = AddrRange::new(self.cursor, usize::MAX.into());
+        for area_start in set.area_starts_in(rest) {
+            let area = set.find(area_start).unwrap();
+            for page in resident_pages(area, self.cursor.max(area.start())) {
+                if scanned == budget {
+                    self.cursor = page;
+                    return self.stats;
+                }
+                scanned += 1;
+                let size = resident_size(area, page);
+                self.pending.rss += size;
+                match area.backend().test_and_clear_accessed(page, page_table) {
+                    Some(true) => self.pending.working_set += size,
+                    Some(false) => self.pending.idle += size,
+                    None => {}
+                }
+            }
+        }
+        self.stats = core::mem::take(&mut self.pending);
+        self.cursor = 0.into();
+        self.passes += 1;
+        self.stats
+    }
+}
+
+impl<B: MappingBackend> Default for StatsSampler<B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the size of the resident page at `page`, as returned by
+/// [`resident_pages`], i.e., of its whole frame if it is larger than a page.
+fn resident_size<B: MappingBackend>(area: &MemoryArea<B>, page: B::Addr) -> usize {
+    #[cfg(feature = "RAII")]
+    return area.frames.frame_size(&page);
+    #[cfg(not(feature = "RAII"))]
+    {
+        let _ = page;
+        area.page_size()
+    }
+}
+
+/// Returns the resident pages of the area, starting from the page containing
+/// `from`.
+#[cfg(feature = "RAII")]
+pub(crate) fn resident_pages<B: MappingBackend>(
+    area: &MemoryArea<B>,
+    from: B::Addr,
+) -> impl Iterator<Item = B::Addr> + '_ {
+    let from = from.align_down(area.page_size());
+    area.frames.range(from..area.end()).map(|(&page, _)| page)
+}
+
+/// Returns the resident pages of the area, starting from the page containing
+/// `from`.
+#[cfg(not(feature = "RAII"))]
+pub(crate) fn resident_pages<B: MappingBackend>(
+    area: &MemoryArea<B>,
+    from: B::Addr,
+) -> impl Iterator<Item = B::Addr> + '_ {
+    let page_size = area.page_size();
+    let from: usize = from.align_down(page_size).into();
+    (from..area.end().into())
+        .step_by(page_size)
+        .map(B::Addr::from)
+        .filter(|&page| area.is_resident(page))
+}
@@ -0,0 +1,173 @@
+//! Incremental scans over a [`MemorySet`], doing a bounded amount of work per
+//! call and resuming from a saved [`ScanCursor`], e.g., for background
+//! daemons working a bit at every tick.
+
+use memory_addr::{AddrRange, MemoryAddr};
+
+use crate::{MappingBackend, MappingResult, MemorySet};
+
+/// The saved position of an incremental scan over a range of a
+/// [`MemorySet`], e.g., by [`MemorySet::populate_step`].
+///
+/// It holds no reference to the set, so it can be kept between the steps
+/// while the set is changed. The next step goes on from the same address,
+/// whatever is mapped there by then.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanCursor<A: MemoryAddr> {
+    range: AddrRange<A>,
+    pos: A,
+}
+
+impl<A: MemoryAddr> ScanCursor<A> {
+    /// Creates a cursor at the start of `range`.
+    pub const fn new(range: AddrRange<A>) -> Self {
+        Self {
+            range,
+            pos: range.start,
+        }
+    }
+
+    /// Returns the range scanned.
+    pub const fn range(&self) -> AddrRange<A> {
+        self.range
+    }
+
+    /// Returns the address the next step starts from.
+    pub const fn position(&self) -> A {
+        self.pos
+    }
+
+    /// Returns whether the whole range has been scanned.
+    pub fn is_done(&self) -> bool {
+        self.pos >= self.range.end
+    }
+
+    /// Moves the cursor back to the start of the range, e.g., to scan it
+    /// again.
+    pub fn rewind(&mut self) {
+        self.pos = self.range.start;
+    }
+
+    /// Returns the part of the range not scanned yet.
+    pub(crate) fn remaining(&self) -> AddrRange<A> {
+        AddrRange::new(self.pos.min(self.range.end), self.range.end)
+    }
+
+    /// Moves the cursor to `pos`, which is clamped to the end of the range.
+    pub(crate) fn advance_to(&mut self, pos: A) {
+        self.pos = pos.min(self.range.end);
+    }
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Faults in at most `max_pages` pages that are not resident yet from the
+    /// position of `cursor`, as if they were accessed with `access_flags`,
+    /// and returns the number of pages faulted in.
+    ///
+    /// Same as [`populate`](Self::populate) done in steps, except that holes,
+    /// reserved areas and areas not allowing the access are skipped instead
+    /// of failing, since the set may change between the steps. The cursor is
+    /// left after the last page looked at, or where
+    /// [`MappingBackend::memory_pressure`] was reported.
+    pub fn populate_step(
+        &mut self,
+        cursor: &mut ScanCursor<B::Addr>,
+        max_pages: usize,
+        access_flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult<usize> {
+        self.bump_generation();
+        let mut pages = 0;
+        while pages < max_pages {
+            let Some(area_start) = self.area_starts_in(cursor.remaining()).next() else {
+                cursor.advance_to(cursor.range().end);
+                break;
+            };
+            let area = &self.areas[&area_start];
+            let end = cursor.range().end.min(area.end());
+            if area.is_reserved() || !area.backend().check_access(area.flags(), access_flags) {
+                cursor.advance_to(end);
+                continue;
+            }
+            let page_size = area.page_size();
+            let mut page = cursor.position().max(area.start()).align_down(page_size);
+            while page < end && pages < max_pages {
+                let area = &self.areas[&area_start];
+                if !area.is_resident(page) {
+                    if area.backend().memory_pressure() {
+                        cursor.advance_to(page);
+                        return Ok(pages);
+                    }
+                    self.fault_area(area_start, page, access_flags, page_table)?;
+                    pages += 1;
+                }
+                page = page.add(page_size);
+            }
+            cursor.advance_to(page.min(end));
+        }
+        Ok(pages)
+    }
+
+    /// Clones the areas starting from the position of `cursor` into
+    /// `new_set` copy-on-write, until at least `max_pages` pages were
+    /// cloned, and returns the number of areas cloned.
+    ///
+    /// Same as [`clone_cow`](Self::clone_cow) done in steps, e.g., to fork a
+    /// large address space without stalling: each area is cloned and
+    /// write-protected as a whole, and mapped in `new_page_table`. Areas
+    /// starting before the position are not cloned again, even if they
+    /// were merged with cloned ones in the meantime. The configuration of
+    /// the set is not copied into `new_set`.
+    #[cfg(feature = "RAII")]
+    pub fn clone_cow_step(
+        &mut self,
+        new_set: &mut Self,
+        cursor: &mut ScanCursor<B::Addr>,
+        max_pages: usize,
+        page_table: &mut B::PageTable,
+        new_page_table: &mut B::PageTable,
+    ) -> MappingResult<usize> {
+        self.bump_generation();
+        let mut pages = 0;
+        let mut cloned = 0;
+        while pages < max_pages {
+            let remaining = cursor.remaining();
+            let Some(area_start) = self
+                .areas
+                .range(remaining.start..remaining.end)
+                .next()
+                .map(|(&start, _)| start)
+            else {
+                cursor.advance_to(cursor.range().end);
+                break;
+            };
+            let area = &self.areas[&area_start];
+            let charge = if !area.is_reserved() && area.backend().charges_commit(area.flags()) {
+                2 * area.size() - area.commit_charge()
+            } else {
+                0
+            };
+            self.check_commit(area.va_range(), charge)?;
+            let area = self.areas.get_mut(&area_start).unwrap();
+            let mut new_area = area.clone_shared(area.flags());
+            let result = new_area
+                .remap_area(new_page_table)
+                .and_then(|_| new_area.write_protect(new_page_table))
+                .and_then(|_| area.write_protect(page_table));
+            let range = area.va_range();
+            pages += range.size().div_ceil(area.page_size());
+            if let Err(err) = result {
+                drop(new_area);
+                self.settle_commit();
+                return Err(err);
+            }
+            self.hand_over_commit(new_set, new_area.commit_charge());
+            new_set.areas.insert(range.start, new_area);
+            new_set.refresh_gaps(range);
+            new_set.bump_generation();
+            cloned += 1;
+            cursor.advance_to(range.end);
+        }
+        Ok(cloned)
+    }
+}
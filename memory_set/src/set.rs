@@ -1,24 +1,738 @@
+use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
 #[allow(unused_imports)] // this is a weird false alarm
 use alloc::vec::Vec;
 use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
 use memory_addr::{AddrRange, MemoryAddr};
 
-use crate::{MappingBackend, MappingError, MappingResult, MemoryArea};
+use crate::area_map::AreaMap;
+use crate::gap::GapIndex;
+#[cfg(feature = "RAII")]
+use crate::quota::FrameQuota;
+use crate::yielding::YieldHook;
+use crate::{
+    AccessError, Advice, Confidentiality, FirstFit, MapObserver, MappingBackend, MappingError,
+    MappingKind, MappingResult, MemoryArea, MpuConstraints, PlacementStrategy, RequestLimits,
+    ThpPolicy, TlbBatch, backend_error, err_range, untyped,
+};
+#[cfg(feature = "RAII")]
+use crate::{AreaFrames, SwapBackend};
+#[cfg(feature = "latency")]
+use crate::{LatencyOp, latency::Latency};
+
+/// Extra requirements on the start address returned by
+/// [`MemorySet::find_free_area_constrained`].
+///
+/// Some devices can only use addresses with specific low bits (e.g., matching
+/// the physical address for large pages), or addresses within a window (e.g.,
+/// below 4G for 32-bit DMA).
+#[derive(Debug, Clone, Copy)]
+pub struct FreeAreaConstraint<A: MemoryAddr> {
+    /// The bits of the start address that must match `value`.
+    pub mask: usize,
+    /// The required value of the masked bits.
+    pub value: usize,
+    /// The found area must end at or below this address.
+    pub max_end: Option<A>,
+}
+
+impl<A: MemoryAddr> FreeAreaConstraint<A> {
+    /// Creates a constraint that accepts any address.
+    pub const fn new() -> Self {
+        Self {
+            mask: 0,
+            value: 0,
+            max_end: None,
+        }
+    }
+
+    /// Requires `start & mask == value`.
+    ///
+    /// Bits of `value` outside `mask` are ignored.
+    pub const fn with_mask(mut self, mask: usize, value: usize) -> Self {
+        self.mask = mask;
+        self.value = value & mask;
+        self
+    }
+
+    /// Requires the found area to end at or below `max_end`.
+    pub const fn with_max_end(mut self, max_end: A) -> Self {
+        self.max_end = Some(max_end);
+        self
+    }
+
+    /// Returns whether `addr` satisfies the mask requirement.
+    pub fn matches(&self, addr: A) -> bool {
+        addr.into() & self.mask == self.value
+    }
+
+    /// Returns the lowest address that is not less than `addr` and satisfies
+    /// the mask requirement, or `None` if there is no such address.
+    fn next_match(&self, addr: A) -> Option<A> {
+        let mut x: usize = addr.into();
+        let diff = (x ^ self.value) & self.mask;
+        if diff == 0 {
+            return Some(addr);
+        }
+        // The highest masked bit that differs decides the direction.
+        let p = usize::BITS - 1 - diff.leading_zeros();
+        // The mask of the bits below `bit`, all of them for the top bit.
+        let low = |bit: u32| 1usize.checked_shl(bit).map_or(usize::MAX, |b| b - 1);
+        if self.value & (1 << p) != 0 {
+            // Set the bit and take the lowest matching suffix.
+            x = (x & !low(p + 1)) | (1 << p) | (self.value & low(p));
+        } else {
+            // Carry into the lowest free bit above `p` that is zero.
+            let free_zeros = !x & !self.mask & !low(p + 1);
+            if free_zeros == 0 {
+                return None;
+            }
+            let q = free_zeros.trailing_zeros();
+            x = (x & !low(q + 1)) | (1 << q) | (self.value & low(q));
+        }
+        Some(A::from(x))
+    }
+}
+
+impl<A: MemoryAddr> Default for FreeAreaConstraint<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How [`MemorySet::map_with_mode`] treats existing mappings in the range of
+/// the new area, like the `MAP_FIXED` flags of `mmap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapMode<A: MemoryAddr> {
+    /// Unmap anything in the range first (`MAP_FIXED`).
+    Fixed,
+    /// Fail with [`MappingError::AlreadyExists`] if anything overlaps.
+    NoReplace,
+    /// Like [`NoReplace`](Self::NoReplace) (`MAP_FIXED_NOREPLACE`), but also
+    /// fail with [`MappingError::Misaligned`] if the start is not aligned to
+    /// `align` (a power of two), and with [`MappingError::OutOfRange`] if
+    /// the area is not within `limit`.
+    FixedNoReplace {
+        /// The range the area must be within.
+        limit: AddrRange<A>,
+        /// The required alignment of the start address.
+        align: usize,
+    },
+}
+
+/// How [`MemorySet::unmap_with_mode`] and [`MemorySet::protect_with_mode`]
+/// treat the parts of the range not covered by any area.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GapMode {
+    /// Skip them, like `munmap` and `mprotect` on Linux.
+    #[default]
+    Lenient,
+    /// Fail with [`MappingError::NotMapped`] of the first hole, before
+    /// anything is done.
+    Strict,
+}
+
+/// Placement options of [`MemorySet::remap`], like the flags of `mremap`.
+#[derive(Debug, Clone, Copy)]
+pub struct RemapFlags<A: MemoryAddr> {
+    /// If the mapping cannot be resized in place, it may be moved to a free
+    /// region within this range (`MREMAP_MAYMOVE`).
+    pub may_move: Option<AddrRange<A>>,
+    /// Moves the mapping to this address, unmapping whatever is already
+    /// there (`MREMAP_FIXED`).
+    pub fixed: Option<A>,
+}
+
+impl<A: MemoryAddr> RemapFlags<A> {
+    /// Creates flags that only allow resizing in place.
+    pub const fn new() -> Self {
+        Self {
+            may_move: None,
+            fixed: None,
+        }
+    }
+
+    /// Allows moving the mapping to a free region within `limit`.
+    pub const fn with_may_move(mut self, limit: AddrRange<A>) -> Self {
+        self.may_move = Some(limit);
+        self
+    }
+
+    /// Requires the mapping to be moved to `new_start`.
+    pub const fn with_fixed(mut self, new_start: A) -> Self {
+        self.fixed = Some(new_start);
+        self
+    }
+}
+
+impl<A: MemoryAddr> Default for RemapFlags<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How [`MemorySet::extract`] copies the contents of the extracted areas.
+#[cfg(feature = "RAII")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtractMode {
+    /// Private copies of the frames are made right away.
+    Copy,
+    /// The frames are shared copy-on-write, like [`MemorySet::clone_cow`].
+    Cow,
+}
+
+/// The progress of [`MemorySet::populate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Populated<A> {
+    /// The number of pages faulted in.
+    pub pages: usize,
+    /// Where to resume if it stopped early because of memory pressure, or
+    /// `None` if the whole range is populated.
+    pub resume_at: Option<A>,
+}
+
+/// A range whose flags were changed by [`MemorySet::protect`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protected<A: MemoryAddr, F> {
+    /// The changed range.
+    pub range: AddrRange<A>,
+    /// The flags before the change.
+    pub old_flags: F,
+    /// The flags after the change.
+    pub new_flags: F,
+}
+
+/// Areas detached from a [`MemorySet`] by [`MemorySet::detach`].
+///
+/// They are no longer mapped in the page table, but still hold their frames
+/// (if RAII is on) and their commit charge until [`destroy`](Self::destroy)
+/// is called or they are dropped, so they can be put back with
+/// [`MemorySet::reattach`].
+pub struct DetachedAreas<B: MappingBackend> {
+    areas: Vec<MemoryArea<B>>,
+    /// The charge granted by the commit check for the areas.
+    committed: usize,
+    commit_release: Option<Arc<dyn Fn(usize) + Send + Sync>>,
+}
+
+impl<B: MappingBackend> DetachedAreas<B> {
+    /// Returns the detached areas, in ascending order.
+    pub fn areas(&self) -> &[MemoryArea<B>] {
+        &self.areas
+    }
+
+    /// Drops the detached areas, releasing their frames and commit charge.
+    pub fn destroy(self) {}
+}
+
+impl<B: MappingBackend> Drop for DetachedAreas<B> {
+    fn drop(&mut self) {
+        if let Some(release) = &self.commit_release
+            && self.committed > 0
+        {
+            release(self.committed);
+        }
+    }
+}
+
+/// The owner of a [`MemorySet`], used to tell address spaces apart in
+/// diagnostics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SetLabel {
+    /// A numeric id, e.g., the pid.
+    Id(u64),
+    /// A name, e.g., the process name.
+    Name(String),
+}
+
+impl From<u64> for SetLabel {
+    fn from(id: u64) -> Self {
+        Self::Id(id)
+    }
+}
+
+impl From<&str> for SetLabel {
+    fn from(name: &str) -> Self {
+        Self::Name(name.into())
+    }
+}
+
+impl From<String> for SetLabel {
+    fn from(name: String) -> Self {
+        Self::Name(name)
+    }
+}
+
+impl fmt::Display for SetLabel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Id(id) => write!(f, "#{id}"),
+            Self::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+/// Statistics of a whole [`MemorySet`], returned by [`MemorySet::stat`].
+///
+/// All sizes are in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemorySetStat {
+    /// The total virtual size of the areas.
+    pub size: usize,
+    /// The resident size, i.e., the size of the frames held by the areas.
+    pub rss: usize,
+    /// The size of the locked areas, see [`MemorySet::lock`].
+    pub locked: usize,
+    /// The swapped out size.
+    pub swap: usize,
+    /// The resident size whose frames are shared with other areas.
+    pub shared: usize,
+    /// The number of areas.
+    pub areas: usize,
+}
 
 /// A container that maintains memory mappings ([`MemoryArea`]).
 pub struct MemorySet<B: MappingBackend> {
-    areas: BTreeMap<B::Addr, MemoryArea<B>>,
+    pub(crate) areas: AreaMap<B::Addr, MemoryArea<B>>,
+    mpu: Option<MpuConstraints>,
+    pub(crate) generation: u64,
+    /// The generation of the last change other than faulting or accessing
+    /// pages.
+    pub(crate) layout_generation: u64,
+    pub(crate) published: Option<Arc<AtomicU64>>,
+    label: Option<SetLabel>,
+    gaps: GapIndex,
+    coalescing: bool,
+    placement: Option<Box<dyn PlacementStrategy<B> + Send + Sync>>,
+    commit_check: Option<Arc<dyn Fn(usize) -> bool + Send + Sync>>,
+    commit_release: Option<Arc<dyn Fn(usize) + Send + Sync>>,
+    /// The bytes taken from the commit check and not released yet.
+    committed: usize,
+    size_limit: Option<usize>,
+    clock: Option<Arc<dyn Fn() -> u64 + Send + Sync>>,
+    observer: Option<Box<dyn MapObserver<B> + Send + Sync>>,
+    pub(crate) thp_policy: ThpPolicy,
+    pub(crate) request_limits: RequestLimits,
+    pub(crate) yield_hook: Option<YieldHook<B::Addr>>,
+    #[cfg(feature = "latency")]
+    pub(crate) latency: Latency,
+    #[cfg(feature = "RAII")]
+    pub(crate) swap: Option<Arc<dyn SwapBackend + Send + Sync>>,
+    #[cfg(feature = "RAII")]
+    pub(crate) frame_quota: Option<FrameQuota>,
 }
 
 impl<B: MappingBackend> MemorySet<B> {
     /// Creates a new memory set.
     pub const fn new() -> Self {
         Self {
-            areas: BTreeMap::new(),
+            areas: AreaMap::new(),
+            mpu: None,
+            generation: 0,
+            layout_generation: 0,
+            published: None,
+            label: None,
+            gaps: GapIndex::new(),
+            coalescing: true,
+            placement: None,
+            commit_check: None,
+            commit_release: None,
+            committed: 0,
+            size_limit: None,
+            clock: None,
+            observer: None,
+            thp_policy: ThpPolicy::Never,
+            request_limits: RequestLimits::new(B::MIN_GRANULARITY),
+            yield_hook: None,
+            #[cfg(feature = "latency")]
+            latency: Latency::new(None),
+            #[cfg(feature = "RAII")]
+            swap: None,
+            #[cfg(feature = "RAII")]
+            frame_quota: None,
+        }
+    }
+
+    /// Creates a new memory set whose areas are MPU regions subject to the
+    /// given constraints.
+    pub const fn with_mpu(constraints: MpuConstraints) -> Self {
+        let mut set = Self::new();
+        set.mpu = Some(constraints);
+        set
+    }
+
+    /// Creates an empty set with the configuration of this one, except for
+    /// its label, placement strategy and observer, counting its own frame
+    /// usage.
+    fn inherit_config(&self) -> Self {
+        Self {
+            mpu: self.mpu,
+            coalescing: self.coalescing,
+            commit_check: self.commit_check.clone(),
+            commit_release: self.commit_release.clone(),
+            size_limit: self.size_limit,
+            clock: self.clock.clone(),
+            thp_policy: self.thp_policy,
+            request_limits: self.request_limits,
+            yield_hook: self.yield_hook.clone(),
+            #[cfg(feature = "latency")]
+            latency: Latency::new(self.latency.counter.clone()),
+            #[cfg(feature = "RAII")]
+            swap: self.swap.clone(),
+            #[cfg(feature = "RAII")]
+            frame_quota: self.frame_quota.as_ref().map(FrameQuota::fork),
+            ..Self::new()
+        }
+    }
+
+    /// Sets the observer of the changes to the set, see
+    /// [`set_observer`](Self::set_observer).
+    pub fn with_observer(mut self, observer: impl MapObserver<B> + Send + Sync + 'static) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Sets the observer of the changes to the set, see [`MapObserver`].
+    ///
+    /// Sets cloned by `clone_cow` or `extract` have no observer.
+    pub fn set_observer(&mut self, observer: impl MapObserver<B> + Send + Sync + 'static) {
+        self.observer = Some(Box::new(observer));
+    }
+
+    /// Returns the observer of the set, if any.
+    pub(crate) fn observer(&mut self) -> Option<&mut (dyn MapObserver<B> + Send + Sync + 'static)> {
+        self.observer.as_deref_mut()
+    }
+
+    /// Sets the owner label of the set, see [`set_label`](Self::set_label).
+    pub fn with_label(mut self, label: impl Into<SetLabel>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// Sets the owner label of the set.
+    ///
+    /// The label is shown in the debug output of the set, so that dumps of
+    /// different address spaces can be told apart.
+    pub fn set_label(&mut self, label: impl Into<SetLabel>) {
+        self.label = Some(label.into());
+    }
+
+    /// Returns the owner label of the set, if any.
+    pub fn label(&self) -> Option<&SetLabel> {
+        self.label.as_ref()
+    }
+
+    /// Attributes the [`ErrorContext`](crate::ErrorContext) of `err` (if
+    /// any) to the set.
+    fn owned(&self, err: MappingError) -> MappingError {
+        err.with_owner(self.label.as_ref())
+    }
+
+    /// Enables or disables merging adjacent compatible areas, which is enabled
+    /// by default.
+    ///
+    /// When enabled, [`map`](Self::map), [`insert`](Self::insert),
+    /// [`protect`](Self::protect), [`lock`](Self::lock) and
+    /// [`unlock`](Self::unlock) merge the areas they produce with their
+    /// neighbors if [`MappingBackend::can_merge`] allows it, so that
+    /// repeated splits do not fragment the set. Areas are never merged in MPU
+    /// mode.
+    pub fn set_coalescing(&mut self, enabled: bool) {
+        self.coalescing = enabled;
+    }
+
+    /// Sets the default [`PlacementStrategy`] of the set, used by
+    /// [`find_placement`](Self::find_placement). It is [`FirstFit`] if not
+    /// set.
+    pub fn set_placement(&mut self, strategy: impl PlacementStrategy<B> + Send + Sync + 'static) {
+        self.placement = Some(Box::new(strategy));
+    }
+
+    /// Enables strict overcommit control with the given check.
+    ///
+    /// Before [`map`](Self::map), [`commit`](Self::commit) or
+    /// `clone_cow` increases the [commit charge](Self::commit_charge),
+    /// `check` is called with the increase in bytes, and the operation fails
+    /// with [`MappingError::LimitExceeded`] if it returns `false`. The check
+    /// is typically shared by all the sets of the system, to keep the global
+    /// commit charge below a limit. Sets cloned by `clone_cow` inherit it.
+    ///
+    /// The check should be set before mapping anything, since only the
+    /// charge it has granted is given back with the release set by
+    /// [`set_commit_release`](Self::set_commit_release).
+    pub fn set_commit_check(&mut self, check: impl Fn(usize) -> bool + Send + Sync + 'static) {
+        self.commit_check = Some(Arc::new(check));
+    }
+
+    /// Sets the counterpart of the [commit check](Self::set_commit_check),
+    /// called with the bytes given back when the commit charge drops.
+    ///
+    /// After the operations that may lower the charge, e.g., unmapping,
+    /// shrinking, decommitting or clearing, and after any operation that
+    /// fails once it was charged, the charge granted by the check beyond the
+    /// current [commit charge](Self::commit_charge) is released, so the
+    /// check and the release balance out once the set is cleared. The charge
+    /// dropped by faulting pages in is released at the next such operation.
+    /// Sets cloned by `clone_cow` inherit it, and take over the charge of
+    /// their areas.
+    pub fn set_commit_release(&mut self, release: impl Fn(usize) + Send + Sync + 'static) {
+        self.commit_release = Some(Arc::new(release));
+    }
+
+    /// Returns the commit charge of the set in bytes, i.e., the memory that
+    /// may still have to be allocated for its areas, see
+    /// [`MemoryArea::commit_charge`].
+    pub fn commit_charge(&self) -> usize {
+        self.areas.values().map(|area| area.commit_charge()).sum()
+    }
+
+    /// Checks with the commit check (if any) that the commit charge can grow
+    /// by `bytes` for `range`, see [`set_commit_check`](Self::set_commit_check).
+    ///
+    /// The granted charge is accounted to the set until it is released by
+    /// [`settle_commit`](Self::settle_commit).
+    pub(crate) fn check_commit(
+        &mut self,
+        range: AddrRange<B::Addr>,
+        bytes: usize,
+    ) -> MappingResult {
+        self.take_commit(range, bytes)?;
+        if self.commit_check.is_some() {
+            self.committed += bytes;
+        }
+        Ok(())
+    }
+
+    /// Same as [`check_commit`](Self::check_commit), but leaves accounting
+    /// the granted charge to the caller.
+    fn take_commit(&self, range: AddrRange<B::Addr>, bytes: usize) -> MappingResult {
+        match &self.commit_check {
+            Some(check) if bytes > 0 && !check(bytes) => {
+                Err(MappingError::LimitExceeded(untyped(range)))
+            }
+            _ => Ok(()),
         }
     }
 
+    /// Releases the charge granted by the commit check beyond the current
+    /// commit charge, see [`set_commit_release`](Self::set_commit_release).
+    ///
+    /// It walks all the areas, so it is only done when a release is set.
+    pub(crate) fn settle_commit(&mut self) {
+        let Some(release) = &self.commit_release else {
+            return;
+        };
+        if self.committed == 0 {
+            return;
+        }
+        let charge = self.areas.values().map(MemoryArea::commit_charge).sum();
+        if charge < self.committed {
+            release(self.committed - charge);
+            self.committed = charge;
+        }
+    }
+
+    /// Releases all the charge granted to the set by the commit check, e.g.,
+    /// before discarding it.
+    #[cfg(feature = "RAII")]
+    fn release_commit(&mut self) {
+        if let Some(release) = &self.commit_release
+            && self.committed > 0
+        {
+            release(self.committed);
+        }
+        self.committed = 0;
+    }
+
+    /// Moves up to `charge` bytes of the charge granted by the commit check
+    /// from this set to `other`, e.g., once areas have been cloned or split
+    /// off into it.
+    pub(crate) fn hand_over_commit(&mut self, other: &mut Self, charge: usize) {
+        let charge = charge.min(self.committed);
+        self.committed -= charge;
+        other.committed += charge;
+    }
+
+    /// Sets or removes the limit of the total size of the areas, like
+    /// `RLIMIT_AS`.
+    ///
+    /// Operations that would grow the [total size](Self::total_size) past the
+    /// limit, e.g., mapping, reserving, growing or moving areas, fail with
+    /// [`MappingError::QuotaExceeded`]. Areas already above it are kept.
+    /// Sets cloned by `clone_cow` inherit it.
+    pub fn set_size_limit(&mut self, limit: Option<usize>) {
+        self.size_limit = limit;
+    }
+
+    /// Sets the clock for the lifetime timestamps of the areas, see
+    /// [`AreaTimes`](crate::AreaTimes).
+    ///
+    /// `clock` returns a coarse monotonic timestamp, e.g., the seconds or
+    /// ticks since boot, and is read when an area is mapped or inserted, when
+    /// [`handle_page_fault`](Self::handle_page_fault) succeeds, and when
+    /// [`protect`](Self::protect) or
+    /// [`remap_area_flags_and_base`](Self::remap_area_flags_and_base)
+    /// changes the flags of an area. Sets cloned by `clone_cow`
+    /// inherit it.
+    pub fn set_clock(&mut self, clock: impl Fn() -> u64 + Send + Sync + 'static) {
+        self.clock = Some(Arc::new(clock));
+    }
+
+    /// Reads the clock, if any, see [`set_clock`](Self::set_clock).
+    fn now(&self) -> Option<u64> {
+        self.clock.as_ref().map(|clock| clock())
+    }
+
+    /// Sets the creation time of `area` to now if it has none yet.
+    fn stamp_created(&self, area: &mut MemoryArea<B>) {
+        let times = area.times_mut();
+        if times.created.is_none() {
+            times.created = self.now();
+        }
+    }
+
+    /// Returns the limit of the total size of the areas, if any.
+    pub const fn size_limit(&self) -> Option<usize> {
+        self.size_limit
+    }
+
+    /// Returns the total size of the areas in bytes, reserved ones included
+    /// but holes excluded, i.e., the virtual memory used by the set.
+    pub fn total_size(&self) -> usize {
+        self.areas
+            .values()
+            .filter(|area| !area.is_hole())
+            .map(|area| area.size())
+            .sum()
+    }
+
+    /// Returns the range from the start of the first area to the end of the
+    /// last one, or an empty range if there is no area.
+    fn span(&self) -> AddrRange<B::Addr> {
+        match (self.areas.values().next(), self.areas.values().next_back()) {
+            (Some(first), Some(last)) => AddrRange::new(first.start(), last.end()),
+            _ => AddrRange::default(),
+        }
+    }
+
+    /// Returns the size of the parts of the areas within `range`.
+    fn size_in(&self, range: AddrRange<B::Addr>) -> usize {
+        self.iter_range(range)
+            .map(|area| {
+                let start = area.start().max(range.start);
+                area.end().min(range.end).sub_addr(start)
+            })
+            .sum()
+    }
+
+    /// Checks that adding `added` bytes of areas within `range` and removing
+    /// `removed` bytes keeps the total size within the limit (if any), see
+    /// [`set_size_limit`](Self::set_size_limit).
+    fn check_size_limit(
+        &self,
+        range: AddrRange<B::Addr>,
+        added: usize,
+        removed: usize,
+    ) -> MappingResult {
+        let Some(limit) = self.size_limit else {
+            return Ok(());
+        };
+        if added <= removed {
+            return Ok(());
+        }
+        let total = self.total_size().saturating_add(added - removed);
+        if total > limit {
+            return Err(MappingError::QuotaExceeded(untyped(range)));
+        }
+        Ok(())
+    }
+
+    /// Returns the MPU constraints of the set, if it is in MPU mode.
+    pub const fn mpu_constraints(&self) -> Option<MpuConstraints> {
+        self.mpu
+    }
+
+    /// In MPU mode, checks that the given ranges can be added as regions
+    /// after `replaced` existing regions are removed.
+    pub(crate) fn check_mpu_regions(
+        &self,
+        ranges: impl IntoIterator<Item = AddrRange<B::Addr>>,
+        replaced: usize,
+    ) -> MappingResult {
+        let Some(mpu) = self.mpu else {
+            return Ok(());
+        };
+        let mut count = self.len() - replaced;
+        let mut last = AddrRange::default();
+        for range in ranges {
+            if !mpu.is_valid_region(range) {
+                return Err(MappingError::InvalidParam(untyped(range)));
+            }
+            count += 1;
+            last = untyped(range);
+        }
+        if count > mpu.max_regions {
+            return Err(MappingError::LimitExceeded(last));
+        }
+        Ok(())
+    }
+
+    /// In MPU mode, checks that no area would be split by an operation on the
+    /// given range.
+    fn check_mpu_whole(&self, range: AddrRange<B::Addr>) -> MappingResult {
+        if self.mpu.is_some()
+            && self
+                .iter_range(range)
+                .any(|area| !area.va_range().contained_in(range))
+        {
+            return Err(MappingError::InvalidParam(untyped(range)));
+        }
+        Ok(())
+    }
+
+    /// Returns the generation of the set.
+    ///
+    /// It changes whenever the set may have been modified, including when a
+    /// mutable reference to an area is handed out, so cached translations can
+    /// be validated against it.
+    pub const fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Advances the generation, and publishes it to the
+    /// [readers](Self::reader) so that their views become stale.
+    pub(crate) fn bump_generation(&mut self) {
+        self.generation += 1;
+        self.layout_generation = self.generation;
+        if let Some(published) = &self.published {
+            published.store(self.generation, Ordering::Release);
+        }
+    }
+
+    /// Returns the statistics of the whole set, folded from
+    /// [`MemoryArea::stat`] of each area.
+    pub fn stat(&self) -> MemorySetStat {
+        self.areas.values().filter(|area| !area.is_hole()).fold(
+            MemorySetStat::default(),
+            |mut total, area| {
+                let stat = area.stat();
+                total.size += stat.size;
+                total.rss += stat.rss;
+                if area.is_locked() {
+                    total.locked += stat.size;
+                }
+                total.swap += stat.swap;
+                total.shared += stat.shared;
+                total.areas += 1;
+                total
+            },
+        )
+    }
+
     /// Returns the number of memory areas in the memory set.
     pub fn len(&self) -> usize {
         self.areas.len()
@@ -34,31 +748,249 @@ impl<B: MappingBackend> MemorySet<B> {
         self.areas.values()
     }
 
-    /// Returns whether the given address range overlaps with any existing area.
+    /// Returns the start addresses of the memory areas that overlap with the
+    /// given range, in ascending order.
+    ///
+    /// Only the candidate areas are visited: the one that may cross the
+    /// range start, and those starting inside the range. Use `.rev()` to walk
+    /// them from the highest one. Since it yields keys instead of references,
+    /// the result can be collected and used to modify the areas one by one in
+    /// custom bulk operations.
+    pub fn area_starts_in(
+        &self,
+        range: AddrRange<B::Addr>,
+    ) -> impl DoubleEndedIterator<Item = B::Addr> + '_ {
+        let before = self
+            .areas
+            .range(..range.start)
+            .next_back()
+            .filter(|(_, area)| !range.is_empty() && area.end() > range.start)
+            .map(|(&start, _)| start);
+        before.into_iter().chain(
+            self.areas
+                .range(range.start..range.end)
+                .map(|(&start, _)| start),
+        )
+    }
+
+    /// Returns the iterator over the memory areas that overlap with the given
+    /// range, in ascending order.
+    ///
+    /// Only the overlapping areas are visited, see
+    /// [`area_starts_in`](Self::area_starts_in).
+    pub fn iter_range(
+        &self,
+        range: AddrRange<B::Addr>,
+    ) -> impl DoubleEndedIterator<Item = &MemoryArea<B>> {
+        self.area_starts_in(range).map(|start| &self.areas[&start])
+    }
+
+    /// Returns the mutable iterator over the memory areas that overlap with
+    /// the given range, in ascending order.
+    ///
+    /// The ranges of the areas must not be changed through it.
+    pub fn iter_range_mut(
+        &mut self,
+        range: AddrRange<B::Addr>,
+    ) -> impl DoubleEndedIterator<Item = &mut MemoryArea<B>> {
+        self.bump_generation();
+        let first = self.area_starts_in(range).next().unwrap_or(range.end);
+        let end = range.end.max(first);
+        self.areas.range_mut(first..end).map(|(_, area)| area)
+    }
+
+    /// Checks that the given range is fully covered by areas.
+    pub(crate) fn check_covered(&self, range: AddrRange<B::Addr>) -> MappingResult {
+        let mut covered = range.start;
+        let mut hole_end = range.end;
+        for area in self.iter_range(range) {
+            if area.start() > covered {
+                hole_end = area.start();
+                break;
+            }
+            covered = area.end();
+        }
+        if covered < range.end {
+            return Err(MappingError::NotMapped(untyped(AddrRange::new(
+                covered, hole_end,
+            ))));
+        }
+        Ok(())
+    }
+
+    /// Checks that `[start, start + size)` has no hole if `gaps` is
+    /// [`GapMode::Strict`].
+    fn check_gaps(&self, start: B::Addr, size: usize, gaps: GapMode) -> MappingResult {
+        if gaps == GapMode::Strict {
+            self.check_covered(self.granular_range(start, size)?)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that no area within the given range is sealed, see
+    /// [`seal`](Self::seal).
+    fn check_sealed(&self, range: AddrRange<B::Addr>) -> MappingResult {
+        if let Some(area) = self.iter_range(range).find(|area| area.is_sealed()) {
+            return Err(MappingError::PermissionDenied(untyped(area.va_range())));
+        }
+        Ok(())
+    }
+
+    /// Returns whether the given address range overlaps with any existing area,
+    /// including its guard regions (see [`MemoryArea::with_guards`]).
     pub fn overlaps(&self, range: AddrRange<B::Addr>) -> bool {
-        if let Some((_, before)) = self.areas.range(..range.start).last() {
-            if before.va_range().overlaps(range) {
-                return true;
+        if let Some((_, before)) = self.areas.range(..range.start).next_back()
+            && before.reserved_range().overlaps(range)
+        {
+            return true;
+        }
+        if let Some((_, after)) = self.areas.range(range.start..).next()
+            && after.reserved_range().overlaps(range)
+        {
+            return true;
+        }
+        false
+    }
+
+    /// Updates the gap index after the areas within `range` have changed.
+    ///
+    /// The gaps between areas intersecting or touching the range are
+    /// recomputed, so `range` must cover all the changes. Gaps are between
+    /// the reserved ranges of the areas, so they never include guard regions.
+    pub(crate) fn refresh_gaps(&mut self, range: AddrRange<B::Addr>) {
+        let end: usize = range.end.into();
+        let mut prev_end: Option<usize> = self
+            .areas
+            .range(..range.start)
+            .next_back()
+            .map(|(_, area)| area.reserved_range().end.into());
+        // The guard regions of the areas around may stick out of the range.
+        let next_start = self
+            .areas
+            .range(range.start..)
+            .map(|(_, area)| area.reserved_range().start.into())
+            .find(|&start: &usize| start > end)
+            .unwrap_or(usize::MAX);
+        let start = prev_end.map_or(range.start.into(), |prev_end| {
+            prev_end.min(range.start.into())
+        });
+        self.gaps.remove_touching(start, next_start);
+        for area in self.areas.range(range.start..).map(|(_, area)| area) {
+            let area_start: usize = area.reserved_range().start.into();
+            if let Some(prev_end) = prev_end
+                && prev_end < area_start
+            {
+                self.gaps.insert(prev_end, area_start);
             }
+            if area_start > end {
+                break;
+            }
+            prev_end = Some(area.reserved_range().end.into());
+        }
+    }
+
+    /// Merges the adjacent compatible areas within or next to `range`, see
+    /// [`set_coalescing`](Self::set_coalescing).
+    fn coalesce(&mut self, range: AddrRange<B::Addr>) {
+        if !self.coalescing || self.mpu.is_some() {
+            return;
         }
-        if let Some((_, after)) = self.areas.range(range.start..).next() {
-            if after.va_range().overlaps(range) {
-                return true;
+        let before = self.areas.range(..range.start).next_back();
+        let starts: Vec<_> = before
+            .into_iter()
+            .chain(self.areas.range(range.start..=range.end))
+            .map(|(&start, _)| start)
+            .collect();
+        let Some((&first, rest)) = starts.split_first() else {
+            return;
+        };
+        let mut current = first;
+        for &next_start in rest {
+            if self.areas[&current].can_merge(&self.areas[&next_start]) {
+                let next = self.areas.remove(&next_start).unwrap();
+                self.areas.get_mut(&current).unwrap().merge(next);
+            } else {
+                current = next_start;
             }
         }
-        false
+    }
+
+    /// Rebuilds the gap index from scratch.
+    fn rebuild_gaps(&mut self) {
+        self.gaps.clear();
+        self.refresh_gaps(AddrRange::new(0.into(), usize::MAX.into()));
+    }
+
+    /// Checks a `[start, start + size)` request against the granularity of
+    /// the areas at its boundaries.
+    ///
+    /// `start` must be aligned to the granularity of the area containing it
+    /// (or to [`MappingBackend::MIN_GRANULARITY`] if it is not mapped), and the
+    /// end is rounded up to the granularity of the area containing it, like
+    /// `munmap` and `mprotect` do with the length.
+    pub(crate) fn granular_range(
+        &self,
+        start: B::Addr,
+        size: usize,
+    ) -> MappingResult<AddrRange<B::Addr>> {
+        let mut range = AddrRange::try_from_start_size(start, size)
+            .ok_or(MappingError::InvalidParam(err_range(start, size)))?;
+        if range.is_empty() {
+            return Ok(range);
+        }
+        let start_granularity = self
+            .find(range.start)
+            .map_or(B::MIN_GRANULARITY, |area| area.granularity());
+        if !range.start.is_aligned(start_granularity) {
+            return Err(MappingError::InvalidParam(untyped(range)));
+        }
+        if let Some(area) = self.find(range.end.wrapping_sub(1)) {
+            // `area.end()` is aligned, so this never goes past it.
+            range.end = range.end.align_up(area.granularity());
+        }
+        Ok(range)
+    }
+
+    /// Returns the class of memory mapped at `addr`, or `None` if it is not
+    /// mapped, see [`MappingBackend::mapping_kind`].
+    pub fn kind_at(&self, addr: B::Addr) -> Option<MappingKind> {
+        self.find(addr).map(|area| area.backend().mapping_kind())
     }
 
     /// Finds the memory area that contains the given address.
     pub fn find(&self, addr: B::Addr) -> Option<&MemoryArea<B>> {
-        let candidate = self.areas.range(..=addr).last().map(|(_, a)| a);
+        let candidate = self.areas.range(..=addr).next_back().map(|(_, a)| a);
         candidate.filter(|a| a.va_range().contains(addr))
     }
 
+    /// Finds the memory area that contains the given address, along with the
+    /// areas right before and after the address, e.g., to check the guard gap
+    /// for stack growth or merge candidates on a fault.
+    ///
+    /// Returns `(prev, area, next)`, where `area` contains `addr`, `prev` is
+    /// the last area ending at or below the start of `area` (or `addr`), and
+    /// `next` is the first area starting above it. The areas are linked to
+    /// their neighbors, so only the lookup of `addr` walks the tree.
+    #[allow(clippy::type_complexity)]
+    pub fn find_with_neighbors(
+        &self,
+        addr: B::Addr,
+    ) -> (
+        Option<&MemoryArea<B>>,
+        Option<&MemoryArea<B>>,
+        Option<&MemoryArea<B>>,
+    ) {
+        match self.areas.floor_with_neighbors(&addr) {
+            (prev, Some(area), next) if area.va_range().contains(addr) => (prev, Some(area), next),
+            (_, floor, next) => (floor, None, next),
+        }
+    }
+
     /// Finds the memory area that contains the given address.
     pub fn find_mut(&mut self, addr: B::Addr) -> Option<&mut MemoryArea<B>> {
+        self.bump_generation();
         let candidate: Option<&mut MemoryArea<B>> =
-            self.areas.range_mut(..=addr).last().map(|(_, a)| a);
+            self.areas.range_mut(..=addr).next_back().map(|(_, a)| a);
         candidate.filter(|a| a.va_range().contains(addr))
     }
 
@@ -69,210 +1001,2379 @@ impl<B: MappingBackend> MemorySet<B> {
     ///
     /// Returns the start address of the free area. Returns `None` if no such
     /// area is found.
+    ///
+    /// The gaps between areas are indexed by size, so it takes logarithmic
+    /// time in the number of areas.
     pub fn find_free_area(
         &self,
         hint: B::Addr,
         size: usize,
         limit: AddrRange<B::Addr>,
     ) -> Option<B::Addr> {
-        // brute force: try each area's end address as the start.
-        let mut last_end = hint.max(limit.start);
-        if let Some((_, area)) = self.areas.range(..last_end).last() {
-            last_end = last_end.max(area.end());
-        }
-        for (&addr, area) in self.areas.range(last_end..) {
-            if last_end.checked_add(size).is_some_and(|end| end <= addr) {
-                return Some(last_end);
-            }
-            last_end = area.end();
+        // The lowest gap that is large enough, if it fits in the limit.
+        // Otherwise, the ones after it do not fit either.
+        let (gap_start, gap_end) = self.large_gaps(hint.max(limit.start), size).next()?;
+        (gap_start + size <= gap_end.min(limit.end.into())).then(|| B::Addr::from(gap_start))
+    }
+
+    /// Returns the free ranges at or above `start` with at least `size`
+    /// bytes, in ascending order, the first one clipped to start at `start`.
+    ///
+    /// The ranges between the areas come from the gap index, so that the
+    /// smaller ones are skipped in logarithmic time each.
+    fn large_gaps(&self, start: B::Addr, size: usize) -> impl Iterator<Item = (usize, usize)> {
+        // Skip the area containing the start, or take the gap containing it.
+        let (mut from, first) = match self.areas.range(..=start).next_back() {
+            Some((_, area)) if area.reserved_range().end > start => {
+                (area.reserved_range().end.into(), None)
+            }
+            _ => {
+                let gap_end = self
+                    .areas
+                    .range(start..)
+                    .next()
+                    .map_or(usize::MAX, |(_, next)| next.reserved_range().start.into());
+                (gap_end, Some((start.into(), gap_end)))
+            }
+        };
+        let first = first.filter(|&(gap_start, gap_end)| gap_end.saturating_sub(gap_start) >= size);
+        let mut last = true;
+        first.into_iter().chain(core::iter::from_fn(move || {
+            if let Some((gap_start, gap_end)) = self.gaps.first_fit(from, size) {
+                from = gap_end;
+                return Some((gap_start, gap_end));
+            }
+            // The gap after the last area, unless it was the first one.
+            let (_, area) = self.areas.last_key_value()?;
+            let last_end: usize = area.reserved_range().end.into();
+            (core::mem::take(&mut last) && last_end >= from && usize::MAX - last_end >= size)
+                .then_some((last_end, usize::MAX))
+        }))
+    }
+
+    /// Returns the free ranges below `end` with at least `size` bytes, in
+    /// descending order, the first one clipped to end at `end`.
+    ///
+    /// Like [`large_gaps`](Self::large_gaps), but downward.
+    fn large_gaps_below(&self, end: B::Addr, size: usize) -> impl Iterator<Item = (usize, usize)> {
+        // Skip the area containing the end, or take the gap containing it.
+        let prev = self.areas.range(..end).next_back();
+        let (mut below, first) = match prev.map(|(_, area)| area.reserved_range()) {
+            Some(reserved) if reserved.end >= end => (reserved.start.into(), None),
+            prev => {
+                let gap_start = prev.map_or(0, |prev| prev.end.into());
+                let gap_end = self
+                    .areas
+                    .range(end..)
+                    .next()
+                    .map_or(end, |(_, next)| next.reserved_range().start.min(end));
+                (gap_start, Some((gap_start, gap_end.into())))
+            }
+        };
+        let first = first.filter(|&(gap_start, gap_end)| gap_end.saturating_sub(gap_start) >= size);
+        let mut first_area = true;
+        first.into_iter().chain(core::iter::from_fn(move || {
+            if let Some((gap_start, gap_end)) = self.gaps.last_fit(below, size) {
+                below = gap_start;
+                return Some((gap_start, gap_end));
+            }
+            // The gap before the first area, unless it was the first one.
+            let (_, area) = self.areas.first_key_value()?;
+            let first_start: usize = area.reserved_range().start.into();
+            (core::mem::take(&mut first_area) && first_start <= below && first_start >= size)
+                .then_some((0, first_start))
+        }))
+    }
+
+    /// Finds a free area that can accommodate the given size and starts at a
+    /// multiple of `align`, e.g., 2M for mappings backed by huge pages.
+    ///
+    /// Works like [`find_free_area`](Self::find_free_area), but within each
+    /// candidate gap the lowest aligned start address is chosen. `align` must
+    /// be a power of two.
+    pub fn find_free_area_aligned(
+        &self,
+        hint: B::Addr,
+        size: usize,
+        limit: AddrRange<B::Addr>,
+        align: usize,
+    ) -> Option<B::Addr> {
+        debug_assert!(align.is_power_of_two());
+        let constraint = FreeAreaConstraint::new().with_mask(align - 1, 0);
+        self.find_free_area_constrained(hint, size, limit, &constraint)
+    }
+
+    /// Finds a free area that can accommodate the given size and satisfies
+    /// the given [`FreeAreaConstraint`].
+    ///
+    /// Works like [`find_free_area`](Self::find_free_area), but within each
+    /// candidate gap the lowest start address matching the constraint is
+    /// chosen. The gaps too small for `size` are skipped with the gap index.
+    pub fn find_free_area_constrained(
+        &self,
+        hint: B::Addr,
+        size: usize,
+        limit: AddrRange<B::Addr>,
+        constraint: &FreeAreaConstraint<B::Addr>,
+    ) -> Option<B::Addr> {
+        let limit_end = constraint
+            .max_end
+            .map_or(limit.end, |max_end| max_end.min(limit.end));
+        let limit_end: usize = limit_end.into();
+        let fit = |gap_start: usize, gap_end: usize| {
+            let start = constraint.next_match(gap_start.into())?;
+            start
+                .checked_add(size)
+                .is_some_and(|end| end.into() <= gap_end)
+                .then_some(start)
+        };
+
+        // Only the gaps large enough without the constraint are tried.
+        self.large_gaps(hint.max(limit.start), size)
+            .take_while(|&(gap_start, _)| gap_start < limit_end)
+            .find_map(|(gap_start, gap_end)| fit(gap_start, gap_end.min(limit_end)))
+    }
+
+    /// Finds a free area that can accommodate the given size within `limit`
+    /// with the default strategy of the set, see
+    /// [`set_placement`](Self::set_placement).
+    pub fn find_placement(
+        &mut self,
+        hint: B::Addr,
+        size: usize,
+        limit: AddrRange<B::Addr>,
+    ) -> Option<B::Addr> {
+        match self.placement.take() {
+            Some(mut strategy) => {
+                let start = strategy.place(self, hint, size, limit);
+                self.placement = Some(strategy);
+                start
+            }
+            None => FirstFit.place(self, hint, size, limit),
+        }
+    }
+
+    /// Finds a free area that can accommodate the given size within `limit`
+    /// with the given strategy.
+    pub fn find_free_area_with(
+        &self,
+        strategy: &mut impl PlacementStrategy<B>,
+        hint: B::Addr,
+        size: usize,
+        limit: AddrRange<B::Addr>,
+    ) -> Option<B::Addr> {
+        strategy.place(self, hint, size, limit)
+    }
+
+    /// Returns the iterator over the unmapped ranges within `limit`, in
+    /// ascending order.
+    ///
+    /// The guard regions of the areas are not free, unlike with
+    /// [`gaps`](Self::gaps), so these are the ranges new areas can be placed
+    /// in.
+    pub fn free_ranges(
+        &self,
+        limit: AddrRange<B::Addr>,
+    ) -> impl Iterator<Item = AddrRange<B::Addr>> {
+        self.uncovered_ranges(limit, MemoryArea::reserved_range)
+    }
+
+    /// Returns the iterator over the ranges within `limit` not covered by
+    /// any area (holes included), in ascending order.
+    ///
+    /// Unlike [`free_ranges`](Self::free_ranges), the guard regions of the
+    /// areas are part of the gaps, since nothing is mapped there. It shows
+    /// the fragmentation of the address space, e.g., for diagnostics.
+    pub fn gaps(&self, limit: AddrRange<B::Addr>) -> impl Iterator<Item = AddrRange<B::Addr>> {
+        self.uncovered_ranges(limit, MemoryArea::va_range)
+    }
+
+    /// Returns the iterator over the ranges within `limit` outside the
+    /// ranges given by `covered` for every area, in ascending order.
+    fn uncovered_ranges(
+        &self,
+        limit: AddrRange<B::Addr>,
+        covered: fn(&MemoryArea<B>) -> AddrRange<B::Addr>,
+    ) -> impl Iterator<Item = AddrRange<B::Addr>> {
+        let mut last_end = limit.start;
+        if let Some((_, area)) = self.areas.range(..last_end).next_back() {
+            last_end = last_end.max(covered(area).end);
+        }
+        self.areas
+            .range(last_end..)
+            .map(move |(_, area)| covered(area))
+            .take_while(move |reserved| reserved.start < limit.end)
+            .map(|reserved| (reserved.start, reserved.end))
+            .chain(core::iter::once((limit.end, limit.end)))
+            .filter_map(move |(start, end)| {
+                let gap = AddrRange::new(last_end, start.min(limit.end).max(last_end));
+                last_end = last_end.max(end);
+                (!gap.is_empty()).then_some(gap)
+            })
+    }
+
+    /// Finds a free area that can accommodate the given size, searching from
+    /// the high end downward, like the top-down `mmap` layout.
+    ///
+    /// The search starts from the given `hint` address, i.e., the area ends at
+    /// or below it, and the area should be within the given `limit` range. It
+    /// is placed at the top of the highest gap that fits.
+    ///
+    /// Returns the start address of the free area. Returns `None` if no such
+    /// area is found.
+    ///
+    /// Like [`find_free_area`](Self::find_free_area), it takes logarithmic
+    /// time in the number of areas.
+    pub fn find_free_area_topdown(
+        &self,
+        hint: B::Addr,
+        size: usize,
+        limit: AddrRange<B::Addr>,
+    ) -> Option<B::Addr> {
+        // The highest gap that is large enough, if it fits in the limit.
+        // Otherwise, the ones below it do not fit either.
+        let (gap_start, gap_end) = self.large_gaps_below(hint.min(limit.end), size).next()?;
+        let start = gap_end - size;
+        (start >= gap_start.max(limit.start.into())).then(|| B::Addr::from(start))
+    }
+
+    /// Finds a free area that can accommodate the given size as close as
+    /// possible to `hint`, searching both above and below it, e.g., to
+    /// honor the address hint of `mmap` when it is taken.
+    ///
+    /// The area starts at `hint` if it fits there. Otherwise, it is the
+    /// closer of the one found by [`find_free_area`](Self::find_free_area)
+    /// and the highest one starting below `hint`, preferring the one above
+    /// on a tie. The area should be within the given `limit` range.
+    ///
+    /// Returns the start address of the free area. Returns `None` if no such
+    /// area is found.
+    pub fn find_free_area_nearest(
+        &self,
+        hint: B::Addr,
+        size: usize,
+        limit: AddrRange<B::Addr>,
+    ) -> Option<B::Addr> {
+        let hint = hint.max(limit.start).min(limit.end);
+        let above = self.find_free_area(hint, size, limit);
+        if above == Some(hint) {
+            return above;
         }
-        if last_end
+        // Ending at or below `hint + size` means starting at or below `hint`.
+        let below_end = hint
             .checked_add(size)
-            .is_some_and(|end| end <= limit.end)
+            .map_or(limit.end, |end| end.min(limit.end));
+        let below = self.find_free_area_topdown(below_end, size, limit);
+        match (above, below) {
+            (Some(above), Some(below)) if hint.sub_addr(below) < above.sub_addr(hint) => {
+                Some(below)
+            }
+            (None, below) => below,
+            (above, _) => above,
+        }
+    }
+
+    /// Finds a free area that can accommodate the given size at a random
+    /// position, e.g., for `mmap` address space layout randomization.
+    ///
+    /// Among all the start addresses aligned to `align` (a power of two) such
+    /// that the area fits in a gap within the `limit` range, one is chosen
+    /// uniformly by `rng`, which should return a random number and is called
+    /// once, or not at all if nothing fits. The gaps too small for `size` are
+    /// skipped with the gap index.
+    ///
+    /// Returns `None` if no such area is found.
+    pub fn find_free_area_randomized(
+        &self,
+        size: usize,
+        limit: AddrRange<B::Addr>,
+        align: usize,
+        mut rng: impl FnMut() -> usize,
+    ) -> Option<B::Addr> {
+        debug_assert!(align.is_power_of_two());
+        let limit_end: usize = limit.end.into();
+        // The number of candidate starts in `[gap_start, gap_end)`.
+        let candidates = |gap_start: usize, gap_end: usize| {
+            let first = gap_start.checked_next_multiple_of(align)?;
+            let last = gap_end.checked_sub(size)?;
+            (first <= last).then(|| (first, (last - first) / align + 1))
+        };
+        // Only the gaps large enough without the alignment are counted.
+        let gaps = || {
+            self.large_gaps(limit.start, size)
+                .take_while(move |&(gap_start, _)| gap_start < limit_end)
+                .map(move |(gap_start, gap_end)| (gap_start, gap_end.min(limit_end)))
+        };
+
+        let total = gaps()
+            .filter_map(|(start, end)| candidates(start, end))
+            .try_fold(0usize, |total, (_, count)| total.checked_add(count))
+            .unwrap_or(usize::MAX);
+        if total == 0 {
+            return None;
+        }
+        let mut index = rng() % total;
+        for (first, count) in gaps().filter_map(|(start, end)| candidates(start, end)) {
+            if index < count {
+                return Some(B::Addr::from(first + index * align));
+            }
+            index -= count;
+        }
+        None
+    }
+
+    /// Inserts an existing memory area into the set without mapping it.
+    ///
+    /// Useful for lazy mappings.
+    pub fn insert(&mut self, mut area: MemoryArea<B>, unmap_overlap: bool) -> MappingResult {
+        self.bump_generation();
+        if area.va_range().is_empty() || !area.is_granule_aligned() {
+            return Err(MappingError::InvalidParam(untyped(area.va_range())));
+        }
+
+        if area.va_range().is_empty() {
+            return Err(MappingError::InvalidParam(untyped(area.va_range())));
+        }
+
+        if self.overlaps(area.reserved_range()) && !unmap_overlap {
+            return Err(MappingError::AlreadyExists(untyped(area.reserved_range())));
+        }
+        self.check_mpu_regions([area.va_range()], 0)?;
+        self.check_size_limit(area.va_range(), area.size(), 0)?;
+        self.stamp_created(&mut area);
+        let range = area.va_range();
+        assert!(self.areas.insert(area.start(), area).is_none());
+        self.refresh_gaps(range);
+        self.coalesce(range);
+        Ok(())
+    }
+    /// Removes the area starting at `vaddr` without unmapping it.
+    ///
+    /// Returns [`MappingError::PermissionDenied`] if the area is sealed.
+    pub fn delete(&mut self, vaddr: B::Addr) -> MappingResult {
+        self.bump_generation();
+        if let Some(area) = self.areas.get(&vaddr)
+            && area.is_sealed()
         {
-            Some(last_end)
+            return Err(MappingError::PermissionDenied(untyped(area.va_range())));
+        }
+        if let Some(area) = self.areas.remove(&vaddr) {
+            self.refresh_gaps(area.va_range());
+            self.settle_commit();
+        }
+        Ok(())
+    }
+    /// Reserves the range of `area` without mapping anything, like
+    /// `VirtualAlloc` with `MEM_RESERVE`.
+    ///
+    /// The area is added to the set as [reserved](MemoryArea::is_reserved),
+    /// so the range is not used by other mappings, but the backend is not
+    /// called until parts of it are [committed](Self::commit).
+    pub fn reserve(&mut self, mut area: MemoryArea<B>) -> MappingResult {
+        area.set_reserved(true);
+        self.insert(area, false)
+    }
+
+    /// Adds a hole over the range of `area`, e.g., for a PCI hole or a legacy
+    /// region when building a guest or user layout around them.
+    ///
+    /// Only the range of `area` is used: the hole is a reserved and sealed
+    /// pseudo-area (see [`MemoryArea::is_hole`]) that blocks placement and
+    /// mapping until it is [released](Self::release_hole), and its backend is
+    /// never called.
+    pub fn add_hole(&mut self, mut area: MemoryArea<B>) -> MappingResult {
+        area.make_hole();
+        self.insert(area, false)
+    }
+
+    /// Returns the iterator over the ranges of the holes, in ascending order.
+    pub fn holes(&self) -> impl Iterator<Item = AddrRange<B::Addr>> + '_ {
+        self.areas
+            .values()
+            .filter(|area| area.is_hole())
+            .map(|area| area.va_range())
+    }
+
+    /// Removes the hole starting at `start`, see [`add_hole`](Self::add_hole).
+    ///
+    /// Returns [`MappingError::NotMapped`] if no hole starts there.
+    pub fn release_hole(&mut self, start: B::Addr) -> MappingResult {
+        self.bump_generation();
+        let area = self
+            .areas
+            .remove(&start)
+            .ok_or(MappingError::NotMapped(err_range(start, 0)))?;
+        if !area.is_hole() {
+            self.areas.insert(start, area);
+            return Err(MappingError::NotMapped(err_range(start, 0)));
+        }
+        self.refresh_gaps(area.va_range());
+        Ok(())
+    }
+
+    /// Commits `[start, start + size)` with the given flags, mapping it, like
+    /// `VirtualAlloc` with `MEM_COMMIT`.
+    ///
+    /// The range must be fully covered by areas, otherwise
+    /// [`MappingError::NotMapped`] is returned. Reserved areas crossing the
+    /// boundaries of the range are split, and the reserved parts within it
+    /// are mapped with `flags`. Parts that are already committed are left as
+    /// they are. If mapping a part fails, the parts before it stay committed.
+    /// Sealed areas and holes cannot be committed.
+    pub fn commit(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.bump_generation();
+        let range = self.granular_range(start, size)?;
+        if range.is_empty() {
+            return Ok(());
+        }
+        self.check_covered(range)?;
+        self.check_mpu_whole(range)?;
+        self.check_sealed(range)?;
+        let charge = self
+            .iter_range(range)
+            .filter(|area| area.is_reserved() && area.backend().charges_commit(flags))
+            .map(|area| {
+                let start = area.start().max(range.start);
+                area.end().min(range.end).sub_addr(start)
+            })
+            .sum();
+        self.check_commit(range, charge)?;
+        let mut result = self
+            .split_at(range.start)
+            .and_then(|_| self.split_at(range.end));
+        let candidates: Vec<_> = match result {
+            Ok(()) => self.area_starts_in(range).collect(),
+            Err(_) => Vec::new(),
+        };
+        for area_start in candidates {
+            let area = self.areas.get_mut(&area_start).unwrap();
+            if !area.is_reserved() {
+                continue;
+            }
+            area.set_reserved(false);
+            result = area.map_area(page_table, Some(flags));
+            if result.is_err() {
+                area.set_reserved(true);
+                break;
+            }
+            area.set_flags(flags);
+        }
+        self.coalesce(range);
+        self.settle_commit();
+        result
+    }
+
+    /// Decommits `[start, start + size)`, unmapping it but keeping it
+    /// reserved, like `VirtualFree` with `MEM_DECOMMIT`.
+    ///
+    /// The range must be fully covered by areas, otherwise
+    /// [`MappingError::NotMapped`] is returned. Areas crossing the boundaries
+    /// of the range are split, and the committed parts within it are unmapped
+    /// and become reserved again.
+    pub fn decommit(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.bump_generation();
+        let range = self.granular_range(start, size)?;
+        if range.is_empty() {
+            return Ok(());
+        }
+        self.check_covered(range)?;
+        self.check_mpu_whole(range)?;
+        self.check_sealed(range)?;
+        self.split_at(range.start)?;
+        self.split_at(range.end)?;
+        let candidates: Vec<_> = self.area_starts_in(range).collect();
+        let mut result = Ok(());
+        for area_start in candidates {
+            let area = self.areas.get_mut(&area_start).unwrap();
+            if !area.is_reserved() {
+                result = area.unmap_area(page_table);
+                if result.is_err() {
+                    break;
+                }
+                area.set_reserved(true);
+            }
+        }
+        self.coalesce(range);
+        self.settle_commit();
+        result
+    }
+
+    /// Add a new memory mapping.
+    ///
+    /// The mapping is represented by a [`MemoryArea`].
+    ///
+    /// If the new area overlaps with any existing area, the behavior is
+    /// determined by the `unmap_overlap` parameter. If it is `true`, the
+    /// overlapped regions will be unmapped first. Otherwise, it returns an
+    /// error. The guard regions of the area (see [`MemoryArea::with_guards`])
+    /// count as part of it here, but only the range of the area itself is
+    /// unmapped first. Guard regions are never replaced: if the guards of the
+    /// area would overlap the areas left around it, or the other way around,
+    /// [`MappingError::AlreadyExists`] is returned.
+    pub fn map(
+        &mut self,
+        area: MemoryArea<B>,
+        page_table: &mut B::PageTable,
+        unmap_overlap: bool,
+        overwrite_flags: Option<B::Flags>,
+    ) -> MappingResult {
+        let mode = if unmap_overlap {
+            MapMode::Fixed
+        } else {
+            MapMode::NoReplace
+        };
+        self.map_with_mode(area, page_table, mode, overwrite_flags)
+    }
+
+    /// Same as [`map`](Self::map), but with the handling of existing
+    /// mappings in the range given by a [`MapMode`].
+    pub fn map_with_mode(
+        &mut self,
+        area: MemoryArea<B>,
+        page_table: &mut B::PageTable,
+        mode: MapMode<B::Addr>,
+        overwrite_flags: Option<B::Flags>,
+    ) -> MappingResult {
+        #[cfg(feature = "latency")]
+        return self.timed(LatencyOp::Map, |set| {
+            set.map_untimed(area, page_table, mode, overwrite_flags)
+        });
+        #[cfg(not(feature = "latency"))]
+        self.map_untimed(area, page_table, mode, overwrite_flags)
+    }
+
+    /// Same as [`map_with_mode`](Self::map_with_mode), but then faults in
+    /// all the pages of the area as if they were accessed with
+    /// `access_flags`, like `mmap` with `MAP_POPULATE`, e.g., for real-time
+    /// buffers that must not fault later.
+    ///
+    /// The area must allow the access, otherwise
+    /// [`MappingError::PermissionDenied`] is returned before mapping it. The
+    /// pages are faulted in like with [`populate`](Self::populate): if that
+    /// fails, the area is unmapped again and the error is returned, and if
+    /// it stops early because of memory pressure, the area is kept and the
+    /// returned [`Populated`] tells where to resume.
+    ///
+    /// With [`MapMode::Fixed`], the areas in the range are
+    /// [detached](Self::detach) first and only destroyed once the area is
+    /// populated, so a failure leaves the old mappings in place. The areas may
+    /// stay split at the range boundaries then.
+    pub fn map_populate(
+        &mut self,
+        area: MemoryArea<B>,
+        access_flags: B::Flags,
+        page_table: &mut B::PageTable,
+        mode: MapMode<B::Addr>,
+    ) -> MappingResult<Populated<B::Addr>> {
+        let range = area.va_range();
+        if !area.backend().check_access(area.flags(), access_flags) {
+            return Err(MappingError::PermissionDenied(untyped(range)));
+        }
+        let replaced = match mode {
+            MapMode::Fixed => Some(self.detach(range.start, range.size(), page_table)?),
+            _ => None,
+        };
+        let result = self
+            .map_with_mode(area, page_table, mode, None)
+            .and_then(|_| {
+                self.populate(range.start, range.size(), access_flags, page_table)
+                    .or_else(|err| {
+                        self.unmap(range.start, range.size(), page_table)
+                            .and(Err(err))
+                    })
+            });
+        match (result, replaced) {
+            (Ok(populated), Some(replaced)) => {
+                if let Some(observer) = self.observer() {
+                    for area in replaced.areas() {
+                        observer.on_unmap(area.va_range());
+                    }
+                }
+                replaced.destroy();
+                Ok(populated)
+            }
+            (Err(err), Some(replaced)) => {
+                self.reattach(replaced, page_table)
+                    .map_err(|(err, _)| err)?;
+                Err(err)
+            }
+            (result, None) => result,
+        }
+    }
+
+    /// Does the work of [`map_with_mode`](Self::map_with_mode).
+    fn map_untimed(
+        &mut self,
+        mut area: MemoryArea<B>,
+        page_table: &mut B::PageTable,
+        mode: MapMode<B::Addr>,
+        overwrite_flags: Option<B::Flags>,
+    ) -> MappingResult {
+        self.bump_generation();
+        if let MapMode::FixedNoReplace { limit, align } = mode {
+            if !area.start().is_aligned(align) {
+                return Err(MappingError::Misaligned(untyped(area.va_range())));
+            }
+            if !area.reserved_range().contained_in(limit) {
+                return Err(MappingError::OutOfRange(untyped(area.reserved_range())));
+            }
+        }
+        if area.va_range().is_empty() || !area.is_granule_aligned() {
+            return Err(MappingError::InvalidParam(untyped(area.va_range())));
+        }
+        let unmap_overlap = mode == MapMode::Fixed;
+
+        if self.mpu.is_some() {
+            self.check_mpu_whole(area.va_range())?;
+            let replaced = if unmap_overlap {
+                self.iter_range(area.va_range()).count()
+            } else {
+                0
+            };
+            self.check_mpu_regions([area.va_range()], replaced)?;
+        }
+        let replaced = if unmap_overlap {
+            self.size_in(area.va_range())
         } else {
-            None
+            0
+        };
+        self.check_size_limit(area.va_range(), area.size(), replaced)?;
+        let overlaps = self.overlaps(area.reserved_range());
+        if overlaps {
+            if !unmap_overlap || self.overlaps_guards(area.va_range(), area.reserved_range()) {
+                return Err(MappingError::AlreadyExists(untyped(area.reserved_range())));
+            }
+            self.check_sealed(area.va_range())?;
+        }
+        // The commit check may account for the charge, so it comes once the
+        // area can no longer be rejected.
+        self.check_commit(area.va_range(), area.commit_charge())?;
+        self.stamp_created(&mut area);
+
+        if overlaps {
+            let range = area.va_range();
+            let flags = overwrite_flags.unwrap_or(area.flags());
+            // Releases the charge of the replaced parts, or of the new area.
+            let result = self.replace_overlapped(area, page_table, overwrite_flags);
+            self.settle_commit();
+            result?;
+            self.coalesce(range);
+            if let Some(observer) = self.observer() {
+                observer.on_map(range, flags);
+            }
+            return Ok(());
+        }
+
+        if let Err(err) = area.map_area(page_table, overwrite_flags) {
+            self.settle_commit();
+            return Err(err);
+        }
+        let range = area.va_range();
+        let flags = overwrite_flags.unwrap_or(area.flags());
+        assert!(self.areas.insert(area.start(), area).is_none());
+        self.refresh_gaps(range);
+        self.coalesce(range);
+        if let Some(observer) = self.observer() {
+            observer.on_map(range, flags);
         }
+        Ok(())
     }
 
-    /// insert an existing memory area into the set.
+    /// Replaces whatever is mapped in the range of `area` with `area` in one
+    /// operation, like `mmap(MAP_FIXED)` over existing memory.
+    ///
+    /// Overlapped areas are split at the range boundaries, the parts inside
+    /// the range are unmapped and the new area is mapped. If any step fails,
+    /// the evicted parts are mapped again with their frames, so the range is
+    /// never left as a hole. The areas may stay split at the boundaries after
+    /// a rollback.
+    pub fn replace_range(
+        &mut self,
+        area: MemoryArea<B>,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.map(area, page_table, true, None)
+    }
 
-    /// Add a new memory area without mapping.
-    /// Useful for lazy.
-    pub fn insert(&mut self, area: MemoryArea<B>, unmap_overlap: bool) -> MappingResult {
-        if area.va_range().is_empty() {
-            return Err(MappingError::InvalidParam);
+    /// Same as [`map_with_mode`](Self::map_with_mode), but leaves the area
+    /// split at each of the `boundaries`, e.g., for a pool of fiber stacks
+    /// whose guard pages are protected one by one later.
+    ///
+    /// The area is mapped with the backend in one go, and the pieces are cut
+    /// from the end so that each split only moves the frames of one piece,
+    /// which is cheaper than splitting the area with N later operations.
+    /// The boundaries must be strictly ascending, strictly inside the area
+    /// and aligned to its [granularity](MemoryArea::granularity), otherwise
+    /// [`MappingError::InvalidParam`] is returned and nothing is mapped. The
+    /// pieces stay apart until an operation coalesces them again (see
+    /// [`set_coalescing`](Self::set_coalescing)).
+    pub fn map_presplit(
+        &mut self,
+        area: MemoryArea<B>,
+        boundaries: &[B::Addr],
+        page_table: &mut B::PageTable,
+        mode: MapMode<B::Addr>,
+    ) -> MappingResult {
+        let range = area.va_range();
+        let granularity = area.granularity();
+        let mut prev = range.start;
+        for &pos in boundaries {
+            if pos <= prev || pos >= range.end || !pos.is_aligned(granularity) {
+                return Err(MappingError::InvalidParam(untyped(range)));
+            }
+            prev = pos;
+        }
+        if self.mpu.is_some() {
+            let pieces = core::iter::once(range.start)
+                .chain(boundaries.iter().copied())
+                .zip(
+                    boundaries
+                        .iter()
+                        .copied()
+                        .chain(core::iter::once(range.end)),
+                )
+                .map(|(start, end)| AddrRange::new(start, end));
+            let replaced = if mode == MapMode::Fixed {
+                self.iter_range(range).count()
+            } else {
+                0
+            };
+            self.check_mpu_regions(pieces, replaced)?;
+        }
+        self.map_with_mode(area, page_table, mode, None)?;
+        for &pos in boundaries.iter().rev() {
+            self.split_at(pos)?;
         }
+        Ok(())
+    }
+
+    /// Splits the area strictly containing `pos` (if any) into two at `pos`.
+    ///
+    /// Only the bookkeeping changes, the backend is not involved.
+    ///
+    /// Fails if the area cannot be split there, see [`MemoryArea::split`].
+    fn split_at(&mut self, pos: B::Addr) -> MappingResult {
+        if let Some((_, area)) = self.areas.range_mut(..pos).next_back() {
+            let range = area.va_range();
+            if let Some(right_part) = area.split(pos)? {
+                self.areas.insert(pos, right_part);
+                if let Some(observer) = self.observer() {
+                    observer.on_split(range, pos);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether an area reserving `reserved` mapped over `range`,
+    /// replacing the areas there, would overlap the guard regions of the
+    /// areas left around it, or its own guards would overlap them.
+    fn overlaps_guards(&self, range: AddrRange<B::Addr>, reserved: AddrRange<B::Addr>) -> bool {
+        let before = self
+            .areas
+            .range(..reserved.end)
+            .rev()
+            .take_while(|(_, area)| area.reserved_range().end > reserved.start);
+        let after = self.areas.range(reserved.end..).next();
+        before.chain(after).any(|(_, area)| {
+            if area.va_range().overlaps(range) {
+                // Only the parts outside of `range` are left, without guards
+                // on its side.
+                (area.start() < range.start && reserved.start < range.start)
+                    || (area.end() > range.end && reserved.end > range.end)
+            } else {
+                area.reserved_range().overlaps(reserved)
+            }
+        })
+    }
+
+    fn replace_overlapped(
+        &mut self,
+        mut area: MemoryArea<B>,
+        page_table: &mut B::PageTable,
+        overwrite_flags: Option<B::Flags>,
+    ) -> MappingResult {
+        let range = area.va_range();
+        self.split_at(range.start)?;
+        self.split_at(range.end)?;
+        let evicted_starts: Vec<_> = self.area_starts_in(range).collect();
+        let evicted: Vec<_> = evicted_starts
+            .iter()
+            .map(|start| self.areas.remove(start).unwrap())
+            .collect();
+
+        for (unmapped, part) in evicted.iter().enumerate() {
+            if let Err(err) = part.unmap_area_keep_frames(page_table) {
+                self.restore_areas(evicted, unmapped, page_table, "map")?;
+                return Err(err);
+            }
+        }
+        if let Err(err) = area.map_area(page_table, overwrite_flags) {
+            let unmapped = evicted.len();
+            self.restore_areas(evicted, unmapped, page_table, "map")?;
+            return Err(err);
+        }
+        assert!(self.areas.insert(area.start(), area).is_none());
+        self.refresh_gaps(range);
+        if let Some(observer) = self.observer() {
+            evicted
+                .iter()
+                .for_each(|part| observer.on_unmap(part.va_range()));
+        }
+        Ok(())
+    }
+
+    /// Replaces the user image of the address space, e.g., on `exec`.
+    ///
+    /// All areas fully contained in `user_range` are torn down and the
+    /// `new_areas` (ELF segments, stack, brk, ...) are mapped instead. Areas
+    /// outside `user_range`, such as the kernel areas, are left untouched.
+    ///
+    /// The replacement is failure-atomic: the new areas are validated up
+    /// front, and if any step fails afterwards, the new areas are unmapped and
+    /// the old image is mapped again with its frames before the error is
+    /// returned. If that rollback fails too, its error is returned instead.
+    ///
+    /// The new areas are charged to the commit check like with
+    /// [`map`](Self::map), and the observer sees the old areas unmapped and
+    /// the new ones mapped.
+    pub fn replace_user_image(
+        &mut self,
+        user_range: AddrRange<B::Addr>,
+        new_areas: impl IntoIterator<Item = MemoryArea<B>>,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.bump_generation();
+        let mut new_areas: Vec<_> = new_areas.into_iter().collect();
+        new_areas.sort_unstable_by_key(|area| area.start());
+        let mut prev_end = user_range.start;
+        for area in &new_areas {
+            if area.va_range().is_empty()
+                || !area.is_granule_aligned()
+                || !area.va_range().contained_in(user_range)
+            {
+                return Err(MappingError::InvalidParam(untyped(area.va_range())));
+            }
+            if area.start() < prev_end {
+                return Err(MappingError::AlreadyExists(untyped(area.va_range())));
+            }
+            prev_end = area.end();
+        }
+
+        self.check_sealed(user_range)?;
+        let replaced: Vec<_> = self
+            .iter_range(user_range)
+            .filter(|area| area.va_range().contained_in(user_range))
+            .map(|area| area.size())
+            .collect();
+        self.check_mpu_regions(new_areas.iter().map(|area| area.va_range()), replaced.len())?;
+        self.check_size_limit(
+            user_range,
+            new_areas.iter().map(|area| area.size()).sum(),
+            replaced.iter().sum(),
+        )?;
+
+        let old_starts: Vec<_> = self
+            .areas
+            .range(user_range.start..user_range.end.max(user_range.start))
+            .filter(|(_, area)| area.end() <= user_range.end)
+            .map(|(&start, _)| start)
+            .collect();
+        let old_areas: Vec<_> = old_starts
+            .iter()
+            .map(|start| self.areas.remove(start).unwrap())
+            .collect();
+        if let Some(area) = new_areas
+            .iter()
+            .find(|area| self.overlaps(area.reserved_range()))
+        {
+            let range = untyped(area.reserved_range());
+            self.restore_areas(old_areas, 0, page_table, "replace_user_image")?;
+            return Err(MappingError::AlreadyExists(range));
+        }
+        // The commit check may account for the charge, so it comes once the
+        // image can no longer be rejected.
+        let charge = new_areas.iter().map(MemoryArea::commit_charge).sum();
+        if let Err(err) = self.check_commit(user_range, charge) {
+            self.restore_areas(old_areas, 0, page_table, "replace_user_image")?;
+            return Err(err);
+        }
+        if let Err((unmapped, err)) = Self::swap_user_image(&old_areas, &mut new_areas, page_table)
+        {
+            let restored =
+                self.restore_areas(old_areas, unmapped, page_table, "replace_user_image");
+            self.settle_commit();
+            restored?;
+            return Err(self.owned(err));
+        }
+
+        if let Some(observer) = self.observer() {
+            for area in &old_areas {
+                observer.on_unmap(area.va_range());
+            }
+            for area in &new_areas {
+                observer.on_map(area.va_range(), area.flags());
+            }
+        }
+        self.areas
+            .extend(new_areas.into_iter().map(|area| (area.start(), area)));
+        self.refresh_gaps(user_range);
+        self.settle_commit();
+        Ok(())
+    }
+
+    /// Does the page table work of
+    /// [`replace_user_image`](Self::replace_user_image): unmaps the old areas
+    /// keeping their frames, then maps the new ones.
+    ///
+    /// On failure, returns the number of old areas to map again along with
+    /// the error. If mapping a new area fails, the ones mapped before it are
+    /// unmapped again, and failing to do so is reported instead.
+    fn swap_user_image(
+        old_areas: &[MemoryArea<B>],
+        new_areas: &mut [MemoryArea<B>],
+        page_table: &mut B::PageTable,
+    ) -> Result<(), (usize, MappingError)> {
+        for (unmapped, area) in old_areas.iter().enumerate() {
+            area.unmap_area_keep_frames(page_table)
+                .map_err(|err| (unmapped, err))?;
+        }
+        for mapped in 0..new_areas.len() {
+            if let Err(err) = new_areas[mapped].map_area(page_table, None) {
+                for area in new_areas.iter_mut().take(mapped) {
+                    let range = untyped(area.va_range());
+                    area.unmap_area(page_table).map_err(|err| {
+                        let err = err.with_context("replace_user_image", "unmap", range);
+                        (old_areas.len(), err)
+                    })?;
+                }
+                return Err((old_areas.len(), err));
+            }
+        }
+        Ok(())
+    }
+
+    /// Puts back areas detached by a failed `op`, mapping the first
+    /// `unmapped` of them again.
+    ///
+    /// All the areas are put back even if mapping one of them fails, and the
+    /// first failure is returned.
+    fn restore_areas(
+        &mut self,
+        mut areas: Vec<MemoryArea<B>>,
+        unmapped: usize,
+        page_table: &mut B::PageTable,
+        op: &'static str,
+    ) -> MappingResult {
+        let mut result = Ok(());
+        for area in areas.iter_mut().take(unmapped) {
+            if let Err(err) = area.remap_area(page_table)
+                && result.is_ok()
+            {
+                result = Err(err.with_context(op, "restore", untyped(area.va_range())));
+            }
+        }
+        self.areas
+            .extend(areas.into_iter().map(|area| (area.start(), area)));
+        result.map_err(|err| self.owned(err))
+    }
+
+    /// Remove memory mappings within the given address range.
+    ///
+    /// All memory areas that are fully contained in the range will be removed
+    /// directly. If the area intersects with the boundary, it will be shrinked.
+    /// If the unmapped range is in the middle of an existing area, it will be
+    /// split into two areas.
+    ///
+    /// `start` must be aligned to the mapping granularity of the area
+    /// containing it, and the end of the range is rounded up to the
+    /// granularity of the area containing it. The parts of the range not
+    /// covered by any area are skipped, see
+    /// [`unmap_with_mode`](Self::unmap_with_mode).
+    pub fn unmap(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.unmap_with_mode(start, size, GapMode::Lenient, page_table)
+    }
+
+    /// Same as [`unmap`](Self::unmap), but with the handling of the holes
+    /// in the range given by a [`GapMode`].
+    pub fn unmap_with_mode(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        gaps: GapMode,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        #[cfg(feature = "latency")]
+        return self.timed(LatencyOp::Unmap, |set| {
+            set.unmap_untimed(start, size, gaps, page_table)
+        });
+        #[cfg(not(feature = "latency"))]
+        self.unmap_untimed(start, size, gaps, page_table)
+    }
+
+    /// Does the work of [`unmap_with_mode`](Self::unmap_with_mode).
+    fn unmap_untimed(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        gaps: GapMode,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.check_gaps(start, size, gaps)?;
+        self.unmap_with(start, size, page_table, |_, _| {})
+    }
+
+    /// Unmaps all the given ranges, like [`unmap`](Self::unmap) on each of
+    /// them, e.g., to tear down scatter-gather mappings.
+    ///
+    /// All the ranges are checked first, so nothing is unmapped if one of
+    /// them is invalid. They are then sorted, merged and unmapped in one
+    /// ascending pass, within a [`TlbBatch`] so that the TLB invalidations
+    /// are done at once. If unmapping fails, the ranges before the failing
+    /// one stay unmapped.
+    pub fn unmap_ranges(
+        &mut self,
+        ranges: impl IntoIterator<Item = AddrRange<B::Addr>>,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.bump_generation();
+        let mut checked = Vec::new();
+        for range in ranges {
+            let range = self.granular_range(range.start, range.size())?;
+            if range.is_empty() {
+                continue;
+            }
+            self.check_mpu_whole(range)?;
+            self.check_sealed(range)?;
+            checked.push(range);
+        }
+        checked.sort_unstable_by_key(|range| range.start);
+        let mut merged: Vec<AddrRange<B::Addr>> = Vec::with_capacity(checked.len());
+        for range in checked {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+
+        let mut batch = TlbBatch::<B>::new(page_table);
+        let mut result = Ok(());
+        for range in merged {
+            result = self.unmap_range(range, batch.page_table(), |_, _| {});
+            self.refresh_gaps(range);
+            batch.add(range);
+            if result.is_err() {
+                break;
+            }
+        }
+        self.settle_commit();
+        result.map_err(|err| self.owned(err))
+    }
+
+    /// Same as [`unmap`](Self::unmap), but calls `on_unmap` with each area and
+    /// the part of it that is about to be unmapped.
+    fn unmap_with(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        page_table: &mut B::PageTable,
+        on_unmap: impl FnMut(&mut MemoryArea<B>, AddrRange<B::Addr>),
+    ) -> MappingResult {
+        self.bump_generation();
+        let range = self.granular_range(start, size)?;
+        if range.is_empty() {
+            return Ok(());
+        }
+        self.check_mpu_whole(range)?;
+        self.check_sealed(range)?;
+        let result = self.unmap_range(range, page_table, on_unmap);
+        self.refresh_gaps(range);
+        self.settle_commit();
+        result.map_err(|err| self.owned(err))
+    }
+
+    /// Does the work of [`unmap_with`](Self::unmap_with) on a checked range.
+    fn unmap_range(
+        &mut self,
+        range: AddrRange<B::Addr>,
+        page_table: &mut B::PageTable,
+        mut on_unmap: impl FnMut(&mut MemoryArea<B>, AddrRange<B::Addr>),
+    ) -> MappingResult {
+        let (start, end) = (range.start, range.end);
+
+        // Split the huge frames crossing the bounds first, so that failing to
+        // split them leaves the set unchanged.
+        #[cfg(feature = "RAII")]
+        for pos in [start, end] {
+            if let Some((_, area)) = self.areas.range_mut(..pos).next_back() {
+                area.frames
+                    .demote(pos)
+                    .map_err(|err| err.with_context("unmap", "split", untyped(area.va_range())))?;
+            }
+        }
+
+        // Unmap entire areas that are contained by the range.
+        let contained: Vec<_> = self
+            .area_starts_in(range)
+            .filter(|area_start| self.areas[area_start].va_range().contained_in(range))
+            .collect();
+        for area_start in contained {
+            let area = self.areas.get_mut(&area_start).unwrap();
+            let area_range = area.va_range();
+            on_unmap(area, area_range);
+            // An area that fails to unmap stays in the set, since some of its
+            // mappings may still be live.
+            area.unmap_area(page_table)
+                .map_err(|err| err.with_context("unmap", "unmap", untyped(area_range)))?;
+            self.areas.remove(&area_start);
+            if let Some(observer) = self.observer() {
+                observer.on_unmap(area_range);
+            }
+        }
+
+        // Shrink right if the area intersects with the left boundary.
+        if let Some((&before_start, before)) = self.areas.range_mut(..start).next_back() {
+            let before_end = before.end();
+            if before_end > start {
+                if before_end <= end {
+                    // the unmapped area is at the end of `before`.
+                    on_unmap(before, AddrRange::new(start, before_end));
+                    before
+                        .shrink_right(boundary_offset(before_start, start)?, page_table)
+                        .map_err(|err| {
+                            err.with_context("unmap", "shrink", untyped(before.va_range()))
+                        })?;
+                    if let Some(observer) = self.observer.as_deref_mut() {
+                        observer.on_unmap(AddrRange::new(start, before_end));
+                    }
+                } else {
+                    // the unmapped area is in the middle `before`, need to split.
+                    on_unmap(before, range);
+                    let area_range = untyped(before.va_range());
+                    let right_part = before
+                        .split(end)
+                        .and_then(|right_part| {
+                            right_part.ok_or(MappingError::BadState(err_range(end, 0), None))
+                        })
+                        .map_err(|err| err.with_context("unmap", "split", area_range))?;
+                    let shrunk = boundary_offset(before_start, start)
+                        .and_then(|new_size| before.shrink_right(new_size, page_table));
+                    // The right part keeps its frames and mappings even if
+                    // `before` fails to shrink, so it must stay in the set.
+                    self.areas.insert(end, right_part);
+                    shrunk.map_err(|err| err.with_context("unmap", "shrink", area_range))?;
+                    if let Some(observer) = self.observer() {
+                        observer.on_split(AddrRange::new(before_start, before_end), end);
+                        observer.on_unmap(range);
+                    }
+                }
+            }
+        }
+
+        // Shrink left if the area intersects with the right boundary.
+        if let Some((&after_start, after)) = self.areas.range_mut(start..).next() {
+            let after_end = after.end();
+            if after_start < end {
+                // the unmapped area is at the start of `after`.
+                let mut new_area = self.areas.remove(&after_start).unwrap();
+                on_unmap(&mut new_area, AddrRange::new(after_start, end));
+                let new_size = boundary_offset(end, after_end)?;
+                let area_range = untyped(new_area.va_range());
+                if let Err(err) = new_area.shrink_left(new_size, page_table) {
+                    self.areas.insert(after_start, new_area);
+                    return Err(err.with_context("unmap", "shrink", area_range));
+                }
+                if new_area.start() != end {
+                    // The rest of `after` must start at the end of the range.
+                    self.areas.insert(new_area.start(), new_area);
+                    return Err(MappingError::BadState(err_range(end, 0), None)
+                        .with_context("unmap", "shrink", area_range));
+                }
+                self.areas.insert(end, new_area);
+                if let Some(observer) = self.observer() {
+                    observer.on_unmap(AddrRange::new(after_start, end));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Detaches the areas within `[start, start + size)` from the set, the
+    /// first phase of a two-phase `munmap`.
+    ///
+    /// Areas crossing the boundaries of the range are split, and the parts
+    /// within it are removed from the set and unmapped from the page table,
+    /// but keep their frames. The caller can then do a TLB shootdown, and
+    /// either [`destroy`](DetachedAreas::destroy) the detached areas to
+    /// commit, or [`reattach`](Self::reattach) them to roll back.
+    ///
+    /// The range is checked like in [`unmap`](Self::unmap), and the
+    /// observer (if any) is told about the unmapped areas, as it is about
+    /// the mapped ones by `reattach`. If unmapping fails, the areas are put
+    /// back and the error is returned.
+    pub fn detach(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult<DetachedAreas<B>> {
+        self.bump_generation();
+        let range = self.granular_range(start, size)?;
+        if range.is_empty() {
+            return Ok(self.detached(Vec::new()));
+        }
+        self.check_mpu_whole(range)?;
+        self.check_sealed(range)?;
+        self.split_at(range.start)?;
+        self.split_at(range.end)?;
+        let starts: Vec<_> = self.area_starts_in(range).collect();
+        let areas: Vec<_> = starts
+            .iter()
+            .map(|start| self.areas.remove(start).unwrap())
+            .collect();
+        for (unmapped, area) in areas.iter().enumerate() {
+            if let Err(err) = area.unmap_area_keep_frames(page_table) {
+                self.restore_areas(areas, unmapped, page_table, "detach")?;
+                return Err(err);
+            }
+        }
+        self.refresh_gaps(range);
+        if let Some(observer) = self.observer() {
+            for area in &areas {
+                observer.on_unmap(area.va_range());
+            }
+        }
+        Ok(self.detached(areas))
+    }
+
+    /// Wraps areas removed from the set into [`DetachedAreas`], along with
+    /// their commit charge.
+    fn detached(&mut self, areas: Vec<MemoryArea<B>>) -> DetachedAreas<B> {
+        let charge = areas
+            .iter()
+            .map(MemoryArea::commit_charge)
+            .sum::<usize>()
+            .min(self.committed);
+        self.committed -= charge;
+        DetachedAreas {
+            areas,
+            committed: charge,
+            commit_release: self.commit_release.clone(),
+        }
+    }
+
+    /// Puts back the areas detached by [`detach`](Self::detach), mapping them
+    /// again with their frames.
+    ///
+    /// If any of their ranges has been mapped in the meantime, or mapping
+    /// fails, the areas are given back along with the error. Otherwise, they
+    /// are merged with their neighbors like by [`map`](Self::map).
+    pub fn reattach(
+        &mut self,
+        mut detached: DetachedAreas<B>,
+        page_table: &mut B::PageTable,
+    ) -> Result<(), (MappingError, DetachedAreas<B>)> {
+        self.bump_generation();
+        if let Some(area) = detached
+            .areas
+            .iter()
+            .find(|area| self.overlaps(area.reserved_range()))
+        {
+            let range = untyped(area.reserved_range());
+            return Err((MappingError::AlreadyExists(range), detached));
+        }
+        if let Err(err) =
+            self.check_mpu_regions(detached.areas.iter().map(|area| area.va_range()), 0)
+        {
+            return Err((err, detached));
+        }
+        let size = detached.areas.iter().map(|area| area.size()).sum();
+        let range = match (detached.areas.first(), detached.areas.last()) {
+            (Some(first), Some(last)) => AddrRange::new(first.start(), last.end()),
+            _ => return Ok(()),
+        };
+        if let Err(err) = self.check_size_limit(range, size, 0) {
+            return Err((err, detached));
+        }
+        for mapped in 0..detached.areas.len() {
+            if let Err(err) = detached.areas[mapped].remap_area(page_table) {
+                for area in &detached.areas[..mapped] {
+                    let _ = area.unmap_area_keep_frames(page_table);
+                }
+                return Err((err, detached));
+            }
+        }
+        for area in core::mem::take(&mut detached.areas) {
+            let area_range = area.va_range();
+            let flags = area.flags();
+            self.areas.insert(area.start(), area);
+            self.refresh_gaps(area_range);
+            if let Some(observer) = self.observer() {
+                observer.on_map(area_range, flags);
+            }
+        }
+        self.committed += core::mem::take(&mut detached.committed);
+        self.coalesce(range);
+        Ok(())
+    }
+
+    /// Moves the areas at or above `addr` into a new set, splitting the area
+    /// containing `addr`, like [`BTreeMap::split_off`], e.g., to carve the
+    /// kernel half off an address space or to hand a region to another
+    /// manager.
+    ///
+    /// Only the bookkeeping changes: the areas keep their frames, and their
+    /// mappings stay in the page table, managed by the new set from then on.
+    /// The new set has the configuration of this one, except for its label,
+    /// placement strategy and observer, and counts its own frame usage.
+    ///
+    /// Fails if the area containing `addr` cannot be split there, see
+    /// [`MemoryArea::split`], or if the set is in MPU mode, leaving the set
+    /// unchanged.
+    pub fn split_off(&mut self, addr: B::Addr) -> MappingResult<Self> {
+        self.bump_generation();
+        if let Some(area) = self.find(addr)
+            && self.mpu.is_some()
+            && area.start() != addr
+        {
+            return Err(MappingError::InvalidParam(untyped(area.va_range())));
+        }
+        self.split_at(addr)?;
+        let mut new_set = self.inherit_config();
+        new_set.areas = self.areas.split_off(&addr);
+        let charge = new_set.commit_charge();
+        self.hand_over_commit(&mut new_set, charge);
+        self.rebuild_gaps();
+        new_set.rebuild_gaps();
+        Ok(new_set)
+    }
+
+    /// Moves all the areas of `other` into this set, mapping them in
+    /// `page_table` with their frames, e.g., to fold a partition of the
+    /// address space back into the main set.
+    ///
+    /// The areas of `other` must not overlap the ones of this set, and must
+    /// fit its MPU and size limits. The mappings in the page table of `other`
+    /// are left as they are, to be discarded by the caller. On success,
+    /// `other` is left empty, like [`Vec::append`]; if anything fails,
+    /// nothing is moved.
+    pub fn absorb(&mut self, other: &mut Self, page_table: &mut B::PageTable) -> MappingResult {
+        self.bump_generation();
+        if let Some(area) = other
+            .iter()
+            .find(|area| self.overlaps(area.reserved_range()))
+        {
+            return Err(MappingError::AlreadyExists(untyped(area.reserved_range())));
+        }
+        self.check_mpu_regions(other.iter().map(|area| area.va_range()), 0)?;
+        self.check_size_limit(other.span(), other.total_size(), 0)?;
+        let failed = other
+            .areas
+            .values_mut()
+            .enumerate()
+            .find_map(|(i, area)| area.remap_area(page_table).err().map(|err| (i, err)));
+        if let Some((mapped, err)) = failed {
+            for area in other.areas.values().take(mapped) {
+                let _ = area.unmap_area_keep_frames(page_table);
+            }
+            return Err(err);
+        }
+        other.bump_generation();
+        for area in core::mem::take(&mut other.areas).into_values() {
+            let range = area.va_range();
+            self.areas.insert(area.start(), area);
+            self.refresh_gaps(range);
+        }
+        other.hand_over_commit(self, other.committed);
+        other.rebuild_gaps();
+        Ok(())
+    }
+
+    /// Moves the mappings of `[old_start, old_start + size)` to `new_start`
+    /// without unmapping the old range, like `mremap` with
+    /// `MREMAP_DONTUNMAP`.
+    ///
+    /// The old range must be inside a single area, and the new range must be
+    /// free. The page table entries (and frames, if RAII is on) are moved by
+    /// [`MappingBackend::move_mappings`] into a new area with the same flags,
+    /// backend and label. The old range stays in the set and is mapped again as if
+    /// it was newly created, so a lazy backend will demand-fault it afresh.
+    pub fn remap_dontunmap(
+        &mut self,
+        old_start: B::Addr,
+        size: usize,
+        new_start: B::Addr,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.bump_generation();
+        let old_range = self.granular_range(old_start, size)?;
+        let size = old_range.size();
+        let new_range = AddrRange::try_from_start_size(new_start, size)
+            .ok_or(MappingError::InvalidParam(err_range(new_start, size)))?;
+        let area = self
+            .find(old_start)
+            .filter(|area| !old_range.is_empty() && old_range.contained_in(area.va_range()))
+            .ok_or(MappingError::InvalidParam(untyped(old_range)))?;
+        if area.is_reserved() {
+            return Err(MappingError::InvalidParam(untyped(old_range)));
+        }
+        if !new_start.is_aligned(area.granularity()) {
+            return Err(MappingError::InvalidParam(untyped(new_range)));
+        }
+        if self.overlaps(new_range) {
+            return Err(MappingError::AlreadyExists(untyped(new_range)));
+        }
+        self.check_mpu_whole(old_range)?;
+        self.check_mpu_regions([new_range], 0)?;
+        self.check_sealed(old_range)?;
+        self.check_size_limit(new_range, size, 0)?;
+
+        let area = self.find_mut(old_start).unwrap();
+        let flags = area.flags();
+        let backend = area.backend().clone();
+        let label = area.label().map(Into::into);
+        // Split the huge frames crossing the moved range first, so that
+        // taking its frames cannot fail once the mappings are moved.
+        #[cfg(feature = "RAII")]
+        {
+            area.frames.demote(old_start)?;
+            area.frames.demote(old_start.add(size))?;
+        }
+        area.backend_move_mappings(old_start, new_start, size, flags, page_table)?;
+        #[cfg(feature = "RAII")]
+        let frames = area
+            .take_frames(old_start, size)
+            .moved(old_start, new_start);
+
+        // Leave the old range present but empty.
+        match area.backend_map(old_start, size, flags, page_table) {
+            #[cfg(feature = "RAII")]
+            Ok(refilled) => area.frames.extend(refilled),
+            #[cfg(not(feature = "RAII"))]
+            Ok(()) => {}
+            Err(err) => {
+                // Roll back so that the old range keeps its contents.
+                let _ = backend.move_mappings(new_start, old_start, size, flags, page_table);
+                #[cfg(feature = "RAII")]
+                area.frames.append(&mut frames.moved(new_start, old_start));
+                return Err(backend_error(old_start, size, err));
+            }
+        }
+
+        let mut new_area = MemoryArea::new(
+            new_start,
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            flags,
+            backend,
+        );
+        new_area.set_label(label);
+        #[cfg(feature = "RAII")]
+        {
+            new_area.frames = frames;
+        }
+        assert!(self.areas.insert(new_start, new_area).is_none());
+        self.refresh_gaps(new_range);
+        Ok(())
+    }
+
+    /// Resizes the mapping of `[old_start, old_start + old_size)` to
+    /// `new_size` bytes, possibly moving it, like `mremap`.
+    ///
+    /// The old range must be inside a single area, and `new_size` is rounded
+    /// up to the granularity of that area.
+    ///
+    /// - Shrinking unmaps the tail of the old range in place.
+    /// - Growing extends the area in place if the old range is at its end and
+    ///   the following range is free.
+    /// - Otherwise, if [`RemapFlags::may_move`] is set, the old range is split
+    ///   off into its own area and moved to a free region with
+    ///   [`MappingBackend::move_mappings`], carrying its frames (if RAII is on)
+    ///   and per-area state along, and then grown there. With
+    ///   [`RemapFlags::fixed`], it is always moved to the given address.
+    ///
+    /// The guard regions of the area (see [`MemoryArea::with_guards`]) stay
+    /// at its ends: they move along with the old range if it is at an end
+    /// of the area, and have to be free at the new place as well.
+    ///
+    /// Returns the start address of the resized mapping.
+    pub fn remap(
+        &mut self,
+        old_start: B::Addr,
+        old_size: usize,
+        new_size: usize,
+        flags: RemapFlags<B::Addr>,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult<B::Addr> {
+        self.bump_generation();
+        let old_range = self.granular_range(old_start, old_size)?;
+        let area = self
+            .find(old_start)
+            .filter(|area| !old_range.is_empty() && old_range.contained_in(area.va_range()))
+            .ok_or(MappingError::InvalidParam(untyped(old_range)))?;
+        let granularity = area.granularity();
+        if new_size == 0 {
+            return Err(MappingError::InvalidParam(untyped(old_range)));
+        }
+        let new_size = new_size
+            .checked_add(granularity - 1)
+            .ok_or(MappingError::InvalidParam(err_range(old_start, new_size)))?
+            & !(granularity - 1);
+        let old_size = old_range.size();
+        let area_range = area.va_range();
+        let charge = area.growth_charge(new_size.saturating_sub(old_size));
+        // The guards of the area go along with the parts at its ends.
+        let (leading, trailing) = area.guards();
+        let leading = if old_range.start == area_range.start {
+            leading
+        } else {
+            0
+        };
+        let trailing = if old_range.end == area_range.end {
+            trailing
+        } else {
+            0
+        };
+        self.check_sealed(old_range)?;
+
+        if let Some(new_start) = flags.fixed {
+            let new_range = AddrRange::try_from_start_size(new_start, new_size)
+                .ok_or(MappingError::InvalidParam(err_range(new_start, new_size)))?;
+            if !new_start.is_aligned(granularity) || new_range.overlaps(old_range) {
+                return Err(MappingError::InvalidParam(untyped(new_range)));
+            }
+            let new_reserved = new_start
+                .checked_sub(leading)
+                .zip(new_range.end.checked_add(trailing))
+                .map(|(start, end)| AddrRange::new(start, end))
+                .ok_or(MappingError::InvalidParam(untyped(new_range)))?;
+            if self.overlaps_guards(new_range, new_reserved) {
+                return Err(MappingError::AlreadyExists(untyped(new_reserved)));
+            }
+            self.check_mpu_whole(old_range)?;
+            self.check_mpu_regions([new_range], 1)?;
+            self.check_size_limit(new_range, new_size, old_size + self.size_in(new_range))?;
+            self.check_commit(new_range, charge)?;
+            let result = self
+                .unmap(new_start, new_size, page_table)
+                .and_then(|_| self.move_range(old_range, new_start, new_size, page_table));
+            self.settle_commit();
+            return result.map(|_| new_start);
+        }
+
+        if new_size <= old_size {
+            if new_size < old_size {
+                self.unmap(old_start.add(new_size), old_size - new_size, page_table)?;
+            }
+            return Ok(old_start);
+        }
+        self.check_size_limit(old_range, new_size, old_size)?;
+
+        let grow_range = old_start
+            .checked_add(new_size)
+            .and_then(|new_end| AddrRange::try_new(old_range.end, new_end));
+        // The trailing guard moves up by the grown size.
+        let newly_reserved = grow_range.and_then(|range| {
+            let start = range.start.checked_add(trailing)?;
+            AddrRange::try_new(start, range.end.checked_add(trailing)?)
+        });
+        if let Some(grow_range) = grow_range
+            && let Some(newly_reserved) = newly_reserved
+            && old_range.end == area_range.end
+            && !self.overlaps(newly_reserved)
+            && self
+                .check_mpu_regions([AddrRange::new(area_range.start, grow_range.end)], 1)
+                .is_ok()
+        {
+            self.check_commit(grow_range, charge)?;
+            let area = self.areas.get_mut(&area_range.start).unwrap();
+            // Safety: the grown part is checked to be free above.
+            let result =
+                unsafe { area.extend_right(grow_range.end.sub_addr(area_range.start), page_table) };
+            self.refresh_gaps(grow_range);
+            self.settle_commit();
+            return result.map(|_| old_start);
+        }
+
+        let grown = err_range(old_start, new_size);
+        let limit = flags.may_move.ok_or(MappingError::AlreadyExists(grown))?;
+        let constraint = FreeAreaConstraint::new().with_mask(granularity - 1, 0);
+        let new_start = (leading + trailing)
+            .checked_add(new_size)
+            .and_then(|size| self.find_free_area_constrained(limit.start, size, limit, &constraint))
+            .ok_or(MappingError::AlreadyExists(grown))?
+            .add(leading);
+        self.check_mpu_whole(old_range)?;
+        let new_range = AddrRange::from_start_size(new_start, new_size);
+        self.check_mpu_regions([new_range], 1)?;
+        self.check_commit(new_range, charge)?;
+        let result = self.move_range(old_range, new_start, new_size, page_table);
+        self.settle_commit();
+        result.map(|_| new_start)
+    }
+
+    /// Moves the area starting at `old_start` to `new_start` and changes its
+    /// flags to `new_flags` in one step, e.g., for a dynamic linker moving a
+    /// segment to its randomized base and applying RELRO protection.
+    ///
+    /// The page table entries are moved by [`MappingBackend::move_mappings`]
+    /// with the new flags, so the contents are never mapped with the old
+    /// flags at the new address. The frames (if RAII is on), per-area state
+    /// and backend state (see [`MappingBackend::relocate`]) move along.
+    ///
+    /// The guard regions of the area move along. The change is reported to
+    /// the [observer](Self::with_observer) as the unmapping of the old range
+    /// and the mapping of the new one.
+    ///
+    /// Returns [`MappingError::NotMapped`] if no area starts at `old_start`,
+    /// [`MappingError::InvalidParam`] if `new_start` is misaligned or the new
+    /// range overlaps the old one, and [`MappingError::AlreadyExists`] if
+    /// the new range or its guard regions are not free.
+    pub fn remap_area_flags_and_base(
+        &mut self,
+        old_start: B::Addr,
+        new_start: B::Addr,
+        new_flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.bump_generation();
+        let area = self
+            .areas
+            .get(&old_start)
+            .ok_or(MappingError::NotMapped(err_range(old_start, 0)))?;
+        let old_range = area.va_range();
+        if area.is_sealed() {
+            return Err(MappingError::PermissionDenied(untyped(old_range)));
+        }
+        let new_range = AddrRange::try_from_start_size(new_start, area.size()).ok_or(
+            MappingError::InvalidParam(err_range(new_start, area.size())),
+        )?;
+        if !new_start.is_aligned(area.granularity()) || new_range.overlaps(old_range) {
+            return Err(MappingError::InvalidParam(untyped(new_range)));
+        }
+        // The guard regions may cover the old range, which is freed, but not
+        // the other areas.
+        let reserved = area.reserved_range();
+        let new_reserved = new_start
+            .checked_sub(old_start.sub_addr(reserved.start))
+            .zip(
+                new_range
+                    .end
+                    .checked_add(reserved.end.sub_addr(old_range.end)),
+            )
+            .map(|(start, end)| AddrRange::new(start, end))
+            .ok_or(MappingError::InvalidParam(untyped(new_range)))?;
+        let other = |area: &&MemoryArea<B>| area.start() != old_start;
+        let prev = self.areas.range(..new_reserved.start).rev();
+        let prev = prev.map(|(_, area)| area).find(other);
+        let next = self.areas.range(new_reserved.start..);
+        let next = next.map(|(_, area)| area).find(other);
+        if [prev, next]
+            .into_iter()
+            .flatten()
+            .any(|other| other.reserved_range().overlaps(new_reserved))
+        {
+            return Err(MappingError::AlreadyExists(untyped(new_range)));
+        }
+        self.check_mpu_regions([new_range], 1)?;
+
+        let mut area = self.areas.remove(&old_start).unwrap();
+        if !area.is_reserved()
+            && let Err(err) =
+                area.backend_move_mappings(old_start, new_start, area.size(), new_flags, page_table)
+        {
+            self.areas.insert(old_start, area);
+            return Err(err);
+        }
+        area.relocate(new_start);
+        area.set_flags(new_flags);
+        area.times_mut().last_protect = self.now();
+        let result = if area.is_write_protected() {
+            // Keep copy-on-write pages read-only under the new flags.
+            area.write_protect(page_table)
+        } else {
+            Ok(())
+        };
+        self.areas.insert(new_start, area);
+        self.refresh_gaps(old_range);
+        self.refresh_gaps(new_range);
+        if let Some(observer) = self.observer() {
+            observer.on_unmap(old_range);
+            observer.on_map(new_range, new_flags);
+        }
+        result
+    }
+
+    /// Moves `old_range`, which must be inside a single area, to the free
+    /// range `[new_start, new_start + new_size)` as an area of its own.
+    fn move_range(
+        &mut self,
+        old_range: AddrRange<B::Addr>,
+        new_start: B::Addr,
+        new_size: usize,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        let result = self.move_area(old_range, new_start, new_size, page_table);
+        self.refresh_gaps(old_range);
+        self.refresh_gaps(AddrRange::from_start_size(new_start, new_size));
+        result
+    }
+
+    /// Does the work of [`move_range`](Self::move_range), without updating
+    /// the gap index.
+    fn move_area(
+        &mut self,
+        old_range: AddrRange<B::Addr>,
+        new_start: B::Addr,
+        new_size: usize,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.split_at(old_range.start)?;
+        self.split_at(old_range.end)?;
+        let mut area = self.areas.remove(&old_range.start).unwrap();
+        let old_size = old_range.size();
+        if new_size < old_size
+            && let Err(err) = area.shrink_right(new_size, page_table)
+        {
+            self.areas.insert(old_range.start, area);
+            return Err(err);
+        }
+
+        let moved_size = area.size();
+        let (flags, backend) = (area.flags(), area.backend().clone());
+        // Nothing is mapped in a reserved area, so only the bookkeeping moves.
+        if !area.is_reserved()
+            && let Err(err) = area.backend_move_mappings(
+                old_range.start,
+                new_start,
+                moved_size,
+                flags,
+                page_table,
+            )
+        {
+            self.areas.insert(area.start(), area);
+            return Err(err);
+        }
+        area.relocate(new_start);
+        if new_size > moved_size {
+            // Safety: the target range is free.
+            if let Err(err) = unsafe { area.extend_right(new_size, page_table) } {
+                // Put the moved part back where it was.
+                let _ = backend.move_mappings(
+                    new_start,
+                    old_range.start,
+                    moved_size,
+                    flags,
+                    page_table,
+                );
+                area.relocate(old_range.start);
+                self.areas.insert(old_range.start, area);
+                return Err(err);
+            }
+        }
+        assert!(self.areas.insert(new_start, area).is_none());
+        Ok(())
+    }
+
+    /// Moves the bounds of the area starting at `area_addr` to `[start, end)`,
+    /// mapping the parts it grows by and unmapping those it shrinks by.
+    ///
+    /// The new range must overlap the old one. Returns
+    /// [`MappingError::NotMapped`] if no area starts at `area_addr`, and
+    /// [`MappingError::AlreadyExists`] if the area would grow into another
+    /// one or its guard regions.
+    pub fn adjust_area(
+        &mut self,
+        area_addr: B::Addr,
+        start: B::Addr,
+        end: B::Addr,
+        page_table: &mut B::PageTable,
+    ) -> Result<(), MappingError> {
+        self.bump_generation();
+        let range = AddrRange::try_new(start, end)
+            .ok_or(MappingError::InvalidParam(err_range(start, 0)))?;
+        if let Some(mpu) = self.mpu
+            && !mpu.is_valid_region(range)
+        {
+            return Err(MappingError::InvalidParam(untyped(range)));
+        }
+        let area = self
+            .areas
+            .get(&area_addr)
+            .ok_or(MappingError::NotMapped(err_range(area_addr, 0)))?;
+        if area.is_sealed() {
+            return Err(MappingError::PermissionDenied(untyped(area.va_range())));
+        }
+        let granularity = area.granularity();
+
+        // 检查新的范围是否有效
+        if start >= end
+            || !start.is_aligned(granularity)
+            || !end.is_aligned(granularity)
+            || !range.overlaps(area.va_range())
+        {
+            return Err(MappingError::InvalidParam(untyped(range)));
+        }
+
+        // The area keeps its guard regions, which must stay clear of its
+        // neighbors.
+        let reserved = area.reserved_range();
+        let new_reserved = AddrRange::new(
+            start.wrapping_sub(area.start().sub_addr(reserved.start)),
+            end.wrapping_add(reserved.end.sub_addr(area.end())),
+        );
+        let (prev, _, next) = self.areas.floor_with_neighbors(&area_addr);
+        if [prev, next]
+            .into_iter()
+            .flatten()
+            .any(|other| other.reserved_range().overlaps(new_reserved))
+        {
+            return Err(MappingError::AlreadyExists(untyped(range)));
+        }
+
+        let span = AddrRange::new(start.min(area.start()), end.max(area.end()));
+        let old_size = area.size();
+        let charge = area.growth_charge(end.sub_addr(start).saturating_sub(old_size));
+        self.check_size_limit(range, end.sub_addr(start), old_size)?;
+        self.check_commit(range, charge)?;
+        // The area is keyed by its start, which may move even if resizing
+        // fails halfway.
+        let mut area = self.areas.remove(&area_addr).unwrap();
+        let result = Self::resize_area(&mut area, start, end, page_table);
+        self.areas.insert(area.start(), area);
+        self.refresh_gaps(span);
+        self.settle_commit();
+        result
+    }
+
+    /// Does the work of [`adjust_area`](Self::adjust_area) on the area.
+    fn resize_area(
+        area: &mut MemoryArea<B>,
+        start: B::Addr,
+        end: B::Addr,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        // 当前区域的边界
+        let current_start = area.start();
+        let current_end = area.end();
+
+        // 处理左边界的变化
+        if start != current_start {
+            if start < current_start {
+                // 需要向左扩展
+                // 新的总size = (current_end - start)
+                unsafe {
+                    area.extend_left(current_end.sub_addr(start), page_table)?;
+                }
+            } else {
+                // 需要向右收缩
+                // 新的总size = (current_end - start)
+
+                area.shrink_left(current_end.sub_addr(start), page_table)?;
+            }
+        }
+
+        // 处理右边界的变化
+        if end != current_end {
+            if end > current_end {
+                // 需要向右扩展
+                // 新的总size = (end - start)
+                unsafe {
+                    area.extend_right(end.sub_addr(start), page_table)?;
+                }
+            } else {
+                // 需要向左收缩
+                // 新的总size = (end - start)
+                area.shrink_right(end.sub_addr(start), page_table)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove all memory areas and the underlying mappings, except the holes
+    /// (see [`add_hole`](Self::add_hole)).
+    ///
+    /// Fails with [`MappingError::PermissionDenied`] if any area is sealed.
+    pub fn clear(&mut self, page_table: &mut B::PageTable) -> MappingResult {
+        self.bump_generation();
+        if let Some(area) = self
+            .areas
+            .values()
+            .find(|area| area.is_sealed() && !area.is_hole())
+        {
+            return Err(MappingError::PermissionDenied(untyped(area.va_range())));
+        }
+        let result = self
+            .areas
+            .values_mut()
+            .filter(|area| !area.is_hole())
+            .try_for_each(|area| area.unmap_area(page_table));
+        if result.is_ok() {
+            self.areas.retain(|_, area| area.is_hole());
+            self.rebuild_gaps();
+        }
+        self.settle_commit();
+        result
+    }
+
+    /// Remove the memory areas that are fully contained in the given range,
+    /// and their underlying mappings.
+    ///
+    /// Unlike [`unmap`](Self::unmap), areas crossing the range boundaries are
+    /// left untouched instead of being shrunk or split, and so are the holes
+    /// like in [`clear`](Self::clear). If unmapping an area fails, the areas
+    /// removed so far stay removed and the failing one is kept.
+    ///
+    /// Fails with [`MappingError::PermissionDenied`] if any area to remove
+    /// is sealed.
+    pub fn clear_range(
+        &mut self,
+        range: AddrRange<B::Addr>,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.bump_generation();
+        let contained: Vec<_> = self
+            .areas
+            .range(range.start..range.end.max(range.start))
+            .filter(|(_, area)| area.end() <= range.end && !area.is_hole())
+            .map(|(&start, area)| {
+                if area.is_sealed() {
+                    return Err(MappingError::PermissionDenied(untyped(area.va_range())));
+                }
+                Ok(start)
+            })
+            .collect::<MappingResult<_>>()?;
+        let mut result = Ok(());
+        for start in contained {
+            result = self.areas.get_mut(&start).unwrap().unmap_area(page_table);
+            if result.is_err() {
+                break;
+            }
+            self.areas.remove(&start);
+        }
+        self.refresh_gaps(range);
+        self.settle_commit();
+        result
+    }
+
+    /// Handles a page fault at `vaddr` with the access described by
+    /// `access_flags`.
+    ///
+    /// Locates the containing area, checks the access against its flags with
+    /// [`MappingBackend::check_access`], and resolves the fault with
+    /// [`MemoryArea::handle_fault`]. Returns [`MappingError::NotMapped`] or
+    /// [`MappingError::PermissionDenied`] if the fault is a genuine access
+    /// violation. Faults in [holes](Self::add_hole) and guard regions are not
+    /// mapped, and faults in reserved areas are denied.
+    pub fn handle_page_fault(
+        &mut self,
+        vaddr: B::Addr,
+        access_flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        // Faults only change the pages of the area, so the views of the
+        // readers stay current.
+        self.generation += 1;
+        let area = self
+            .find(vaddr)
+            .filter(|area| !area.is_hole())
+            .ok_or(MappingError::NotMapped(err_range(vaddr, 1)))?;
+        if !area.backend().check_access(area.flags(), access_flags) {
+            return Err(MappingError::PermissionDenied(err_range(vaddr, 1)));
+        }
+        let area_start = area.start();
+        self.fault_area(area_start, vaddr, access_flags, page_table)?;
+        let now = self.now();
+        let area = self.areas.get_mut(&area_start).unwrap();
+        area.times_mut().last_fault = now;
+        Ok(())
+    }
+
+    /// Handles a fault at `vaddr` in the area starting at `area_start`,
+    /// within the frame quota of the set if RAII is on.
+    pub(crate) fn fault_area(
+        &mut self,
+        area_start: B::Addr,
+        vaddr: B::Addr,
+        access_flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        #[cfg(feature = "RAII")]
+        return self.fault_in_quota(area_start, vaddr, access_flags, page_table);
+        #[cfg(not(feature = "RAII"))]
+        return self.areas.get_mut(&area_start).unwrap().handle_fault(
+            vaddr,
+            access_flags,
+            page_table,
+        );
+    }
 
-        if area.va_range().is_empty() {
-            return Err(MappingError::InvalidParam);
+    /// Checks that `range` is fully covered by areas allowing an access
+    /// described by `access_flags` according to
+    /// [`MappingBackend::check_access`], like `access_ok`, e.g., before
+    /// copying from or to user memory. Nothing is faulted in.
+    ///
+    /// Returns the first hole or the first area denying the access, in
+    /// ascending order of addresses. An empty range is always accessible.
+    pub fn check_access(
+        &self,
+        range: AddrRange<B::Addr>,
+        access_flags: B::Flags,
+    ) -> Result<(), AccessError> {
+        let mut covered = range.start;
+        for area in self.iter_range(range) {
+            if area.start() > covered {
+                let hole = AddrRange::new(covered, area.start());
+                return Err(AccessError::NotMapped(untyped(hole)));
+            }
+            if !area.backend().check_access(area.flags(), access_flags) {
+                return Err(AccessError::PermissionDenied(untyped(area.va_range())));
+            }
+            covered = area.end();
         }
-
-        if self.overlaps(area.va_range()) && !unmap_overlap {
-            return Err(MappingError::AlreadyExists);
+        if covered < range.end {
+            let hole = AddrRange::new(covered, range.end);
+            return Err(AccessError::NotMapped(untyped(hole)));
         }
-        assert!(self.areas.insert(area.start(), area).is_none());
         Ok(())
     }
-    pub fn delete(&mut self, vaddr: B::Addr) {
-        self.areas.remove(&vaddr);
-    }
-    /// Add a new memory mapping.
-    ///
-    /// The mapping is represented by a [`MemoryArea`].
+
+    /// Faults in the pages within `[start, start + size)` that are not
+    /// resident yet, as if they were accessed with `access_flags`, like
+    /// `MAP_POPULATE` or `MADV_POPULATE_*`.
     ///
-    /// If the new area overlaps with any existing area, the behavior is
-    /// determined by the `unmap_overlap` parameter. If it is `true`, the
-    /// overlapped regions will be unmapped first. Otherwise, it returns an
-    /// error.
-    pub fn map(
+    /// The range must be fully covered by areas allowing the access, none of
+    /// them [reserved](MemoryArea::is_reserved) (holes fail with
+    /// [`MappingError::NotMapped`], other reserved areas with
+    /// [`MappingError::PermissionDenied`]). Before each page, [`MappingBackend::memory_pressure`] is checked, and if it
+    /// reports pressure, populating stops and the page to resume from is
+    /// returned instead of failing the whole operation.
+    pub fn populate(
         &mut self,
-        mut area: MemoryArea<B>,
+        start: B::Addr,
+        size: usize,
+        access_flags: B::Flags,
         page_table: &mut B::PageTable,
-        unmap_overlap: bool,
-        overwrite_flags: Option<B::Flags>,
-    ) -> MappingResult {
-        if area.va_range().is_empty() {
-            return Err(MappingError::InvalidParam);
+    ) -> MappingResult<Populated<B::Addr>> {
+        self.bump_generation();
+        let range = self.granular_range(start, size)?;
+        let mut populated = Populated {
+            pages: 0,
+            resume_at: None,
+        };
+        if range.is_empty() {
+            return Ok(populated);
         }
-
-        if self.overlaps(area.va_range()) {
-            if unmap_overlap {
-                self.unmap(area.start(), area.size(), page_table)?;
+        self.check_access(range, access_flags)?;
+        // Faults in these fail, so nothing is populated.
+        if let Some(area) = self.iter_range(range).find(|area| area.is_reserved()) {
+            let range = untyped(area.va_range());
+            return Err(if area.is_hole() {
+                MappingError::NotMapped(range)
             } else {
-                return Err(MappingError::AlreadyExists);
+                MappingError::PermissionDenied(range)
+            });
+        }
+
+        let candidates: Vec<_> = self.area_starts_in(range).collect();
+        for area_start in candidates {
+            let area = &self.areas[&area_start];
+            let page_size = area.page_size();
+            let end = range.end.min(area.end());
+            let mut page = range.start.max(area.start()).align_down(page_size);
+            while page < end {
+                let area = &self.areas[&area_start];
+                if !area.is_resident(page) {
+                    if area.backend().memory_pressure() {
+                        populated.resume_at = Some(page);
+                        return Ok(populated);
+                    }
+                    self.fault_area(area_start, page, access_flags, page_table)?;
+                    populated.pages += 1;
+                }
+                page = page.add(page_size);
             }
         }
+        Ok(populated)
+    }
 
-        area.map_area(page_table, overwrite_flags)?;
-        assert!(self.areas.insert(area.start(), area).is_none());
+    /// Clears the soft-dirty marks of the pages within the given range, and
+    /// write-protects them so that later writes are recorded.
+    ///
+    /// See [`MemoryArea::clear_soft_dirty`].
+    pub fn clear_soft_dirty(
+        &mut self,
+        range: AddrRange<B::Addr>,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.bump_generation();
+        let candidates: Vec<_> = self.area_starts_in(range).collect();
+        for area_start in candidates {
+            let area = self.areas.get_mut(&area_start).unwrap();
+            area.clear_soft_dirty(range, page_table)?;
+        }
         Ok(())
     }
 
-    /// Remove memory mappings within the given address range.
+    /// Applies an [`Advice`] to `[start, start + size)`, like `madvise`.
     ///
-    /// All memory areas that are fully contained in the range will be removed
-    /// directly. If the area intersects with the boundary, it will be shrinked.
-    /// If the unmapped range is in the middle of an existing area, it will be
-    /// split into two areas.
-    pub fn unmap(
+    /// The range must be fully covered by areas, otherwise
+    /// [`MappingError::NotMapped`] is returned and nothing is done. Dropping
+    /// the contents of locked areas or of [device](MappingKind::Device)
+    /// memory is refused with [`MappingError::InvalidParam`]. For
+    /// [`Advice::HugePage`] and [`Advice::NoHugePage`], areas crossing the
+    /// boundaries of the range are split first. See [`MemoryArea::advise`]
+    /// for how each area handles the advice.
+    pub fn advise(
         &mut self,
         start: B::Addr,
         size: usize,
+        advice: Advice,
         page_table: &mut B::PageTable,
     ) -> MappingResult {
-        let range =
-            AddrRange::try_from_start_size(start, size).ok_or(MappingError::InvalidParam)?;
+        self.bump_generation();
+        let range = self.granular_range(start, size)?;
         if range.is_empty() {
             return Ok(());
         }
+        self.check_covered(range)?;
+        if matches!(advice, Advice::DontNeed | Advice::Free) {
+            if let Some(area) = self.iter_range(range).find(|area| {
+                area.is_locked() || area.backend().mapping_kind() == MappingKind::Device
+            }) {
+                return Err(MappingError::InvalidParam(untyped(area.va_range())));
+            }
+            self.check_sealed(range)?;
+        }
+        if matches!(advice, Advice::HugePage | Advice::NoHugePage) {
+            // The advice is recorded per area.
+            self.check_mpu_whole(range)?;
+            self.split_at(range.start)?;
+            self.split_at(range.end)?;
+        }
+        let candidates: Vec<_> = self.area_starts_in(range).collect();
+        for area_start in candidates {
+            let area = self.areas.get_mut(&area_start).unwrap();
+            area.advise(range, advice, page_table)?;
+        }
+        Ok(())
+    }
 
-        let end = range.end;
+    /// Locks `[start, start + size)` in memory, like `mlock`.
+    ///
+    /// The range must be fully covered by areas other than holes, otherwise
+    /// [`MappingError::NotMapped`] is returned. Areas crossing the boundaries
+    /// of the range are split, and the parts within it are marked as locked,
+    /// see [`MemoryArea::is_locked`]. The frames of locked areas must not be
+    /// swapped out or reclaimed, and their contents are not dropped by
+    /// [`advise`](Self::advise).
+    pub fn lock(&mut self, start: B::Addr, size: usize) -> MappingResult {
+        self.set_locked(start, size, true)
+    }
 
-        // Unmap entire areas that are contained by the range.
-        self.areas.retain(|_, area| {
-            if area.va_range().contained_in(range) {
-                area.unmap_area(page_table).unwrap();
-                false
-            } else {
-                true
-            }
-        });
+    /// Unlocks `[start, start + size)`, like `munlock`.
+    ///
+    /// See [`lock`](Self::lock).
+    pub fn unlock(&mut self, start: B::Addr, size: usize) -> MappingResult {
+        self.set_locked(start, size, false)
+    }
 
-        // Shrink right if the area intersects with the left boundary.
-        if let Some((&before_start, before)) = self.areas.range_mut(..start).last() {
-            let before_end = before.end();
-            if before_end > start {
-                if before_end <= end {
-                    // the unmapped area is at the end of `before`.
-                    before.shrink_right(start.sub_addr(before_start), page_table)?;
-                } else {
-                    // the unmapped area is in the middle `before`, need to split.
-                    let right_part = before.split(end).unwrap();
-                    before.shrink_right(start.sub_addr(before_start), page_table)?;
-                    assert_eq!(right_part.start().into(), Into::<usize>::into(end));
-                    self.areas.insert(end, right_part);
-                }
-            }
+    fn set_locked(&mut self, start: B::Addr, size: usize, locked: bool) -> MappingResult {
+        self.bump_generation();
+        let range = self.granular_range(start, size)?;
+        if range.is_empty() {
+            return Ok(());
         }
-
-        // Shrink left if the area intersects with the right boundary.
-        if let Some((&after_start, after)) = self.areas.range_mut(start..).next() {
-            let after_end = after.end();
-            if after_start < end {
-                // the unmapped area is at the start of `after`.
-                let mut new_area = self.areas.remove(&after_start).unwrap();
-                new_area.shrink_left(after_end.sub_addr(end), page_table)?;
-                assert_eq!(new_area.start().into(), Into::<usize>::into(end));
-                self.areas.insert(end, new_area);
-            }
+        self.check_covered(range)?;
+        if let Some(hole) = self.iter_range(range).find(|area| area.is_hole()) {
+            return Err(MappingError::NotMapped(untyped(hole.va_range())));
         }
+        self.check_mpu_whole(range)?;
+        self.split_at(range.start)?;
+        self.split_at(range.end)?;
+        let candidates: Vec<_> = self.area_starts_in(range).collect();
+        for area_start in candidates {
+            self.areas.get_mut(&area_start).unwrap().set_locked(locked);
+        }
+        self.coalesce(range);
+        Ok(())
+    }
 
+    /// Seals `[start, start + size)`, like `mseal`, e.g., for the kernel text
+    /// and read-only data.
+    ///
+    /// The range must be fully covered by areas, otherwise
+    /// [`MappingError::NotMapped`] is returned. Areas crossing the boundaries
+    /// of the range are split, and the parts within it are sealed for good,
+    /// see [`MemoryArea::is_sealed`]. From then on, the operations of the set
+    /// that would unmap, move, resize, replace or change the flags of a
+    /// sealed area fail with [`MappingError::PermissionDenied`], as do
+    /// [`advise`](Self::advise) calls that would drop its contents.
+    pub fn seal(&mut self, start: B::Addr, size: usize) -> MappingResult {
+        self.bump_generation();
+        let range = self.granular_range(start, size)?;
+        if range.is_empty() {
+            return Ok(());
+        }
+        self.check_covered(range)?;
+        self.check_mpu_whole(range)?;
+        self.split_at(range.start)?;
+        self.split_at(range.end)?;
+        let candidates: Vec<_> = self.area_starts_in(range).collect();
+        for area_start in candidates {
+            self.areas.get_mut(&area_start).unwrap().seal();
+        }
         Ok(())
     }
 
-    pub fn adjust_area(
-        &mut self,
-        area_addr: B::Addr,
-        start: B::Addr,
-        end: B::Addr,
+    /// Maps the given areas and seals them, e.g., the kernel text and
+    /// read-only data when the kernel address space is built.
+    ///
+    /// The areas should be mapped read-only or read/execute, since their
+    /// flags can never change afterwards. See [`seal`](Self::seal).
+    pub fn with_sealed(
+        mut self,
+        areas: impl IntoIterator<Item = MemoryArea<B>>,
         page_table: &mut B::PageTable,
-    ) -> Result<(), MappingError> {
-        let area = self.areas.get_mut(&area_addr).unwrap();
-        assert!(start.is_aligned_4k());
-        assert!(end.is_aligned_4k());
-
-        // 检查新的范围是否有效
-        if start >= end {
-            return Err(MappingError::InvalidParam);
+    ) -> MappingResult<Self> {
+        for area in areas {
+            let range = area.va_range();
+            self.map(area, page_table, false, None)?;
+            self.seal(range.start, range.size())?;
         }
+        Ok(self)
+    }
 
-        // 当前区域的边界
-        let current_start = area.start();
-        let current_end = area.end();
+    /// Converts `[start, start + size)` to memory shared with the host in a
+    /// confidential VM (e.g., with SEV or TDX), e.g., for I/O buffers.
+    ///
+    /// The range must be fully covered by areas, otherwise
+    /// [`MappingError::NotMapped`] is returned. Sealed areas are refused with
+    /// [`MappingError::PermissionDenied`], and areas mapping a shared object
+    /// or file with [`MappingError::InvalidParam`], since their frames are
+    /// mapped elsewhere too. Areas crossing the boundaries of the range are
+    /// split, and the parts within it are converted with
+    /// [`MappingBackend::convert_confidentiality`] after their frames shared
+    /// copy-on-write were copied, then take the
+    /// [flags](MappingBackend::confidential_flags) of shared memory. If a
+    /// conversion fails, the parts already converted are converted back and
+    /// [`MappingError::BadState`] is returned.
+    pub fn convert_private_to_shared(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.convert_range(start, size, Confidentiality::Shared, page_table)
+    }
 
-        // 处理左边界的变化
-        if start != current_start {
-            if start < current_start {
-                // 需要向左扩展
-                // 新的总size = (current_end - start)
-                unsafe {
-                    area.extend_left(current_end.sub_addr(start), page_table)?;
-                }
-            } else {
-                // 需要向右收缩
-                // 新的总size = (current_end - start)
+    /// Converts `[start, start + size)` back to private memory of a
+    /// confidential VM.
+    ///
+    /// See [`convert_private_to_shared`](Self::convert_private_to_shared).
+    pub fn convert_shared_to_private(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.convert_range(start, size, Confidentiality::Private, page_table)
+    }
 
-                area.shrink_left(current_end.sub_addr(start), page_table)?;
-            }
+    fn convert_range(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        to: Confidentiality,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.bump_generation();
+        let range = self.granular_range(start, size)?;
+        if range.is_empty() {
+            return Ok(());
         }
-
-        // 处理右边界的变化
-        if end != current_end {
-            if end > current_end {
-                // 需要向右扩展
-                // 新的总size = (end - current_start)
-                unsafe {
-                    area.extend_right(end.sub_addr(current_start), page_table)?;
+        self.check_covered(range)?;
+        self.check_sealed(range)?;
+        #[cfg(feature = "RAII")]
+        if let Some(area) = self.iter_range(range).find(|area| area.is_shared()) {
+            return Err(MappingError::InvalidParam(untyped(area.va_range())));
+        }
+        self.check_mpu_whole(range)?;
+        self.split_at(range.start)?;
+        self.split_at(range.end)?;
+        let candidates: Vec<_> = self
+            .iter_range(range)
+            .filter(|area| area.confidentiality() != to)
+            .map(|area| area.start())
+            .collect();
+        for (i, area_start) in candidates.iter().enumerate() {
+            let area = self.areas.get_mut(area_start).unwrap();
+            if let Err(err) = area.convert_confidentiality(to, page_table) {
+                let from = match to {
+                    Confidentiality::Private => Confidentiality::Shared,
+                    Confidentiality::Shared => Confidentiality::Private,
+                };
+                for done in &candidates[..i] {
+                    let area = self.areas.get_mut(done).unwrap();
+                    let _ = area.convert_confidentiality(from, page_table);
                 }
-            } else {
-                // 需要向左收缩
-                // 新的总size = (end - current_start)
-                area.shrink_right(end.sub_addr(current_start), page_table)?;
+                return Err(err);
             }
         }
-
         Ok(())
     }
 
-    /// Remove all memory areas and the underlying mappings.
-    pub fn clear(&mut self, page_table: &mut B::PageTable) -> MappingResult {
-        for (_, area) in self.areas.iter_mut() {
-            area.unmap_area(page_table)?;
+    /// Returns the total size of the locked areas in bytes, e.g., to enforce
+    /// `RLIMIT_MEMLOCK`.
+    pub fn locked_size(&self) -> usize {
+        self.areas
+            .values()
+            .filter(|area| area.is_locked())
+            .map(|area| area.size())
+            .sum()
+    }
+
+    /// Returns the soft-dirty pages within the given range, in ascending
+    /// order.
+    ///
+    /// See [`MemoryArea::soft_dirty_pages`].
+    pub fn soft_dirty_pages(&self, range: AddrRange<B::Addr>) -> Vec<B::Addr> {
+        self.iter_range(range)
+            .flat_map(|area| area.soft_dirty_pages(range))
+            .collect()
+    }
+
+    /// Returns the number of pages within the given range allocated on each
+    /// NUMA node at their first touch, keyed by node.
+    ///
+    /// See [`MemoryArea::first_touch_nodes`].
+    pub fn first_touch_nodes(&self, range: AddrRange<B::Addr>) -> BTreeMap<usize, usize> {
+        let mut nodes = BTreeMap::new();
+        for area in self.iter_range(range) {
+            for (node, pages) in area.first_touch_nodes(range) {
+                *nodes.entry(node).or_insert(0) += pages;
+            }
         }
-        self.areas.clear();
-        Ok(())
+        nodes
     }
 
     /// Change the flags of memory mappings within the given address range.
@@ -283,65 +3384,141 @@ impl<B: MappingBackend> MemorySet<B> {
     ///
     /// Memory areas will be skipped according to `update_flags`. Memory areas
     /// that are fully contained in the range or contains the range or
-    /// intersects with the boundary will be handled similarly to `munmap`.
+    /// intersects with the boundary will be handled similarly to `munmap`,
+    /// including the granularity checks.
+    ///
+    /// Returns the changed ranges in ascending order with their old and new
+    /// flags (see [`Protected`]), e.g., to flush the TLB entries of these
+    /// ranges only. There is one range per changed area, before the areas
+    /// are coalesced. The parts of the range not covered by any area are
+    /// skipped, see [`protect_with_mode`](Self::protect_with_mode).
     pub fn protect(
         &mut self,
         start: B::Addr,
         size: usize,
         update_flags: impl Fn(B::Flags) -> Option<B::Flags>,
         page_table: &mut B::PageTable,
-    ) -> MappingResult {
-        let end = start.checked_add(size).ok_or(MappingError::InvalidParam)?;
+    ) -> MappingResult<Vec<Protected<B::Addr, B::Flags>>> {
+        self.protect_with_mode(start, size, update_flags, GapMode::Lenient, page_table)
+    }
+
+    /// Same as [`protect`](Self::protect), but with the handling of the
+    /// holes in the range given by a [`GapMode`].
+    pub fn protect_with_mode(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        update_flags: impl Fn(B::Flags) -> Option<B::Flags>,
+        gaps: GapMode,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult<Vec<Protected<B::Addr, B::Flags>>> {
+        #[cfg(feature = "latency")]
+        return self.timed(LatencyOp::Protect, |set| {
+            set.protect_untimed(start, size, update_flags, gaps, page_table)
+        });
+        #[cfg(not(feature = "latency"))]
+        self.protect_untimed(start, size, update_flags, gaps, page_table)
+    }
+
+    /// Does the work of [`protect_with_mode`](Self::protect_with_mode).
+    fn protect_untimed(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        update_flags: impl Fn(B::Flags) -> Option<B::Flags>,
+        gaps: GapMode,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult<Vec<Protected<B::Addr, B::Flags>>> {
+        self.check_gaps(start, size, gaps)?;
+        self.bump_generation();
+        let AddrRange { start, end } = self.granular_range(start, size)?;
+        self.check_mpu_whole(AddrRange::new(start, end))?;
+        self.check_sealed(AddrRange::new(start, end))?;
+        let candidates: Vec<_> = self.area_starts_in(AddrRange::new(start, end)).collect();
         let mut to_insert = Vec::new();
-        for (&area_start, area) in self.areas.iter_mut() {
+        let mut changed = Vec::new();
+        let now = self.now();
+        for area_start in candidates {
+            let area = self.areas.get_mut(&area_start).unwrap();
             let area_end = area.end();
 
-            if let Some(new_flags) = update_flags(area.flags()) {
-                if area_start >= end {
-                    // [ prot ]
-                    //          [ area ]
-                    break;
-                } else if area_end <= start {
-                    //          [ prot ]
-                    // [ area ]
-                    // Do nothing
-                } else if area_start >= start && area_end <= end {
+            let old_flags = area.flags();
+            if let Some(new_flags) = update_flags(old_flags) {
+                let changed_range = AddrRange::new(area_start.max(start), area_end.min(end));
+                let mut protect_part = |part: &mut MemoryArea<B>| {
+                    part.protect_area(new_flags, page_table).map_err(|err| {
+                        err.with_context("protect", "change the flags of", untyped(part.va_range()))
+                    })?;
+                    part.set_flags(new_flags);
+                    part.times_mut().last_protect = now;
+                    MappingResult::Ok(())
+                };
+                // The parts split off are kept even if the protection fails.
+                let result = if area_start >= start && area_end <= end {
                     // [   prot   ]
                     //   [ area ]
-                    area.protect_area(new_flags, page_table)?;
-                    area.set_flags(new_flags);
+                    protect_part(area)
                 } else if area_start < start && area_end > end {
                     //        [ prot ]
                     // [ left | area | right ]
-                    let right_part = area.split(end).unwrap();
-                    let mut middle_part = area.split(start).unwrap();
-
-                    middle_part.protect_area(new_flags, page_table)?;
-                    middle_part.set_flags(new_flags);
-
-                    to_insert.push((right_part.start(), right_part));
-                    to_insert.push((middle_part.start(), middle_part));
+                    match area.split(end) {
+                        Ok(right_part) => {
+                            to_insert.push((end, right_part.unwrap()));
+                            if let Some(observer) = self.observer.as_deref_mut() {
+                                observer.on_split(AddrRange::new(area_start, area_end), end);
+                            }
+                            area.split(start).and_then(|middle_part| {
+                                let mut middle_part = middle_part.unwrap();
+                                if let Some(observer) = self.observer.as_deref_mut() {
+                                    observer.on_split(AddrRange::new(area_start, end), start);
+                                }
+                                let result = protect_part(&mut middle_part);
+                                to_insert.push((start, middle_part));
+                                result
+                            })
+                        }
+                        Err(err) => Err(err),
+                    }
                 } else if area_end > end {
                     // [    prot ]
                     //   [  area | right ]
-                    let right_part = area.split(end).unwrap();
-                    area.protect_area(new_flags, page_table)?;
-                    area.set_flags(new_flags);
-
-                    to_insert.push((right_part.start(), right_part));
+                    area.split(end).and_then(|right_part| {
+                        to_insert.push((end, right_part.unwrap()));
+                        if let Some(observer) = self.observer.as_deref_mut() {
+                            observer.on_split(AddrRange::new(area_start, area_end), end);
+                        }
+                        protect_part(area)
+                    })
                 } else {
                     //        [ prot    ]
                     // [ left |  area ]
-                    let mut right_part = area.split(start).unwrap();
-                    right_part.protect_area(new_flags, page_table)?;
-                    right_part.set_flags(new_flags);
-
-                    to_insert.push((right_part.start(), right_part));
+                    area.split(start).and_then(|right_part| {
+                        let mut right_part = right_part.unwrap();
+                        if let Some(observer) = self.observer.as_deref_mut() {
+                            observer.on_split(AddrRange::new(area_start, area_end), start);
+                        }
+                        let result = protect_part(&mut right_part);
+                        to_insert.push((start, right_part));
+                        result
+                    })
+                };
+                if let Err(err) = result {
+                    self.areas.extend(to_insert);
+                    return Err(self.owned(err));
                 }
+                if let Some(observer) = self.observer.as_deref_mut() {
+                    observer.on_protect(changed_range, old_flags, new_flags);
+                }
+                changed.push(Protected {
+                    range: changed_range,
+                    old_flags,
+                    new_flags,
+                });
             }
         }
         self.areas.extend(to_insert);
-        Ok(())
+        self.coalesce(AddrRange::new(start, end));
+        Ok(changed)
     }
 }
 
@@ -360,11 +3537,295 @@ impl<B: MappingBackend> MemorySet<B> {
         frame: B::FrameTrackerRef,
     ) -> Option<B::FrameTrackerRef> {
         if let Some(area) = self.find_mut(vaddr) {
-            return area.insert_frame(vaddr.align_down_4k(), frame);
+            let page = vaddr.align_down(area.frame_size());
+            return area.insert_frame(page, frame);
         }
         None
     }
 
+    /// Returns an iterator over the maximal ranges backed by resident frames
+    /// within the given range, in ascending order.
+    ///
+    /// Ranges are clipped to `range` and never span two areas. Areas outside
+    /// the range and absent regions inside areas are skipped.
+    pub fn resident_ranges(
+        &self,
+        range: AddrRange<B::Addr>,
+    ) -> impl Iterator<Item = AddrRange<B::Addr>> + '_ {
+        self.iter_range(range).flat_map(move |area| {
+            // Start from the frame covering `range.start`, possibly huge.
+            let page = range.start.align_down(area.frame_size());
+            let first = match area.frames.covering(page) {
+                Some((start, ..)) => start,
+                None => range.start.max(area.start()),
+            };
+            area.resident_ranges_in(AddrRange::new(first, range.end.min(area.end())))
+                .map(move |r| AddrRange::new(r.start.max(range.start), r.end.min(range.end)))
+        })
+    }
+
+    /// Same as [`unmap`](MemorySet::unmap), but returns the frames that were
+    /// held by the unmapped parts instead of dropping them, grouped by area.
+    ///
+    /// This lets the caller free the frames later (e.g., after a grace
+    /// period), or move them into a cache. If unmapping fails, the frames of
+    /// the area that failed stay with it.
+    pub fn unmap_take_frames(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult<Vec<AreaFrames<B>>> {
+        let mut taken = Vec::new();
+        let result = self.unmap_with(start, size, page_table, |area, range| {
+            taken.push((range, area.take_frames(range.start, range.size())));
+        });
+        if let Err(err) = result {
+            // The frames last taken are from the area that failed to unmap,
+            // whose mappings may still be live, so they go back to it.
+            if let Some((range, mut frames)) = taken.pop()
+                && let Some(area) = self.find_mut(range.start)
+            {
+                area.frames.append(&mut frames);
+            }
+            return Err(err);
+        }
+        Ok(taken
+            .into_iter()
+            .map(|(range, frames)| AreaFrames {
+                range,
+                frames: frames.into(),
+            })
+            .collect())
+    }
+
+    /// Same as [`unmap_take_frames`](Self::unmap_take_frames), but returns
+    /// the frames alone, in ascending order of their old virtual addresses.
+    ///
+    /// This suits callers that recycle the frames into a local cache, or
+    /// insert them into another set, without caring which area held them.
+    pub fn unmap_collect(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult<Vec<B::FrameTrackerRef>> {
+        Ok(self
+            .unmap_take_frames(start, size, page_table)?
+            .into_iter()
+            .flat_map(|taken| taken.frames.into_values())
+            .collect())
+    }
+
+    /// Duplicates the set for `fork()`, sharing the frames copy-on-write.
+    ///
+    /// Every area is cloned into a new set sharing its frames, and mapped in
+    /// `new_page_table`. The resident pages are then write-protected in both
+    /// page tables with [`MemoryArea::write_protect`], so that the first write
+    /// to a page from either side faults and can be resolved with
+    /// [`MemoryArea::break_cow`]. The new set has no label, since it belongs
+    /// to a new owner.
+    ///
+    /// If cloning an area fails, the areas cloned so far are unmapped from
+    /// `new_page_table`, the source areas get their write access back and the
+    /// charge is refunded. If that rollback fails too, its error is returned
+    /// instead.
+    pub fn clone_cow(
+        &mut self,
+        page_table: &mut B::PageTable,
+        new_page_table: &mut B::PageTable,
+    ) -> MappingResult<Self> {
+        self.bump_generation();
+        // Both copies share all the resident pages afterwards, so each one is
+        // charged the whole size of its areas.
+        let charge = self
+            .areas
+            .values()
+            .filter(|area| !area.is_reserved() && area.backend().charges_commit(area.flags()))
+            .map(|area| 2 * area.size() - area.commit_charge())
+            .sum();
+        self.check_commit(self.span(), charge)?;
+        let mut new_set = self.inherit_config();
+        // The source areas write-protected by this call, to undo on failure.
+        let mut protected = Vec::new();
+        let result = self.areas.values_mut().try_for_each(|area| {
+            let mut new_area = area.clone_shared(area.flags());
+            new_area.remap_area(new_page_table)?;
+            let new_area = new_set.areas.get_or_insert(new_area.start(), new_area);
+            new_area.write_protect(new_page_table)?;
+            if !area.is_write_protected() {
+                protected.push(area.start());
+            }
+            area.write_protect(page_table)
+        });
+        if let Err(err) = result {
+            let rollback =
+                self.undo_clone_cow("clone_cow", new_set, &protected, page_table, new_page_table);
+            self.settle_commit();
+            rollback?;
+            return Err(err);
+        }
+        let charge = new_set.commit_charge();
+        self.hand_over_commit(&mut new_set, charge);
+        new_set.rebuild_gaps();
+        Ok(new_set)
+    }
+
+    /// Undoes a failed [`clone_cow`](Self::clone_cow) or
+    /// [`extract`](Self::extract), named `op` in the errors: unmaps the areas
+    /// cloned into `new_set` and restores the write access of the source
+    /// areas starting at `protected`.
+    ///
+    /// Everything is undone even if a step fails, and the first failure is
+    /// returned.
+    fn undo_clone_cow(
+        &mut self,
+        op: &'static str,
+        new_set: Self,
+        protected: &[B::Addr],
+        page_table: &mut B::PageTable,
+        new_page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        let mut result = Ok(());
+        for mut area in new_set.areas.into_values() {
+            let range = untyped(area.va_range());
+            if let Err(err) = area.unmap_area(new_page_table)
+                && result.is_ok()
+            {
+                result = Err(err.with_context(op, "unmap", range));
+            }
+        }
+        for start in protected {
+            let area = self.areas.get_mut(start).unwrap();
+            let range = untyped(area.va_range());
+            if let Err(err) = area.restore_write(page_table)
+                && result.is_ok()
+            {
+                result = Err(err.with_context(op, "restore", range));
+            }
+        }
+        result.map_err(|err| self.owned(err))
+    }
+
+    /// Duplicates the set with private copies of its resident frames, mapped
+    /// in `new_page_table`, like `fork()` without copy-on-write, e.g., to
+    /// take a snapshot of an address space.
+    ///
+    /// Unlike [`clone_cow`](Self::clone_cow), the set is left untouched: the
+    /// frames of every area are copied with [`MappingBackend::copy_frame`]
+    /// right away, except for the areas mapping a
+    /// [shared](MemoryArea::is_shared) object, which keep sharing it. The
+    /// areas keep their attributes, and the new set inherits the
+    /// configuration like with `clone_cow` and has no label.
+    pub fn clone_into(&self, new_page_table: &mut B::PageTable) -> MappingResult<Self> {
+        let charge = self.areas.values().map(MemoryArea::commit_charge).sum();
+        self.take_commit(self.span(), charge)?;
+        let mut new_set = self.inherit_config();
+        if new_set.commit_check.is_some() {
+            new_set.committed = charge;
+        }
+        for area in self.areas.values() {
+            match area.clone_copied(area.flags(), new_page_table) {
+                Ok(new_area) => {
+                    new_set.areas.insert(new_area.start(), new_area);
+                }
+                Err(err) => {
+                    new_set.release_commit();
+                    return Err(err);
+                }
+            }
+        }
+        new_set.rebuild_gaps();
+        Ok(new_set)
+    }
+
+    /// Duplicates the part of the set within `range` into a new set, e.g., to
+    /// fork a sandbox with only the memory region of a plugin.
+    ///
+    /// The range is checked like by [`unmap`](Self::unmap). The areas
+    /// intersecting it are cut to it and copied into a new set at the same
+    /// addresses, and mapped in `new_page_table`. Their contents are copied
+    /// according to `mode`. For [`ExtractMode::Cow`], the source areas are
+    /// write-protected as a whole. The new set inherits the configuration
+    /// like with [`clone_cow`](Self::clone_cow), and is charged the commit
+    /// of its areas.
+    ///
+    /// Also returns the `(offset, size)` of each extracted area, relative to
+    /// the start of the range, so the caller can lay them out elsewhere.
+    ///
+    /// If extracting an area fails, everything is rolled back like with
+    /// `clone_cow`.
+    pub fn extract(
+        &mut self,
+        range: AddrRange<B::Addr>,
+        mode: ExtractMode,
+        page_table: &mut B::PageTable,
+        new_page_table: &mut B::PageTable,
+    ) -> MappingResult<(Self, Vec<(usize, usize)>)> {
+        self.bump_generation();
+        let range = self.granular_range(range.start, range.size())?;
+        // The parts within the range, with the starts of their areas.
+        let mut parts = Vec::new();
+        for area in self.iter_range(range) {
+            let mut part = area.clone();
+            if let Some(right_part) = part.split(range.start)? {
+                part = right_part;
+            }
+            part.split(range.end)?;
+            parts.push((area.start(), part));
+        }
+        let charge = match mode {
+            ExtractMode::Copy => parts.iter().map(|(_, part)| part.commit_charge()).sum(),
+            // Both sides share the resident pages of the parts afterwards, and
+            // the source areas are write-protected as a whole.
+            ExtractMode::Cow => parts
+                .iter()
+                .filter(|(_, part)| {
+                    !part.is_reserved() && part.backend().charges_commit(part.flags())
+                })
+                .map(|(start, part)| {
+                    let area = &self.areas[start];
+                    part.size() + area.size() - area.commit_charge()
+                })
+                .sum(),
+        };
+        self.check_commit(range, charge)?;
+        let mut new_set = self.inherit_config();
+        let mut offsets = Vec::new();
+        // The source areas write-protected by this call, to undo on failure.
+        let mut protected = Vec::new();
+        let result = parts.into_iter().try_for_each(|(start, mut part)| {
+            if mode == ExtractMode::Copy {
+                part = part.clone_copied(part.flags(), new_page_table)?;
+            } else {
+                part.remap_area(new_page_table)?;
+            }
+            offsets.push((part.start().sub_addr(range.start), part.size()));
+            let part = new_set.areas.get_or_insert(part.start(), part);
+            if mode == ExtractMode::Cow {
+                part.write_protect(new_page_table)?;
+                let area = self.areas.get_mut(&start).unwrap();
+                if !area.is_write_protected() {
+                    protected.push(start);
+                }
+                area.write_protect(page_table)?;
+            }
+            Ok(())
+        });
+        if let Err(err) = result {
+            let rollback =
+                self.undo_clone_cow("extract", new_set, &protected, page_table, new_page_table);
+            self.settle_commit();
+            rollback?;
+            return Err(err);
+        }
+        let charge = new_set.commit_charge();
+        self.hand_over_commit(&mut new_set, charge);
+        self.settle_commit();
+        new_set.rebuild_gaps();
+        Ok((new_set, offsets))
+    }
+
     /// Remap a vaddr to a new frame.pub fn remap_frame(&mut self, vaddr:
     /// B::Addr, new_frame: B::FrameTrackerImpl) {
     pub fn remap_frame(&mut self, vaddr: B::Addr, new_frame: B::FrameTrackerRef) {
@@ -373,12 +3834,75 @@ impl<B: MappingBackend> MemorySet<B> {
     }
 }
 
+/// Returns the offset of `boundary` from the start of an area, or
+/// [`MappingError::BadState`] if the boundary is not above the start, which
+/// means the area does not look like the set expects it to.
+fn boundary_offset<A: MemoryAddr>(area_start: A, boundary: A) -> MappingResult<usize> {
+    match boundary.checked_sub_addr(area_start) {
+        Some(offset) if offset > 0 => Ok(offset),
+        _ => Err(MappingError::BadState(err_range(boundary, 0), None)),
+    }
+}
+
+impl<B: MappingBackend> Default for MemorySet<B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<B: MappingBackend> fmt::Debug for MemorySet<B>
+where
+    B::Addr: fmt::Debug,
+    B::Flags: fmt::Debug,
+{
+    /// Terse by default, and with the state of each area and the sizes of
+    /// the gaps around it in the alternate form (`{:#?}`).
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(label) = &self.label {
+            write!(f, "{label}: ")?;
+        }
+        if !f.alternate() {
+            return f
+                .debug_list()
+                .entries(self.areas.values().filter(|area| !area.is_hole()))
+                .finish();
+        }
+        let areas: Vec<_> = self.areas.values().collect();
+        let entries = areas.iter().enumerate().filter(|(_, area)| !area.is_hole());
+        f.debug_list()
+            .entries(entries.map(|(i, area)| {
+                DebugWithGaps {
+                    area,
+                    gap_before: i
+                        .checked_sub(1)
+                        .map(|prev| area.start().sub_addr(areas[prev].end())),
+                    gap_after: areas
+                        .get(i + 1)
+                        .map(|next| next.start().sub_addr(area.end())),
+                }
+            }))
+            .finish()
+    }
+}
+
+/// An area in the alternate [`Debug`](fmt::Debug) output of a set, with the
+/// sizes of the gaps to its neighbors (holes included).
+struct DebugWithGaps<'a, B: MappingBackend> {
+    area: &'a MemoryArea<B>,
+    gap_before: Option<usize>,
+    gap_after: Option<usize>,
+}
+
+impl<B: MappingBackend> fmt::Debug for DebugWithGaps<'_, B>
 where
     B::Addr: fmt::Debug,
     B::Flags: fmt::Debug,
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_list().entries(self.areas.values()).finish()
+        self.area
+            .debug_struct(f)
+            .field("gap_before", &self.gap_before)
+            .field("gap_after", &self.gap_after)
+            .finish()
     }
 }
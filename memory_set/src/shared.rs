@@ -0,0 +1,183 @@
+//! Memory objects whose frames are shared by areas in several
+//! [`MemorySet`](crate::MemorySet)s, e.g., for `shmget` or
+//! `mmap(MAP_SHARED)`.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use memory_addr::{FrameTracker, MemoryAddr};
+
+use crate::{MappingBackend, MappingError, MappingResult, MemoryArea, err_range};
+
+/// The frames of a shared memory object, mapped by the areas created with
+/// [`MemoryArea::new_shared`].
+///
+/// All its frames are allocated and zeroed when it is created, and live as
+/// long as the object or an area mapping them does. Since the frames never
+/// change, areas in different address spaces mapping the same object always
+/// see the same memory, and the object needs no synchronization.
+pub struct SharedFrames<B: MappingBackend> {
+    frames: Vec<B::FrameTrackerRef>,
+}
+
+impl<B: MappingBackend> SharedFrames<B> {
+    /// Creates a shared object of `size` bytes, rounded up to whole frames.
+    pub fn new(size: usize) -> Arc<Self> {
+        let frame_size = <B::FrameTrackerImpl as FrameTracker>::PAGE_SIZE;
+        let frames = (0..size.div_ceil(frame_size))
+            .map(|_| {
+                let mut frame = B::FrameTrackerImpl::alloc_frame();
+                frame.as_mut_slice().fill(0);
+                frame.into()
+            })
+            .collect();
+        Arc::new(Self { frames })
+    }
+
+    /// Creates a shared object of existing frames of a page each, e.g., the
+    /// frames backing a range of another set.
+    pub(crate) fn from_frames(frames: Vec<B::FrameTrackerRef>) -> Arc<Self> {
+        Arc::new(Self { frames })
+    }
+
+    /// Returns the size of the object in bytes.
+    pub fn size(&self) -> usize {
+        self.frames.len() * <B::FrameTrackerImpl as FrameTracker>::PAGE_SIZE
+    }
+
+    /// Returns the frame at `offset` bytes into the object, if any.
+    pub fn frame(&self, offset: usize) -> Option<&B::FrameTrackerRef> {
+        self.frames
+            .get(offset / <B::FrameTrackerImpl as FrameTracker>::PAGE_SIZE)
+    }
+}
+
+/// The shared object mapped by an area, and the offset in it of the start of
+/// the area.
+pub(crate) struct SharedMapping<B: MappingBackend> {
+    pub(crate) object: Arc<SharedFrames<B>>,
+    pub(crate) offset: usize,
+}
+
+impl<B: MappingBackend> Clone for SharedMapping<B> {
+    fn clone(&self) -> Self {
+        Self {
+            object: self.object.clone(),
+            offset: self.offset,
+        }
+    }
+}
+
+impl<B: MappingBackend> SharedMapping<B> {
+    /// Returns whether `next`, mapped right after an area of `size` bytes
+    /// mapping `self`, continues the same object.
+    pub(crate) fn is_continued_by(&self, size: usize, next: &Self) -> bool {
+        Arc::ptr_eq(&self.object, &next.object) && self.offset + size == next.offset
+    }
+}
+
+impl<B: MappingBackend> MemoryArea<B> {
+    /// Creates a memory area mapping `object` from `offset` bytes into it.
+    ///
+    /// The area never allocates frames of its own: mapping it and faults in
+    /// it install the frames of the object with
+    /// [`MappingBackend::map_frame`], and writes after
+    /// [`write_protect`](Self::write_protect) (e.g., in a set cloned
+    /// copy-on-write) make the shared frame writable again instead of
+    /// copying it. The area is never swapped out.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `offset` is not aligned to the frame size, or if the area
+    /// extends past the end of the object.
+    pub fn new_shared(
+        start: B::Addr,
+        size: usize,
+        object: Arc<SharedFrames<B>>,
+        offset: usize,
+        flags: B::Flags,
+        backend: B,
+    ) -> Self {
+        let mut area = Self::new(start, size, None, flags, backend);
+        assert!(
+            offset.is_multiple_of(area.frame_size()),
+            "misaligned shared object offset"
+        );
+        assert!(
+            offset
+                .checked_add(size)
+                .is_some_and(|end| end <= object.size()),
+            "area out of the shared object"
+        );
+        area.shared = Some(SharedMapping { object, offset });
+        area
+    }
+
+    /// Returns the shared object mapped by the area and the offset in it of
+    /// the start of the area, if the area was created with
+    /// [`new_shared`](Self::new_shared).
+    pub fn shared_object(&self) -> Option<(&Arc<SharedFrames<B>>, usize)> {
+        self.shared
+            .as_ref()
+            .map(|shared| (&shared.object, shared.offset))
+    }
+
+    /// Returns whether the area maps a shared object, or a file with
+    /// [shared](Self::new_file) semantics.
+    pub fn is_shared(&self) -> bool {
+        #[cfg(feature = "mmap")]
+        if self.file.as_ref().is_some_and(|file| file.shared) {
+            return true;
+        }
+        self.shared.is_some()
+    }
+
+    /// Returns whether the pages of the area come from a shared object or a
+    /// file instead of the backend.
+    pub(crate) fn maps_object(&self) -> bool {
+        #[cfg(feature = "mmap")]
+        if self.file.is_some() {
+            return true;
+        }
+        self.shared.is_some()
+    }
+
+    /// Maps the frame backing `page` from the shared object or file mapped
+    /// by the area with `flags`.
+    pub(crate) fn map_object_page(
+        &mut self,
+        page: B::Addr,
+        flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        let size = self.frame_size();
+        let offset = page.sub_addr(self.start());
+        let frame = match &self.shared {
+            Some(shared) => shared.object.frame(shared.offset + offset).cloned(),
+            #[cfg(feature = "mmap")]
+            None => self.file.as_ref().and_then(|file| file.frame(offset)),
+            #[cfg(not(feature = "mmap"))]
+            None => None,
+        }
+        .ok_or(MappingError::BadState(err_range(page, size), None))?;
+        self.backend_map_frame(page, &frame, flags, page_table)?;
+        self.frames.insert(page, frame);
+        Ok(())
+    }
+
+    /// Maps the frames of the shared object backing the whole area with
+    /// `flags`.
+    pub(crate) fn map_shared(
+        &mut self,
+        flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        let frame_size = self.frame_size();
+        let mut page = self.start();
+        while page < self.end() {
+            self.map_object_page(page, flags, page_table)?;
+            page = page.add(frame_size);
+        }
+        Ok(())
+    }
+}
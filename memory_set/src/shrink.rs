@@ -0,0 +1,194 @@
+//! Reclaiming memory under pressure through [`Shrinker`]s registered in a
+//! [`ShrinkerRegistry`], like the shrinkers of Linux.
+//!
+//! The memory held by this crate is the frames of the sets, reclaimed by
+//! swapping them out with [`SetShrinker`]. Its caches, e.g., [`TlbCache`],
+//! are fixed-size and hold nothing to reclaim.
+//!
+//! [`TlbCache`]: crate::TlbCache
+
+#[cfg(feature = "RAII")]
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+
+#[cfg(feature = "RAII")]
+use memory_addr::AddrRange;
+
+#[cfg(feature = "RAII")]
+use crate::{MappingBackend, MemorySet, ScanCursor};
+
+/// Memory that can be reclaimed under pressure, registered in a
+/// [`ShrinkerRegistry`].
+///
+/// It is shared by the registry and its owner, so it takes `&self` and has
+/// to synchronize itself.
+pub trait Shrinker {
+    /// Returns the number of bytes that [`shrink`](Self::shrink) could
+    /// reclaim, which may be an estimate.
+    fn count(&self) -> usize;
+
+    /// Reclaims about `target` bytes, and returns the number of bytes
+    /// reclaimed, which may fall short or overshoot.
+    fn shrink(&self, target: usize) -> usize;
+}
+
+/// The handle of a [`Shrinker`] registered in a [`ShrinkerRegistry`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ShrinkerId(u64);
+
+/// The [`Shrinker`]s the out-of-memory or pressure handler of the kernel
+/// invokes to reclaim memory uniformly.
+///
+/// The kernel keeps it, e.g., in a global behind its own lock.
+#[derive(Default)]
+pub struct ShrinkerRegistry {
+    shrinkers: Vec<(ShrinkerId, Arc<dyn Shrinker + Send + Sync>)>,
+    next_id: u64,
+}
+
+impl ShrinkerRegistry {
+    /// Creates an empty registry.
+    pub const fn new() -> Self {
+        Self {
+            shrinkers: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Registers `shrinker`, and returns its handle to unregister it.
+    pub fn register(&mut self, shrinker: Arc<dyn Shrinker + Send + Sync>) -> ShrinkerId {
+        let id = ShrinkerId(self.next_id);
+        self.next_id += 1;
+        self.shrinkers.push((id, shrinker));
+        id
+    }
+
+    /// Unregisters the shrinker of `id`, and returns it, if registered.
+    pub fn unregister(&mut self, id: ShrinkerId) -> Option<Arc<dyn Shrinker + Send + Sync>> {
+        let index = self.shrinkers.iter().position(|(i, _)| *i == id)?;
+        Some(self.shrinkers.remove(index).1)
+    }
+
+    /// Returns the number of shrinkers registered.
+    pub fn len(&self) -> usize {
+        self.shrinkers.len()
+    }
+
+    /// Returns whether no shrinker is registered.
+    pub fn is_empty(&self) -> bool {
+        self.shrinkers.is_empty()
+    }
+
+    /// Returns the number of bytes the shrinkers could reclaim in total.
+    pub fn count(&self) -> usize {
+        self.shrinkers.iter().map(|(_, s)| s.count()).sum()
+    }
+
+    /// Reclaims at least `target` bytes if possible, and returns the number
+    /// of bytes reclaimed.
+    ///
+    /// The shrinkers holding the most reclaimable memory are asked first,
+    /// each for what is still missing, until the target is reached.
+    pub fn shrink(&self, target: usize) -> usize {
+        let mut shrinkers: Vec<_> = self
+            .shrinkers
+            .iter()
+            .map(|(_, s)| (s.count(), s))
+            .filter(|&(count, _)| count > 0)
+            .collect();
+        shrinkers.sort_by_key(|&(count, _)| Reverse(count));
+        let mut reclaimed = 0;
+        for (_, shrinker) in shrinkers {
+            if reclaimed >= target {
+                break;
+            }
+            reclaimed += shrinker.shrink(target - reclaimed);
+        }
+        reclaimed
+    }
+}
+
+#[cfg(feature = "RAII")]
+impl<B: MappingBackend> MemorySet<B> {
+    /// Returns the number of bytes [`shrink`](Self::shrink) could reclaim,
+    /// i.e., the size of the frames that could be swapped out, or zero if
+    /// no swap is set.
+    pub fn reclaimable(&self) -> usize {
+        if self.swap.is_none() {
+            return 0;
+        }
+        self.areas
+            .values()
+            .filter(|area| area.is_swappable())
+            .map(|area| {
+                let frames = area.frames.values();
+                frames.filter(|frame| !area.is_zero_frame(frame)).count() * area.frame_size()
+            })
+            .sum()
+    }
+
+    /// Swaps out about `target` bytes of frames from the lowest addresses
+    /// up, and returns the number of bytes reclaimed.
+    ///
+    /// Same as [`swap_out_step`](Self::swap_out_step) over the whole set,
+    /// but errors are not reported, since the caller is reclaiming and can
+    /// only try elsewhere: the pages swapped out before an error count.
+    pub fn shrink(&mut self, target: usize, page_table: &mut B::PageTable) -> usize {
+        let (Some((&start, _)), Some((_, last))) =
+            (self.areas.first_key_value(), self.areas.last_key_value())
+        else {
+            return 0;
+        };
+        let mut cursor = ScanCursor::new(AddrRange::new(start, last.end()));
+        let max_pages = target.div_ceil(last.frame_size());
+        let before = self.reclaimable();
+        let _ = self.swap_out_step(&mut cursor, max_pages, page_table);
+        before - self.reclaimable()
+    }
+}
+
+/// The type of the function lending a set and its page table to a
+/// [`SetShrinker`].
+#[cfg(feature = "RAII")]
+type WithSet<B> =
+    dyn Fn(&mut dyn FnMut(&mut MemorySet<B>, &mut <B as MappingBackend>::PageTable)) + Send + Sync;
+
+/// A [`Shrinker`] reclaiming the frames of a [`MemorySet`] with
+/// [`MemorySet::shrink`].
+///
+/// The set and its page table usually live behind a lock of the kernel,
+/// so it is built from a function that locks them and lends them to its
+/// argument, and does nothing if they are gone.
+#[cfg(feature = "RAII")]
+pub struct SetShrinker<B: MappingBackend> {
+    with_set: Box<WithSet<B>>,
+}
+
+#[cfg(feature = "RAII")]
+impl<B: MappingBackend> SetShrinker<B> {
+    /// Creates a shrinker reclaiming the set lent by `with_set`.
+    pub fn new(
+        with_set: impl Fn(&mut dyn FnMut(&mut MemorySet<B>, &mut B::PageTable)) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            with_set: Box::new(with_set),
+        }
+    }
+}
+
+#[cfg(feature = "RAII")]
+impl<B: MappingBackend> Shrinker for SetShrinker<B> {
+    fn count(&self) -> usize {
+        let mut count = 0;
+        (self.with_set)(&mut |set, _| count = set.reclaimable());
+        count
+    }
+
+    fn shrink(&self, target: usize) -> usize {
+        let mut reclaimed = 0;
+        (self.with_set)(&mut |set, page_table| reclaimed = set.shrink(target, page_table));
+        reclaimed
+    }
+}
@@ -0,0 +1,358 @@
+//! A compact binary format for layout snapshots.
+//!
+//! Unlike [`JsonLayout`](crate::JsonLayout), a snapshot can be written and
+//! read back without `std` or a serialization framework, e.g., to checkpoint
+//! an address space inside a kernel. All integers are little-endian:
+//!
+//! ```text
+//! snapshot := magic "AXMS" | version: u16 | record* | end record
+//! record   := tag: u8 | len: u32 | payload: [u8; len]
+//! ```
+//!
+//! The records are:
+//!
+//! - [`TAG_END`] (empty): the end of the snapshot.
+//! - [`TAG_AREA`]: `start: u64 | end: u64 | flags: u64 | kind_len: u16 |
+//!   kind | label_len: u16 | label`, where an absent label has the length
+//!   `0xffff`.
+//! - [`TAG_PAGE`]: `vaddr: u64 | data`, the contents of memory at `vaddr`.
+//! - [`TAG_OWNER`]: `label`, the label of the set, see
+//!   [`MemorySet::label`].
+//!
+//! Readers skip the records with unknown tags, and the trailing bytes of
+//! known records, so that snapshots written by later versions stay readable
+//! as long as the version is not bumped.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::convert::Infallible;
+
+use crate::{MappingBackend, MemorySet};
+
+/// The magic number at the start of a snapshot.
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"AXMS";
+/// The version of the format written by [`SnapshotWriter`], and the latest
+/// one [`SnapshotReader`] accepts. It is only bumped for changes that older
+/// readers cannot skip over.
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+/// The tag of the end record.
+pub const TAG_END: u8 = 0;
+/// The tag of an area record.
+pub const TAG_AREA: u8 = 1;
+/// The tag of a page payload record.
+pub const TAG_PAGE: u8 = 2;
+/// The tag of the owner record.
+pub const TAG_OWNER: u8 = 3;
+
+/// The length of an absent label.
+const NO_LABEL: u16 = u16::MAX;
+
+/// A sink of snapshot bytes, like `std::io::Write`.
+pub trait SnapshotWrite {
+    /// The error of the sink.
+    type Error;
+
+    /// Writes all of `buf`.
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// A source of snapshot bytes, like `std::io::Read`.
+pub trait SnapshotRead {
+    /// The error of the source.
+    type Error;
+
+    /// Reads exactly `buf.len()` bytes into `buf`, failing if the source
+    /// ends first.
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+impl SnapshotWrite for Vec<u8> {
+    type Error = Infallible;
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Infallible> {
+        self.extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+impl SnapshotRead for &[u8] {
+    /// The source ended early.
+    type Error = ();
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), ()> {
+        if self.len() < buf.len() {
+            return Err(());
+        }
+        let (head, tail) = self.split_at(buf.len());
+        buf.copy_from_slice(head);
+        *self = tail;
+        Ok(())
+    }
+}
+
+/// Error type for reading and writing snapshots.
+#[derive(Debug, Eq, PartialEq)]
+pub enum SnapshotError<E> {
+    /// The underlying sink or source failed.
+    Io(E),
+    /// The snapshot does not start with [`SNAPSHOT_MAGIC`].
+    BadMagic,
+    /// The snapshot was written with a version later than
+    /// [`SNAPSHOT_VERSION`].
+    UnsupportedVersion(u16),
+    /// A record is malformed, e.g., too short for its tag.
+    Corrupt,
+}
+
+/// An area in a snapshot.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AreaRecord {
+    /// The start address.
+    pub start: u64,
+    /// The end address (exclusive).
+    pub end: u64,
+    /// The flags, as encoded by the writer.
+    pub flags: u64,
+    /// The backend kind, see [`MappingBackend::kind`].
+    pub kind: String,
+    /// The label of the area, if any.
+    pub label: Option<String>,
+}
+
+/// A record read from a snapshot.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SnapshotRecord {
+    /// An area.
+    Area(AreaRecord),
+    /// The contents of memory at `vaddr`.
+    Page {
+        /// The start address of the contents.
+        vaddr: u64,
+        /// The contents.
+        data: Vec<u8>,
+    },
+    /// The label of the set the snapshot was taken from.
+    Owner(String),
+}
+
+/// Writes a snapshot record by record, see the [module documentation](self).
+///
+/// The header is written by [`SnapshotWriter::new`], and the end record by
+/// [`SnapshotWriter::finish`].
+pub struct SnapshotWriter<W: SnapshotWrite> {
+    sink: W,
+}
+
+impl<W: SnapshotWrite> SnapshotWriter<W> {
+    /// Creates a writer, writing the header to `sink`.
+    pub fn new(mut sink: W) -> Result<Self, SnapshotError<W::Error>> {
+        sink.write_all(&SNAPSHOT_MAGIC).map_err(SnapshotError::Io)?;
+        sink.write_all(&SNAPSHOT_VERSION.to_le_bytes())
+            .map_err(SnapshotError::Io)?;
+        Ok(Self { sink })
+    }
+
+    fn write_record(&mut self, tag: u8, parts: &[&[u8]]) -> Result<(), SnapshotError<W::Error>> {
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        let len = u32::try_from(len).map_err(|_| SnapshotError::Corrupt)?;
+        self.sink.write_all(&[tag]).map_err(SnapshotError::Io)?;
+        self.sink
+            .write_all(&len.to_le_bytes())
+            .map_err(SnapshotError::Io)?;
+        for part in parts {
+            self.sink.write_all(part).map_err(SnapshotError::Io)?;
+        }
+        Ok(())
+    }
+
+    /// Writes an area record.
+    ///
+    /// Returns [`SnapshotError::Corrupt`] if the kind is longer than `0xffff`
+    /// bytes, or the label than `0xfffe` bytes.
+    pub fn write_area(&mut self, area: &AreaRecord) -> Result<(), SnapshotError<W::Error>> {
+        let kind_len = u16::try_from(area.kind.len()).map_err(|_| SnapshotError::Corrupt)?;
+        let label = area.label.as_deref().unwrap_or("");
+        let label_len = match area.label {
+            Some(_) => u16::try_from(label.len())
+                .ok()
+                .filter(|&len| len != NO_LABEL)
+                .ok_or(SnapshotError::Corrupt)?,
+            None => NO_LABEL,
+        };
+        self.write_record(
+            TAG_AREA,
+            &[
+                &area.start.to_le_bytes(),
+                &area.end.to_le_bytes(),
+                &area.flags.to_le_bytes(),
+                &kind_len.to_le_bytes(),
+                area.kind.as_bytes(),
+                &label_len.to_le_bytes(),
+                label.as_bytes(),
+            ],
+        )
+    }
+
+    /// Writes the label of the set the snapshot is taken from.
+    pub fn write_owner(&mut self, label: &str) -> Result<(), SnapshotError<W::Error>> {
+        self.write_record(TAG_OWNER, &[label.as_bytes()])
+    }
+
+    /// Writes the contents of memory at `vaddr`.
+    ///
+    /// Large contents can be split into several records, e.g., one per page.
+    pub fn write_page(&mut self, vaddr: u64, data: &[u8]) -> Result<(), SnapshotError<W::Error>> {
+        self.write_record(TAG_PAGE, &[&vaddr.to_le_bytes(), data])
+    }
+
+    /// Writes the end record and returns the sink.
+    pub fn finish(mut self) -> Result<W, SnapshotError<W::Error>> {
+        self.write_record(TAG_END, &[])?;
+        Ok(self.sink)
+    }
+}
+
+/// Reads a snapshot record by record, see the [module documentation](self).
+pub struct SnapshotReader<R: SnapshotRead> {
+    source: R,
+    version: u16,
+    done: bool,
+}
+
+impl<R: SnapshotRead> SnapshotReader<R> {
+    /// Creates a reader, reading and checking the header from `source`.
+    pub fn new(mut source: R) -> Result<Self, SnapshotError<R::Error>> {
+        let mut header = [0; 6];
+        source.read_exact(&mut header).map_err(SnapshotError::Io)?;
+        if header[..4] != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version > SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        Ok(Self {
+            source,
+            version,
+            done: false,
+        })
+    }
+
+    /// Returns the version the snapshot was written with.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Reads the next known record, or returns `None` after the end record.
+    pub fn next_record(&mut self) -> Result<Option<SnapshotRecord>, SnapshotError<R::Error>> {
+        while !self.done {
+            let mut header = [0; 5];
+            self.source
+                .read_exact(&mut header)
+                .map_err(SnapshotError::Io)?;
+            let len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+            let mut payload = alloc::vec![0; len as usize];
+            self.source
+                .read_exact(&mut payload)
+                .map_err(SnapshotError::Io)?;
+            match header[0] {
+                TAG_END => self.done = true,
+                TAG_AREA => {
+                    return parse_area(&payload).map(|area| Some(SnapshotRecord::Area(area)));
+                }
+                TAG_PAGE => {
+                    if payload.len() < 8 {
+                        return Err(SnapshotError::Corrupt);
+                    }
+                    let data = payload.split_off(8);
+                    let vaddr = u64::from_le_bytes(payload.try_into().unwrap());
+                    return Ok(Some(SnapshotRecord::Page { vaddr, data }));
+                }
+                TAG_OWNER => {
+                    let label = String::from_utf8(payload).map_err(|_| SnapshotError::Corrupt)?;
+                    return Ok(Some(SnapshotRecord::Owner(label)));
+                }
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the source, e.g., to read what follows the snapshot.
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+}
+
+/// Splits the first `n` bytes off `buf`.
+fn take<'a, E>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8], SnapshotError<E>> {
+    if buf.len() < n {
+        return Err(SnapshotError::Corrupt);
+    }
+    let (head, tail) = buf.split_at(n);
+    *buf = tail;
+    Ok(head)
+}
+
+fn take_u64<E>(buf: &mut &[u8]) -> Result<u64, SnapshotError<E>> {
+    Ok(u64::from_le_bytes(take(buf, 8)?.try_into().unwrap()))
+}
+
+fn take_u16<E>(buf: &mut &[u8]) -> Result<u16, SnapshotError<E>> {
+    Ok(u16::from_le_bytes(take(buf, 2)?.try_into().unwrap()))
+}
+
+fn take_str<E>(buf: &mut &[u8], len: u16) -> Result<String, SnapshotError<E>> {
+    let bytes = take(buf, len as usize)?;
+    String::from_utf8(bytes.into()).map_err(|_| SnapshotError::Corrupt)
+}
+
+fn parse_area<E>(mut buf: &[u8]) -> Result<AreaRecord, SnapshotError<E>> {
+    let buf = &mut buf;
+    let start = take_u64(buf)?;
+    let end = take_u64(buf)?;
+    let flags = take_u64(buf)?;
+    let kind_len = take_u16(buf)?;
+    let kind = take_str(buf, kind_len)?;
+    let label = match take_u16(buf)? {
+        NO_LABEL => None,
+        len => Some(take_str(buf, len)?),
+    };
+    Ok(AreaRecord {
+        start,
+        end,
+        flags,
+        kind,
+        label,
+    })
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Writes the areas of the set as a snapshot to `sink`, encoding their
+    /// flags with `flags_bits`.
+    ///
+    /// The label of the set (if any) is written first. Holes are left out.
+    /// The page contents are up to the caller, who can write them with
+    /// [`SnapshotWriter::write_page`] before finishing the returned writer.
+    pub fn write_snapshot<W: SnapshotWrite>(
+        &self,
+        sink: W,
+        mut flags_bits: impl FnMut(B::Flags) -> u64,
+    ) -> Result<SnapshotWriter<W>, SnapshotError<W::Error>> {
+        let mut writer = SnapshotWriter::new(sink)?;
+        if let Some(label) = self.label() {
+            writer.write_owner(&label.to_string())?;
+        }
+        for area in self.iter().filter(|area| !area.is_hole()) {
+            writer.write_area(&AreaRecord {
+                start: area.start().into() as u64,
+                end: area.end().into() as u64,
+                flags: flags_bits(area.flags()),
+                kind: area.backend().kind().into(),
+                label: area.label().map(Into::into),
+            })?;
+        }
+        Ok(writer)
+    }
+}
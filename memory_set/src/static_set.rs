@@ -0,0 +1,329 @@
+//! A [`MemorySet`](crate::MemorySet) of a fixed capacity, for when there is
+//! no heap yet.
+
+use core::fmt;
+
+use memory_addr::{AddrRange, MemoryAddr};
+
+use crate::{MappingBackend, MappingError, MappingResult, err_range, untyped};
+
+/// An area of a [`StaticMemorySet`]: a range mapped with the same flags by a
+/// backend, without any per-page state.
+#[derive(Clone)]
+pub struct StaticArea<B: MappingBackend> {
+    va_range: AddrRange<B::Addr>,
+    flags: B::Flags,
+    backend: B,
+}
+
+impl<B: MappingBackend> StaticArea<B> {
+    /// Returns the virtual address range.
+    pub const fn va_range(&self) -> AddrRange<B::Addr> {
+        self.va_range
+    }
+
+    /// Returns the start address of the area.
+    pub const fn start(&self) -> B::Addr {
+        self.va_range.start
+    }
+
+    /// Returns the end address of the area.
+    pub const fn end(&self) -> B::Addr {
+        self.va_range.end
+    }
+
+    /// Returns the size of the area.
+    pub fn size(&self) -> usize {
+        self.va_range.size()
+    }
+
+    /// Returns the memory flags, e.g., the permission bits.
+    pub const fn flags(&self) -> B::Flags {
+        self.flags
+    }
+
+    /// Returns the mapping backend of the area.
+    pub const fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Splits the area at `pos`, which must be strictly inside it, and
+    /// returns the right part.
+    fn split(&mut self, pos: B::Addr) -> Self {
+        let right = Self {
+            va_range: AddrRange::new(pos, self.end()),
+            flags: self.flags,
+            backend: self.backend.clone(),
+        };
+        self.va_range.end = pos;
+        right
+    }
+}
+
+impl<B: MappingBackend> fmt::Debug for StaticArea<B>
+where
+    B::Addr: fmt::Debug,
+    B::Flags: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StaticArea")
+            .field("va_range", &self.va_range)
+            .field("flags", &self.flags)
+            .finish()
+    }
+}
+
+/// A set of at most `N` memory areas kept in a sorted array, which never
+/// allocates, e.g., to map the kernel at boot before the heap is up, or on
+/// tiny targets without one.
+///
+/// It only maps, unmaps and protects whole ranges: there are no frames,
+/// faults or any other per-page state. With RAII, the frames returned by
+/// [`MappingBackend::map`] are dropped, so it only suits backends that map
+/// memory they do not allocate, e.g., linear mappings. The errors of the
+/// backend are not kept in [`MappingError::BadState`], since boxing them
+/// would allocate.
+pub struct StaticMemorySet<B: MappingBackend, const N: usize> {
+    /// The areas in ascending order in `areas[..len]`, and `None` after.
+    areas: [Option<StaticArea<B>>; N],
+    len: usize,
+}
+
+impl<B: MappingBackend, const N: usize> StaticMemorySet<B, N> {
+    /// Creates a new empty set.
+    pub fn new() -> Self {
+        Self {
+            areas: core::array::from_fn(|_| None),
+            len: 0,
+        }
+    }
+
+    /// Returns the number of areas.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether there is no area.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the maximum number of areas, `N`.
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Returns an iterator over the areas, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = &StaticArea<B>> {
+        self.areas[..self.len].iter().flatten()
+    }
+
+    /// Returns the area at `index`, which must be less than the length.
+    fn area(&self, index: usize) -> &StaticArea<B> {
+        self.areas[index].as_ref().unwrap()
+    }
+
+    /// Returns the index of the first area ending after `vaddr`.
+    fn index_after(&self, vaddr: B::Addr) -> usize {
+        self.areas[..self.len].partition_point(|area| area.as_ref().unwrap().end() <= vaddr)
+    }
+
+    /// Finds the area containing `vaddr`.
+    pub fn find(&self, vaddr: B::Addr) -> Option<&StaticArea<B>> {
+        let index = self.index_after(vaddr);
+        (index < self.len)
+            .then(|| self.area(index))
+            .filter(|area| area.va_range.contains(vaddr))
+    }
+
+    /// Returns whether the range overlaps with any area.
+    pub fn overlaps(&self, range: AddrRange<B::Addr>) -> bool {
+        let index = self.index_after(range.start);
+        index < self.len && self.area(index).va_range.overlaps(range)
+    }
+
+    /// Inserts `area` at `index`, which must keep the areas sorted.
+    fn insert_at(&mut self, index: usize, area: StaticArea<B>) {
+        self.areas[index..=self.len].rotate_right(1);
+        self.areas[index] = Some(area);
+        self.len += 1;
+    }
+
+    /// Removes the area at `index`.
+    fn remove_at(&mut self, index: usize) {
+        self.areas[index] = None;
+        self.areas[index..self.len].rotate_left(1);
+        self.len -= 1;
+    }
+
+    /// Returns the range `[start, start + size)`, checked to be non-empty and
+    /// aligned to the granularity of the backend.
+    fn checked_range(start: B::Addr, size: usize) -> MappingResult<AddrRange<B::Addr>> {
+        let range = AddrRange::try_from_start_size(start, size)
+            .filter(|range| !range.is_empty())
+            .ok_or(MappingError::InvalidParam(err_range(start, size)))?;
+        if !start.is_aligned(B::MIN_GRANULARITY) || !size.is_multiple_of(B::MIN_GRANULARITY) {
+            return Err(MappingError::InvalidParam(untyped(range)));
+        }
+        Ok(range)
+    }
+
+    /// Maps `[start, start + size)` with `flags` by `backend` as a new area.
+    ///
+    /// Returns [`MappingError::AlreadyExists`] if the range overlaps with an
+    /// area, and [`MappingError::LimitExceeded`] if the set is full.
+    pub fn map(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        flags: B::Flags,
+        backend: B,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        let range = Self::checked_range(start, size)?;
+        if self.overlaps(range) {
+            return Err(MappingError::AlreadyExists(untyped(range)));
+        }
+        if self.len == N {
+            return Err(MappingError::LimitExceeded(untyped(range)));
+        }
+        backend
+            .map(start, size, flags, page_table)
+            .map_err(|_| MappingError::BadState(untyped(range), None))?;
+        let index = self.index_after(start);
+        self.insert_at(
+            index,
+            StaticArea {
+                va_range: range,
+                flags,
+                backend,
+            },
+        );
+        Ok(())
+    }
+
+    /// Splits the area strictly containing `pos`, if any.
+    fn split_at(&mut self, pos: B::Addr) {
+        let index = self.index_after(pos);
+        if index < self.len && self.area(index).start() < pos {
+            let right = self.areas[index].as_mut().unwrap().split(pos);
+            self.insert_at(index + 1, right);
+        }
+    }
+
+    /// Returns the number of areas to split to work on `range` alone.
+    fn splits_needed(&self, range: AddrRange<B::Addr>) -> usize {
+        [range.start, range.end]
+            .into_iter()
+            .filter(|&pos| {
+                let index = self.index_after(pos);
+                index < self.len && self.area(index).start() < pos
+            })
+            .count()
+    }
+
+    /// Unmaps `[start, start + size)`, shrinking or splitting the areas
+    /// partially in it.
+    ///
+    /// Returns [`MappingError::LimitExceeded`] and does nothing if an area
+    /// would be split in two while the set is full.
+    pub fn unmap(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        let range = Self::checked_range(start, size)?;
+        let index = self.index_after(start);
+        if self.len == N
+            && index < self.len
+            && self.area(index).start() < range.start
+            && range.end < self.area(index).end()
+        {
+            return Err(MappingError::LimitExceeded(untyped(range)));
+        }
+        let mut index = index;
+        while index < self.len && self.area(index).start() < range.end {
+            let area = self.areas[index].as_mut().unwrap();
+            let unmap_start = range.start.max(area.start());
+            let unmap_end = range.end.min(area.end());
+            area.backend
+                .unmap(unmap_start, unmap_end.sub_addr(unmap_start), page_table)
+                .map_err(|_| {
+                    MappingError::BadState(untyped(AddrRange::new(unmap_start, unmap_end)), None)
+                })?;
+            if area.start() < unmap_start {
+                let mut right = area.split(unmap_start);
+                index += 1;
+                if unmap_end < right.end() {
+                    let rest = right.split(unmap_end);
+                    self.insert_at(index, rest);
+                }
+            } else if unmap_end < area.end() {
+                area.va_range.start = unmap_end;
+                index += 1;
+            } else {
+                self.remove_at(index);
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the flags of `[start, start + size)` to `new_flags`, splitting
+    /// the areas partially in it.
+    ///
+    /// Holes are skipped. Returns [`MappingError::LimitExceeded`] and does
+    /// nothing if the set has no room for the areas split.
+    pub fn protect(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        new_flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        let range = Self::checked_range(start, size)?;
+        if self.len + self.splits_needed(range) > N {
+            return Err(MappingError::LimitExceeded(untyped(range)));
+        }
+        self.split_at(range.start);
+        self.split_at(range.end);
+        let mut index = self.index_after(range.start);
+        while index < self.len && self.area(index).start() < range.end {
+            let area = self.areas[index].as_mut().unwrap();
+            area.backend
+                .protect(area.start(), area.size(), new_flags, page_table)
+                .map_err(|_| MappingError::BadState(untyped(area.va_range), None))?;
+            area.flags = new_flags;
+            index += 1;
+        }
+        Ok(())
+    }
+
+    /// Unmaps all the areas.
+    pub fn clear(&mut self, page_table: &mut B::PageTable) -> MappingResult {
+        while self.len > 0 {
+            let area = self.area(self.len - 1);
+            area.backend
+                .unmap(area.start(), area.size(), page_table)
+                .map_err(|_| MappingError::BadState(untyped(area.va_range), None))?;
+            self.remove_at(self.len - 1);
+        }
+        Ok(())
+    }
+}
+
+impl<B: MappingBackend, const N: usize> Default for StaticMemorySet<B, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<B: MappingBackend, const N: usize> fmt::Debug for StaticMemorySet<B, N>
+where
+    B::Addr: fmt::Debug,
+    B::Flags: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
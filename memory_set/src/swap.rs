@@ -0,0 +1,256 @@
+//! Swapping the resident pages of a [`MemorySet`] out to a [`SwapBackend`],
+//! and back in on page faults.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::task::{Context, Poll};
+
+use memory_addr::{FrameTracker, MemoryAddr};
+
+use crate::{
+    MappingBackend, MappingError, MappingKind, MappingResult, MemoryArea, MemorySet, ScanCursor,
+    backend_error, untyped,
+};
+
+/// A store of swapped-out pages, e.g., a swap partition or compressed
+/// memory, set with [`MemorySet::set_swap`].
+///
+/// It is shared by the areas holding pages in it, so it takes `&self` and
+/// has to synchronize itself.
+pub trait SwapBackend {
+    /// Stores the contents of a page, and returns the slot holding it, or
+    /// `None` if the swap is full.
+    fn store(&self, data: &[u8]) -> Option<usize>;
+
+    /// Loads the contents of the page held by `slot` into `data`.
+    fn load(&self, slot: usize, data: &mut [u8]);
+
+    /// Frees `slot`, whose page is no longer needed.
+    fn free(&self, slot: usize);
+
+    /// Polls whether the page held by `slot` can be loaded without
+    /// blocking, for [`MemorySet::handle_page_fault_async`].
+    ///
+    /// A swap on a disk starts reading the page into a cache on the first
+    /// poll and returns [`Poll::Pending`] until it is there, waking the waker
+    /// of `cx` then. Returns [`Poll::Ready`] by default.
+    fn poll_ready(&self, _slot: usize, _cx: &mut Context<'_>) -> Poll<()> {
+        Poll::Ready(())
+    }
+}
+
+/// A page held by a [`SwapBackend`], whose slot is freed on drop.
+///
+/// Areas hold it behind an [`Arc`], so that areas cloned copy-on-write
+/// share the swapped pages like they share the frames.
+pub struct SwapSlot {
+    swap: Arc<dyn SwapBackend + Send + Sync>,
+    slot: usize,
+}
+
+impl SwapSlot {
+    /// Returns the slot in the swap.
+    pub const fn slot(&self) -> usize {
+        self.slot
+    }
+
+    /// Polls whether the page can be loaded without blocking, see
+    /// [`SwapBackend::poll_ready`].
+    pub(crate) fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.swap.poll_ready(self.slot, cx)
+    }
+}
+
+impl Drop for SwapSlot {
+    fn drop(&mut self) {
+        self.swap.free(self.slot);
+    }
+}
+
+impl<B: MappingBackend> MemoryArea<B> {
+    /// Returns whether the page containing `vaddr` is swapped out.
+    pub fn is_swapped(&self, vaddr: B::Addr) -> bool {
+        let page = vaddr.align_down(self.frame_size());
+        self.swapped.contains_key(&page)
+    }
+
+    /// Returns whether the pages of the area may be swapped out: locked,
+    /// reserved and [shared](Self::is_shared) areas and
+    /// [device](MappingKind::Device) memory are never swapped.
+    pub(crate) fn is_swappable(&self) -> bool {
+        !self.is_locked()
+            && !self.is_reserved()
+            && !self.is_shared()
+            && self.backend().mapping_kind() != MappingKind::Device
+    }
+
+    /// Returns the total size of the swapped-out pages.
+    pub fn swapped_size(&self) -> usize {
+        self.swapped.len() * self.frame_size()
+    }
+
+    /// Swaps out the resident page `page`: stores its contents in `swap`,
+    /// unmaps it and drops its frame.
+    ///
+    /// Returns `false` if the swap is full.
+    fn swap_out_page(
+        &mut self,
+        page: B::Addr,
+        swap: &Arc<dyn SwapBackend + Send + Sync>,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult<bool> {
+        let size = self.frame_size();
+        debug_assert_eq!(
+            self.frames.frame_size(&page),
+            size,
+            "swapping out a huge frame"
+        );
+        let frame = &self.frames.get(&page).unwrap();
+        let Some(slot) = swap.store(frame.as_slice()) else {
+            return Ok(false);
+        };
+        let slot = Arc::new(SwapSlot {
+            swap: swap.clone(),
+            slot,
+        });
+        self.backend
+            .unmap(page, size, page_table)
+            .map_err(|err| backend_error(page, size, err))?;
+        self.frames.remove(&page);
+        self.swapped.insert(page, slot);
+        Ok(true)
+    }
+
+    /// Swaps in the page `page` into a new frame, and maps it.
+    pub(crate) fn swap_in_page(
+        &mut self,
+        page: B::Addr,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        let size = self.frame_size();
+        let slot = &self.swapped[&page];
+        let mut frame = B::FrameTrackerImpl::alloc_frame();
+        slot.swap.load(slot.slot, frame.as_mut_slice());
+        self.backend_map_frame(page, &frame, self.flags(), page_table)?;
+        if self.is_write_protected() {
+            self.backend_write_protect(page, size, self.flags(), page_table)?;
+        }
+        self.swapped.remove(&page);
+        self.frames.insert(page, frame.into());
+        Ok(())
+    }
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Sets the swap that [`swap_out`](Self::swap_out) stores pages in.
+    ///
+    /// The pages already swapped out stay in their previous swap.
+    pub fn set_swap(&mut self, swap: impl SwapBackend + Send + Sync + 'static) {
+        self.swap = Some(Arc::new(swap));
+    }
+
+    /// Swaps out the resident pages within `[start, start + size)`, and
+    /// returns the number of pages swapped out.
+    ///
+    /// Their contents are stored in the swap set with
+    /// [`set_swap`](Self::set_swap), they are unmapped and their frames are
+    /// dropped. The next fault in such a page, handled by
+    /// [`handle_page_fault`](Self::handle_page_fault), swaps it back in.
+    /// Locked, reserved and [shared](MemoryArea::is_shared) areas,
+    /// [device](MappingKind::Device) memory and zero pages are skipped, and
+    /// swapping stops early when the swap is full.
+    ///
+    /// Returns [`MappingError::BadState`] if no swap is set.
+    pub fn swap_out(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult<usize> {
+        self.bump_generation();
+        let range = self.granular_range(start, size)?;
+        let swap = self
+            .swap
+            .clone()
+            .ok_or(MappingError::BadState(untyped(range), None))?;
+        let candidates: Vec<_> = self.area_starts_in(range).collect();
+        let mut swapped = 0;
+        for area_start in candidates {
+            let area = self.areas.get_mut(&area_start).unwrap();
+            if !area.is_swappable() {
+                continue;
+            }
+            // Huge frames are swapped out page by page.
+            area.frames.demote_range(range.start, range.end)?;
+            let pages: Vec<_> = area
+                .frames
+                .range(range.start.align_down(area.frame_size())..range.end)
+                .filter(|(_, frame)| !area.is_zero_frame(frame))
+                .map(|(&page, _)| page)
+                .collect();
+            for page in pages {
+                if !area.swap_out_page(page, &swap, page_table)? {
+                    return Ok(swapped);
+                }
+                swapped += 1;
+            }
+        }
+        Ok(swapped)
+    }
+
+    /// Swaps out at most `max_pages` resident pages from the position of
+    /// `cursor`, and returns the number of pages swapped out.
+    ///
+    /// Same as [`swap_out`](Self::swap_out) done in steps, e.g., by a
+    /// reclaim daemon at every tick. The cursor is left after the last page
+    /// swapped out, or at the page that did not fit when the swap is full.
+    pub fn swap_out_step(
+        &mut self,
+        cursor: &mut ScanCursor<B::Addr>,
+        max_pages: usize,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult<usize> {
+        self.bump_generation();
+        let swap = self
+            .swap
+            .clone()
+            .ok_or(MappingError::BadState(untyped(cursor.range()), None))?;
+        let mut swapped = 0;
+        while swapped < max_pages {
+            let Some(area_start) = self.area_starts_in(cursor.remaining()).next() else {
+                cursor.advance_to(cursor.range().end);
+                break;
+            };
+            let area = self.areas.get_mut(&area_start).unwrap();
+            let end = cursor.range().end.min(area.end());
+            if !area.is_swappable() {
+                cursor.advance_to(end);
+                continue;
+            }
+            let first = cursor
+                .position()
+                .max(area.start())
+                .align_down(area.frame_size());
+            area.frames.demote_range(first, end)?;
+            let pages: Vec<_> = area
+                .frames
+                .range(first..end)
+                .filter(|(_, frame)| !area.is_zero_frame(frame))
+                .map(|(&page, _)| page)
+                .take(max_pages - swapped)
+                .collect();
+            let frame_size = area.frame_size();
+            let mut next = end;
+            for page in pages {
+                if !area.swap_out_page(page, &swap, page_table)? {
+                    cursor.advance_to(page);
+                    return Ok(swapped);
+                }
+                swapped += 1;
+                next = page.add(frame_size);
+            }
+            cursor.advance_to(if swapped < max_pages { end } else { next });
+        }
+        Ok(swapped)
+    }
+}
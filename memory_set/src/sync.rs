@@ -0,0 +1,272 @@
+//! A [`MemorySet`] shared between threads, with a lock per area so that page
+//! faults in different areas run in parallel.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use memory_addr::{AddrRange, MemoryAddr};
+use spin::{Mutex, RwLock};
+
+use crate::{
+    MappingBackend, MappingError, MappingResult, MemoryArea, MemorySet, err_range, untyped,
+};
+
+/// A set of memory areas shared between threads, guarding the tree of areas
+/// with a read-write lock and each area with its own lock.
+///
+/// Page faults only take the tree for reading and lock the area faulting,
+/// so that faults in different areas do not serialize on one address space
+/// lock, while mapping, unmapping and protecting take the tree for writing.
+/// It offers the operations needed on the hot paths; build the set as a
+/// [`MemorySet`] and convert it with [`From`] for the others.
+///
+/// The page table is passed to each call, so concurrent callers need their
+/// own handles to it, e.g., a page table type that synchronizes itself.
+pub struct SyncMemorySet<B: MappingBackend> {
+    areas: RwLock<BTreeMap<B::Addr, Mutex<MemoryArea<B>>>>,
+}
+
+impl<B: MappingBackend> SyncMemorySet<B> {
+    /// Creates a new empty set.
+    pub const fn new() -> Self {
+        Self {
+            areas: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns the number of areas.
+    pub fn len(&self) -> usize {
+        self.areas.read().len()
+    }
+
+    /// Returns whether there is no area.
+    pub fn is_empty(&self) -> bool {
+        self.areas.read().is_empty()
+    }
+
+    /// Calls `f` with the area containing `vaddr` locked, and returns its
+    /// result, or `None` if no area contains `vaddr`.
+    pub fn with_area<R>(
+        &self,
+        vaddr: B::Addr,
+        f: impl FnOnce(&mut MemoryArea<B>) -> R,
+    ) -> Option<R> {
+        let areas = self.areas.read();
+        let (_, area) = areas.range(..=vaddr).next_back()?;
+        let mut area = area.lock();
+        area.va_range().contains(vaddr).then(|| f(&mut area))
+    }
+
+    /// Handles a page fault at `vaddr`, like
+    /// [`MemorySet::handle_page_fault`], locking only the area faulting.
+    ///
+    /// The frame quota and the other configuration of a [`MemorySet`] are
+    /// not kept by this set, so they are not enforced here.
+    pub fn handle_page_fault(
+        &self,
+        vaddr: B::Addr,
+        access_flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.with_area(vaddr, |area| {
+            if !area.backend().check_access(area.flags(), access_flags) {
+                return Err(MappingError::PermissionDenied(err_range(vaddr, 1)));
+            }
+            area.handle_fault(vaddr, access_flags, page_table)
+        })
+        .unwrap_or(Err(MappingError::NotMapped(err_range(vaddr, 1))))
+    }
+
+    /// Maps `area` in the page table and adds it to the set.
+    ///
+    /// Returns [`MappingError::InvalidParam`] if the area is empty or not
+    /// aligned to its granularity, and [`MappingError::AlreadyExists`] if it
+    /// overlaps with another area, counting the guard regions of both (see
+    /// [`MemoryArea::with_guards`]).
+    pub fn map(&self, mut area: MemoryArea<B>, page_table: &mut B::PageTable) -> MappingResult {
+        if area.va_range().is_empty() || !area.is_granule_aligned() {
+            return Err(MappingError::InvalidParam(untyped(area.va_range())));
+        }
+        let mut areas = self.areas.write();
+        let reserved = area.reserved_range();
+        let overlaps =
+            |area: &mut Mutex<MemoryArea<B>>| area.get_mut().reserved_range().overlaps(reserved);
+        let before = areas.range_mut(..reserved.start).next_back();
+        let overlaps_before = before.is_some_and(|(_, area)| overlaps(area));
+        let after = areas.range_mut(reserved.start..).next();
+        if overlaps_before || after.is_some_and(|(_, area)| overlaps(area)) {
+            return Err(MappingError::AlreadyExists(untyped(reserved)));
+        }
+        area.map_area(page_table, None)?;
+        assert!(areas.insert(area.start(), Mutex::new(area)).is_none());
+        Ok(())
+    }
+
+    /// Returns the area containing `vaddr`, if any.
+    fn find(
+        areas: &mut BTreeMap<B::Addr, Mutex<MemoryArea<B>>>,
+        vaddr: B::Addr,
+    ) -> Option<&mut MemoryArea<B>> {
+        let (_, area) = areas.range_mut(..=vaddr).next_back()?;
+        let area = area.get_mut();
+        area.va_range().contains(vaddr).then_some(area)
+    }
+
+    /// Returns `[start, start + size)` with its end rounded up to the
+    /// granularity of the area there, like [`MemorySet::unmap`] does.
+    ///
+    /// Returns [`MappingError::InvalidParam`] if the range overflows, or if
+    /// its start is not aligned to the granularity of the area there, so
+    /// that nothing is split halfway.
+    fn granular_range(
+        areas: &mut BTreeMap<B::Addr, Mutex<MemoryArea<B>>>,
+        start: B::Addr,
+        size: usize,
+    ) -> MappingResult<AddrRange<B::Addr>> {
+        let mut range = AddrRange::try_from_start_size(start, size)
+            .ok_or(MappingError::InvalidParam(err_range(start, size)))?;
+        if range.is_empty() {
+            return Ok(range);
+        }
+        let start_granularity =
+            Self::find(areas, range.start).map_or(B::MIN_GRANULARITY, |area| area.granularity());
+        if !range.start.is_aligned(start_granularity) {
+            return Err(MappingError::InvalidParam(untyped(range)));
+        }
+        if let Some(area) = Self::find(areas, range.end.wrapping_sub(1)) {
+            // `area.end()` is aligned, so this never goes past it.
+            range.end = range.end.align_up(area.granularity());
+        }
+        Ok(range)
+    }
+
+    /// Checks that no area within `range` is sealed, like
+    /// [`MemorySet::seal`] requires. Holes are sealed too.
+    fn check_sealed(
+        areas: &mut BTreeMap<B::Addr, Mutex<MemoryArea<B>>>,
+        range: AddrRange<B::Addr>,
+    ) -> MappingResult {
+        let crossing = areas
+            .range_mut(..range.start)
+            .next_back()
+            .map(|(_, area)| area.get_mut())
+            .filter(|area| area.end() > range.start && area.is_sealed())
+            .map(|area| area.va_range());
+        let sealed = crossing.or_else(|| {
+            areas
+                .range_mut(range.start..range.end)
+                .map(|(_, area)| area.get_mut())
+                .find(|area| area.is_sealed())
+                .map(|area| area.va_range())
+        });
+        match sealed {
+            Some(sealed) => Err(MappingError::PermissionDenied(untyped(sealed))),
+            None => Ok(()),
+        }
+    }
+
+    /// Splits the area strictly containing `pos`, if any.
+    fn split_at(
+        areas: &mut BTreeMap<B::Addr, Mutex<MemoryArea<B>>>,
+        pos: B::Addr,
+    ) -> MappingResult {
+        let right = match areas.range_mut(..pos).next_back() {
+            Some((_, area)) => area.get_mut().split(pos)?,
+            None => None,
+        };
+        if let Some(right) = right {
+            areas.insert(pos, Mutex::new(right));
+        }
+        Ok(())
+    }
+
+    /// Returns the starts of the areas within `range`, after splitting the
+    /// areas crossing its ends.
+    fn isolate(
+        areas: &mut BTreeMap<B::Addr, Mutex<MemoryArea<B>>>,
+        range: AddrRange<B::Addr>,
+    ) -> MappingResult<Vec<B::Addr>> {
+        Self::split_at(areas, range.start)?;
+        Self::split_at(areas, range.end)?;
+        Ok(areas
+            .range(range.start..range.end)
+            .map(|(&start, _)| start)
+            .collect())
+    }
+
+    /// Unmaps `[start, start + size)`, shrinking or splitting the areas
+    /// partially in it, like [`MemorySet::unmap`].
+    ///
+    /// The range is checked and rounded like by [`MemorySet::unmap`], and
+    /// [`MappingError::PermissionDenied`] is returned if a sealed area is in
+    /// it, before anything is split.
+    pub fn unmap(
+        &self,
+        start: B::Addr,
+        size: usize,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        let mut areas = self.areas.write();
+        let range = Self::granular_range(&mut areas, start, size)?;
+        if range.is_empty() {
+            return Ok(());
+        }
+        Self::check_sealed(&mut areas, range)?;
+        for area_start in Self::isolate(&mut areas, range)? {
+            // An area that fails to unmap stays, like in `MemorySet::unmap`.
+            areas
+                .get_mut(&area_start)
+                .unwrap()
+                .get_mut()
+                .unmap_area(page_table)?;
+            areas.remove(&area_start);
+        }
+        Ok(())
+    }
+
+    /// Changes the flags of `[start, start + size)` to `new_flags`, splitting
+    /// the areas partially in it, like [`MemorySet::protect`] with the same
+    /// flags for all the areas.
+    ///
+    /// The range is checked like by [`unmap`](Self::unmap), and sealed areas
+    /// are refused the same way.
+    pub fn protect(
+        &self,
+        start: B::Addr,
+        size: usize,
+        new_flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        let mut areas = self.areas.write();
+        let range = Self::granular_range(&mut areas, start, size)?;
+        if range.is_empty() {
+            return Ok(());
+        }
+        Self::check_sealed(&mut areas, range)?;
+        for area_start in Self::isolate(&mut areas, range)? {
+            let area = areas.get_mut(&area_start).unwrap().get_mut();
+            area.protect_area(new_flags, page_table)?;
+            area.set_flags(new_flags);
+        }
+        Ok(())
+    }
+}
+
+impl<B: MappingBackend> Default for SyncMemorySet<B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<B: MappingBackend> From<MemorySet<B>> for SyncMemorySet<B> {
+    /// Takes the areas of `set`, leaving its configuration behind.
+    fn from(mut set: MemorySet<B>) -> Self {
+        let areas = core::mem::take(&mut set.areas)
+            .into_iter()
+            .map(|(start, area)| (start, Mutex::new(area)))
+            .collect();
+        Self {
+            areas: RwLock::new(areas),
+        }
+    }
+}
@@ -0,0 +1,830 @@
+//! A configurable backend for testing code built on [`MemorySet`].
+//!
+//! [`TestBackend`] keeps one flags byte per address in a [`TestPageTable`],
+//! and its `map`, `unmap` and `protect` can be told to fail at the N-th call,
+//! to apply only part of the range before failing, or to sleep, so that the
+//! error unwinding and rollback paths of the set (and of the code using it)
+//! can be exercised, including from several threads.
+//!
+//! Its base page size is 4K unless given as `PAGE_SIZE`, e.g.,
+//! `TestBackend<0x4000>` for a 16K-page configuration.
+//!
+//! [`assert_layout_eq`] and [`MemorySet::check_invariants`] check the state
+//! of any set, e.g., in the integration tests of a kernel.
+//!
+//! [`MemorySet`]: crate::MemorySet
+
+#[cfg(feature = "RAII")]
+use alloc::boxed::Box;
+#[cfg(feature = "RAII")]
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+use std::sync::Mutex;
+
+use memory_addr::{AddrRange, MemoryAddr, PAGE_SIZE_4K, PhysAddr, VirtAddr};
+
+use crate::{Confidentiality, MappingBackend, MappingKind, MemorySet};
+
+/// The page table of [`TestBackend`]: the flags of every address, `0` if
+/// unmapped.
+pub type TestPageTable = Vec<u8>;
+
+/// Creates a [`TestPageTable`] covering `[0, size)`, with nothing mapped.
+pub fn test_page_table(size: usize) -> TestPageTable {
+    vec![0; size]
+}
+
+/// The bit of the access flags of [`TestBackend`] marking writes, see
+/// [`MappingBackend::is_write_access`].
+pub const WRITE_ACCESS: u8 = 0x80;
+
+/// The bit of the flags of [`TestBackend`] marking memory shared with the
+/// host of a confidential VM, see [`MappingBackend::confidential_flags`].
+pub const SHARED_BIT: u8 = 0x40;
+
+/// A backend operation whose behavior can be configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// [`MappingBackend::map`].
+    Map,
+    /// [`MappingBackend::unmap`].
+    Unmap,
+    /// [`MappingBackend::protect`].
+    Protect,
+    /// [`MappingBackend::convert_confidentiality`].
+    Convert,
+    /// [`MappingBackend::move_mappings`].
+    Move,
+}
+
+/// The error of [`TestBackend`]: the operation failed, either as configured
+/// or because the page table does not allow it, e.g., when mapping an
+/// address twice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TestError(pub Op);
+
+impl fmt::Display for TestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "test backend: {:?} failed", self.0)
+    }
+}
+
+impl core::error::Error for TestError {}
+
+/// The injected behavior of one operation.
+struct Inject {
+    calls: AtomicUsize,
+    /// The call number to fail at, or `0` for never.
+    fail_at: AtomicUsize,
+    partial: AtomicBool,
+    delay_us: AtomicU64,
+}
+
+impl Inject {
+    const fn new() -> Self {
+        Self {
+            calls: AtomicUsize::new(0),
+            fail_at: AtomicUsize::new(0),
+            partial: AtomicBool::new(false),
+            delay_us: AtomicU64::new(0),
+        }
+    }
+}
+
+/// A backend for tests, see the [module documentation](self).
+///
+/// The configuration is shared by all the clones of a backend, i.e., by all
+/// the areas created with it, and can be changed from any thread.
+#[derive(Clone)]
+pub struct TestBackend<const PAGE_SIZE: usize = PAGE_SIZE_4K> {
+    inject: Arc<[Inject; 5]>,
+    /// The calls of [`MappingBackend::memory_pressure`], failing when under
+    /// pressure.
+    pressure: Arc<Inject>,
+    mapping_kind: MappingKind,
+    #[cfg(feature = "RAII")]
+    zero_frame: Option<Arc<TestFrame<PAGE_SIZE>>>,
+    collapsed: Arc<Mutex<Vec<AddrRange<VirtAddr>>>>,
+    write_protected: Arc<Mutex<Vec<AddrRange<VirtAddr>>>>,
+    sources: Arc<Mutex<Vec<usize>>>,
+    last_page_size: Arc<AtomicUsize>,
+    access_check: bool,
+    merging: bool,
+    /// The accessed pages, or `None` if the accessed bits are not tracked.
+    accessed: Option<Arc<Mutex<Vec<VirtAddr>>>>,
+    pending_faults: Arc<AtomicUsize>,
+    numa_node_size: Arc<AtomicUsize>,
+    granularity: usize,
+}
+
+impl<const PAGE_SIZE: usize> TestBackend<PAGE_SIZE> {
+    /// Creates a backend whose operations all succeed.
+    pub fn new() -> Self {
+        Self {
+            inject: Arc::new([const { Inject::new() }; 5]),
+            pressure: Arc::new(Inject::new()),
+            mapping_kind: MappingKind::Anonymous,
+            #[cfg(feature = "RAII")]
+            zero_frame: None,
+            collapsed: Arc::new(Mutex::new(Vec::new())),
+            write_protected: Arc::new(Mutex::new(Vec::new())),
+            sources: Arc::new(Mutex::new(Vec::new())),
+            last_page_size: Arc::new(AtomicUsize::new(0)),
+            access_check: false,
+            merging: false,
+            accessed: None,
+            pending_faults: Arc::new(AtomicUsize::new(0)),
+            numa_node_size: Arc::new(AtomicUsize::new(0)),
+            granularity: Self::MIN_GRANULARITY,
+        }
+    }
+
+    /// Makes the backend provide a zero frame from
+    /// [`MappingBackend::zero_frame`], shared by its clones.
+    #[cfg(feature = "RAII")]
+    pub fn with_zero_frame(mut self) -> Self {
+        use memory_addr::FrameTracker;
+        self.zero_frame = Some(Arc::new(TestFrame::alloc_frame()));
+        self
+    }
+
+    /// Makes the backend report `kind` from
+    /// [`MappingBackend::mapping_kind`], instead of
+    /// [`MappingKind::Anonymous`].
+    pub fn with_mapping_kind(mut self, kind: MappingKind) -> Self {
+        self.mapping_kind = kind;
+        self
+    }
+
+    /// Makes [`MappingBackend::check_access`] only allow the accesses whose
+    /// flags, apart from [`WRITE_ACCESS`], are all set in the flags of the
+    /// area, instead of everything.
+    pub fn with_access_check(mut self) -> Self {
+        self.access_check = true;
+        self
+    }
+
+    /// Makes [`MappingBackend::can_merge`] allow merging the areas with the
+    /// same flags mapped by backends created this way, instead of nothing.
+    pub fn with_merging(mut self) -> Self {
+        self.merging = true;
+        self
+    }
+
+    /// Makes [`MappingBackend::test_and_clear_accessed`] report the pages
+    /// [touched](TestBackend::touch) since the previous call, instead of
+    /// nothing.
+    pub fn with_accessed_bits(mut self) -> Self {
+        self.accessed = Some(Arc::new(Mutex::new(Vec::new())));
+        self
+    }
+
+    /// Sets the accessed bit of the page containing `vaddr`, if they are
+    /// tracked, see [`TestBackend::with_accessed_bits`].
+    pub fn touch(&self, vaddr: VirtAddr) {
+        if let Some(accessed) = &self.accessed {
+            accessed.lock().unwrap().push(vaddr.align_down(PAGE_SIZE));
+        }
+    }
+
+    /// Makes [`MappingBackend::granularity`] report `granularity` instead of
+    /// [`MIN_GRANULARITY`](MappingBackend::MIN_GRANULARITY), which is a
+    /// single byte.
+    pub fn with_granularity(mut self, granularity: usize) -> Self {
+        debug_assert!(granularity.is_power_of_two());
+        self.granularity = granularity;
+        self
+    }
+
+    /// Returns the regions collapsed into huge pages by
+    /// [`MappingBackend::collapse_huge`], in the order of the calls.
+    pub fn collapsed(&self) -> Vec<AddrRange<VirtAddr>> {
+        self.collapsed.lock().unwrap().clone()
+    }
+
+    /// Returns the ranges passed to [`MappingBackend::write_protect`], in
+    /// the order of the calls.
+    pub fn write_protected(&self) -> Vec<AddrRange<VirtAddr>> {
+        self.write_protected.lock().unwrap().clone()
+    }
+
+    /// Returns the frame sources selected by
+    /// [`MappingBackend::select_source`], in the order of the calls.
+    pub fn sources(&self) -> Vec<usize> {
+        self.sources.lock().unwrap().clone()
+    }
+
+    /// Returns the page size passed to the last call of
+    /// [`MappingBackend::map_with_page_size`], or `0` if there was none.
+    pub fn last_page_size(&self) -> usize {
+        self.last_page_size.load(Ordering::SeqCst)
+    }
+
+    fn inject(&self, op: Op) -> &Inject {
+        &self.inject[op as usize]
+    }
+
+    /// Makes the `n`-th call of `op` from now on fail (counting from 1), or
+    /// never if `n` is `0`.
+    pub fn fail_at(&self, op: Op, n: usize) {
+        let inject = self.inject(op);
+        let target = match n {
+            0 => 0,
+            n => inject.calls.load(Ordering::SeqCst) + n,
+        };
+        inject.fail_at.store(target, Ordering::SeqCst);
+    }
+
+    /// Makes [`MappingBackend::memory_pressure`] report pressure from its
+    /// `n`-th call from now on (counting from 1), or never if `n` is `0`.
+    pub fn pressure_at(&self, n: usize) {
+        let target = match n {
+            0 => 0,
+            n => self.pressure.calls.load(Ordering::SeqCst) + n,
+        };
+        self.pressure.fail_at.store(target, Ordering::SeqCst);
+    }
+
+    /// Makes the failing calls of `op` apply the first half of their range
+    /// before reporting the failure, like a page table running out of memory
+    /// halfway.
+    pub fn set_partial(&self, op: Op, partial: bool) {
+        self.inject(op).partial.store(partial, Ordering::SeqCst);
+    }
+
+    /// Makes every call of `op` sleep for `delay` first.
+    pub fn set_delay(&self, op: Op, delay: Duration) {
+        let us = delay.as_micros().try_into().unwrap_or(u64::MAX);
+        self.inject(op).delay_us.store(us, Ordering::SeqCst);
+    }
+
+    /// Makes the next `n` calls of [`MappingBackend::poll_fault_ready`]
+    /// return [`Poll::Pending`], waking the waker at once, as if a page were
+    /// being fetched.
+    pub fn set_pending_faults(&self, n: usize) {
+        self.pending_faults.store(n, Ordering::SeqCst);
+    }
+
+    /// Makes [`MappingBackend::numa_node`] report the physical memory as
+    /// split into NUMA nodes of `size` bytes each, or unknown if `size` is
+    /// `0` (the default).
+    pub fn set_numa_node_size(&self, size: usize) {
+        self.numa_node_size.store(size, Ordering::SeqCst);
+    }
+
+    /// Returns the number of calls of `op` so far.
+    pub fn calls(&self, op: Op) -> usize {
+        self.inject(op).calls.load(Ordering::SeqCst)
+    }
+
+    /// Runs a call of `op`, applying `apply` to the part of
+    /// `[start, start + size)` the call gets to.
+    ///
+    /// Returns [`TestError`] if the call fails.
+    fn run(
+        &self,
+        op: Op,
+        start: VirtAddr,
+        size: usize,
+        mut apply: impl FnMut(usize) -> bool,
+    ) -> Result<(), TestError> {
+        let inject = self.inject(op);
+        let delay_us = inject.delay_us.load(Ordering::SeqCst);
+        if delay_us > 0 {
+            std::thread::sleep(Duration::from_micros(delay_us));
+        }
+        let call = inject.calls.fetch_add(1, Ordering::SeqCst) + 1;
+        let fail = inject.fail_at.load(Ordering::SeqCst) == call;
+        let size = match (fail, inject.partial.load(Ordering::SeqCst)) {
+            (false, _) => size,
+            (true, true) => size / 2,
+            (true, false) => 0,
+        };
+        let start = start.as_usize();
+        if (start..start + size).all(&mut apply) && !fail {
+            Ok(())
+        } else {
+            Err(TestError(op))
+        }
+    }
+}
+
+impl<const PAGE_SIZE: usize> Default for TestBackend<PAGE_SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serialized as a unit, so that the backend restored from a
+/// [layout](crate::SetLayout) is a new one, without the failures injected.
+#[cfg(feature = "serde")]
+impl<const PAGE_SIZE: usize> serde::Serialize for TestBackend<PAGE_SIZE> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_unit_struct("TestBackend")
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, const PAGE_SIZE: usize> serde::Deserialize<'de> for TestBackend<PAGE_SIZE> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        serde::de::IgnoredAny::deserialize(deserializer)?;
+        Ok(Self::new())
+    }
+}
+
+#[cfg(feature = "RAII")]
+/// The frame tracker of [`TestBackend`], backed by a heap buffer of
+/// `PAGE_SIZE` bytes.
+pub struct TestFrame<const PAGE_SIZE: usize = PAGE_SIZE_4K> {
+    start: memory_addr::PhysAddr,
+    _buf: Option<Box<[u8]>>,
+    pinned: bool,
+}
+
+#[cfg(feature = "RAII")]
+impl<const PAGE_SIZE: usize> TestFrame<PAGE_SIZE> {
+    /// Creates an untracked frame at `pa` that [`TestBackend`] refuses to
+    /// split, like a block its allocator cannot break up.
+    pub fn pinned(pa: memory_addr::PhysAddr) -> Self {
+        Self {
+            start: pa,
+            _buf: None,
+            pinned: true,
+        }
+    }
+}
+
+#[cfg(feature = "RAII")]
+impl<const PAGE_SIZE: usize> memory_addr::FrameTracker for TestFrame<PAGE_SIZE> {
+    const PAGE_SIZE: usize = PAGE_SIZE;
+
+    fn new(pa: memory_addr::PhysAddr) -> Self {
+        Self {
+            start: pa,
+            _buf: None,
+            pinned: false,
+        }
+    }
+
+    fn no_tracking(pa: memory_addr::PhysAddr) -> Self {
+        Self::new(pa)
+    }
+
+    fn alloc_frame() -> Self {
+        let buf = vec![0; Self::PAGE_SIZE].into_boxed_slice();
+        Self {
+            start: (buf.as_ptr() as usize).into(),
+            _buf: Some(buf),
+            pinned: false,
+        }
+    }
+
+    fn dealloc_frame(&mut self) {
+        self._buf = None;
+    }
+
+    fn start(&self) -> memory_addr::PhysAddr {
+        self.start
+    }
+}
+
+impl<const PAGE_SIZE: usize> MappingBackend for TestBackend<PAGE_SIZE> {
+    type Addr = VirtAddr;
+    type Flags = u8;
+    type PageTable = TestPageTable;
+    type Error = TestError;
+
+    /// Byte granularity, so that tests can use small ranges.
+    const MIN_GRANULARITY: usize = 1;
+    const BASE_PAGE_SIZE: usize = PAGE_SIZE;
+
+    #[cfg(feature = "RAII")]
+    type FrameTrackerImpl = TestFrame<PAGE_SIZE>;
+    #[cfg(feature = "RAII")]
+    type FrameTrackerRef = Arc<TestFrame<PAGE_SIZE>>;
+
+    #[cfg(feature = "RAII")]
+    fn map(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        flags: u8,
+        pt: &mut TestPageTable,
+    ) -> Result<BTreeMap<VirtAddr, Arc<TestFrame<PAGE_SIZE>>>, TestError> {
+        self.run(Op::Map, start, size, |addr| map_entry(pt, addr, flags))?;
+        Ok(BTreeMap::new())
+    }
+
+    #[cfg(not(feature = "RAII"))]
+    fn map(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        flags: u8,
+        pt: &mut TestPageTable,
+    ) -> Result<(), TestError> {
+        self.run(Op::Map, start, size, |addr| map_entry(pt, addr, flags))
+    }
+
+    #[cfg(feature = "RAII")]
+    fn map_with_page_size(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        flags: u8,
+        page_size: usize,
+        pt: &mut TestPageTable,
+    ) -> Result<BTreeMap<VirtAddr, Arc<TestFrame<PAGE_SIZE>>>, TestError> {
+        self.last_page_size.store(page_size, Ordering::SeqCst);
+        self.map(start, size, flags, pt)
+    }
+
+    #[cfg(not(feature = "RAII"))]
+    fn map_with_page_size(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        flags: u8,
+        page_size: usize,
+        pt: &mut TestPageTable,
+    ) -> Result<(), TestError> {
+        self.last_page_size.store(page_size, Ordering::SeqCst);
+        self.map(start, size, flags, pt)
+    }
+
+    /// Addresses left unmapped, e.g. swapped out, are skipped.
+    fn unmap(&self, start: VirtAddr, size: usize, pt: &mut TestPageTable) -> Result<(), TestError> {
+        self.run(Op::Unmap, start, size, |addr| {
+            update_entry(pt, addr, |entry| *entry = 0) || pt.get(addr).is_some()
+        })
+    }
+
+    /// Addresses left unmapped, e.g. swapped out, are skipped.
+    fn protect(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        new_flags: u8,
+        pt: &mut TestPageTable,
+    ) -> Result<(), TestError> {
+        self.run(Op::Protect, start, size, |addr| {
+            update_entry(pt, addr, |entry| *entry = new_flags) || pt.get(addr).is_some()
+        })
+    }
+
+    /// Moves every entry of the old range to the new one with `flags`,
+    /// failing without moving anything if the new range is not free.
+    /// Unmapped entries are moved as is.
+    fn move_mappings(
+        &self,
+        old_start: VirtAddr,
+        new_start: VirtAddr,
+        size: usize,
+        flags: u8,
+        pt: &mut TestPageTable,
+    ) -> Result<bool, TestError> {
+        let (old, new) = (old_start.as_usize(), new_start.as_usize());
+        let fits = old.checked_add(size).is_some_and(|end| end <= pt.len())
+            && pt
+                .get(new..new.saturating_add(size))
+                .is_some_and(|entries| entries.iter().all(|&entry| entry == 0));
+        self.run(Op::Move, old_start, if fits { size } else { 0 }, |addr| {
+            let entry = core::mem::take(&mut pt[addr]);
+            pt[addr - old + new] = if entry == 0 { 0 } else { flags };
+            true
+        })?;
+        if !fits {
+            return Err(TestError(Op::Move));
+        }
+        Ok(true)
+    }
+
+    #[cfg(feature = "RAII")]
+    fn zero_frame(&self) -> Option<Arc<TestFrame<PAGE_SIZE>>> {
+        self.zero_frame.clone()
+    }
+
+    /// The frames are counted by their [`Arc`]s.
+    #[cfg(feature = "RAII")]
+    fn frame_ref_count(frame: &Arc<TestFrame<PAGE_SIZE>>) -> Option<usize> {
+        Some(Arc::strong_count(frame))
+    }
+
+    /// Splits the frame into untracked frames of its pages, unless it was
+    /// created with [`TestFrame::pinned`].
+    #[cfg(feature = "RAII")]
+    fn split_frame(
+        frame: &Arc<TestFrame<PAGE_SIZE>>,
+        size: usize,
+    ) -> Result<Vec<Arc<TestFrame<PAGE_SIZE>>>, Option<TestError>> {
+        use memory_addr::FrameTracker;
+        if frame.pinned {
+            return Err(None);
+        }
+        Ok((0..size / PAGE_SIZE)
+            .map(|index| Arc::new(TestFrame::no_tracking(frame.start() + index * PAGE_SIZE)))
+            .collect())
+    }
+
+    /// Maps every address of the frame with `flags`.
+    #[cfg(feature = "RAII")]
+    fn map_frame(
+        &self,
+        vaddr: VirtAddr,
+        _frame: &TestFrame<PAGE_SIZE>,
+        flags: u8,
+        pt: &mut TestPageTable,
+    ) -> Result<bool, TestError> {
+        let start = vaddr.as_usize();
+        let end = start + PAGE_SIZE;
+        let entries = pt.get_mut(start..end).ok_or(TestError(Op::Map))?;
+        entries.fill(flags);
+        Ok(true)
+    }
+
+    /// Faults on mapped addresses are spurious and resolved as is, others
+    /// fail.
+    #[cfg(feature = "RAII")]
+    fn handle_fault(
+        &self,
+        vaddr: VirtAddr,
+        _access_flags: u8,
+        _area_flags: u8,
+        pt: &mut TestPageTable,
+    ) -> Result<Option<Arc<TestFrame<PAGE_SIZE>>>, Option<TestError>> {
+        match pt.get(vaddr.as_usize()) {
+            Some(&entry) if entry != 0 => Ok(None),
+            _ => Err(None),
+        }
+    }
+
+    /// Faults on mapped addresses are spurious and resolved as is, others
+    /// fail.
+    #[cfg(not(feature = "RAII"))]
+    fn handle_fault(
+        &self,
+        vaddr: VirtAddr,
+        _access_flags: u8,
+        _area_flags: u8,
+        pt: &mut TestPageTable,
+    ) -> bool {
+        pt.get(vaddr.as_usize()).is_some_and(|&entry| entry != 0)
+    }
+
+    /// Records the source, see [`TestBackend::sources`].
+    fn select_source(&self, source: usize) {
+        self.sources.lock().unwrap().push(source);
+    }
+
+    /// Allows everything, unless the backend was created
+    /// [`with_access_check`](TestBackend::with_access_check).
+    fn check_access(&self, area_flags: u8, access_flags: u8) -> bool {
+        !self.access_check || access_flags & !WRITE_ACCESS & !area_flags == 0
+    }
+
+    /// Accesses with [`WRITE_ACCESS`] set are writes.
+    fn is_write_access(&self, access_flags: u8) -> bool {
+        access_flags & WRITE_ACCESS != 0
+    }
+
+    /// The entries are left as is, only checked to be mapped, and the range
+    /// is recorded in [`TestBackend::write_protected`].
+    fn write_protect(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        _flags: u8,
+        pt: &mut TestPageTable,
+    ) -> Result<bool, TestError> {
+        let range = AddrRange::from_start_size(start, size);
+        let start = start.as_usize();
+        match pt.get(start..start + size) {
+            Some(entries) if entries.iter().all(|&entry| entry != 0) => {
+                self.write_protected.lock().unwrap().push(range);
+                Ok(true)
+            }
+            _ => Err(TestError(Op::Protect)),
+        }
+    }
+
+    fn mapping_kind(&self) -> MappingKind {
+        self.mapping_kind
+    }
+
+    fn granularity(&self) -> usize {
+        self.granularity
+    }
+
+    /// See [`TestBackend::pressure_at`].
+    fn memory_pressure(&self) -> bool {
+        let call = self.pressure.calls.fetch_add(1, Ordering::SeqCst) + 1;
+        let target = self.pressure.fail_at.load(Ordering::SeqCst);
+        target != 0 && call >= target
+    }
+
+    /// See [`TestBackend::with_accessed_bits`].
+    fn test_and_clear_accessed(&self, vaddr: VirtAddr, _pt: &mut TestPageTable) -> Option<bool> {
+        let mut accessed = self.accessed.as_ref()?.lock().unwrap();
+        let len = accessed.len();
+        accessed.retain(|&page| page != vaddr);
+        Some(accessed.len() < len)
+    }
+
+    /// See [`TestBackend::with_merging`].
+    fn can_merge(&self, flags: u8, next: &Self, next_flags: u8) -> bool {
+        self.merging && next.merging && flags == next_flags
+    }
+
+    /// Shared memory has [`SHARED_BIT`] set.
+    fn confidential_flags(&self, flags: u8, to: Confidentiality) -> u8 {
+        match to {
+            Confidentiality::Private => flags & !SHARED_BIT,
+            Confidentiality::Shared => flags | SHARED_BIT,
+        }
+    }
+
+    /// Updates the entries to `flags`, failing if any is unmapped.
+    fn convert_confidentiality(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        flags: u8,
+        _to: Confidentiality,
+        pt: &mut TestPageTable,
+    ) -> bool {
+        self.run(Op::Convert, start, size, |addr| {
+            update_entry(pt, addr, |entry| *entry = flags)
+        })
+        .is_ok()
+    }
+
+    /// Records the region if all of it is mapped, see
+    /// [`TestBackend::collapsed`].
+    fn collapse_huge(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        _flags: u8,
+        pt: &mut TestPageTable,
+    ) -> bool {
+        let range = start.as_usize()..start.as_usize() + size;
+        if !pt
+            .get(range)
+            .is_some_and(|entries| entries.iter().all(|&entry| entry != 0))
+        {
+            return false;
+        }
+        let region = AddrRange::from_start_size(start, size);
+        self.collapsed.lock().unwrap().push(region);
+        true
+    }
+
+    /// See [`TestBackend::set_numa_node_size`].
+    fn numa_node(&self, paddr: PhysAddr) -> Option<usize> {
+        let size = self.numa_node_size.load(Ordering::SeqCst);
+        (size != 0).then(|| paddr.as_usize() / size)
+    }
+
+    /// Only the collapsed regions have a translation, to themselves.
+    fn translate(&self, vaddr: VirtAddr) -> Option<(PhysAddr, usize)> {
+        let collapsed = self.collapsed.lock().unwrap();
+        let region = collapsed.iter().find(|region| region.contains(vaddr))?;
+        Some((PhysAddr::from(region.start.as_usize()), region.size()))
+    }
+
+    fn poll_fault_ready(
+        &self,
+        _vaddr: VirtAddr,
+        _access_flags: u8,
+        cx: &mut Context<'_>,
+    ) -> Poll<()> {
+        let pending = self
+            .pending_faults
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+        if pending.is_ok() {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        Poll::Ready(())
+    }
+
+    /// All the mappings are charged, as if they were anonymous memory.
+    fn charges_commit(&self, _flags: u8) -> bool {
+        true
+    }
+}
+
+/// Maps `addr` with `flags`, failing if it is already mapped.
+fn map_entry(pt: &mut TestPageTable, addr: usize, flags: u8) -> bool {
+    match pt.get_mut(addr) {
+        Some(entry) if *entry == 0 => {
+            *entry = flags;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Updates the entry of `addr`, failing if it is not mapped.
+fn update_entry(pt: &mut TestPageTable, addr: usize, f: impl FnOnce(&mut u8)) -> bool {
+    match pt.get_mut(addr) {
+        Some(entry) if *entry != 0 => {
+            f(entry);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The layout of an area compared by [`assert_layout_eq`]: its range, flags
+/// and kinds of backend.
+type AreaLayout = (usize, usize, String, &'static str, MappingKind);
+
+fn layout<B: MappingBackend>(set: &MemorySet<B>) -> Vec<AreaLayout> {
+    set.iter()
+        .map(|area| {
+            let backend = area.backend();
+            (
+                area.start().into(),
+                area.end().into(),
+                area.flags().to_string(),
+                backend.kind(),
+                backend.mapping_kind(),
+            )
+        })
+        .collect()
+}
+
+/// Asserts that `a` and `b` have the same layout: the same areas, with the
+/// same ranges, flags and kinds of backends, whatever their frames.
+///
+/// # Panics
+///
+/// Panics with the first area that differs.
+pub fn assert_layout_eq<B: MappingBackend>(a: &MemorySet<B>, b: &MemorySet<B>) {
+    let (a, b) = (layout(a), layout(b));
+    for (i, (left, right)) in a.iter().zip(&b).enumerate() {
+        assert_eq!(left, right, "area {i} differs");
+    }
+    assert_eq!(a.len(), b.len(), "numbers of areas differ");
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Checks the invariants of the set: the areas are non-empty, keyed by
+    /// their start addresses in ascending order, linked to their neighbors
+    /// and do not overlap, and their frames and swapped-out pages lie within
+    /// them.
+    ///
+    /// # Panics
+    ///
+    /// Panics with the first invariant found broken.
+    pub fn check_invariants(&self) {
+        assert!(
+            self.areas.links_consistent(),
+            "areas not linked in address order"
+        );
+        let mut prev_end: Option<usize> = None;
+        for (&key, area) in self.areas.iter() {
+            let (start, end): (usize, usize) = (area.start().into(), area.end().into());
+            assert_eq!(
+                key.into(),
+                start,
+                "area [{start:#x}, {end:#x}) keyed at another address"
+            );
+            assert!(start < end, "empty area at {start:#x}");
+            let reserved = area.reserved_range();
+            if let Some(prev_end) = prev_end {
+                assert!(
+                    prev_end <= reserved.start.into(),
+                    "area [{start:#x}, {end:#x}) overlaps the previous one reserved up to {prev_end:#x}"
+                );
+            }
+            prev_end = Some(reserved.end.into());
+            #[cfg(feature = "RAII")]
+            {
+                for (&page, _) in area.frames.iter() {
+                    let page_start: usize = page.into();
+                    let page_end = page_start + area.frames.frame_size(&page);
+                    assert!(
+                        start <= page_start && page_end <= end,
+                        "frame at {page_start:#x} out of the area [{start:#x}, {end:#x})"
+                    );
+                }
+                for &page in area.swapped.keys() {
+                    let page: usize = page.into();
+                    assert!(
+                        start <= page && page < end,
+                        "swapped page at {page:#x} out of the area [{start:#x}, {end:#x})"
+                    );
+                }
+            }
+        }
+    }
+}
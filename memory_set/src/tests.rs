@@ -1,57 +1,26 @@
-use memory_addr::{MemoryAddr, VirtAddr, va_range};
+use memory_addr::{MemoryAddr, VirtAddr, addr_range, va_range};
 
-use crate::{MappingBackend, MappingError, MemoryArea, MemorySet};
+use crate::test_utils::{Op, TestBackend, test_page_table};
+use crate::{
+    AreaTimes, GapMode, MapMode, MapObserver, MappingError, MemoryArea, MemorySet,
+    PlacementStrategy, Protected, TlbBatch,
+};
 
 const MAX_ADDR: usize = 0x10000;
 
 type MockFlags = u8;
-type MockPageTable = [MockFlags; MAX_ADDR];
-
-#[derive(Clone)]
-struct MockBackend;
-
+type MockBackend = TestBackend;
 type MockMemorySet = MemorySet<MockBackend>;
 
-impl MappingBackend for MockBackend {
-    type Addr = VirtAddr;
-    type Flags = MockFlags;
-    type PageTable = MockPageTable;
-
-    fn map(&self, start: VirtAddr, size: usize, flags: MockFlags, pt: &mut MockPageTable) -> bool {
-        for entry in pt.iter_mut().skip(start.as_usize()).take(size) {
-            if *entry != 0 {
-                return false;
-            }
-            *entry = flags;
-        }
-        true
-    }
-
-    fn unmap(&self, start: VirtAddr, size: usize, pt: &mut MockPageTable) -> bool {
-        for entry in pt.iter_mut().skip(start.as_usize()).take(size) {
-            if *entry == 0 {
-                return false;
-            }
-            *entry = 0;
-        }
-        true
-    }
-
-    fn protect(
-        &self,
-        start: VirtAddr,
-        size: usize,
-        new_flags: MockFlags,
-        pt: &mut MockPageTable,
-    ) -> bool {
-        for entry in pt.iter_mut().skip(start.as_usize()).take(size) {
-            if *entry == 0 {
-                return false;
-            }
-            *entry = new_flags;
-        }
-        true
-    }
+fn new_area(start: VirtAddr, size: usize, flags: MockFlags) -> MemoryArea<MockBackend> {
+    MemoryArea::new(
+        start,
+        size,
+        #[cfg(feature = "RAII")]
+        None,
+        flags,
+        MockBackend::new(),
+    )
 }
 
 macro_rules! assert_ok {
@@ -65,7 +34,10 @@ macro_rules! assert_err {
         assert!(($expr).is_err())
     };
     ($expr: expr, $err: ident) => {
-        assert_eq!(($expr).err(), Some(MappingError::$err))
+        match ($expr).err() {
+            Some(MappingError::$err(..)) => {}
+            err => panic!("expected {}, got {:?}", stringify!($err), err),
+        }
     };
 }
 
@@ -83,30 +55,20 @@ fn dump_memory_set(set: &MockMemorySet) {
 #[test]
 fn test_map_unmap() {
     let mut set = MockMemorySet::new();
-    let mut pt = [0; MAX_ADDR];
+    let mut pt = test_page_table(MAX_ADDR);
 
     // Map [0, 0x1000), [0x2000, 0x3000), [0x4000, 0x5000), ...
     for start in (0..MAX_ADDR).step_by(0x2000) {
-        assert_ok!(set.map(
-            MemoryArea::new(start.into(), 0x1000, 1, MockBackend),
-            &mut pt,
-            false,
-            None
-        ));
+        assert_ok!(set.map(new_area(start.into(), 0x1000, 1), &mut pt, false, None));
     }
     // Map [0x1000, 0x2000), [0x3000, 0x4000), [0x5000, 0x6000), ...
     for start in (0x1000..MAX_ADDR).step_by(0x2000) {
-        assert_ok!(set.map(
-            MemoryArea::new(start.into(), 0x1000, 2, MockBackend),
-            &mut pt,
-            false,
-            None
-        ));
+        assert_ok!(set.map(new_area(start.into(), 0x1000, 2), &mut pt, false, None));
     }
     dump_memory_set(&set);
     assert_eq!(set.len(), 16);
-    for addr in 0..MAX_ADDR {
-        assert!(pt[addr] == 1 || pt[addr] == 2);
+    for &entry in pt.iter() {
+        assert!(entry == 1 || entry == 2);
     }
 
     // Found [0x4000, 0x5000), flags = 1.
@@ -118,21 +80,11 @@ fn test_map_unmap() {
 
     // The area [0x4000, 0x8000) is already mapped, map returns an error.
     assert_err!(
-        set.map(
-            MemoryArea::new(0x4000.into(), 0x4000, 3, MockBackend),
-            &mut pt,
-            false,
-            None
-        ),
+        set.map(new_area(0x4000.into(), 0x4000, 3), &mut pt, false, None),
         AlreadyExists
     );
     // Unmap overlapped areas before adding the new mapping [0x4000, 0x8000).
-    assert_ok!(set.map(
-        MemoryArea::new(0x4000.into(), 0x4000, 3, MockBackend),
-        &mut pt,
-        true,
-        None
-    ));
+    assert_ok!(set.map(new_area(0x4000.into(), 0x4000, 3), &mut pt, true, None));
     dump_memory_set(&set);
     assert_eq!(set.len(), 13);
 
@@ -141,8 +93,8 @@ fn test_map_unmap() {
     assert_eq!(area.start(), 0x4000.into());
     assert_eq!(area.end(), 0x8000.into());
     assert_eq!(area.flags(), 3);
-    for addr in 0x4000..0x8000 {
-        assert_eq!(pt[addr], 3);
+    for &entry in &pt[0x4000..0x8000] {
+        assert_eq!(entry, 3);
     }
 
     // Unmap areas in the middle.
@@ -151,24 +103,19 @@ fn test_map_unmap() {
     // Unmap the remaining areas, including the unmapped ranges.
     assert_ok!(set.unmap(0.into(), MAX_ADDR * 2, &mut pt));
     assert_eq!(set.len(), 0);
-    for addr in 0..MAX_ADDR {
-        assert_eq!(pt[addr], 0);
+    for &entry in pt.iter() {
+        assert_eq!(entry, 0);
     }
 }
 
 #[test]
 fn test_unmap_split() {
     let mut set = MockMemorySet::new();
-    let mut pt = [0; MAX_ADDR];
+    let mut pt = test_page_table(MAX_ADDR);
 
     // Map [0, 0x1000), [0x2000, 0x3000), [0x4000, 0x5000), ...
     for start in (0..MAX_ADDR).step_by(0x2000) {
-        assert_ok!(set.map(
-            MemoryArea::new(start.into(), 0x1000, 1, MockBackend),
-            &mut pt,
-            false,
-            None
-        ));
+        assert_ok!(set.map(new_area(start.into(), 0x1000, 1), &mut pt, false, None));
     }
     assert_eq!(set.len(), 8);
 
@@ -188,8 +135,8 @@ fn test_unmap_split() {
             assert_eq!(area.end().align_offset_4k(), 0xc00);
             assert_eq!(area.size(), 0x800);
         }
-        for addr in area.start().as_usize()..area.end().as_usize() {
-            assert_eq!(pt[addr], 1);
+        for &entry in &pt[area.start().as_usize()..area.end().as_usize()] {
+            assert_eq!(entry, 1);
         }
     }
 
@@ -212,15 +159,15 @@ fn test_unmap_split() {
         } else {
             unreachable!();
         }
-        for addr in area.start().as_usize()..area.end().as_usize() {
-            assert_eq!(pt[addr], 1);
+        for &entry in &pt[area.start().as_usize()..area.end().as_usize()] {
+            assert_eq!(entry, 1);
         }
     }
     let mut iter = set.iter();
     while let Some(area) = iter.next() {
         if let Some(next) = iter.next() {
-            for addr in area.end().as_usize()..next.start().as_usize() {
-                assert_eq!(pt[addr], 0);
+            for &entry in &pt[area.end().as_usize()..next.start().as_usize()] {
+                assert_eq!(entry, 0);
             }
         }
     }
@@ -229,15 +176,15 @@ fn test_unmap_split() {
     // Unmap all areas.
     assert_ok!(set.unmap(0.into(), MAX_ADDR, &mut pt));
     assert_eq!(set.len(), 0);
-    for addr in 0..MAX_ADDR {
-        assert_eq!(pt[addr], 0);
+    for &entry in pt.iter() {
+        assert_eq!(entry, 0);
     }
 }
 
 #[test]
 fn test_protect() {
     let mut set = MockMemorySet::new();
-    let mut pt = [0; MAX_ADDR];
+    let mut pt = test_page_table(MAX_ADDR);
     let update_flags = |new_flags: MockFlags| {
         move |old_flags: MockFlags| -> Option<MockFlags> {
             if (old_flags & 0x7) == (new_flags & 0x7) {
@@ -250,19 +197,24 @@ fn test_protect() {
 
     // Map [0, 0x1000), [0x2000, 0x3000), [0x4000, 0x5000), ...
     for start in (0..MAX_ADDR).step_by(0x2000) {
-        assert_ok!(set.map(
-            MemoryArea::new(start.into(), 0x1000, 0x7, MockBackend),
-            &mut pt,
-            false,
-            None
-        ));
+        assert_ok!(set.map(new_area(start.into(), 0x1000, 0x7), &mut pt, false, None));
     }
     assert_eq!(set.len(), 8);
 
     // Protect [0xc00, 0x2400), [0x2c00, 0x4400), [0x4c00, 0x6400), ...
     // The areas are split into two areas.
     for start in (0..MAX_ADDR).step_by(0x2000) {
-        assert_ok!(set.protect((start + 0xc00).into(), 0x1800, update_flags(0x1), &mut pt));
+        let changed = set.protect((start + 0xc00).into(), 0x1800, update_flags(0x1), &mut pt);
+        let protected = |start: usize, end: usize| Protected {
+            range: va_range!(start..end),
+            old_flags: 0x7,
+            new_flags: 0x1,
+        };
+        let mut expected = vec![protected(start + 0xc00, start + 0x1000)];
+        if start + 0x2000 < MAX_ADDR {
+            expected.push(protected(start + 0x2000, start + 0x2400));
+        }
+        assert_eq!(changed, Ok(expected));
     }
     dump_memory_set(&set);
     assert_eq!(set.len(), 23);
@@ -321,31 +273,450 @@ fn test_protect() {
 
     // Test skip [0x880, 0x900), [0x2880, 0x2900), [0x4880, 0x4900), ...
     for start in (0..MAX_ADDR).step_by(0x2000) {
-        assert_ok!(set.protect((start + 0x880).into(), 0x80, update_flags(0x3), &mut pt));
+        let changed = set.protect((start + 0x880).into(), 0x80, update_flags(0x3), &mut pt);
+        assert_eq!(changed, Ok(vec![]));
     }
     assert_eq!(set.len(), 39);
 
     // Unmap all areas.
     assert_ok!(set.unmap(0.into(), MAX_ADDR, &mut pt));
     assert_eq!(set.len(), 0);
-    for addr in 0..MAX_ADDR {
-        assert_eq!(pt[addr], 0);
+    for &entry in pt.iter() {
+        assert_eq!(entry, 0);
+    }
+}
+
+#[test]
+fn test_area_starts_in() {
+    use crate::test_utils::Op;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new();
+    for (start, size) in [
+        (0x1000, 0x1000),
+        (0x3000, 0x2000),
+        (0x6000, 0x1000),
+        (0x8000, 0x1000),
+    ] {
+        let area = MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            1,
+            backend.clone(),
+        );
+        assert_ok!(set.map(area, &mut pt, false, None));
+    }
+    let starts = |start: usize, end: usize| {
+        set.area_starts_in(va_range!(start..end))
+            .map(VirtAddr::as_usize)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(starts(0, MAX_ADDR), [0x1000, 0x3000, 0x6000, 0x8000]);
+    // The area crossing the start is included, the one at the end is not.
+    assert_eq!(starts(0x4000, 0x6800), [0x3000, 0x6000]);
+    assert_eq!(starts(0x4fff, 0x5000), [0x3000]);
+    assert_eq!(starts(0x4000, 0x8000), [0x3000, 0x6000]);
+    assert!(starts(0x5000, 0x6000).is_empty());
+    assert!(starts(0x2000, 0x3000).is_empty());
+    assert!(starts(0x3000, 0x3000).is_empty());
+    assert!(starts(0x4000, 0x4000).is_empty());
+    assert!(starts(0x9000, MAX_ADDR).is_empty());
+    assert_eq!(
+        set.area_starts_in(va_range!(0x4000..0x8001))
+            .rev()
+            .map(VirtAddr::as_usize)
+            .collect::<Vec<_>>(),
+        [0x8000, 0x6000, 0x3000]
+    );
+
+    // Protecting a range only touches the areas overlapping with it.
+    let calls = backend.calls(Op::Protect);
+    let changed = set
+        .protect(0x4000.into(), 0x2800, |_| Some(2), &mut pt)
+        .unwrap();
+    assert_eq!(changed.len(), 2);
+    assert_eq!(backend.calls(Op::Protect), calls + 2);
+    assert_eq!(pt[0x3fff], 1);
+    assert!(pt[0x4000..0x5000].iter().all(|&flags| flags == 2));
+    assert!(pt[0x6000..0x6800].iter().all(|&flags| flags == 2));
+    assert_eq!(pt[0x6800], 1);
+    set.check_invariants();
+}
+
+#[test]
+fn test_iter_range() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    for (start, size) in [(0x1000, 0x1000), (0x3000, 0x2000), (0x6000, 0x1000)] {
+        assert_ok!(set.map(new_area(start.into(), size, 1), &mut pt, false, None));
+    }
+    let starts = |set: &MockMemorySet, start: usize, end: usize| {
+        set.iter_range(va_range!(start..end))
+            .map(|area| area.start().as_usize())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(starts(&set, 0, MAX_ADDR), [0x1000, 0x3000, 0x6000]);
+    assert_eq!(starts(&set, 0x4fff, 0x6001), [0x3000, 0x6000]);
+    assert!(starts(&set, 0x5000, 0x6000).is_empty());
+    assert!(starts(&set, 0x4000, 0x4000).is_empty());
+    assert_eq!(
+        set.iter_range(va_range!(0x1800..0x3001))
+            .rev()
+            .map(|area| area.start().as_usize())
+            .collect::<Vec<_>>(),
+        [0x3000, 0x1000]
+    );
+
+    // The mutable iterator visits the same areas, including the one crossing
+    // the start.
+    for (start, end) in [
+        (0, MAX_ADDR),
+        (0x4fff, 0x6001),
+        (0x5000, 0x6000),
+        (0x4000, 0x4000),
+    ] {
+        let range = va_range!(start..end);
+        let expected = starts(&set, start, end);
+        let visited = set
+            .iter_range_mut(range)
+            .map(|area| area.start().as_usize())
+            .collect::<Vec<_>>();
+        assert_eq!(visited, expected);
+    }
+    for area in set.iter_range_mut(va_range!(0x4000..0x6800)) {
+        area.set_label(Some("touched".into()));
+    }
+    let labels = set.iter().map(|area| area.label()).collect::<Vec<_>>();
+    assert_eq!(labels, [None, Some("touched"), Some("touched")]);
+    assert_eq!(
+        set.iter_range_mut(va_range!(0..MAX_ADDR))
+            .next_back()
+            .map(|area| area.start().as_usize()),
+        Some(0x6000)
+    );
+    set.check_invariants();
+}
+
+#[test]
+fn test_find_with_neighbors() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let starts = |set: &MockMemorySet, addr: usize| {
+        let (prev, area, next) = set.find_with_neighbors(addr.into());
+        [prev, area, next].map(|area| area.map(|area| area.start().as_usize()))
+    };
+    assert_eq!(starts(&set, 0x1000), [None, None, None]);
+    for start in [0x5000, 0x1000, 0x3000, 0x7000] {
+        assert_ok!(set.map(new_area(start.into(), 0x1000, 1), &mut pt, false, None));
+    }
+    assert_eq!(starts(&set, 0), [None, None, Some(0x1000)]);
+    assert_eq!(starts(&set, 0x1000), [None, Some(0x1000), Some(0x3000)]);
+    assert_eq!(starts(&set, 0x2000), [Some(0x1000), None, Some(0x3000)]);
+    assert_eq!(
+        starts(&set, 0x3fff),
+        [Some(0x1000), Some(0x3000), Some(0x5000)]
+    );
+    assert_eq!(starts(&set, 0x7800), [Some(0x5000), Some(0x7000), None]);
+    assert_eq!(starts(&set, 0x9000), [Some(0x7000), None, None]);
+
+    // The links follow unmapping, splitting and splitting off.
+    assert_ok!(set.unmap(0x3000.into(), 0x1000, &mut pt));
+    assert_eq!(
+        starts(&set, 0x5000),
+        [Some(0x1000), Some(0x5000), Some(0x7000)]
+    );
+    assert_ok!(set.protect(0x5400.into(), 0x400, |_| Some(2), &mut pt));
+    assert_eq!(
+        starts(&set, 0x5400),
+        [Some(0x5000), Some(0x5400), Some(0x5800)]
+    );
+    assert_eq!(
+        starts(&set, 0x5800),
+        [Some(0x5400), Some(0x5800), Some(0x7000)]
+    );
+    set.check_invariants();
+    let right = set.split_off(0x5800.into()).unwrap();
+    assert_eq!(starts(&set, 0x5400), [Some(0x5000), Some(0x5400), None]);
+    assert_eq!(starts(&right, 0x5800), [None, Some(0x5800), Some(0x7000)]);
+    set.check_invariants();
+    right.check_invariants();
+
+    // Removing everything and mapping again reuses the links.
+    assert_ok!(set.unmap(0.into(), MAX_ADDR, &mut pt));
+    assert_eq!(starts(&set, 0x1000), [None, None, None]);
+    assert_ok!(set.map(new_area(0x2000.into(), 0x1000, 1), &mut pt, false, None));
+    assert_eq!(starts(&set, 0x1000), [None, None, Some(0x2000)]);
+    assert_eq!(starts(&set, 0x3000), [Some(0x2000), None, None]);
+    set.check_invariants();
+}
+
+#[test]
+fn test_cursor_mut() {
+    let backend = MockBackend::new().with_granularity(0x1000).with_merging();
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let area = |start: usize, size, flags| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            flags,
+            backend.clone(),
+        )
+    };
+    assert_ok!(set.map(area(0x1000, 0x3000, 1), &mut pt, false, None));
+    assert_ok!(set.map(area(0x6000, 0x1000, 2), &mut pt, false, None));
+    assert_ok!(set.map(area(0x7000, 0x1000, 3), &mut pt, false, None));
+    let start = |area: Option<&MemoryArea<MockBackend>>| area.map(|area| area.start().as_usize());
+
+    // A cursor in a gap points to the next area, or past the last one.
+    let cursor = set.cursor_mut(0x5000.into());
+    assert_eq!(start(cursor.current()), Some(0x6000));
+    assert_eq!(start(cursor.peek_prev()), Some(0x1000));
+    assert_eq!(start(cursor.peek_next()), Some(0x7000));
+    let mut cursor = set.cursor_mut(0x8000.into());
+    assert!(cursor.current().is_none());
+    assert!(cursor.peek_next().is_none());
+    assert_eq!(start(cursor.peek_prev()), Some(0x7000));
+    cursor.move_next();
+    assert!(cursor.current().is_none());
+    assert!(cursor.move_prev());
+    assert_eq!(start(cursor.current()), Some(0x7000));
+
+    // Walking stops at both ends.
+    let mut cursor = set.cursor_front_mut();
+    assert!(!cursor.move_prev());
+    assert_eq!(start(cursor.current()), Some(0x1000));
+    let mut walked = Vec::new();
+    while let Some(area) = cursor.current() {
+        walked.push(area.start().as_usize());
+        cursor.move_next();
+    }
+    assert_eq!(walked, [0x1000, 0x6000, 0x7000]);
+    assert!(cursor.split(0x7800.into()).is_err());
+    assert!(!cursor.merge_next());
+    assert_err!(cursor.remove_current(&mut pt), InvalidParam);
+
+    // Splits must be strictly inside the area and aligned.
+    let mut cursor = set.cursor_mut(0x2000.into());
+    for pos in [0x1000, 0x4000, 0x5000, 0x2800] {
+        assert_err!(cursor.split(pos.into()), InvalidParam);
+    }
+    // The cursor stays at the left part.
+    assert_ok!(cursor.split(0x3000.into()));
+    assert_ok!(cursor.split(0x2000.into()));
+    assert_eq!(start(cursor.current()), Some(0x1000));
+    assert_eq!(cursor.current().unwrap().size(), 0x1000);
+    assert_eq!(start(cursor.peek_next()), Some(0x2000));
+    cursor.move_next();
+    cursor.move_next();
+    assert_eq!(start(cursor.current()), Some(0x3000));
+    assert_eq!(start(cursor.peek_prev()), Some(0x2000));
+    set.check_invariants();
+    assert_eq!(set.len(), 5);
+
+    // Only adjacent compatible areas merge.
+    let mut cursor = set.cursor_front_mut();
+    assert!(cursor.merge_next());
+    assert!(cursor.merge_next());
+    assert_eq!(
+        cursor.current().unwrap().va_range(),
+        va_range!(0x1000..0x4000)
+    );
+    assert!(!cursor.merge_next());
+    cursor.move_next();
+    assert!(!cursor.merge_next());
+    assert_eq!(set.len(), 3);
+    set.check_invariants();
+
+    // Removing moves to the next area. A sealed area or one failing to unmap
+    // stays, with the cursor at it.
+    assert_ok!(set.seal(0x7000.into(), 0x1000));
+    let mut cursor = set.cursor_mut(0x6000.into());
+    backend.fail_at(Op::Unmap, 1);
+    assert_err!(cursor.remove_current(&mut pt), BadState);
+    assert_eq!(start(cursor.current()), Some(0x6000));
+    assert_ok!(cursor.remove_current(&mut pt));
+    assert_eq!(start(cursor.current()), Some(0x7000));
+    assert_err!(cursor.remove_current(&mut pt), PermissionDenied);
+    assert_eq!(start(cursor.current()), Some(0x7000));
+    assert!(cursor.move_prev());
+    assert_ok!(cursor.remove_current(&mut pt));
+    assert_eq!(start(cursor.current()), Some(0x7000));
+    assert!(!cursor.move_prev());
+    assert_eq!(
+        set.iter().map(|area| area.va_range()).collect::<Vec<_>>(),
+        [va_range!(0x7000..0x8000)]
+    );
+    assert!(pt[..0x7000].iter().all(|&flags| flags == 0));
+    assert_eq!(
+        set.find_free_area(0.into(), 0x7000, va_range!(0..MAX_ADDR)),
+        Some(0.into())
+    );
+    set.check_invariants();
+}
+
+#[test]
+fn test_map_mode() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let area = |start: usize, size, flags| new_area(start.into(), size, flags);
+    let ranges = |set: &MockMemorySet| {
+        set.iter()
+            .map(|area| (area.va_range(), area.flags()))
+            .collect::<Vec<_>>()
+    };
+    assert_ok!(set.map_with_mode(area(0x2000, 0x2000, 1), &mut pt, MapMode::NoReplace, None));
+    assert_ok!(set.map_with_mode(
+        area(0x8000, 0x1000, 2).with_guards(0x1000, 0),
+        &mut pt,
+        MapMode::NoReplace,
+        None
+    ));
+
+    // Without replacing, overlapping an area or its guards fails.
+    for (start, size) in [(0x1000, 0x2000), (0x3000, 0x1000), (0x7000, 0x1000)] {
+        assert_err!(
+            set.map_with_mode(area(start, size, 3), &mut pt, MapMode::NoReplace, None),
+            AlreadyExists
+        );
+    }
+    // So does a new guard region overlapping an area.
+    assert_err!(
+        set.map_with_mode(
+            area(0x4000, 0x1000, 3).with_guards(0x1000, 0),
+            &mut pt,
+            MapMode::NoReplace,
+            None
+        ),
+        AlreadyExists
+    );
+
+    // Fixed replaces the areas, but not the guard regions.
+    assert_err!(
+        set.map_with_mode(area(0x6000, 0x2000, 3), &mut pt, MapMode::Fixed, None),
+        AlreadyExists
+    );
+    assert_ok!(set.map_with_mode(area(0x3000, 0x2000, 3), &mut pt, MapMode::Fixed, None));
+    assert_eq!(
+        ranges(&set),
+        [
+            (va_range!(0x2000..0x3000), 1),
+            (va_range!(0x3000..0x5000), 3),
+            (va_range!(0x8000..0x9000), 2),
+        ]
+    );
+    assert!(pt[0x3000..0x5000].iter().all(|&flags| flags == 3));
+
+    // FixedNoReplace checks the alignment first, then the limit including the
+    // guards, and then the overlaps.
+    let fixed_no_replace = |limit, align| MapMode::FixedNoReplace { limit, align };
+    let full = fixed_no_replace(va_range!(0..MAX_ADDR), 0x2000);
+    assert_err!(
+        set.map_with_mode(area(0x3000, 0x1000, 4), &mut pt, full, None),
+        Misaligned
+    );
+    assert_err!(
+        set.map_with_mode(area(0x2000, 0x1000, 4), &mut pt, full, None),
+        AlreadyExists
+    );
+    let limited = fixed_no_replace(va_range!(0xa000..0xc000), 0x1000);
+    for area in [
+        area(0x9000, 0x2000, 4),
+        area(0xb000, 0x2000, 4),
+        area(0xa000, 0x1000, 4).with_guards(0x1000, 0),
+        area(0xb000, 0x1000, 4).with_guards(0, 0x1000),
+    ] {
+        assert_err!(set.map_with_mode(area, &mut pt, limited, None), OutOfRange);
     }
+    assert_err!(
+        set.map_with_mode(area(0x1000, 0x1000, 4), &mut pt, limited, None),
+        OutOfRange
+    );
+    assert_ok!(set.map_with_mode(area(0xa000, 0x2000, 4), &mut pt, limited, Some(5)));
+    assert_eq!(
+        set.find(0xa000.into()).unwrap().va_range(),
+        va_range!(0xa000..0xc000)
+    );
+    assert!(pt[0xa000..0xc000].iter().all(|&flags| flags == 5));
+
+    // Sealed areas are not replaced.
+    assert_ok!(set.seal(0x2000.into(), 0x1000));
+    assert_err!(
+        set.map_with_mode(area(0x2000, 0x2000, 6), &mut pt, MapMode::Fixed, None),
+        PermissionDenied
+    );
+    assert_eq!(ranges(&set).len(), 4);
+    set.check_invariants();
+}
+
+#[test]
+fn test_granularity() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_granularity(0x4000);
+    let area = |start: usize, size| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            1,
+            backend.clone(),
+        )
+    };
+
+    // Areas must start and end at multiples of the granularity.
+    assert_err!(
+        set.map(area(0x2000, 0x4000), &mut pt, false, None),
+        InvalidParam
+    );
+    assert_err!(
+        set.map(area(0x4000, 0x2000), &mut pt, false, None),
+        InvalidParam
+    );
+    assert_err!(set.insert(area(0x4000, 0x5000), false), InvalidParam);
+    assert!(set.is_empty());
+    assert_ok!(set.map(area(0x4000, 0x8000), &mut pt, false, None));
+    assert_eq!(set.find(0x4000.into()).unwrap().granularity(), 0x4000);
+
+    // So must the start of the ranges within them, while the end is rounded
+    // up like the length of `munmap`.
+    assert_err!(set.unmap(0x5000.into(), 0x3000, &mut pt), InvalidParam);
+    assert_err!(
+        set.protect(0x6000.into(), 0x2000, |_| Some(3), &mut pt),
+        InvalidParam
+    );
+    assert_ok!(set.protect(0x8000.into(), 0x1000, |_| Some(3), &mut pt));
+    assert_eq!(
+        set.find(0x8000.into()).unwrap().va_range(),
+        va_range!(0x8000..0xc000)
+    );
+    assert_ok!(set.unmap(0x4000.into(), 0x1, &mut pt));
+    assert!(set.find(0x7fff.into()).is_none());
+    assert!(pt[0x4000..0x8000].iter().all(|&flags| flags == 0));
+    assert!(pt[0x8000..0xc000].iter().all(|&flags| flags == 3));
+
+    // Unmapped starts only need the granularity of the backend type, and the
+    // end is not rounded if it is not mapped either.
+    assert_ok!(set.unmap(0x3fff.into(), 0x2, &mut pt));
+    assert_eq!(set.total_size(), 0x4000);
+    assert_ok!(set.unmap(0x7000.into(), 0x5000, &mut pt));
+    assert!(set.is_empty());
 }
 
 #[test]
 fn test_find_free_area() {
     let mut set = MockMemorySet::new();
-    let mut pt = [0; MAX_ADDR];
+    let mut pt = test_page_table(MAX_ADDR);
 
     // Map [0, 0x1000), [0x2000, 0x3000), ..., [0xe000, 0xf000)
     for start in (0..MAX_ADDR).step_by(0x2000) {
-        assert_ok!(set.map(
-            MemoryArea::new(start.into(), 0x1000, 1, MockBackend),
-            &mut pt,
-            false,
-            None
-        ));
+        assert_ok!(set.map(new_area(start.into(), 0x1000, 1), &mut pt, false, None));
     }
 
     let addr = set.find_free_area(0.into(), 0x1000, va_range!(0..MAX_ADDR));
@@ -369,3 +740,6808 @@ fn test_find_free_area() {
     let addr = set.find_free_area(0xf001.into(), 0x1000, va_range!(0..MAX_ADDR));
     assert_eq!(addr, None);
 }
+
+#[test]
+fn test_find_free_area_topdown() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let topdown = |set: &MockMemorySet, hint: usize, size, limit| {
+        set.find_free_area_topdown(hint.into(), size, limit)
+            .map(|addr| addr.as_usize())
+    };
+    let all = va_range!(0..MAX_ADDR);
+    assert_eq!(topdown(&set, 0x5000, 0x1000, all), Some(0x4000));
+    assert_eq!(topdown(&set, 0, 0x1000, all), None);
+
+    // Free: [0, 0x1000), [0x3000, 0x6000), [0x8000, 0xa000), [0xb000, ..).
+    let areas = [
+        new_area(0x2000.into(), 0x1000, 1).with_guards(0x1000, 0),
+        new_area(0x6000.into(), 0x1000, 1).with_guards(0, 0x1000),
+        new_area(0xa000.into(), 0x1000, 1),
+    ];
+    for area in areas {
+        assert_ok!(set.map(area, &mut pt, false, None));
+    }
+
+    // At the top of the highest gap ending at or below the hint, skipping
+    // the areas and guards the hint falls in.
+    assert_eq!(topdown(&set, MAX_ADDR, 0x1000, all), Some(0xf000));
+    assert_eq!(topdown(&set, 0xa800, 0x1000, all), Some(0x9000));
+    assert_eq!(topdown(&set, 0x7800, 0x1000, all), Some(0x5000));
+    assert_eq!(topdown(&set, 0x5800, 0x1000, all), Some(0x4800));
+    assert_eq!(topdown(&set, 0x1000, 0x1000, all), Some(0));
+    // Exact fits, and gaps made too small by the hint.
+    assert_eq!(topdown(&set, 0x5800, 0x2800, all), Some(0x3000));
+    assert_eq!(topdown(&set, 0x5800, 0x3000, all), None);
+    assert_eq!(topdown(&set, 0xa000, 0x3000, all), Some(0x3000));
+
+    // The limit clips the gaps at both ends.
+    assert_eq!(
+        topdown(&set, MAX_ADDR, 0x2000, va_range!(0..0x9800)),
+        Some(0x4000)
+    );
+    assert_eq!(
+        topdown(&set, 0x5000, 0x1000, va_range!(0x3800..MAX_ADDR)),
+        Some(0x4000)
+    );
+    assert_eq!(
+        topdown(&set, 0x4800, 0x1000, va_range!(0x4000..MAX_ADDR)),
+        None
+    );
+}
+
+#[test]
+fn test_find_free_area_constrained() {
+    use crate::FreeAreaConstraint;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0x1000.into(), 0x1000, 1), &mut pt, false, None));
+    assert_ok!(set.map(new_area(0x5000.into(), 0x1000, 1), &mut pt, false, None));
+    let find = |size, limit, constraint: FreeAreaConstraint<VirtAddr>| {
+        set.find_free_area_constrained(0.into(), size, limit, &constraint)
+    };
+
+    // The masked bits of the start must match, here bits 12 and 13, like a
+    // cache color. Matching addresses may need carries into higher bits.
+    let color = |value| FreeAreaConstraint::new().with_mask(0x3000, value);
+    assert_eq!(
+        find(0x1000, va_range!(0..MAX_ADDR), color(0x1000)),
+        Some(0x9000.into())
+    );
+    assert_eq!(
+        find(0x1000, va_range!(0..MAX_ADDR), color(0x3000)),
+        Some(0x3000.into())
+    );
+    assert_eq!(find(0x1000, va_range!(0..0x9fff), color(0x1000)), None);
+    // Unmasked bits of the value are ignored.
+    assert!(color(0x1234).matches(0x1000.into()));
+    assert!(!color(0x1234).matches(0x2000.into()));
+
+    // The end may be bounded below the limit, e.g., for 32-bit DMA.
+    let below = FreeAreaConstraint::new().with_max_end(0x5000.into());
+    assert_eq!(
+        find(0x3000, va_range!(0..MAX_ADDR), below),
+        Some(0x2000.into())
+    );
+    assert_eq!(find(0x3001, va_range!(0..MAX_ADDR), below), None);
+    assert_eq!(
+        find(
+            0x1000,
+            va_range!(0..MAX_ADDR),
+            color(0x1000).with_max_end(0x9000.into())
+        ),
+        None
+    );
+
+    // Alignment is a mask with a zero value.
+    let aligned = |hint: usize, size, align| {
+        set.find_free_area_aligned(hint.into(), size, va_range!(0..MAX_ADDR), align)
+    };
+    assert_eq!(aligned(0, 0x1000, 0x4000), Some(0.into()));
+    assert_eq!(aligned(0x1000, 0x1000, 0x4000), Some(0x4000.into()));
+    assert_eq!(aligned(0x1000, 0x2000, 0x4000), Some(0x8000.into()));
+}
+
+#[test]
+fn test_find_free_area_aligned() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    // Free: [0, 0x1000), [0x4000, 0x9000), [0xa000, ..).
+    let area = new_area(0x1000.into(), 0x2000, 1).with_guards(0, 0x1000);
+    assert_ok!(set.map(area, &mut pt, false, None));
+    assert_ok!(set.map(new_area(0x9000.into(), 0x1000, 1), &mut pt, false, None));
+    let aligned = |hint: usize, size, limit, align| {
+        set.find_free_area_aligned(hint.into(), size, limit, align)
+            .map(|addr| addr.as_usize())
+    };
+    let all = va_range!(0..MAX_ADDR);
+
+    // The lowest aligned start in the first gap it fits in, never in a guard.
+    assert_eq!(aligned(0, 0x1000, all, 0x4000), Some(0));
+    assert_eq!(aligned(0x800, 0x1000, all, 0x4000), Some(0x4000));
+    assert_eq!(aligned(0x800, 0x1000, all, 0x1000), Some(0x4000));
+    assert_eq!(aligned(0x4800, 0x1000, all, 0x2000), Some(0x6000));
+    // An alignment of 1 is no constraint.
+    assert_eq!(aligned(0x800, 0x800, all, 1), Some(0x800));
+
+    // Gaps that fit only unaligned are skipped, up to the limit.
+    assert_eq!(aligned(0x4000, 0x4000, all, 0x8000), None);
+    assert_eq!(
+        aligned(0x4000, 0x4000, va_range!(0..usize::MAX), 0x8000),
+        Some(0x10000)
+    );
+
+    // Aligned starts past the end of the address space are not found.
+    let top = 1 << (usize::BITS - 1);
+    let unlimited = va_range!(0..usize::MAX);
+    assert_eq!(aligned(0xb000, 0x1000, unlimited, top), Some(top));
+    assert_eq!(aligned(top + 1, 0x1000, unlimited, top), None);
+}
+
+#[test]
+fn test_find_free_area_nearest() {
+    use crate::NearestFit;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    for (start, size) in [(0x2000, 0x1000), (0x4000, 0x2000), (0x9000, 0x1000)] {
+        assert_ok!(set.map(new_area(start.into(), size, 1), &mut pt, false, None));
+    }
+    let nearest = |hint: usize, size, limit| set.find_free_area_nearest(hint.into(), size, limit);
+
+    // At the hint if it fits, otherwise in the closest gap on either side.
+    assert_eq!(
+        nearest(0x1000, 0x1000, va_range!(0..MAX_ADDR)),
+        Some(0x1000.into())
+    );
+    assert_eq!(
+        nearest(0x4400, 0x1000, va_range!(0..MAX_ADDR)),
+        Some(0x3000.into())
+    );
+    assert_eq!(
+        nearest(0x5800, 0x1000, va_range!(0..MAX_ADDR)),
+        Some(0x6000.into())
+    );
+    // Ties go upward.
+    assert_eq!(
+        nearest(0x4800, 0x1000, va_range!(0..MAX_ADDR)),
+        Some(0x6000.into())
+    );
+    assert_eq!(
+        nearest(0x9800, 0x2000, va_range!(0..MAX_ADDR)),
+        Some(0xa000.into())
+    );
+    assert_eq!(
+        nearest(0x9800, 0x2000, va_range!(0..0xb000)),
+        Some(0x7000.into())
+    );
+    assert_eq!(nearest(0x9800, 0x8000, va_range!(0..0xb000)), None);
+
+    let addr = set.find_free_area_with(
+        &mut NearestFit,
+        0x4400.into(),
+        0x1000,
+        va_range!(0..MAX_ADDR),
+    );
+    assert_eq!(addr, Some(0x3000.into()));
+}
+
+#[test]
+fn test_find_free_area_randomized() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let area = new_area(0x1000.into(), 0x1000, 1).with_guards(0, 0x1000);
+    assert_ok!(set.map(area, &mut pt, false, None));
+    assert_ok!(set.add_hole(new_area(0x5000.into(), 0x1000, 1)));
+    let limit = va_range!(0x800..0x7800);
+    let randomized = |size, align, pick| {
+        let mut calls = 0;
+        let addr = set.find_free_area_randomized(size, limit, align, || {
+            calls += 1;
+            pick
+        });
+        assert!(calls <= 1);
+        addr.map(|addr| addr.as_usize())
+    };
+
+    // Every aligned start in the free ranges of the limit, skipping the
+    // guard and the hole, is picked by exactly one number, in order.
+    let picks: Vec<_> = (0..8).map(|pick| randomized(0x800, 0x800, pick)).collect();
+    let expected = [
+        0x800, 0x3000, 0x3800, 0x4000, 0x4800, 0x6000, 0x6800, 0x7000,
+    ];
+    assert_eq!(picks, expected.map(Some));
+    assert_eq!(randomized(0x800, 0x800, 8), Some(0x800));
+    assert_eq!(randomized(0x800, 0x800, usize::MAX), Some(0x7000));
+
+    // Gaps without an aligned start that fits are skipped.
+    let picks: Vec<_> = (0..4).map(|pick| randomized(0x800, 0x1000, pick)).collect();
+    assert_eq!(picks, [0x3000, 0x4000, 0x6000, 0x7000].map(Some));
+
+    // Nothing fits.
+    assert_eq!(randomized(0x2800, 0x800, 0), None);
+    assert_eq!(randomized(0x1800, 0x4000, 0), None);
+
+    // The gap up to the end of the address space is counted too, and every
+    // pick fits.
+    for pick in [0, 1, usize::MAX / 2, usize::MAX] {
+        let addr = set
+            .find_free_area_randomized(0x1000, va_range!(0..usize::MAX), 1, || pick)
+            .unwrap();
+        assert!(addr.as_usize().checked_add(0x1000).is_some());
+        assert!(!set.overlaps(memory_addr::AddrRange::from_start_size(addr, 0x1000)));
+    }
+}
+
+#[test]
+fn test_placement_strategies() {
+    use crate::{BestFit, FirstFit, NearestFit, Random, TopDown};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0.into(), 0x1000, 1), &mut pt, false, None));
+    assert_ok!(set.map(new_area(0x4000.into(), 0x1000, 1), &mut pt, false, None));
+    let guarded = new_area(0x8000.into(), 0x1000, 1).with_guards(0x1000, 0);
+    assert_ok!(set.map(guarded, &mut pt, false, None));
+    // Free: [0x1000, 0x4000), [0x5000, 0x7000), [0x9000, 0x10000).
+    let limit = va_range!(0..MAX_ADDR);
+    let place = |strategy: &mut dyn PlacementStrategy<MockBackend>, hint: usize, size, limit| {
+        strategy
+            .place(&set, hint.into(), size, limit)
+            .map(VirtAddr::as_usize)
+    };
+
+    assert_eq!(place(&mut FirstFit, 0, 0x2000, limit), Some(0x1000));
+    assert_eq!(place(&mut FirstFit, 0x2000, 0x2000, limit), Some(0x2000));
+
+    // The smallest gap wins, the guard region is not free, and ties go to
+    // the lowest gap.
+    assert_eq!(place(&mut BestFit, 0, 0x2000, limit), Some(0x5000));
+    assert_eq!(place(&mut BestFit, 0, 0x3000, limit), Some(0x1000));
+    assert_eq!(place(&mut BestFit, 0, 0x4000, limit), Some(0x9000));
+    assert_eq!(place(&mut BestFit, 0, 0x8000, limit), None);
+    assert_eq!(place(&mut BestFit, 0x2000, 0x2000, limit), Some(0x2000));
+    // The hint and the limit clip the gaps.
+    assert_eq!(place(&mut BestFit, 0x3800, 0x1000, limit), Some(0x5000));
+    let low = va_range!(0..0xa000);
+    assert_eq!(place(&mut BestFit, 0x8800, 0x1000, low), Some(0x9000));
+    assert_eq!(place(&mut BestFit, 0x8800, 0x2000, low), None);
+    assert_eq!(place(&mut BestFit, 0xb000, 0x1000, low), None);
+
+    assert_eq!(place(&mut TopDown, MAX_ADDR, 0x2000, limit), Some(0xe000));
+    assert_eq!(place(&mut TopDown, 0x7000, 0x2000, limit), Some(0x5000));
+    assert_eq!(place(&mut NearestFit, 0x5800, 0x1000, limit), Some(0x5800));
+    assert_eq!(place(&mut NearestFit, 0x4800, 0x1000, limit), Some(0x5000));
+
+    // A random placement calls the source once, and is aligned and free.
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let mut random = Random::new(0x1000, move || counter.fetch_add(1, Ordering::Relaxed) * 3);
+    let mut placed = Vec::new();
+    for _ in 0..4 {
+        let start = place(&mut random, 0, 0x2000, limit).unwrap();
+        assert_eq!(start % 0x1000, 0);
+        assert!(
+            set.free_ranges(limit).any(|gap| {
+                gap.start.as_usize() <= start && start + 0x2000 <= gap.end.as_usize()
+            })
+        );
+        placed.push(start);
+    }
+    assert_eq!(calls.load(Ordering::Relaxed), 4);
+    // 9 candidates: 0x1000, 0x2000, 0x5000 and 0x9000..=0xe000.
+    assert_eq!(placed, [0x1000, 0x9000, 0xc000, 0x1000]);
+    assert_eq!(place(&mut random, 0, 0x8000, limit), None);
+    assert_eq!(calls.load(Ordering::Relaxed), 4);
+
+    // The default strategy of the set is first fit, and a stateful one keeps
+    // its state between calls.
+    assert_eq!(
+        set.find_placement(0.into(), 0x2000, limit),
+        Some(0x1000.into())
+    );
+    set.set_placement(BestFit);
+    assert_eq!(
+        set.find_placement(0.into(), 0x2000, limit),
+        Some(0x5000.into())
+    );
+    let counter = calls.clone();
+    set.set_placement(Random::new(0x1000, move || {
+        counter.fetch_add(1, Ordering::Relaxed)
+    }));
+    let first = set.find_placement(0.into(), 0x1000, limit);
+    let second = set.find_placement(0.into(), 0x1000, limit);
+    assert_ne!(first, second);
+    assert_eq!(calls.load(Ordering::Relaxed), 6);
+    assert_eq!(
+        set.find_free_area_with(&mut TopDown, 0x4000.into(), 0x1000, limit),
+        Some(0x3000.into())
+    );
+}
+
+#[test]
+fn test_guards() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+
+    // A stack at [0x2000, 0x4000) with a guard page below it.
+    let stack = new_area(0x2000.into(), 0x2000, 1).with_guards(0x1000, 0);
+    assert_eq!(stack.reserved_range(), va_range!(0x1000..0x4000));
+    assert_ok!(set.map(stack, &mut pt, false, None));
+    assert!(pt[0x1000..0x2000].iter().all(|&flags| flags == 0));
+    assert!(pt[0x2000..0x4000].iter().all(|&flags| flags == 1));
+    assert!(set.find(0x1800.into()).is_none());
+
+    // The guard page is neither free nor mappable.
+    assert!(set.overlaps(va_range!(0x1800..0x1900)));
+    assert_err!(
+        set.map(new_area(0x1000.into(), 0x1000, 2), &mut pt, false, None),
+        AlreadyExists
+    );
+    let addr = set.find_free_area(0.into(), 0x1000, va_range!(0..MAX_ADDR));
+    assert_eq!(addr, Some(0.into()));
+    let addr = set.find_free_area(0.into(), 0x2000, va_range!(0..MAX_ADDR));
+    assert_eq!(addr, Some(0x4000.into()));
+
+    // A new area with guards on both sides keeps them free too.
+    let area = new_area(0x5000.into(), 0x1000, 2).with_guards(0x1000, 0x1000);
+    assert_ok!(set.map(area, &mut pt, false, None));
+    let addr = set.find_free_area(0x3000.into(), 0x1000, va_range!(0..MAX_ADDR));
+    assert_eq!(addr, Some(0x7000.into()));
+
+    // Splitting keeps the guards at the outer ends.
+    assert_ok!(set.unmap(0x5000.into(), 0x800, &mut pt));
+    let area = set.find(0x5800.into()).unwrap();
+    assert_eq!(area.reserved_range(), va_range!(0x4800..0x7000));
+    assert_ok!(set.protect(0x2000.into(), 0x1000, |_| Some(3), &mut pt));
+    assert_eq!(set.find(0x2000.into()).unwrap().guards(), (0x1000, 0));
+    assert_eq!(set.find(0x3000.into()).unwrap().guards(), (0, 0));
+    assert_eq!(set.find(0x3000.into()).unwrap().flags(), 1);
+}
+
+#[test]
+fn test_gaps() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let stack = new_area(0x2000.into(), 0x2000, 1).with_guards(0x1000, 0);
+    assert_ok!(set.map(stack, &mut pt, false, None));
+    let area = new_area(0x5000.into(), 0x1000, 1).with_guards(0x1000, 0x1000);
+    assert_ok!(set.map(area, &mut pt, false, None));
+    assert_ok!(set.add_hole(new_area(0x9000.into(), 0x1000, 0)));
+    assert_ok!(set.map(new_area(0xa000.into(), 0x1000, 1), &mut pt, false, None));
+
+    // Guard regions are gaps, but not free.
+    let gaps: Vec<_> = set.gaps(va_range!(0..0xc000)).collect();
+    assert_eq!(
+        gaps,
+        [
+            va_range!(0..0x2000),
+            va_range!(0x4000..0x5000),
+            va_range!(0x6000..0x9000),
+            va_range!(0xb000..0xc000),
+        ]
+    );
+    let free: Vec<_> = set.free_ranges(va_range!(0..0xc000)).collect();
+    assert_eq!(
+        free,
+        [
+            va_range!(0..0x1000),
+            va_range!(0x7000..0x9000),
+            va_range!(0xb000..0xc000),
+        ]
+    );
+
+    // The gaps are clipped to the limit.
+    let gaps: Vec<_> = set.gaps(va_range!(0x2800..0x6800)).collect();
+    assert_eq!(gaps, [va_range!(0x4000..0x5000), va_range!(0x6000..0x6800)]);
+    assert_eq!(set.gaps(va_range!(0x5000..0x6000)).count(), 0);
+}
+
+#[test]
+fn test_free_area_index() {
+    use crate::FreeAreaConstraint;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    // xorshift, with a fixed seed to be reproducible.
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut rand = |n: usize| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as usize % n
+    };
+
+    for _ in 0..2000 {
+        let start = (rand(0xff) + 1) * 0x100;
+        let size = (rand(0x10) + 1) * 0x100;
+        match rand(3) {
+            0 | 1 => {
+                let guards = (rand(2) * 0x100, rand(2) * 0x100);
+                let area = new_area(start.into(), size.min(MAX_ADDR - start), 1)
+                    .with_guards(guards.0, guards.1);
+                let _ = set.map(area, &mut pt, false, None);
+            }
+            _ => assert_ok!(set.unmap(start.into(), size, &mut pt)),
+        }
+
+        // Every search agrees with a scan of all the free ranges.
+        let free: Vec<_> = set
+            .free_ranges(va_range!(0..usize::MAX))
+            .map(|range| (range.start.as_usize(), range.end.as_usize()))
+            .collect();
+        let hint = rand(0x110) * 0x100;
+        let size = (rand(0x20) + 1) * 0x100;
+        let limit_start = rand(0x100) * 0x100;
+        let limit_end = match rand(4) {
+            0 => usize::MAX,
+            _ => limit_start + rand(0x100) * 0x100,
+        };
+        let limit = va_range!(limit_start..limit_end);
+        let align = 0x100 << rand(5);
+
+        let first_fit = |align: usize| {
+            let from = hint.max(limit_start);
+            free.iter().find_map(|&(gap_start, gap_end)| {
+                let start = gap_start.max(from).next_multiple_of(align);
+                (start.checked_add(size)? <= gap_end.min(limit_end)).then_some(start)
+            })
+        };
+        assert_eq!(
+            set.find_free_area(hint.into(), size, limit)
+                .map(|addr| addr.as_usize()),
+            first_fit(1)
+        );
+        let constraint = FreeAreaConstraint::new().with_mask(align - 1, 0);
+        assert_eq!(
+            set.find_free_area_constrained(hint.into(), size, limit, &constraint)
+                .map(|addr| addr.as_usize()),
+            first_fit(align)
+        );
+
+        let last_fit = free.iter().rev().find_map(|&(gap_start, gap_end)| {
+            let start = gap_end.min(hint).min(limit_end).checked_sub(size)?;
+            (start >= gap_start.max(limit_start)).then_some(start)
+        });
+        assert_eq!(
+            set.find_free_area_topdown(hint.into(), size, limit)
+                .map(|addr| addr.as_usize()),
+            last_fit
+        );
+
+        let candidates: Vec<_> = free
+            .iter()
+            .filter_map(|&(gap_start, gap_end)| {
+                let first = gap_start.max(limit_start).next_multiple_of(align);
+                let last = gap_end.min(limit_end).checked_sub(size)?;
+                (first <= last).then(|| (first, (last - first) / align + 1))
+            })
+            .collect();
+        let total = candidates.iter().map(|&(_, count)| count).sum::<usize>();
+        let pick = rand(usize::MAX);
+        let expected = (total > 0).then(|| {
+            let mut index = pick % total;
+            let (first, _) = candidates
+                .iter()
+                .find(|&&(_, count)| {
+                    let found = index < count;
+                    if !found {
+                        index -= count;
+                    }
+                    found
+                })
+                .unwrap();
+            first + index * align
+        });
+        assert_eq!(
+            set.find_free_area_randomized(size, limit, align, || pick)
+                .map(|addr| addr.as_usize()),
+            expected
+        );
+    }
+    set.check_invariants();
+}
+
+#[test]
+fn test_seal() {
+    let mut pt = test_page_table(MAX_ADDR);
+    // Kernel text at [0x1000, 0x3000), data at [0x3000, 0x5000).
+    let mut set = MockMemorySet::new()
+        .with_sealed([new_area(0x1000.into(), 0x2000, 5)], &mut pt)
+        .unwrap();
+    assert_ok!(set.map(new_area(0x3000.into(), 0x2000, 3), &mut pt, false, None));
+    assert!(set.find(0x1000.into()).unwrap().is_sealed());
+
+    assert_err!(
+        set.protect(0x1000.into(), 0x1000, |_| Some(7), &mut pt),
+        PermissionDenied
+    );
+    assert_err!(set.unmap(0x2000.into(), 0x2000, &mut pt), PermissionDenied);
+    assert_err!(
+        set.map(new_area(0x2000.into(), 0x1000, 7), &mut pt, true, None),
+        PermissionDenied
+    );
+    assert_err!(set.clear(&mut pt), PermissionDenied);
+    assert_err!(set.delete(0x1000.into()), PermissionDenied);
+    assert!(pt[0x1000..0x3000].iter().all(|&flags| flags == 5));
+    assert!(pt[0x3000..0x5000].iter().all(|&flags| flags == 3));
+
+    // Sealing a part of the data splits it, the rest stays unsealed.
+    assert_ok!(set.seal(0x3000.into(), 0x1000));
+    assert_err!(set.unmap(0x3000.into(), 0x1000, &mut pt), PermissionDenied);
+    assert_ok!(set.unmap(0x4000.into(), 0x1000, &mut pt));
+    assert_err!(set.seal(0x4000.into(), 0x1000), NotMapped);
+    assert_eq!(set.len(), 2);
+    assert_ok!(set.map(new_area(0x8000.into(), 0x1000, 3), &mut pt, false, None));
+    assert_ok!(set.delete(0x8000.into()));
+    assert_eq!(set.len(), 2);
+}
+
+#[test]
+fn test_coalescing() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_granularity(0x1000).with_merging();
+    let area = |start: usize, size, flags| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            flags,
+            backend.clone(),
+        )
+    };
+    let ranges = |set: &MockMemorySet| set.iter().map(|area| area.va_range()).collect::<Vec<_>>();
+
+    // Adjacent areas with the same flags merge, on either side.
+    assert_ok!(set.map(area(0x2000, 0x1000, 1), &mut pt, false, None));
+    assert_ok!(set.map(area(0x3000, 0x1000, 1), &mut pt, false, None));
+    assert_ok!(set.map(area(0x1000, 0x1000, 1), &mut pt, false, None));
+    assert_eq!(ranges(&set), [va_range!(0x1000..0x4000)]);
+    // Different flags, a gap or a backend refusing to merge keep them apart.
+    assert_ok!(set.map(area(0x4000, 0x1000, 3), &mut pt, false, None));
+    assert_ok!(set.map(area(0x6000, 0x1000, 1), &mut pt, false, None));
+    assert_ok!(set.map(new_area(0x7000.into(), 0x1000, 1), &mut pt, false, None));
+    assert_eq!(set.len(), 4);
+
+    // Resident frames survive the merges.
+    #[cfg(feature = "RAII")]
+    let frames = {
+        use crate::test_utils::TestFrame;
+        use memory_addr::FrameTracker;
+        use std::sync::Arc;
+        [0x1000, 0x4000].map(|addr| {
+            let frame = Arc::new(TestFrame::alloc_frame());
+            set.insert_frame(addr.into(), frame.clone());
+            frame
+        })
+    };
+
+    // Protecting the middle splits the area, protecting it back merges it.
+    assert_ok!(set.protect(0x2000.into(), 0x1000, |_| Some(3), &mut pt));
+    assert_eq!(
+        ranges(&set)[..3],
+        [
+            va_range!(0x1000..0x2000),
+            va_range!(0x2000..0x3000),
+            va_range!(0x3000..0x4000),
+        ]
+    );
+    assert_ok!(set.protect(0x2000.into(), 0x1000, |_| Some(1), &mut pt));
+    assert_eq!(ranges(&set)[0], va_range!(0x1000..0x4000));
+    // A protection bridging two areas merges all three.
+    assert_ok!(set.protect(0x4000.into(), 0x1000, |_| Some(1), &mut pt));
+    assert_eq!(ranges(&set)[0], va_range!(0x1000..0x5000));
+    assert_eq!(set.len(), 3);
+    #[cfg(feature = "RAII")]
+    {
+        use std::sync::Arc;
+        assert!(Arc::ptr_eq(
+            &set.find_frame(0x1000.into()).unwrap(),
+            &frames[0]
+        ));
+        assert!(Arc::ptr_eq(
+            &set.find_frame(0x4000.into()).unwrap(),
+            &frames[1]
+        ));
+        assert!(set.find_frame(0x2000.into()).is_none());
+    }
+    assert!(pt[0x1000..0x5000].iter().all(|&flags| flags == 1));
+
+    // Per-area states must match.
+    let mut labeled = area(0x5000, 0x1000, 1);
+    labeled.set_label(Some("heap".into()));
+    assert_ok!(set.map(labeled, &mut pt, false, None));
+    assert_eq!(set.len(), 4);
+    assert_ok!(set.lock(0x6000.into(), 0x1000));
+    assert_ok!(set.unmap(0x5000.into(), 0x1000, &mut pt));
+    assert_ok!(set.map(area(0x5000, 0x1000, 1), &mut pt, false, None));
+    assert_eq!(
+        ranges(&set)[..2],
+        [va_range!(0x1000..0x6000), va_range!(0x6000..0x7000)]
+    );
+    assert_ok!(set.unlock(0x6000.into(), 0x1000));
+    assert_eq!(ranges(&set)[0], va_range!(0x1000..0x7000));
+
+    // Nothing merges with coalescing off, until it is turned back on.
+    assert_ok!(set.unmap(0.into(), MAX_ADDR, &mut pt));
+    set.set_coalescing(false);
+    assert_ok!(set.map(area(0x1000, 0x1000, 1), &mut pt, false, None));
+    assert_ok!(set.map(area(0x2000, 0x1000, 1), &mut pt, false, None));
+    assert_ok!(set.protect(0x1000.into(), 0x2000, |_| Some(1), &mut pt));
+    assert_eq!(set.len(), 2);
+    set.set_coalescing(true);
+    assert_ok!(set.map(area(0x3000, 0x1000, 1), &mut pt, false, None));
+    assert_eq!(set.len(), 2);
+    assert_ok!(set.protect(0x1000.into(), 0x3000, |_| Some(1), &mut pt));
+    assert_eq!(ranges(&set), [va_range!(0x1000..0x4000)]);
+    set.check_invariants();
+}
+
+#[test]
+fn test_area_labels() {
+    use crate::RemapFlags;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_merging();
+    let area = |start: usize, size, label: Option<&str>| {
+        let mut area = MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            1,
+            backend.clone(),
+        );
+        area.set_label(label.map(Into::into));
+        area
+    };
+    let labels = |set: &MockMemorySet| {
+        set.iter()
+            .map(|area| (area.va_range(), area.label().map(String::from)))
+            .collect::<Vec<_>>()
+    };
+    let heap = || Some(String::from("heap"));
+
+    // Only areas with the same label are merged.
+    assert_ok!(set.map(area(0x1000, 0x1000, Some("heap")), &mut pt, false, None));
+    assert_ok!(set.map(area(0x2000, 0x1000, Some("heap")), &mut pt, false, None));
+    assert_ok!(set.map(area(0x3000, 0x1000, Some("[stack]")), &mut pt, false, None));
+    assert_ok!(set.map(area(0x4000, 0x1000, None), &mut pt, false, None));
+    assert_eq!(
+        labels(&set),
+        [
+            (va_range!(0x1000..0x3000), heap()),
+            (va_range!(0x3000..0x4000), Some("[stack]".into())),
+            (va_range!(0x4000..0x5000), None),
+        ]
+    );
+    assert!(format!("{:?}", set.find(0x1000.into()).unwrap()).contains("\"heap\""));
+
+    // Setting and clearing it.
+    let stack = set.find_mut(0x3000.into()).unwrap();
+    stack.set_label(None);
+    assert_eq!(stack.label(), None);
+    stack.set_label(Some("[stack]".into()));
+    assert_eq!(stack.label(), Some("[stack]"));
+
+    // The parts split off keep it.
+    assert_ok!(set.map(area(0x8000, 0x4000, Some("heap")), &mut pt, false, None));
+    assert_ok!(set.unmap(0x9000.into(), 0x1000, &mut pt));
+    assert_ok!(set.protect(0xa800.into(), 0x800, |_| Some(2), &mut pt));
+    assert_eq!(
+        labels(&set)[3..],
+        [
+            (va_range!(0x8000..0x9000), heap()),
+            (va_range!(0xa000..0xa800), heap()),
+            (va_range!(0xa800..0xb000), heap()),
+            (va_range!(0xb000..0xc000), heap()),
+        ]
+    );
+
+    // So do the moved areas and parts.
+    assert_ok!(set.remap_area_flags_and_base(0x8000.into(), 0xd000.into(), 1, &mut pt));
+    assert_eq!(set.find(0xd000.into()).unwrap().label(), Some("heap"));
+    let fixed = RemapFlags::new().with_fixed(0x5000.into());
+    assert_eq!(
+        set.remap(0x1000.into(), 0x1000, 0x1000, fixed, &mut pt),
+        Ok(0x5000.into())
+    );
+    assert_eq!(set.find(0x5000.into()).unwrap().label(), Some("heap"));
+    assert_ok!(set.remap_dontunmap(0x2000.into(), 0x1000, 0x6000.into(), &mut pt));
+    assert_eq!(set.find(0x2000.into()).unwrap().label(), Some("heap"));
+    assert_eq!(set.find(0x6000.into()).unwrap().label(), Some("heap"));
+    set.check_invariants();
+}
+
+#[test]
+fn test_lock() {
+    use crate::Advice;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_granularity(0x1000).with_merging();
+    let area = |start: usize, size| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            1,
+            backend.clone(),
+        )
+    };
+    assert_ok!(set.map(area(0x1000, 0x4000), &mut pt, false, None));
+    assert_ok!(set.add_hole(area(0x6000, 0x1000)));
+    assert_ok!(set.map(area(0x7000, 0x1000), &mut pt, false, None));
+    let ranges = |set: &MockMemorySet| set.iter().map(|area| area.va_range()).collect::<Vec<_>>();
+
+    // Locking a part splits the area, the length is rounded up.
+    assert_err!(set.lock(0x2800.into(), 0x1000), InvalidParam);
+    assert_ok!(set.lock(0x2000.into(), 0x1800));
+    assert_eq!(set.locked_size(), 0x2000);
+    assert!(set.find(0x3000.into()).unwrap().is_locked());
+    assert!(!set.find(0x1000.into()).unwrap().is_locked());
+    assert_eq!(
+        ranges(&set)[..3],
+        [
+            va_range!(0x1000..0x2000),
+            va_range!(0x2000..0x4000),
+            va_range!(0x4000..0x5000),
+        ]
+    );
+
+    // Gaps and holes are not mapped, and nothing changes.
+    assert_err!(set.lock(0x4000.into(), 0x2000), NotMapped);
+    assert_err!(set.lock(0x6000.into(), 0x2000), NotMapped);
+    assert_err!(set.unlock(0x6000.into(), 0x1000), NotMapped);
+    assert_eq!(set.locked_size(), 0x2000);
+    assert_eq!(set.len(), 5);
+    assert!(!set.find(0x6000.into()).unwrap().is_locked());
+
+    // The contents of locked areas are kept, other advice is fine.
+    for advice in [Advice::DontNeed, Advice::Free] {
+        assert_err!(
+            set.advise(0x1000.into(), 0x2000, advice, &mut pt),
+            InvalidParam
+        );
+    }
+    assert_ok!(set.advise(0x1000.into(), 0x2000, Advice::WillNeed, &mut pt));
+
+    // Locking again is idempotent and extends to the neighbor, which merges.
+    assert_ok!(set.lock(0x3000.into(), 0x2000));
+    assert_eq!(set.locked_size(), 0x3000);
+    assert_eq!(
+        set.find(0x2000.into()).unwrap().va_range(),
+        va_range!(0x2000..0x5000)
+    );
+
+    // Unmapping a part of a locked area is accounted.
+    assert_ok!(set.unmap(0x4000.into(), 0x1000, &mut pt));
+    assert_eq!(set.locked_size(), 0x2000);
+
+    // Unlocking everything merges the area back.
+    assert_ok!(set.unlock(0x1000.into(), 0x3000));
+    assert_eq!(set.locked_size(), 0);
+    assert_eq!(ranges(&set)[0], va_range!(0x1000..0x4000));
+    set.check_invariants();
+}
+
+#[test]
+fn test_reserve_commit() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new();
+    let area = MemoryArea::new(
+        0x1000.into(),
+        0x8000,
+        #[cfg(feature = "RAII")]
+        None,
+        0,
+        backend.clone(),
+    );
+
+    // Reserving calls nothing.
+    assert_ok!(set.reserve(area));
+    assert_eq!(backend.calls(Op::Map), 0);
+    assert!(set.find(0x1000.into()).unwrap().is_reserved());
+    assert_err!(
+        set.map(new_area(0x2000.into(), 0x1000, 1), &mut pt, false, None),
+        AlreadyExists
+    );
+    assert_err!(
+        set.handle_page_fault(0x2000.into(), 1, &mut pt),
+        PermissionDenied
+    );
+
+    // Commit [0x3000, 0x5000) and [0x4000, 0x6000).
+    assert_ok!(set.commit(0x3000.into(), 0x2000, 1, &mut pt));
+    assert_ok!(set.commit(0x4000.into(), 0x2000, 2, &mut pt));
+    assert_eq!(backend.calls(Op::Map), 2);
+    assert_eq!(set.len(), 5);
+    assert!(pt[0x1000..0x3000].iter().all(|&flags| flags == 0));
+    assert!(pt[0x3000..0x5000].iter().all(|&flags| flags == 1));
+    assert!(pt[0x5000..0x6000].iter().all(|&flags| flags == 2));
+    assert!(pt[0x6000..0x9000].iter().all(|&flags| flags == 0));
+    assert!(!set.find(0x5000.into()).unwrap().is_reserved());
+    assert_err!(set.commit(0x8000.into(), 0x2000, 1, &mut pt), NotMapped);
+
+    // Decommit [0x4000, 0x6000), then release everything.
+    assert_ok!(set.decommit(0x4000.into(), 0x2000, &mut pt));
+    assert!(pt[0x3000..0x4000].iter().all(|&flags| flags == 1));
+    assert!(pt[0x4000..0x6000].iter().all(|&flags| flags == 0));
+    assert!(set.find(0x5000.into()).unwrap().is_reserved());
+    assert_ok!(set.unmap(0x1000.into(), 0x8000, &mut pt));
+    assert_eq!(backend.calls(Op::Unmap), 3);
+    assert!(set.is_empty());
+}
+
+#[test]
+fn test_commit_charge() {
+    use crate::RemapFlags;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // A strict overcommit limit of 0x6000 bytes for all the sets.
+    let committed = Arc::new(AtomicUsize::new(0));
+    let check = {
+        let committed = committed.clone();
+        move |bytes| {
+            committed
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |total| {
+                    (total + bytes <= 0x6000).then_some(total + bytes)
+                })
+                .is_ok()
+        }
+    };
+    let mut set = MockMemorySet::new();
+    set.set_commit_check(check);
+    let mut pt = test_page_table(MAX_ADDR);
+
+    assert_ok!(set.map(new_area(0.into(), 0x4000, 1), &mut pt, false, None));
+    assert_eq!(set.commit_charge(), 0x4000);
+    // Rejected mappings are not charged.
+    assert_err!(
+        set.map(new_area(0x2000.into(), 0x1000, 1), &mut pt, false, None),
+        AlreadyExists
+    );
+    assert_eq!(committed.load(Ordering::SeqCst), 0x4000);
+    assert_err!(
+        set.map(new_area(0x4000.into(), 0x4000, 1), &mut pt, false, None),
+        LimitExceeded
+    );
+    assert!(set.find(0x4000.into()).is_none());
+
+    // Reserving is free, committing is charged.
+    let area = new_area(0x8000.into(), 0x4000, 0);
+    assert_ok!(set.reserve(area));
+    assert_eq!(set.commit_charge(), 0x4000);
+    assert_ok!(set.commit(0x8000.into(), 0x2000, 1, &mut pt));
+    assert_err!(set.commit(0xa000.into(), 0x1000, 1, &mut pt), LimitExceeded);
+    assert_eq!(set.commit_charge(), 0x6000);
+    assert_eq!(committed.load(Ordering::SeqCst), 0x6000);
+
+    // Growing is charged too.
+    assert_err!(
+        set.remap(0.into(), 0x4000, 0x5000, RemapFlags::new(), &mut pt),
+        LimitExceeded
+    );
+    assert_err!(
+        set.adjust_area(0.into(), 0.into(), 0x5000.into(), &mut pt),
+        LimitExceeded
+    );
+    assert_eq!(set.find(0.into()).unwrap().size(), 0x4000);
+    assert_ok!(set.remap(0.into(), 0x4000, 0x2000, RemapFlags::new(), &mut pt));
+}
+
+#[test]
+fn test_commit_release() {
+    use crate::RemapFlags;
+    use crate::test_utils::Op;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let committed = Arc::new(AtomicUsize::new(0));
+    let mut set = MockMemorySet::new();
+    let check = committed.clone();
+    set.set_commit_check(move |bytes| {
+        check.fetch_add(bytes, Ordering::SeqCst);
+        true
+    });
+    let release = committed.clone();
+    set.set_commit_release(move |bytes| {
+        release.fetch_sub(bytes, Ordering::SeqCst);
+    });
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new();
+    let area = |start: usize, size: usize, flags: MockFlags| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            flags,
+            backend.clone(),
+        )
+    };
+
+    assert_ok!(set.map(area(0, 0x4000, 1), &mut pt, false, None));
+    assert_eq!(committed.load(Ordering::SeqCst), 0x4000);
+    // Unmapping, shrinking and decommitting give the charge back.
+    assert_ok!(set.unmap(0x1000.into(), 0x1000, &mut pt));
+    assert_eq!(committed.load(Ordering::SeqCst), 0x3000);
+    assert_ok!(set.remap(0x2000.into(), 0x2000, 0x1000, RemapFlags::new(), &mut pt));
+    assert_eq!(committed.load(Ordering::SeqCst), 0x2000);
+    assert_ok!(set.decommit(0.into(), 0x1000, &mut pt));
+    assert_eq!(committed.load(Ordering::SeqCst), 0x1000);
+    assert_eq!(committed.load(Ordering::SeqCst), set.commit_charge());
+
+    // Operations failing once charged are refunded.
+    backend.fail_at(Op::Map, 1);
+    assert_err!(set.map(area(0x8000, 0x2000, 1), &mut pt, false, None));
+    assert_eq!(committed.load(Ordering::SeqCst), 0x1000);
+    backend.fail_at(Op::Map, 1);
+    assert_err!(set.commit(0.into(), 0x1000, 1, &mut pt));
+    assert_eq!(committed.load(Ordering::SeqCst), 0x1000);
+    assert_ok!(set.commit(0.into(), 0x1000, 1, &mut pt));
+    assert_eq!(committed.load(Ordering::SeqCst), 0x2000);
+
+    // Clearing the set releases everything.
+    assert_ok!(set.clear(&mut pt));
+    assert_eq!(committed.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn test_remap() {
+    use crate::RemapFlags;
+    use crate::test_utils::Op;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_granularity(0x1000);
+    let area = |start: usize, size, flags| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            flags,
+            backend.clone(),
+        )
+    };
+    assert_ok!(set.map(area(0x1000, 0x3000, 1), &mut pt, false, None));
+    assert_ok!(set.map(area(0x6000, 0x1000, 2), &mut pt, false, None));
+    #[cfg(feature = "RAII")]
+    let frame = {
+        use crate::test_utils::TestFrame;
+        use memory_addr::FrameTracker;
+        let frame = std::sync::Arc::new(TestFrame::alloc_frame());
+        set.insert_frame(0x2000.into(), frame.clone());
+        frame
+    };
+    let ranges = |set: &MockMemorySet| set.iter().map(|area| area.va_range()).collect::<Vec<_>>();
+    let in_place = RemapFlags::new();
+    let may_move = RemapFlags::new().with_may_move(va_range!(0x8000..0x10000));
+
+    // The old range must be inside one area, and the new size not zero.
+    for (start, size, new_size) in [
+        (0x1000, 0x4000, 0x1000),
+        (0x5000, 0x1000, 0x1000),
+        (0x1000, 0x1000, 0),
+    ] {
+        assert_err!(
+            set.remap(start.into(), size, new_size, may_move, &mut pt),
+            InvalidParam
+        );
+    }
+
+    // Shrinking rounds the new size up and unmaps the tail in place, growing
+    // maps the following free range.
+    assert_eq!(
+        set.remap(0x1000.into(), 0x3000, 0x1800, in_place, &mut pt),
+        Ok(0x1000.into())
+    );
+    assert!(pt[0x3000..0x4000].iter().all(|&flags| flags == 0));
+    assert_eq!(
+        set.remap(0x1000.into(), 0x2000, 0x4000, in_place, &mut pt),
+        Ok(0x1000.into())
+    );
+    assert_eq!(
+        ranges(&set),
+        [va_range!(0x1000..0x5000), va_range!(0x6000..0x7000)]
+    );
+    assert!(pt[0x1000..0x5000].iter().all(|&flags| flags == 1));
+
+    // Growing into another area needs moving.
+    assert_err!(
+        set.remap(0x1000.into(), 0x4000, 0x6000, in_place, &mut pt),
+        AlreadyExists
+    );
+    assert_eq!(
+        set.find(0x1000.into()).unwrap().va_range(),
+        va_range!(0x1000..0x5000)
+    );
+
+    // A part of an area is split off and moved with its frames.
+    assert_eq!(
+        set.remap(0x2000.into(), 0x1000, 0x2000, may_move, &mut pt),
+        Ok(0x8000.into())
+    );
+    assert_eq!(
+        ranges(&set),
+        [
+            va_range!(0x1000..0x2000),
+            va_range!(0x3000..0x5000),
+            va_range!(0x6000..0x7000),
+            va_range!(0x8000..0xa000),
+        ]
+    );
+    assert!(pt[0x2000..0x3000].iter().all(|&flags| flags == 0));
+    assert!(pt[0x8000..0xa000].iter().all(|&flags| flags == 1));
+    #[cfg(feature = "RAII")]
+    {
+        use memory_addr::FrameTracker;
+        assert_eq!(
+            set.find_frame(0x8000.into()).unwrap().start(),
+            frame.start()
+        );
+        assert!(set.find_frame(0x2000.into()).is_none());
+    }
+
+    // A failure to move or to grow after moving leaves the mapping where it
+    // was.
+    for op in [Op::Move, Op::Map] {
+        backend.fail_at(op, 1);
+        assert_err!(
+            set.remap(0x3000.into(), 0x2000, 0x4000, may_move, &mut pt),
+            BadState
+        );
+        assert_eq!(
+            set.find(0x3000.into()).unwrap().va_range(),
+            va_range!(0x3000..0x5000)
+        );
+        assert!(pt[0x3000..0x5000].iter().all(|&flags| flags == 1));
+        assert!(pt[0xa000..].iter().all(|&flags| flags == 0));
+    }
+
+    // A fixed target replaces what is there, but must not overlap the old
+    // range and must be aligned.
+    let fixed = |addr: usize| RemapFlags::new().with_fixed(addr.into());
+    assert_err!(
+        set.remap(0x3000.into(), 0x2000, 0x2000, fixed(0x4000), &mut pt),
+        InvalidParam
+    );
+    assert_err!(
+        set.remap(0x3000.into(), 0x2000, 0x2000, fixed(0x6800), &mut pt),
+        InvalidParam
+    );
+    assert_eq!(
+        set.remap(0x3000.into(), 0x2000, 0x2000, fixed(0x6000), &mut pt),
+        Ok(0x6000.into())
+    );
+    assert!(set.find(0x3000.into()).is_none());
+    assert!(pt[0x6000..0x8000].iter().all(|&flags| flags == 1));
+    set.check_invariants();
+}
+
+#[test]
+fn test_remap_guards() {
+    use crate::RemapFlags;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let area = |start: usize, flags| new_area(start.into(), 0x1000, flags);
+    assert_ok!(set.map(area(0x1000, 1).with_guards(0, 0x1000), &mut pt, false, None));
+    assert_ok!(set.map(area(0x4000, 2), &mut pt, false, None));
+    assert_ok!(set.map(area(0x8000, 2), &mut pt, false, None));
+
+    // Growing in place moves the trailing guard up, which needs room too.
+    assert_eq!(
+        set.remap(0x1000.into(), 0x1000, 0x2000, RemapFlags::new(), &mut pt),
+        Ok(0x1000.into())
+    );
+    assert_eq!(
+        set.find(0x1000.into()).unwrap().reserved_range(),
+        va_range!(0x1000..0x4000)
+    );
+    assert_err!(
+        set.remap(0x1000.into(), 0x2000, 0x3000, RemapFlags::new(), &mut pt),
+        AlreadyExists
+    );
+
+    // Moving needs room for the guards, so [0x5000, 0x8000) is too small.
+    let may_move = RemapFlags::new().with_may_move(va_range!(0x5000..0x10000));
+    assert_eq!(
+        set.remap(0x1000.into(), 0x2000, 0x3000, may_move, &mut pt),
+        Ok(0x9000.into())
+    );
+    let moved = set.find(0x9000.into()).unwrap();
+    assert_eq!(moved.reserved_range(), va_range!(0x9000..0xd000));
+    assert!(pt[0x9000..0xc000].iter().all(|&flags| flags == 1));
+
+    // So does a fixed target.
+    assert_ok!(set.map(area(0x2000, 3).with_guards(0x1000, 0), &mut pt, false, None));
+    let fixed = |addr: usize| RemapFlags::new().with_fixed(addr.into());
+    assert_err!(
+        set.remap(0x2000.into(), 0x1000, 0x1000, fixed(0x5000), &mut pt),
+        AlreadyExists
+    );
+    assert!(pt[0x4000..0x5000].iter().all(|&flags| flags == 2));
+    assert_eq!(
+        set.remap(0x2000.into(), 0x1000, 0x1000, fixed(0x6000), &mut pt),
+        Ok(0x6000.into())
+    );
+    assert_eq!(
+        set.find(0x6000.into()).unwrap().reserved_range(),
+        va_range!(0x5000..0x7000)
+    );
+    set.check_invariants();
+}
+
+#[test]
+fn test_remap_area_flags_and_base() {
+    use memory_addr::VirtAddrRange;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Map(VirtAddrRange, MockFlags),
+        Unmap(VirtAddrRange),
+    }
+
+    struct Recorder(Arc<Mutex<Vec<Event>>>);
+
+    impl MapObserver<MockBackend> for Recorder {
+        fn on_map(&mut self, range: VirtAddrRange, flags: MockFlags) {
+            self.0.lock().unwrap().push(Event::Map(range, flags));
+        }
+
+        fn on_unmap(&mut self, range: VirtAddrRange) {
+            self.0.lock().unwrap().push(Event::Unmap(range));
+        }
+    }
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut set = MockMemorySet::new().with_observer(Recorder(events.clone()));
+    let mut pt = test_page_table(MAX_ADDR);
+    let take = || core::mem::take(&mut *events.lock().unwrap());
+    let clock = Arc::new(AtomicU64::new(10));
+    let now = clock.clone();
+    set.set_clock(move || now.load(Ordering::SeqCst));
+    let backend = MockBackend::new().with_granularity(0x1000);
+    let area = |start: usize, size, flags| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            flags,
+            backend.clone(),
+        )
+    };
+    assert_ok!(set.map(
+        area(0x1000, 0x2000, 1).with_guards(0x1000, 0x1000),
+        &mut pt,
+        false,
+        None
+    ));
+    assert_ok!(set.map(area(0x8000, 0x1000, 2), &mut pt, false, None));
+    assert_ok!(set.map(area(0xc000, 0x1000, 3), &mut pt, false, None));
+    assert_ok!(set.seal(0xc000.into(), 0x1000));
+    #[cfg(feature = "RAII")]
+    let frame = {
+        use crate::test_utils::TestFrame;
+        use memory_addr::FrameTracker;
+        let frame = Arc::new(TestFrame::alloc_frame());
+        set.insert_frame(0x2000.into(), frame.clone());
+        frame
+    };
+    take();
+
+    // Only whole areas move, and not sealed ones.
+    assert_err!(
+        set.remap_area_flags_and_base(0x2000.into(), 0xa000.into(), 4, &mut pt),
+        NotMapped
+    );
+    assert_err!(
+        set.remap_area_flags_and_base(0xc000.into(), 0xa000.into(), 4, &mut pt),
+        PermissionDenied
+    );
+    // The target must be aligned, apart from the old range and in the
+    // address space.
+    for new_start in [0xa800, 0x2000, 0x0, usize::MAX - 0xfff] {
+        assert_err!(
+            set.remap_area_flags_and_base(0x1000.into(), new_start.into(), 4, &mut pt),
+            InvalidParam
+        );
+    }
+    // The guard regions need room too: [0x9000, 0xc000) fits the area but
+    // not its guards.
+    for new_start in [0x7000, 0x9000, 0xa000] {
+        assert_err!(
+            set.remap_area_flags_and_base(0x1000.into(), new_start.into(), 4, &mut pt),
+            AlreadyExists
+        );
+    }
+    // A failing move keeps the area in place.
+    backend.fail_at(Op::Move, 1);
+    assert_err!(
+        set.remap_area_flags_and_base(0x1000.into(), 0x4000.into(), 4, &mut pt),
+        BadState
+    );
+    assert!(pt[0x1000..0x3000].iter().all(|&flags| flags == 1));
+    assert_eq!(set.find(0x1000.into()).unwrap().flags(), 1);
+    assert!(take().is_empty());
+    set.check_invariants();
+
+    // The leading guard may cover the old range, which is freed.
+    clock.store(20, Ordering::SeqCst);
+    assert_ok!(set.remap_area_flags_and_base(0x1000.into(), 0x4000.into(), 4, &mut pt));
+    assert!(set.find(0x1000.into()).is_none());
+    let moved = set.find(0x4000.into()).unwrap();
+    assert_eq!(moved.va_range(), va_range!(0x4000..0x6000));
+    assert_eq!(moved.reserved_range(), va_range!(0x3000..0x7000));
+    assert_eq!(moved.flags(), 4);
+    assert_eq!(moved.times().created, Some(10));
+    assert_eq!(moved.times().last_protect, Some(20));
+    assert!(pt[0x1000..0x4000].iter().all(|&flags| flags == 0));
+    assert!(pt[0x4000..0x6000].iter().all(|&flags| flags == 4));
+    #[cfg(feature = "RAII")]
+    {
+        use memory_addr::FrameTracker;
+        assert_eq!(
+            set.find_frame(0x5000.into()).unwrap().start(),
+            frame.start()
+        );
+        assert!(set.find_frame(0x2000.into()).is_none());
+    }
+    assert_eq!(
+        take(),
+        [
+            Event::Unmap(va_range!(0x1000..0x3000)),
+            Event::Map(va_range!(0x4000..0x6000), 4),
+        ]
+    );
+    set.check_invariants();
+
+    // The freed range takes new areas.
+    assert_ok!(set.map(area(0x1000, 0x2000, 5), &mut pt, false, None));
+    set.check_invariants();
+}
+
+#[test]
+fn test_remap_dontunmap() {
+    use crate::test_utils::Op;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new();
+    let area = |start: usize, size: usize, backend: &MockBackend| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            1,
+            backend.clone(),
+        )
+    };
+    assert_ok!(set.map(area(0x1000, 0x4000, &backend), &mut pt, false, None));
+    #[cfg(feature = "RAII")]
+    let frames = {
+        use crate::test_utils::TestFrame;
+        use memory_addr::FrameTracker;
+        use std::sync::Arc;
+        [0x1000, 0x3000].map(|page| {
+            let frame = Arc::new(TestFrame::alloc_frame());
+            set.insert_frame(page.into(), frame.clone());
+            frame
+        })
+    };
+    // A page never touched, moved as is.
+    pt[0x2000..0x3000].fill(0);
+
+    assert_ok!(set.remap_dontunmap(0x2000.into(), 0x2000, 0x8000.into(), &mut pt));
+    assert_eq!(set.len(), 2);
+    assert_eq!(
+        set.find(0x4fff.into()).unwrap().va_range(),
+        va_range!(0x1000..0x5000)
+    );
+    let moved = set.find(0x8000.into()).unwrap();
+    assert_eq!(moved.va_range(), va_range!(0x8000..0xa000));
+    assert_eq!(moved.flags(), 1);
+    assert!(pt[0x8000..0x9000].iter().all(|&flags| flags == 0));
+    assert!(pt[0x9000..0xa000].iter().all(|&flags| flags == 1));
+    // The old range is mapped afresh.
+    assert!(pt[0x1000..0x5000].iter().all(|&flags| flags == 1));
+    #[cfg(feature = "RAII")]
+    {
+        use memory_addr::FrameTracker;
+        assert_eq!(
+            set.find_frame(0x9000.into()).unwrap().start(),
+            frames[1].start()
+        );
+        assert!(set.find_frame(0x3000.into()).is_none());
+        assert_eq!(
+            set.find_frame(0x1000.into()).unwrap().start(),
+            frames[0].start()
+        );
+    }
+    set.check_invariants();
+
+    // The old range must be mapped, inside one area, and the new one free.
+    assert_err!(
+        set.remap_dontunmap(0x4000.into(), 0x2000, 0xc000.into(), &mut pt),
+        InvalidParam
+    );
+    assert_err!(
+        set.remap_dontunmap(0x5000.into(), 0x1000, 0xc000.into(), &mut pt),
+        InvalidParam
+    );
+    assert_err!(
+        set.remap_dontunmap(0x1000.into(), 0, 0xc000.into(), &mut pt),
+        InvalidParam
+    );
+    assert_err!(
+        set.remap_dontunmap(0x1000.into(), 0x2000, 0x9000.into(), &mut pt),
+        AlreadyExists
+    );
+    // The backend refusing to move leaves everything in place.
+    assert_err!(
+        set.remap_dontunmap(0x1000.into(), 0x2000, (MAX_ADDR - 0x1000).into(), &mut pt),
+        BadState
+    );
+    assert!(pt[0x1000..0x3000].iter().all(|&flags| flags == 1));
+    assert!(set.find((MAX_ADDR - 0x1000).into()).is_none());
+    assert_ok!(set.reserve(area(0xa000, 0x1000, &backend)));
+    assert_err!(
+        set.remap_dontunmap(0xa000.into(), 0x1000, 0xc000.into(), &mut pt),
+        InvalidParam
+    );
+    let coarse = MockBackend::new().with_granularity(0x1000);
+    assert_ok!(set.map(area(0xb000, 0x1000, &coarse), &mut pt, false, None));
+    assert_err!(
+        set.remap_dontunmap(0xb000.into(), 0x1000, 0xc800.into(), &mut pt),
+        InvalidParam
+    );
+
+    backend.fail_at(Op::Move, 1);
+    assert_err!(
+        set.remap_dontunmap(0x1000.into(), 0x1000, 0xc000.into(), &mut pt),
+        BadState
+    );
+    assert!(set.find(0xc000.into()).is_none());
+    assert!(pt[0x1000..0x2000].iter().all(|&flags| flags == 1));
+    assert!(pt[0xc000..0xd000].iter().all(|&flags| flags == 0));
+
+    // So does a failed refill of the old range: the mappings move back.
+    backend.fail_at(Op::Map, 1);
+    assert_err!(
+        set.remap_dontunmap(0x1000.into(), 0x1000, 0xc000.into(), &mut pt),
+        BadState
+    );
+    assert!(set.find(0xc000.into()).is_none());
+    assert!(pt[0x1000..0x2000].iter().all(|&flags| flags == 1));
+    assert!(pt[0xc000..0xd000].iter().all(|&flags| flags == 0));
+    #[cfg(feature = "RAII")]
+    {
+        use memory_addr::FrameTracker;
+        assert_eq!(
+            set.find_frame(0x1000.into()).unwrap().start(),
+            frames[0].start()
+        );
+    }
+    set.check_invariants();
+}
+
+#[test]
+fn test_detach_reattach() {
+    use memory_addr::VirtAddrRange;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Map(VirtAddrRange, MockFlags),
+        Unmap(VirtAddrRange),
+    }
+
+    struct Recorder(Arc<Mutex<Vec<Event>>>);
+
+    impl MapObserver<MockBackend> for Recorder {
+        fn on_map(&mut self, range: VirtAddrRange, flags: MockFlags) {
+            self.0.lock().unwrap().push(Event::Map(range, flags));
+        }
+
+        fn on_unmap(&mut self, range: VirtAddrRange) {
+            self.0.lock().unwrap().push(Event::Unmap(range));
+        }
+    }
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut set = MockMemorySet::new().with_observer(Recorder(events.clone()));
+    let take_events = || core::mem::take(&mut *events.lock().unwrap());
+    let committed = Arc::new(AtomicUsize::new(0));
+    let check = committed.clone();
+    set.set_commit_check(move |bytes| {
+        check.fetch_add(bytes, Ordering::SeqCst);
+        true
+    });
+    let release = committed.clone();
+    set.set_commit_release(move |bytes| {
+        release.fetch_sub(bytes, Ordering::SeqCst);
+    });
+    let committed = move || committed.load(Ordering::SeqCst);
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_granularity(0x1000).with_merging();
+    let area = |start: usize, size, flags| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            flags,
+            backend.clone(),
+        )
+    };
+    assert_ok!(set.map(area(0, 0x4000, 1), &mut pt, false, None));
+    assert_ok!(set.map(area(0x4000, 0x2000, 2), &mut pt, false, None));
+    #[cfg(feature = "RAII")]
+    let frame = {
+        use crate::test_utils::TestFrame;
+        use memory_addr::FrameTracker;
+        let frame = Arc::new(TestFrame::alloc_frame());
+        set.insert_frame(0x1000.into(), frame.clone());
+        frame
+    };
+    let ranges = |set: &MockMemorySet| set.iter().map(|area| area.va_range()).collect::<Vec<_>>();
+    let charge = committed();
+    take_events();
+
+    // The parts within the range are unmapped but keep their frames and
+    // charge.
+    let detached = set.detach(0x1000.into(), 0x4000, &mut pt).unwrap();
+    let detached_ranges: Vec<_> = detached
+        .areas()
+        .iter()
+        .map(|area| area.va_range())
+        .collect();
+    assert_eq!(
+        detached_ranges,
+        [va_range!(0x1000..0x4000), va_range!(0x4000..0x5000)]
+    );
+    assert_eq!(
+        ranges(&set),
+        [va_range!(0..0x1000), va_range!(0x5000..0x6000)]
+    );
+    assert!(pt[0x1000..0x5000].iter().all(|&entry| entry == 0));
+    #[cfg(feature = "RAII")]
+    assert_eq!(Arc::strong_count(&frame), 2);
+    assert_eq!(committed(), charge);
+    assert_eq!(
+        take_events(),
+        [
+            Event::Unmap(va_range!(0x1000..0x4000)),
+            Event::Unmap(va_range!(0x4000..0x5000)),
+        ]
+    );
+
+    // Nothing is put back over a new mapping.
+    assert_ok!(set.map(area(0x2000, 0x1000, 3), &mut pt, false, None));
+    let (err, detached) = set.reattach(detached, &mut pt).unwrap_err();
+    assert!(matches!(err, MappingError::AlreadyExists(_)));
+    assert_ok!(set.unmap(0x2000.into(), 0x1000, &mut pt));
+    take_events();
+
+    // Reattaching maps the areas again with their frames, merged back.
+    assert!(set.reattach(detached, &mut pt).is_ok());
+    assert_eq!(
+        ranges(&set),
+        [va_range!(0..0x4000), va_range!(0x4000..0x6000)]
+    );
+    assert!(pt[0x1000..0x4000].iter().all(|&entry| entry == 1));
+    assert!(pt[0x4000..0x5000].iter().all(|&entry| entry == 2));
+    #[cfg(feature = "RAII")]
+    assert!(Arc::ptr_eq(&set.find_frame(0x1000.into()).unwrap(), &frame));
+    assert_eq!(committed(), set.commit_charge());
+    assert_eq!(
+        take_events(),
+        [
+            Event::Map(va_range!(0x1000..0x4000), 1),
+            Event::Map(va_range!(0x4000..0x5000), 2),
+        ]
+    );
+
+    // Failing to unmap the second area puts the first one back.
+    backend.fail_at(Op::Unmap, 2);
+    assert_err!(set.detach(0.into(), 0x6000, &mut pt), BadState);
+    assert_eq!(set.len(), 2);
+    assert!(pt[..0x6000].iter().all(|&entry| entry != 0));
+    assert!(take_events().is_empty());
+
+    // Destroying releases the frames and the charge, sealed areas stay.
+    let detached = set.detach(0x4000.into(), 0x1000, &mut pt).unwrap();
+    detached.destroy();
+    assert_eq!(committed(), set.commit_charge());
+    assert_ok!(set.seal(0.into(), 0x1000));
+    assert_err!(set.detach(0.into(), 0x2000, &mut pt), PermissionDenied);
+    let detached = set.detach(0x1000.into(), 0x3000, &mut pt).unwrap();
+    drop(detached);
+    #[cfg(feature = "RAII")]
+    assert_eq!(Arc::strong_count(&frame), 1);
+    assert_eq!(committed(), set.commit_charge());
+    set.check_invariants();
+}
+
+#[test]
+fn test_clear_range() {
+    use crate::test_utils::Op;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new();
+    let area = |start: usize, size: usize| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            1,
+            backend.clone(),
+        )
+    };
+    for (start, size) in [
+        (0, 0x2000),
+        (0x2000, 0x2000),
+        (0x4000, 0x2000),
+        (0x7000, 0x2000),
+    ] {
+        assert_ok!(set.map(area(start, size), &mut pt, false, None));
+    }
+    assert_ok!(set.add_hole(area(0x6000, 0x1000)));
+    #[cfg(feature = "RAII")]
+    {
+        use crate::test_utils::TestFrame;
+        use memory_addr::FrameTracker;
+        set.insert_frame(0x4000.into(), std::sync::Arc::new(TestFrame::alloc_frame()));
+    }
+
+    // Only the areas inside the range go, the crossing ones and holes stay.
+    assert_ok!(set.clear_range(va_range!(0x1000..0x8000), &mut pt));
+    assert_eq!(
+        set.iter().map(|area| area.va_range()).collect::<Vec<_>>(),
+        [
+            va_range!(0..0x2000),
+            va_range!(0x6000..0x7000),
+            va_range!(0x7000..0x9000)
+        ]
+    );
+    assert!(pt[..0x2000].iter().all(|&flags| flags == 1));
+    assert!(pt[0x2000..0x6000].iter().all(|&flags| flags == 0));
+    assert!(pt[0x7000..0x9000].iter().all(|&flags| flags == 1));
+    assert_eq!(set.total_size(), 0x4000);
+    assert_eq!(set.commit_charge(), 0x4000);
+    #[cfg(feature = "RAII")]
+    assert!(set.find_frame(0x4000.into()).is_none());
+    let limit = va_range!(0..MAX_ADDR);
+    assert_eq!(
+        set.find_free_area(0.into(), 0x4000, limit),
+        Some(0x2000.into())
+    );
+    set.check_invariants();
+
+    // Empty and vacant ranges clear nothing.
+    assert_ok!(set.clear_range(va_range!(0x1000..0x1000), &mut pt));
+    assert_ok!(set.clear_range(va_range!(0x2000..0x6000), &mut pt));
+    assert_eq!(set.len(), 3);
+
+    // Sealed areas block only if they would be removed.
+    assert_ok!(set.map(area(0xa000, 0x2000), &mut pt, false, None));
+    assert_ok!(set.seal(0xa000.into(), 0x1000));
+    assert_err!(
+        set.clear_range(va_range!(0x9000..0xb000), &mut pt),
+        PermissionDenied
+    );
+    assert!(set.find(0xa000.into()).is_some());
+    assert_ok!(set.clear_range(va_range!(0xa800..0xc000), &mut pt));
+    assert!(set.find(0xb000.into()).is_none());
+    assert!(set.find(0xa000.into()).unwrap().is_sealed());
+
+    // A failure keeps the failing area and those after it.
+    for start in [0xc000, 0xd000, 0xe000] {
+        assert_ok!(set.map(area(start, 0x1000), &mut pt, false, None));
+    }
+    backend.fail_at(Op::Unmap, 2);
+    assert_err!(
+        set.clear_range(va_range!(0xc000..0xf000), &mut pt),
+        BadState
+    );
+    assert!(set.find(0xc000.into()).is_none());
+    assert!(set.find(0xd000.into()).is_some());
+    assert!(set.find(0xe000.into()).is_some());
+    assert!(pt[0xd000..0xf000].iter().all(|&flags| flags == 1));
+    set.check_invariants();
+}
+
+#[test]
+fn test_interleave() {
+    use crate::InterleavePolicy;
+
+    assert!(InterleavePolicy::new(vec![0, 0]).is_none());
+    assert!(InterleavePolicy::new(vec![]).is_none());
+    assert!(InterleavePolicy::uniform(0).is_none());
+    let mut policy = InterleavePolicy::new(vec![2, 0, 1]).unwrap();
+    let picked: Vec<_> = (0..6).map(|_| policy.next_source()).collect();
+    assert_eq!(picked, [0, 0, 2, 0, 0, 2]);
+    assert_eq!(policy.pages_per_source(), [4, 0, 2]);
+    // Excluded sources are skipped from the start too.
+    let mut policy = InterleavePolicy::new(vec![0, 3]).unwrap();
+    assert!((0..4).all(|_| policy.next_source() == 1));
+    let mut policy = InterleavePolicy::uniform(3).unwrap();
+    let picked: Vec<_> = (0..4).map(|_| policy.next_source()).collect();
+    assert_eq!(picked, [0, 1, 2, 0]);
+
+    // Populating an area picks the source of each page from its policy.
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new();
+    let area = |start: usize, size: usize| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            1,
+            backend.clone(),
+        )
+    };
+    let mut interleaved = area(0, 0x6000);
+    interleaved.set_interleave(InterleavePolicy::new(vec![2, 1]));
+    assert_ok!(set.map(interleaved, &mut pt, false, None));
+    assert_ok!(set.map(area(0x6000, 0x2000), &mut pt, false, None));
+    let populated = set.populate(0.into(), 0x8000, 1, &mut pt).unwrap();
+    assert_eq!(populated.pages, 8);
+    assert_eq!(backend.sources(), [0, 0, 1, 0, 0, 1]);
+    let policy = set.find(0.into()).unwrap().interleave().unwrap();
+    assert_eq!(policy.pages_per_source(), [4, 2]);
+    assert!(set.find(0x6000.into()).unwrap().interleave().is_none());
+
+    // Faults go on where the policy left off.
+    assert_ok!(set.handle_page_fault(0x1000.into(), 1, &mut pt));
+    assert_ok!(set.handle_page_fault(0x2000.into(), 1, &mut pt));
+    assert_eq!(backend.sources()[6..], [0, 0]);
+
+    // The parts of a split area keep the weights, with fresh statistics.
+    assert_ok!(set.unmap(0x3000.into(), 0x1000, &mut pt));
+    let left = set.find(0.into()).unwrap().interleave().unwrap();
+    assert_eq!(left.weights(), [2, 1]);
+    assert_eq!(left.pages_per_source(), [6, 2]);
+    let right = set.find(0x4000.into()).unwrap().interleave().unwrap();
+    assert_eq!(right.weights(), [2, 1]);
+    assert_eq!(right.pages_per_source(), [0, 0]);
+}
+
+#[test]
+fn test_mpu() {
+    use crate::MpuConstraints;
+
+    let mpu = MpuConstraints::new(3, 0x1000);
+    assert!(mpu.is_valid_region(va_range!(0x2000..0x4000)));
+    assert!(mpu.is_valid_region(va_range!(0..0x8000)));
+    assert!(!mpu.is_valid_region(va_range!(0x1000..0x3000)));
+    assert!(!mpu.is_valid_region(va_range!(0..0x3000)));
+    assert!(!mpu.is_valid_region(va_range!(0..0x800)));
+    assert!(!mpu.is_valid_region(va_range!(0x1000..0x1000)));
+
+    let mut set = MockMemorySet::with_mpu(mpu);
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_eq!(set.mpu_constraints(), Some(mpu));
+    assert_eq!(MockMemorySet::new().mpu_constraints(), None);
+
+    // Every area must be a valid region, and there are at most 3 of them.
+    assert_err!(
+        set.map(new_area(0x1000.into(), 0x2000, 1), &mut pt, false, None),
+        InvalidParam
+    );
+    assert_err!(
+        set.map(new_area(0.into(), 0x3000, 1), &mut pt, false, None),
+        InvalidParam
+    );
+    assert_ok!(set.map(new_area(0.into(), 0x1000, 1), &mut pt, false, None));
+    assert_ok!(set.map(new_area(0x2000.into(), 0x2000, 1), &mut pt, false, None));
+    assert_ok!(set.map(new_area(0x4000.into(), 0x4000, 1), &mut pt, false, None));
+    // Adjacent regions with the same flags are not coalesced.
+    assert_eq!(set.len(), 3);
+    assert_err!(
+        set.map(new_area(0x8000.into(), 0x1000, 1), &mut pt, false, None),
+        LimitExceeded
+    );
+    assert!(set.find(0x8000.into()).is_none());
+    // Replacing a region frees it first.
+    assert_ok!(set.map(new_area(0x4000.into(), 0x4000, 2), &mut pt, true, None));
+    assert_err!(
+        set.map(new_area(0x2000.into(), 0x1000, 2), &mut pt, true, None),
+        InvalidParam
+    );
+
+    // Nothing may split a region.
+    assert_err!(set.unmap(0x2000.into(), 0x1000, &mut pt), InvalidParam);
+    assert_err!(
+        set.protect(0x5000.into(), 0x1000, |_| Some(3), &mut pt),
+        InvalidParam
+    );
+    assert_err!(set.seal(0x4000.into(), 0x2000), InvalidParam);
+    assert_err!(set.lock(0x4000.into(), 0x2000), InvalidParam);
+    assert_err!(set.split_off(0x5000.into()), InvalidParam);
+    assert_eq!(set.len(), 3);
+    assert_eq!(
+        set.find(0x4000.into()).unwrap().va_range(),
+        va_range!(0x4000..0x8000)
+    );
+    assert!(pt[0x4000..0x8000].iter().all(|&flags| flags == 2));
+
+    // Whole regions can be changed, and resized to valid regions only.
+    assert_ok!(set.protect(0x4000.into(), 0x4000, |_| Some(3), &mut pt));
+    assert!(pt[0x4000..0x8000].iter().all(|&flags| flags == 3));
+    assert_err!(
+        set.adjust_area(0x2000.into(), 0x2000.into(), 0x3800.into(), &mut pt),
+        InvalidParam
+    );
+    assert_ok!(set.unmap(0x2000.into(), 0x2000, &mut pt));
+    assert_ok!(set.adjust_area(0.into(), 0.into(), 0x2000.into(), &mut pt));
+    assert_eq!(
+        set.find(0x1000.into()).unwrap().va_range(),
+        va_range!(0..0x2000)
+    );
+    let right = set.split_off(0x4000.into()).unwrap();
+    assert_eq!(right.mpu_constraints(), Some(mpu));
+    assert_eq!((set.len(), right.len()), (1, 1));
+    set.check_invariants();
+}
+
+#[test]
+fn test_replace_range() {
+    use crate::test_utils::Op;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new();
+    let area = |start: usize, size, flags| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            flags,
+            backend.clone(),
+        )
+    };
+    assert_ok!(set.map(area(0x1000, 0x2000, 1), &mut pt, false, None));
+    assert_ok!(set.map(area(0x3000, 0x2000, 2), &mut pt, false, None));
+    #[cfg(feature = "RAII")]
+    let frame = {
+        use crate::test_utils::TestFrame;
+        use memory_addr::FrameTracker;
+        let frame = std::sync::Arc::new(TestFrame::alloc_frame());
+        set.insert_frame(0x2000.into(), frame.clone());
+        frame
+    };
+    let before = pt.clone();
+    let ranges = |set: &MockMemorySet| set.iter().map(|area| area.va_range()).collect::<Vec<_>>();
+
+    // A failure to unmap the second overlapped part maps the first one
+    // back, and a failure to map the new area maps both back, with their
+    // frames. The areas stay split at the boundaries.
+    backend.fail_at(Op::Unmap, 2);
+    assert_err!(
+        set.replace_range(area(0x2000, 0x2000, 3), &mut pt),
+        BadState
+    );
+    assert_eq!(pt, before);
+    backend.fail_at(Op::Map, 1);
+    assert_err!(
+        set.replace_range(area(0x2000, 0x2000, 3), &mut pt),
+        BadState
+    );
+    assert_eq!(pt, before);
+    assert_eq!(
+        ranges(&set),
+        [
+            va_range!(0x1000..0x2000),
+            va_range!(0x2000..0x3000),
+            va_range!(0x3000..0x4000),
+            va_range!(0x4000..0x5000),
+        ]
+    );
+    #[cfg(feature = "RAII")]
+    {
+        use memory_addr::FrameTracker;
+        let found = set.find_frame(0x2000.into()).unwrap();
+        assert_eq!(found.start(), frame.start());
+    }
+    set.check_invariants();
+
+    // The parts in the range are replaced, the others are kept.
+    assert_ok!(set.replace_range(area(0x2000, 0x2000, 3), &mut pt));
+    assert!(pt[0x1000..0x2000].iter().all(|&flags| flags == 1));
+    assert!(pt[0x2000..0x4000].iter().all(|&flags| flags == 3));
+    assert!(pt[0x4000..0x5000].iter().all(|&flags| flags == 2));
+    assert_eq!(
+        set.find(0x2000.into()).unwrap().va_range(),
+        va_range!(0x2000..0x4000)
+    );
+    #[cfg(feature = "RAII")]
+    assert_eq!(std::sync::Arc::strong_count(&frame), 1);
+    set.check_invariants();
+
+    // Guards are never replaced, neither those of the areas left around nor
+    // by the guards of the new area.
+    let stack = area(0x8000, 0x2000, 1).with_guards(0x1000, 0);
+    assert_ok!(set.map(stack, &mut pt, false, None));
+    assert_err!(
+        set.replace_range(area(0x6000, 0x2000, 3), &mut pt),
+        AlreadyExists
+    );
+    let guarded = area(0xb000, 0x1000, 3).with_guards(0x2000, 0);
+    assert_err!(set.replace_range(guarded, &mut pt), AlreadyExists);
+    assert!(pt[0x6000..0x8000].iter().all(|&flags| flags == 0));
+    assert!(pt[0xb000..0xc000].iter().all(|&flags| flags == 0));
+
+    // But an area can replace one with the same guards, or a part of it
+    // leaving its guard alone.
+    let stack = area(0x8000, 0x2000, 4).with_guards(0x1000, 0);
+    assert_ok!(set.replace_range(stack, &mut pt));
+    assert!(pt[0x8000..0xa000].iter().all(|&flags| flags == 4));
+    assert_ok!(set.replace_range(area(0x9000, 0x1000, 5), &mut pt));
+    assert_eq!(set.find(0x8000.into()).unwrap().guards(), (0x1000, 0));
+    assert!(pt[0x9000..0xa000].iter().all(|&flags| flags == 5));
+    set.check_invariants();
+}
+
+#[test]
+fn test_replace_user_image() {
+    use crate::test_utils::Op;
+    use memory_addr::VirtAddrRange;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Map(VirtAddrRange, MockFlags),
+        Unmap(VirtAddrRange),
+    }
+
+    struct Recorder(Arc<Mutex<Vec<Event>>>);
+
+    impl MapObserver<MockBackend> for Recorder {
+        fn on_map(&mut self, range: VirtAddrRange, flags: MockFlags) {
+            self.0.lock().unwrap().push(Event::Map(range, flags));
+        }
+
+        fn on_unmap(&mut self, range: VirtAddrRange) {
+            self.0.lock().unwrap().push(Event::Unmap(range));
+        }
+    }
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let committed = Arc::new(AtomicUsize::new(0));
+    let mut set = MockMemorySet::new().with_observer(Recorder(events.clone()));
+    let check = committed.clone();
+    set.set_commit_check(move |bytes| {
+        check.fetch_add(bytes, Ordering::SeqCst);
+        true
+    });
+    let release = committed.clone();
+    set.set_commit_release(move |bytes| {
+        release.fetch_sub(bytes, Ordering::SeqCst);
+    });
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new();
+    let area = |start: usize, size: usize, flags: MockFlags| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            flags,
+            backend.clone(),
+        )
+    };
+    let user = va_range!(0..0x8000);
+    assert_ok!(set.map(area(0x1000, 0x2000, 1), &mut pt, false, None));
+    assert_ok!(set.map(area(0x4000, 0x1000, 1), &mut pt, false, None));
+    assert_ok!(set.map(area(0xc000, 0x1000, 3), &mut pt, false, None));
+    events.lock().unwrap().clear();
+
+    // The new image is charged and reported, the old one released.
+    let image = [area(0x2000, 0x3000, 2), area(0x6000, 0x1000, 2)];
+    assert_ok!(set.replace_user_image(user, image, &mut pt));
+    assert_eq!(
+        core::mem::take(&mut *events.lock().unwrap()),
+        [
+            Event::Unmap(va_range!(0x1000..0x3000)),
+            Event::Unmap(va_range!(0x4000..0x5000)),
+            Event::Map(va_range!(0x2000..0x5000), 2),
+            Event::Map(va_range!(0x6000..0x7000), 2),
+        ]
+    );
+    assert_eq!(committed.load(Ordering::SeqCst), 0x5000);
+    assert!(pt[0x1000..0x2000].iter().all(|&entry| entry == 0));
+    assert!(pt[0x2000..0x5000].iter().all(|&entry| entry == 2));
+
+    // A failure maps the old image again and refunds the new one.
+    backend.fail_at(Op::Map, 2);
+    let image = [area(0, 0x1000, 4), area(0x7000, 0x1000, 4)];
+    assert_err!(set.replace_user_image(user, image, &mut pt), BadState);
+    assert!(events.lock().unwrap().is_empty());
+    assert_eq!(committed.load(Ordering::SeqCst), 0x5000);
+    assert_eq!(pt[0], 0);
+    assert!(pt[0x2000..0x5000].iter().all(|&entry| entry == 2));
+    assert_eq!(set.len(), 3);
+
+    // So does failing to roll back, which is reported instead.
+    backend.fail_at(Op::Map, 2);
+    backend.fail_at(Op::Unmap, 3);
+    let image = [area(0, 0x1000, 4), area(0x7000, 0x1000, 4)];
+    let err = set.replace_user_image(user, image, &mut pt).unwrap_err();
+    let context = err.context().unwrap();
+    assert_eq!((context.op, context.step), ("replace_user_image", "unmap"));
+    assert_eq!(context.area, addr_range!(0usize..0x1000));
+    assert_eq!(set.len(), 3);
+    assert_eq!(committed.load(Ordering::SeqCst), 0x5000);
+}
+
+#[test]
+fn test_adjust_area() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0x2000.into(), 0x2000, 1), &mut pt, false, None));
+    assert_ok!(set.map(new_area(0x8000.into(), 0x2000, 2), &mut pt, false, None));
+
+    // No area starts there.
+    assert_err!(
+        set.adjust_area(0x3000.into(), 0x3000.into(), 0x5000.into(), &mut pt),
+        NotMapped
+    );
+    // The new range must overlap the old one.
+    assert_err!(
+        set.adjust_area(0x2000.into(), 0x5000.into(), 0x6000.into(), &mut pt),
+        InvalidParam
+    );
+    // Growing into a neighbor fails and leaves both areas untouched.
+    assert_err!(
+        set.adjust_area(0x2000.into(), 0x2000.into(), 0x9000.into(), &mut pt),
+        AlreadyExists
+    );
+    assert_eq!(
+        set.find(0x2000.into()).unwrap().va_range(),
+        va_range!(0x2000..0x4000)
+    );
+    assert!(pt[0x4000..0x8000].iter().all(|&entry| entry == 0));
+
+    // Moving the start re-keys the area.
+    assert_ok!(set.adjust_area(0x2000.into(), 0x1000.into(), 0x6000.into(), &mut pt));
+    let area = set.find(0x5000.into()).unwrap();
+    assert_eq!(area.va_range(), va_range!(0x1000..0x6000));
+    assert!(pt[0x1000..0x6000].iter().all(|&entry| entry == 1));
+    assert_ok!(set.adjust_area(0x1000.into(), 0x3000.into(), 0x8000.into(), &mut pt));
+    assert_eq!(set.find(0x7000.into()).unwrap().start(), 0x3000.into());
+    assert!(set.find(0x2000.into()).is_none());
+    assert!(pt[0x1000..0x3000].iter().all(|&entry| entry == 0));
+    assert!(pt[0x3000..0x8000].iter().all(|&entry| entry == 1));
+    assert_eq!(set.len(), 2);
+    set.check_invariants();
+}
+
+#[test]
+fn test_size_limit() {
+    use crate::RemapFlags;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    set.set_size_limit(Some(0x4000));
+
+    assert_ok!(set.map(new_area(0.into(), 0x2000, 1), &mut pt, false, None));
+    assert_ok!(set.map(new_area(0x4000.into(), 0x1000, 1), &mut pt, false, None));
+    assert_err!(
+        set.map(new_area(0x8000.into(), 0x2000, 1), &mut pt, false, None),
+        QuotaExceeded
+    );
+    // Replacing counts the replaced part as freed.
+    assert_ok!(set.map(new_area(0x1000.into(), 0x2000, 2), &mut pt, true, None));
+    assert_eq!(set.total_size(), 0x4000);
+
+    // Growing fails, shrinking and moving within the limit do not.
+    assert_err!(
+        set.remap(0x4000.into(), 0x1000, 0x2000, RemapFlags::new(), &mut pt),
+        QuotaExceeded
+    );
+    assert_err!(
+        set.adjust_area(0x4000.into(), 0x4000.into(), 0x6000.into(), &mut pt),
+        QuotaExceeded
+    );
+    assert_ok!(set.unmap(0.into(), 0x1000, &mut pt));
+    assert_ok!(set.remap(0x4000.into(), 0x1000, 0x2000, RemapFlags::new(), &mut pt));
+    assert_eq!(set.total_size(), 0x4000);
+    assert_eq!(set.size_limit(), Some(0x4000));
+}
+
+#[test]
+fn test_stat() {
+    use crate::MemorySetStat;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    #[cfg(not(feature = "RAII"))]
+    let backend = MockBackend::new().with_granularity(0x1000);
+    #[cfg(feature = "RAII")]
+    let backend = MockBackend::new()
+        .with_granularity(0x1000)
+        .with_zero_frame();
+    let area = |start: usize, size| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            1,
+            backend.clone(),
+        )
+    };
+    assert_eq!(set.stat(), MemorySetStat::default());
+    assert_ok!(set.map(area(0, 0x4000), &mut pt, false, None));
+    assert_ok!(set.map(area(0x6000, 0x2000), &mut pt, false, None));
+    assert_ok!(set.add_hole(area(0xa000, 0x3000)));
+    assert_ok!(set.lock(0x6000.into(), 0x1000));
+
+    // Holes are left out, locked parts are counted by area.
+    let stat = set.stat();
+    assert_eq!(stat.size, 0x6000);
+    assert_eq!(stat.locked, 0x1000);
+    assert_eq!(stat.areas, 3);
+    assert_eq!(stat.rss, 0);
+    assert_eq!(
+        stat.size,
+        set.iter()
+            .filter(|area| !area.is_hole())
+            .map(|area| area.stat().size)
+            .sum::<usize>()
+    );
+
+    // Zero pages are not resident, frames mapped twice are shared.
+    #[cfg(feature = "RAII")]
+    {
+        use crate::test_utils::TestFrame;
+        use memory_addr::FrameTracker;
+        use std::sync::Arc;
+
+        assert_ok!(set.handle_page_fault(0x1000.into(), 1, &mut pt));
+        let frame = Arc::new(TestFrame::alloc_frame());
+        set.insert_frame(0x2000.into(), frame.clone());
+        set.insert_frame(0x7000.into(), frame);
+        set.insert_frame(0x3000.into(), Arc::new(TestFrame::alloc_frame()));
+        let stat = set.stat();
+        assert_eq!(stat.rss, 0x3000);
+        assert_eq!(stat.shared, 0x2000);
+        assert_eq!(stat.swap, 0);
+        assert_eq!(
+            stat.rss,
+            set.iter().map(|area| area.stat().rss).sum::<usize>()
+        );
+    }
+
+    // Unlocking and unmapping update the totals.
+    assert_ok!(set.unlock(0x6000.into(), 0x1000));
+    assert_ok!(set.unmap(0x2000.into(), 0x1000, &mut pt));
+    let stat = set.stat();
+    assert_eq!((stat.size, stat.locked, stat.areas), (0x5000, 0, 4));
+    #[cfg(feature = "RAII")]
+    assert_eq!((stat.rss, stat.shared), (0x2000, 0));
+}
+
+#[test]
+fn test_holes() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new();
+    let hole = MemoryArea::new(
+        0x2000.into(),
+        0x2000,
+        #[cfg(feature = "RAII")]
+        None,
+        0,
+        backend.clone(),
+    );
+    assert_ok!(set.add_hole(hole));
+    assert_ok!(set.map(new_area(0.into(), 0x1000, 1), &mut pt, false, None));
+    assert_eq!(backend.calls(Op::Map), 0);
+
+    // The hole blocks placement and mapping.
+    let addr = set.find_free_area(0.into(), 0x2000, va_range!(0..MAX_ADDR));
+    assert_eq!(addr, Some(0x4000.into()));
+    assert_err!(
+        set.map(new_area(0x3000.into(), 0x1000, 1), &mut pt, true, None),
+        PermissionDenied
+    );
+    assert_err!(
+        set.commit(0x2000.into(), 0x1000, 1, &mut pt),
+        PermissionDenied
+    );
+    assert_err!(set.unmap(0x2000.into(), 0x1000, &mut pt), PermissionDenied);
+
+    // But it is not part of the statistics and dumps.
+    assert_eq!(set.stat().areas, 1);
+    assert_eq!(set.total_size(), 0x1000);
+    let mut maps = String::new();
+    set.dump_maps(&mut maps).unwrap();
+    assert_eq!(maps.lines().count(), 1);
+    assert_eq!(set.holes().collect::<Vec<_>>(), [va_range!(0x2000..0x4000)]);
+    // The label of the set comes first.
+    set.set_label(7);
+    let mut labeled = String::new();
+    set.dump_maps(&mut labeled).unwrap();
+    assert_eq!(labeled, format!("# #7\n{maps}"));
+
+    // Clearing keeps it, releasing removes it.
+    assert_ok!(set.clear(&mut pt));
+    assert_eq!(set.holes().count(), 1);
+    assert_err!(set.release_hole(0x3000.into()), NotMapped);
+    assert_ok!(set.release_hole(0x2000.into()));
+    assert!(set.is_empty());
+    let addr = set.find_free_area(0x1000.into(), 0x2000, va_range!(0..MAX_ADDR));
+    assert_eq!(addr, Some(0x1000.into()));
+}
+
+#[test]
+fn test_dump_maps() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let dump = |set: &MockMemorySet| {
+        let mut maps = String::new();
+        set.dump_maps(&mut maps).unwrap();
+        maps
+    };
+    assert_eq!(dump(&set), "");
+
+    let mut heap = new_area(0x1000.into(), 0x3000, 1);
+    heap.set_label(Some("[heap]".into()));
+    assert_ok!(set.map(heap, &mut pt, false, None));
+    assert_ok!(set.map(new_area(0x5000.into(), 0x1000, 3), &mut pt, false, None));
+    assert_ok!(set.add_hole(new_area(0x6000.into(), 0x1000, 0)));
+    // Both parts of a split area keep the label.
+    assert_ok!(set.unmap(0x2000.into(), 0x1000, &mut pt));
+    assert_eq!(
+        dump(&set),
+        "00001000-00002000 1 00000000 unknown [heap]\n\
+         00003000-00004000 1 00000000 unknown [heap]\n\
+         00005000-00006000 3 00000000 unknown\n"
+    );
+
+    // File mappings show the offset of each part in the file.
+    #[cfg(feature = "mmap")]
+    {
+        use crate::MmapObject;
+        use crate::test_utils::TestFrame;
+        use std::sync::Arc;
+
+        struct File;
+        impl MmapObject<MockBackend> for File {
+            fn page(&self, _offset: usize) -> Option<Arc<TestFrame>> {
+                None
+            }
+        }
+
+        let file = MemoryArea::new_file(
+            0x8000.into(),
+            0x2000,
+            Arc::new(File),
+            0x3000,
+            false,
+            1,
+            MockBackend::new(),
+        );
+        assert_ok!(set.map(file, &mut pt, false, None));
+        assert_ok!(set.protect(0x9000.into(), 0x1000, |_| Some(3), &mut pt));
+        let maps = dump(&set);
+        let file_lines: Vec<_> = maps.lines().skip(3).collect();
+        assert_eq!(
+            file_lines,
+            [
+                "00008000-00009000 1 00003000 unknown",
+                "00009000-0000a000 3 00004000 unknown",
+            ]
+        );
+    }
+}
+
+#[test]
+fn test_layout_json() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_eq!(
+        set.layout_json().to_string(),
+        r#"{"label":null,"areas":[],"gaps":[]}"#
+    );
+
+    assert_ok!(set.map(new_area(0x1000.into(), 0x1000, 1), &mut pt, false, None));
+    assert_ok!(set.add_hole(new_area(0x2000.into(), 0x1000, 1)));
+    assert_ok!(set.map(new_area(0x5000.into(), 0x1000, 3), &mut pt, false, None));
+    assert_ok!(set.lock(0x5000.into(), 0x1000));
+    #[cfg(feature = "RAII")]
+    {
+        use crate::test_utils::TestFrame;
+        use memory_addr::FrameTracker;
+        set.insert_frame(0x5000.into(), std::sync::Arc::new(TestFrame::alloc_frame()));
+    }
+    set.set_label("a \"b\"\n");
+
+    // The hole is not an area but part of the gap, and the label is escaped.
+    #[cfg(feature = "RAII")]
+    let resident = [r#","resident":[]"#, r#","resident":[["0x5000","0x6000"]]"#];
+    #[cfg(not(feature = "RAII"))]
+    let resident = ["", ""];
+    let expected = format!(
+        concat!(
+            r#"{{"label":"a \"b\"\u000a","areas":["#,
+            r#"{{"start":"0x1000","end":"0x2000","flags":"1","kind":"unknown","#,
+            r#""locked":false,"write_protected":false{}}},"#,
+            r#"{{"start":"0x5000","end":"0x6000","flags":"3","kind":"unknown","#,
+            r#""locked":true,"write_protected":false{}}}"#,
+            r#"],"gaps":[["0x2000","0x5000"]]}}"#,
+        ),
+        resident[0], resident[1],
+    );
+    assert_eq!(set.layout_json().to_string(), expected);
+}
+
+#[test]
+fn test_unmap_ranges() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0.into(), 0x8000, 1), &mut pt, false, None));
+    assert_ok!(set.map(new_area(0x8000.into(), 0x8000, 2), &mut pt, false, None));
+
+    // Unsorted, overlapping and crossing ranges.
+    let ranges = [
+        va_range!(0xc000..0xd000),
+        va_range!(0x1000..0x2000),
+        va_range!(0x7000..0x9000),
+        va_range!(0x1800..0x3000),
+        va_range!(0x4000..0x4000),
+    ];
+    assert_ok!(set.unmap_ranges(ranges, &mut pt));
+    dump_memory_set(&set);
+    let left: Vec<_> = set.iter().map(|area| area.va_range()).collect();
+    assert_eq!(
+        left,
+        [
+            va_range!(0..0x1000),
+            va_range!(0x3000..0x7000),
+            va_range!(0x9000..0xc000),
+            va_range!(0xd000..0x10000),
+        ]
+    );
+    for (addr, &entry) in pt.iter().enumerate() {
+        let mapped = set.find(addr.into()).is_some();
+        assert_eq!(entry != 0, mapped);
+    }
+
+    // Nothing is unmapped if a range cannot be unmapped.
+    assert_ok!(set.seal(0x3000.into(), 0x1000));
+    let ranges = [va_range!(0..0x1000), va_range!(0x3800..0x3900)];
+    assert_err!(set.unmap_ranges(ranges, &mut pt), PermissionDenied);
+    assert_eq!(set.len(), 5);
+}
+
+#[test]
+fn test_unmap_failure_keeps_area() {
+    let backend = MockBackend::new();
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    for start in [0x1000, 0x4000] {
+        let area = MemoryArea::new(
+            start.into(),
+            0x2000,
+            #[cfg(feature = "RAII")]
+            None,
+            1,
+            backend.clone(),
+        );
+        assert_ok!(set.map(area, &mut pt, false, None));
+    }
+
+    // The area failing to unmap is kept along with its mappings, and the
+    // gap left by the other one can be reused.
+    backend.fail_at(Op::Unmap, 2);
+    assert_err!(set.unmap(0.into(), 0x8000, &mut pt), BadState);
+    set.check_invariants();
+    assert!(set.find(0x1000.into()).is_none());
+    assert_eq!(
+        set.find(0x4000.into()).unwrap().va_range(),
+        va_range!(0x4000..0x6000)
+    );
+    assert_eq!(pt[0x1000], 0);
+    assert_eq!(pt[0x4000], 1);
+    assert_eq!(
+        set.find_free_area(0.into(), 0x4000, va_range!(0..0x10000)),
+        Some(0.into())
+    );
+    assert_ok!(set.unmap(0.into(), 0x8000, &mut pt));
+    assert!(set.is_empty());
+
+    // Failing to unmap the middle of an area keeps both parts of it mapped.
+    let area = MemoryArea::new(
+        0x1000.into(),
+        0x4000,
+        #[cfg(feature = "RAII")]
+        None,
+        1,
+        backend.clone(),
+    );
+    assert_ok!(set.map(area, &mut pt, false, None));
+    backend.fail_at(Op::Unmap, 1);
+    assert_err!(set.unmap(0x2000.into(), 0x1000, &mut pt), BadState);
+    set.check_invariants();
+    assert_eq!(
+        set.iter().map(|area| area.va_range()).collect::<Vec<_>>(),
+        [va_range!(0x1000..0x3000), va_range!(0x3000..0x5000)]
+    );
+    assert_eq!(pt[0x3000], 1);
+    assert_eq!(pt[0x4000], 1);
+}
+
+#[test]
+fn test_unmap_boundaries() {
+    let backend = MockBackend::new();
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    for start in [0x1000, 0x4000] {
+        let area = MemoryArea::new(
+            start.into(),
+            0x2000,
+            #[cfg(feature = "RAII")]
+            None,
+            1,
+            backend.clone(),
+        );
+        assert_ok!(set.map(area, &mut pt, false, None));
+    }
+    let ranges = |set: &MockMemorySet| set.iter().map(|area| area.va_range()).collect::<Vec<_>>();
+
+    // Ranges in gaps or touching the areas only at their ends change nothing.
+    let calls = backend.calls(Op::Unmap);
+    assert_ok!(set.unmap(0x3000.into(), 0x1000, &mut pt));
+    assert_ok!(set.unmap(0.into(), 0x1000, &mut pt));
+    assert_ok!(set.unmap(0x6000.into(), 0x1000, &mut pt));
+    assert_eq!(backend.calls(Op::Unmap), calls);
+    assert_eq!(
+        ranges(&set),
+        [va_range!(0x1000..0x3000), va_range!(0x4000..0x6000)]
+    );
+
+    // Failing to shrink the area after the range keeps it whole, the area
+    // before is shrunk already.
+    backend.fail_at(Op::Unmap, 2);
+    assert_err!(set.unmap(0x2000.into(), 0x3000, &mut pt), BadState);
+    set.check_invariants();
+    assert_eq!(
+        ranges(&set),
+        [va_range!(0x1000..0x2000), va_range!(0x4000..0x6000)]
+    );
+    assert_eq!(pt[0x2000], 0);
+    assert!(pt[0x4000..0x6000].iter().all(|&flags| flags == 1));
+
+    // Same for the tail of the area before the range.
+    backend.fail_at(Op::Unmap, 1);
+    assert_err!(set.unmap(0x1800.into(), 0x800, &mut pt), BadState);
+    set.check_invariants();
+    assert_eq!(ranges(&set)[0], va_range!(0x1000..0x2000));
+    assert_eq!(pt[0x1800], 1);
+
+    // Ranges ending exactly at the ends of the areas shrink them.
+    assert_ok!(set.unmap(0x1800.into(), 0x800, &mut pt));
+    assert_ok!(set.unmap(0x4000.into(), 0x1000, &mut pt));
+    assert_eq!(
+        ranges(&set),
+        [va_range!(0x1000..0x1800), va_range!(0x5000..0x6000)]
+    );
+    assert!(pt[0x1800..0x5000].iter().all(|&flags| flags == 0));
+    assert_eq!(pt[0x5000], 1);
+    set.check_invariants();
+}
+
+#[test]
+fn test_protect_failure() {
+    use crate::test_utils::TestError;
+    use core::error::Error;
+
+    let backend = MockBackend::new();
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let area = MemoryArea::new(
+        0x1000.into(),
+        0x4000,
+        #[cfg(feature = "RAII")]
+        None,
+        1,
+        backend.clone(),
+    );
+    assert_ok!(set.map(area, &mut pt, false, None));
+
+    // The backend error is returned, and the parts split off are kept with
+    // their old flags.
+    backend.fail_at(Op::Protect, 1);
+    let err = set
+        .protect(0x2000.into(), 0x1000, |_| Some(3), &mut pt)
+        .unwrap_err();
+    assert_eq!(
+        err.source()
+            .unwrap()
+            .source()
+            .unwrap()
+            .downcast_ref::<TestError>(),
+        Some(&TestError(Op::Protect))
+    );
+    set.check_invariants();
+    let areas: Vec<_> = set
+        .iter()
+        .map(|area| (area.va_range(), area.flags()))
+        .collect();
+    assert_eq!(
+        areas,
+        [
+            (va_range!(0x1000..0x2000), 1),
+            (va_range!(0x2000..0x3000), 1),
+            (va_range!(0x3000..0x5000), 1),
+        ]
+    );
+    assert!(pt[0x1000..0x5000].iter().all(|&entry| entry == 1));
+}
+
+#[test]
+fn test_tlb_batch() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0.into(), 0x8000, 1), &mut pt, false, None));
+
+    let mut batch = TlbBatch::new(&mut pt);
+    assert!(batch.ranges().is_empty());
+    assert_ok!(set.unmap_batched(0x1000.into(), 0x1000, &mut batch));
+    assert_ok!(set.unmap_batched(0x4000.into(), 0, &mut batch));
+    let changed = set.protect_batched(0x2000.into(), 0x2000, |_| Some(2), &mut batch);
+    assert_eq!(changed.unwrap().len(), 1);
+    // Areas left unchanged are not recorded.
+    assert_ok!(set.protect_batched(0x2000.into(), 0x1000, |_| None, &mut batch));
+    assert_ok!(set.map_batched(
+        new_area(0x8000.into(), 0x1000, 1),
+        MapMode::NoReplace,
+        &mut batch
+    ));
+    assert_ok!(set.map_batched(
+        new_area(0x5000.into(), 0x1000, 3),
+        MapMode::Fixed,
+        &mut batch
+    ));
+    assert_eq!(
+        batch.ranges(),
+        [
+            va_range!(0x1000..0x2000),
+            va_range!(0x2000..0x4000),
+            va_range!(0x5000..0x6000),
+        ]
+    );
+    // Failures are recorded too, as parts may be unmapped already.
+    assert_ok!(set.seal(0x7000.into(), 0x1000));
+    assert_err!(
+        set.unmap_batched(0x6000.into(), 0x2000, &mut batch),
+        PermissionDenied
+    );
+    assert_eq!(batch.ranges().len(), 4);
+    batch.flush();
+    assert!(batch.ranges().is_empty());
+    drop(batch);
+
+    assert_eq!(pt[0x1000], 0);
+    assert_eq!(pt[0x2000], 2);
+    assert_eq!(pt[0x5000], 3);
+    assert_eq!(pt[0x8000], 1);
+}
+
+#[test]
+fn test_area_times() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0.into(), 0x1000, 1), &mut pt, false, None));
+    assert_eq!(set.find(0.into()).unwrap().times(), AreaTimes::default());
+
+    let clock = Arc::new(AtomicU64::new(10));
+    let now = clock.clone();
+    set.set_clock(move || now.load(Ordering::SeqCst));
+    assert_ok!(set.map(new_area(0x4000.into(), 0x4000, 1), &mut pt, false, None));
+    clock.store(20, Ordering::SeqCst);
+    assert_ok!(set.protect(0x6000.into(), 0x2000, |_| Some(2), &mut pt));
+    clock.store(30, Ordering::SeqCst);
+    assert_ok!(set.handle_page_fault(0x4000.into(), 1, &mut pt));
+
+    let left = set.find(0x4000.into()).unwrap().stat().times;
+    assert_eq!(left.created, Some(10));
+    assert_eq!(left.last_fault, Some(30));
+    assert_eq!(left.last_protect, None);
+    assert_eq!(left.last_active(), Some(30));
+    let right = set.find(0x6000.into()).unwrap().times();
+    assert_eq!(right.created, Some(10));
+    assert_eq!(right.last_fault, None);
+    assert_eq!(right.last_protect, Some(20));
+
+    // Idle areas can be found from the timestamps.
+    let idle: Vec<_> = set
+        .iter()
+        .filter(|area| area.times().last_active().is_none_or(|t| t + 15 < 40))
+        .map(|area| area.va_range())
+        .collect();
+    assert_eq!(idle, [va_range!(0..0x1000), va_range!(0x6000..0x8000)]);
+}
+
+#[test]
+fn test_stats_sampler() {
+    use crate::{SampledStats, StatsSampler};
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let tracked = MockBackend::new()
+        .with_granularity(0x1000)
+        .with_accessed_bits();
+    let untracked = MockBackend::new().with_granularity(0x1000);
+    let area = |start: usize, size, backend: &MockBackend| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            1,
+            backend.clone(),
+        )
+    };
+    assert_ok!(set.map(area(0, 0x4000, &tracked), &mut pt, false, None));
+    assert_ok!(set.map(area(0x6000, 0x2000, &untracked), &mut pt, false, None));
+    // Resident: [0, 0x2000) and [0x3000, 0x4000) with accessed bits, and
+    // [0x6000, 0x8000) without, in one frame if RAII is on.
+    #[cfg(feature = "RAII")]
+    {
+        use crate::test_utils::TestFrame;
+        use memory_addr::{FrameTracker, PhysAddr};
+        use std::sync::Arc;
+        for page in [0, 0x1000, 0x3000] {
+            set.insert_frame(page.into(), Arc::new(TestFrame::alloc_frame()));
+        }
+        let huge = Arc::new(TestFrame::no_tracking(PhysAddr::from(0x20_0000)));
+        let area = set.find_mut(0x6000.into()).unwrap();
+        area.insert_frame_sized(0x6000.into(), huge, 0x2000);
+    }
+    #[cfg(not(feature = "RAII"))]
+    {
+        use crate::MappingBackend;
+        assert!(tracked.collapse_huge(0.into(), 0x2000, 1, &mut pt));
+        assert!(tracked.collapse_huge(0x3000.into(), 0x1000, 1, &mut pt));
+        assert!(untracked.collapse_huge(0x6000.into(), 0x2000, 1, &mut pt));
+    }
+    tracked.touch(0x1800.into());
+    tracked.touch(0x3000.into());
+    untracked.touch(0x6000.into());
+
+    // Nothing is reported before the first pass is complete.
+    let mut sampler = StatsSampler::new();
+    assert_eq!(sampler.sample(&set, &mut pt, 0), SampledStats::default());
+    assert_eq!(sampler.sample(&set, &mut pt, 2), SampledStats::default());
+    assert_eq!(sampler.passes(), 0);
+
+    // The pass goes on where it stopped. The pages without accessed bits
+    // only count towards the RSS.
+    let stats = SampledStats {
+        rss: 0x5000,
+        working_set: 0x2000,
+        idle: 0x1000,
+    };
+    assert_eq!(sampler.sample(&set, &mut pt, 100), stats);
+    assert_eq!((sampler.passes(), sampler.stats()), (1, stats));
+    assert_eq!(sampler.sample(&set, &mut pt, 0), stats);
+
+    // The accessed bits were cleared by the previous pass.
+    tracked.touch(0.into());
+    let stats = SampledStats {
+        rss: 0x5000,
+        working_set: 0x1000,
+        idle: 0x2000,
+    };
+    assert_eq!(sampler.sample(&set, &mut pt, usize::MAX), stats);
+    assert_eq!(sampler.passes(), 2);
+}
+
+#[cfg(feature = "access-count")]
+#[test]
+fn test_access_count() {
+    use crate::HotCold;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0.into(), 0x8000, 1), &mut pt, false, None));
+    for _ in 0..3 {
+        assert_ok!(set.handle_page_fault(0x1234.into(), 1, &mut pt));
+        assert_ok!(set.handle_page_fault(0x2000.into(), 1, &mut pt));
+    }
+    assert_ok!(set.handle_page_fault(0x3000.into(), 1, &mut pt));
+    assert_ok!(set.handle_page_fault(0x5000.into(), 1, &mut pt));
+    for _ in 0..300 {
+        assert_ok!(set.handle_page_fault(0x6000.into(), 1, &mut pt));
+    }
+
+    let area = set.find(0.into()).unwrap();
+    assert_eq!(area.access_count(0x1000.into()), 3);
+    assert_eq!(area.access_count(0x4000.into()), 0);
+    assert_eq!(area.access_count(0x6fff.into()), 255);
+    assert_eq!(
+        area.hot_cold(2),
+        HotCold {
+            hot: vec![va_range!(0x1000..0x3000), va_range!(0x6000..0x7000)],
+            cold: vec![va_range!(0x3000..0x4000), va_range!(0x5000..0x6000)],
+        }
+    );
+
+    // Split areas keep the counts of their pages.
+    assert_ok!(set.protect(0x2000.into(), 0x2000, |_| Some(2), &mut pt));
+    let middle = set.find_mut(0x2000.into()).unwrap();
+    assert_eq!(middle.access_count(0x2000.into()), 3);
+    middle.age_access_counts();
+    assert_eq!(
+        middle.hot_cold(1),
+        HotCold {
+            hot: vec![va_range!(0x2000..0x3000)],
+            cold: vec![va_range!(0x3000..0x4000)],
+        }
+    );
+    assert_eq!(set.find(0.into()).unwrap().hot_cold(1).hot.len(), 1);
+
+    // The backend knows nothing about accessed bits.
+    assert_eq!(set.harvest_accessed(&mut pt), 0);
+}
+
+#[test]
+fn test_observer() {
+    use memory_addr::VirtAddrRange;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Map(VirtAddrRange, MockFlags),
+        Unmap(VirtAddrRange),
+        Protect(VirtAddrRange, MockFlags, MockFlags),
+        Split(VirtAddrRange, VirtAddr),
+    }
+
+    struct Recorder(Arc<Mutex<Vec<Event>>>);
+
+    impl MapObserver<MockBackend> for Recorder {
+        fn on_map(&mut self, range: VirtAddrRange, flags: MockFlags) {
+            self.0.lock().unwrap().push(Event::Map(range, flags));
+        }
+
+        fn on_unmap(&mut self, range: VirtAddrRange) {
+            self.0.lock().unwrap().push(Event::Unmap(range));
+        }
+
+        fn on_protect(&mut self, range: VirtAddrRange, old: MockFlags, new: MockFlags) {
+            self.0.lock().unwrap().push(Event::Protect(range, old, new));
+        }
+
+        fn on_split(&mut self, range: VirtAddrRange, pos: VirtAddr) {
+            self.0.lock().unwrap().push(Event::Split(range, pos));
+        }
+    }
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut set = MockMemorySet::new().with_observer(Recorder(events.clone()));
+    let mut pt = test_page_table(MAX_ADDR);
+    let take = || core::mem::take(&mut *events.lock().unwrap());
+
+    assert_ok!(set.map(new_area(0.into(), 0x4000, 1), &mut pt, false, None));
+    assert_eq!(take(), [Event::Map(va_range!(0..0x4000), 1)]);
+
+    assert_ok!(set.protect(0x1000.into(), 0x1000, |_| Some(2), &mut pt));
+    assert_eq!(
+        take(),
+        [
+            Event::Split(va_range!(0..0x4000), 0x2000.into()),
+            Event::Split(va_range!(0..0x2000), 0x1000.into()),
+            Event::Protect(va_range!(0x1000..0x2000), 1, 2),
+        ]
+    );
+
+    assert_ok!(set.unmap(0x1800.into(), 0x1000, &mut pt));
+    assert_eq!(
+        take(),
+        [
+            Event::Unmap(va_range!(0x1800..0x2000)),
+            Event::Unmap(va_range!(0x2000..0x2800)),
+        ]
+    );
+
+    // Failed operations are not reported.
+    assert_err!(
+        set.map(new_area(0x3000.into(), 0x1000, 1), &mut pt, false, None),
+        AlreadyExists
+    );
+    assert_ok!(set.map(new_area(0x3000.into(), 0x2000, 3), &mut pt, true, None));
+    assert_eq!(
+        take(),
+        [
+            Event::Split(va_range!(0x2800..0x4000), 0x3000.into()),
+            Event::Unmap(va_range!(0x3000..0x4000)),
+            Event::Map(va_range!(0x3000..0x5000), 3),
+        ]
+    );
+
+    let mut cursor = set.cursor_mut(0.into());
+    assert_ok!(cursor.split(0x800.into()));
+    assert_ok!(cursor.remove_current(&mut pt));
+    assert_eq!(
+        take(),
+        [
+            Event::Split(va_range!(0..0x1000), 0x800.into()),
+            Event::Unmap(va_range!(0..0x800)),
+        ]
+    );
+}
+
+#[cfg(feature = "bench")]
+#[test]
+fn test_journal_replay() {
+    use crate::bench::replay;
+    use crate::journal::{Journal, JournalEntry, format_journal, parse_journal};
+    use core::cell::Cell;
+
+    let journal = Journal::new();
+    let mut set = MockMemorySet::new().with_observer(journal.clone());
+    let mut pt = test_page_table(MAX_ADDR);
+    assert!(journal.is_empty());
+    assert_ok!(set.map(new_area(0.into(), 0x4000, 1), &mut pt, false, None));
+    assert_ok!(set.protect(0x1000.into(), 0x1000, |_| Some(3), &mut pt));
+    assert_ok!(set.map(new_area(0x3000.into(), 0x2000, 2), &mut pt, true, None));
+    assert_ok!(set.unmap(0.into(), 0x1000, &mut pt));
+    // Failed operations are not journaled.
+    assert_err!(set.unmap(usize::MAX.into(), 0x1000, &mut pt));
+    let entries = journal.entries();
+    assert_eq!(
+        entries,
+        [
+            JournalEntry::Map {
+                range: va_range!(0..0x4000),
+                flags: 1
+            },
+            JournalEntry::Protect {
+                range: va_range!(0x1000..0x2000),
+                old_flags: 1,
+                new_flags: 3
+            },
+            JournalEntry::Unmap {
+                range: va_range!(0x3000..0x4000)
+            },
+            JournalEntry::Map {
+                range: va_range!(0x3000..0x5000),
+                flags: 2
+            },
+            JournalEntry::Unmap {
+                range: va_range!(0..0x1000)
+            },
+        ]
+    );
+
+    // The textual form reads back, with comments and blank lines skipped.
+    let text = format_journal(&entries);
+    assert_eq!(
+        text.lines().take(2).collect::<Vec<_>>(),
+        ["map 0x0 0x4000 0x1", "protect 0x1000 0x2000 0x1 0x3"]
+    );
+    let parsed = parse_journal::<VirtAddr, MockFlags>(&format!("# boot\n\n{text}")).unwrap();
+    assert_eq!(parsed, entries);
+    assert_eq!(
+        parse_journal::<usize, u8>("map 0 1000 1\nmap 0 1000"),
+        Err(2)
+    );
+    assert_eq!(parse_journal::<usize, u8>("unmap 2000 1000"), Err(1));
+    assert_eq!(parse_journal::<usize, u8>("map 0 1000 100"), Err(1));
+    assert_eq!(parse_journal::<usize, u8>("unmap 0 1000 1"), Err(1));
+    assert_eq!(parse_journal::<usize, u8>("find 0 1000"), Err(1));
+
+    // Replaying times every entry as the operation that caused it.
+    let now = Cell::new(0);
+    let clock = || {
+        now.set(now.get() + 1);
+        now.get()
+    };
+    let report = replay(journal.take(), clock);
+    assert!(journal.is_empty());
+    assert_eq!((report.map.count, report.map.errors), (2, 0));
+    assert_eq!((report.unmap.count, report.unmap.errors), (2, 0));
+    assert_eq!((report.protect.count, report.protect.errors), (1, 0));
+    assert_eq!(
+        (report.map.min, report.map.max, report.map.mean()),
+        (1, 1, 1)
+    );
+    assert_eq!(report.map.percentile(99), 1);
+    assert!(report.to_string().starts_with("map "));
+    // Changes to vacant ranges replay fine, like unmapping them.
+    let report = replay(
+        parse_journal::<usize, u64>("unmap 0 1000\nprotect 0 1000 1 2").unwrap(),
+        clock,
+    );
+    assert_eq!(report.unmap.errors, 0);
+    assert_eq!(report.protect.errors, 0);
+    assert_eq!(report.map.count, 0);
+}
+
+#[test]
+fn test_dirty_log() {
+    use crate::test_utils::WRITE_ACCESS;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0.into(), 0x4000, 1), &mut pt, false, None));
+    assert_ok!(set.map(new_area(0x4000.into(), 0x2000, 2), &mut pt, false, None));
+    assert_err!(
+        set.write_protect_range(0x5000.into(), 0x2000, &mut pt),
+        NotMapped
+    );
+    assert_ok!(set.write_protect_range(0.into(), 0x6000, &mut pt));
+    assert!(
+        set.collect_dirty(0.into(), 0x6000, &mut pt)
+            .unwrap()
+            .is_empty()
+    );
+
+    // Reads are not recorded, writes are.
+    assert_ok!(set.handle_page_fault(0x1000.into(), 1, &mut pt));
+    for addr in [0x2000, 0x3800, 0x4000, 0x5000] {
+        assert_ok!(set.handle_page_fault(addr.into(), WRITE_ACCESS, &mut pt));
+    }
+    let log = set.collect_dirty(0.into(), 0x6000, &mut pt).unwrap();
+    assert_eq!(log.ranges, [va_range!(0x2000..0x6000)]);
+    assert_eq!(log.size(), 0x4000);
+
+    // Collecting starts over, and only covers the given range.
+    assert_ok!(set.handle_page_fault(0x2000.into(), WRITE_ACCESS, &mut pt));
+    assert_ok!(set.handle_page_fault(0x5000.into(), WRITE_ACCESS, &mut pt));
+    let log = set.collect_dirty(0x4000.into(), 0x2000, &mut pt).unwrap();
+    assert_eq!(log.ranges, [va_range!(0x5000..0x6000)]);
+    let log = set.collect_dirty(0.into(), 0x6000, &mut pt).unwrap();
+    assert_eq!(log.ranges, [va_range!(0x2000..0x3000)]);
+}
+
+#[test]
+fn test_mapping_kind() {
+    use crate::{Advice, MappingKind};
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let mmio = MockBackend::new().with_mapping_kind(MappingKind::Device);
+    assert_ok!(set.map(new_area(0.into(), 0x1000, 1), &mut pt, false, None));
+    assert_ok!(set.map(
+        MemoryArea::new(
+            0x1000.into(),
+            0x1000,
+            #[cfg(feature = "RAII")]
+            None,
+            1,
+            mmio,
+        ),
+        &mut pt,
+        false,
+        None
+    ));
+    assert_eq!(set.kind_at(0x800.into()), Some(MappingKind::Anonymous));
+    assert_eq!(set.kind_at(0x1800.into()), Some(MappingKind::Device));
+    assert_eq!(set.kind_at(0x2000.into()), None);
+
+    // Device memory is never discarded.
+    assert_ok!(set.advise(0.into(), 0x2000, Advice::WillNeed, &mut pt));
+    assert_err!(
+        set.advise(0.into(), 0x2000, Advice::DontNeed, &mut pt),
+        InvalidParam
+    );
+    assert_ok!(set.advise(0.into(), 0x1000, Advice::DontNeed, &mut pt));
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_advise() {
+    use crate::Advice;
+    use crate::test_utils::{Op, TestFrame, WRITE_ACCESS};
+    use memory_addr::FrameTracker;
+    use std::sync::Arc;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_zero_frame();
+    let area = |start: usize, size| MemoryArea::new(start.into(), size, None, 1, backend.clone());
+    assert_ok!(set.map(area(0, 0x4000), &mut pt, false, None));
+    assert_ok!(set.map(area(0x4000, 0x2000), &mut pt, false, None));
+    let frames: Vec<_> = (0..0x6000)
+        .step_by(0x1000)
+        .map(|page| {
+            let frame = Arc::new(TestFrame::alloc_frame());
+            set.insert_frame(page.into(), frame.clone());
+            frame
+        })
+        .collect();
+    let all = va_range!(0..0x6000);
+    assert_ok!(set.clear_soft_dirty(all, &mut pt));
+    assert_ok!(set.handle_page_fault(0x1000.into(), WRITE_ACCESS | 1, &mut pt));
+    assert_eq!(set.soft_dirty_pages(all), [0x1000.into()]);
+
+    // Nothing is done unless the whole range is covered.
+    assert_err!(
+        set.advise(0x4000.into(), 0x4000, Advice::DontNeed, &mut pt),
+        NotMapped
+    );
+    assert!(set.find_frame(0x4000.into()).is_some());
+
+    // The frames are dropped across areas, which stay whole and mapped, and
+    // so are the soft-dirty marks.
+    let maps = backend.calls(Op::Map);
+    assert_ok!(set.advise(0x1000.into(), 0x4000, Advice::DontNeed, &mut pt));
+    assert_eq!(set.len(), 2);
+    assert_eq!(backend.calls(Op::Map), maps + 2);
+    assert!(pt[0..0x6000].iter().all(|&flags| flags == 1));
+    for page in (0x1000..0x5000).step_by(0x1000) {
+        assert!(set.find_frame(page.into()).is_none());
+    }
+    assert_eq!(Arc::strong_count(&frames[2]), 1);
+    assert_eq!(set.find_frame(0.into()).unwrap().start(), frames[0].start());
+    assert_eq!(
+        set.find_frame(0x5000.into()).unwrap().start(),
+        frames[5].start()
+    );
+    assert!(set.soft_dirty_pages(all).is_empty());
+    // The next read maps the zero page.
+    assert_ok!(set.handle_page_fault(0x2000.into(), 1, &mut pt));
+    assert_eq!(set.find(0.into()).unwrap().zero_pages(), 1);
+
+    // Free drops the frames too, WillNeed keeps them.
+    assert_ok!(set.advise(0.into(), 0x1000, Advice::WillNeed, &mut pt));
+    assert!(set.find_frame(0.into()).is_some());
+    assert_ok!(set.advise(0.into(), 0x1000, Advice::Free, &mut pt));
+    assert!(set.find_frame(0.into()).is_none());
+
+    // Locked areas keep their frames.
+    assert_ok!(set.lock(0x5000.into(), 0x1000));
+    assert_err!(
+        set.advise(0x4000.into(), 0x2000, Advice::DontNeed, &mut pt),
+        InvalidParam
+    );
+    assert!(set.find_frame(0x5000.into()).is_some());
+
+    // A failure to map the range again is reported.
+    backend.fail_at(Op::Map, 1);
+    assert_err!(
+        set.advise(0x2000.into(), 0x1000, Advice::DontNeed, &mut pt),
+        BadState
+    );
+    set.check_invariants();
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_swap() {
+    use crate::test_utils::TestFrame;
+    use crate::{Advice, SwapBackend};
+    use memory_addr::FrameTracker;
+    use std::sync::{Arc, Mutex};
+
+    /// A swap holding at most `capacity` pages in memory.
+    struct MemSwap {
+        slots: Arc<Mutex<Vec<Option<Vec<u8>>>>>,
+        capacity: usize,
+    }
+
+    impl SwapBackend for MemSwap {
+        fn store(&self, data: &[u8]) -> Option<usize> {
+            let mut slots = self.slots.lock().unwrap();
+            if slots.iter().flatten().count() == self.capacity {
+                return None;
+            }
+            slots.push(Some(data.to_vec()));
+            Some(slots.len() - 1)
+        }
+
+        fn load(&self, slot: usize, data: &mut [u8]) {
+            data.copy_from_slice(self.slots.lock().unwrap()[slot].as_ref().unwrap());
+        }
+
+        fn free(&self, slot: usize) {
+            self.slots.lock().unwrap()[slot] = None;
+        }
+    }
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0.into(), 0x4000, 1), &mut pt, false, None));
+    for (i, page) in [0x1000, 0x2000, 0x3000].into_iter().enumerate() {
+        let mut frame = TestFrame::alloc_frame();
+        frame.as_mut_slice().fill(i as u8 + 1);
+        set.insert_frame(page.into(), Arc::new(frame));
+    }
+    assert_err!(set.swap_out(0.into(), 0x4000, &mut pt), BadState);
+
+    let slots = Arc::new(Mutex::new(Vec::new()));
+    set.set_swap(MemSwap {
+        slots: slots.clone(),
+        capacity: 2,
+    });
+    let used = || slots.lock().unwrap().iter().flatten().count();
+    // The swap is full after two pages.
+    assert_eq!(set.swap_out(0.into(), 0x4000, &mut pt).unwrap(), 2);
+    assert_eq!(used(), 2);
+    let area = set.find(0.into()).unwrap();
+    assert!(area.is_swapped(0x1800.into()));
+    assert!(!area.is_swapped(0x3000.into()));
+    assert_eq!(area.stat().swap, 0x2000);
+    assert_eq!(area.stat().rss, 0x1000);
+    assert_eq!(pt[0x1000], 0);
+
+    // Faults swap the pages back in.
+    assert_ok!(set.handle_page_fault(0x1004.into(), 1, &mut pt));
+    assert_eq!(used(), 1);
+    assert_eq!(pt[0x1000], 1);
+    let frame = set.find_frame(0x1000.into()).unwrap();
+    assert!(frame.as_slice().iter().all(|&byte| byte == 1));
+    assert_eq!(set.stat().swap, 0x1000);
+
+    // Split areas keep their swapped pages, and dropping them frees the
+    // slots.
+    assert_ok!(set.protect(0x2000.into(), 0x1000, |_| Some(2), &mut pt));
+    assert!(set.find(0x2000.into()).unwrap().is_swapped(0x2000.into()));
+    assert_eq!(set.find(0.into()).unwrap().stat().swap, 0);
+    drop(set);
+    assert_eq!(used(), 0);
+
+    // Discarding swapped-out pages frees their slots too.
+    let mut set = MockMemorySet::new();
+    set.set_swap(MemSwap {
+        slots: slots.clone(),
+        capacity: 2,
+    });
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0.into(), 0x2000, 1), &mut pt, false, None));
+    for page in [0, 0x1000] {
+        set.insert_frame(page.into(), Arc::new(TestFrame::alloc_frame()));
+    }
+    assert_eq!(set.swap_out(0.into(), 0x2000, &mut pt).unwrap(), 2);
+    assert_ok!(set.advise(0.into(), 0x1000, Advice::DontNeed, &mut pt));
+    assert!(!set.find(0.into()).unwrap().is_swapped(0.into()));
+    assert_eq!(used(), 1);
+}
+
+#[test]
+fn test_write_protect() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new();
+    #[cfg(feature = "RAII")]
+    let backend = backend.with_zero_frame();
+    let area = |start: usize, size| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            1,
+            backend.clone(),
+        )
+    };
+    assert_ok!(set.map(area(0x1000, 0x4000), &mut pt, false, None));
+    assert_ok!(set.reserve(area(0x8000, 0x2000)));
+
+    // With RAII, only the resident pages are visited, and [0x1000, 0x2000)
+    // maps the zero frame.
+    #[cfg(feature = "RAII")]
+    {
+        use crate::test_utils::TestFrame;
+        use memory_addr::FrameTracker;
+        use std::sync::Arc;
+
+        for page in [0x2000, 0x3000] {
+            set.insert_frame(page.into(), Arc::new(TestFrame::alloc_frame()));
+        }
+        assert_ok!(set.handle_page_fault(0x1000.into(), 1, &mut pt));
+    }
+    let calls = backend.write_protected().len();
+    let expected = if cfg!(feature = "RAII") {
+        vec![va_range!(0x1000..0x4000)]
+    } else {
+        vec![va_range!(0x1000..0x5000)]
+    };
+
+    // The entries are downgraded, but the logical flags are kept.
+    let area = set.find_mut(0x1000.into()).unwrap();
+    assert_ok!(area.write_protect(&mut pt));
+    assert!(area.is_write_protected());
+    assert_eq!(area.flags(), 1);
+    assert_eq!(backend.write_protected()[calls..], expected);
+
+    // Reserved areas have nothing to protect.
+    let reserved = set.find_mut(0x8000.into()).unwrap();
+    assert_ok!(reserved.write_protect(&mut pt));
+    assert!(reserved.is_write_protected());
+    assert_ok!(reserved.restore_write(&mut pt));
+    assert!(!reserved.is_write_protected());
+    assert_eq!(backend.write_protected().len(), calls + 1);
+    assert_eq!(backend.calls(Op::Protect), 0);
+    assert!(pt[0x8000..0xa000].iter().all(|&flags| flags == 0));
+
+    // A failure to restore the entries keeps the area write-protected.
+    pt[0x1000..0x5000].fill(3);
+    backend.fail_at(Op::Protect, 1);
+    let area = set.find_mut(0x1000.into()).unwrap();
+    assert_err!(area.restore_write(&mut pt), BadState);
+    assert!(area.is_write_protected());
+
+    // Restoring rewrites the entries with the stored flags, except for the
+    // zero page, which must still be copied on write.
+    assert_ok!(area.restore_write(&mut pt));
+    assert!(!area.is_write_protected());
+    assert!(pt[0x1000..0x5000].iter().all(|&flags| flags == 1));
+    #[cfg(feature = "RAII")]
+    {
+        use crate::test_utils::WRITE_ACCESS;
+        use memory_addr::FrameTracker;
+
+        assert_eq!(
+            backend.write_protected()[calls + 1..],
+            [va_range!(0x1000..0x2000)]
+        );
+        let zero = set.find_frame(0x1000.into()).unwrap();
+        assert_ok!(set.handle_page_fault(0x1000.into(), WRITE_ACCESS, &mut pt));
+        assert_ne!(set.find_frame(0x1000.into()).unwrap().start(), zero.start());
+    }
+    #[cfg(not(feature = "RAII"))]
+    assert_eq!(backend.write_protected().len(), calls + 1);
+    set.check_invariants();
+}
+
+#[test]
+fn test_soft_dirty() {
+    use crate::test_utils::WRITE_ACCESS;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new();
+    let area = MemoryArea::new(
+        0.into(),
+        0x4000,
+        #[cfg(feature = "RAII")]
+        None,
+        1,
+        backend.clone(),
+    );
+    assert_ok!(set.map(area, &mut pt, false, None));
+    #[cfg(feature = "RAII")]
+    for page in (0..0x4000).step_by(0x1000) {
+        use crate::test_utils::TestFrame;
+        use memory_addr::FrameTracker;
+        set.insert_frame(page.into(), std::sync::Arc::new(TestFrame::alloc_frame()));
+    }
+    let all = va_range!(0..0x4000);
+    let pages = |pages: &[usize]| -> Vec<VirtAddr> { pages.iter().map(|&p| p.into()).collect() };
+
+    // Every page is dirty until the marks are cleared.
+    assert_eq!(
+        set.soft_dirty_pages(all),
+        pages(&[0, 0x1000, 0x2000, 0x3000])
+    );
+    assert!(set.find(0.into()).unwrap().is_soft_dirty(0x2800.into()));
+
+    // The pages partially within the range are cleared, the others stay
+    // dirty.
+    assert_ok!(set.clear_soft_dirty(va_range!(0x1800..0x2800), &mut pt));
+    assert_eq!(set.soft_dirty_pages(all), pages(&[0, 0x3000]));
+    assert_eq!(backend.write_protected(), [va_range!(0x1000..0x3000)]);
+    let area = set.find(0.into()).unwrap();
+    assert!(area.is_write_protected());
+    assert!(!area.is_soft_dirty(0x1000.into()));
+    assert_eq!(area.flags(), 1);
+
+    // Writes are recorded, reads are not.
+    assert_ok!(set.handle_page_fault(0x1800.into(), 1, &mut pt));
+    assert_eq!(set.soft_dirty_pages(all), pages(&[0, 0x3000]));
+    assert_ok!(set.handle_page_fault(0x1800.into(), WRITE_ACCESS | 1, &mut pt));
+    assert_eq!(set.soft_dirty_pages(all), pages(&[0, 0x1000, 0x3000]));
+    assert_eq!(
+        set.soft_dirty_pages(va_range!(0x1800..0x2000)),
+        pages(&[0x1000])
+    );
+
+    // The marks follow the pieces of a split area, and protecting keeps the
+    // pages faulting on write.
+    assert_ok!(set.unmap(0x2000.into(), 0x1000, &mut pt));
+    assert_eq!(set.soft_dirty_pages(all), pages(&[0, 0x1000, 0x3000]));
+    assert_ok!(set.protect(0.into(), 0x4000, |_| Some(3), &mut pt));
+    assert!(set.iter().all(|area| area.is_write_protected()));
+    assert_ok!(set.handle_page_fault(0x3000.into(), WRITE_ACCESS | 1, &mut pt));
+
+    // Clearing everything leaves nothing dirty, and empty ranges are ignored.
+    assert_ok!(set.clear_soft_dirty(all, &mut pt));
+    assert!(set.soft_dirty_pages(all).is_empty());
+    assert_ok!(set.clear_soft_dirty(va_range!(0x8000..0x9000), &mut pt));
+    set.check_invariants();
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_zero_page() {
+    use crate::test_utils::WRITE_ACCESS;
+    use memory_addr::FrameTracker;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_zero_frame();
+    let area = MemoryArea::new(0.into(), 0x4000, None, 1, backend);
+    assert_ok!(set.map(area, &mut pt, false, None));
+
+    // Reads share the zero frame, which is not counted in the RSS.
+    assert_ok!(set.handle_page_fault(0x1000.into(), 1, &mut pt));
+    assert_ok!(set.handle_page_fault(0x2000.into(), 1, &mut pt));
+    let zero = set.find_frame(0x1000.into()).unwrap();
+    assert_eq!(zero.start(), set.find_frame(0x2000.into()).unwrap().start());
+    let area = set.find(0.into()).unwrap();
+    assert_eq!(area.zero_pages(), 2);
+    assert_eq!(area.stat().rss, 0);
+    assert_eq!(area.commit_charge(), 0x4000);
+
+    // Writes copy them into private frames.
+    assert_ok!(set.handle_page_fault(0x1000.into(), WRITE_ACCESS, &mut pt));
+    let frame = set.find_frame(0x1000.into()).unwrap();
+    assert_ne!(frame.start(), zero.start());
+    assert!(frame.as_slice().iter().all(|&byte| byte == 0));
+    let area = set.find(0.into()).unwrap();
+    assert_eq!(area.zero_pages(), 1);
+    assert_eq!(area.stat().rss, 0x1000);
+    assert_eq!(area.commit_charge(), 0x3000);
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_shared_area() {
+    use crate::test_utils::WRITE_ACCESS;
+    use crate::{Advice, SharedFrames};
+    use memory_addr::FrameTracker;
+
+    let object = SharedFrames::<MockBackend>::new(0x3000);
+    assert_eq!(object.size(), 0x3000);
+    let mut set1 = MockMemorySet::new();
+    let mut pt1 = test_page_table(MAX_ADDR);
+    let mut set2 = MockMemorySet::new();
+    let mut pt2 = test_page_table(MAX_ADDR);
+    let area = MemoryArea::new_shared(
+        0x1000.into(),
+        0x3000,
+        object.clone(),
+        0,
+        1,
+        MockBackend::new(),
+    );
+    assert_ok!(set1.map(area, &mut pt1, false, None));
+    // Mapped lazily at another address and offset in the second set.
+    let area = MemoryArea::new_shared(
+        0x8000.into(),
+        0x2000,
+        object.clone(),
+        0x1000,
+        1,
+        MockBackend::new(),
+    );
+    assert_ok!(set2.insert(area, false));
+    assert_ok!(set2.handle_page_fault(0x9000.into(), WRITE_ACCESS | 1, &mut pt2));
+    let frame = set2.find_frame(0x9000.into()).unwrap();
+    assert_eq!(
+        frame.start(),
+        set1.find_frame(0x3000.into()).unwrap().start()
+    );
+    assert_eq!(frame.start(), object.frame(0x2000).unwrap().start());
+
+    // Splitting keeps the offsets, and the parts merge back.
+    assert_ok!(set1.protect(0x2000.into(), 0x1000, |_| Some(3), &mut pt1));
+    let area = set1.find(0x3000.into()).unwrap();
+    assert_eq!(area.shared_object().unwrap().1, 0x2000);
+    assert!(area.is_shared());
+
+    // Writes in a copy-on-write clone stay shared.
+    let mut pt3 = test_page_table(MAX_ADDR);
+    let mut set3 = set1.clone_cow(&mut pt1, &mut pt3).unwrap();
+    assert_ok!(set3.handle_page_fault(0x1000.into(), WRITE_ACCESS | 1, &mut pt3));
+    assert_eq!(
+        set3.find_frame(0x1000.into()).unwrap().start(),
+        object.frame(0).unwrap().start()
+    );
+
+    // Discarded pages lose their soft-dirty marks, and are faulted in again
+    // from the object.
+    assert_ok!(set3.clear_soft_dirty(va_range!(0x1000..0x4000), &mut pt3));
+    assert_ok!(set3.handle_page_fault(0x1000.into(), WRITE_ACCESS | 1, &mut pt3));
+    assert_eq!(
+        set3.soft_dirty_pages(va_range!(0x1000..0x4000)),
+        [0x1000.into()]
+    );
+    assert_ok!(set3.advise(0x1000.into(), 0x1000, Advice::DontNeed, &mut pt3));
+    assert!(set3.soft_dirty_pages(va_range!(0x1000..0x4000)).is_empty());
+    assert!(set3.find_frame(0x1000.into()).is_none());
+    assert_eq!(pt3[0x1000], 0);
+    assert_ok!(set3.handle_page_fault(0x1000.into(), 1, &mut pt3));
+    assert_eq!(
+        set3.find_frame(0x1000.into()).unwrap().start(),
+        object.frame(0).unwrap().start()
+    );
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_thp_promotion() {
+    use crate::{Advice, ThpPolicy};
+    use memory_addr::{AddrRange, FrameTracker};
+    use std::sync::Arc;
+
+    let huge = 0x4000;
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new();
+    // Three pages of the first huge region and one of the second are
+    // resident.
+    let frames = [0x0, 0x1000, 0x3000, 0x4000]
+        .into_iter()
+        .map(|vaddr| {
+            (
+                vaddr.into(),
+                Arc::new(crate::test_utils::TestFrame::alloc_frame()),
+            )
+        })
+        .collect();
+    let area = MemoryArea::new(0.into(), 0xc000, Some(frames), 1, backend.clone());
+    assert_ok!(set.map(area, &mut pt, false, None));
+
+    assert_eq!(set.thp_policy(), ThpPolicy::Never);
+    assert_eq!(set.promote_huge_pages(huge, 3, &mut pt), 0);
+
+    // Only advised areas are promoted with `Madvise`.
+    set.set_thp_policy(ThpPolicy::Madvise);
+    assert_eq!(set.promote_huge_pages(huge, 3, &mut pt), 0);
+    assert_ok!(set.advise(0.into(), 0x8000, Advice::HugePage, &mut pt));
+    assert_eq!(set.find(0x8000.into()).unwrap().thp_advice(), None);
+    assert_eq!(set.promote_huge_pages(huge, 3, &mut pt), 1);
+    assert_eq!(
+        backend.collapsed(),
+        [AddrRange::from_start_size(0.into(), huge)]
+    );
+    // Regions already huge are skipped.
+    assert_eq!(set.promote_huge_pages(huge, 1, &mut pt), 1);
+    assert_eq!(backend.collapsed().len(), 2);
+
+    // `NoHugePage` opts out of `Always`.
+    set.set_thp_policy(ThpPolicy::Always);
+    assert_ok!(set.advise(0x8000.into(), 0x4000, Advice::NoHugePage, &mut pt));
+    assert_eq!(set.promote_huge_pages(huge, 0, &mut pt), 0);
+    assert_ok!(set.advise(0x8000.into(), 0x4000, Advice::HugePage, &mut pt));
+    assert_eq!(set.promote_huge_pages(huge, 0, &mut pt), 1);
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_file_mapping() {
+    use crate::MmapObject;
+    use crate::test_utils::{TestFrame, WRITE_ACCESS};
+    use memory_addr::FrameTracker;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    /// A two-page file whose bytes are their page numbers, with a page cache.
+    #[derive(Default)]
+    struct File {
+        cache: Mutex<BTreeMap<usize, Arc<TestFrame>>>,
+        synced: Mutex<Vec<(usize, usize)>>,
+    }
+
+    impl MmapObject<MockBackend> for File {
+        fn page(&self, offset: usize) -> Option<Arc<TestFrame>> {
+            if offset >= 0x2000 {
+                return None;
+            }
+            let mut cache = self.cache.lock().unwrap();
+            let frame = cache.entry(offset).or_insert_with(|| {
+                let mut frame = TestFrame::alloc_frame();
+                frame.as_mut_slice().fill((offset / 0x1000) as u8);
+                Arc::new(frame)
+            });
+            Some(frame.clone())
+        }
+
+        fn sync(&self, offset: usize, size: usize) {
+            self.synced.lock().unwrap().push((offset, size));
+        }
+    }
+
+    let file = Arc::new(File::default());
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let shared = MemoryArea::new_file(
+        0x1000.into(),
+        0x2000,
+        file.clone(),
+        0,
+        true,
+        1,
+        MockBackend::new(),
+    );
+    assert_ok!(set.map(shared, &mut pt, false, None));
+    let private = MemoryArea::new_file(
+        0x3000.into(),
+        0x2000,
+        file.clone(),
+        0x1000,
+        false,
+        1,
+        MockBackend::new(),
+    );
+    assert_ok!(set.map(private, &mut pt, false, None));
+
+    // Shared mappings map the page cache, private ones copy it.
+    assert_ok!(set.handle_page_fault(0x2000.into(), WRITE_ACCESS | 1, &mut pt));
+    assert_ok!(set.handle_page_fault(0x3000.into(), WRITE_ACCESS | 1, &mut pt));
+    let cached = file.page(0x1000).unwrap();
+    assert_eq!(
+        set.find_frame(0x2000.into()).unwrap().start(),
+        cached.start()
+    );
+    let copy = set.find_frame(0x3000.into()).unwrap();
+    assert_ne!(copy.start(), cached.start());
+    assert!(copy.as_slice().iter().all(|&byte| byte == 1));
+    // Past the end of the file.
+    assert_err!(set.handle_page_fault(0x4000.into(), 1, &mut pt), BadState);
+
+    let area = set.find(0x3000.into()).unwrap();
+    assert!(!area.is_shared());
+    assert_eq!(area.file_object().unwrap().1, 0x1000);
+    assert!(set.find(0x1000.into()).unwrap().is_shared());
+
+    // Only the shared part of the range is written back.
+    assert_ok!(set.msync(0x2000.into(), 0x3000));
+    assert_err!(set.msync(0x2000.into(), 0x6000), NotMapped);
+    assert_eq!(*file.synced.lock().unwrap(), [(0x1000, 0x1000)]);
+}
+
+#[test]
+fn test_validate_request() {
+    use crate::{MapMode, RequestLimits, RequestMode};
+    use memory_addr::AddrRange;
+
+    let mut set = MockMemorySet::new();
+    set.set_request_limits(RequestLimits {
+        min_addr: 0x1000,
+        max_end: 0x10000,
+        ..RequestLimits::new(0x1000)
+    });
+
+    // Lengths are rounded up to whole pages.
+    let req = set
+        .validate_request(0x2000.into(), 0x1800, 3, RequestMode::Fixed)
+        .unwrap();
+    assert_eq!(req.start, Some(0x2000.into()));
+    assert_eq!(req.size, 0x2000);
+    assert_eq!(req.flags, 3);
+    assert_eq!(req.mode, MapMode::Fixed);
+    let req = set
+        .validate_request(0x2000.into(), 0x1000, 1, RequestMode::FixedNoReplace)
+        .unwrap();
+    assert_eq!(
+        req.mode,
+        MapMode::FixedNoReplace {
+            limit: AddrRange::new(0x1000.into(), 0x10000.into()),
+            align: 0x1000,
+        }
+    );
+
+    let fixed = |addr: usize, len| set.validate_request(addr.into(), len, 1, RequestMode::Fixed);
+    assert_err!(fixed(0x2000, 0), InvalidParam);
+    assert_err!(fixed(0x2000, usize::MAX), OutOfRange);
+    assert_err!(fixed(0x2100, 0x1000), Misaligned);
+    assert_err!(fixed(0, 0x1000), PermissionDenied);
+    assert_err!(fixed(0xf000, 0x2000), OutOfRange);
+    assert_err!(fixed(usize::MAX & !0xfff, 0x2000), OutOfRange);
+    assert_ok!(fixed(0xf000, 0x1000));
+
+    // Bad hints are dropped rather than rejected.
+    let hint = |addr: usize| {
+        set.validate_request(addr.into(), 0x1000, 1, RequestMode::Hint)
+            .unwrap()
+    };
+    assert_eq!(hint(0x3000).start, Some(0x3000.into()));
+    assert_eq!(hint(0x3000).mode, MapMode::NoReplace);
+    assert_eq!(hint(0).start, None);
+    assert_eq!(hint(0x3100).start, None);
+    assert_eq!(hint(0x10000).start, None);
+}
+
+#[test]
+fn test_map_page_size() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new();
+    let huge = |start: usize, size| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            1,
+            backend.clone(),
+        )
+        .with_map_page_size(0x4000)
+    };
+
+    // The area must be aligned to its page size.
+    assert_err!(
+        set.map(huge(0x2000, 0x4000), &mut pt, false, None),
+        InvalidParam
+    );
+    assert_err!(
+        set.map(huge(0x4000, 0x2000), &mut pt, false, None),
+        InvalidParam
+    );
+    assert_eq!(backend.last_page_size(), 0);
+    assert_ok!(set.map(huge(0x4000, 0x8000), &mut pt, false, None));
+    assert_eq!(backend.last_page_size(), 0x4000);
+    let area = set.find(0x4000.into()).unwrap();
+    assert_eq!(area.granularity(), 0x4000);
+    assert_eq!(area.map_page_size(), Some(0x4000));
+
+    // So must be the ranges splitting it, while lengths are rounded up.
+    assert_err!(
+        set.protect(0x6000.into(), 0x2000, |_| Some(3), &mut pt),
+        InvalidParam
+    );
+    assert_ok!(set.protect(0x8000.into(), 0x1000, |_| Some(3), &mut pt));
+    assert_eq!(
+        set.find(0x8000.into()).unwrap().va_range(),
+        va_range!(0x8000..0xc000)
+    );
+    assert_eq!(
+        set.find(0x8000.into()).unwrap().map_page_size(),
+        Some(0x4000)
+    );
+    assert_err!(
+        set.adjust_area(0x4000.into(), 0x4000.into(), 0x6000.into(), &mut pt),
+        InvalidParam
+    );
+}
+
+#[cfg(feature = "latency")]
+#[test]
+fn test_latency() {
+    use crate::{LatencyHistogram, LatencyOp, LatencySummary};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    let mut hist = LatencyHistogram::new();
+    assert_eq!(hist.summary(), LatencySummary::default());
+    for cycles in 1..=100 {
+        hist.record(cycles);
+    }
+    assert_eq!(hist.count(), 100);
+    assert_eq!(hist.mean(), 50);
+    // 50 falls in [32, 64), 99 in [64, 128) capped by the max.
+    assert_eq!(hist.percentile(500), 63);
+    assert_eq!(hist.percentile(990), 100);
+    assert_eq!(hist.percentile(0), 1);
+
+    // Each read of the counter advances it by 10 cycles.
+    let cycles = Arc::new(AtomicU64::new(0));
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0x1000.into(), 0x1000, 1), &mut pt, false, None));
+    assert_eq!(set.latency(LatencyOp::Map).count(), 0);
+    let counter = cycles.clone();
+    set.set_cycle_counter(move || counter.fetch_add(10, Ordering::SeqCst));
+    assert_ok!(set.map(new_area(0x2000.into(), 0x1000, 1), &mut pt, false, None));
+    assert_ok!(set.protect(0x1000.into(), 0x1000, |_| Some(3), &mut pt));
+    assert_ok!(set.unmap(0x1000.into(), 0x2000, &mut pt));
+    // Failed operations are measured too.
+    assert_err!(set.unmap(usize::MAX.into(), 0x1000, &mut pt), InvalidParam);
+    assert_eq!(set.latency(LatencyOp::Map).summary().max, 10);
+    assert_eq!(set.latency(LatencyOp::Protect).count(), 1);
+    assert_eq!(set.latency(LatencyOp::Unmap).count(), 2);
+    set.reset_latency();
+    assert_eq!(set.latency(LatencyOp::Unmap).count(), 0);
+}
+
+#[test]
+fn test_error_context_chain() {
+    use crate::test_utils::TestError;
+    use core::error::Error;
+
+    let backend = MockBackend::new();
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let area = MemoryArea::new(
+        0x1000.into(),
+        0x4000,
+        #[cfg(feature = "RAII")]
+        None,
+        1,
+        backend.clone(),
+    );
+    assert_ok!(set.map(area, &mut pt, false, None));
+
+    // The failing step and area are kept, with the backend error below.
+    backend.fail_at(Op::Unmap, 1);
+    let err = set.unmap(0x4000.into(), 0x1000, &mut pt).unwrap_err();
+    assert_eq!(
+        err,
+        MappingError::BadState(addr_range!(0x4000usize..0x5000), None)
+    );
+    let context = err.context().unwrap();
+    assert_eq!((context.op, context.step), ("unmap", "shrink"));
+    assert_eq!(context.area, addr_range!(0x1000usize..0x5000));
+    let source = err.source().unwrap().source().unwrap();
+    assert_eq!(
+        source.downcast_ref::<TestError>(),
+        Some(&TestError(Op::Unmap))
+    );
+    assert_eq!(
+        err.to_string(),
+        "BadState at [0x4000, 0x5000): unmap failed to shrink area [0x1000, 0x5000): \
+         test backend: Unmap failed"
+    );
+
+    // The label of the set is part of the context.
+    set.set_label("init");
+    backend.fail_at(Op::Unmap, 1);
+    let err = set.unmap(0x3000.into(), 0x1000, &mut pt).unwrap_err();
+    let context = err.context().unwrap();
+    assert_eq!(
+        context.owner.as_ref().map(ToString::to_string).as_deref(),
+        Some("init")
+    );
+    assert_eq!(
+        err.to_string(),
+        "BadState at [0x3000, 0x4000): init: unmap failed to shrink area [0x1000, 0x5000): \
+         test backend: Unmap failed"
+    );
+
+    // Errors other than `BadState` carry no context.
+    let err = set.unmap(usize::MAX.into(), 0x1000, &mut pt).unwrap_err();
+    assert!(err.context().is_none());
+    let err = MappingError::NotMapped(addr_range!(0usize..1)).with_context(
+        "unmap",
+        "shrink",
+        addr_range!(0usize..1),
+    );
+    assert!(err.context().is_none());
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_frame_map_thresholds() {
+    use crate::FrameMap;
+    use crate::test_utils::TestFrame;
+    use memory_addr::{FrameTracker, PhysAddr};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    let frame = |page: usize| Arc::new(TestFrame::no_tracking(PhysAddr::from(page * 0x1000)));
+    let page = |index: usize| VirtAddr::from(index * 0x1000);
+    let mut frames = FrameMap::<MockBackend>::new();
+
+    // Dense once 64 frames span at most 4/3 of their number of pages.
+    for index in (0..128).step_by(2) {
+        frames.insert(page(index), frame(index));
+    }
+    assert_eq!(frames.len(), 64);
+    assert!(!frames.is_dense());
+    for index in (1..62).step_by(2) {
+        frames.insert(page(index), frame(index));
+        assert!(!frames.is_dense(), "dense at {} frames", frames.len());
+    }
+    assert_eq!(frames.len(), 95);
+    frames.insert(page(63), frame(63));
+    assert!(frames.is_dense());
+
+    // Sparse again below 1/4 of the slots, or 32 frames.
+    let mut frames: FrameMap<MockBackend> =
+        (0..100).map(|index| (page(index), frame(index))).collect();
+    assert!(frames.is_dense());
+    for index in 32..100 {
+        frames.remove(&page(index));
+    }
+    assert!(frames.is_dense());
+    frames.remove(&page(0));
+    assert!(!frames.is_dense());
+    let mut frames: FrameMap<MockBackend> =
+        (0..200).map(|index| (page(index), frame(index))).collect();
+    for index in 0..150 {
+        frames.remove(&page(index * 4 / 3));
+    }
+    assert_eq!(frames.len(), 50);
+    assert!(frames.is_dense());
+    frames.remove(&page(199));
+    assert!(!frames.is_dense());
+
+    // Fewer than 64 frames stay sparse however dense.
+    let frames: FrameMap<MockBackend> = (0..63).map(|index| (page(index), frame(index))).collect();
+    assert!(!frames.is_dense());
+
+    // The array grows up and down to nearby pages, far ones make it sparse.
+    let mut frames: FrameMap<MockBackend> = (100..200)
+        .map(|index| (page(index), frame(index)))
+        .collect();
+    assert!(frames.is_dense());
+    frames.insert(page(250), frame(250));
+    frames.insert(page(60), frame(60));
+    assert!(frames.is_dense());
+    assert_eq!(
+        frames.get(&page(60)).unwrap().start(),
+        PhysAddr::from(60 * 0x1000)
+    );
+    assert!(frames.get(&page(61)).is_none());
+    assert!(frames.get(&VirtAddr::from(0x60800)).is_none());
+    frames.insert(page(10_000), frame(10_000));
+    assert!(!frames.is_dense());
+    assert_eq!(frames.len(), 103);
+
+    // Both representations behave like a tree.
+    let mut model = BTreeMap::new();
+    let mut frames = FrameMap::<MockBackend>::new();
+    let mut seed = 1usize;
+    let mut dense_seen = [false; 2];
+    for step in 0..4000 {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        let index = (seed >> 33) % if step < 2000 { 160 } else { 1000 };
+        if (seed >> 20).is_multiple_of(if step < 2000 { 5 } else { 2 }) {
+            assert_eq!(
+                frames.remove(&page(index)).map(|frame| frame.start()),
+                model
+                    .remove(&index)
+                    .map(|index| PhysAddr::from(index * 0x1000))
+            );
+        } else {
+            let old = frames.insert(page(index), frame(index + step));
+            assert_eq!(
+                old.map(|frame| frame.start()),
+                model
+                    .insert(index, index + step)
+                    .map(|index| PhysAddr::from(index * 0x1000))
+            );
+        }
+        dense_seen[frames.is_dense() as usize] = true;
+        if step.is_multiple_of(97) {
+            let expected: Vec<_> = model
+                .iter()
+                .map(|(&index, &frame)| (index, frame))
+                .collect();
+            let got: Vec<_> = frames
+                .iter()
+                .map(|(vaddr, frame)| {
+                    (vaddr.as_usize() / 0x1000, frame.start().as_usize() / 0x1000)
+                })
+                .collect();
+            assert_eq!(got, expected);
+            assert_eq!(frames.len(), model.len());
+            let (low, high) = (index.saturating_sub(20), index + 20);
+            let got: Vec<_> = frames
+                .range(page(low)..=page(high))
+                .rev()
+                .map(|(vaddr, _)| vaddr.as_usize() / 0x1000)
+                .collect();
+            let expected: Vec<_> = model
+                .range(low..=high)
+                .rev()
+                .map(|(&index, _)| index)
+                .collect();
+            assert_eq!(got, expected);
+            // Splitting and appending back keeps everything.
+            let right = frames.split_off(&page(index));
+            assert!(
+                right
+                    .iter()
+                    .all(|(vaddr, _)| vaddr.as_usize() >= index * 0x1000)
+            );
+            assert_eq!(frames.len() + right.len(), model.len());
+            frames.append(&mut { right });
+            assert_eq!(frames.len(), model.len());
+        }
+    }
+    assert_eq!(dense_seen, [true, true]);
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_mixed_frame_sizes() {
+    use memory_addr::{FrameTracker, PhysAddr};
+    use std::sync::Arc;
+
+    use crate::test_utils::TestFrame;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0.into(), 0x10000, 1), &mut pt, false, None));
+    let huge_pa = PhysAddr::from(0x80_0000);
+    let area = set.find_mut(0.into()).unwrap();
+    area.insert_frame_sized(0.into(), Arc::new(TestFrame::no_tracking(huge_pa)), 0x4000);
+    area.insert_frame_sized(
+        0x8000.into(),
+        Arc::new(TestFrame::no_tracking(huge_pa + 0x8000)),
+        0x8000,
+    );
+    area.insert_frame(0x4000.into(), Arc::new(TestFrame::alloc_frame()));
+    assert_eq!(area.frames_count(), 3);
+    assert_eq!(area.resident_size(), 0xd000);
+    assert_eq!(
+        area.resident_ranges().collect::<Vec<_>>(),
+        [va_range!(0..0x5000), va_range!(0x8000..0x10000)]
+    );
+    assert_eq!(area.frames.covering(0x3000.into()).unwrap().2, 0x4000);
+    assert!(area.frames.covering(0x6000.into()).is_none());
+
+    // Unmapping through a huge frame splits it, keeping the other pages.
+    assert_ok!(set.unmap(0x1000.into(), 0x1000, &mut pt));
+    let area = set.find(0.into()).unwrap();
+    assert_eq!(area.frames_count(), 1);
+    assert_eq!(area.find_frame(0.into()).unwrap().start(), huge_pa);
+    let area = set.find(0x2000.into()).unwrap();
+    assert_eq!(area.resident_size(), 0xb000);
+    assert_eq!(
+        area.find_frame(0x3000.into()).unwrap().start(),
+        huge_pa + 0x3000
+    );
+    assert_eq!(area.frames.frame_size(&0x3000.into()), 0x1000);
+    assert_eq!(area.frames.frame_size(&0x8000.into()), 0x8000);
+
+    // So does shrinking the area.
+    assert_ok!(set.unmap(0xe000.into(), 0x2000, &mut pt));
+    let area = set.find(0x2000.into()).unwrap();
+    assert_eq!(area.resident_size(), 0x9000);
+    assert_eq!(
+        area.find_frame(0xd000.into()).unwrap().start(),
+        huge_pa + 0xd000
+    );
+
+    // Splitting an area between frames keeps them whole.
+    let mut area = new_area(0.into(), 0x10000, 1);
+    area.insert_frame_sized(
+        0x8000.into(),
+        Arc::new(TestFrame::no_tracking(huge_pa)),
+        0x8000,
+    );
+    let right = area.split(0x8000.into()).unwrap().unwrap();
+    assert_eq!(area.resident_size(), 0);
+    assert_eq!(right.frames_count(), 1);
+    assert_eq!(right.resident_size(), 0x8000);
+
+    // A frame the backend cannot split keeps the area whole.
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0.into(), 0x8000, 1), &mut pt, false, None));
+    let area = set.find_mut(0.into()).unwrap();
+    area.insert_frame_sized(0.into(), Arc::new(TestFrame::pinned(huge_pa)), 0x4000);
+    assert_err!(area.split(0x2000.into()), BadState);
+    assert_err!(set.unmap(0x1000.into(), 0x1000, &mut pt), BadState);
+    assert_err!(
+        set.protect(0x2000.into(), 0x6000, |_| Some(2), &mut pt),
+        BadState
+    );
+    assert_err!(set.split_off(0x2000.into()), BadState);
+    set.check_invariants();
+    let area = set.find(0.into()).unwrap();
+    assert_eq!(area.va_range(), va_range!(0..0x8000));
+    assert_eq!(area.flags(), 1);
+    assert_eq!(area.resident_size(), 0x4000);
+    assert!(pt[..0x8000].iter().all(|&entry| entry == 1));
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_resident_ranges() {
+    use memory_addr::{FrameTracker, PhysAddr};
+    use std::sync::Arc;
+
+    use crate::test_utils::TestFrame;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0.into(), 0x8000, 1), &mut pt, false, None));
+    assert_ok!(set.map(new_area(0x8000.into(), 0x8000, 2), &mut pt, false, None));
+    let ranges = |set: &MockMemorySet, start: usize, end: usize| {
+        set.resident_ranges(va_range!(start..end))
+            .collect::<Vec<_>>()
+    };
+    assert!(ranges(&set, 0, MAX_ADDR).is_empty());
+
+    let area = set.find_mut(0.into()).unwrap();
+    let huge = Arc::new(TestFrame::no_tracking(PhysAddr::from(0x80_0000)));
+    area.insert_frame_sized(0.into(), huge, 0x4000);
+    area.insert_frame(0x7000.into(), Arc::new(TestFrame::alloc_frame()));
+    for page in [0x8000, 0x9000, 0xf000] {
+        set.insert_frame(page.into(), Arc::new(TestFrame::alloc_frame()));
+    }
+
+    // Adjacent frames of two areas give two ranges.
+    assert_eq!(
+        ranges(&set, 0, MAX_ADDR),
+        [
+            va_range!(0..0x4000),
+            va_range!(0x7000..0x8000),
+            va_range!(0x8000..0xa000),
+            va_range!(0xf000..0x10000),
+        ]
+    );
+    // The ranges are clipped, including the huge frame starting before.
+    assert_eq!(
+        ranges(&set, 0x2800, 0x8800),
+        [
+            va_range!(0x2800..0x4000),
+            va_range!(0x7000..0x8000),
+            va_range!(0x8000..0x8800),
+        ]
+    );
+    assert_eq!(ranges(&set, 0x3fff, 0x4001), [va_range!(0x3fff..0x4000)]);
+    assert_eq!(
+        ranges(&set, 0x7800, 0x8800),
+        [va_range!(0x7800..0x8000), va_range!(0x8000..0x8800)]
+    );
+    assert!(ranges(&set, 0x4000, 0x7000).is_empty());
+    assert!(ranges(&set, 0xa000, 0xf000).is_empty());
+    assert!(ranges(&set, 0x8000, 0x8000).is_empty());
+
+    // Only the resident pages of a sparse area are counted.
+    let area = set.find(0x8000.into()).unwrap();
+    assert_eq!(area.resident_size(), 0x3000);
+    assert_eq!(
+        area.resident_ranges().collect::<Vec<_>>(),
+        [va_range!(0x8000..0xa000), va_range!(0xf000..0x10000)]
+    );
+    assert_eq!(
+        area.resident_ranges_in(va_range!(0x9000..0xf000))
+            .collect::<Vec<_>>(),
+        [va_range!(0x9000..0xa000)]
+    );
+    assert_eq!(set.find(0.into()).unwrap().resident_size(), 0x5000);
+    assert!(set.find_frame(0x5000.into()).is_none());
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_unmap_take_frames() {
+    use memory_addr::{FrameTracker, PhysAddr};
+    use std::sync::Arc;
+
+    use crate::test_utils::{Op, TestFrame};
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new();
+    let area =
+        |start: usize, size: usize| MemoryArea::new(start.into(), size, None, 1, backend.clone());
+    assert_ok!(set.map(area(0, 0x4000), &mut pt, false, None));
+    assert_ok!(set.map(area(0x4000, 0x4000), &mut pt, false, None));
+    let frames: Vec<_> = [0x1000, 0x3000, 0x4000]
+        .map(|page| {
+            let frame = Arc::new(TestFrame::alloc_frame());
+            set.insert_frame(page.into(), frame.clone());
+            frame
+        })
+        .into();
+    let huge_pa = PhysAddr::from(0x80_0000);
+    let huge = Arc::new(TestFrame::no_tracking(huge_pa));
+    let area_b = set.find_mut(0x4000.into()).unwrap();
+    area_b.insert_frame_sized(0x6000.into(), huge, 0x2000);
+
+    // The frames are grouped by area, the huge one split at the bound.
+    let taken = set
+        .unmap_take_frames(0x2000.into(), 0x5000, &mut pt)
+        .unwrap();
+    assert_eq!(taken.len(), 2);
+    assert_eq!(taken[0].range, va_range!(0x2000..0x4000));
+    assert_eq!(
+        taken[0].frames.keys().copied().collect::<Vec<_>>(),
+        [0x3000.into()]
+    );
+    assert!(Arc::ptr_eq(&taken[0].frames[&0x3000.into()], &frames[1]));
+    assert_eq!(taken[1].range, va_range!(0x4000..0x7000));
+    assert_eq!(
+        taken[1].frames.keys().copied().collect::<Vec<_>>(),
+        [0x4000.into(), 0x6000.into()]
+    );
+    assert_eq!(taken[1].frames[&0x6000.into()].start(), huge_pa);
+    assert_eq!(
+        set.find_frame(0x7000.into()).unwrap().start(),
+        huge_pa + 0x1000
+    );
+    assert!(Arc::ptr_eq(
+        &set.find_frame(0x1000.into()).unwrap(),
+        &frames[0]
+    ));
+    assert!(pt[0x2000..0x7000].iter().all(|&flags| flags == 0));
+    // The caller owns the frames until it drops them.
+    assert_eq!(Arc::strong_count(&frames[1]), 2);
+    drop(taken);
+    assert_eq!(Arc::strong_count(&frames[1]), 1);
+    set.check_invariants();
+
+    // Nothing is taken from vacant ranges.
+    assert!(
+        set.unmap_take_frames(0x2000.into(), 0x5000, &mut pt)
+            .unwrap()
+            .is_empty()
+    );
+
+    // A failing area keeps its frames, those before it give theirs.
+    assert_ok!(set.map(area(0x8000, 0x2000), &mut pt, false, None));
+    assert_ok!(set.map(area(0xa000, 0x2000), &mut pt, false, None));
+    for page in [0x8000, 0xa000] {
+        set.insert_frame(page.into(), Arc::new(TestFrame::alloc_frame()));
+    }
+    backend.fail_at(Op::Unmap, 2);
+    assert_err!(
+        set.unmap_take_frames(0x8000.into(), 0x4000, &mut pt),
+        BadState
+    );
+    assert!(set.find(0x8000.into()).is_none());
+    assert!(set.find_frame(0xa000.into()).is_some());
+    assert_eq!(set.find(0xa000.into()).unwrap().resident_size(), 0x1000);
+    backend.fail_at(Op::Unmap, 1);
+    assert_err!(
+        set.unmap_take_frames(0xb000.into(), 0x1000, &mut pt),
+        BadState
+    );
+    assert!(set.find_frame(0xa000.into()).is_some());
+
+    // Or returns them alone, in ascending order.
+    let collected = set.unmap_collect(0.into(), 0x10000, &mut pt).unwrap();
+    assert_eq!(collected.len(), 3);
+    assert!(Arc::ptr_eq(&collected[0], &frames[0]));
+    assert_eq!(collected[1].start(), huge_pa + 0x1000);
+    assert!(set.is_empty());
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_copy_huge_frame() {
+    use crate::test_utils::{TestFrame, WRITE_ACCESS};
+    use memory_addr::{FrameTracker, PhysAddr};
+    use std::sync::Arc;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0.into(), 0x4000, 1), &mut pt, false, None));
+    let mut huge = vec![0u8; 0x4000];
+    for (i, page) in huge.chunks_mut(0x1000).enumerate() {
+        page.fill(i as u8 + 1);
+    }
+    let huge_pa = PhysAddr::from(huge.as_ptr() as usize);
+    let area = set.find_mut(0.into()).unwrap();
+    area.insert_frame_sized(0.into(), Arc::new(TestFrame::no_tracking(huge_pa)), 0x4000);
+    assert_eq!(area.stat().rss, 0x4000);
+
+    // Copies are made page by page.
+    let mut pt2 = test_page_table(MAX_ADDR);
+    let copy = set
+        .find(0.into())
+        .unwrap()
+        .clone_copied(1, &mut pt2)
+        .unwrap();
+    assert_eq!(copy.frames_count(), 4);
+    for (i, page) in [0, 0x1000, 0x2000, 0x3000].into_iter().enumerate() {
+        let frame = copy.find_frame(page.into()).unwrap();
+        assert_ne!(frame.start(), huge_pa + page);
+        assert!(frame.as_slice().iter().all(|&byte| byte == i as u8 + 1));
+    }
+
+    // Breaking copy-on-write only copies the page written to.
+    let mut pt3 = test_page_table(MAX_ADDR);
+    let mut set2 = set.clone_cow(&mut pt, &mut pt3).unwrap();
+    assert_ok!(set2.handle_page_fault(0x2000.into(), WRITE_ACCESS | 1, &mut pt3));
+    let area = set2.find(0.into()).unwrap();
+    assert_eq!(area.frames_count(), 4);
+    let frame = area.find_frame(0x2000.into()).unwrap();
+    assert_ne!(frame.start(), huge_pa + 0x2000);
+    assert!(frame.as_slice().iter().all(|&byte| byte == 3));
+    assert_eq!(
+        area.find_frame(0x3000.into()).unwrap().start(),
+        huge_pa + 0x3000
+    );
+    assert_eq!(area.stat().rss, 0x4000);
+    let area = set.find(0.into()).unwrap();
+    assert_eq!(area.frames.frame_size(&0.into()), 0x4000);
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_swap_huge_frame() {
+    use crate::SwapBackend;
+    use crate::test_utils::TestFrame;
+    use memory_addr::{FrameTracker, PhysAddr};
+    use std::sync::{Arc, Mutex};
+
+    struct VecSwap(Mutex<Vec<Vec<u8>>>);
+
+    impl SwapBackend for VecSwap {
+        fn store(&self, data: &[u8]) -> Option<usize> {
+            let mut slots = self.0.lock().unwrap();
+            slots.push(data.to_vec());
+            Some(slots.len() - 1)
+        }
+
+        fn load(&self, slot: usize, data: &mut [u8]) {
+            data.copy_from_slice(&self.0.lock().unwrap()[slot]);
+        }
+
+        fn free(&self, _slot: usize) {}
+    }
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0.into(), 0x4000, 1), &mut pt, false, None));
+    let mut huge = vec![0u8; 0x4000];
+    for (i, page) in huge.chunks_mut(0x1000).enumerate() {
+        page.fill(i as u8 + 1);
+    }
+    let huge_pa = PhysAddr::from(huge.as_ptr() as usize);
+    let frame = Arc::new(TestFrame::no_tracking(huge_pa));
+    let area = set.find_mut(0.into()).unwrap();
+    area.insert_frame_sized(0.into(), frame, 0x4000);
+    set.set_swap(VecSwap(Mutex::new(Vec::new())));
+
+    // Only the pages in the range are swapped out, the rest of the huge
+    // frame stays mapped page by page.
+    assert_eq!(set.swap_out(0.into(), 0x2000, &mut pt).unwrap(), 2);
+    let area = set.find(0.into()).unwrap();
+    assert!(area.is_swapped(0x1000.into()));
+    assert_eq!(area.resident_size(), 0x2000);
+    assert_eq!(
+        area.find_frame(0x3000.into()).unwrap().start(),
+        huge_pa + 0x3000
+    );
+    assert_eq!(pt[0x1000], 0);
+    assert_eq!(pt[0x2000], 1);
+
+    assert_ok!(set.handle_page_fault(0x1000.into(), 1, &mut pt));
+    let frame = set.find_frame(0x1000.into()).unwrap();
+    assert!(frame.as_slice().iter().all(|&byte| byte == 2));
+}
+
+#[test]
+fn test_gap_mode() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0x1000.into(), 0x1000, 1), &mut pt, false, None));
+    assert_ok!(set.map(new_area(0x3000.into(), 0x2000, 1), &mut pt, false, None));
+
+    // Strict mode reports the first hole and leaves everything in place.
+    assert_eq!(
+        set.protect_with_mode(0x1000.into(), 0x5000, |_| Some(2), GapMode::Strict, &mut pt),
+        Err(MappingError::NotMapped(addr_range!(0x2000usize..0x3000)))
+    );
+    assert_eq!(
+        set.unmap_with_mode(0x3000.into(), 0x3000, GapMode::Strict, &mut pt),
+        Err(MappingError::NotMapped(addr_range!(0x5000usize..0x6000)))
+    );
+    assert_eq!(
+        set.unmap_with_mode(0.into(), 0x2000, GapMode::Strict, &mut pt),
+        Err(MappingError::NotMapped(addr_range!(0usize..0x1000)))
+    );
+    assert_eq!(set.len(), 2);
+    assert!(set.iter().all(|area| area.flags() == 1));
+
+    // Fully covered ranges work the same in both modes.
+    assert_ok!(set.protect_with_mode(0x3000.into(), 0x1000, |_| Some(2), GapMode::Strict, &mut pt));
+    assert_eq!(set.find(0x3000.into()).unwrap().flags(), 2);
+    assert_ok!(set.unmap_with_mode(0x4000.into(), 0x1000, GapMode::Strict, &mut pt));
+
+    // Lenient mode skips the holes, like `munmap`.
+    assert_ok!(set.protect_with_mode(0.into(), 0x6000, |_| Some(3), GapMode::Lenient, &mut pt));
+    assert!(set.iter().all(|area| area.flags() == 3));
+    assert_ok!(set.unmap_with_mode(0.into(), 0x6000, GapMode::Lenient, &mut pt));
+    assert_eq!(set.len(), 0);
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_user_copy() {
+    use crate::SharedFrames;
+    use crate::test_utils::{TestFrame, WRITE_ACCESS};
+    use alloc::collections::BTreeMap;
+    use memory_addr::FrameTracker;
+    use std::sync::Arc;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let frames: BTreeMap<_, _> = [0x1000, 0x2000]
+        .into_iter()
+        .map(|vaddr| (vaddr.into(), Arc::new(TestFrame::alloc_frame())))
+        .collect();
+    let area = MemoryArea::new(0x1000.into(), 0x2000, Some(frames), 1, MockBackend::new());
+    assert_ok!(set.map(area, &mut pt, false, None));
+    // The pages of the next area are faulted in on the first copy.
+    let object = SharedFrames::<MockBackend>::new(0x2000);
+    let area = MemoryArea::new_shared(
+        0x3000.into(),
+        0x2000,
+        object.clone(),
+        0,
+        1,
+        MockBackend::new(),
+    );
+    assert_ok!(set.insert(area, false));
+
+    // Copies cross pages and areas.
+    let data: Vec<u8> = (0..0x1800).map(|i| i as u8).collect();
+    assert_ok!(set.write_bytes(0x2c00.into(), &data, WRITE_ACCESS | 1, &mut pt));
+    let mut buf = vec![0; 0x1800];
+    assert_ok!(set.read_bytes(0x2c00.into(), &mut buf, 1, &mut pt));
+    assert_eq!(buf, data);
+    assert_eq!(
+        &object.frame(0).unwrap().as_slice()[..0x400],
+        &data[0x400..0x800]
+    );
+    assert_eq!(object.frame(0x1000).unwrap().as_slice()[0], data[0x1400]);
+
+    // Nothing is copied over holes.
+    assert_err!(
+        set.read_bytes(0x4c00.into(), &mut buf, 1, &mut pt),
+        NotMapped
+    );
+    assert_err!(
+        set.write_bytes(0x800.into(), &data, WRITE_ACCESS | 1, &mut pt),
+        NotMapped
+    );
+    assert_eq!(set.find_frame(0x1000.into()).unwrap().as_slice()[0x800], 0);
+
+    // Writes break copy-on-write sharing.
+    let mut pt2 = test_page_table(MAX_ADDR);
+    let mut set2 = set.clone_cow(&mut pt, &mut pt2).unwrap();
+    assert_ok!(set2.write_bytes(0x2000.into(), &[0xff; 4], WRITE_ACCESS | 1, &mut pt2));
+    let mut byte = [0];
+    assert_ok!(set2.read_bytes(0x2001.into(), &mut byte, 1, &mut pt2));
+    assert_eq!(byte, [0xff]);
+    assert_ok!(set.read_bytes(0x2001.into(), &mut byte, 1, &mut pt));
+    assert_eq!(byte, [0]);
+}
+
+#[test]
+fn test_map_presplit() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let boundaries = [0x4000.into(), 0x8000.into(), 0xc000.into()];
+
+    // Invalid boundaries are rejected before anything is mapped.
+    for bad in [
+        [0x8000.into(), 0x4000.into()],
+        [0x4000.into(), 0x4000.into()],
+        [0.into(), 0x4000.into()],
+        [0x4000.into(), 0x10000.into()],
+    ] {
+        assert_err!(
+            set.map_presplit(
+                new_area(0.into(), 0x10000, 1),
+                &bad,
+                &mut pt,
+                MapMode::NoReplace
+            ),
+            InvalidParam
+        );
+    }
+    assert_eq!(set.len(), 0);
+
+    assert_ok!(set.map_presplit(
+        new_area(0.into(), 0x10000, 1),
+        &boundaries,
+        &mut pt,
+        MapMode::NoReplace
+    ));
+    assert_eq!(
+        set.iter().map(|area| area.va_range()).collect::<Vec<_>>(),
+        [
+            va_range!(0..0x4000),
+            va_range!(0x4000..0x8000),
+            va_range!(0x8000..0xc000),
+            va_range!(0xc000..0x10000)
+        ]
+    );
+    assert!(pt[..0x10000].iter().all(|&entry| entry == 1));
+
+    // Protecting a piece does not split anything.
+    assert_ok!(set.protect(0x8000.into(), 0x4000, |_| Some(2), &mut pt));
+    assert_eq!(set.len(), 4);
+}
+
+#[test]
+fn test_page_fault_dispatch() {
+    use crate::test_utils::WRITE_ACCESS;
+
+    let mut set = MockMemorySet::new();
+    set.set_clock(|| 1);
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_access_check();
+    let area = |start: usize, flags| {
+        MemoryArea::new(
+            start.into(),
+            0x1000,
+            #[cfg(feature = "RAII")]
+            None,
+            flags,
+            backend.clone(),
+        )
+    };
+    assert_ok!(set.map(area(0x2000, 1).with_guards(0x1000, 0), &mut pt, false, None));
+    assert_ok!(set.map(area(0x3000, 3), &mut pt, false, None));
+    assert_ok!(set.reserve(area(0x5000, 1)));
+    assert_ok!(set.add_hole(area(0x6000, 1)));
+
+    // Resolved faults stamp the area, spurious ones included.
+    assert_ok!(set.handle_page_fault(0x2800.into(), 1, &mut pt));
+    assert_ok!(set.handle_page_fault(0x3800.into(), 2 | WRITE_ACCESS, &mut pt));
+    assert!(
+        set.find(0x3000.into())
+            .unwrap()
+            .times()
+            .last_fault
+            .is_some()
+    );
+
+    // Genuine violations: nothing there, in a guard or a hole, an access
+    // not allowed by the flags, or a reserved area.
+    set.set_clock(|| 2);
+    for addr in [0, 0x1800, 0x4000, 0x6000, 0xf000] {
+        assert_err!(set.handle_page_fault(addr.into(), 1, &mut pt), NotMapped);
+    }
+    assert_err!(
+        set.handle_page_fault(0x2000.into(), 2, &mut pt),
+        PermissionDenied
+    );
+    assert_err!(
+        set.handle_page_fault(0x5000.into(), 1, &mut pt),
+        PermissionDenied
+    );
+    assert!(
+        set.find(0x2000.into())
+            .unwrap()
+            .times()
+            .last_fault
+            .is_some()
+    );
+
+    // The backend failing to resolve the fault is reported as is.
+    pt[0x3000..0x4000].fill(0);
+    assert_err!(set.handle_page_fault(0x3000.into(), 1, &mut pt), BadState);
+    assert!(pt[0x3000..0x4000].iter().all(|&entry| entry == 0));
+    assert_eq!(set.find(0x3000.into()).unwrap().times().last_fault, Some(1));
+}
+
+#[test]
+fn test_first_touch() {
+    use crate::{Advice, MappingBackend, RemapFlags};
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_granularity(0x1000);
+    assert_ok!(set.map(
+        MemoryArea::new(
+            0.into(),
+            0x8000,
+            #[cfg(feature = "RAII")]
+            None,
+            1,
+            backend.clone(),
+        ),
+        &mut pt,
+        false,
+        None,
+    ));
+    // Only `[0, 0x6000)` has a translation: 4 pages on node 0, 2 on node 1.
+    assert!(backend.collapse_huge(0.into(), 0x4000, 1, &mut pt));
+    assert!(backend.collapse_huge(0x4000.into(), 0x2000, 1, &mut pt));
+    backend.set_numa_node_size(0x4000);
+    let fault_all = |set: &mut MockMemorySet, pt: &mut _| {
+        for addr in (0..0x8000).step_by(0x1000) {
+            assert_ok!(set.handle_page_fault(addr.into(), 1, pt));
+        }
+    };
+    fault_all(&mut set, &mut pt);
+    let all = va_range!(0..MAX_ADDR);
+    assert_eq!(set.first_touch_nodes(all), [(0, 4), (1, 2)].into());
+    let area = set.find(0.into()).unwrap();
+    assert_eq!(area.first_touch_node(0x4800.into()), Some(1));
+    assert_eq!(area.first_touch_node(0x6000.into()), None);
+    // Partial pages at both ends are counted.
+    assert_eq!(
+        set.first_touch_nodes(va_range!(0x3800..0x4800)),
+        [(0, 1), (1, 1)].into()
+    );
+
+    // Later faults keep the node of the first one.
+    backend.set_numa_node_size(0x1000);
+    fault_all(&mut set, &mut pt);
+    assert_eq!(set.first_touch_nodes(all), [(0, 4), (1, 2)].into());
+
+    // Unmapped and discarded pages are forgotten, the rest is kept by
+    // splits.
+    assert_ok!(set.unmap(0x2000.into(), 0x1000, &mut pt));
+    assert_ok!(set.advise(0.into(), 0x1000, Advice::DontNeed, &mut pt));
+    assert_eq!(set.first_touch_nodes(all), [(0, 2), (1, 2)].into());
+    assert_eq!(set.find(0.into()).unwrap().first_touch_node(0.into()), None);
+
+    // Shrinking forgets the dropped pages, which are not touched again when
+    // growing back.
+    assert_ok!(set.remap(0x3000.into(), 0x5000, 0x1000, RemapFlags::new(), &mut pt));
+    assert_ok!(set.remap(0x3000.into(), 0x1000, 0x5000, RemapFlags::new(), &mut pt));
+    assert_eq!(set.first_touch_nodes(all), [(0, 2)].into());
+    set.check_invariants();
+}
+
+#[test]
+fn test_check_access() {
+    use crate::AccessError;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_access_check();
+    for (start, flags) in [(0x1000, 1), (0x2000, 3), (0x4000, 3)] {
+        let area = MemoryArea::new(
+            start.into(),
+            0x1000,
+            #[cfg(feature = "RAII")]
+            None,
+            flags,
+            backend.clone(),
+        );
+        assert_ok!(set.map(area, &mut pt, false, None));
+    }
+
+    assert_eq!(set.check_access(va_range!(0x1000..0x3000), 1), Ok(()));
+    assert_eq!(set.check_access(va_range!(0x2800..0x2800), 2), Ok(()));
+    // The first failure in the range is reported.
+    assert_eq!(
+        set.check_access(va_range!(0x1800..0x5000), 2),
+        Err(AccessError::PermissionDenied(addr_range!(
+            0x1000usize..0x2000
+        )))
+    );
+    assert_eq!(
+        set.check_access(va_range!(0x2000..0x5000), 2),
+        Err(AccessError::NotMapped(addr_range!(0x3000usize..0x4000)))
+    );
+    assert_eq!(
+        set.check_access(va_range!(0x4000..0x6000), 2),
+        Err(AccessError::NotMapped(addr_range!(0x5000usize..0x6000)))
+    );
+    assert_eq!(
+        MappingError::from(AccessError::NotMapped(addr_range!(0usize..1))),
+        MappingError::NotMapped(addr_range!(0usize..1))
+    );
+}
+
+#[test]
+fn test_yield_points() {
+    use crate::YieldAction;
+    use std::sync::{Arc, Mutex};
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    // Without a hook, everything is done at once.
+    assert_ok!(set.map(new_area(0.into(), 0x8000, 1), &mut pt, false, None));
+    assert_eq!(set.unmap_yielding(0.into(), 0x8000, &mut pt), Ok(None));
+    assert_eq!(set.len(), 0);
+
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let recorded = calls.clone();
+    set.set_yield_hook(4, move |addr: VirtAddr| {
+        let mut calls = recorded.lock().unwrap();
+        calls.push(addr.as_usize());
+        if calls.len() % 2 == 0 {
+            YieldAction::Pause
+        } else {
+            YieldAction::Continue
+        }
+    });
+    assert_ok!(set.map(new_area(0x1000.into(), 0x9000, 1), &mut pt, false, None));
+
+    // The hook is called every 4 pages, and pausing leaves the rest mapped.
+    assert_eq!(
+        set.unmap_yielding(0.into(), 0xa000, &mut pt),
+        Ok(Some(0x9000.into()))
+    );
+    assert_eq!(*calls.lock().unwrap(), [0x5000, 0x9000]);
+    assert_eq!(
+        set.find(0x9000.into()).unwrap().va_range(),
+        va_range!(0x9000..0xa000)
+    );
+    assert!(pt[0x1000..0x9000].iter().all(|&entry| entry == 0));
+    assert_eq!(set.unmap_yielding(0x9000.into(), 0x1000, &mut pt), Ok(None));
+    assert_eq!(set.len(), 0);
+
+    // Pages are counted across areas when clearing.
+    calls.lock().unwrap().clear();
+    for start in [0x1000, 0x5000, 0x9000] {
+        assert_ok!(set.map(new_area(start.into(), 0x3000, 1), &mut pt, false, None));
+    }
+    assert_eq!(set.clear_yielding(&mut pt), Ok(Some(0xb000.into())));
+    assert_eq!(*calls.lock().unwrap(), [0x6000, 0xb000]);
+    assert_eq!(
+        set.find(0xb000.into()).unwrap().va_range(),
+        va_range!(0xb000..0xc000)
+    );
+    assert_eq!(set.clear_yielding(&mut pt), Ok(None));
+    assert_eq!(set.len(), 0);
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_clone_into() {
+    use crate::SharedFrames;
+    use crate::test_utils::{TestFrame, WRITE_ACCESS};
+    use memory_addr::FrameTracker;
+    use std::sync::Arc;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let mut frame = TestFrame::alloc_frame();
+    frame.as_mut_slice()[0] = 42;
+    let frames = [(0x1000.into(), Arc::new(frame))].into();
+    let area = MemoryArea::new(0x1000.into(), 0x2000, Some(frames), 1, MockBackend::new());
+    assert_ok!(set.map(area, &mut pt, false, None));
+    let object = SharedFrames::<MockBackend>::new(0x1000);
+    let area = MemoryArea::new_shared(
+        0x4000.into(),
+        0x1000,
+        object.clone(),
+        0,
+        3,
+        MockBackend::new(),
+    );
+    assert_ok!(set.map(area, &mut pt, false, None));
+    assert_ok!(set.seal(0x4000.into(), 0x1000));
+
+    let mut new_pt = test_page_table(MAX_ADDR);
+    let mut new_set = set.clone_into(&mut new_pt).unwrap();
+    assert_eq!(new_set.len(), 2);
+    assert_eq!(new_pt[0x1000], 1);
+    assert_eq!(new_pt[0x4000], 3);
+    assert!(new_set.find(0x4000.into()).unwrap().is_sealed());
+
+    // Private frames are copied, shared ones are not.
+    let old_frame = set.find_frame(0x1000.into()).unwrap();
+    let new_frame = new_set.find_frame(0x1000.into()).unwrap();
+    assert_ne!(new_frame.start(), old_frame.start());
+    assert_eq!(new_frame.as_slice()[0], 42);
+    assert_eq!(
+        new_set.find_frame(0x4000.into()).unwrap().start(),
+        object.frame(0).unwrap().start()
+    );
+
+    // The copies are independent, and nothing is write-protected.
+    assert_ok!(new_set.write_bytes(0x1000.into(), &[7], WRITE_ACCESS | 1, &mut new_pt));
+    assert_eq!(old_frame.as_slice()[0], 42);
+    assert!(!set.find(0x1000.into()).unwrap().is_write_protected());
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_clone_copied() {
+    use crate::test_utils::{TestFrame, WRITE_ACCESS};
+    use memory_addr::FrameTracker;
+    use std::sync::Arc;
+
+    let backend = MockBackend::new().with_zero_frame();
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let mut area = MemoryArea::new(0.into(), 0x3000, None, 1, backend.clone());
+    area.set_label(Some("heap".into()));
+    assert_ok!(set.map(area, &mut pt, false, None));
+    assert_ok!(set.handle_page_fault(0.into(), 1, &mut pt));
+    let mut frame = TestFrame::alloc_frame();
+    frame.as_mut_slice()[0] = 42;
+    set.insert_frame(0x1000.into(), Arc::new(frame));
+    assert_ok!(set.clear_soft_dirty(va_range!(0..0x3000), &mut pt));
+    assert_ok!(set.handle_page_fault(0x1000.into(), WRITE_ACCESS | 1, &mut pt));
+
+    // The attributes are kept, and only the private frames are copied.
+    let mut new_pt = test_page_table(MAX_ADDR);
+    let area = set.find(0.into()).unwrap();
+    let copy = area.clone_copied(3, &mut new_pt).unwrap();
+    assert_eq!((copy.label(), copy.flags()), (Some("heap"), 3));
+    assert_eq!(copy.zero_pages(), 1);
+    let old_frame = area.find_frame(0x1000.into()).unwrap();
+    let new_frame = copy.find_frame(0x1000.into()).unwrap();
+    assert_ne!(new_frame.start(), old_frame.start());
+    assert_eq!(new_frame.as_slice()[0], 42);
+    assert!(copy.is_write_protected());
+    assert_eq!(copy.soft_dirty_pages(va_range!(0..0x3000)), [0x1000.into()]);
+
+    // A shared clone aliases the same frames.
+    let alias = area.clone_shared(2);
+    assert_eq!((alias.label(), alias.flags()), (Some("heap"), 2));
+    assert!(Arc::ptr_eq(
+        &alias.find_frame(0x1000.into()).unwrap(),
+        &old_frame
+    ));
+    assert!(Arc::ptr_eq(
+        &area.clone_(1).find_frame(0x1000.into()).unwrap(),
+        &old_frame
+    ));
+
+    // Failing to map the copy leaves the original alone.
+    let mut failed_pt = test_page_table(MAX_ADDR);
+    backend.fail_at(crate::test_utils::Op::Map, 1);
+    assert_err!(area.clone_copied(3, &mut failed_pt), BadState);
+    assert!(failed_pt.iter().all(|&entry| entry == 0));
+    assert_eq!(Arc::strong_count(&old_frame), 3);
+    assert_eq!(area.find_frame(0x1000.into()).unwrap().as_slice()[0], 42);
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_shared_pages() {
+    use crate::test_utils::TestFrame;
+    use memory_addr::{FrameTracker, PhysAddr};
+    use std::sync::Arc;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_zero_frame();
+    let area = MemoryArea::new(0.into(), 0x8000, None, 1, backend.clone());
+    assert_ok!(set.map(area, &mut pt, false, None));
+    let shared = Arc::new(TestFrame::alloc_frame());
+    let huge = Arc::new(TestFrame::no_tracking(PhysAddr::from(0x80_0000)));
+    let area = set.find_mut(0.into()).unwrap();
+    area.insert_frame(0.into(), Arc::new(TestFrame::alloc_frame()));
+    area.insert_frame(0x1000.into(), shared.clone());
+    area.insert_frame_sized(0x4000.into(), huge.clone(), 0x4000);
+    assert_ok!(set.handle_page_fault(0x2000.into(), 1, &mut pt));
+
+    // The private frame and the zero page are not counted, the huge frame
+    // counts for each of its pages.
+    let area = set.find(0.into()).unwrap();
+    assert_eq!(area.zero_pages(), 1);
+    assert_eq!(area.shared_pages(), 5);
+    assert_eq!(area.stat().shared, 0x5000);
+    // Shared pages are only charged once write-protected for copy-on-write.
+    let charge = area.commit_charge();
+    let area = set.find_mut(0.into()).unwrap();
+    assert_ok!(area.write_protect(&mut pt));
+    assert_eq!(area.commit_charge(), charge + 0x5000);
+
+    // The pages are private again once the other references are gone.
+    drop((shared, huge));
+    let area = set.find(0.into()).unwrap();
+    assert_eq!(area.shared_pages(), 0);
+    assert_eq!(area.stat().shared, 0);
+    assert_eq!(area.commit_charge(), charge);
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_clone_cow() {
+    use crate::test_utils::{TestFrame, WRITE_ACCESS};
+    use memory_addr::FrameTracker;
+    use std::sync::Arc;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0.into(), 0x3000, 1), &mut pt, false, None));
+    for page in [0, 0x1000] {
+        let mut frame = TestFrame::alloc_frame();
+        frame.as_mut_slice()[0] = 42;
+        set.insert_frame(page.into(), Arc::new(frame));
+    }
+    let frame_at =
+        |set: &MockMemorySet, vaddr: usize| set.find_frame(vaddr.into()).unwrap().start();
+    let old = frame_at(&set, 0);
+
+    // Both sides share the resident frames, write-protected.
+    let mut new_pt = test_page_table(MAX_ADDR);
+    let mut new_set = set.clone_cow(&mut pt, &mut new_pt).unwrap();
+    assert_eq!(new_set.len(), 1);
+    assert_eq!(new_pt, pt);
+    for set in [&set, &new_set] {
+        let area = set.find(0.into()).unwrap();
+        assert!(area.is_write_protected());
+        assert_eq!(area.shared_pages(), 2);
+    }
+    assert_eq!(frame_at(&new_set, 0), old);
+
+    // A write from the new side copies the page and leaves the old one alone.
+    assert_ok!(new_set.handle_page_fault(0x10.into(), WRITE_ACCESS | 1, &mut new_pt));
+    let copy = new_set.find_frame(0.into()).unwrap();
+    assert_ne!(copy.start(), old);
+    assert_eq!(copy.as_slice()[0], 42);
+    drop(copy);
+    assert_eq!(frame_at(&set, 0), old);
+    assert_eq!(set.find(0.into()).unwrap().shared_pages(), 1);
+    assert_eq!(new_set.find(0.into()).unwrap().shared_pages(), 1);
+
+    // The old side is the only owner left, so no copy is needed.
+    assert_ok!(set.handle_page_fault(0.into(), WRITE_ACCESS | 1, &mut pt));
+    assert_eq!(frame_at(&set, 0), old);
+
+    // Pages without a frame are left to the demand fault, and a read is not
+    // a write.
+    let area = set.find_mut(0.into()).unwrap();
+    assert_ok!(area.break_cow(0x2000.into(), &mut pt));
+    assert!(area.find_frame(0x2000.into()).is_none());
+    assert_ok!(set.handle_page_fault(0x1000.into(), 1, &mut pt));
+    assert_eq!(set.find(0.into()).unwrap().shared_pages(), 1);
+
+    // If the copy cannot be mapped, the page keeps sharing the frame.
+    let shared = frame_at(&new_set, 0x1000);
+    let mut short_pt = test_page_table(0x1000);
+    assert_err!(
+        new_set.handle_page_fault(0x1000.into(), WRITE_ACCESS | 1, &mut short_pt),
+        BadState
+    );
+    assert_eq!(frame_at(&new_set, 0x1000), shared);
+    assert_eq!(new_set.find(0.into()).unwrap().shared_pages(), 1);
+    assert_ok!(new_set.handle_page_fault(0x1000.into(), WRITE_ACCESS | 1, &mut new_pt));
+    assert_ne!(frame_at(&new_set, 0x1000), shared);
+    assert_eq!(new_set.find(0.into()).unwrap().shared_pages(), 0);
+    set.check_invariants();
+    new_set.check_invariants();
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_clone_cow_failure() {
+    use crate::test_utils::{Op, TestFrame};
+    use memory_addr::FrameTracker;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let committed = Arc::new(AtomicUsize::new(0));
+    let mut set = MockMemorySet::new();
+    let check = committed.clone();
+    set.set_commit_check(move |bytes| {
+        check.fetch_add(bytes, Ordering::SeqCst);
+        true
+    });
+    let release = committed.clone();
+    set.set_commit_release(move |bytes| {
+        release.fetch_sub(bytes, Ordering::SeqCst);
+    });
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new();
+    let area = |start: usize| MemoryArea::new(start.into(), 0x2000, None, 1, backend.clone());
+    assert_ok!(set.map(area(0), &mut pt, false, None));
+    set.insert_frame(0.into(), Arc::new(TestFrame::alloc_frame()));
+    let mut first_pt = test_page_table(MAX_ADDR);
+    let first = set.clone_cow(&mut pt, &mut first_pt).unwrap();
+    for start in [0x4000, 0x8000] {
+        assert_ok!(set.map(area(start), &mut pt, false, None));
+        set.insert_frame(start.into(), Arc::new(TestFrame::alloc_frame()));
+    }
+    let charge = set.commit_charge();
+
+    // Cloning the last area fails, the others are rolled back and the charge
+    // is refunded.
+    let mut new_pt = test_page_table(MAX_ADDR);
+    backend.fail_at(Op::Map, 3);
+    assert_err!(set.clone_cow(&mut pt, &mut new_pt), BadState);
+    assert!(new_pt.iter().all(|&entry| entry == 0));
+    // The area shared with the first clone stays write-protected.
+    assert!(set.find(0.into()).unwrap().is_write_protected());
+    for start in [0x4000, 0x8000] {
+        let area = set.find(start.into()).unwrap();
+        assert!(!area.is_write_protected());
+        // Held by the area and the returned clone.
+        let frame = area.find_frame(start.into()).unwrap();
+        assert_eq!(Arc::strong_count(&frame), 2);
+    }
+    assert_eq!(set.commit_charge(), charge);
+    assert_eq!(
+        committed.load(Ordering::SeqCst),
+        charge + first.commit_charge()
+    );
+    set.check_invariants();
+
+    // Nothing is left behind for the next attempt.
+    let new_set = set.clone_cow(&mut pt, &mut new_pt).unwrap();
+    assert_eq!(new_set.len(), 3);
+    let frame = set.find(0x4000.into()).unwrap().find_frame(0x4000.into());
+    assert_eq!(Arc::strong_count(&frame.unwrap()), 3);
+    drop((first, new_set));
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_extract() {
+    use crate::ExtractMode;
+    use crate::test_utils::{Op, TestFrame};
+    use memory_addr::FrameTracker;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let committed = Arc::new(AtomicUsize::new(0));
+    let mut set = MockMemorySet::new();
+    let check = committed.clone();
+    set.set_commit_check(move |bytes| {
+        check.fetch_add(bytes, Ordering::SeqCst);
+        true
+    });
+    let release = committed.clone();
+    set.set_commit_release(move |bytes| {
+        release.fetch_sub(bytes, Ordering::SeqCst);
+    });
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_granularity(0x1000);
+    let area = |start: usize, size| MemoryArea::new(start.into(), size, None, 1, backend.clone());
+    assert_ok!(set.map(area(0x1000, 0x3000), &mut pt, false, None));
+    assert_ok!(set.map(area(0x5000, 0x2000), &mut pt, false, None));
+    let frame = Arc::new(TestFrame::alloc_frame());
+    set.insert_frame(0x2000.into(), frame.clone());
+    let ranges = |set: &MockMemorySet| set.iter().map(|area| area.va_range()).collect::<Vec<_>>();
+    let committed = move || committed.load(Ordering::SeqCst);
+
+    // The range is checked like for `unmap`.
+    let mut new_pt = test_page_table(MAX_ADDR);
+    assert_err!(
+        set.extract(
+            va_range!(0x2800..0x3000),
+            ExtractMode::Copy,
+            &mut pt,
+            &mut new_pt
+        ),
+        InvalidParam
+    );
+
+    // The areas are cut to the range, its end rounded up, and copied.
+    let (copy, offsets) = set
+        .extract(
+            va_range!(0x2000..0x5800),
+            ExtractMode::Copy,
+            &mut pt,
+            &mut new_pt,
+        )
+        .unwrap();
+    assert_eq!(offsets, [(0, 0x2000), (0x3000, 0x1000)]);
+    assert_eq!(
+        ranges(&copy),
+        [va_range!(0x2000..0x4000), va_range!(0x5000..0x6000)]
+    );
+    for (addr, &entry) in new_pt.iter().enumerate() {
+        let mapped = (0x2000..0x4000).contains(&addr) || (0x5000..0x6000).contains(&addr);
+        assert_eq!(entry, mapped as u8);
+    }
+    assert!(!Arc::ptr_eq(
+        &copy.find_frame(0x2000.into()).unwrap(),
+        &frame
+    ));
+    assert_eq!(set.len(), 2);
+    assert!(set.iter().all(|area| !area.is_write_protected()));
+    assert_eq!(committed(), set.commit_charge() + copy.commit_charge());
+    copy.check_invariants();
+
+    // Failing to map the second area rolls everything back.
+    let before = committed();
+    let mut failed_pt = test_page_table(MAX_ADDR);
+    backend.fail_at(Op::Map, 2);
+    assert_err!(
+        set.extract(
+            va_range!(0..0x8000),
+            ExtractMode::Cow,
+            &mut pt,
+            &mut failed_pt
+        ),
+        BadState
+    );
+    assert!(failed_pt.iter().all(|&entry| entry == 0));
+    assert!(set.iter().all(|area| !area.is_write_protected()));
+    assert_eq!(Arc::strong_count(&frame), 2);
+    assert_eq!(committed(), before);
+
+    // The frames are shared copy-on-write, and the whole source area is
+    // write-protected.
+    let mut cow_pt = test_page_table(MAX_ADDR);
+    let (cow, offsets) = set
+        .extract(
+            va_range!(0x2000..0x3000),
+            ExtractMode::Cow,
+            &mut pt,
+            &mut cow_pt,
+        )
+        .unwrap();
+    assert_eq!(offsets, [(0, 0x1000)]);
+    assert!(Arc::ptr_eq(&cow.find_frame(0x2000.into()).unwrap(), &frame));
+    assert!(cow.find(0x2000.into()).unwrap().is_write_protected());
+    assert!(set.find(0x1000.into()).unwrap().is_write_protected());
+    assert!(!set.find(0x5000.into()).unwrap().is_write_protected());
+    assert_eq!(
+        committed(),
+        set.commit_charge() + copy.commit_charge() + cow.commit_charge()
+    );
+    set.check_invariants();
+    cow.check_invariants();
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_inherit_config() {
+    use crate::ExtractMode;
+
+    let mut set = MockMemorySet::new().with_label("init");
+    let mut pt = test_page_table(MAX_ADDR);
+    set.set_size_limit(Some(0x8000));
+    assert_ok!(set.map(new_area(0.into(), 0x2000, 1), &mut pt, false, None));
+    assert_ok!(set.map(new_area(0x4000.into(), 0x2000, 1), &mut pt, false, None));
+
+    // The copies keep the configuration but not the label.
+    let mut new_pt = test_page_table(MAX_ADDR);
+    let copy = set.clone_into(&mut new_pt).unwrap();
+    assert_eq!((copy.size_limit(), copy.label()), (Some(0x8000), None));
+    let mut new_pt = test_page_table(MAX_ADDR);
+    let (part, _) = set
+        .extract(
+            va_range!(0..0x2000),
+            ExtractMode::Copy,
+            &mut pt,
+            &mut new_pt,
+        )
+        .unwrap();
+    assert_eq!((part.size_limit(), part.label()), (Some(0x8000), None));
+    let mut new_pt = test_page_table(MAX_ADDR);
+    let cow = set.clone_cow(&mut pt, &mut new_pt).unwrap();
+    assert_eq!(cow.size_limit(), Some(0x8000));
+    let rest = set.split_off(0x4000.into()).unwrap();
+    assert_eq!(rest.size_limit(), Some(0x8000));
+    assert_eq!(rest.total_size(), 0x2000);
+    assert!(set.label().is_some());
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_scan_cursor() {
+    use crate::test_utils::TestFrame;
+    use crate::{ScanCursor, SwapBackend};
+    use memory_addr::FrameTracker;
+    use std::sync::{Arc, Mutex};
+
+    struct VecSwap(Mutex<Vec<Vec<u8>>>);
+
+    impl SwapBackend for VecSwap {
+        fn store(&self, data: &[u8]) -> Option<usize> {
+            let mut slots = self.0.lock().unwrap();
+            slots.push(data.to_vec());
+            Some(slots.len() - 1)
+        }
+
+        fn load(&self, slot: usize, data: &mut [u8]) {
+            data.copy_from_slice(&self.0.lock().unwrap()[slot]);
+        }
+
+        fn free(&self, _slot: usize) {}
+    }
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_zero_frame();
+    for (start, size) in [(0, 0x6000), (0x8000, 0x2000)] {
+        let area = MemoryArea::new(start.into(), size, None, 1, backend.clone());
+        assert_ok!(set.map(area, &mut pt, false, None));
+    }
+
+    // Populating goes on from where the last step stopped, over holes.
+    let mut cursor = ScanCursor::new(va_range!(0..0xa000));
+    assert_eq!(set.populate_step(&mut cursor, 4, 1, &mut pt), Ok(4));
+    assert_eq!(cursor.position(), 0x4000.into());
+    assert_eq!(set.populate_step(&mut cursor, 4, 1, &mut pt), Ok(4));
+    assert!(cursor.is_done());
+    assert_eq!(set.populate_step(&mut cursor, 4, 1, &mut pt), Ok(0));
+    assert_eq!(set.find(0x9000.into()).unwrap().zero_pages(), 2);
+
+    // So does reclaiming, skipping the zero pages.
+    for page in [0x1000, 0x3000, 0x5000] {
+        set.insert_frame(page.into(), Arc::new(TestFrame::alloc_frame()));
+    }
+    set.set_swap(VecSwap(Mutex::new(Vec::new())));
+    cursor.rewind();
+    assert_eq!(set.swap_out_step(&mut cursor, 2, &mut pt), Ok(2));
+    assert_eq!(cursor.position(), 0x4000.into());
+    assert_eq!(set.swap_out_step(&mut cursor, 2, &mut pt), Ok(1));
+    assert!(cursor.is_done());
+    assert_eq!(set.find(0.into()).unwrap().stat().swap, 0x3000);
+
+    // Cloning takes whole areas.
+    let mut new_set = MockMemorySet::new();
+    let mut new_pt = test_page_table(MAX_ADDR);
+    cursor.rewind();
+    assert_eq!(
+        set.clone_cow_step(&mut new_set, &mut cursor, 1, &mut pt, &mut new_pt),
+        Ok(1)
+    );
+    assert_eq!(cursor.position(), 0x6000.into());
+    assert_eq!(new_set.len(), 1);
+    assert!(set.find(0.into()).unwrap().is_write_protected());
+    assert!(!set.find(0x8000.into()).unwrap().is_write_protected());
+    assert_eq!(
+        set.clone_cow_step(&mut new_set, &mut cursor, 1, &mut pt, &mut new_pt),
+        Ok(1)
+    );
+    assert!(cursor.is_done());
+    assert_eq!(new_set.len(), 2);
+    assert_eq!(
+        new_set.find_free_area(0.into(), 0x2000, va_range!(0..0xa000)),
+        Some(0x6000.into())
+    );
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_unmap_collect() {
+    use crate::test_utils::TestFrame;
+    use memory_addr::FrameTracker;
+    use std::sync::Arc;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0.into(), 0x3000, 1), &mut pt, false, None));
+    assert_ok!(set.map(new_area(0x3000.into(), 0x3000, 2), &mut pt, false, None));
+    let frames: Vec<_> = (0..6).map(|_| Arc::new(TestFrame::alloc_frame())).collect();
+    for (i, frame) in frames.iter().enumerate() {
+        set.insert_frame((i * 0x1000).into(), frame.clone());
+    }
+
+    // The frames come back from both areas, in address order.
+    let taken = set.unmap_collect(0x1000.into(), 0x4000, &mut pt).unwrap();
+    assert_eq!(taken.len(), 4);
+    for (frame, expected) in taken.iter().zip(&frames[1..5]) {
+        assert!(Arc::ptr_eq(frame, expected));
+    }
+    assert_eq!(set.len(), 2);
+    assert_eq!(set.find(0.into()).unwrap().stat().rss, 0x1000);
+    assert_eq!(set.find(0x5000.into()).unwrap().stat().rss, 0x1000);
+
+    // They can be handed over to another set.
+    let mut new_set = MockMemorySet::new();
+    let mut new_pt = test_page_table(MAX_ADDR);
+    assert_ok!(new_set.map(new_area(0.into(), 0x4000, 1), &mut new_pt, false, None));
+    for (i, frame) in taken.into_iter().enumerate() {
+        new_set.insert_frame((i * 0x1000).into(), frame);
+    }
+    assert_eq!(new_set.find(0.into()).unwrap().stat().rss, 0x4000);
+    assert!(
+        set.unmap_collect(0x1000.into(), 0x4000, &mut pt)
+            .unwrap()
+            .is_empty()
+    );
+}
+
+/// Runs the page-granular operations on a set whose base pages and frames
+/// are `PAGE_SIZE` bytes.
+#[cfg(feature = "RAII")]
+fn check_base_page_size<const PAGE_SIZE: usize>() {
+    use crate::ScanCursor;
+    use crate::test_utils::{TestFrame, WRITE_ACCESS};
+    use memory_addr::{AddrRange, FrameTracker};
+    use std::sync::Arc;
+
+    let mut set = MemorySet::<TestBackend<PAGE_SIZE>>::new();
+    let mut pt = test_page_table(8 * PAGE_SIZE);
+    let backend = TestBackend::<PAGE_SIZE>::new().with_zero_frame();
+    let area = MemoryArea::new(0.into(), 4 * PAGE_SIZE, None, 1, backend);
+    assert_ok!(set.map(area, &mut pt, false, None));
+
+    // Frames are inserted at the start of their page.
+    let frame = Arc::new(TestFrame::<PAGE_SIZE>::alloc_frame());
+    assert!(
+        set.insert_frame((PAGE_SIZE + 0x1000).into(), frame.clone())
+            .is_none()
+    );
+    assert!(Arc::ptr_eq(
+        &set.find_frame(PAGE_SIZE.into()).unwrap(),
+        &frame
+    ));
+    assert_eq!(set.stat().rss, PAGE_SIZE);
+
+    let mut cursor = ScanCursor::new(AddrRange::new(0.into(), (4 * PAGE_SIZE).into()));
+    assert_eq!(set.populate_step(&mut cursor, 8, 1, &mut pt), Ok(3));
+    assert_eq!(set.find(0.into()).unwrap().zero_pages(), 3);
+
+    // Copies cross the pages where they end, not at 4K.
+    let vaddr = VirtAddr::from(2 * PAGE_SIZE - 2);
+    assert_ok!(set.write_bytes(vaddr, &[1, 2, 3, 4], WRITE_ACCESS | 1, &mut pt));
+    let mut buf = [0; 4];
+    assert_ok!(set.read_bytes(vaddr, &mut buf, 1, &mut pt));
+    assert_eq!(buf, [1, 2, 3, 4]);
+    assert_eq!(frame.as_slice()[PAGE_SIZE - 2..], [1, 2]);
+    assert_eq!(set.find(0.into()).unwrap().zero_pages(), 2);
+
+    let taken = set
+        .unmap_collect(PAGE_SIZE.into(), 2 * PAGE_SIZE, &mut pt)
+        .unwrap();
+    assert_eq!(taken.len(), 2);
+    assert!(Arc::ptr_eq(&taken[0], &frame));
+    assert_eq!(set.len(), 2);
+    assert_eq!(set.find((3 * PAGE_SIZE).into()).unwrap().size(), PAGE_SIZE);
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_large_base_pages() {
+    check_base_page_size::<0x4000>();
+    check_base_page_size::<0x10000>();
+}
+
+#[test]
+fn test_confidentiality() {
+    use crate::Confidentiality;
+    use crate::test_utils::SHARED_BIT;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new();
+    let area = MemoryArea::new(
+        0.into(),
+        0x4000,
+        #[cfg(feature = "RAII")]
+        None,
+        1,
+        backend.clone(),
+    );
+    assert_ok!(set.map(area, &mut pt, false, None));
+
+    // The range is split off and takes the shared flags.
+    assert_ok!(set.convert_private_to_shared(0x1000.into(), 0x2000, &mut pt));
+    assert_eq!(set.len(), 3);
+    let area = set.find(0x1000.into()).unwrap();
+    assert_eq!(area.va_range(), va_range!(0x1000..0x3000));
+    assert_eq!(area.confidentiality(), Confidentiality::Shared);
+    assert_eq!(area.flags(), 1 | SHARED_BIT);
+    assert!(
+        pt[0x1000..0x3000]
+            .iter()
+            .all(|&flags| flags == 1 | SHARED_BIT)
+    );
+    assert_eq!(pt[0], 1);
+    assert_eq!(
+        set.find(0.into()).unwrap().confidentiality(),
+        Confidentiality::Private
+    );
+
+    // A failed conversion is rolled back.
+    backend.fail_at(Op::Convert, 2);
+    assert_err!(
+        set.convert_private_to_shared(0.into(), 0x4000, &mut pt),
+        BadState
+    );
+    assert_eq!(
+        set.find(0.into()).unwrap().confidentiality(),
+        Confidentiality::Private
+    );
+    assert_eq!(pt[0], 1);
+    assert_eq!(pt[0x1000], 1 | SHARED_BIT);
+    assert_eq!(pt[0x3000], 1);
+
+    assert_ok!(set.convert_shared_to_private(0.into(), 0x4000, &mut pt));
+    assert!(
+        set.iter()
+            .all(|area| area.confidentiality() == Confidentiality::Private)
+    );
+    assert!(pt[0..0x4000].iter().all(|&flags| flags == 1));
+    assert_err!(
+        set.convert_private_to_shared(0x3000.into(), 0x2000, &mut pt),
+        NotMapped
+    );
+    assert_ok!(set.seal(0x3000.into(), 0x1000));
+    assert_err!(
+        set.convert_private_to_shared(0.into(), 0x4000, &mut pt),
+        PermissionDenied
+    );
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_frame_quota() {
+    use crate::test_utils::WRITE_ACCESS;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_zero_frame();
+    let area = MemoryArea::new(0.into(), 0x4000, None, 1, backend);
+    assert_ok!(set.map(area, &mut pt, false, None));
+    set.set_frame_quota(Some(0x2000));
+
+    // The zero pages are free, the private copies count.
+    assert_ok!(set.populate(0.into(), 0x4000, 1, &mut pt));
+    assert_eq!(set.frame_usage(), 0);
+    for page in [0, 0x1000] {
+        assert_ok!(set.handle_page_fault(page.into(), WRITE_ACCESS | 1, &mut pt));
+    }
+    assert_eq!(set.frame_usage(), 0x2000);
+
+    // A fault beyond the quota fails before allocating, but pages with
+    // frames of their own can still fault.
+    assert_err!(
+        set.handle_page_fault(0x2000.into(), WRITE_ACCESS | 1, &mut pt),
+        OutOfFrames
+    );
+    assert_err!(
+        set.write_bytes(0x2ffe.into(), &[1, 2, 3, 4], WRITE_ACCESS | 1, &mut pt),
+        OutOfFrames
+    );
+    assert_eq!(set.find(0.into()).unwrap().zero_pages(), 2);
+    assert_ok!(set.handle_page_fault(0x1000.into(), WRITE_ACCESS | 1, &mut pt));
+
+    // Releasing frames makes room.
+    assert_ok!(set.unmap(0.into(), 0x1000, &mut pt));
+    assert_eq!(set.frame_usage(), 0x1000);
+    assert_ok!(set.handle_page_fault(0x2000.into(), WRITE_ACCESS | 1, &mut pt));
+    assert_eq!(set.frame_usage(), set.stat().rss);
+
+    let mut new_pt = test_page_table(MAX_ADDR);
+    let new_set = set.clone_cow(&mut pt, &mut new_pt).unwrap();
+    assert_eq!(new_set.frame_quota(), Some(0x2000));
+    assert_eq!(new_set.frame_usage(), 0x2000);
+}
+
+#[test]
+fn test_populate() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new()
+        .with_granularity(0x1000)
+        .with_access_check();
+    let area = |start: usize, size, flags| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            flags,
+            backend.clone(),
+        )
+    };
+    assert_ok!(set.map(area(0, 0x4000, 1), &mut pt, false, None));
+    assert_ok!(set.map(area(0x4000, 0x2000, 1), &mut pt, false, None));
+    assert_ok!(set.reserve(area(0x6000, 0x1000, 1)));
+    assert_ok!(set.add_hole(area(0x8000, 0x1000, 1)));
+    assert_ok!(set.map(area(0x9000, 0x1000, 2), &mut pt, false, None));
+
+    // Every page across the areas, the length rounded up.
+    let populated = set.populate(0.into(), 0x5800, 1, &mut pt).unwrap();
+    assert_eq!((populated.pages, populated.resume_at), (6, None));
+    assert_eq!(set.populate(0.into(), 0, 1, &mut pt).unwrap().pages, 0);
+    assert_err!(set.populate(0x800.into(), 0x1000, 1, &mut pt), InvalidParam);
+
+    // Under pressure, it stops before the page to resume from.
+    backend.pressure_at(3);
+    let populated = set.populate(0.into(), 0x6000, 1, &mut pt).unwrap();
+    assert_eq!(
+        (populated.pages, populated.resume_at),
+        (2, Some(0x2000.into()))
+    );
+    backend.pressure_at(1);
+    let populated = set.populate(0x2000.into(), 0x4000, 1, &mut pt).unwrap();
+    assert_eq!(
+        (populated.pages, populated.resume_at),
+        (0, Some(0x2000.into()))
+    );
+
+    // Resident pages are skipped without checking the pressure.
+    #[cfg(feature = "RAII")]
+    {
+        use crate::test_utils::TestFrame;
+        use memory_addr::FrameTracker;
+        set.insert_frame(0x2000.into(), std::sync::Arc::new(TestFrame::alloc_frame()));
+        let populated = set.populate(0x2000.into(), 0x1000, 1, &mut pt).unwrap();
+        assert_eq!((populated.pages, populated.resume_at), (0, None));
+    }
+
+    // Ranges that cannot be populated fail before anything is done, even
+    // under pressure.
+    assert_err!(
+        set.populate(0x5000.into(), 0x2000, 1, &mut pt),
+        PermissionDenied
+    );
+    assert_err!(set.populate(0x6000.into(), 0x2000, 1, &mut pt), NotMapped);
+    assert_err!(set.populate(0x8000.into(), 0x1000, 1, &mut pt), NotMapped);
+    assert_err!(
+        set.populate(0x9000.into(), 0x1000, 1, &mut pt),
+        PermissionDenied
+    );
+    backend.pressure_at(0);
+    let populated = set.populate(0x9000.into(), 0x1000, 2, &mut pt).unwrap();
+    assert_eq!((populated.pages, populated.resume_at), (1, None));
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_map_populate() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_zero_frame().with_access_check();
+    let new_area = |start: usize| MemoryArea::new(start.into(), 0x4000, None, 1, backend.clone());
+
+    let populated = set
+        .map_populate(new_area(0), 1, &mut pt, MapMode::NoReplace)
+        .unwrap();
+    assert_eq!(populated.pages, 4);
+    assert_eq!(populated.resume_at, None);
+    assert_eq!(set.find(0.into()).unwrap().zero_pages(), 4);
+
+    // Nothing is mapped if the access is not allowed or populating fails.
+    assert_err!(
+        set.map_populate(new_area(0x4000), 2, &mut pt, MapMode::NoReplace),
+        PermissionDenied
+    );
+    set.set_frame_quota(Some(0));
+    assert_err!(
+        set.map_populate(new_area(0x4000), 1, &mut pt, MapMode::NoReplace),
+        OutOfFrames
+    );
+    assert!(set.find(0x4000.into()).is_none());
+    assert!(pt[0x4000..0x8000].iter().all(|&flags| flags == 0));
+
+    // Failing to replace mappings keeps them.
+    assert_ok!(set.map(new_area(0x4000), &mut pt, false, None));
+    let entries = pt[..0x8000].to_vec();
+    assert_err!(
+        set.map_populate(new_area(0x2000), 1, &mut pt, MapMode::Fixed),
+        OutOfFrames
+    );
+    assert_eq!(set.len(), 4);
+    assert_eq!(set.total_size(), 0x8000);
+    assert_eq!(set.find(0x2000.into()).unwrap().zero_pages(), 2);
+    assert_eq!(pt[..0x8000], entries[..]);
+    set.set_frame_quota(None);
+    assert_ok!(set.map_populate(new_area(0x2000), 1, &mut pt, MapMode::Fixed));
+    assert_eq!(
+        set.find(0x2000.into()).unwrap().va_range(),
+        va_range!(0x2000..0x6000)
+    );
+    assert_eq!(
+        set.find(0x6000.into()).unwrap().va_range(),
+        va_range!(0x6000..0x8000)
+    );
+    set.check_invariants();
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_shrinker() {
+    use crate::test_utils::{TestFrame, TestPageTable};
+    use crate::{SetShrinker, ShrinkerRegistry, SwapBackend};
+    use memory_addr::FrameTracker;
+    use std::sync::{Arc, Mutex};
+
+    struct VecSwap(Mutex<Vec<Vec<u8>>>);
+
+    impl SwapBackend for VecSwap {
+        fn store(&self, data: &[u8]) -> Option<usize> {
+            let mut slots = self.0.lock().unwrap();
+            slots.push(data.to_vec());
+            Some(slots.len() - 1)
+        }
+
+        fn load(&self, slot: usize, data: &mut [u8]) {
+            data.copy_from_slice(&self.0.lock().unwrap()[slot]);
+        }
+
+        fn free(&self, _slot: usize) {}
+    }
+
+    /// Maps `pages` pages with frames of their own in a new set.
+    fn new_space(pages: usize, swap: bool) -> Arc<Mutex<(MockMemorySet, TestPageTable)>> {
+        let mut set = MockMemorySet::new();
+        let mut pt = test_page_table(MAX_ADDR);
+        assert_ok!(set.map(new_area(0.into(), 0x8000, 1), &mut pt, false, None));
+        for i in 0..pages {
+            set.insert_frame((i * 0x1000).into(), Arc::new(TestFrame::alloc_frame()));
+        }
+        if swap {
+            set.set_swap(VecSwap(Mutex::new(Vec::new())));
+        }
+        Arc::new(Mutex::new((set, pt)))
+    }
+
+    fn shrinker(
+        space: &Arc<Mutex<(MockMemorySet, TestPageTable)>>,
+    ) -> Arc<SetShrinker<MockBackend>> {
+        let space = space.clone();
+        Arc::new(SetShrinker::new(move |f| {
+            let (set, pt) = &mut *space.lock().unwrap();
+            f(set, pt)
+        }))
+    }
+
+    let small = new_space(2, true);
+    let large = new_space(5, true);
+    let unswappable = new_space(3, false);
+    let mut registry = ShrinkerRegistry::new();
+    let small_id = registry.register(shrinker(&small));
+    registry.register(shrinker(&large));
+    registry.register(shrinker(&unswappable));
+    assert_eq!(registry.len(), 3);
+    assert_eq!(registry.count(), 0x7000);
+
+    // The largest shrinker goes first, then the others for what is missing.
+    assert_eq!(registry.shrink(0x3000), 0x3000);
+    assert_eq!(large.lock().unwrap().0.reclaimable(), 0x2000);
+    assert_eq!(small.lock().unwrap().0.reclaimable(), 0x2000);
+    assert_eq!(registry.shrink(0x3000), 0x3000);
+    assert_eq!(large.lock().unwrap().0.reclaimable(), 0x1000);
+    assert_eq!(small.lock().unwrap().0.reclaimable(), 0);
+    {
+        let (set, _) = &*large.lock().unwrap();
+        let area = set.find(0.into()).unwrap();
+        assert!(area.is_swapped(0x3000.into()) && !area.is_swapped(0x4000.into()));
+    }
+
+    // Nothing is left but the unswappable set once the others are gone.
+    assert!(registry.unregister(small_id).is_some());
+    assert!(registry.unregister(small_id).is_none());
+    assert_eq!(registry.shrink(0x2000), 0x1000);
+    assert_eq!(registry.shrink(0x1000), 0);
+    assert_eq!(unswappable.lock().unwrap().0.stat().rss, 0x3000);
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_file_sync() {
+    use crate::test_utils::{TestFrame, WRITE_ACCESS};
+    use crate::{MmapObject, SyncMode};
+    use memory_addr::FrameTracker;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    /// A three-page file with a page cache, recording its write-backs.
+    #[derive(Default)]
+    struct File {
+        cache: Mutex<BTreeMap<usize, Arc<TestFrame>>>,
+        written: Mutex<Vec<(usize, usize, SyncMode)>>,
+    }
+
+    impl MmapObject<MockBackend> for File {
+        fn page(&self, offset: usize) -> Option<Arc<TestFrame>> {
+            let mut cache = self.cache.lock().unwrap();
+            let frame = cache
+                .entry(offset)
+                .or_insert_with(|| Arc::new(TestFrame::alloc_frame()));
+            Some(frame.clone())
+        }
+
+        fn write_back(&self, offset: usize, size: usize, mode: SyncMode) {
+            self.written.lock().unwrap().push((offset, size, mode));
+        }
+    }
+
+    let file = Arc::new(File::default());
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    for (start, shared) in [(0x1000, true), (0x4000, false)] {
+        let area = MemoryArea::new_file(
+            start.into(),
+            0x3000,
+            file.clone(),
+            0,
+            shared,
+            1,
+            MockBackend::new(),
+        );
+        assert_ok!(set.map(area, &mut pt, false, None));
+    }
+    let flags = WRITE_ACCESS | 1;
+    assert_ok!(set.handle_page_fault(0x1000.into(), 1, &mut pt));
+    assert_ok!(set.handle_page_fault(0x2000.into(), flags, &mut pt));
+    assert_ok!(set.handle_page_fault(0x4000.into(), flags, &mut pt));
+
+    // The pages resident before the first sync are all dirty.
+    let area = set.find(0x1000.into()).unwrap();
+    assert!(area.is_file_dirty(0x1000.into()) && !area.is_file_dirty(0x3000.into()));
+    assert_eq!(
+        set.sync(0x1000.into(), 0x6000, SyncMode::Sync, &mut pt),
+        Ok(2)
+    );
+    assert!(
+        !set.find(0x1000.into())
+            .unwrap()
+            .is_file_dirty(0x1000.into())
+    );
+    assert_eq!(
+        set.sync(0x1000.into(), 0x6000, SyncMode::Sync, &mut pt),
+        Ok(0)
+    );
+
+    // Writes after the sync fault, and only their pages are written back.
+    assert_ok!(set.handle_page_fault(0x2000.into(), flags, &mut pt));
+    assert_ok!(set.handle_page_fault(0x3000.into(), flags, &mut pt));
+    assert!(
+        set.find(0x1000.into())
+            .unwrap()
+            .is_file_dirty(0x2000.into())
+    );
+    assert_eq!(
+        set.sync(0x3000.into(), 0x1000, SyncMode::Async, &mut pt),
+        Ok(1)
+    );
+    assert_eq!(
+        set.sync(0x1000.into(), 0x3000, SyncMode::Async, &mut pt),
+        Ok(1)
+    );
+    assert_err!(
+        set.sync(0x1000.into(), 0x8000, SyncMode::Sync, &mut pt),
+        NotMapped
+    );
+    assert_eq!(
+        *file.written.lock().unwrap(),
+        [
+            (0, 0x2000, SyncMode::Sync),
+            (0x2000, 0x1000, SyncMode::Async),
+            (0x1000, 0x1000, SyncMode::Async),
+        ]
+    );
+}
+
+#[test]
+fn test_layout_and_invariants() {
+    use crate::test_utils::assert_layout_eq;
+    use std::panic::{AssertUnwindSafe, catch_unwind};
+
+    let mut a = MockMemorySet::new();
+    let mut b = MockMemorySet::new();
+    let mut pt_a = test_page_table(MAX_ADDR);
+    let mut pt_b = test_page_table(MAX_ADDR);
+    for (start, size) in [(0x1000, 0x3000), (0x6000, 0x2000)] {
+        assert_ok!(a.map(new_area(start.into(), size, 1), &mut pt_a, false, None));
+        assert_ok!(b.map(new_area(start.into(), size, 1), &mut pt_b, false, None));
+    }
+    assert_ok!(a.populate(0x1000.into(), 0x2000, 1, &mut pt_a));
+    a.check_invariants();
+    b.check_invariants();
+
+    // Frames do not matter, but flags and splits do.
+    assert_layout_eq(&a, &b);
+    assert_ok!(b.protect(0x2000.into(), 0x1000, |_| Some(2), &mut pt_b));
+    b.check_invariants();
+    assert!(catch_unwind(AssertUnwindSafe(|| assert_layout_eq(&a, &b))).is_err());
+    assert_ok!(b.protect(0x2000.into(), 0x1000, |_| Some(1), &mut pt_b));
+    assert!(catch_unwind(AssertUnwindSafe(|| assert_layout_eq(&a, &b))).is_err());
+    assert_ok!(a.clear(&mut pt_a));
+    assert!(catch_unwind(AssertUnwindSafe(|| assert_layout_eq(&b, &a))).is_err());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_layout_restore() {
+    use crate::SetLayout;
+    use crate::test_utils::assert_layout_eq;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0x1000.into(), 0x2000, 1), &mut pt, false, None));
+    let mut labeled = new_area(0x4000.into(), 0x3000, 3);
+    labeled.set_label(Some("heap".into()));
+    assert_ok!(set.map(labeled, &mut pt, false, None));
+
+    let json = serde_json::to_string(&set.layout()).unwrap();
+    let layout: SetLayout<MockBackend> = serde_json::from_str(&json).unwrap();
+    let mut new_pt = test_page_table(MAX_ADDR);
+    let restored = MockMemorySet::restore(&layout, &mut new_pt).unwrap();
+    assert_layout_eq(&set, &restored);
+    assert_eq!(restored.find(0x5000.into()).unwrap().label(), Some("heap"));
+    assert_eq!(new_pt, pt);
+
+    // A conflicting layout leaves nothing mapped.
+    let mut bad = layout.clone();
+    bad.areas[1].start = 0x2000;
+    let mut bad_pt = test_page_table(MAX_ADDR);
+    assert_err!(MockMemorySet::restore(&bad, &mut bad_pt), AlreadyExists);
+    assert!(bad_pt.iter().all(|&entry| entry == 0));
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_debug_alternate() {
+    use crate::test_utils::TestFrame;
+    use memory_addr::FrameTracker;
+    use std::sync::Arc;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0x1000.into(), 0x2000, 1), &mut pt, false, None));
+    assert_ok!(set.map(new_area(0x5000.into(), 0x1000, 1), &mut pt, false, None));
+    set.insert_frame(0x1000.into(), Arc::new(TestFrame::alloc_frame()));
+
+    // The default form stays terse.
+    let terse = format!("{set:?}");
+    assert!(terse.contains("va_range"));
+    assert!(!terse.contains("frames") && !terse.contains("gap_before"));
+
+    let verbose = format!("{set:#?}");
+    for field in [
+        "frames: 1,",
+        "resident: [",
+        "mapping_kind: Anonymous,",
+        "gap_before: None,",
+        "gap_after: Some(\n            8192,",
+        "gap_after: None,",
+    ] {
+        assert!(verbose.contains(field), "{field:?} missing in {verbose}");
+    }
+    assert!(format!("{:#?}", set.find(0x5000.into()).unwrap()).contains("swapped: 0,"));
+}
+
+#[test]
+fn test_static_memory_set() {
+    use crate::StaticMemorySet;
+
+    let mut set = StaticMemorySet::<MockBackend, 3>::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new();
+    assert_ok!(set.map(0x4000.into(), 0x4000, 1, backend.clone(), &mut pt));
+    assert_ok!(set.map(0x1000.into(), 0x1000, 1, backend.clone(), &mut pt));
+    assert_err!(
+        set.map(0x3000.into(), 0x2000, 1, backend.clone(), &mut pt),
+        AlreadyExists
+    );
+    assert_err!(
+        set.map(0x3000.into(), 0, 1, backend.clone(), &mut pt),
+        InvalidParam
+    );
+    assert_eq!(set.find(0x5000.into()).unwrap().start(), 0x4000.into());
+    assert!(set.find(0x3000.into()).is_none());
+
+    // Splitting in three needs two free slots.
+    assert_err!(
+        set.protect(0x5000.into(), 0x1000, 2, &mut pt),
+        LimitExceeded
+    );
+    assert_ok!(set.protect(0x5000.into(), 0x3000, 2, &mut pt));
+    assert_eq!(set.len(), 3);
+    assert_eq!(pt[0x4000], 1);
+    assert_eq!(pt[0x7fff], 2);
+    assert_err!(
+        set.map(0xa000.into(), 0x1000, 1, backend.clone(), &mut pt),
+        LimitExceeded
+    );
+
+    // Unmapping the middle of a full set fails, the edges shrink.
+    assert_err!(set.unmap(0x6000.into(), 0x1000, &mut pt), LimitExceeded);
+    assert_ok!(set.unmap(0x1000.into(), 0x5000, &mut pt));
+    let ranges: Vec<_> = set.iter().map(|area| area.va_range()).collect();
+    assert_eq!(ranges, [va_range!(0x6000..0x8000)]);
+    assert_eq!(pt[0x5fff], 0);
+    assert_eq!(pt[0x6000], 2);
+
+    assert_ok!(set.map(0x1000.into(), 0x1000, 1, backend.clone(), &mut pt));
+    assert_ok!(set.unmap(0x6000.into(), 0x1000, &mut pt));
+    assert_ok!(set.clear(&mut pt));
+    assert!(set.is_empty());
+    assert!(pt.iter().all(|&entry| entry == 0));
+}
+
+#[cfg(all(feature = "sync", feature = "RAII"))]
+#[test]
+fn test_sync_memory_set() {
+    use crate::SyncMemorySet;
+    use std::sync::Arc;
+    use std::thread;
+
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_zero_frame().with_access_check();
+    let mut set = MockMemorySet::new();
+    let area = MemoryArea::new(0x1000.into(), 0x4000, None, 1, backend.clone());
+    assert_ok!(set.map(area, &mut pt, false, None));
+    let set = Arc::new(SyncMemorySet::from(set));
+    let area = MemoryArea::new(0x8000.into(), 0x4000, None, 1, backend.clone());
+    assert_ok!(set.map(area, &mut pt));
+    let area = MemoryArea::new(0x4000.into(), 0x2000, None, 1, backend.clone());
+    assert_err!(set.map(area, &mut pt), AlreadyExists);
+
+    // Faults in different areas run in parallel.
+    let threads: Vec<_> = [0x1000, 0x8000]
+        .into_iter()
+        .map(|start| {
+            let set = set.clone();
+            let mut pt = pt.clone();
+            thread::spawn(move || {
+                for page in (start..start + 0x4000).step_by(0x1000) {
+                    set.handle_page_fault(page.into(), 1, &mut pt).unwrap();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    for start in [0x1000, 0x8000] {
+        assert_eq!(
+            set.with_area(start.into(), |area| area.zero_pages()),
+            Some(4)
+        );
+    }
+    assert_err!(set.handle_page_fault(0x6000.into(), 1, &mut pt), NotMapped);
+    assert_err!(
+        set.handle_page_fault(0x1000.into(), 2, &mut pt),
+        PermissionDenied
+    );
+
+    // Protecting and unmapping split the areas.
+    assert_ok!(set.protect(0x2000.into(), 0x1000, 3, &mut pt));
+    assert_eq!(set.len(), 4);
+    assert_eq!(set.with_area(0x2000.into(), |area| area.flags()), Some(3));
+    assert_ok!(set.unmap(0x3000.into(), 0x6000, &mut pt));
+    assert_eq!(set.len(), 3);
+    assert!(set.with_area(0x8000.into(), |_| ()).is_none());
+    assert_eq!(
+        set.with_area(0x9000.into(), |area| area.va_range()),
+        Some(va_range!(0x9000..0xc000))
+    );
+
+    // An area that fails to unmap is kept.
+    backend.fail_at(Op::Unmap, 1);
+    assert_err!(set.unmap(0x9000.into(), 0x3000, &mut pt), BadState);
+    assert_eq!(
+        set.with_area(0x9000.into(), |area| area.va_range()),
+        Some(va_range!(0x9000..0xc000))
+    );
+}
+
+#[cfg(feature = "sync")]
+#[test]
+fn test_sync_memory_set_checks() {
+    use crate::SyncMemorySet;
+
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_granularity(0x1000);
+    let area = |start: usize, size| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            1,
+            backend.clone(),
+        )
+    };
+    let mut set = MockMemorySet::new();
+    assert_ok!(set.map(area(0x1000, 0x2000), &mut pt, false, None));
+    assert_ok!(set.seal(0x1000.into(), 0x2000));
+    assert_ok!(set.add_hole(area(0x4000, 0x1000)));
+    assert_ok!(set.map(area(0x6000, 0x2000), &mut pt, false, None));
+    let set = SyncMemorySet::from(set);
+    let range = |addr: usize| set.with_area(addr.into(), |area| area.va_range());
+
+    // Sealed areas and holes are neither unmapped nor protected, even in
+    // part, and nothing is split on the way.
+    for (start, size) in [
+        (0x1000, 0x1000),
+        (0x2000, 0x2000),
+        (0x4000, 0x1000),
+        (0, 0x8000),
+    ] {
+        assert_err!(set.unmap(start.into(), size, &mut pt), PermissionDenied);
+        assert_err!(
+            set.protect(start.into(), size, 2, &mut pt),
+            PermissionDenied
+        );
+    }
+    assert_eq!(set.len(), 3);
+    assert_eq!(range(0x1000), Some(va_range!(0x1000..0x3000)));
+    assert_eq!(set.with_area(0x1000.into(), |area| area.flags()), Some(1));
+    assert!(pt[0x1000..0x3000].iter().all(|&flags| flags == 1));
+
+    // Misaligned ranges are refused before anything is split, and ends are
+    // rounded up to the granularity.
+    assert_err!(set.unmap(0x6800.into(), 0x800, &mut pt), InvalidParam);
+    assert_err!(set.protect(0x6800.into(), 0x800, 2, &mut pt), InvalidParam);
+    assert_eq!(range(0x6000), Some(va_range!(0x6000..0x8000)));
+    assert_ok!(set.protect(0x6000.into(), 0x800, 2, &mut pt));
+    assert_eq!(range(0x6000), Some(va_range!(0x6000..0x7000)));
+    assert_eq!(set.with_area(0x6000.into(), |area| area.flags()), Some(2));
+    assert_ok!(set.unmap(0x7000.into(), 0x10, &mut pt));
+    assert!(range(0x7000).is_none());
+    assert_ok!(set.unmap(0x7000.into(), 0, &mut pt));
+
+    // Empty or misaligned areas are refused, and so are the areas
+    // overlapping another one or its guards, instead of replacing it.
+    for area in [area(0x6000, 0), area(0x8000, 0x800), area(0x8800, 0x1000)] {
+        assert_err!(set.map(area, &mut pt), InvalidParam);
+    }
+    for area in [
+        area(0x6000, 0x1000),
+        area(0x5000, 0x2000),
+        area(0x7000, 0x1000).with_guards(0x1000, 0),
+    ] {
+        assert_err!(set.map(area, &mut pt), AlreadyExists);
+    }
+    assert_eq!(range(0x6000), Some(va_range!(0x6000..0x7000)));
+    assert_ok!(set.map(area(0x8000, 0x1000).with_guards(0x1000, 0), &mut pt));
+    assert_eq!(set.len(), 4);
+    assert!(pt[0x7000..0x8000].iter().all(|&flags| flags == 0));
+    assert!(pt[0x8000..0x9000].iter().all(|&flags| flags == 1));
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_page_fault_async() {
+    use crate::SwapBackend;
+    use crate::test_utils::TestFrame;
+    use core::future::Future;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+    use memory_addr::FrameTracker;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// A swap whose pages take one poll to be read in.
+    #[derive(Default)]
+    struct SlowSwap {
+        slots: Mutex<Vec<Vec<u8>>>,
+        cached: AtomicBool,
+    }
+
+    impl SwapBackend for SlowSwap {
+        fn store(&self, data: &[u8]) -> Option<usize> {
+            let mut slots = self.slots.lock().unwrap();
+            slots.push(data.to_vec());
+            Some(slots.len() - 1)
+        }
+
+        fn load(&self, slot: usize, data: &mut [u8]) {
+            data.copy_from_slice(&self.slots.lock().unwrap()[slot]);
+        }
+
+        fn free(&self, _slot: usize) {}
+
+        fn poll_ready(&self, _slot: usize, cx: &mut Context<'_>) -> Poll<()> {
+            if self.cached.swap(true, Ordering::SeqCst) {
+                return Poll::Ready(());
+            }
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_zero_frame();
+    let area = MemoryArea::new(0x1000.into(), 0x2000, None, 1, backend.clone());
+    assert_ok!(set.map(area, &mut pt, false, None));
+    let mut cx = Context::from_waker(Waker::noop());
+
+    // The fault waits for the backend, then resolves.
+    backend.set_pending_faults(2);
+    let mut fault = pin!(set.handle_page_fault_async(0x1000.into(), 1, &mut pt));
+    assert!(fault.as_mut().poll(&mut cx).is_pending());
+    assert!(fault.as_mut().poll(&mut cx).is_pending());
+    assert_eq!(fault.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+    assert_eq!(set.find(0x1000.into()).unwrap().zero_pages(), 1);
+    let fault = pin!(set.handle_page_fault_async(0x4000.into(), 1, &mut pt));
+    assert!(matches!(
+        fault.poll(&mut cx),
+        Poll::Ready(Err(MappingError::NotMapped(_)))
+    ));
+
+    // Swapped-out pages wait for the swap instead.
+    let mut frame = TestFrame::alloc_frame();
+    frame.as_mut_slice().fill(7);
+    set.insert_frame(0x2000.into(), Arc::new(frame));
+    set.set_swap(SlowSwap::default());
+    assert_eq!(set.swap_out(0x2000.into(), 0x1000, &mut pt), Ok(1));
+    let mut fault = pin!(set.handle_page_fault_async(0x2000.into(), 1, &mut pt));
+    assert!(fault.as_mut().poll(&mut cx).is_pending());
+    assert_eq!(fault.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+    let mut data = [0; 1];
+    assert_ok!(set.read_bytes(0x2000.into(), &mut data, 1, &mut pt));
+    assert_eq!(data, [7]);
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_set_reader() {
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_zero_frame().with_access_check();
+    let mut set = MockMemorySet::new();
+    for start in [0x1000, 0x8000] {
+        let area = MemoryArea::new(start.into(), 0x4000, None, 1, backend.clone());
+        assert_ok!(set.map(area, &mut pt, false, None));
+    }
+    let reader = set.reader();
+    assert_eq!(reader.len(), 2);
+    assert_eq!(reader.find(0x9000.into()).unwrap().start(), 0x8000.into());
+    assert!(reader.find(0x5000.into()).is_none());
+    assert_ok!(reader.check_fault(0x2000.into(), 1));
+    assert_err!(reader.check_fault(0x6000.into(), 1), NotMapped);
+    assert_err!(reader.check_fault(0x2000.into(), 2), PermissionDenied);
+
+    // Faults keep the view current.
+    assert_ok!(set.handle_page_fault(0x2000.into(), 1, &mut pt));
+    assert!(reader.is_current());
+
+    // Changing the layout makes it stale, and a new reader sees the change.
+    assert_ok!(set.protect(0x1000.into(), 0x4000, |_| Some(3), &mut pt));
+    assert!(!reader.is_current());
+    assert_err!(reader.check_fault(0x2000.into(), 2), PermissionDenied);
+    let reader = set.reader();
+    assert!(reader.is_current());
+    assert_ok!(reader.check_fault(0x2000.into(), 2));
+    assert!(reader.clone().is_current());
+}
+
+#[test]
+fn test_tlb_lookup() {
+    use crate::TlbCache;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0.into(), 0x8000, 1), &mut pt, false, None));
+    let mut cache = TlbCache::<MockBackend, 4>::new();
+    // Unmapped addresses and pages without translation refill nothing.
+    assert!(set.lookup_for_tlb(0x8000.into()).is_none());
+    assert!(set.lookup_for_tlb(0x6000.into()).is_none());
+    assert!(cache.lookup(&set, 0x6000.into()).is_none());
+    assert!(cache.lookup(&set, 0x6000.into()).is_none());
+    assert_eq!(cache.stats(), (0, 2));
+
+    #[cfg(feature = "RAII")]
+    {
+        use crate::TlbEntry;
+        use crate::test_utils::TestFrame;
+        use memory_addr::{FrameTracker, PhysAddr};
+        use std::sync::Arc;
+
+        let huge_pa = PhysAddr::from(0x80_0000);
+        let area = set.find_mut(0.into()).unwrap();
+        area.insert_frame_sized(0.into(), Arc::new(TestFrame::no_tracking(huge_pa)), 0x4000);
+        let frame = Arc::new(TestFrame::alloc_frame());
+        area.insert_frame(0x5000.into(), frame.clone());
+        let small = TlbEntry {
+            vaddr: 0x5000.into(),
+            paddr: frame.start(),
+            flags: 1,
+            page_size: 0x1000,
+        };
+        assert_eq!(set.lookup_for_tlb(0x5fff.into()), Some(small));
+        // Addresses within a huge frame get the whole frame.
+        let huge = TlbEntry {
+            vaddr: 0.into(),
+            paddr: huge_pa,
+            flags: 1,
+            page_size: 0x4000,
+        };
+        assert_eq!(set.lookup_for_tlb(0x2800.into()), Some(huge));
+        assert!(huge.contains(0x3fff.into()));
+        assert!(!huge.contains(0x4000.into()));
+
+        // The cache serves the addresses of a page from one refill.
+        let mut cache = TlbCache::<MockBackend, 4>::new();
+        assert_eq!(cache.lookup(&set, 0x5000.into()), Some(small));
+        assert_eq!(cache.lookup(&set, 0x5800.into()), Some(small));
+        assert_eq!(cache.stats(), (1, 1));
+        // A page of the same slot evicts it.
+        assert_eq!(cache.lookup(&set, 0x1000.into()), Some(huge));
+        assert_eq!(cache.lookup(&set, 0x5000.into()), Some(small));
+        assert_eq!(cache.stats(), (1, 3));
+        // Any change to the set flushes the cache.
+        assert_ok!(set.protect(0x4000.into(), 0x4000, |_| Some(2), &mut pt));
+        let entry = cache.lookup(&set, 0x5000.into()).unwrap();
+        assert_eq!(entry.flags, 2);
+        assert_eq!(cache.stats(), (1, 4));
+        cache.flush();
+        assert!(cache.lookup(&set, 0x5000.into()).is_some());
+        assert_eq!(cache.stats(), (1, 5));
+        assert_ok!(set.unmap(0x4000.into(), 0x4000, &mut pt));
+        assert!(cache.lookup(&set, 0x5000.into()).is_none());
+    }
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_remap_window() {
+    use crate::test_utils::TestFrame;
+    use memory_addr::FrameTracker;
+    use std::sync::Arc;
+
+    let mut user_pt = test_page_table(MAX_ADDR);
+    let mut user = MockMemorySet::new();
+    assert_ok!(user.map(
+        new_area(0x1000.into(), 0x3000, 1),
+        &mut user_pt,
+        false,
+        None
+    ));
+    for page in [0x1000, 0x2000] {
+        let mut frame = TestFrame::alloc_frame();
+        frame.as_mut_slice().fill(page as u8 + 1);
+        user.insert_frame(page.into(), Arc::new(frame));
+    }
+    assert_err!(
+        user.pin_pages(0x1800.into(), 0, 1, &mut user_pt),
+        InvalidParam
+    );
+    assert_err!(
+        user.pin_pages(0x3800.into(), 0x1000, 1, &mut user_pt),
+        NotMapped
+    );
+    let pages = user
+        .pin_pages(0x1800.into(), 0x1000, 1, &mut user_pt)
+        .unwrap();
+    assert_eq!(pages.len(), 0x1000);
+    let user_frame = user.find_frame(0x2000.into()).unwrap();
+    assert_eq!(pages.frame(0x800).unwrap().start(), user_frame.start());
+    assert!(pages.frame(0x1000).is_none());
+
+    // The window maps the same frames in the kernel set until dropped.
+    let mut kernel_pt = test_page_table(MAX_ADDR);
+    let mut kernel = MockMemorySet::new();
+    assert_ok!(kernel.map(
+        new_area(0x8000.into(), 0x1000, 1),
+        &mut kernel_pt,
+        false,
+        None
+    ));
+    let limit = va_range!(0x8000..MAX_ADDR);
+    let window = kernel
+        .map_window(&pages, 1, MockBackend::new(), limit, &mut kernel_pt)
+        .unwrap();
+    assert_eq!(window.addr(), 0x9800.into());
+    assert_eq!(window.area_range(), va_range!(0x9000..0xb000));
+    drop(window);
+    assert_eq!(kernel.len(), 1);
+
+    // The frames outlive the user mapping.
+    assert_ok!(user.unmap(0x1000.into(), 0x3000, &mut user_pt));
+    assert_eq!(pages.frame(0).unwrap().as_slice()[0], 1);
+    let small = va_range!(0x9000..0xa000);
+    assert_err!(
+        kernel.map_window(&pages, 1, MockBackend::new(), small, &mut kernel_pt),
+        OutOfRange
+    );
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_user_guards() {
+    use crate::test_utils::{TestFrame, WRITE_ACCESS};
+    use memory_addr::FrameTracker;
+    use std::sync::Arc;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new().with_access_check();
+    let area = MemoryArea::new(0x1000.into(), 0x3000, None, 1, backend);
+    assert_ok!(set.map(area, &mut pt, false, None));
+    for page in [0x1000, 0x2000] {
+        set.insert_frame(page.into(), Arc::new(TestFrame::alloc_frame()));
+    }
+    assert_err!(set.user_ptr::<u32>(0x1002.into(), 1), Misaligned);
+    assert_err!(set.user_ptr::<u32>(0x4000.into(), 1), NotMapped);
+    assert_err!(set.user_ptr::<u32>(0x1000.into(), 2), PermissionDenied);
+
+    let ptr = set
+        .user_ptr::<u32>(0x1004.into(), WRITE_ACCESS | 1)
+        .unwrap();
+    assert_ok!(ptr.write(0xdead_beef, &mut set, &mut pt));
+    assert_eq!(ptr.read(&mut set, &mut pt).unwrap(), 0xdead_beef);
+    let ptr = set.user_ptr::<u32>(0x1004.into(), 1).unwrap();
+    assert_eq!(ptr.read(&mut set, &mut pt).unwrap(), 0xdead_beef);
+    assert_err!(ptr.write(0, &mut set, &mut pt), PermissionDenied);
+
+    // Slices cross pages, and their lengths are checked.
+    let slice = set
+        .user_slice::<u16>(0x1ffc.into(), 4, WRITE_ACCESS | 1)
+        .unwrap();
+    assert_ok!(slice.write_from(&[1, 2, 3, 4], &mut set, &mut pt));
+    let mut buf = [0; 4];
+    assert_ok!(slice.read_into(&mut buf, &mut set, &mut pt));
+    assert_eq!(buf, [1, 2, 3, 4]);
+    assert_eq!(slice.get(3).unwrap().read(&mut set, &mut pt).unwrap(), 4);
+    assert!(slice.get(4).is_none());
+    assert_err!(slice.write_from(&[1], &mut set, &mut pt), InvalidParam);
+
+    // Faults keep the guards valid, but unmapping makes them stale.
+    assert_ok!(set.handle_page_fault(0x3000.into(), 1, &mut pt));
+    assert_ok!(ptr.read(&mut set, &mut pt));
+    assert_ok!(set.unmap(0x3000.into(), 0x1000, &mut pt));
+    assert_err!(ptr.read(&mut set, &mut pt), InvalidParam);
+    assert_err!(slice.read_into(&mut buf, &mut set, &mut pt), InvalidParam);
+}
+
+#[cfg(feature = "RAII")]
+#[test]
+fn test_split_off() {
+    use crate::test_utils::TestFrame;
+    use memory_addr::FrameTracker;
+    use std::sync::Arc;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    for (start, size) in [(0x1000, 0x2000), (0x4000, 0x4000), (0x9000, 0x1000)] {
+        assert_ok!(set.map(new_area(start.into(), size, 1), &mut pt, false, None));
+    }
+    let frame = Arc::new(TestFrame::alloc_frame());
+    set.insert_frame(0x7000.into(), frame.clone());
+
+    // The area containing the address is split.
+    let mut upper = set.split_off(0x6000.into()).unwrap();
+    set.check_invariants();
+    upper.check_invariants();
+    let ranges = |set: &MockMemorySet| set.iter().map(|area| area.va_range()).collect::<Vec<_>>();
+    assert_eq!(
+        ranges(&set),
+        [va_range!(0x1000..0x3000), va_range!(0x4000..0x6000)]
+    );
+    assert_eq!(
+        ranges(&upper),
+        [va_range!(0x6000..0x8000), va_range!(0x9000..0xa000)]
+    );
+    assert_eq!(
+        upper.find_frame(0x7000.into()).unwrap().start(),
+        frame.start()
+    );
+    assert!(set.find_frame(0x7000.into()).is_none());
+
+    // Splitting at a boundary or past the end moves whole areas or nothing.
+    let top = upper.split_off(0x9000.into()).unwrap();
+    assert_eq!(ranges(&top), [va_range!(0x9000..0xa000)]);
+    assert!(upper.split_off(0x8000.into()).unwrap().is_empty());
+    assert_eq!(upper.len(), 1);
+    assert_ok!(upper.unmap(0x6000.into(), 0x2000, &mut pt));
+    assert!(upper.is_empty());
+}
+
+#[test]
+fn test_snapshot() {
+    use crate::snapshot::*;
+
+    let mut set = MockMemorySet::new().with_label("init");
+    let mut pt = test_page_table(MAX_ADDR);
+    let mut area = new_area(0x1000.into(), 0x2000, 1);
+    area.set_label(Some("heap".into()));
+    assert_ok!(set.map(area, &mut pt, false, None));
+    assert_ok!(set.map(new_area(0x5000.into(), 0x1000, 3), &mut pt, false, None));
+    assert_ok!(set.add_hole(new_area(0x8000.into(), 0x1000, 0)));
+
+    let mut writer = set.write_snapshot(Vec::new(), u64::from).unwrap();
+    writer.write_page(0x5000, &[1, 2, 3]).unwrap();
+    let mut buf = writer.finish().unwrap();
+    // Trailing bytes are left for the caller.
+    buf.push(0xff);
+
+    let mut reader = SnapshotReader::new(buf.as_slice()).unwrap();
+    assert_eq!(reader.version(), SNAPSHOT_VERSION);
+    let mut records = Vec::new();
+    while let Some(record) = reader.next_record().unwrap() {
+        records.push(record);
+    }
+    let area = |start, end, flags, label: Option<&str>| {
+        SnapshotRecord::Area(AreaRecord {
+            start,
+            end,
+            flags,
+            kind: "unknown".into(),
+            label: label.map(Into::into),
+        })
+    };
+    assert_eq!(
+        records,
+        [
+            SnapshotRecord::Owner("init".into()),
+            area(0x1000, 0x3000, 1, Some("heap")),
+            area(0x5000, 0x6000, 3, None),
+            SnapshotRecord::Page {
+                vaddr: 0x5000,
+                data: vec![1, 2, 3],
+            },
+        ]
+    );
+    assert_eq!(reader.next_record(), Ok(None));
+    assert_eq!(reader.into_inner(), [0xff]);
+
+    // Unknown records and trailing fields are skipped.
+    let mut buf = SNAPSHOT_MAGIC.to_vec();
+    buf.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+    buf.extend_from_slice(&[0x7f, 2, 0, 0, 0, 0xaa, 0xbb]);
+    buf.extend_from_slice(&[TAG_PAGE, 10, 0, 0, 0, 0x10, 0, 0, 0, 0, 0, 0, 0, 9, 9]);
+    buf.extend_from_slice(&[TAG_END, 0, 0, 0, 0]);
+    let mut reader = SnapshotReader::new(buf.as_slice()).unwrap();
+    assert_eq!(
+        reader.next_record(),
+        Ok(Some(SnapshotRecord::Page {
+            vaddr: 0x10,
+            data: vec![9, 9],
+        }))
+    );
+    assert_eq!(reader.next_record(), Ok(None));
+
+    // Bad headers and truncated snapshots.
+    assert_eq!(
+        SnapshotReader::new(&b"AXMX\x01\x00"[..]).err(),
+        Some(SnapshotError::BadMagic)
+    );
+    assert_eq!(
+        SnapshotReader::new(&b"AXMS\x02\x00"[..]).err(),
+        Some(SnapshotError::UnsupportedVersion(2))
+    );
+    let mut reader = SnapshotReader::new(&buf[..10]).unwrap();
+    assert_eq!(reader.next_record(), Err(SnapshotError::Io(())));
+}
+
+#[test]
+fn test_absorb() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    assert_ok!(set.map(new_area(0x1000.into(), 0x1000, 1), &mut pt, false, None));
+
+    let mut part = MockMemorySet::new();
+    let mut part_pt = test_page_table(MAX_ADDR);
+    assert_ok!(part.map(
+        new_area(0x3000.into(), 0x2000, 2),
+        &mut part_pt,
+        false,
+        None
+    ));
+    assert_ok!(part.map(
+        new_area(0x8000.into(), 0x1000, 3),
+        &mut part_pt,
+        false,
+        None
+    ));
+
+    // Overlapping sets are left alone.
+    let mut overlapping = MockMemorySet::new();
+    let mut other_pt = test_page_table(MAX_ADDR);
+    assert_ok!(overlapping.map(
+        new_area(0x1800.into(), 0x1000, 4),
+        &mut other_pt,
+        false,
+        None
+    ));
+    assert_err!(set.absorb(&mut overlapping, &mut pt), AlreadyExists);
+    assert_eq!(overlapping.len(), 1);
+    assert_eq!(set.len(), 1);
+
+    set.set_size_limit(Some(0x3000));
+    assert_err!(set.absorb(&mut part, &mut pt), QuotaExceeded);
+    set.set_size_limit(None);
+
+    assert_ok!(set.absorb(&mut part, &mut pt));
+    assert!(part.is_empty());
+    dump_memory_set(&set);
+    assert_eq!(set.len(), 3);
+    assert!(pt[0x3000..0x5000].iter().all(|&flags| flags == 2));
+    assert!(pt[0x8000..0x9000].iter().all(|&flags| flags == 3));
+    let addr = set.find_free_area(0x3000.into(), 0x1000, va_range!(0..MAX_ADDR));
+    assert_eq!(addr, Some(0x5000.into()));
+}
+
+#[test]
+fn test_map_failure_rollback() {
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new();
+    let area = |start: usize, size: usize, flags: MockFlags| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            flags,
+            backend.clone(),
+        )
+    };
+    assert_ok!(set.map(area(0, 0x2000, 1), &mut pt, false, None));
+
+    // Replacing [0x1000, 0x3000) fails, the evicted part is mapped again.
+    backend.fail_at(Op::Map, 1);
+    assert_err!(
+        set.map(area(0x1000, 0x2000, 2), &mut pt, true, None),
+        BadState
+    );
+    assert_eq!(backend.calls(Op::Map), 3);
+    assert!(pt[..0x2000].iter().all(|&flags| flags == 1));
+    assert_eq!(pt[0x2000], 0);
+    assert_eq!(set.iter().map(|area| area.size()).sum::<usize>(), 0x2000);
+
+    // The failure is reported once.
+    assert_ok!(set.map(area(0x2000, 0x1000, 2), &mut pt, false, None));
+    assert_eq!(pt[0x2000], 2);
+}
+
+#[test]
+fn test_error_context() {
+    use crate::test_utils::TestError;
+    use core::error::Error;
+
+    let mut set = MockMemorySet::new();
+    let mut pt = test_page_table(MAX_ADDR);
+    let backend = MockBackend::new();
+    let area = |start: usize, size: usize| {
+        MemoryArea::new(
+            start.into(),
+            size,
+            #[cfg(feature = "RAII")]
+            None,
+            1,
+            backend.clone(),
+        )
+    };
+    assert_ok!(set.map(area(0x1000, 0x1000), &mut pt, false, None));
+    assert_ok!(set.map(area(0x3000, 0x1000), &mut pt, false, None));
+
+    let err = set.map(area(0x800, 0x1000), &mut pt, false, None);
+    assert_eq!(
+        err,
+        Err(MappingError::AlreadyExists(addr_range!(0x800usize..0x1800)))
+    );
+    // The first hole of the range is reported.
+    let err = set.populate(0x1000.into(), 0x3000, 1, &mut pt).unwrap_err();
+    assert_eq!(
+        err,
+        MappingError::NotMapped(addr_range!(0x2000usize..0x3000))
+    );
+    assert_eq!(err.to_string(), "NotMapped at [0x2000, 0x3000)");
+
+    // Backend errors are kept as the source.
+    backend.fail_at(Op::Map, 1);
+    let err = set
+        .map(area(0x5000, 0x2000), &mut pt, false, None)
+        .unwrap_err();
+    assert_eq!(err.range(), addr_range!(0x5000usize..0x7000));
+    let source = err.source().unwrap().downcast_ref::<TestError>();
+    assert_eq!(source, Some(&TestError(Op::Map)));
+    assert_eq!(
+        err.to_string(),
+        "BadState at [0x5000, 0x7000): test backend: Map failed"
+    );
+}
+
+#[test]
+fn test_backend_injection() {
+    use crate::MappingBackend;
+    use crate::test_utils::TestError;
+    use std::time::{Duration, Instant};
+
+    let backend = MockBackend::new();
+    let clone = backend.clone();
+    let mut pt = test_page_table(MAX_ADDR);
+    let map = |pt: &mut _, start: usize| {
+        clone
+            .map(start.into(), 0x1000, 1, pt)
+            .map(|_| ())
+            .map_err(|err| err.0)
+    };
+
+    // A failure fires once, at the n-th call from now, and the failing call
+    // is counted but changes nothing. Clones share it all.
+    assert_eq!(map(&mut pt, 0), Ok(()));
+    backend.fail_at(Op::Map, 2);
+    assert_eq!(map(&mut pt, 0x1000), Ok(()));
+    assert_eq!(map(&mut pt, 0x2000), Err(Op::Map));
+    assert!(pt[0x2000..0x3000].iter().all(|&flags| flags == 0));
+    assert_eq!(map(&mut pt, 0x2000), Ok(()));
+    assert_eq!(backend.calls(Op::Map), 4);
+    // Zero cancels it, and it only affects its own operation.
+    backend.fail_at(Op::Map, 1);
+    backend.fail_at(Op::Map, 0);
+    assert_eq!(map(&mut pt, 0x3000), Ok(()));
+    backend.fail_at(Op::Protect, 1);
+    assert_eq!(map(&mut pt, 0x4000), Ok(()));
+    assert_eq!(
+        backend.protect(0.into(), 0x1000, 2, &mut pt),
+        Err(TestError(Op::Protect))
+    );
+    assert_eq!(backend.calls(Op::Protect), 1);
+    assert_eq!(backend.calls(Op::Unmap), 0);
+
+    // The page table refuses to map an address twice, or outside of it.
+    assert_eq!(map(&mut pt, 0x4000), Err(Op::Map));
+    assert_eq!(map(&mut pt, MAX_ADDR), Err(Op::Map));
+
+    // A partial failure applies the first half of the range.
+    backend.set_partial(Op::Unmap, true);
+    backend.fail_at(Op::Unmap, 1);
+    assert_eq!(
+        backend.unmap(0x1000.into(), 0x2000, &mut pt),
+        Err(TestError(Op::Unmap))
+    );
+    assert!(pt[0x1000..0x2000].iter().all(|&flags| flags == 0));
+    assert!(pt[0x2000..0x3000].iter().all(|&flags| flags == 1));
+    // It only applies to the failing calls.
+    assert_eq!(backend.unmap(0x2000.into(), 0x1000, &mut pt), Ok(()));
+    assert!(pt[0x2000..0x3000].iter().all(|&flags| flags == 0));
+
+    // A delay makes every call sleep, failing or not, until it is cleared.
+    backend.set_delay(Op::Protect, Duration::from_millis(5));
+    let start = Instant::now();
+    assert_eq!(backend.protect(0.into(), 0x1000, 2, &mut pt), Ok(()));
+    assert!(start.elapsed() >= Duration::from_millis(5));
+    backend.fail_at(Op::Protect, 1);
+    let start = Instant::now();
+    assert!(backend.protect(0.into(), 0x1000, 2, &mut pt).is_err());
+    assert!(start.elapsed() >= Duration::from_millis(5));
+    backend.set_delay(Op::Protect, Duration::ZERO);
+    assert_eq!(backend.protect(0.into(), 0x1000, 3, &mut pt), Ok(()));
+    assert!(pt[..0x1000].iter().all(|&flags| flags == 3));
+}
+
+#[test]
+fn test_backend_shared_between_threads() {
+    use std::time::Duration;
+
+    let backend = MockBackend::new();
+    backend.set_delay(Op::Map, Duration::from_millis(1));
+    let threads: std::vec::Vec<_> = (0..4)
+        .map(|_| {
+            let backend = backend.clone();
+            std::thread::spawn(move || {
+                let mut set = MockMemorySet::new();
+                let mut pt = test_page_table(MAX_ADDR);
+                for start in (0..MAX_ADDR).step_by(0x2000) {
+                    let area = MemoryArea::new(
+                        start.into(),
+                        0x1000,
+                        #[cfg(feature = "RAII")]
+                        None,
+                        1,
+                        backend.clone(),
+                    );
+                    assert_ok!(set.map(area, &mut pt, false, None));
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(backend.calls(Op::Map), 4 * MAX_ADDR / 0x2000);
+}
+
+#[cfg(all(feature = "paging", feature = "RAII"))]
+#[test]
+fn test_paging_backends() {
+    use crate::MappingBackend;
+    use crate::paging::{Alloc, Linear, PagingOps};
+    use crate::test_utils::{TestError, TestFrame};
+    use memory_addr::{FrameTracker, PhysAddr};
+    use std::collections::BTreeMap;
+
+    type PageTable = BTreeMap<VirtAddr, (PhysAddr, u8)>;
+
+    struct Ops;
+
+    impl PagingOps for Ops {
+        type PageTable = PageTable;
+        type Flags = u8;
+        type Error = TestError;
+        type Frame = TestFrame;
+
+        fn map_page(
+            pt: &mut PageTable,
+            vaddr: VirtAddr,
+            paddr: PhysAddr,
+            flags: u8,
+        ) -> Result<(), TestError> {
+            match pt.insert(vaddr, (paddr, flags)) {
+                None => Ok(()),
+                Some(_) => Err(TestError(Op::Map)),
+            }
+        }
+
+        fn unmap_page(pt: &mut PageTable, vaddr: VirtAddr) -> Result<(), TestError> {
+            pt.remove(&vaddr).map(|_| ()).ok_or(TestError(Op::Unmap))
+        }
+
+        fn protect_page(pt: &mut PageTable, vaddr: VirtAddr, flags: u8) -> Result<(), TestError> {
+            let entry = pt.get_mut(&vaddr).ok_or(TestError(Op::Protect))?;
+            entry.1 = flags;
+            Ok(())
+        }
+
+        fn query(pt: &PageTable, vaddr: VirtAddr) -> Option<PhysAddr> {
+            pt.get(&vaddr).map(|&(paddr, _)| paddr)
+        }
+
+        fn no_phys_addr(_vaddr: VirtAddr) -> TestError {
+            TestError(Op::Map)
+        }
+    }
+
+    // Linear mappings are installed at once.
+    let mut pt = PageTable::new();
+    let mut set = MemorySet::new();
+    let linear = Linear::<Ops>::new(0x8000_0000);
+    let area = MemoryArea::new(
+        0x8000_2000.into(),
+        0x2000,
+        #[cfg(feature = "RAII")]
+        None,
+        1,
+        linear,
+    );
+    assert_ok!(set.map(area, &mut pt, false, None));
+    assert_eq!(pt.len(), 2);
+    assert_eq!(pt[&VirtAddr::from(0x8000_3000)], (0x3000.into(), 1));
+    assert_ok!(set.protect(0x8000_2000.into(), 0x2000, |_| Some(3), &mut pt));
+    assert!(pt.values().all(|&(_, flags)| flags == 3));
+    assert_ok!(set.unmap(0x8000_2000.into(), 0x2000, &mut pt));
+    assert!(pt.is_empty());
+
+    // Addresses below the offset have no physical address.
+    let linear = Linear::<Ops>::new(0x8000_0000);
+    assert_eq!(linear.translate(0x1000.into()), None);
+    assert_eq!(
+        linear.translate(0x8000_1234.into()),
+        Some((0x1234.into(), 0x1000))
+    );
+    let area = MemoryArea::new(
+        0x1000.into(),
+        0x1000,
+        #[cfg(feature = "RAII")]
+        None,
+        1,
+        linear.clone(),
+    );
+    assert_err!(set.map(area, &mut pt, false, None), BadState);
+    assert!(pt.is_empty());
+
+    // A failed mapping leaves no pages behind.
+    pt.insert(0x8000_4000.into(), (0.into(), 1));
+    let area = MemoryArea::new(
+        0x8000_2000.into(),
+        0x4000,
+        #[cfg(feature = "RAII")]
+        None,
+        1,
+        linear,
+    );
+    assert_err!(set.map(area, &mut pt, false, None), BadState);
+    assert_eq!(pt.len(), 1);
+    pt.clear();
+
+    // Populated areas allocate all their frames when mapped.
+    let mut set = MemorySet::new();
+    let area = MemoryArea::new(
+        0x1000.into(),
+        0x2000,
+        #[cfg(feature = "RAII")]
+        None,
+        1,
+        Alloc::<Ops>::new(true),
+    );
+    assert_ok!(set.map(area, &mut pt, false, None));
+    assert_eq!(pt.len(), 2);
+    assert_eq!(set.find(0x1000.into()).unwrap().frames_count(), 2);
+
+    // And unmap them again if one of them cannot be mapped.
+    pt.insert(0x6000.into(), (0.into(), 1));
+    let area = MemoryArea::new(
+        0x4000.into(),
+        0x4000,
+        #[cfg(feature = "RAII")]
+        None,
+        1,
+        Alloc::<Ops>::new(true),
+    );
+    assert_err!(set.map(area, &mut pt, false, None), BadState);
+    assert_eq!(pt.len(), 3);
+    assert!(set.find(0x4000.into()).is_none());
+    pt.remove(&VirtAddr::from(0x6000));
+
+    // Lazy areas allocate their frames on the first access.
+    let area = MemoryArea::new(
+        0x10000.into(),
+        0x4000,
+        #[cfg(feature = "RAII")]
+        None,
+        1,
+        Alloc::<Ops>::new(false),
+    );
+    assert_ok!(set.map(area, &mut pt, false, None));
+    assert_eq!(pt.len(), 2);
+    assert_ok!(set.handle_page_fault(0x11234.into(), 1, &mut pt));
+    let area = set.find(0x10000.into()).unwrap();
+    assert_eq!(area.frames_count(), 1);
+    let frame = area.find_frame(0x11000.into()).unwrap();
+    assert_eq!(pt[&VirtAddr::from(0x11000)], (frame.start(), 1));
+    assert_ok!(set.protect(0x10000.into(), 0x4000, |_| Some(3), &mut pt));
+    assert_eq!(pt[&VirtAddr::from(0x11000)].1, 3);
+    assert_ok!(set.unmap(0x1000.into(), 0x13000, &mut pt));
+    assert!(pt.is_empty());
+}
+
+#[cfg(all(feature = "page-table-multiarch", feature = "RAII"))]
+#[test]
+fn test_page_table64() {
+    use crate::paging::{Alloc, Linear, PageTable64Ops, PageTableError, PteFlags};
+    use crate::test_utils::TestFrame;
+    use memory_addr::{FrameTracker, PhysAddr};
+    use page_table_multiarch::{
+        GenericPTE, MappingFlags, PageSize, PageTable64, PagingError, PagingHandler, PagingMetaData,
+    };
+    use std::alloc::{Layout, alloc_zeroed, dealloc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static FLUSHES: AtomicUsize = AtomicUsize::new(0);
+
+    // A 4-level table in host memory, with the entries keeping the flags in
+    // the low bits.
+    struct MetaData;
+
+    impl PagingMetaData for MetaData {
+        const LEVELS: usize = 4;
+        const PA_MAX_BITS: usize = 52;
+        const VA_MAX_BITS: usize = 48;
+        type VirtAddr = VirtAddr;
+
+        fn flush_tlb(_vaddr: Option<VirtAddr>) {
+            FLUSHES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    struct Pte(usize);
+
+    impl Pte {
+        const PRESENT: usize = 1 << 6;
+        const HUGE: usize = 1 << 7;
+        const FLAGS: usize = 0x3f;
+        const PADDR: usize = 0x000f_ffff_ffff_f000;
+    }
+
+    impl GenericPTE for Pte {
+        fn new_page(paddr: PhysAddr, flags: MappingFlags, is_huge: bool) -> Self {
+            let huge = if is_huge { Self::HUGE } else { 0 };
+            Self(paddr.as_usize() & Self::PADDR | flags.bits() | Self::PRESENT | huge)
+        }
+        fn new_table(paddr: PhysAddr) -> Self {
+            Self(paddr.as_usize() & Self::PADDR | Self::PRESENT)
+        }
+        fn paddr(&self) -> PhysAddr {
+            (self.0 & Self::PADDR).into()
+        }
+        fn flags(&self) -> MappingFlags {
+            MappingFlags::from_bits_truncate(self.0 & Self::FLAGS)
+        }
+        fn set_paddr(&mut self, paddr: PhysAddr) {
+            self.0 = self.0 & !Self::PADDR | paddr.as_usize() & Self::PADDR;
+        }
+        fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
+            let huge = if is_huge { Self::HUGE } else { 0 };
+            self.0 = self.0 & Self::PADDR | flags.bits() | Self::PRESENT | huge;
+        }
+        fn bits(self) -> usize {
+            self.0
+        }
+        fn is_unused(&self) -> bool {
+            self.0 == 0
+        }
+        fn is_present(&self) -> bool {
+            self.0 & Self::PRESENT != 0
+        }
+        fn is_huge(&self) -> bool {
+            self.0 & Self::HUGE != 0
+        }
+        fn clear(&mut self) {
+            self.0 = 0;
+        }
+    }
+
+    // The tables are heap pages, with the physical addresses being the host
+    // addresses.
+    struct Handler;
+
+    const TABLE: Layout = unsafe { Layout::from_size_align_unchecked(0x1000, 0x1000) };
+
+    impl PagingHandler for Handler {
+        fn alloc_frame() -> Option<PhysAddr> {
+            let ptr = unsafe { alloc_zeroed(TABLE) };
+            (!ptr.is_null()).then(|| (ptr as usize).into())
+        }
+        fn dealloc_frame(paddr: PhysAddr) {
+            unsafe { dealloc(paddr.as_usize() as *mut u8, TABLE) }
+        }
+        fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
+            paddr.as_usize().into()
+        }
+    }
+
+    type Ops = PageTable64Ops<MetaData, Pte, Handler, TestFrame>;
+    type PageTable = PageTable64<MetaData, Pte, Handler>;
+
+    let rw = PteFlags(MappingFlags::READ | MappingFlags::WRITE);
+    let ro = PteFlags(MappingFlags::READ);
+    let query = |pt: &PageTable, vaddr: usize| pt.query(vaddr.into()).ok();
+
+    // Linear mappings are installed at once.
+    let mut pt = PageTable::try_new().unwrap();
+    let mut set = MemorySet::new();
+    let area = MemoryArea::new(
+        0xffff_8000_0000_2000.into(),
+        0x2000,
+        #[cfg(feature = "RAII")]
+        None,
+        rw,
+        Linear::<Ops>::new(0xffff_8000_0000_0000),
+    );
+    assert_ok!(set.map(area, &mut pt, false, None));
+    let (paddr, flags, _) = query(&pt, 0xffff_8000_0000_3000).unwrap();
+    assert_eq!((paddr, PteFlags(flags)), (0x3000.into(), rw));
+    assert!(FLUSHES.load(Ordering::Relaxed) >= 2);
+    assert_ok!(set.protect(0xffff_8000_0000_2000.into(), 0x2000, |_| Some(ro), &mut pt));
+    assert_eq!(query(&pt, 0xffff_8000_0000_2000).unwrap().1, ro.0);
+    assert_eq!(
+        set.find(0xffff_8000_0000_2000.into())
+            .unwrap()
+            .flags()
+            .to_string(),
+        "READ"
+    );
+
+    // A page mapped behind the set fails with the error of the page table,
+    // and the pages mapped before are unmapped.
+    pt.map(
+        0xffff_8000_0000_6000.into(),
+        0x6000.into(),
+        PageSize::Size4K,
+        rw.0,
+    )
+    .unwrap()
+    .ignore();
+    let area = MemoryArea::new(
+        0xffff_8000_0000_4000.into(),
+        0x3000,
+        #[cfg(feature = "RAII")]
+        None,
+        rw,
+        Linear::<Ops>::new(0xffff_8000_0000_0000),
+    );
+    let err = set.map(area, &mut pt, false, None).unwrap_err();
+    let source = core::error::Error::source(&err).unwrap();
+    assert_eq!(
+        source.downcast_ref::<PageTableError>(),
+        Some(&PageTableError::Paging(PagingError::AlreadyMapped))
+    );
+    assert!(query(&pt, 0xffff_8000_0000_4000).is_none());
+    assert!(query(&pt, 0xffff_8000_0000_5000).is_none());
+    assert_ok!(set.unmap(0xffff_8000_0000_2000.into(), 0x2000, &mut pt));
+    assert!(query(&pt, 0xffff_8000_0000_3000).is_none());
+
+    // Lazy areas allocate their frames on the first access.
+    let mut set = MemorySet::new();
+    let area = MemoryArea::new(
+        0x10000.into(),
+        0x4000,
+        #[cfg(feature = "RAII")]
+        None,
+        rw,
+        Alloc::<Ops>::new(false),
+    );
+    assert_ok!(set.map(area, &mut pt, false, None));
+    assert!(query(&pt, 0x11000).is_none());
+    assert_ok!(set.handle_page_fault(0x11234.into(), rw, &mut pt));
+    let frame = set
+        .find(0x10000.into())
+        .unwrap()
+        .find_frame(0x11000.into())
+        .unwrap();
+    assert_eq!(
+        query(&pt, 0x11000).unwrap().0,
+        frame.start().align_down_4k()
+    );
+    assert_ok!(set.unmap(0x10000.into(), 0x4000, &mut pt));
+    assert!(query(&pt, 0x11000).is_none());
+}
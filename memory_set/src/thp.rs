@@ -0,0 +1,115 @@
+//! Transparent huge pages: promoting densely populated regions of a
+//! [`MemorySet`] to huge pages according to a [`ThpPolicy`].
+
+use alloc::vec::Vec;
+
+use memory_addr::{AddrRange, MemoryAddr};
+
+use crate::{MappingBackend, MappingKind, MemoryArea, MemorySet};
+
+/// When the regions of a [`MemorySet`] may be promoted to huge pages by
+/// [`MemorySet::promote_huge_pages`], like
+/// `/sys/kernel/mm/transparent_hugepage/enabled`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ThpPolicy {
+    /// All areas, except the ones advised with
+    /// [`Advice::NoHugePage`](crate::Advice::NoHugePage).
+    Always,
+    /// Only the areas advised with
+    /// [`Advice::HugePage`](crate::Advice::HugePage).
+    Madvise,
+    /// No area.
+    #[default]
+    Never,
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Returns the huge page policy of the set.
+    pub const fn thp_policy(&self) -> ThpPolicy {
+        self.thp_policy
+    }
+
+    /// Sets the huge page policy of the set, [`ThpPolicy::Never`] by default.
+    ///
+    /// It only decides which areas
+    /// [`promote_huge_pages`](Self::promote_huge_pages) considers, regions
+    /// already promoted stay huge.
+    pub fn set_thp_policy(&mut self, policy: ThpPolicy) {
+        self.thp_policy = policy;
+    }
+
+    /// Returns whether the huge page policy allows promoting the regions of
+    /// `area`.
+    fn thp_eligible(&self, area: &MemoryArea<B>) -> bool {
+        let allowed = match self.thp_policy {
+            ThpPolicy::Always => area.thp_advice() != Some(false),
+            ThpPolicy::Madvise => area.thp_advice() == Some(true),
+            ThpPolicy::Never => false,
+        };
+        allowed
+            && !area.is_reserved()
+            && !area.is_write_protected()
+            && area.backend().mapping_kind() != MappingKind::Device
+    }
+
+    /// Scans the set for regions to promote to huge pages of `huge_size`
+    /// bytes, e.g., periodically from a background task, and returns the
+    /// number of regions collapsed.
+    ///
+    /// The candidates are the `huge_size`-aligned regions lying entirely
+    /// within an area allowed by the [policy](Self::set_thp_policy), not
+    /// already mapped by a huge page according to
+    /// [`MappingBackend::translate`], and with at least `min_resident`
+    /// resident pages. Each one is collapsed with
+    /// [`MappingBackend::collapse_huge`]. Reserved and write-protected areas
+    /// and [device](MappingKind::Device) memory are never promoted.
+    pub fn promote_huge_pages(
+        &mut self,
+        huge_size: usize,
+        min_resident: usize,
+        page_table: &mut B::PageTable,
+    ) -> usize {
+        if self.thp_policy == ThpPolicy::Never {
+            return 0;
+        }
+        let mut candidates = Vec::new();
+        for area in self.areas.values() {
+            if !self.thp_eligible(area) {
+                continue;
+            }
+            let mut start = area.start().align_up(huge_size);
+            while start < area.end() && area.end().sub_addr(start) >= huge_size {
+                let region = AddrRange::from_start_size(start, huge_size);
+                let is_huge = area
+                    .backend()
+                    .translate(start)
+                    .is_some_and(|(_, size)| size >= huge_size);
+                if !is_huge && Self::resident_in(area, region) >= min_resident {
+                    candidates.push((area.start(), region));
+                }
+                start = region.end;
+            }
+        }
+        let mut collapsed = 0;
+        for (area_start, region) in candidates {
+            let area = &self.areas[&area_start];
+            if area
+                .backend()
+                .collapse_huge(region.start, huge_size, area.flags(), page_table)
+            {
+                collapsed += 1;
+            }
+        }
+        if collapsed > 0 {
+            self.bump_generation();
+        }
+        collapsed
+    }
+
+    /// Returns the number of resident pages of `area` within `region`.
+    fn resident_in(area: &MemoryArea<B>, region: AddrRange<B::Addr>) -> usize {
+        crate::sample::resident_pages(area, region.start)
+            .take_while(|&page| page < region.end)
+            .count()
+    }
+}
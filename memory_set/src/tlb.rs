@@ -0,0 +1,244 @@
+use alloc::vec::Vec;
+
+use memory_addr::{AddrRange, MemoryAddr, PhysAddr};
+
+use crate::{MapMode, MappingBackend, MappingResult, MemoryArea, MemorySet, Protected};
+
+/// A translation for refilling a software-managed TLB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlbEntry<A, F> {
+    /// The start virtual address of the page.
+    pub vaddr: A,
+    /// The start physical address of the page.
+    pub paddr: PhysAddr,
+    /// The flags of the area containing the page.
+    pub flags: F,
+    /// The size of the page.
+    pub page_size: usize,
+}
+
+impl<A: MemoryAddr, F> TlbEntry<A, F> {
+    /// Returns whether the entry translates the given address.
+    pub fn contains(&self, vaddr: A) -> bool {
+        self.vaddr <= vaddr && vaddr.sub_addr(self.vaddr) < self.page_size
+    }
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Looks up the translation of `vaddr`, for refilling a software-managed
+    /// TLB (e.g., on some MIPS or PowerPC cores).
+    ///
+    /// The frame tracked by the containing area is used if there is one (with
+    /// RAII), possibly a huge one, otherwise [`MappingBackend::translate`] is asked. Returns
+    /// `None` if the address is not mapped or has no translation yet, in which
+    /// case the refill should turn into a page fault.
+    pub fn lookup_for_tlb(&self, vaddr: B::Addr) -> Option<TlbEntry<B::Addr, B::Flags>> {
+        let area = self.find(vaddr)?;
+        #[cfg(feature = "RAII")]
+        if let Some((page, frame, page_size)) =
+            area.frames.covering(vaddr.align_down(area.frame_size()))
+        {
+            use memory_addr::FrameTracker;
+            return Some(TlbEntry {
+                vaddr: page,
+                paddr: frame.start(),
+                flags: area.flags(),
+                page_size,
+            });
+        }
+        let (paddr, page_size) = area.backend().translate(vaddr)?;
+        Some(TlbEntry {
+            vaddr: vaddr.align_down(page_size),
+            paddr,
+            flags: area.flags(),
+            page_size,
+        })
+    }
+}
+
+/// A small direct-mapped cache of [`MemorySet::lookup_for_tlb`] results, with
+/// `N` entries.
+///
+/// It is usually kept per CPU. The cache remembers the
+/// [generation](MemorySet::generation) of the set it was filled from and
+/// flushes itself when the set has changed since, so it never returns stale
+/// translations as long as it is always used with the same set.
+pub struct TlbCache<B: MappingBackend, const N: usize> {
+    generation: u64,
+    entries: [Option<TlbEntry<B::Addr, B::Flags>>; N],
+    hits: u64,
+    misses: u64,
+}
+
+impl<B: MappingBackend, const N: usize> TlbCache<B, N> {
+    /// Creates an empty cache.
+    pub const fn new() -> Self {
+        Self {
+            generation: 0,
+            entries: [None; N],
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn slot(vaddr: B::Addr) -> usize {
+        (vaddr.into() / B::BASE_PAGE_SIZE) % N
+    }
+
+    /// Looks up the translation of `vaddr` in the cache, falling back to
+    /// [`MemorySet::lookup_for_tlb`] on a miss.
+    pub fn lookup(
+        &mut self,
+        set: &MemorySet<B>,
+        vaddr: B::Addr,
+    ) -> Option<TlbEntry<B::Addr, B::Flags>> {
+        if self.generation != set.generation() {
+            self.flush();
+            self.generation = set.generation();
+        }
+        let slot = Self::slot(vaddr);
+        if let Some(entry) = self.entries[slot].filter(|entry| entry.contains(vaddr)) {
+            self.hits += 1;
+            return Some(entry);
+        }
+        self.misses += 1;
+        let entry = set.lookup_for_tlb(vaddr)?;
+        self.entries[slot] = Some(entry);
+        Some(entry)
+    }
+
+    /// Drops all cached translations.
+    pub fn flush(&mut self) {
+        self.entries = [None; N];
+    }
+
+    /// Returns the number of cache hits and misses so far.
+    pub const fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+}
+
+impl<B: MappingBackend, const N: usize> Default for TlbCache<B, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A batch of changes to a page table whose TLB invalidations are deferred
+/// and done at once when it is flushed or dropped.
+///
+/// The batch borrows the page table and is passed to
+/// [`MemorySet::map_batched`], [`MemorySet::unmap_batched`] and
+/// [`MemorySet::protect_batched`] instead of it. It starts with
+/// [`MappingBackend::begin_batch`], so that the backend can skip its own
+/// invalidations, records the ranges whose translations may be stale, and
+/// finally hands them to [`MappingBackend::flush_tlb`] before
+/// [`MappingBackend::end_batch`].
+pub struct TlbBatch<'a, B: MappingBackend> {
+    page_table: &'a mut B::PageTable,
+    ranges: Vec<AddrRange<B::Addr>>,
+}
+
+impl<'a, B: MappingBackend> TlbBatch<'a, B> {
+    /// Starts a batch of changes to `page_table`.
+    pub fn new(page_table: &'a mut B::PageTable) -> Self {
+        B::begin_batch(page_table);
+        Self {
+            page_table,
+            ranges: Vec::new(),
+        }
+    }
+
+    /// Returns the page table, e.g., for operations not recording their
+    /// ranges.
+    pub fn page_table(&mut self) -> &mut B::PageTable {
+        self.page_table
+    }
+
+    /// Records that the translations of `range` must be invalidated.
+    pub fn add(&mut self, range: AddrRange<B::Addr>) {
+        if !range.is_empty() {
+            self.ranges.push(range);
+        }
+    }
+
+    /// Returns the ranges recorded so far, in the order they were added.
+    pub fn ranges(&self) -> &[AddrRange<B::Addr>] {
+        &self.ranges
+    }
+
+    /// Invalidates the recorded ranges with [`MappingBackend::flush_tlb`],
+    /// sorted and merged, and forgets them. The batch goes on.
+    pub fn flush(&mut self) {
+        if self.ranges.is_empty() {
+            return;
+        }
+        self.ranges.sort_unstable_by_key(|range| range.start);
+        let mut merged: Vec<AddrRange<B::Addr>> = Vec::with_capacity(self.ranges.len());
+        for range in self.ranges.drain(..) {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        B::flush_tlb(self.page_table, &merged);
+    }
+}
+
+impl<B: MappingBackend> Drop for TlbBatch<'_, B> {
+    fn drop(&mut self) {
+        self.flush();
+        B::end_batch(self.page_table);
+    }
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Same as [`map_with_mode`](Self::map_with_mode), but within a
+    /// [`TlbBatch`]. With [`MapMode::Fixed`], the range of the area is
+    /// recorded, since it may replace existing mappings.
+    pub fn map_batched(
+        &mut self,
+        area: MemoryArea<B>,
+        mode: MapMode<B::Addr>,
+        batch: &mut TlbBatch<'_, B>,
+    ) -> MappingResult {
+        let range = area.va_range();
+        let result = self.map_with_mode(area, batch.page_table, mode, None);
+        if mode == MapMode::Fixed {
+            batch.add(range);
+        }
+        result
+    }
+
+    /// Same as [`unmap`](Self::unmap), but within a [`TlbBatch`]. The range
+    /// is recorded even if unmapping fails, since parts of it may be
+    /// unmapped already.
+    pub fn unmap_batched(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        batch: &mut TlbBatch<'_, B>,
+    ) -> MappingResult {
+        let result = self.unmap(start, size, batch.page_table);
+        if let Some(range) = AddrRange::try_from_start_size(start, size) {
+            batch.add(range);
+        }
+        result
+    }
+
+    /// Same as [`protect`](Self::protect), but within a [`TlbBatch`]. The
+    /// changed ranges are recorded.
+    pub fn protect_batched(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        update_flags: impl Fn(B::Flags) -> Option<B::Flags>,
+        batch: &mut TlbBatch<'_, B>,
+    ) -> MappingResult<Vec<Protected<B::Addr, B::Flags>>> {
+        let changed = self.protect(start, size, update_flags, batch.page_table)?;
+        for protected in &changed {
+            batch.add(protected.range);
+        }
+        Ok(changed)
+    }
+}
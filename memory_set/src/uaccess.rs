@@ -0,0 +1,346 @@
+//! Copying data between kernel buffers and the memory of a [`MemorySet`],
+//! e.g., for the arguments of system calls, through the frames of the areas
+//! instead of raw user pointers.
+//!
+//! [`UserPtr`] and [`UserSlice`] wrap the copies in typed guards, validated
+//! once when created and bound to the layout of the set.
+
+use core::marker::PhantomData;
+use core::{mem, ptr, slice};
+
+use memory_addr::{AddrRange, FrameTracker, MemoryAddr};
+
+use crate::{MappingBackend, MappingError, MappingResult, MemorySet, err_range};
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Copies the memory at `[vaddr, vaddr + buf.len())` into `buf`, like
+    /// `copy_from_user`.
+    ///
+    /// The range is checked with [`check_access`](Self::check_access) first,
+    /// and nothing is copied if it fails. Pages that are not resident are
+    /// faulted in, as with [`handle_page_fault`](Self::handle_page_fault),
+    /// and must end up with a frame held by the area, otherwise
+    /// [`MappingError::BadState`] is returned.
+    pub fn read_bytes(
+        &mut self,
+        vaddr: B::Addr,
+        buf: &mut [u8],
+        access_flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.copy_with(
+            vaddr,
+            buf.len(),
+            access_flags,
+            false,
+            page_table,
+            |src, offset, len| {
+                // SAFETY: `src` points to `len` bytes within a frame held by
+                // the area, which stays alive during the copy.
+                unsafe { ptr::copy_nonoverlapping(src, buf[offset..].as_mut_ptr(), len) }
+            },
+        )
+    }
+
+    /// Copies `buf` into the memory at `[vaddr, vaddr + buf.len())`, like
+    /// `copy_to_user`.
+    ///
+    /// Same as [`read_bytes`](Self::read_bytes), except that `access_flags`
+    /// must describe a write according to
+    /// [`MappingBackend::is_write_access`]: copy-on-write sharing is broken
+    /// before the pages are written to, and the writes are recorded for
+    /// soft-dirty tracking.
+    pub fn write_bytes(
+        &mut self,
+        vaddr: B::Addr,
+        buf: &[u8],
+        access_flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.copy_with(
+            vaddr,
+            buf.len(),
+            access_flags,
+            true,
+            page_table,
+            |dst, offset, len| {
+                // SAFETY: `dst` points to `len` bytes within a private (or
+                // shared on purpose) frame held by the area, which stays
+                // alive during the copy.
+                unsafe { ptr::copy_nonoverlapping(buf[offset..].as_ptr(), dst, len) }
+            },
+        )
+    }
+
+    /// Walks the frames backing `[vaddr, vaddr + len)`, faulting them in if
+    /// needed, and calls `copy` with a pointer into each frame, the offset
+    /// of that part in the range and its length.
+    fn copy_with(
+        &mut self,
+        vaddr: B::Addr,
+        len: usize,
+        access_flags: B::Flags,
+        is_write: bool,
+        page_table: &mut B::PageTable,
+        mut copy: impl FnMut(*mut u8, usize, usize),
+    ) -> MappingResult {
+        if len == 0 {
+            return Ok(());
+        }
+        let end = vaddr
+            .checked_add(len)
+            .ok_or(MappingError::OutOfRange(err_range(vaddr, len)))?;
+        let range = AddrRange::new(vaddr, end);
+        self.check_access(range, access_flags)?;
+
+        // Accesses only change the pages of the areas, like faults.
+        self.generation += 1;
+        let mut addr = vaddr;
+        while addr < end {
+            let area = self.find(addr).unwrap();
+            debug_assert!(!is_write || area.backend().is_write_access(access_flags));
+            let area_start = area.start();
+            let frame_size = area.frame_size();
+            let page = addr.align_down(frame_size);
+            let chunk = (frame_size - addr.sub_addr(page)).min(end.sub_addr(addr));
+            let needs_fault = match area.frames.covering(page) {
+                None => true,
+                Some((_, frame, _)) => {
+                    is_write && (area.is_write_protected() || area.is_zero_frame(frame))
+                }
+            };
+            if needs_fault {
+                self.fault_area(area_start, page, access_flags, page_table)?;
+            }
+            let area = self.areas.get_mut(&area_start).unwrap();
+            let (start, frame, _) = area
+                .frames
+                .covering(page)
+                .ok_or(MappingError::BadState(err_range(page, frame_size), None))?;
+            let frame_ptr = frame.as_ptr().cast_mut().wrapping_add(addr.sub_addr(start));
+            copy(frame_ptr, addr.sub_addr(vaddr), chunk);
+            if is_write {
+                area.record_write(page);
+            }
+            addr = addr.add(chunk);
+        }
+        Ok(())
+    }
+}
+
+/// Plain old data, which can be copied from and to memory as bytes, e.g.,
+/// the arguments of system calls.
+///
+/// # Safety
+///
+/// Every bit pattern must be a valid value of the type, and it must have no
+/// padding bytes.
+pub unsafe trait Pod: Copy {}
+
+macro_rules! impl_pod {
+    ($($ty:ty),*) => {
+        $(
+            // SAFETY: Primitive integers have no padding and no invalid values.
+            unsafe impl Pod for $ty {}
+        )*
+    };
+}
+
+impl_pod!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize
+);
+
+// SAFETY: Arrays have no padding between their elements.
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// A validated slice of `T`s in the memory of a [`MemorySet`], returned by
+/// [`MemorySet::user_slice`].
+///
+/// It is bound to the layout of the set: once the set maps, unmaps or
+/// protects anything, or changes anything else than the pages of its areas,
+/// the slice is stale and accesses through it fail with
+/// [`MappingError::InvalidParam`], so that it cannot outlive an unmap. It
+/// must be used with the set it was created from.
+pub struct UserSlice<B: MappingBackend, T: Pod> {
+    vaddr: B::Addr,
+    len: usize,
+    access_flags: B::Flags,
+    generation: u64,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<B: MappingBackend, T: Pod> Clone for UserSlice<B, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<B: MappingBackend, T: Pod> Copy for UserSlice<B, T> {}
+
+impl<B: MappingBackend, T: Pod> UserSlice<B, T> {
+    /// Returns the address of the first element.
+    pub const fn addr(&self) -> B::Addr {
+        self.vaddr
+    }
+
+    /// Returns the number of elements.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether there is no element.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns a pointer to the element at `index`, if it is in the slice.
+    pub fn get(&self, index: usize) -> Option<UserPtr<B, T>> {
+        (index < self.len).then(|| UserPtr {
+            slice: Self {
+                vaddr: self.vaddr.add(index * mem::size_of::<T>()),
+                len: 1,
+                ..*self
+            },
+        })
+    }
+
+    /// Checks that the slice is still valid in `set`, and that it holds as
+    /// many elements as `len`.
+    fn check(&self, set: &MemorySet<B>, len: usize) -> MappingResult {
+        let size = self.len * mem::size_of::<T>();
+        if set.layout_generation != self.generation || len != self.len {
+            return Err(MappingError::InvalidParam(err_range(self.vaddr, size)));
+        }
+        Ok(())
+    }
+
+    /// Copies the elements into `buf`, which must have the same length, with
+    /// [`MemorySet::read_bytes`].
+    pub fn read_into(
+        &self,
+        buf: &mut [T],
+        set: &mut MemorySet<B>,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.check(set, buf.len())?;
+        // SAFETY: `T` is plain old data, so any bytes make valid values.
+        let bytes = unsafe { slice::from_raw_parts_mut(buf.as_mut_ptr().cast(), size_of_val(buf)) };
+        set.read_bytes(self.vaddr, bytes, self.access_flags, page_table)
+    }
+
+    /// Copies `buf`, which must have the same length, into the elements with
+    /// [`MemorySet::write_bytes`].
+    ///
+    /// Returns [`MappingError::PermissionDenied`] if the slice was not
+    /// validated for a write.
+    pub fn write_from(
+        &self,
+        buf: &[T],
+        set: &mut MemorySet<B>,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.check(set, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let size = size_of_val(buf);
+        let area = set
+            .find(self.vaddr)
+            .ok_or(MappingError::NotMapped(err_range(self.vaddr, size)))?;
+        if !area.backend().is_write_access(self.access_flags) {
+            return Err(MappingError::PermissionDenied(err_range(self.vaddr, size)));
+        }
+        // SAFETY: `T` is plain old data, so it has no padding bytes.
+        let bytes = unsafe { slice::from_raw_parts(buf.as_ptr().cast(), size) };
+        set.write_bytes(self.vaddr, bytes, self.access_flags, page_table)
+    }
+}
+
+/// A validated pointer to a `T` in the memory of a [`MemorySet`], returned by
+/// [`MemorySet::user_ptr`].
+///
+/// It is bound to the layout of the set like a [`UserSlice`].
+pub struct UserPtr<B: MappingBackend, T: Pod> {
+    slice: UserSlice<B, T>,
+}
+
+impl<B: MappingBackend, T: Pod> Clone for UserPtr<B, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<B: MappingBackend, T: Pod> Copy for UserPtr<B, T> {}
+
+impl<B: MappingBackend, T: Pod> UserPtr<B, T> {
+    /// Returns the address of the value.
+    pub const fn addr(&self) -> B::Addr {
+        self.slice.vaddr
+    }
+
+    /// Reads the value, see [`UserSlice::read_into`].
+    pub fn read(&self, set: &mut MemorySet<B>, page_table: &mut B::PageTable) -> MappingResult<T> {
+        // SAFETY: `T` is plain old data, so zeroes make a valid value.
+        let mut value = unsafe { mem::zeroed() };
+        self.slice
+            .read_into(slice::from_mut(&mut value), set, page_table)?;
+        Ok(value)
+    }
+
+    /// Writes `value`, see [`UserSlice::write_from`].
+    pub fn write(
+        &self,
+        value: T,
+        set: &mut MemorySet<B>,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult {
+        self.slice
+            .write_from(slice::from_ref(&value), set, page_table)
+    }
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Validates `len` `T`s at `vaddr` for accesses described by
+    /// `access_flags`, and returns them as a [`UserSlice`].
+    ///
+    /// Returns [`MappingError::Misaligned`] if `vaddr` is not aligned for
+    /// `T`, and the errors of [`check_access`](Self::check_access) if the
+    /// range is not accessible. Pages are not faulted in until accessed.
+    pub fn user_slice<T: Pod>(
+        &self,
+        vaddr: B::Addr,
+        len: usize,
+        access_flags: B::Flags,
+    ) -> MappingResult<UserSlice<B, T>> {
+        let size = len
+            .checked_mul(mem::size_of::<T>())
+            .ok_or(MappingError::OutOfRange(err_range(vaddr, usize::MAX)))?;
+        if !vaddr.is_aligned(mem::align_of::<T>()) {
+            return Err(MappingError::Misaligned(err_range(vaddr, size)));
+        }
+        if size > 0 {
+            let end = vaddr
+                .checked_add(size)
+                .ok_or(MappingError::OutOfRange(err_range(vaddr, size)))?;
+            self.check_access(AddrRange::new(vaddr, end), access_flags)?;
+        }
+        Ok(UserSlice {
+            vaddr,
+            len,
+            access_flags,
+            generation: self.layout_generation,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Validates a `T` at `vaddr` for accesses described by `access_flags`,
+    /// and returns it as a [`UserPtr`], see [`user_slice`](Self::user_slice).
+    pub fn user_ptr<T: Pod>(
+        &self,
+        vaddr: B::Addr,
+        access_flags: B::Flags,
+    ) -> MappingResult<UserPtr<B, T>> {
+        let slice = self.user_slice(vaddr, 1, access_flags)?;
+        Ok(UserPtr { slice })
+    }
+}
@@ -0,0 +1,185 @@
+//! Mapping the frames backing user memory into a window of a kernel
+//! [`MemorySet`], e.g., to inspect the pages of a process without toggling
+//! SMAP or dereferencing addresses the process controls.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::slice;
+
+use memory_addr::{AddrRange, FrameTracker, MemoryAddr};
+
+use crate::{MappingBackend, MappingError, MappingResult, MemoryArea, MemorySet, SharedFrames};
+use crate::{err_range, untyped};
+
+/// The frames backing a range of a set, returned by
+/// [`MemorySet::pin_pages`], held so that they outlive the mapping they were
+/// taken from.
+///
+/// They are the frames at the time of the call: later changes of the
+/// mapping, e.g., copy-on-write breaks or swapping, are not reflected.
+pub struct PinnedPages<B: MappingBackend> {
+    frames: Arc<SharedFrames<B>>,
+    offset: usize,
+    len: usize,
+}
+
+impl<B: MappingBackend> PinnedPages<B> {
+    /// Returns the length of the range in bytes.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the range is empty.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the frame backing the byte at `offset` in the range, if any.
+    pub fn frame(&self, offset: usize) -> Option<&B::FrameTrackerRef> {
+        (offset < self.len)
+            .then(|| self.frames.frame(self.offset + offset))
+            .flatten()
+    }
+}
+
+/// A read-only window of a kernel set mapping [`PinnedPages`], returned by
+/// [`MemorySet::map_window`], unmapped when dropped.
+///
+/// It borrows the kernel set and its page table, so the window cannot
+/// outlive them, and nothing else can change the set while it is mapped.
+pub struct RemapWindow<'a, B: MappingBackend> {
+    set: &'a mut MemorySet<B>,
+    page_table: &'a mut B::PageTable,
+    area_range: AddrRange<B::Addr>,
+    offset: usize,
+    len: usize,
+}
+
+impl<B: MappingBackend> RemapWindow<'_, B> {
+    /// Returns the kernel address of the first byte of the pinned range.
+    pub fn addr(&self) -> B::Addr {
+        self.area_range.start.add(self.offset)
+    }
+
+    /// Returns the length of the pinned range in bytes.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the pinned range is empty.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the range of the kernel area of the window, i.e., the pinned
+    /// range extended to whole pages.
+    pub const fn area_range(&self) -> AddrRange<B::Addr> {
+        self.area_range
+    }
+
+    /// Returns the pinned range through the window.
+    ///
+    /// # Safety
+    ///
+    /// The page table of the kernel set must be the one in use, so that
+    /// [`addr`](Self::addr) is accessible in the current address space.
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        let ptr = self.addr().into() as *const u8;
+        // SAFETY: The window maps `len` bytes from `ptr` to frames held by
+        // its area, which stays mapped as long as `self` lives.
+        unsafe { slice::from_raw_parts(ptr, self.len) }
+    }
+}
+
+impl<B: MappingBackend> Drop for RemapWindow<'_, B> {
+    fn drop(&mut self) {
+        let (start, size) = (self.area_range.start, self.area_range.size());
+        let _ = self.set.unmap(start, size, self.page_table);
+    }
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Returns the frames backing `[vaddr, vaddr + len)`, to be mapped in a
+    /// kernel window with [`map_window`](Self::map_window).
+    ///
+    /// The range is checked with [`check_access`](Self::check_access) first,
+    /// and pages that are not resident are faulted in, like
+    /// [`read_bytes`](Self::read_bytes) does. Returns
+    /// [`MappingError::InvalidParam`] if the range is empty, and
+    /// [`MappingError::BadState`] if a page does not end up with a frame of
+    /// its own held by the area, e.g., if it is part of a huge page.
+    pub fn pin_pages(
+        &mut self,
+        vaddr: B::Addr,
+        len: usize,
+        access_flags: B::Flags,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult<PinnedPages<B>> {
+        if len == 0 {
+            return Err(MappingError::InvalidParam(err_range(vaddr, len)));
+        }
+        let end = vaddr
+            .checked_add(len)
+            .ok_or(MappingError::OutOfRange(err_range(vaddr, len)))?;
+        self.check_access(AddrRange::new(vaddr, end), access_flags)?;
+
+        let page_size = <B::FrameTrackerImpl as FrameTracker>::PAGE_SIZE;
+        let first = vaddr.align_down(page_size);
+        let mut frames = Vec::new();
+        let mut page = first;
+        while page < end {
+            let area = self.find(page.max(vaddr)).unwrap();
+            let area_start = area.start();
+            if area.frames.covering(page).is_none() {
+                self.generation += 1;
+                self.fault_area(area_start, page, access_flags, page_table)?;
+            }
+            let frame = match self.areas[&area_start].frames.covering(page) {
+                Some((start, frame, size)) if start == page && size == page_size => frame.clone(),
+                _ => return Err(MappingError::BadState(err_range(page, page_size), None)),
+            };
+            frames.push(frame);
+            page = page.add(page_size);
+        }
+        Ok(PinnedPages {
+            frames: SharedFrames::from_frames(frames),
+            offset: vaddr.sub_addr(first),
+            len,
+        })
+    }
+
+    /// Maps `pages` in a new area of this set, placed in `limit` with
+    /// `flags` by `backend`, and returns the window, which unmaps the area
+    /// when dropped.
+    ///
+    /// `flags` must be read-only: the window is meant to inspect the pages,
+    /// and writing through it would bypass copy-on-write in the set they
+    /// were pinned from. The area maps the frames like a
+    /// [shared](MemoryArea::new_shared) area, so `backend` must support
+    /// [`MappingBackend::map_frame`]. The window is placed with the
+    /// [strategy](Self::set_placement) of the set, and
+    /// [`MappingError::OutOfRange`] is returned if there is no room for it in
+    /// `limit`.
+    pub fn map_window<'a>(
+        &'a mut self,
+        pages: &PinnedPages<B>,
+        flags: B::Flags,
+        backend: B,
+        limit: AddrRange<B::Addr>,
+        page_table: &'a mut B::PageTable,
+    ) -> MappingResult<RemapWindow<'a, B>> {
+        let size = pages.frames.size();
+        let start = self
+            .find_placement(limit.start, size, limit)
+            .ok_or(MappingError::OutOfRange(untyped(limit)))?;
+        let area = MemoryArea::new_shared(start, size, pages.frames.clone(), 0, flags, backend);
+        self.map(area, page_table, false, None)?;
+        Ok(RemapWindow {
+            set: self,
+            page_table,
+            area_range: AddrRange::from_start_size(start, size),
+            offset: pages.offset,
+            len: pages.len,
+        })
+    }
+}
@@ -0,0 +1,154 @@
+//! Yield points in long-running operations of a [`MemorySet`], so that
+//! kernels running them with interrupts disabled can do the work in chunks.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use memory_addr::{AddrRange, MemoryAddr};
+
+use crate::{MappingBackend, MappingError, MappingResult, MemorySet, untyped};
+
+/// What a yield hook asks of the operation that called it, see
+/// [`MemorySet::set_yield_hook`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum YieldAction {
+    /// Go on with the next chunk.
+    Continue,
+    /// Stop after the current chunk, and return where to resume.
+    Pause,
+}
+
+/// The yield hook of a set, and the number of pages between two calls.
+pub(crate) struct YieldHook<A> {
+    every: usize,
+    hook: Arc<dyn Fn(A) -> YieldAction + Send + Sync>,
+}
+
+impl<A> Clone for YieldHook<A> {
+    fn clone(&self) -> Self {
+        Self {
+            every: self.every,
+            hook: self.hook.clone(),
+        }
+    }
+}
+
+impl<B: MappingBackend> MemorySet<B> {
+    /// Sets the hook called by [`unmap_yielding`](Self::unmap_yielding) and
+    /// [`clear_yielding`](Self::clear_yielding) every `every` pages with the
+    /// address they reached, e.g., to check for pending interrupts or a
+    /// reschedule. If it returns [`YieldAction::Pause`], the operation stops
+    /// there and returns that address.
+    ///
+    /// Sets cloned by `clone_cow` inherit it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `every` is zero.
+    pub fn set_yield_hook(
+        &mut self,
+        every: usize,
+        hook: impl Fn(B::Addr) -> YieldAction + Send + Sync + 'static,
+    ) {
+        assert!(every > 0, "yield hook never called");
+        self.yield_hook = Some(YieldHook {
+            every,
+            hook: Arc::new(hook),
+        });
+    }
+
+    /// Same as [`unmap`](Self::unmap), but unmaps the range in chunks of the
+    /// number of pages given to [`set_yield_hook`](Self::set_yield_hook),
+    /// calling the hook after each one.
+    ///
+    /// Each chunk is unmapped atomically like with [`unmap`](Self::unmap),
+    /// and the areas of the range are left consistent between chunks.
+    /// Returns `Some(addr)` if the hook paused the operation, in which case
+    /// the rest of the range (from `addr`, possibly empty) is still mapped
+    /// and can be unmapped by calling it again, or `None` once the whole
+    /// range is unmapped. Without a hook, the range is unmapped at once.
+    pub fn unmap_yielding(
+        &mut self,
+        start: B::Addr,
+        size: usize,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult<Option<B::Addr>> {
+        let Some(hook) = self.yield_hook.clone() else {
+            self.unmap(start, size, page_table)?;
+            return Ok(None);
+        };
+        let range = self.granular_range(start, size)?;
+        self.unmap_chunked(range, &hook, &mut 0, page_table)
+    }
+
+    /// Same as [`clear`](Self::clear), but unmaps the areas in chunks like
+    /// [`unmap_yielding`](Self::unmap_yielding).
+    ///
+    /// Returns `Some(addr)` if the hook paused the operation, in which case
+    /// the areas from `addr` are still mapped and calling it again goes on
+    /// with them, or `None` once the set is empty (except for holes).
+    pub fn clear_yielding(
+        &mut self,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult<Option<B::Addr>> {
+        let Some(hook) = self.yield_hook.clone() else {
+            self.clear(page_table)?;
+            return Ok(None);
+        };
+        if let Some(area) = self
+            .areas
+            .values()
+            .find(|area| area.is_sealed() && !area.is_hole())
+        {
+            return Err(MappingError::PermissionDenied(untyped(area.va_range())));
+        }
+        let ranges: Vec<_> = self
+            .areas
+            .values()
+            .filter(|area| !area.is_hole())
+            .map(|area| area.va_range())
+            .collect();
+        let mut pages = 0;
+        for range in ranges {
+            if let Some(resume) = self.unmap_chunked(range, &hook, &mut pages, page_table)? {
+                return Ok(Some(resume));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Unmaps the areas within `range` in chunks, counting the pages done
+    /// since the last call of the hook in `pages`.
+    fn unmap_chunked(
+        &mut self,
+        range: AddrRange<B::Addr>,
+        hook: &YieldHook<B::Addr>,
+        pages: &mut usize,
+        page_table: &mut B::PageTable,
+    ) -> MappingResult<Option<B::Addr>> {
+        let mut pos = range.start;
+        loop {
+            let Some((area_range, page_size)) = self
+                .iter_range(AddrRange::new(pos, range.end))
+                .next()
+                .map(|area| (area.va_range(), area.granularity().max(area.page_size())))
+            else {
+                return Ok(None);
+            };
+            pos = pos.max(area_range.start);
+            let end = pos
+                .checked_add((hook.every - *pages).saturating_mul(page_size))
+                .map_or(area_range.end, |end| end.min(area_range.end))
+                .min(range.end);
+            *pages += end.sub_addr(pos).div_ceil(page_size);
+            self.unmap(pos, end.sub_addr(pos), page_table)?;
+            pos = end;
+            if *pages >= hook.every {
+                *pages = 0;
+                if (hook.hook)(pos) == YieldAction::Pause {
+                    return Ok(Some(pos));
+                }
+            }
+        }
+    }
+}